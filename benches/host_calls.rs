@@ -0,0 +1,93 @@
+// Measures the overhead of calling into the host and of resolving the host imports.
+// Run with `cargo bench --features bench --bench host_calls`.
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use wasi_services_management::{
+    money::MoneyUnit,
+    runtime::{SMStore, WasmRuntime, WasmtimeRuntime},
+    store::UserStore,
+    State, UserData, UserId,
+};
+
+const CALLS_PER_RUN: u64 = 1_000;
+
+// Calls `host.balance` `CALLS_PER_RUN` times
+const DISPATCH_WAT: &str = r#"
+    (module
+        (import "host" "balance" (func $balance (result i64)))
+        (func (export "run") (result i64)
+            (local $i i32)
+            (local $sum i64)
+            (loop $calls
+                (local.set $sum (i64.add (local.get $sum) (call $balance)))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br_if $calls (i32.lt_u (local.get $i) (i32.const 1000)))
+            )
+            (local.get $sum)
+        )
+    )
+"#;
+
+const NO_IMPORTS_WAT: &str = r#"
+    (module
+        (func (export "run") (result i64) (i64.const 0))
+    )
+"#;
+
+const ALL_IMPORTS_WAT: &str = r#"
+    (module
+        (import "host" "balance" (func (result i64)))
+        (import "host" "order_hosting" (func (param i32) (result i32)))
+        (import "host" "transfer" (func (param i64 i64) (result i32)))
+        (import "host" "trial_days_left" (func (result i32)))
+        (import "host" "last_error_message" (func (param i32 i32) (result i32)))
+        (import "host" "heartbeat" (func))
+        (import "host" "register_user" (func (result i64)))
+        (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+        (func (export "run") (result i64) (i64.const 0))
+    )
+"#;
+
+fn new_store(runtime: &WasmtimeRuntime) -> SMStore {
+    let mut users = UserStore::new();
+    users.insert(UserId(0), UserData::new(MoneyUnit::from_cents(100)));
+    runtime.new_store(State::new(users))
+}
+
+fn host_function_dispatch(c: &mut Criterion) {
+    let runtime = WasmtimeRuntime::new();
+    let module = runtime.compile(DISPATCH_WAT.as_bytes()).unwrap();
+    let mut store = new_store(&runtime);
+    let instance = runtime.instantiate(&mut store, &module, UserId(0)).unwrap();
+
+    let mut group = c.benchmark_group("host_function_dispatch");
+    group.throughput(Throughput::Elements(CALLS_PER_RUN));
+    group.bench_function("balance", |b| {
+        b.iter(|| runtime.call(&mut store, &instance, "run").unwrap())
+    });
+    group.finish();
+}
+
+// The difference between the two measurements is the cost of checking
+// and resolving the imports.
+fn import_resolution(c: &mut Criterion) {
+    let runtime = WasmtimeRuntime::new();
+    let mut group = c.benchmark_group("import_resolution");
+    for (name, wat) in [
+        ("no_imports", NO_IMPORTS_WAT),
+        ("all_imports", ALL_IMPORTS_WAT),
+    ] {
+        let module = runtime.compile(wat.as_bytes()).unwrap();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut store = new_store(&runtime);
+                black_box(runtime.instantiate(&mut store, &module, UserId(0)).unwrap());
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, host_function_dispatch, import_resolution);
+criterion_main!(benches);
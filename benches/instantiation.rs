@@ -0,0 +1,45 @@
+// Compares the instantiation throughput of the on-demand and the pooling
+// instance allocators. Run with `cargo bench --features bench --bench instantiation`.
+use criterion::{criterion_group, criterion_main, Criterion};
+use wasi_services_management::{
+    config::{PoolingConfig, RuntimeConfig},
+    money::MoneyUnit,
+    runtime::{WasmRuntime, WasmtimeRuntime},
+    store::UserStore,
+    State, UserData, UserId,
+};
+
+const WAT: &str = r#"
+    (module
+        (import "host" "balance" (func $balance (result i64)))
+        (memory (export "memory") 16)
+        (func (export "run") (result i64) (call $balance))
+    )
+"#;
+
+fn instantiate_and_run(runtime: &WasmtimeRuntime, module: &wasmtime::Module) -> i64 {
+    let mut users = UserStore::new();
+    users.insert(UserId(0), UserData::new(MoneyUnit::from_cents(100)));
+    let mut store = runtime.new_store(State::new(users));
+    let instance = runtime.instantiate(&mut store, module, UserId(0)).unwrap();
+    runtime.call(&mut store, &instance, "run").unwrap()
+}
+
+fn instantiation(c: &mut Criterion) {
+    let on_demand = WasmtimeRuntime::new();
+    let pooling = WasmtimeRuntime::with_config(&RuntimeConfig {
+        pooling: Some(PoolingConfig::default()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let mut group = c.benchmark_group("instantiation");
+    for (name, runtime) in [("on-demand", &on_demand), ("pooling", &pooling)] {
+        let module = runtime.compile(WAT.as_bytes()).unwrap();
+        group.bench_function(name, |b| b.iter(|| instantiate_and_run(runtime, &module)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, instantiation);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+// Measures the checked arithmetic of `MoneyUnit` used on every charge.
+// Run with `cargo bench --features bench --bench money`.
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use wasi_services_management::money::{Currency, MoneyUnit};
+
+fn arithmetic(c: &mut Criterion) {
+    let balance = MoneyUnit::from_cents(1_000_000);
+    let price = MoneyUnit::from_cents(100);
+
+    let mut group = c.benchmark_group("money");
+    group.bench_function("add", |b| b.iter(|| black_box(balance) + black_box(price)));
+    group.bench_function("sub", |b| b.iter(|| black_box(balance) - black_box(price)));
+    group.bench_function("mul", |b| b.iter(|| black_box(price) * black_box(30i32)));
+    group.bench_function("basis_points", |b| {
+        b.iter(|| black_box(balance).basis_points(black_box(250)))
+    });
+    group.bench_function("convert", |b| {
+        b.iter(|| black_box(balance).convert(Currency::JPY, black_box(15_000), black_box(100)))
+    });
+    group.bench_function("parse", |b| {
+        b.iter(|| MoneyUnit::parse(black_box("12345.678"), Currency::KWD))
+    });
+    group.bench_function("display", |b| b.iter(|| black_box(balance).to_string()));
+    group.finish();
+}
+
+criterion_group!(benches, arithmetic);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+// Compares the throughput of invocations running in a new store each with those running in
+// stores recycled by `store_pool::StorePool`. Run with
+// `cargo bench --features bench --bench store_pool`.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use wasi_services_management::{
+    money::MoneyUnit,
+    runtime::{WasmRuntime, WasmtimeRuntime},
+    store::UserStore,
+    store_pool::{StorePool, StorePoolConfig},
+    State, UserData, UserId,
+};
+
+const WAT: &str = r#"
+    (module
+        (import "host" "balance" (func $balance (result i64)))
+        (memory (export "memory") 1)
+        (func (export "run") (result i64) (call $balance))
+    )
+"#;
+
+fn state() -> State {
+    let mut users = UserStore::new();
+    users.insert(UserId(0), UserData::new(MoneyUnit::from_cents(100)));
+    State::new(users)
+}
+
+fn store_per_invocation(c: &mut Criterion) {
+    let runtime = WasmtimeRuntime::new();
+    let module = runtime.compile(WAT.as_bytes()).unwrap();
+
+    let mut group = c.benchmark_group("store_per_invocation");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("new", |b| {
+        b.iter(|| {
+            let mut store = runtime.new_store(state());
+            let instance = runtime.instantiate(&mut store, &module, UserId(0)).unwrap();
+            runtime.call(&mut store, &instance, "run").unwrap()
+        })
+    });
+    let mut pool = StorePool::new(StorePoolConfig::default());
+    group.bench_function("recycled", |b| {
+        b.iter(|| {
+            let mut pooled = pool.checkout(&runtime, state);
+            let instance = runtime
+                .instantiate(&mut pooled.store, &module, UserId(0))
+                .unwrap();
+            let balance = runtime.call(&mut pooled.store, &instance, "run").unwrap();
+            pool.checkin(&runtime, pooled);
+            balance
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, store_per_invocation);
+criterion_main!(benches);
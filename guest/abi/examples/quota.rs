@@ -0,0 +1,42 @@
+// A guest storing an object of 4096 bytes, which reports the limit from the context of the error
+// when the object exceeds the storage quota of the user, see `StorageConfig::quota_bytes` of the
+// host. Built with `cargo build --target wasm32-unknown-unknown --example quota`, its `run`
+// returns the limit in bytes, or -1 if the object was stored or the result could not be read.
+
+// It calls the host, so it is built for wasm32 only
+#![cfg(target_arch = "wasm32")]
+
+use wsm_abi::{guest, Payload};
+
+#[link(wasm_import_module = "host")]
+extern "C" {
+    fn storage_put(key_ptr: i32, key_len: i32, data_ptr: i32, data_len: i32) -> i32;
+}
+
+#[no_mangle]
+pub extern "C" fn run() -> i64 {
+    guest::register_result_buffer();
+    let key = b"too-large";
+    let data = [0u8; 4096];
+    let code = unsafe {
+        storage_put(
+            key.as_ptr() as i32,
+            key.len() as i32,
+            data.as_ptr() as i32,
+            data.len() as i32,
+        )
+    };
+    let Ok(result) = guest::last_result() else {
+        return -1;
+    };
+    if code == 0 || result.code != code || !matches!(result.payload, Payload::ErrorContext(_)) {
+        return -1;
+    }
+    let Ok(context) = guest::last_error_context() else {
+        return -1;
+    };
+    context
+        .iter()
+        .find(|(key, _)| key == "limit_bytes")
+        .map_or(-1, |(_, limit)| *limit)
+}
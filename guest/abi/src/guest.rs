@@ -0,0 +1,56 @@
+// The helpers of the guests, which register a buffer for the results of their calls and read
+// the results and the contexts of the errors back. The guests are single-threaded, so the
+// buffer is shared by all of their calls: a result is read before the next mutating call.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::cell::UnsafeCell;
+
+use crate::{DecodeError, GuestResult, Payload, RESULT_LEN};
+
+#[link(wasm_import_module = "host")]
+extern "C" {
+    #[link_name = "set_result_buffer"]
+    fn host_set_result_buffer(ptr: i32, len: i32) -> i32;
+    #[link_name = "last_error_context"]
+    fn host_last_error_context(ptr: i32, len: i32) -> i32;
+}
+
+struct ResultBuffer(UnsafeCell<[u8; RESULT_LEN]>);
+
+// The guests have a single thread
+unsafe impl Sync for ResultBuffer {}
+
+static RESULT_BUFFER: ResultBuffer = ResultBuffer(UnsafeCell::new([0; RESULT_LEN]));
+
+// Registers the buffer the host writes the results into, returning the error code of
+// `host.set_result_buffer`, 0 on success
+pub fn register_result_buffer() -> i32 {
+    let ptr = RESULT_BUFFER.0.get() as i32;
+    unsafe { host_set_result_buffer(ptr, RESULT_LEN as i32) }
+}
+
+// Unregisters the buffer, e.g. before the memory it is in is reused
+pub fn unregister_result_buffer() {
+    unsafe { host_set_result_buffer(0, 0) };
+}
+
+// The result of the last mutating call since the buffer was registered
+pub fn last_result() -> Result<GuestResult, DecodeError> {
+    // Written by the host behind the back of the compiler
+    let bytes = unsafe { core::ptr::read_volatile(RESULT_BUFFER.0.get()) };
+    GuestResult::decode(&bytes)
+}
+
+// The key/value pairs of the context of the last error, empty if it has none
+pub fn last_error_context() -> Result<Vec<(String, i64)>, DecodeError> {
+    let len = match last_result()?.payload {
+        Payload::ErrorContext(len) => len as usize,
+        _ => unsafe { host_last_error_context(0, 0) }.max(0) as usize,
+    };
+    let mut bytes = vec![0; len];
+    let written = unsafe { host_last_error_context(bytes.as_mut_ptr() as i32, len as i32) };
+    if written < 0 || written as usize != len {
+        return Err(DecodeError);
+    }
+    crate::decode_context(&bytes)
+}
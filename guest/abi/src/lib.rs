@@ -0,0 +1,208 @@
+// The structured results of the mutating host functions. A guest that registers a buffer
+// with `host.set_result_buffer` gets the result of every mutating call written into it,
+// in addition to the bare error code returned by the call. The layout is little-endian:
+//
+// | Offset | Type | Field                                       |
+// | -----: | ---- | ------------------------------------------- |
+// |      0 | i32  | The error code, 0 on success                |
+// |      4 | u32  | The kind of the payload, see `Payload::tag` |
+// |      8 | i64  | The payload, 0 if there is none             |
+//
+// A failed call whose error has a context, e.g. the limit and the usage of an exceeded quota,
+// carries `Payload::ErrorContext` with the length of the context, which the guest reads with
+// `host.last_error_context`. The context is a sequence of key/value pairs, each laid out as:
+//
+// | Offset | Type | Field                             |
+// | -----: | ---- | --------------------------------- |
+// |      0 | u32  | The length of the key             |
+// |      4 | [u8] | The key in UTF-8, e.g. `limit`    |
+// |  4 + n | i64  | The value                         |
+//
+// The host encodes the results and the contexts, the guests decode them. The crate has no
+// dependencies and needs only `alloc`, so that guests written in Rust can depend on it; they
+// call the host through the helpers of `guest`, e.g.
+//
+//     wsm_abi::guest::register_result_buffer();
+//     let code = unsafe { order_hosting(30) };
+//     let result = wsm_abi::guest::last_result()?;
+//     let context = wsm_abi::guest::last_error_context()?;
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+#[cfg(target_arch = "wasm32")]
+pub mod guest;
+
+pub const RESULT_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Payload {
+    None,
+    // The balance of the caller in cents after the call
+    Balance(i64),
+    // The id of a user created by the call
+    User(u64),
+    // The id of a job scheduled by the call
+    Job(u64),
+    // The id of the order placed by a purchase
+    Order(u64),
+    // The id of a support ticket opened by the call
+    Ticket(u64),
+    // The length in bytes of the context of the error the call failed with
+    ErrorContext(u64),
+}
+
+impl Payload {
+    pub const fn tag(self) -> u32 {
+        match self {
+            Payload::None => 0,
+            Payload::Balance(_) => 1,
+            Payload::User(_) => 2,
+            Payload::Job(_) => 3,
+            Payload::Order(_) => 4,
+            Payload::Ticket(_) => 5,
+            Payload::ErrorContext(_) => 6,
+        }
+    }
+
+    const fn value(self) -> i64 {
+        match self {
+            Payload::None => 0,
+            Payload::Balance(cents) => cents,
+            Payload::User(id)
+            | Payload::Job(id)
+            | Payload::Order(id)
+            | Payload::Ticket(id)
+            | Payload::ErrorContext(id) => id as i64,
+        }
+    }
+}
+
+// The bytes are not a result or a context in the layout above, e.g. they are cut short
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeError;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("malformed result or error context")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestResult {
+    pub code: i32,
+    pub payload: Payload,
+}
+
+impl GuestResult {
+    pub const fn ok(payload: Payload) -> Self {
+        Self { code: 0, payload }
+    }
+
+    // Failed calls carry no payload but the length of the context of their error, if any,
+    // which the host sets when it writes the result
+    pub const fn error(code: i32) -> Self {
+        Self {
+            code,
+            payload: Payload::None,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; RESULT_LEN] {
+        let mut bytes = [0; RESULT_LEN];
+        bytes[0..4].copy_from_slice(&self.code.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.payload.tag().to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.payload.value().to_le_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let bytes: &[u8; RESULT_LEN] = bytes
+            .get(..RESULT_LEN)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(DecodeError)?;
+        let code = i32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let tag = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let value = i64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let payload = match tag {
+            0 => Payload::None,
+            1 => Payload::Balance(value),
+            2 => Payload::User(value as u64),
+            3 => Payload::Job(value as u64),
+            4 => Payload::Order(value as u64),
+            5 => Payload::Ticket(value as u64),
+            6 => Payload::ErrorContext(value as u64),
+            _ => return Err(DecodeError),
+        };
+        Ok(Self { code, payload })
+    }
+}
+
+// The context of an error in the layout above
+pub fn encode_context(context: &[(&str, i64)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (key, value) in context {
+        bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
+
+// The key/value pairs of the context read with `host.last_error_context`
+pub fn decode_context(mut bytes: &[u8]) -> Result<Vec<(String, i64)>, DecodeError> {
+    let mut context = Vec::new();
+    while !bytes.is_empty() {
+        let (len, rest) = bytes.split_first_chunk::<4>().ok_or(DecodeError)?;
+        let (key, rest) = rest
+            .split_at_checked(u32::from_le_bytes(*len) as usize)
+            .ok_or(DecodeError)?;
+        let (value, rest) = rest.split_first_chunk::<8>().ok_or(DecodeError)?;
+        let key = String::from_utf8(key.to_vec()).map_err(|_| DecodeError)?;
+        context.push((key, i64::from_le_bytes(*value)));
+        bytes = rest;
+    }
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::borrow::ToOwned;
+
+    use super::*;
+
+    #[test]
+    fn results_round_trip() {
+        let results = [
+            GuestResult::ok(Payload::Balance(-5)),
+            GuestResult::ok(Payload::Order(7)),
+            GuestResult {
+                code: 42,
+                payload: Payload::ErrorContext(31),
+            },
+        ];
+        for result in results {
+            assert_eq!(GuestResult::decode(&result.encode()), Ok(result));
+        }
+        let mut unknown = GuestResult::error(1).encode();
+        unknown[4] = 99;
+        assert_eq!(GuestResult::decode(&unknown), Err(DecodeError));
+        assert_eq!(GuestResult::decode(&unknown[..15]), Err(DecodeError));
+    }
+
+    #[test]
+    fn contexts_round_trip() {
+        let bytes = encode_context(&[("limit", 100), ("used", -1)]);
+        let context = decode_context(&bytes).unwrap();
+        assert_eq!(
+            context,
+            [("limit".to_owned(), 100), ("used".to_owned(), -1)]
+        );
+        assert_eq!(decode_context(&bytes[..bytes.len() - 1]), Err(DecodeError));
+        assert_eq!(decode_context(&[]), Ok(Vec::new()));
+    }
+}
@@ -0,0 +1,15 @@
+// The guest bindings of the `wsm:catalog/service-catalog` interface, generated from the same
+// `wit/service-catalog.wit` as the ones of the host, for the components to list, quote, order and
+// cancel the services of the catalog on behalf of the user running them, e.g.
+//
+//     let price = wsm_service_catalog::quote("hosting", 30)?;
+//     let order = wsm_service_catalog::order("hosting", 30)?;
+//
+// The errors carry the codes the host documents with `host-api docs` and their messages.
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "catalog-guest",
+});
+
+pub use wsm::catalog::service_catalog::*;
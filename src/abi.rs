@@ -0,0 +1,80 @@
+// The structured results of the mutating host functions and the contexts of their errors, in
+// the layout of the `wsm-abi` crate in `guest/abi`. The host writes the result of every mutating
+// call into the buffer the guest registers with `host.set_result_buffer`, see
+// `wasmtime_host::write_result`, and hands the context of the last error out with
+// `host.last_error_context`. The guests written in Rust decode both with the same crate, which
+// has no dependencies on the host.
+
+pub use wsm_abi::{decode_context, encode_context, DecodeError, GuestResult, Payload, RESULT_LEN};
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        memory_store,
+        money::MoneyUnit,
+        runtime::{WasmRuntime, WasmtimeRuntime},
+        store::UserStore,
+        BillingError, State, UserData, UserId,
+    };
+
+    const USER: UserId = UserId(0);
+
+    // Registers the result buffer at 0, orders a day of hosting and copies its result to 16, then
+    // stores an object of 8 bytes and copies the result of the failed call to 32 and the context
+    // of its error to 64
+    const MODULE: &str = r#"
+        (module
+            (import "host" "set_result_buffer"
+                (func $set_result_buffer (param i32 i32) (result i32)))
+            (import "host" "order_hosting" (func $order_hosting (param i32) (result i32)))
+            (import "host" "storage_put" (func $storage_put (param i32 i32 i32 i32) (result i32)))
+            (import "host" "last_error_context"
+                (func $last_error_context (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 256) "key")
+            (func (export "run") (result i64)
+                (drop (call $set_result_buffer (i32.const 0) (i32.const 16)))
+                (drop (call $order_hosting (i32.const 1)))
+                (memory.copy (i32.const 16) (i32.const 0) (i32.const 16))
+                (drop (call $storage_put
+                    (i32.const 256) (i32.const 3) (i32.const 0) (i32.const 8)))
+                (memory.copy (i32.const 32) (i32.const 0) (i32.const 16))
+                (i64.extend_i32_s (call $last_error_context (i32.const 64) (i32.const 128)))))
+    "#;
+
+    // A user with 10.00 who may store 4 bytes
+    fn state() -> State {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(1_000)));
+        let mut state = State::new(users);
+        state.config.storage.quota_bytes = 4;
+        memory_store::install(&mut state);
+        state
+    }
+
+    #[test]
+    fn results_written_by_the_host_are_decoded_by_the_guest_crate() {
+        let runtime = WasmtimeRuntime::new();
+        let mut store = runtime.new_store(state());
+        let module = runtime.compile(MODULE.as_bytes()).unwrap();
+        let instance = runtime.instantiate(&mut store, &module, USER).unwrap();
+        let context_len = runtime.call(&mut store, &instance, "run").unwrap() as usize;
+        let balance = store.data().users.get(&USER).unwrap().balance;
+        assert_eq!(balance, MoneyUnit::from_cents(900));
+
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let memory = memory.data(&store);
+        let ordered = GuestResult::decode(&memory[16..32]).unwrap();
+        assert_eq!(ordered, GuestResult::ok(Payload::Order(0)));
+        let failed = GuestResult::decode(&memory[32..48]).unwrap();
+        assert_eq!(failed.code, BillingError::StorageQuotaExceeded.code());
+        assert_eq!(failed.payload, Payload::ErrorContext(context_len as u64));
+        let context = decode_context(&memory[64..64 + context_len]).unwrap();
+        assert_eq!(
+            context,
+            [("limit_bytes".to_owned(), 4), ("used_bytes".to_owned(), 8)]
+        );
+    }
+}
@@ -0,0 +1,303 @@
+// Manual corrections of the balances by the operators, e.g. of a charge made in error, see
+// `ledger adjust` of the CLI. The operators authenticate with the write tokens of their accounts,
+// see `Config::operators` and `auth::authorize_operator`. An adjustment is proposed with the token
+// of an operator and, with `AdjustmentConfig::require_second_approver`, only committed once the
+// operator of another account approves it, so that no operator can move money alone, however many
+// tokens they issue themselves. The proposals, approvals and rejections are recorded in a
+// tamper-evident audit log, see `audit`, with the accounts of the operators.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    audit::AuditLog,
+    auth::{self, Scope},
+    ledger::{self, EntryKind, LedgerEntry},
+    money::MoneyUnit,
+    BillingError, Error, State, UserId,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct AdjustmentConfig {
+    // Whether the adjustments wait for the approval of the operator of another account
+    pub require_second_approver: bool,
+}
+
+impl Default for AdjustmentConfig {
+    fn default() -> Self {
+        Self {
+            require_second_approver: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct AdjustmentId(pub u64);
+
+// An adjustment waiting for its approval
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Adjustment {
+    pub id: AdjustmentId,
+    pub user: UserId,
+    // Positive amounts are credited, negative ones debited
+    pub amount: MoneyUnit,
+    pub reason: String,
+    // The account of the operator who proposed it
+    pub proposed_by: UserId,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AdjustmentEvent {
+    Proposed {
+        // Seconds since the Unix epoch
+        at: u64,
+        id: AdjustmentId,
+        user: UserId,
+        amount: MoneyUnit,
+        reason: String,
+        operator: UserId,
+    },
+    // Approved and committed to the ledger, by the proposing operator if no second approver
+    // is required
+    Approved {
+        at: u64,
+        id: AdjustmentId,
+        operator: UserId,
+    },
+    Rejected {
+        at: u64,
+        id: AdjustmentId,
+        operator: UserId,
+    },
+}
+
+#[derive(Default)]
+pub struct Adjustments {
+    pending: BTreeMap<AdjustmentId, Adjustment>,
+    next_id: u64,
+    audit_log: AuditLog<AdjustmentEvent>,
+}
+
+impl Adjustments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The adjustments waiting for their approval, oldest first
+    pub fn pending(&self) -> impl Iterator<Item = &Adjustment> {
+        self.pending.values()
+    }
+
+    // The proposals, approvals and rejections, oldest first
+    pub fn audit_log(&self) -> &AuditLog<AdjustmentEvent> {
+        &self.audit_log
+    }
+}
+
+// The account of the operator, if the token is one of its write tokens
+fn operator(state: &State, token: &str) -> Result<UserId, Error> {
+    auth::authorize_operator(state, token, Scope::Write)
+}
+
+fn commit(state: &mut State, adjustment: &Adjustment, operator: UserId) -> Result<(), Error> {
+    let user_data = state
+        .users
+        .get_mut(&adjustment.user)
+        .ok_or(BillingError::UnknownUser)?;
+    let balance = (user_data.balance + adjustment.amount)?;
+    user_data.balance = balance;
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::Adjustment {
+            adjustment: adjustment.id.0,
+            reason: adjustment.reason.clone(),
+        },
+        adjustment.amount,
+        balance,
+    ));
+    state
+        .adjustments
+        .audit_log
+        .append(AdjustmentEvent::Approved {
+            at: ledger::now_secs(),
+            id: adjustment.id,
+            operator,
+        });
+    Ok(())
+}
+
+// Proposes to credit, or with a negative amount to debit, the account. The amount must be in
+// the currency of its balance and the reason must not be empty. The adjustment is committed
+// right away unless a second approver is required.
+pub fn propose(
+    state: &mut State,
+    token: &str,
+    user: UserId,
+    amount: MoneyUnit,
+    reason: &str,
+) -> Result<AdjustmentId, Error> {
+    let operator = operator(state, token)?;
+    let currency = state
+        .users
+        .get(&user)
+        .ok_or(BillingError::UnknownUser)?
+        .balance
+        .currency();
+    if amount.is_zero() || amount.currency() != currency || reason.trim().is_empty() {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    let adjustments = &mut state.adjustments;
+    let id = AdjustmentId(adjustments.next_id);
+    adjustments.next_id += 1;
+    let adjustment = Adjustment {
+        id,
+        user,
+        amount,
+        reason: reason.to_owned(),
+        proposed_by: operator,
+    };
+    adjustments.audit_log.append(AdjustmentEvent::Proposed {
+        at: ledger::now_secs(),
+        id,
+        user,
+        amount,
+        reason: adjustment.reason.clone(),
+        operator,
+    });
+    match state.config.adjustments.require_second_approver {
+        true => {
+            adjustments.pending.insert(id, adjustment);
+        }
+        false => commit(state, &adjustment, operator)?,
+    }
+    Ok(id)
+}
+
+fn pending(state: &State, id: AdjustmentId) -> Result<&Adjustment, Error> {
+    state
+        .adjustments
+        .pending
+        .get(&id)
+        .ok_or(BillingError::UnknownApproval.into())
+}
+
+// Commits the pending adjustment on behalf of an operator other than the one who proposed it
+pub fn approve(state: &mut State, id: AdjustmentId, token: &str) -> Result<(), Error> {
+    let operator = operator(state, token)?;
+    if pending(state, id)?.proposed_by == operator {
+        return Err(BillingError::SecondApproverRequired.into());
+    }
+    let adjustment = state.adjustments.pending.remove(&id).unwrap();
+    commit(state, &adjustment, operator).inspect_err(|_| {
+        // The adjustment can be approved again once its account can take it
+        state.adjustments.pending.insert(id, adjustment.clone());
+    })
+}
+
+// Drops the pending adjustment on behalf of any operator, including the one who proposed it
+pub fn reject(state: &mut State, id: AdjustmentId, token: &str) -> Result<(), Error> {
+    let operator = operator(state, token)?;
+    pending(state, id)?;
+    state.adjustments.pending.remove(&id);
+    state
+        .adjustments
+        .audit_log
+        .append(AdjustmentEvent::Rejected {
+            at: ledger::now_secs(),
+            id,
+            operator,
+        });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{store::UserStore, UserData};
+
+    const USER: UserId = UserId(0);
+    const ALICE: UserId = UserId(1);
+    const BOB: UserId = UserId(2);
+
+    // A user with 100.00 and two operators, with the write tokens of the operators and of the user
+    fn state() -> (State, [String; 3]) {
+        let mut users = UserStore::new();
+        for user in [USER, ALICE, BOB] {
+            users.insert(user, UserData::new(MoneyUnit::from_cents(10_000)));
+        }
+        let mut state = State::new(users);
+        state.config.operators.extend([ALICE, BOB]);
+        let tokens = [ALICE, BOB, USER]
+            .map(|user| auth::issue_token(&mut state.users, user, Scope::Write).unwrap());
+        (state, tokens)
+    }
+
+    fn balance(state: &State) -> MoneyUnit {
+        state.users.get(&USER).unwrap().balance
+    }
+
+    #[test]
+    fn adjustment_is_committed_once_another_operator_approves_it() {
+        let (mut state, [alice, bob, _]) = state();
+        let credit = MoneyUnit::from_cents(1_000);
+        let id = propose(&mut state, &alice, USER, credit, "Refund").unwrap();
+        assert_eq!(state.adjustments.pending().count(), 1);
+        assert_eq!(balance(&state), MoneyUnit::from_cents(10_000));
+        assert!(matches!(
+            approve(&mut state, id, &alice),
+            Err(Error::Billing(BillingError::SecondApproverRequired))
+        ));
+
+        approve(&mut state, id, &bob).unwrap();
+        assert_eq!(state.adjustments.pending().count(), 0);
+        assert_eq!(balance(&state), MoneyUnit::from_cents(11_000));
+        let user_data = state.users.get(&USER).unwrap();
+        let entry = user_data.ledger.last().unwrap();
+        assert_eq!(
+            entry.kind,
+            EntryKind::Adjustment {
+                adjustment: id.0,
+                reason: "Refund".to_owned(),
+            }
+        );
+        drop(user_data);
+        let events = state.adjustments.audit_log().entries();
+        assert!(matches!(
+            events[1].event,
+            AdjustmentEvent::Approved { operator: BOB, .. }
+        ));
+
+        state.config.adjustments.require_second_approver = false;
+        let debit = MoneyUnit::from_cents(-500);
+        propose(&mut state, &alice, USER, debit, "Correction").unwrap();
+        assert_eq!(balance(&state), MoneyUnit::from_cents(10_500));
+    }
+
+    #[test]
+    fn invalid_and_unauthorized_adjustments_are_refused() {
+        let (mut state, [alice, bob, user]) = state();
+        let credit = MoneyUnit::from_cents(1_000);
+        assert!(matches!(
+            propose(&mut state, &user, USER, credit, "Refund"),
+            Err(Error::Billing(BillingError::InsufficientScope))
+        ));
+        assert!(matches!(
+            propose(&mut state, &alice, USER, credit, " "),
+            Err(Error::Billing(BillingError::InvalidArgumentValue))
+        ));
+        assert!(matches!(
+            propose(&mut state, &alice, UserId(3), credit, "Refund"),
+            Err(Error::Billing(BillingError::UnknownUser))
+        ));
+
+        let id = propose(&mut state, &alice, USER, credit, "Refund").unwrap();
+        reject(&mut state, id, &alice).unwrap();
+        assert!(matches!(
+            approve(&mut state, id, &bob),
+            Err(Error::Billing(BillingError::UnknownApproval))
+        ));
+        assert_eq!(balance(&state), MoneyUnit::from_cents(10_000));
+        assert_eq!(state.adjustments.audit_log().entries().len(), 2);
+    }
+}
@@ -0,0 +1,560 @@
+// Spending alerts. Every user may set up rules, e.g. "more than 50.00 spent within 24 hours"
+// or "a single charge over 10.00", which are evaluated on the entries written to the ledger
+// since the last evaluation: after every host call that charges the user and after the daily
+// job. The alerts are delivered through the `AlertChannel`s of `State::alert_channels` the user
+// has not muted them on, see `notifications`, and an alert of a rule is not repeated within
+// `AlertConfig::dedup_secs` to avoid alert storms.
+
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+    email::{Email, EmailTransport},
+    ledger::{self, LedgerEntry},
+    money::MoneyUnit,
+    notifications::{Notification, NotificationChannel, NotificationEvent},
+    outbox::{DeliveryStatus, Outbox},
+    trace, BillingError, Error, HostError, State, UserData, UserId,
+};
+
+// The window of `AlertCondition::DailySpendOver`
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug)]
+pub struct AlertConfig {
+    // An alert of a rule is not delivered again for this long
+    pub dedup_secs: u64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            dedup_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertCondition {
+    // The charges of the last 24 hours, net of the discounts, exceed the amount
+    DailySpendOver(MoneyUnit),
+    // A single charge exceeds the amount
+    ChargeOver(MoneyUnit),
+}
+
+impl AlertCondition {
+    fn threshold(self) -> MoneyUnit {
+        match self {
+            AlertCondition::DailySpendOver(amount) | AlertCondition::ChargeOver(amount) => amount,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AlertRule {
+    pub condition: AlertCondition,
+    // Seconds since the Unix epoch of the last alert of the rule
+    last_sent: Option<u64>,
+}
+
+// A rule met by the spending of a user
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Alert {
+    pub user: UserId,
+    pub condition: AlertCondition,
+    // The spend or the charge that met the condition
+    pub observed: MoneyUnit,
+    // Seconds since the Unix epoch
+    pub at: u64,
+    // The trace of the invocation that made the charges, see `trace`
+    pub trace_id: Option<String>,
+}
+
+impl Alert {
+    pub fn message(&self) -> String {
+        let observed = self.observed.to_decimal_string();
+        let threshold = self.condition.threshold().to_decimal_string();
+        let currency = self.observed.currency().code();
+        match self.condition {
+            AlertCondition::DailySpendOver(_) => format!(
+                "User {} has spent {observed} {currency} within 24 hours, over the alert \
+                 threshold of {threshold} {currency}.",
+                self.user.0
+            ),
+            AlertCondition::ChargeOver(_) => format!(
+                "User {} has been charged {observed} {currency} at once, over the alert \
+                 threshold of {threshold} {currency}.",
+                self.user.0
+            ),
+        }
+    }
+}
+
+// Delivers the alerts and the other notifications, e.g. to the owners of the accounts or to the
+// operator
+pub trait AlertChannel {
+    // The channel the users mute the events on, see `notifications::NotificationPreferences`
+    fn channel(&self) -> NotificationChannel;
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), Error>;
+
+    fn deliver(&mut self, notification: &Notification) -> Result<(), Error>;
+}
+
+// Writes the alerts to the standard error, e.g. for development
+pub struct LogChannel;
+
+impl AlertChannel for LogChannel {
+    fn channel(&self) -> NotificationChannel {
+        NotificationChannel::Log
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), Error> {
+        eprintln!("{}Alert: {}", trace::log_prefix(), alert.message());
+        Ok(())
+    }
+
+    fn deliver(&mut self, notification: &Notification) -> Result<(), Error> {
+        eprintln!(
+            "{}Notification ({}): {}",
+            trace::log_prefix(),
+            notification.event.name(),
+            notification.message
+        );
+        Ok(())
+    }
+}
+
+// Emails the alerts to the addresses of the users, skipping the users without one
+pub struct EmailChannel {
+    transport: Box<dyn EmailTransport>,
+    addresses: BTreeMap<UserId, String>,
+}
+
+impl EmailChannel {
+    pub fn new(transport: Box<dyn EmailTransport>) -> Self {
+        Self {
+            transport,
+            addresses: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_address(mut self, user: UserId, address: impl Into<String>) -> Self {
+        self.addresses.insert(user, address.into());
+        self
+    }
+}
+
+impl EmailChannel {
+    fn send(&mut self, user: UserId, subject: &str, body: String) -> Result<(), Error> {
+        let Some(to) = self.addresses.get(&user) else {
+            return Ok(());
+        };
+        self.transport.send(&Email {
+            from: user,
+            to: to.clone(),
+            subject: subject.to_owned(),
+            body,
+        })
+    }
+}
+
+impl AlertChannel for EmailChannel {
+    fn channel(&self) -> NotificationChannel {
+        NotificationChannel::Email
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), Error> {
+        self.send(alert.user, "Spending alert", alert.message())
+    }
+
+    fn deliver(&mut self, notification: &Notification) -> Result<(), Error> {
+        let subject = match notification.event {
+            NotificationEvent::LowBalance => "Low balance",
+            NotificationEvent::Expiry => "Expiry reminder",
+            NotificationEvent::Invoice => "New invoice",
+            NotificationEvent::Incident => "Service incident",
+            NotificationEvent::SpendingAlert => "Spending alert",
+            NotificationEvent::CostAnomaly => "Unusual spending",
+        };
+        self.send(notification.user, subject, notification.message.clone())
+    }
+}
+
+// POSTs the alerts as JSON to a plain `http://host[:port][/path]` URL, e.g. of a chat
+// integration. Responses other than 2xx fail the delivery. With an outbox, the alerts are
+// recorded in it first and the failed deliveries are attempted again, see `outbox`.
+pub struct WebhookChannel {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+    outbox: Option<Outbox>,
+}
+
+fn webhook_error(e: impl ToString) -> Error {
+    HostError::Webhook(e.to_string()).into()
+}
+
+impl WebhookChannel {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| webhook_error(format!("`{url}` is not an http:// URL")))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(webhook_error)?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(webhook_error(format!("`{url}` has no host")));
+        }
+        Ok(Self {
+            url: url.to_owned(),
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+            outbox: None,
+        })
+    }
+
+    pub fn with_outbox(mut self, outbox: Outbox) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+}
+
+impl WebhookChannel {
+    pub(crate) fn post(&self, body: &str) -> Result<(), Error> {
+        let mut stream =
+            TcpStream::connect((self.host.as_str(), self.port)).map_err(webhook_error)?;
+        stream
+            .set_read_timeout(Some(WEBHOOK_TIMEOUT))
+            .map_err(webhook_error)?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.host,
+            body.len()
+        );
+        stream
+            .write_all(request.as_bytes())
+            .map_err(webhook_error)?;
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .map_err(webhook_error)?;
+        let status = response.split_whitespace().nth(1).unwrap_or_default();
+        match status.starts_with('2') {
+            true => Ok(()),
+            false => Err(webhook_error(format!(
+                "the webhook responded with `{status}`"
+            ))),
+        }
+    }
+}
+
+impl WebhookChannel {
+    fn send(&self, body: String) -> Result<(), Error> {
+        let Some(outbox) = &self.outbox else {
+            return self.post(&body);
+        };
+        let id = outbox.enqueue(&self.url, body)?;
+        let delivery = outbox.delivery(id);
+        match delivery
+            .map(|delivery| outbox.attempt(delivery))
+            .transpose()?
+        {
+            Some(DeliveryStatus::Pending | DeliveryStatus::Dead) => Err(webhook_error(
+                "the delivery failed and is kept in the outbox",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AlertChannel for WebhookChannel {
+    fn channel(&self) -> NotificationChannel {
+        NotificationChannel::Webhook
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), Error> {
+        self.send(serde_json::to_string(alert).map_err(webhook_error)?)
+    }
+
+    fn deliver(&mut self, notification: &Notification) -> Result<(), Error> {
+        self.send(serde_json::to_string(notification).map_err(webhook_error)?)
+    }
+}
+
+// Adds the rule, returning its index among the rules of the user. The threshold must be
+// positive and in the currency of the balance.
+pub fn add_rule(
+    state: &mut State,
+    user: UserId,
+    condition: AlertCondition,
+) -> Result<usize, Error> {
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    let threshold = condition.threshold();
+    if threshold.is_negative()
+        || threshold.is_zero()
+        || threshold.currency() != user_data.balance.currency()
+    {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    // The entries written before the rule are not evaluated against it
+    user_data.alerts_checked = user_data.archived_entries + user_data.ledger.len();
+    user_data.alert_rules.push(AlertRule {
+        condition,
+        last_sent: None,
+    });
+    Ok(user_data.alert_rules.len() - 1)
+}
+
+pub fn remove_rule(state: &mut State, user: UserId, index: usize) -> Result<AlertRule, Error> {
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    if index >= user_data.alert_rules.len() {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    Ok(user_data.alert_rules.remove(index))
+}
+
+// Replaces the user's rule of the kind (0 for `DailySpendOver`, 1 for `ChargeOver`) with one of
+// the threshold in minor units of the balance's currency, or removes it with a threshold of 0.
+// See the `set_spend_alert` host function.
+pub(crate) fn guest_set_rule(
+    state: &mut State,
+    user: UserId,
+    kind: i32,
+    threshold: i64,
+) -> Result<(), Error> {
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    let threshold = MoneyUnit::from_minor_units(threshold, user_data.balance.currency());
+    let condition = match kind {
+        0 => AlertCondition::DailySpendOver(threshold),
+        1 => AlertCondition::ChargeOver(threshold),
+        _ => return Err(BillingError::InvalidArgumentValue.into()),
+    };
+    if threshold.is_negative() {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    user_data.alert_rules.retain(|rule| {
+        std::mem::discriminant(&rule.condition) != std::mem::discriminant(&condition)
+    });
+    if !threshold.is_zero() {
+        add_rule(state, user, condition)?;
+    }
+    Ok(())
+}
+
+// The charge of the entry, positive, or `None` for the entries that are not charges
+fn charge(entry: &LedgerEntry) -> Option<MoneyUnit> {
+    match entry.kind.is_billable() && entry.amount.is_negative() {
+        true => entry.amount.checked_neg(),
+        false => None,
+    }
+}
+
+// The charges net of the discounts of the last 24 hours
+fn daily_spend(user_data: &UserData, now: u64) -> Option<MoneyUnit> {
+    let since = now.saturating_sub(SECS_PER_DAY);
+    user_data
+        .ledger
+        .iter()
+        .filter(|entry| entry.at >= since && entry.kind.is_billable())
+        .try_fold(
+            MoneyUnit::zero(user_data.balance.currency()),
+            |total, entry| total.sub_allowing_negative(entry.amount).ok(),
+        )
+}
+
+fn exceeds(amount: MoneyUnit, threshold: MoneyUnit) -> bool {
+    matches!(amount.checked_cmp(threshold), Ok(Ordering::Greater))
+}
+
+// Evaluates the rules of the user on the entries written since the last evaluation and
+// delivers the alerts. Failures of the channels are ignored, the spending itself is unaffected.
+pub(crate) fn evaluate(state: &mut State, user: UserId) {
+    let dedup_secs = state.config.alerts.dedup_secs;
+    let Some(user_data) = state.users.get_mut(&user) else {
+        return;
+    };
+    let first = user_data
+        .alerts_checked
+        .saturating_sub(user_data.archived_entries);
+    user_data.alerts_checked = user_data.archived_entries + user_data.ledger.len();
+    let largest_charge = user_data
+        .ledger
+        .iter()
+        .skip(first)
+        .filter_map(charge)
+        .max_by_key(|charge| charge.minor_units());
+    let (Some(largest_charge), false) = (largest_charge, user_data.alert_rules.is_empty()) else {
+        return;
+    };
+    let now = ledger::now_secs();
+    let spend = daily_spend(user_data, now);
+    let mut alerts = Vec::new();
+    for rule in user_data.alert_rules.iter_mut() {
+        let observed = match rule.condition {
+            AlertCondition::DailySpendOver(_) => spend,
+            AlertCondition::ChargeOver(_) => Some(largest_charge),
+        };
+        let Some(observed) = observed.filter(|&o| exceeds(o, rule.condition.threshold())) else {
+            continue;
+        };
+        if rule.last_sent.is_some_and(|at| now < at + dedup_secs) {
+            continue;
+        }
+        rule.last_sent = Some(now);
+        alerts.push(Alert {
+            user,
+            condition: rule.condition,
+            observed,
+            at: now,
+            trace_id: trace::current(),
+        });
+    }
+    let preferences = &user_data.notification_preferences;
+    for alert in &alerts {
+        for channel in state.alert_channels.iter_mut() {
+            if preferences.is_enabled(NotificationEvent::SpendingAlert, channel.channel()) {
+                let _ = channel.notify(alert);
+            }
+        }
+    }
+}
+
+// Evaluates the rules of every user, e.g. after the daily job
+pub(crate) fn evaluate_all(state: &mut State) {
+    let users = state
+        .users
+        .read()
+        .iter()
+        .filter(|(_, user_data)| !user_data.alert_rules.is_empty())
+        .map(|(&user, _)| user)
+        .collect::<Vec<_>>();
+    for user in users {
+        evaluate(state, user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{host, money::Currency, store::UserStore};
+
+    const USER: UserId = UserId(0);
+
+    // Keeps the alerts delivered through it
+    struct Recorder(Arc<Mutex<Vec<Alert>>>);
+
+    impl AlertChannel for Recorder {
+        fn channel(&self) -> NotificationChannel {
+            NotificationChannel::Log
+        }
+
+        fn notify(&mut self, alert: &Alert) -> Result<(), Error> {
+            self.0.lock().unwrap().push(alert.clone());
+            Ok(())
+        }
+
+        fn deliver(&mut self, _notification: &Notification) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    // A user with 100.00 whose alerts are recorded
+    fn state() -> (State, Arc<Mutex<Vec<Alert>>>) {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(10_000)));
+        let mut state = State::new(users);
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        state
+            .alert_channels
+            .push(Box::new(Recorder(alerts.clone())));
+        (state, alerts)
+    }
+
+    // Orders the days of hosting at 1.00 a day, evaluating the rules like the host functions do
+    fn order(state: &mut State, days: i32) {
+        host::record_charges(state, USER, |state| host::order_hosting(state, USER, days)).unwrap();
+    }
+
+    #[test]
+    fn rules_met_by_the_charges_alert_once_per_dedup_period() {
+        let (mut state, alerts) = state();
+        let charge_over = AlertCondition::ChargeOver(MoneyUnit::from_cents(1_000));
+        let daily_over = AlertCondition::DailySpendOver(MoneyUnit::from_cents(2_500));
+        add_rule(&mut state, USER, charge_over).unwrap();
+        add_rule(&mut state, USER, daily_over).unwrap();
+
+        order(&mut state, 5);
+        assert!(alerts.lock().unwrap().is_empty());
+        order(&mut state, 15);
+        order(&mut state, 15);
+        let conditions = alerts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|a| a.condition)
+            .collect::<Vec<_>>();
+        assert_eq!(conditions, [charge_over, daily_over]);
+        let alert = alerts.lock().unwrap()[1].clone();
+        assert_eq!(alert.observed, MoneyUnit::from_cents(3_500));
+        assert!(alert.message().contains("35.00 USD within 24 hours"));
+    }
+
+    #[test]
+    fn invalid_rules_and_webhooks_are_rejected() {
+        let (mut state, _) = state();
+        let invalid = [
+            AlertCondition::ChargeOver(MoneyUnit::from_cents(0)),
+            AlertCondition::DailySpendOver(MoneyUnit::from_minor_units(100, Currency::EUR)),
+        ];
+        for condition in invalid {
+            assert!(matches!(
+                add_rule(&mut state, USER, condition),
+                Err(Error::Billing(BillingError::InvalidArgumentValue))
+            ));
+        }
+        assert!(matches!(
+            remove_rule(&mut state, USER, 0),
+            Err(Error::Billing(BillingError::InvalidArgumentValue))
+        ));
+        for url in [
+            "https://example.com/hook",
+            "http://:80/hook",
+            "http://host:port",
+        ] {
+            assert!(matches!(
+                WebhookChannel::new(url),
+                Err(Error::Host(HostError::Webhook(_)))
+            ));
+        }
+    }
+}
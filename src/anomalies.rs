@@ -0,0 +1,197 @@
+// Detection of the unusual spending of the accounts, e.g. of a guest stuck ordering in a loop or
+// of a leaked token. The spend of a user within the last `AnomalyConfig::window_secs` is compared
+// with the average spend of the same windows before it, the baseline, after every host call that
+// charges the user and after the daily job. A spend over `AnomalyConfig::factor` times the
+// baseline is an anomaly: the user is notified of it, see `notifications`, and with
+// `AnomalyConfig::soft_cap` the orders and the transfers of the user fail with
+// `BillingError::SpendCapped` until the anomaly is acknowledged by the operator with
+// `acknowledge` or by the user with `host.acknowledge_anomaly`. The cap is soft: the usage
+// charged by the host, e.g. the compute, goes on. No anomaly is detected while another one is
+// waiting for its acknowledgement or within a window of the last one, nor before the account has
+// spent a whole window.
+
+use serde::Serialize;
+
+use crate::{
+    ledger,
+    money::MoneyUnit,
+    notifications::{self, NotificationEvent},
+    BillingError, Error, State, UserData, UserId,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct AnomalyConfig {
+    // How many times the baseline the spend must exceed, 0 disables the detection
+    pub factor: f64,
+    pub window_secs: u64,
+    // The windows before the current one averaged into the baseline, at most
+    pub baseline_windows: u32,
+    // Smaller spends are never anomalies, in the currency of the balances
+    pub min_spend: MoneyUnit,
+    // Whether the orders and the transfers fail until the anomaly is acknowledged
+    pub soft_cap: bool,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            factor: 0.0,
+            window_secs: 24 * 60 * 60,
+            baseline_windows: 28,
+            min_spend: MoneyUnit::default(),
+            soft_cap: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Acknowledger {
+    User,
+    Operator,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Anomaly {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    // The spend within the window
+    pub spend: MoneyUnit,
+    // The average spend of the windows before it
+    pub baseline: MoneyUnit,
+    pub acknowledged_by: Option<Acknowledger>,
+    pub acknowledged_at: Option<u64>,
+}
+
+// The anomaly of the user waiting for its acknowledgement, if any
+pub fn active(user_data: &UserData) -> Option<&Anomaly> {
+    user_data
+        .anomalies
+        .last()
+        .filter(|anomaly| anomaly.acknowledged_by.is_none())
+}
+
+// Acknowledges the anomaly of the user, lifting the soft cap
+pub fn acknowledge(state: &mut State, user: UserId, by: Acknowledger) -> Result<(), Error> {
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    let anomaly = user_data
+        .anomalies
+        .last_mut()
+        .filter(|anomaly| anomaly.acknowledged_by.is_none())
+        .ok_or(BillingError::NoActiveAnomaly)?;
+    anomaly.acknowledged_by = Some(by);
+    anomaly.acknowledged_at = Some(ledger::now_secs());
+    Ok(())
+}
+
+// Fails the orders and the transfers of the users capped by an anomaly, see
+// `policy::authorize`
+pub(crate) fn check_cap(state: &State, user: UserId) -> Result<(), Error> {
+    let capped = state.config.anomalies.soft_cap
+        && state
+            .users
+            .get(&user)
+            .is_some_and(|user_data| active(&user_data).is_some());
+    match capped {
+        true => Err(BillingError::SpendCapped.into()),
+        false => Ok(()),
+    }
+}
+
+// The charges net of the discounts in minor units of the balance's currency, from `since` until
+// before `until`
+fn spend(user_data: &UserData, since: u64, until: u64) -> i128 {
+    let currency = user_data.balance.currency();
+    -user_data
+        .ledger
+        .iter()
+        .filter(|entry| entry.at >= since && entry.at < until)
+        .filter(|entry| entry.kind.is_billable() && entry.amount.currency() == currency)
+        .map(|entry| entry.amount.minor_units() as i128)
+        .sum::<i128>()
+}
+
+// The spend of the current window and the baseline, `None` before the account has spent a whole
+// window
+fn measure(user_data: &UserData, config: &AnomalyConfig, now: u64) -> Option<(i128, i128)> {
+    let window = config.window_secs.max(1);
+    let start = now.saturating_sub(window);
+    let first = user_data.ledger.first()?.at;
+    let windows = (start.saturating_sub(first) / window).min(config.baseline_windows as u64);
+    if windows == 0 {
+        return None;
+    }
+    let current = spend(user_data, start, now.saturating_add(1));
+    let before = spend(user_data, start - windows * window, start);
+    Some((current, before / windows as i128))
+}
+
+// Looks for an anomaly in the spend of the user, notifying the user of a new one
+pub(crate) fn evaluate(state: &mut State, user: UserId) {
+    let config = state.config.anomalies;
+    let Some(user_data) = state.users.get_mut(&user) else {
+        return;
+    };
+    let now = ledger::now_secs();
+    // The spend acknowledged is not reported again within its window
+    let recent = user_data
+        .anomalies
+        .last()
+        .is_some_and(|anomaly| now < anomaly.at.saturating_add(config.window_secs));
+    if config.factor <= 0.0 || recent || active(user_data).is_some() {
+        return;
+    }
+    let Some((current, baseline)) = measure(user_data, &config, now) else {
+        return;
+    };
+    let currency = user_data.balance.currency();
+    let min_spend = match config.min_spend.currency() == currency {
+        true => config.min_spend.minor_units() as i128,
+        false => 0,
+    };
+    if current <= 0 || current < min_spend || current as f64 <= baseline as f64 * config.factor {
+        return;
+    }
+    let to_money = |minor: i128| {
+        MoneyUnit::from_minor_units(minor.clamp(0, i64::MAX as i128) as i64, currency)
+    };
+    let anomaly = Anomaly {
+        at: now,
+        spend: to_money(current),
+        baseline: to_money(baseline),
+        acknowledged_by: None,
+        acknowledged_at: None,
+    };
+    let mut message = format!(
+        "User {} has spent {} within {} hours, over {} times the usual {}.",
+        user.0,
+        anomaly.spend,
+        config.window_secs / 3600,
+        config.factor,
+        anomaly.baseline
+    );
+    if config.soft_cap {
+        message.push_str(" Orders and transfers are paused until the spend is acknowledged.");
+    }
+    user_data.anomalies.push(anomaly);
+    notifications::notify(state, user, NotificationEvent::CostAnomaly, message);
+}
+
+// Looks for anomalies in the spend of every user, e.g. after the daily job
+pub(crate) fn evaluate_all(state: &mut State) {
+    if state.config.anomalies.factor <= 0.0 {
+        return;
+    }
+    let users = state
+        .users
+        .read()
+        .iter()
+        .map(|(&user, _)| user)
+        .collect::<Vec<_>>();
+    for user in users {
+        evaluate(state, user);
+    }
+}
@@ -0,0 +1,332 @@
+// Archival of old ledger entries. The entries as old as `ArchiveConfig::max_age_days` are
+// moved out of memory into zstd-compressed JSON files, one per user and archival run,
+// while a summary of every archived month stays in `UserData::ledger_summaries`.
+//
+// Ledger entries keep their indices, e.g. those of the orders and the disputes, since
+// `UserData::archived_entries` counts the archived ones: the entry at index `i` is
+// `ledger[i - archived_entries]`. Entries under open disputes are never archived.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    balance_history,
+    config::ArchiveConfig,
+    disputes::DisputeStatus,
+    ledger::{self, LedgerEntry},
+    money::MoneyUnit,
+    BillingError, Error, HostError, State, UserData, UserId,
+};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const COMPRESSION_LEVEL: i32 = 3;
+const EXTENSION: &str = "json.zst";
+
+// The archived entries of a calendar month (UTC) of the user's ledger
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MonthlySummary {
+    pub year: i32,
+    pub month: u8,
+    pub entries: u64,
+    // The entries for which `EntryKind::is_purchase` holds
+    pub purchases: u64,
+    // The sum of the debits, positive
+    pub debits: MoneyUnit,
+    pub credits: MoneyUnit,
+    pub closing_balance: MoneyUnit,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveReport {
+    // Users with entries archived
+    pub users: u64,
+    pub entries: u64,
+}
+
+fn persistence_error(e: impl ToString) -> Error {
+    HostError::Persistence(e.to_string()).into()
+}
+
+fn year_month(at: u64) -> Result<(i32, u8), Error> {
+    let date = OffsetDateTime::from_unix_timestamp(at as i64)
+        .map_err(|_| BillingError::InvalidArgumentValue)?;
+    Ok((date.year(), date.month() as u8))
+}
+
+// The archive of the entries starting at the index, e.g. `7-120.json.zst`
+fn archive_path(dir: &Path, user: UserId, first: usize) -> PathBuf {
+    dir.join(format!("{}-{first}.{EXTENSION}", user.0))
+}
+
+fn add_to_summaries(summaries: &mut Vec<MonthlySummary>, entry: &LedgerEntry) -> Result<(), Error> {
+    let (year, month) = year_month(entry.at)?;
+    let zero = MoneyUnit::zero(entry.amount.currency());
+    let summary = match summaries.last_mut() {
+        Some(last) if (last.year, last.month) == (year, month) => last,
+        _ => {
+            summaries.push(MonthlySummary {
+                year,
+                month,
+                entries: 0,
+                purchases: 0,
+                debits: zero,
+                credits: zero,
+                closing_balance: entry.balance_after,
+            });
+            summaries.last_mut().unwrap()
+        }
+    };
+    summary.entries += 1;
+    summary.purchases += entry.kind.is_purchase() as u64;
+    match entry.amount.checked_neg() {
+        Some(debit) if entry.amount.is_negative() => summary.debits = (summary.debits + debit)?,
+        _ => summary.credits = (summary.credits + entry.amount)?,
+    }
+    summary.closing_balance = entry.balance_after;
+    Ok(())
+}
+
+// Archives the entries of the user as old as the age limit or older, returning their number.
+// Does nothing if archival is not configured.
+pub fn archive(state: &mut State, user: UserId, now: u64) -> Result<usize, Error> {
+    let config = &state.config.archive;
+    let Some(max_age_days) = config.max_age_days else {
+        return Ok(0);
+    };
+    let cutoff = now.saturating_sub(max_age_days as u64 * SECS_PER_DAY);
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let archived = user_data.archived_entries;
+    // The open disputes need their entries at hand
+    let first_disputed = state
+        .disputes
+        .of_user(user)
+        .filter(|dispute| dispute.status == DisputeStatus::Open)
+        .map(|dispute| dispute.entry.saturating_sub(archived))
+        .min()
+        .unwrap_or(usize::MAX);
+    let count = user_data
+        .ledger
+        .iter()
+        .take_while(|entry| entry.at <= cutoff)
+        .count()
+        .min(first_disputed);
+    if count == 0 {
+        return Ok(0);
+    }
+    write_archive(config, user, archived, &user_data.ledger[..count])?;
+    drop(user_data);
+
+    let user_data = state.users.get_mut(&user).unwrap();
+    let mut summaries = user_data.ledger_summaries.clone();
+    for entry in &user_data.ledger[..count] {
+        add_to_summaries(&mut summaries, entry)?;
+    }
+    // The balance history keeps the samples of the archived entries
+    balance_history::sync(user_data);
+    user_data.balance_history.synced_entries -= count;
+    user_data.ledger.drain(..count);
+    user_data.ledger_summaries = summaries;
+    user_data.archived_entries += count;
+    Ok(count)
+}
+
+// Written aside and renamed, so that a crash never leaves a partial archive behind
+fn write_archive(
+    config: &ArchiveConfig,
+    user: UserId,
+    first: usize,
+    entries: &[LedgerEntry],
+) -> Result<(), Error> {
+    fs::create_dir_all(&config.dir).map_err(persistence_error)?;
+    let json = serde_json::to_vec(entries).map_err(persistence_error)?;
+    let compressed =
+        zstd::stream::encode_all(json.as_slice(), COMPRESSION_LEVEL).map_err(persistence_error)?;
+    let path = archive_path(&config.dir, user, first);
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp).map_err(persistence_error)?;
+    file.write_all(&compressed)
+        .and_then(|_| file.sync_data())
+        .map_err(persistence_error)?;
+    fs::rename(&tmp, &path).map_err(persistence_error)
+}
+
+// Archives the old entries of every user, e.g. as the daily archival job.
+// Fails on the first user whose entries fail to be archived.
+pub fn archive_all(state: &mut State, now: u64) -> Result<ArchiveReport, Error> {
+    let users = state
+        .users
+        .read()
+        .iter()
+        .map(|(&user, _)| user)
+        .collect::<Vec<_>>();
+    let mut report = ArchiveReport::default();
+    for user in users {
+        let count = archive(state, user, now)?;
+        if count > 0 {
+            report.users += 1;
+            report.entries += count as u64;
+        }
+    }
+    Ok(report)
+}
+
+// The daily archival job. Entries that fail to be archived stay in memory until
+// the next day.
+pub(crate) fn advance_day(state: &mut State) {
+    let _ = archive_all(state, ledger::now_secs());
+}
+
+// Moves every archived entry of the user back into the ledger and removes the archives,
+// returning the number of restored entries.
+pub fn restore(state: &mut State, user: UserId) -> Result<usize, Error> {
+    let dir = state.config.archive.dir.clone();
+    let archived = state
+        .users
+        .get(&user)
+        .ok_or(BillingError::UnknownUser)?
+        .archived_entries;
+    let mut entries = Vec::with_capacity(archived);
+    let mut paths = Vec::new();
+    // The archives follow each other, each starting where the previous one ends
+    while entries.len() < archived {
+        let path = archive_path(&dir, user, entries.len());
+        let compressed = fs::read(&path).map_err(persistence_error)?;
+        let json = zstd::stream::decode_all(compressed.as_slice()).map_err(persistence_error)?;
+        let archive: Vec<LedgerEntry> = serde_json::from_slice(&json).map_err(persistence_error)?;
+        if archive.is_empty() {
+            return Err(persistence_error(format!("{} is empty", path.display())));
+        }
+        entries.extend(archive);
+        paths.push(path);
+    }
+    if entries.len() != archived {
+        return Err(persistence_error("the archives do not match the ledger"));
+    }
+
+    let user_data: &mut UserData = state.users.get_mut(&user).unwrap();
+    user_data.ledger.splice(..0, entries);
+    user_data.balance_history.synced_entries += archived;
+    user_data.archived_entries = 0;
+    user_data.ledger_summaries.clear();
+    for path in paths {
+        fs::remove_file(path).map_err(persistence_error)?;
+    }
+    Ok(archived)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        disputes::{self, Resolution},
+        host,
+        orders::{self, OrderId, OrderStatus},
+        reconcile,
+        sharded_store::UserRef,
+        store::UserStore,
+    };
+
+    const USER: UserId = UserId(0);
+    const LATER: u64 = 40 * SECS_PER_DAY;
+
+    // A user with 1000.00 and four orders of 10 days of hosting, the last two of them `LATER`,
+    // archived after 30 days into a directory of the test
+    fn state(name: &str) -> (State, Vec<OrderId>) {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(100_000)));
+        let mut state = State::new(users);
+        let dir = std::env::temp_dir().join(format!("archive-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        state.config.archive = ArchiveConfig {
+            max_age_days: Some(30),
+            dir,
+        };
+        let orders = (0..4)
+            .map(|_| {
+                host::record_charges(&mut state, USER, |state| {
+                    host::order_hosting(state, USER, 10)
+                })
+                .unwrap()
+                .unwrap()
+            })
+            .collect();
+        let ledger = &mut state.users.get_mut(&USER).unwrap().ledger;
+        for entry in &mut ledger[2..] {
+            entry.at += LATER;
+        }
+        (state, orders)
+    }
+
+    fn user_data(state: &State) -> UserRef<'_> {
+        state.users.get(&USER).unwrap()
+    }
+
+    #[test]
+    fn indices_survive_archival() {
+        let (mut state, orders) = state("indices");
+        let now = ledger::now_secs() + LATER;
+        let dispute = disputes::open(&mut state, USER, 1, "never used").unwrap();
+        // The disputed entry and the ones after it stay in memory
+        assert_eq!(archive(&mut state, USER, now).unwrap(), 1);
+        let order = orders::get(&state, USER, orders[1]).unwrap();
+        assert_eq!(order.entry, 1);
+        assert_eq!(orders::status(&state, order), OrderStatus::Disputed);
+
+        disputes::resolve(&mut state, dispute, "operator", Resolution::Refund, "").unwrap();
+        assert_eq!(archive(&mut state, USER, now).unwrap(), 1);
+        assert_eq!(user_data(&state).archived_entries, 2);
+        let order = orders::get(&state, USER, orders[1]).unwrap().clone();
+        assert_eq!(orders::status(&state, &order), OrderStatus::Refunded);
+        assert!(matches!(
+            user_data(&state).ledger_entry(order.entry),
+            Err(Error::Billing(BillingError::EntryArchived))
+        ));
+        for &id in &orders[2..] {
+            let order = orders::get(&state, USER, id).unwrap();
+            let entry = user_data(&state).ledger_entry(order.entry).unwrap().clone();
+            assert!(matches!(entry.kind, ledger::EntryKind::HostingOrder { .. }));
+            assert!(orders::details_json(&state, order).is_ok());
+        }
+        fs::remove_dir_all(&state.config.archive.dir).unwrap();
+    }
+
+    #[test]
+    fn history_and_reconciliation_line_up_with_the_archives() {
+        let (mut state, _) = state("history");
+        let early = user_data(&state).ledger[1].clone();
+        let user_data_mut = state.users.get_mut(&USER).unwrap();
+        let before = balance_history::balance_at(user_data_mut, early.at);
+        assert_eq!(before, Some(early.balance_after));
+
+        let now = ledger::now_secs() + LATER;
+        assert_eq!(archive(&mut state, USER, now).unwrap(), 2);
+        let summary = user_data(&state).ledger_summaries.last().copied().unwrap();
+        assert_eq!(summary.entries, 2);
+        assert_eq!(summary.closing_balance, early.balance_after);
+        assert!(reconcile::reconcile(&state).is_empty());
+        let user_data_mut = state.users.get_mut(&USER).unwrap();
+        assert_eq!(balance_history::balance_at(user_data_mut, early.at), before);
+        let balance = user_data(&state).balance;
+        let user_data_mut = state.users.get_mut(&USER).unwrap();
+        assert_eq!(
+            balance_history::balance_at(user_data_mut, now),
+            Some(balance)
+        );
+
+        assert_eq!(restore(&mut state, USER).unwrap(), 2);
+        assert_eq!(user_data(&state).ledger.len(), 4);
+        assert!(reconcile::reconcile(&state).is_empty());
+        let user_data_mut = state.users.get_mut(&USER).unwrap();
+        assert_eq!(balance_history::balance_at(user_data_mut, early.at), before);
+        assert_eq!(user_data_mut.balance_history.samples().len(), 5);
+        fs::remove_dir_all(&state.config.archive.dir).unwrap();
+    }
+}
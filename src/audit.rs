@@ -0,0 +1,151 @@
+// Tamper-evident audit logs, e.g. of the API tokens and of the secrets. Entries can only be
+// appended, and each one carries the hash of the previous one, so that modifying, removing
+// or reordering entries breaks the chain, see `AuditLog::verify`.
+//
+// The hash of an entry is the hex-encoded SHA-256 of its sequence number, the hash of the
+// previous entry (`GENESIS_HASH` for the first one) and the canonical JSON of its event,
+// the object keys sorted, separated by newlines. `AuditLog::export` writes the entries
+// as JSON Lines for compliance archives, which `verify_export` checks without knowing
+// the type of the events.
+
+use std::io::{BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{sha256_hex, Error, HostError};
+
+// The previous hash of the first entry
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry<T> {
+    // The position in the log, from 0
+    pub seq: u64,
+    pub event: T,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditLog<T> {
+    entries: Vec<AuditEntry<T>>,
+}
+
+impl<T> Default for AuditLog<T> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+fn persistence_error(e: impl ToString) -> Error {
+    HostError::Persistence(e.to_string()).into()
+}
+
+// The JSON of the event with the object keys sorted, as `serde_json::Value` keeps them
+fn canonical_json(event: &impl Serialize) -> Result<String, Error> {
+    serde_json::to_value(event)
+        .map(|value| value.to_string())
+        .map_err(persistence_error)
+}
+
+fn entry_hash(seq: u64, prev_hash: &str, event_json: &str) -> String {
+    sha256_hex(format!("{seq}\n{prev_hash}\n{event_json}").as_bytes())
+}
+
+// Checks that the entry follows the previous hash and that its own hash matches
+fn check_link<T: Serialize>(entry: &AuditEntry<T>, seq: u64, prev_hash: &str) -> Result<(), Error> {
+    let intact = entry.seq == seq
+        && entry.prev_hash == prev_hash
+        && entry.hash == entry_hash(seq, prev_hash, &canonical_json(&entry.event)?);
+    match intact {
+        true => Ok(()),
+        false => Err(HostError::AuditChainBroken(seq).into()),
+    }
+}
+
+impl<T: Serialize> AuditLog<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The events are plain data, so they always serialize
+    pub(crate) fn append(&mut self, event: T) {
+        let seq = self.entries.len() as u64;
+        let prev_hash = self
+            .entries
+            .last()
+            .map_or(GENESIS_HASH, |entry| entry.hash.as_str())
+            .to_owned();
+        let hash = entry_hash(seq, &prev_hash, &canonical_json(&event).unwrap());
+        self.entries.push(AuditEntry {
+            seq,
+            event,
+            prev_hash,
+            hash,
+        });
+    }
+
+    // Oldest first
+    pub fn entries(&self) -> &[AuditEntry<T>] {
+        &self.entries
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter().map(|entry| &entry.event)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // The hash of the last entry, which commits to the whole log, e.g. to be published
+    pub fn head(&self) -> &str {
+        self.entries
+            .last()
+            .map_or(GENESIS_HASH, |entry| entry.hash.as_str())
+    }
+
+    // Fails with `HostError::AuditChainBroken` at the first entry that does not match
+    pub fn verify(&self) -> Result<(), Error> {
+        let mut prev_hash = GENESIS_HASH;
+        for (seq, entry) in self.entries.iter().enumerate() {
+            check_link(entry, seq as u64, prev_hash)?;
+            prev_hash = &entry.hash;
+        }
+        Ok(())
+    }
+
+    // Writes the entries as JSON Lines, one `AuditEntry` per line
+    pub fn export(&self, mut out: impl Write) -> Result<(), Error> {
+        for entry in &self.entries {
+            serde_json::to_writer(&mut out, entry).map_err(persistence_error)?;
+            out.write_all(b"\n").map_err(persistence_error)?;
+        }
+        out.flush().map_err(persistence_error)
+    }
+}
+
+// Verifies the chain of a log exported with `AuditLog::export`, returning the number of
+// entries. Fails with `HostError::AuditChainBroken` at the first entry that does not match.
+pub fn verify_export(input: impl BufRead) -> Result<u64, Error> {
+    let mut prev_hash = GENESIS_HASH.to_owned();
+    let mut seq = 0;
+    for line in input.lines() {
+        let line = line.map_err(persistence_error)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: AuditEntry<serde_json::Value> =
+            serde_json::from_str(&line).map_err(|_| HostError::AuditChainBroken(seq))?;
+        check_link(&entry, seq, &prev_hash)?;
+        prev_hash = entry.hash;
+        seq += 1;
+    }
+    Ok(seq)
+}
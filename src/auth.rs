@@ -0,0 +1,308 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use rand::RngCore;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::AuditLog, ledger, sha256_hex, store::UserStore, BillingError, Error, HostError, State,
+    UserId,
+};
+
+const TOKEN_PREFIX: &str = "wsm_";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Read,
+    // Write access implies read access
+    Write,
+}
+
+impl Scope {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            _ => Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+
+    fn allows(self, required: Scope) -> bool {
+        self == Scope::Write || required == Scope::Read
+    }
+}
+
+pub(crate) struct TokenRecord {
+    pub(crate) user: UserId,
+    pub(crate) scope: Scope,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenOp {
+    Issued,
+    Revoked,
+    // Replaced by a new token with the same scope, see `rotate_token`
+    Rotated,
+}
+
+// An entry of the audit log of the tokens, which never contains the tokens themselves
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TokenEvent {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    pub user: UserId,
+    pub op: TokenOp,
+    // The guest of the user rather than the operator made the change
+    pub by_guest: bool,
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    sha256_hex(token.as_bytes())
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    std::iter::once(TOKEN_PREFIX.to_owned())
+        .chain(bytes.iter().map(|b| format!("{b:02x}")))
+        .collect()
+}
+
+fn log(store: &mut UserStore, user: UserId, op: TokenOp, by_guest: bool) {
+    store.token_audit.append(TokenEvent {
+        at: ledger::now_secs(),
+        user,
+        op,
+        by_guest,
+    });
+}
+
+// The changes of the tokens, oldest first, see `audit`
+pub fn audit_log(store: &UserStore) -> &AuditLog<TokenEvent> {
+    &store.token_audit
+}
+
+// Issues a new API token for the user. The plain text token is returned only once,
+// the store keeps only its hash.
+pub fn issue_token(store: &mut UserStore, user: UserId, scope: Scope) -> Result<String, Error> {
+    if !store.contains(&user) {
+        return Err(BillingError::UnknownUser.into());
+    }
+    let token = generate_token();
+    store
+        .tokens
+        .insert(hash_token(&token), TokenRecord { user, scope });
+    log(store, user, TokenOp::Issued, false);
+    Ok(token)
+}
+
+pub fn revoke_token(store: &mut UserStore, token: &str) -> Result<(), Error> {
+    let record = store
+        .tokens
+        .remove(&hash_token(token))
+        .ok_or(BillingError::InvalidToken)?;
+    log(store, record.user, TokenOp::Revoked, false);
+    Ok(())
+}
+
+// Revokes every token of the user, e.g. when the account is compromised.
+pub fn revoke_all_tokens(store: &mut UserStore, user: UserId) {
+    let before = store.tokens.len();
+    store.tokens.retain(|_, record| record.user != user);
+    if store.tokens.len() != before {
+        log(store, user, TokenOp::Revoked, false);
+    }
+}
+
+// Replaces a token of the user with a new one of the same scope on behalf of the user's
+// guest, see `host.rotate_api_key`. The old token stops working as the new one starts to.
+pub fn rotate_token(store: &mut UserStore, user: UserId, token: &str) -> Result<String, Error> {
+    let hash = hash_token(token);
+    let scope = match store.tokens.get(&hash) {
+        Some(record) if record.user == user => record.scope,
+        // Tokens of other users are not revealed to exist
+        _ => return Err(BillingError::InvalidToken.into()),
+    };
+    let rotated = generate_token();
+    store.tokens.remove(&hash);
+    store
+        .tokens
+        .insert(hash_token(&rotated), TokenRecord { user, scope });
+    log(store, user, TokenOp::Rotated, true);
+    Ok(rotated)
+}
+
+// The check performed by the management API before serving a request.
+// Returns the user on whose behalf the request is made.
+pub fn authorize(store: &UserStore, token: &str, required: Scope) -> Result<UserId, Error> {
+    let record = store
+        .tokens
+        .get(&hash_token(token))
+        .ok_or(BillingError::InvalidToken)?;
+    if !record.scope.allows(required) {
+        return Err(BillingError::InsufficientScope.into());
+    }
+    Ok(record.user)
+}
+
+// Like `authorize`, for the operations of the operators, which need a token of one of the
+// accounts of `Config::operators`. Returns the account of the operator.
+pub fn authorize_operator(state: &State, token: &str, required: Scope) -> Result<UserId, Error> {
+    let user = authorize(&state.users, token, required)?;
+    if !state.config.operators.contains(&user) {
+        return Err(BillingError::InsufficientScope.into());
+    }
+    Ok(user)
+}
+
+// A token of the token file, see `save_tokens`
+#[derive(Serialize, Deserialize)]
+struct TokenRow {
+    hash: String,
+    user: UserId,
+    scope: Scope,
+}
+
+fn persistence_error(e: impl ToString) -> Error {
+    HostError::Persistence(e.to_string()).into()
+}
+
+// Writes the tokens to the file as JSON Lines, e.g. for the CLI to issue and revoke the tokens
+// the daemon checks. Only the hashes of the tokens are written, never the tokens themselves. The
+// file is written aside and renamed over the old one, so that a crash leaves one of them intact.
+pub fn save_tokens(store: &UserStore, path: impl AsRef<Path>) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut rows = String::new();
+    for (hash, record) in &store.tokens {
+        let row = TokenRow {
+            hash: hash.clone(),
+            user: record.user,
+            scope: record.scope,
+        };
+        rows.push_str(&serde_json::to_string(&row).map_err(persistence_error)?);
+        rows.push('\n');
+    }
+    let mut aside = path.to_owned().into_os_string();
+    aside.push(".tmp");
+    let mut file = File::create(&aside).map_err(persistence_error)?;
+    file.write_all(rows.as_bytes())
+        .and_then(|_| file.sync_data())
+        .map_err(persistence_error)?;
+    fs::rename(&aside, path).map_err(persistence_error)
+}
+
+// Replaces the tokens of the store with the ones of the file written by `save_tokens`, none if
+// the file does not exist yet
+pub fn load_tokens(store: &mut UserStore, path: impl AsRef<Path>) -> Result<(), Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            store.tokens.clear();
+            return Ok(());
+        }
+        Err(e) => return Err(persistence_error(e)),
+    };
+    let mut tokens = BTreeMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(persistence_error)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = serde_json::from_str::<TokenRow>(&line).map_err(persistence_error)?;
+        let record = TokenRecord {
+            user: row.user,
+            scope: row.scope,
+        };
+        tokens.insert(row.hash, record);
+    }
+    store.tokens = tokens;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{money::MoneyUnit, UserData};
+
+    const USER: UserId = UserId(0);
+
+    fn store() -> UserStore {
+        let mut store = UserStore::new();
+        store.insert(USER, UserData::new(MoneyUnit::from_cents(0)));
+        store
+    }
+
+    #[test]
+    fn tokens_authorize_their_scope_until_rotated() {
+        let mut store = store();
+        let token = issue_token(&mut store, USER, Scope::Read).unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(authorize(&store, &token, Scope::Read).unwrap(), USER);
+        assert!(matches!(
+            authorize(&store, &token, Scope::Write),
+            Err(Error::Billing(BillingError::InsufficientScope))
+        ));
+
+        let rotated = rotate_token(&mut store, USER, &token).unwrap();
+        assert!(matches!(
+            authorize(&store, &token, Scope::Read),
+            Err(Error::Billing(BillingError::InvalidToken))
+        ));
+        assert_eq!(authorize(&store, &rotated, Scope::Read).unwrap(), USER);
+        let ops = audit_log(&store)
+            .entries()
+            .iter()
+            .map(|entry| entry.event.op)
+            .collect::<Vec<_>>();
+        assert_eq!(ops, [TokenOp::Issued, TokenOp::Rotated]);
+    }
+
+    #[test]
+    fn unknown_and_revoked_tokens_are_rejected() {
+        let mut store = store();
+        assert!(matches!(
+            issue_token(&mut store, UserId(1), Scope::Read),
+            Err(Error::Billing(BillingError::UnknownUser))
+        ));
+        let token = issue_token(&mut store, USER, Scope::Write).unwrap();
+        // Other users cannot rotate the token
+        assert!(matches!(
+            rotate_token(&mut store, UserId(1), &token),
+            Err(Error::Billing(BillingError::InvalidToken))
+        ));
+        revoke_token(&mut store, &token).unwrap();
+        assert!(matches!(
+            authorize(&store, &token, Scope::Read),
+            Err(Error::Billing(BillingError::InvalidToken))
+        ));
+        assert!(matches!(
+            revoke_token(&mut store, &token),
+            Err(Error::Billing(BillingError::InvalidToken))
+        ));
+    }
+
+    #[test]
+    fn saved_tokens_are_loaded_by_hash() {
+        let path = std::env::temp_dir().join(format!("auth-tokens-{}", std::process::id()));
+        let mut store = store();
+        let token = issue_token(&mut store, USER, Scope::Write).unwrap();
+        save_tokens(&store, &path).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains(&token));
+
+        let mut loaded = self::store();
+        load_tokens(&mut loaded, &path).unwrap();
+        assert_eq!(authorize(&loaded, &token, Scope::Write).unwrap(), USER);
+        fs::remove_file(&path).unwrap();
+        // A missing file has no tokens
+        load_tokens(&mut loaded, &path).unwrap();
+        assert!(authorize(&loaded, &token, Scope::Read).is_err());
+    }
+}
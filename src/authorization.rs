@@ -0,0 +1,133 @@
+// Authorization of the calls of the mutating host functions by the embedders of the library,
+// e.g. against a directory service or the entitlements of an external billing system. Every
+// hook of `State::authorization_hooks` is asked before the call runs, in order, and the first
+// one vetoing the call fails it with its error, which the guest receives like any other.
+
+use crate::{BillingError, Error, State, UserId};
+
+// A call of a mutating host function, see `HostFunction::mutating`
+#[derive(Clone, Copy, Debug)]
+pub struct HostCall<'a> {
+    pub user: UserId,
+    pub function: &'static str,
+    // The arguments as passed by the guest. Strings and buffers are passed as pointers into
+    // the guest memory followed by their lengths, which are not resolved.
+    pub args: &'a [i64],
+}
+
+impl HostCall<'_> {
+    // The call as text, e.g. `order_hosting(30)` for logs
+    pub fn summary(&self) -> String {
+        let args = self
+            .args
+            .iter()
+            .map(i64::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("{}({args})", self.function)
+    }
+}
+
+pub trait AuthorizationHook {
+    // Fails to veto the call, e.g. with `BillingError::CallVetoed` or with a custom error
+    // registered in `State::custom_errors`
+    fn authorize(&mut self, call: &HostCall) -> Result<(), Error>;
+}
+
+// Vetoes the calls of the listed functions, e.g. to disable them for every guest
+pub struct DenyFunctions(pub Vec<&'static str>);
+
+impl AuthorizationHook for DenyFunctions {
+    fn authorize(&mut self, call: &HostCall) -> Result<(), Error> {
+        match self.0.contains(&call.function) {
+            true => Err(BillingError::CallVetoed.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn authorize(state: &mut State, call: &HostCall) -> Result<(), Error> {
+    state
+        .authorization_hooks
+        .iter_mut()
+        .try_for_each(|hook| hook.authorize(call))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        history, ledger,
+        money::MoneyUnit,
+        queues::Message,
+        runtime::{WasmRuntime, WasmtimeRuntime},
+        store::UserStore,
+        UserData,
+    };
+
+    const USER: UserId = UserId(0);
+
+    // Polls the inbox into a buffer of 64 bytes
+    const MODULE: &str = r#"
+        (module
+            (import "host" "queue_poll" (func $queue_poll (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "poll") (result i64)
+                (i64.extend_i32_s (call $queue_poll (i32.const 0) (i32.const 64)))))
+    "#;
+
+    // Records the calls it is asked about, vetoing the ones of `DenyFunctions`
+    struct Recording(Rc<RefCell<Vec<String>>>, DenyFunctions);
+
+    impl AuthorizationHook for Recording {
+        fn authorize(&mut self, call: &HostCall) -> Result<(), Error> {
+            self.0.borrow_mut().push(call.summary());
+            self.1.authorize(call)
+        }
+    }
+
+    // A user with a message in the inbox
+    fn state() -> State {
+        let mut users = UserStore::new();
+        let mut user_data = UserData::new(MoneyUnit::from_cents(10_000));
+        user_data.inbox.push_back(Message {
+            from: USER,
+            sent_at: ledger::now_secs(),
+            body: b"hello".to_vec(),
+        });
+        users.insert(USER, user_data);
+        State::new(users)
+    }
+
+    fn poll(denied: Vec<&'static str>) -> (Result<i64, Error>, Vec<String>, usize) {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut state = state();
+        let hook = Recording(calls.clone(), DenyFunctions(denied));
+        state.authorization_hooks.push(Box::new(hook));
+        let runtime = WasmtimeRuntime::new();
+        let mut store = runtime.new_store(state);
+        let result = history::execute(&runtime, &mut store, USER, MODULE.as_bytes(), "poll");
+        let inbox = store.data().users.get(&USER).unwrap().inbox.len();
+        let calls = calls.borrow().clone();
+        (result, calls, inbox)
+    }
+
+    #[test]
+    fn hooks_are_asked_before_the_inbox_is_consumed() {
+        let (result, calls, inbox) = poll(Vec::new());
+        assert_eq!(result.unwrap(), 5);
+        assert_eq!(calls, ["queue_poll(0, 64)"]);
+        assert_eq!(inbox, 0);
+    }
+
+    #[test]
+    fn vetoed_poll_fails_and_leaves_the_message() {
+        let (result, calls, inbox) = poll(vec!["queue_poll"]);
+        let code = BillingError::CallVetoed.code();
+        assert_eq!(result.unwrap(), -code as i64);
+        assert_eq!(calls, ["queue_poll(0, 64)"]);
+        assert_eq!(inbox, 1);
+    }
+}
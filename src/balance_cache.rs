@@ -0,0 +1,431 @@
+// A write-behind cache of the balances in front of a store that is slow to query, e.g.
+// `pg_store::PgBalanceStore`, for the chatty guests checking their balances on every call. The
+// balances are read from memory, see `CacheMetrics`, and the updates are logged to a WAL and
+// written to the store every `WriteBehindConfig::flush_interval`.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    money::{Currency, MoneyUnit},
+    BillingError, Error, HostError, UserId,
+};
+
+// Where the balances are persisted, e.g. `pg_store::PgBalanceStore`
+pub trait BalanceBackend {
+    fn balance(&mut self, user: UserId) -> Result<MoneyUnit, Error>;
+    // Both return the new balance
+    fn charge(&mut self, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error>;
+    fn credit(&mut self, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error>;
+}
+
+#[cfg(feature = "postgres")]
+impl BalanceBackend for crate::pg_store::PgBalanceStore {
+    fn balance(&mut self, user: UserId) -> Result<MoneyUnit, Error> {
+        self.balance(user)
+    }
+
+    fn charge(&mut self, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error> {
+        self.charge(user, amount)
+    }
+
+    fn credit(&mut self, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error> {
+        self.credit(user, amount)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WriteBehindConfig {
+    // How often the updates are written to the backend. They are flushed by the first
+    // update or `WriteBehindCache::tick` after the interval has passed.
+    pub flush_interval: Duration,
+    // The log of the updates not yet written to the backend
+    pub wal_path: PathBuf,
+    // Whether every update is synced to the disk before it is acknowledged. Without it,
+    // the updates of the last moments before a crash of the machine (not just of the
+    // process) may be lost.
+    pub sync_wal: bool,
+}
+
+impl Default for WriteBehindConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval: Duration::from_secs(1),
+            wal_path: PathBuf::from("balances.wal"),
+            sync_wal: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub flushes: u64,
+    // Users whose pending updates were written to the backend
+    pub flushed_users: u64,
+    // Users whose pending updates failed to be written, see `WriteBehindCache::flush`
+    pub failed_users: u64,
+}
+
+impl CacheMetrics {
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct CachedBalance {
+    balance: MoneyUnit,
+    // The sum of the updates not yet written to the backend, negative for net charges
+    pending: MoneyUnit,
+}
+
+fn persistence_error(e: impl ToString) -> Error {
+    HostError::Persistence(e.to_string()).into()
+}
+
+// A WAL record is a line of `<user id> <minor units> <currency code>`, e.g. `7 -300 USD`
+fn parse_record(line: &str) -> Option<(UserId, MoneyUnit)> {
+    let mut fields = line.split(' ');
+    let user = fields.next()?.parse().ok()?;
+    let minor = fields.next()?.parse().ok()?;
+    let currency = Currency::from_code(fields.next()?)?;
+    Some((UserId(user), MoneyUnit::from_minor_units(minor, currency)))
+}
+
+// Serves the balances from memory, applying the updates optimistically and writing them
+// to the backend in batches. Every update is appended to the WAL before it is applied,
+// so the updates acknowledged before a crash are replayed by the next `open`.
+//
+// The updates are optimistic: other host instances sharing the backend may spend
+// the same money in the meantime, in which case the backend rejects the flush.
+pub struct WriteBehindCache<B: BalanceBackend> {
+    backend: B,
+    config: WriteBehindConfig,
+    balances: HashMap<UserId, CachedBalance>,
+    wal: File,
+    last_flush: Instant,
+    metrics: CacheMetrics,
+}
+
+impl<B: BalanceBackend> WriteBehindCache<B> {
+    // Opens the WAL, writing the updates left in it by a previous instance to the backend.
+    // Fails like `flush` if any of them fails to be written.
+    pub fn open(backend: B, config: WriteBehindConfig) -> Result<Self, Error> {
+        let mut balances = HashMap::<UserId, CachedBalance>::new();
+        if let Ok(file) = File::open(&config.wal_path) {
+            // A record torn by a crash is the last line and was never acknowledged
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(persistence_error)?;
+                let Some((user, delta)) = parse_record(&line) else {
+                    break;
+                };
+                let pending = match balances.get(&user) {
+                    Some(cached) => (cached.pending + delta)?,
+                    None => delta,
+                };
+                // The balance is reloaded from the backend after the flush below
+                balances.insert(
+                    user,
+                    CachedBalance {
+                        balance: pending,
+                        pending,
+                    },
+                );
+            }
+        }
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.wal_path)
+            .map_err(persistence_error)?;
+        let mut cache = Self {
+            backend,
+            config,
+            balances,
+            wal,
+            last_flush: Instant::now(),
+            metrics: CacheMetrics::default(),
+        };
+        cache.flush()?;
+        cache.balances.clear();
+        Ok(cache)
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        self.metrics
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    fn cached(&mut self, user: UserId) -> Result<&mut CachedBalance, Error> {
+        if self.balances.contains_key(&user) {
+            self.metrics.hits += 1;
+        } else {
+            self.metrics.misses += 1;
+            let balance = self.backend.balance(user)?;
+            let pending = MoneyUnit::zero(balance.currency());
+            self.balances
+                .insert(user, CachedBalance { balance, pending });
+        }
+        Ok(self.balances.get_mut(&user).unwrap())
+    }
+
+    pub fn balance(&mut self, user: UserId) -> Result<MoneyUnit, Error> {
+        let balance = self.cached(user)?.balance;
+        self.tick()?;
+        Ok(balance)
+    }
+
+    // Debits the amount, failing if the cached balance would become negative.
+    // Returns the new balance.
+    pub fn charge(&mut self, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error> {
+        let delta = amount
+            .checked_neg()
+            .ok_or(BillingError::InvalidArgumentValue)?;
+        let cached = self.cached(user)?;
+        let balance = (cached.balance - amount)?;
+        self.update(user, balance, delta)
+    }
+
+    pub fn credit(&mut self, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error> {
+        let cached = self.cached(user)?;
+        let balance = (cached.balance + amount)?;
+        self.update(user, balance, amount)
+    }
+
+    fn update(
+        &mut self,
+        user: UserId,
+        balance: MoneyUnit,
+        delta: MoneyUnit,
+    ) -> Result<MoneyUnit, Error> {
+        let pending = (self.balances[&user].pending + delta)?;
+        let record = format!(
+            "{} {} {}\n",
+            user.0,
+            delta.minor_units(),
+            delta.currency().code()
+        );
+        self.wal
+            .write_all(record.as_bytes())
+            .map_err(persistence_error)?;
+        if self.config.sync_wal {
+            self.wal.sync_data().map_err(persistence_error)?;
+        }
+        self.balances
+            .insert(user, CachedBalance { balance, pending });
+        self.tick()?;
+        Ok(balance)
+    }
+
+    // Flushes the pending updates if the flush interval has passed, e.g. to be called
+    // periodically by embedders whose users may stop making requests.
+    pub fn tick(&mut self) -> Result<(), Error> {
+        if self.last_flush.elapsed() >= self.config.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Writes the pending updates to the backend, refreshing the cached balances with
+    // the updates of other instances. The updates the backend rejects, e.g. charges
+    // exceeding the balance left by other instances, are dropped from the cache and the WAL,
+    // so that the balances of their users are reloaded from the backend. The updates that
+    // fail for other reasons stay pending. The first error is returned after the updates
+    // of the other users have been flushed.
+    //
+    // The WAL is rewritten once the backend has been updated, so a crash in between
+    // replays the flushed updates again. The window is kept short but not closed,
+    // since closing it needs the backend to deduplicate the updates.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.last_flush = Instant::now();
+        self.metrics.flushes += 1;
+        let mut failed = None;
+        let pending = self
+            .balances
+            .iter()
+            .filter(|(_, cached)| !cached.pending.is_zero())
+            .map(|(&user, cached)| (user, cached.pending))
+            .collect::<Vec<_>>();
+        for (user, pending) in pending {
+            let written = match pending.checked_neg() {
+                Some(charge) if pending.is_negative() => self.backend.charge(user, charge),
+                _ => self.backend.credit(user, pending),
+            };
+            match written {
+                Ok(balance) => {
+                    self.metrics.flushed_users += 1;
+                    let pending = MoneyUnit::zero(balance.currency());
+                    self.balances
+                        .insert(user, CachedBalance { balance, pending });
+                }
+                Err(e) => {
+                    self.metrics.failed_users += 1;
+                    if let Error::Billing(_) = e {
+                        self.balances.remove(&user);
+                    }
+                    failed.get_or_insert(e);
+                }
+            }
+        }
+        self.rewrite_wal()?;
+        failed.map_or(Ok(()), Err)
+    }
+
+    // Replaces the WAL with the still pending updates. The new WAL is written aside
+    // and renamed over the old one, so that a crash leaves one of them intact.
+    fn rewrite_wal(&mut self) -> Result<(), Error> {
+        let mut path = self.config.wal_path.clone().into_os_string();
+        path.push(".tmp");
+        let path = PathBuf::from(path);
+        let mut records = String::new();
+        for (user, cached) in &self.balances {
+            if !cached.pending.is_zero() {
+                records.push_str(&format!(
+                    "{} {} {}\n",
+                    user.0,
+                    cached.pending.minor_units(),
+                    cached.pending.currency().code()
+                ));
+            }
+        }
+        let mut file = File::create(&path).map_err(persistence_error)?;
+        file.write_all(records.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(persistence_error)?;
+        fs::rename(&path, &self.config.wal_path).map_err(persistence_error)?;
+        self.wal = OpenOptions::new()
+            .append(true)
+            .open(&self.config.wal_path)
+            .map_err(persistence_error)?;
+        Ok(())
+    }
+}
+
+impl<B: BalanceBackend> Drop for WriteBehindCache<B> {
+    fn drop(&mut self) {
+        // The WAL keeps the updates that fail to be flushed for the next `open`
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory_store::MemoryAccountStore,
+        migrate::{AccountBackend, AccountRecord},
+    };
+
+    const USER: UserId = UserId(1);
+
+    fn backend() -> MemoryAccountStore {
+        let mut backend = MemoryAccountStore::new();
+        backend
+            .put_account(USER, &AccountRecord::new(MoneyUnit::from_cents(1_000)))
+            .unwrap();
+        backend
+    }
+
+    // A WAL of its own for every test, flushed only when asked to
+    fn config(name: &str) -> WriteBehindConfig {
+        let wal_path =
+            std::env::temp_dir().join(format!("balance-cache-{name}-{}.wal", std::process::id()));
+        let _ = fs::remove_file(&wal_path);
+        WriteBehindConfig {
+            flush_interval: Duration::from_secs(3600),
+            wal_path,
+            sync_wal: true,
+        }
+    }
+
+    fn stored(cache: &WriteBehindCache<MemoryAccountStore>) -> MoneyUnit {
+        cache.backend().clone().account(USER).unwrap().balance
+    }
+
+    #[test]
+    fn balances_are_read_from_the_cache() {
+        let mut cache = WriteBehindCache::open(backend(), config("reads")).unwrap();
+        for _ in 0..4 {
+            assert_eq!(cache.balance(USER).unwrap(), MoneyUnit::from_cents(1_000));
+        }
+        let metrics = cache.metrics();
+        assert_eq!((metrics.hits, metrics.misses), (3, 1));
+        assert_eq!(metrics.hit_ratio(), 0.75);
+        assert!(cache.balance(UserId(2)).is_err());
+    }
+
+    #[test]
+    fn updates_are_written_once_the_flush_interval_has_passed() {
+        let config = WriteBehindConfig {
+            flush_interval: Duration::from_millis(50),
+            ..config("interval")
+        };
+        let wal_path = config.wal_path.clone();
+        let mut cache = WriteBehindCache::open(backend(), config).unwrap();
+        cache.charge(USER, MoneyUnit::from_cents(300)).unwrap();
+        cache.credit(USER, MoneyUnit::from_cents(50)).unwrap();
+        assert_eq!(stored(&cache), MoneyUnit::from_cents(1_000));
+        assert_eq!(
+            fs::read_to_string(&wal_path).unwrap(),
+            "1 -300 USD\n1 50 USD\n"
+        );
+
+        std::thread::sleep(Duration::from_millis(60));
+        cache.tick().unwrap();
+        assert_eq!(stored(&cache), MoneyUnit::from_cents(750));
+        assert_eq!(fs::read_to_string(&wal_path).unwrap(), "");
+        let metrics = cache.metrics();
+        assert_eq!((metrics.flushed_users, metrics.failed_users), (1, 0));
+        drop(cache);
+        fs::remove_file(&wal_path).unwrap();
+    }
+
+    #[test]
+    fn charges_exceeding_the_balance_are_not_logged() {
+        let config = config("overdraw");
+        let wal_path = config.wal_path.clone();
+        let mut cache = WriteBehindCache::open(backend(), config).unwrap();
+        assert!(matches!(
+            cache.charge(USER, MoneyUnit::from_cents(1_001)),
+            Err(Error::Billing(BillingError::BalanceWouldBecomeNegative))
+        ));
+        assert_eq!(cache.balance(USER).unwrap(), MoneyUnit::from_cents(1_000));
+        assert_eq!(fs::read_to_string(&wal_path).unwrap(), "");
+        drop(cache);
+        fs::remove_file(&wal_path).unwrap();
+    }
+
+    #[test]
+    fn the_wal_is_replayed_after_a_crash() {
+        let config = config("crash");
+        let wal_path = config.wal_path.clone();
+        let mut cache = WriteBehindCache::open(backend(), config.clone()).unwrap();
+        cache.charge(USER, MoneyUnit::from_cents(300)).unwrap();
+        cache.charge(USER, MoneyUnit::from_cents(200)).unwrap();
+        // The process dies before the flush, leaving a torn record behind
+        let backend = cache.backend().clone();
+        std::mem::forget(cache);
+        let mut wal = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        wal.write_all(b"1 -9").unwrap();
+
+        let mut cache = WriteBehindCache::open(backend, config).unwrap();
+        assert_eq!(stored(&cache), MoneyUnit::from_cents(500));
+        assert_eq!(cache.balance(USER).unwrap(), MoneyUnit::from_cents(500));
+        assert_eq!(fs::read_to_string(&wal_path).unwrap(), "");
+        drop(cache);
+        fs::remove_file(&wal_path).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{
+    auth::{self, Scope},
+    config::BalanceHistoryConfig,
+    ledger,
+    money::MoneyUnit,
+    store::UserStore,
+    BillingError, Error, UserData,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BalanceSample {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    pub balance: MoneyUnit,
+}
+
+// The balance of a user over time, one sample per mutation. The samples are taken from
+// the ledger, whose entries record the balance after every mutation, so none is missed.
+#[derive(Clone, Debug)]
+pub struct BalanceSeries {
+    // Oldest first
+    samples: Vec<BalanceSample>,
+    // The ledger entries already turned into samples
+    pub(crate) synced_entries: usize,
+}
+
+impl BalanceSeries {
+    // Starts with the balance of a new account
+    pub fn new(balance: MoneyUnit) -> Self {
+        Self {
+            samples: vec![BalanceSample {
+                at: ledger::now_secs(),
+                balance,
+            }],
+            synced_entries: 0,
+        }
+    }
+
+    pub fn samples(&self) -> &[BalanceSample] {
+        &self.samples
+    }
+
+    // The balance at the time, `None` before the series starts,
+    // e.g. before the retention period
+    pub fn balance_at(&self, at: u64) -> Option<MoneyUnit> {
+        let after = self.samples.partition_point(|sample| sample.at <= at);
+        after.checked_sub(1).map(|i| self.samples[i].balance)
+    }
+}
+
+// Appends the samples of the ledger entries recorded since the last sync
+pub fn sync(user_data: &mut UserData) {
+    let series = &mut user_data.balance_history;
+    for entry in &user_data.ledger[series.synced_entries..] {
+        series.samples.push(BalanceSample {
+            at: entry.at,
+            balance: entry.balance_after,
+        });
+    }
+    series.synced_entries = user_data.ledger.len();
+}
+
+// Keeps one sample per bucket, the last one, for the samples older than the full
+// resolution window, and drops the samples older than the retention period.
+// The last dropped sample is kept as the balance at the start of the retention period.
+pub fn compact(series: &mut BalanceSeries, config: &BalanceHistoryConfig, now: u64) {
+    let retention_start = now.saturating_sub(config.retention_secs);
+    let full_resolution_start = now.saturating_sub(config.full_resolution_secs);
+    let bucket_secs = config.bucket_secs.max(1);
+    let samples = std::mem::take(&mut series.samples);
+    let mut kept: Vec<BalanceSample> = Vec::with_capacity(samples.len());
+    for sample in samples {
+        let replaces_last = kept.last().is_some_and(|last| {
+            sample.at < retention_start
+                || (sample.at < full_resolution_start
+                    && last.at / bucket_secs == sample.at / bucket_secs)
+        });
+        if replaces_last {
+            kept.pop();
+        }
+        kept.push(sample);
+    }
+    if let Some(first) = kept.first_mut() {
+        first.at = first.at.max(retention_start);
+    }
+    series.samples = kept;
+}
+
+// Syncs and compacts the series of every user, e.g. nightly
+pub(crate) fn compact_all(users: &mut UserStore, config: &BalanceHistoryConfig) {
+    let now = ledger::now_secs();
+    for (_, user_data) in users.iter_mut() {
+        sync(user_data);
+        compact(&mut user_data.balance_history, config, now);
+    }
+}
+
+// The balance of the user at the time
+pub fn balance_at(user_data: &mut UserData, at: u64) -> Option<MoneyUnit> {
+    sync(user_data);
+    user_data.balance_history.balance_at(at)
+}
+
+// The samples between `from` and `to` (exclusive) for charting, reduced to at most
+// `max_points` evenly spaced ones if there are more
+pub fn samples(
+    user_data: &mut UserData,
+    from: u64,
+    to: u64,
+    max_points: usize,
+) -> Vec<BalanceSample> {
+    sync(user_data);
+    let samples = user_data
+        .balance_history
+        .samples
+        .iter()
+        .filter(|sample| (from..to).contains(&sample.at))
+        .copied()
+        .collect::<Vec<_>>();
+    if max_points == 0 || samples.len() <= max_points {
+        return samples;
+    }
+    // The last sample is always included, so that the chart ends with the latest balance
+    let step = samples.len().div_ceil(max_points);
+    let mut reduced = samples
+        .iter()
+        .rev()
+        .step_by(step)
+        .copied()
+        .collect::<Vec<_>>();
+    reduced.reverse();
+    reduced
+}
+
+// The samples of the owner of the API token, see `samples`
+pub fn samples_with_token(
+    users: &mut UserStore,
+    token: &str,
+    from: u64,
+    to: u64,
+    max_points: usize,
+) -> Result<Vec<BalanceSample>, Error> {
+    let user = auth::authorize(users, token, Scope::Read)?;
+    let user_data = users.get_mut(&user).ok_or(BillingError::UnknownUser)?;
+    Ok(samples(user_data, from, to, max_points))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ledger::{EntryKind, LedgerEntry},
+        UserId,
+    };
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn sample(at: u64, cents: i64) -> BalanceSample {
+        BalanceSample {
+            at,
+            balance: MoneyUnit::from_cents(cents),
+        }
+    }
+
+    fn series(samples: Vec<BalanceSample>) -> BalanceSeries {
+        BalanceSeries {
+            samples,
+            synced_entries: 0,
+        }
+    }
+
+    #[test]
+    fn old_samples_are_reduced_to_one_per_bucket() {
+        let config = BalanceHistoryConfig {
+            full_resolution_secs: DAY,
+            bucket_secs: DAY,
+            retention_secs: 10 * DAY,
+        };
+        let now = 20 * DAY;
+        let mut series = series(vec![
+            // Before the retention period
+            sample(DAY, 1),
+            sample(2 * DAY, 2),
+            // Two of the same old bucket
+            sample(12 * DAY, 3),
+            sample(12 * DAY + 1, 4),
+            // Within the full resolution window
+            sample(now - 2, 5),
+            sample(now - 1, 6),
+        ]);
+        compact(&mut series, &config, now);
+        assert_eq!(
+            series.samples(),
+            [
+                sample(10 * DAY, 2),
+                sample(12 * DAY + 1, 4),
+                sample(now - 2, 5),
+                sample(now - 1, 6)
+            ]
+        );
+        assert_eq!(series.balance_at(11 * DAY), Some(MoneyUnit::from_cents(2)));
+        assert_eq!(series.balance_at(9 * DAY), None);
+    }
+
+    #[test]
+    fn samples_follow_the_ledger_and_need_a_token() {
+        let mut user_data = UserData::new(MoneyUnit::from_cents(0));
+        for cents in 1..=5 {
+            let balance = MoneyUnit::from_cents(cents);
+            let kind = EntryKind::ReferralCredit {
+                referred: UserId(1),
+            };
+            let entry = LedgerEntry::new(kind, MoneyUnit::from_cents(1), balance);
+            user_data.ledger.push(entry);
+        }
+        let balances = samples(&mut user_data, 0, u64::MAX, 3)
+            .iter()
+            .map(|sample| sample.balance.minor_units())
+            .collect::<Vec<_>>();
+        // The new account, then the balance after every entry, ending with the latest one
+        assert_eq!(balances, [1, 3, 5]);
+
+        let mut users = UserStore::new();
+        users.insert(UserId(0), user_data);
+        assert!(matches!(
+            samples_with_token(&mut users, "wsm_unknown", 0, u64::MAX, 0),
+            Err(Error::Billing(BillingError::InvalidToken))
+        ));
+    }
+}
@@ -0,0 +1,261 @@
+use crate::{
+    capability::Capability,
+    config::{HostingConfig, RegistrationConfig, TransferFee},
+    ledger::{EntryKind, LedgerEntry},
+    money::MoneyUnit,
+    plan::{GraceConfig, Plan, TrialConfig},
+    store::UserStore,
+    BillingError, Error, UserData, UserId,
+};
+
+// The price of the hosting days, without the late fee
+pub fn hosting_cost(hosting: &HostingConfig, days: i32) -> Result<MoneyUnit, Error> {
+    if days <= 0 {
+        return Err(BillingError::InvalidArgumentValue.into());
+    };
+    Ok((hosting.price_per_day * days).ok_or(BillingError::TotalCostExceededMaxValue)?)
+}
+
+// Orders hosting, ending the grace period or the suspension of the account.
+// Orders placed during the grace period are charged the late fee on top.
+pub fn order_hosting(
+    user_data: &mut UserData,
+    hosting: &HostingConfig,
+    grace: &GraceConfig,
+    days: i32,
+) -> Result<(), Error> {
+    let total_cost = hosting_cost(hosting, days)?;
+    let late_fee = match user_data.plan {
+        Plan::Grace { .. } => grace.late_fee,
+        _ => MoneyUnit::zero(user_data.balance.currency()),
+    };
+    let balance_after_order = (user_data.balance - total_cost)?;
+    // Both charges are checked before any of them is recorded
+    let balance = (balance_after_order - late_fee)?;
+    user_data.balance = balance;
+    user_data.hosting_days_left += days as u32;
+    if matches!(user_data.plan, Plan::Grace { .. } | Plan::Suspended) {
+        user_data.plan = Plan::Paid;
+    }
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::HostingOrder { days: days as u32 },
+        total_cost.checked_neg().unwrap(),
+        balance_after_order,
+    ));
+    if !late_fee.is_zero() {
+        user_data.ledger.push(LedgerEntry::new(
+            EntryKind::LateFee,
+            late_fee.checked_neg().unwrap(),
+            balance,
+        ));
+    }
+    Ok(())
+}
+
+pub fn start_trial(user_data: &mut UserData, trial: &TrialConfig) {
+    if trial.days == 0 {
+        return;
+    }
+    user_data.plan = Plan::Trial {
+        days_left: trial.days,
+    };
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::TrialStarted { days: trial.days },
+        MoneyUnit::zero(user_data.balance.currency()),
+        user_data.balance,
+    ));
+}
+
+pub fn trial_days_left(user_data: &UserData) -> u32 {
+    match user_data.plan {
+        Plan::Trial { days_left } => days_left,
+        Plan::Paid | Plan::Grace { .. } | Plan::Suspended => 0,
+    }
+}
+
+pub fn grace_days_left(user_data: &UserData) -> u32 {
+    match user_data.plan {
+        Plan::Grace { days_left } => days_left,
+        Plan::Trial { .. } | Plan::Paid | Plan::Suspended => 0,
+    }
+}
+
+// Moves `amount` from one account to another, charging the fee to the sender.
+// Either both accounts are updated or neither is.
+pub fn transfer(
+    users: &mut UserStore,
+    fee: TransferFee,
+    from: UserId,
+    to: UserId,
+    amount: MoneyUnit,
+) -> Result<(), Error> {
+    if from == to {
+        return Err(BillingError::SelfTransfer.into());
+    }
+    if amount.is_negative() || amount.is_zero() {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    let sender = users.get(&from).ok_or(BillingError::UnknownUser)?;
+    if !sender.capabilities.contains(&Capability::Transfer) {
+        return Err(BillingError::MissingCapability.into());
+    }
+    // Read one at a time, as both may be in the same shard of the store
+    let sender_balance = sender.balance;
+    drop(sender);
+    let receiver_balance = users.get(&to).ok_or(BillingError::UnknownUser)?.balance;
+
+    let fee = (amount
+        .basis_points(fee.basis_points)
+        .ok_or(BillingError::TotalCostExceededMaxValue)?
+        + fee.flat)
+        .map_err(|_| BillingError::TotalCostExceededMaxValue)?;
+    let total_debit = (amount + fee).map_err(|_| BillingError::TotalCostExceededMaxValue)?;
+    // Both new balances are computed before any of them is written
+    let sender_balance = (sender_balance - total_debit)?;
+    let receiver_balance = (receiver_balance + amount)?;
+
+    let sender = users.get_mut(&from).unwrap();
+    sender.balance = sender_balance;
+    sender.ledger.push(LedgerEntry::new(
+        EntryKind::TransferOut { to },
+        amount.checked_neg().unwrap(),
+        (sender_balance + fee).unwrap(),
+    ));
+    if !fee.is_zero() {
+        sender.ledger.push(LedgerEntry::new(
+            EntryKind::TransferFee,
+            fee.checked_neg().unwrap(),
+            sender_balance,
+        ));
+    }
+
+    let receiver = users.get_mut(&to).unwrap();
+    receiver.balance = receiver_balance;
+    receiver.ledger.push(LedgerEntry::new(
+        EntryKind::TransferIn { from },
+        amount,
+        receiver_balance,
+    ));
+    Ok(())
+}
+
+// Credits the user who referred the account, to be called when the account
+// makes its first purchase.
+pub fn credit_referrer(
+    users: &mut UserStore,
+    registration: &RegistrationConfig,
+    referred: UserId,
+) -> Result<(), Error> {
+    let Some(referrer) = users
+        .get(&referred)
+        .ok_or(BillingError::UnknownUser)?
+        .referred_by
+    else {
+        return Ok(());
+    };
+    let credit = registration.referral_credit;
+    if credit.is_zero() {
+        return Ok(());
+    }
+    let referrer_data = users.get_mut(&referrer).ok_or(BillingError::UnknownUser)?;
+    referrer_data.balance = (referrer_data.balance + credit)?;
+    referrer_data.ledger.push(LedgerEntry::new(
+        EntryKind::ReferralCredit { referred },
+        credit,
+        referrer_data.balance,
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENDER: UserId = UserId(0);
+    const RECEIVER: UserId = UserId(1);
+
+    // The sender has 100.00 and may transfer, the receiver has nothing
+    fn users() -> UserStore {
+        let mut users = UserStore::new();
+        let mut sender = UserData::new(MoneyUnit::from_cents(10_000));
+        sender.capabilities.insert(Capability::Transfer);
+        users.insert(SENDER, sender);
+        users.insert(RECEIVER, UserData::new(MoneyUnit::from_cents(0)));
+        users
+    }
+
+    fn fee() -> TransferFee {
+        TransferFee {
+            flat: MoneyUnit::from_cents(50),
+            // 1%
+            basis_points: 100,
+        }
+    }
+
+    #[test]
+    fn transfer_charges_the_fee_to_the_sender() {
+        let mut users = users();
+        transfer(
+            &mut users,
+            fee(),
+            SENDER,
+            RECEIVER,
+            MoneyUnit::from_cents(1_000),
+        )
+        .unwrap();
+        let sender = users.get(&SENDER).unwrap();
+        // 10.00 and a fee of 0.50 + 0.10
+        assert_eq!(sender.balance, MoneyUnit::from_cents(8_940));
+        let kinds = sender
+            .ledger
+            .iter()
+            .map(|e| e.kind.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                EntryKind::TransferOut { to: RECEIVER },
+                EntryKind::TransferFee
+            ]
+        );
+        drop(sender);
+        let receiver = users.get(&RECEIVER).unwrap();
+        assert_eq!(receiver.balance, MoneyUnit::from_cents(1_000));
+        assert_eq!(
+            receiver.ledger[0].kind,
+            EntryKind::TransferIn { from: SENDER }
+        );
+    }
+
+    #[test]
+    fn failed_transfer_changes_neither_account() {
+        let mut users = users();
+        // The fee does not fit into the balance
+        let all = MoneyUnit::from_cents(10_000);
+        assert!(matches!(
+            transfer(&mut users, fee(), SENDER, RECEIVER, all),
+            Err(Error::Billing(BillingError::BalanceWouldBecomeNegative))
+        ));
+        assert!(matches!(
+            transfer(
+                &mut users,
+                fee(),
+                RECEIVER,
+                SENDER,
+                MoneyUnit::from_cents(1)
+            ),
+            Err(Error::Billing(BillingError::MissingCapability))
+        ));
+        assert!(matches!(
+            transfer(&mut users, fee(), SENDER, SENDER, MoneyUnit::from_cents(1)),
+            Err(Error::Billing(BillingError::SelfTransfer))
+        ));
+        for user in [SENDER, RECEIVER] {
+            assert!(users.get(&user).unwrap().ledger.is_empty());
+        }
+        assert_eq!(
+            users.get(&SENDER).unwrap().balance,
+            MoneyUnit::from_cents(10_000)
+        );
+    }
+}
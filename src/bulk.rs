@@ -0,0 +1,439 @@
+// Bulk import and export of accounts, e.g. to migrate from another billing system with
+// `users import <file>` and `users export`. A `UserRecord` carries the balance, the plan, the
+// hosting days, the group and the free-form metadata of an account. In CSV, the columns are
+// those of `CSV_HEADER` and the metadata is written as `key=value` pairs separated by `;`.
+//
+// An import applies all its rows or none: every row is validated first, and any invalid
+// row fails the whole import with the errors of all the invalid rows. Existing accounts are
+// updated, the change of their balance recorded as an `EntryKind::Import` entry.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    features,
+    ledger::{EntryKind, ExportFormat, LedgerEntry},
+    money::{Currency, MoneyUnit},
+    plan::Plan,
+    BillingError, Error, HostError, State, UserData, UserId,
+};
+
+pub const CSV_HEADER: &str =
+    "id,balance,currency,plan,plan_days_left,hosting_days_left,group,metadata";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub id: usize,
+    // A decimal in the major units of the currency, e.g. `12.50`
+    pub balance: String,
+    pub currency: String,
+    // `trial`, `paid`, `grace` or `suspended`
+    pub plan: String,
+    // The days left of the trial or of the grace period, 0 on the other plans
+    #[serde(default)]
+    pub plan_days_left: u32,
+    #[serde(default)]
+    pub hosting_days_left: u32,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+// The record of a row that failed to parse or to validate, numbered from 1
+#[derive(Debug)]
+pub struct RowError {
+    pub row: usize,
+    pub error: Error,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub created: usize,
+    pub updated: usize,
+    // Nothing has been imported if any row failed
+    pub errors: Vec<RowError>,
+}
+
+fn persistence_error(e: impl ToString) -> Error {
+    HostError::Persistence(e.to_string()).into()
+}
+
+fn invalid() -> Error {
+    BillingError::InvalidArgumentValue.into()
+}
+
+impl UserRecord {
+    pub fn new(user: UserId, user_data: &UserData) -> Self {
+        let plan_days_left = match user_data.plan {
+            Plan::Trial { days_left } | Plan::Grace { days_left } => days_left,
+            Plan::Paid | Plan::Suspended => 0,
+        };
+        Self {
+            id: user.0,
+            balance: user_data.balance.to_decimal_string(),
+            currency: user_data.balance.currency().code().to_owned(),
+            plan: features::plan_name(user_data.plan).to_owned(),
+            plan_days_left,
+            hosting_days_left: user_data.hosting_days_left,
+            group: user_data.group.clone(),
+            metadata: user_data.metadata.clone(),
+        }
+    }
+
+    fn parse_plan(&self) -> Result<Plan, Error> {
+        let days_left = self.plan_days_left;
+        match self.plan.as_str() {
+            "trial" => Ok(Plan::Trial { days_left }),
+            "paid" => Ok(Plan::Paid),
+            "grace" => Ok(Plan::Grace { days_left }),
+            "suspended" => Ok(Plan::Suspended),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn parse_balance(&self) -> Result<MoneyUnit, Error> {
+        let currency = Currency::from_code(&self.currency).ok_or_else(invalid)?;
+        MoneyUnit::parse(&self.balance, currency)
+    }
+
+    fn to_csv(&self) -> String {
+        let metadata = self
+            .metadata
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join(";");
+        [
+            self.id.to_string(),
+            self.balance.clone(),
+            self.currency.clone(),
+            self.plan.clone(),
+            self.plan_days_left.to_string(),
+            self.hosting_days_left.to_string(),
+            csv_field(self.group.as_deref().unwrap_or_default()),
+            csv_field(&metadata),
+        ]
+        .join(",")
+    }
+
+    fn from_csv(fields: &[String]) -> Result<Self, Error> {
+        let [id, balance, currency, plan, plan_days_left, hosting_days_left, group, metadata] =
+            fields
+        else {
+            return Err(invalid());
+        };
+        let number = |field: &str| field.trim().parse::<u32>().map_err(|_| invalid());
+        let metadata = metadata
+            .split(';')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').ok_or_else(invalid)?;
+                Ok((key.to_owned(), value.to_owned()))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self {
+            id: id.trim().parse().map_err(|_| invalid())?,
+            balance: balance.trim().to_owned(),
+            currency: currency.trim().to_owned(),
+            plan: plan.trim().to_owned(),
+            plan_days_left: number(plan_days_left)?,
+            hosting_days_left: number(hosting_days_left)?,
+            group: Some(group.clone()).filter(|group| !group.is_empty()),
+            metadata,
+        })
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+// Splits a line into its fields, unquoting the quoted ones. Fields span a single line.
+pub(crate) fn split_csv_line(line: &str) -> Result<Vec<String>, Error> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(invalid());
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+// The records of the rows following the header, each failing on its own
+pub fn parse_csv(text: &str) -> Result<Vec<Result<UserRecord, Error>>, Error> {
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    if lines.next().map(str::trim) != Some(CSV_HEADER) {
+        return Err(persistence_error(format!(
+            "the CSV header must be `{CSV_HEADER}`"
+        )));
+    }
+    Ok(lines
+        .map(|line| UserRecord::from_csv(&split_csv_line(line)?))
+        .collect())
+}
+
+// The records of a JSON array, each failing on its own
+pub fn parse_json(text: &str) -> Result<Vec<Result<UserRecord, Error>>, Error> {
+    let values = serde_json::from_str::<Vec<serde_json::Value>>(text).map_err(persistence_error)?;
+    Ok(values
+        .into_iter()
+        .map(|value| serde_json::from_value(value).map_err(|_| invalid()))
+        .collect())
+}
+
+pub fn parse(text: &str, format: ExportFormat) -> Result<Vec<Result<UserRecord, Error>>, Error> {
+    match format {
+        ExportFormat::Csv => parse_csv(text),
+        ExportFormat::Json => parse_json(text),
+    }
+}
+
+// An account as it will be imported
+struct Validated {
+    user: UserId,
+    balance: MoneyUnit,
+    plan: Plan,
+    record: UserRecord,
+}
+
+fn validate(state: &State, record: UserRecord) -> Result<Validated, Error> {
+    let balance = record.parse_balance()?;
+    let plan = record.parse_plan()?;
+    let user = UserId(record.id);
+    if let Some(existing) = state.users.get(&user) {
+        // The ledger of an account stays in a single currency
+        if existing.balance.currency() != balance.currency() {
+            return Err(invalid());
+        }
+    }
+    if let Some(group) = &record.group {
+        if !state.config.groups.contains_key(group) {
+            return Err(BillingError::UnknownGroup.into());
+        }
+    }
+    Ok(Validated {
+        user,
+        balance,
+        plan,
+        record,
+    })
+}
+
+// The rows of the groups that would end up with more members than they allow
+fn check_group_capacity(state: &State, rows: &[(usize, Validated)]) -> Vec<RowError> {
+    let imported = rows
+        .iter()
+        .map(|(_, row)| row.user)
+        .collect::<BTreeSet<_>>();
+    let users = state.users.read();
+    let mut members = BTreeMap::<&str, u32>::new();
+    for (user, user_data) in users.iter() {
+        if let Some(group) = user_data
+            .group
+            .as_deref()
+            .filter(|_| !imported.contains(user))
+        {
+            *members.entry(group).or_default() += 1;
+        }
+    }
+    let mut errors = Vec::new();
+    for (row, validated) in rows {
+        let Some(group) = validated.record.group.as_deref() else {
+            continue;
+        };
+        let count = members.entry(group).or_default();
+        *count += 1;
+        let max_members = state.config.groups[group].max_members;
+        if max_members.is_some_and(|max_members| *count > max_members) {
+            errors.push(RowError {
+                row: *row,
+                error: BillingError::GroupFull.into(),
+            });
+        }
+    }
+    errors
+}
+
+fn apply(state: &mut State, validated: Validated, report: &mut ImportReport) {
+    let Validated {
+        user,
+        balance,
+        plan,
+        record,
+    } = validated;
+    let user_data = match state.users.get_mut(&user) {
+        Some(user_data) => {
+            report.updated += 1;
+            // Cannot fail, both balances are within the range of the minor units
+            let change = balance.sub_allowing_negative(user_data.balance).unwrap();
+            if !change.is_zero() {
+                user_data
+                    .ledger
+                    .push(LedgerEntry::new(EntryKind::Import, change, balance));
+            }
+            user_data.balance = balance;
+            user_data
+        }
+        None => {
+            report.created += 1;
+            state.users.insert(user, UserData::new(balance));
+            state.users.get_mut(&user).unwrap()
+        }
+    };
+    user_data.plan = plan;
+    user_data.hosting_days_left = record.hosting_days_left;
+    user_data.group = record.group;
+    user_data.metadata = record.metadata;
+}
+
+// Creates or updates the accounts of the rows, all or none of them
+pub fn import(
+    state: &mut State,
+    rows: impl IntoIterator<Item = Result<UserRecord, Error>>,
+) -> ImportReport {
+    let mut report = ImportReport::default();
+    let mut seen = BTreeSet::new();
+    let mut valid = Vec::new();
+    for (i, row) in rows.into_iter().enumerate() {
+        let row_number = i + 1;
+        let validated = row.and_then(|record| {
+            if !seen.insert(record.id) {
+                return Err(BillingError::UserAlreadyExists.into());
+            }
+            validate(state, record)
+        });
+        match validated {
+            Ok(validated) => valid.push((row_number, validated)),
+            Err(error) => report.errors.push(RowError {
+                row: row_number,
+                error,
+            }),
+        }
+    }
+    report.errors.extend(check_group_capacity(state, &valid));
+    if !report.errors.is_empty() {
+        report.errors.sort_by_key(|error| error.row);
+        return report;
+    }
+    for (_, validated) in valid {
+        apply(state, validated, &mut report);
+    }
+    report
+}
+
+// The records of all the accounts, by id
+pub fn records(state: &State) -> Vec<UserRecord> {
+    let mut records = state
+        .users
+        .read()
+        .iter()
+        .map(|(&user, user_data)| UserRecord::new(user, user_data))
+        .collect::<Vec<_>>();
+    records.sort_by_key(|record| record.id);
+    records
+}
+
+pub fn export(
+    records: &[UserRecord],
+    format: ExportFormat,
+    mut out: impl Write,
+) -> Result<(), Error> {
+    match format {
+        ExportFormat::Csv => {
+            writeln!(out, "{CSV_HEADER}").map_err(persistence_error)?;
+            for record in records {
+                writeln!(out, "{}", record.to_csv()).map_err(persistence_error)?;
+            }
+            Ok(())
+        }
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, records).map_err(persistence_error)?;
+            writeln!(out).map_err(persistence_error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::UserStore;
+
+    const CSV: &str = "\
+id,balance,currency,plan,plan_days_left,hosting_days_left,group,metadata
+0,12.50,USD,paid,0,30,,\"note=a, b;tier=gold\"
+1,0.00,USD,trial,7,0,,
+";
+
+    // A state with the user 0 holding 10.00
+    fn state() -> State {
+        let mut users = UserStore::new();
+        users.insert(UserId(0), UserData::new(MoneyUnit::from_cents(1_000)));
+        State::new(users)
+    }
+
+    #[test]
+    fn imported_accounts_are_exported_unchanged() {
+        let mut state = state();
+        let report = import(&mut state, parse_csv(CSV).unwrap());
+        assert!(report.errors.is_empty());
+        assert_eq!((report.created, report.updated), (1, 1));
+        let user_data = state.users.get(&UserId(0)).unwrap();
+        assert_eq!(user_data.balance, MoneyUnit::from_cents(1_250));
+        assert_eq!(user_data.ledger[0].kind, EntryKind::Import);
+        assert_eq!(user_data.metadata["note"], "a, b");
+        drop(user_data);
+        assert_eq!(
+            state.users.get(&UserId(1)).unwrap().plan,
+            Plan::Trial { days_left: 7 }
+        );
+
+        let mut csv = Vec::new();
+        export(&records(&state), ExportFormat::Csv, &mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), CSV);
+    }
+
+    #[test]
+    fn invalid_rows_fail_the_whole_import() {
+        let mut state = state();
+        let csv =
+            format!("{CSV}2,1.00,XYZ,paid,0,0,,\n1,1.00,USD,paid,0,0,,\n3,1.00,USD,gold,0,0,,\n");
+        let report = import(&mut state, parse_csv(&csv).unwrap());
+        let rows = report.errors.iter().map(|e| e.row).collect::<Vec<_>>();
+        assert_eq!(rows, [3, 4, 5]);
+        assert!(matches!(
+            report.errors[1].error,
+            Error::Billing(BillingError::UserAlreadyExists)
+        ));
+        assert_eq!(report.created + report.updated, 0);
+        assert!(!state.users.contains(&UserId(1)));
+        assert!(state.users.get(&UserId(0)).unwrap().ledger.is_empty());
+
+        assert!(matches!(
+            parse_csv("id,balance\n"),
+            Err(Error::Host(HostError::Persistence(_)))
+        ));
+    }
+}
@@ -0,0 +1,36 @@
+// Burst credits smooth spiky workloads under `ComputeConfig::fuel_per_call`. An invocation that
+// consumes less fuel than the limit saves the rest as credits of its user, up to
+// `ComputeConfig::max_burst_credits`, and a later invocation may consume as much as the limit
+// and all the saved credits, drawing the fuel it consumes beyond the limit from the credits.
+// No invocation ever gets more than the limit and the cap, however long its user saved up.
+
+use crate::{State, UserId};
+
+// The fuel the next invocation of the user may consume, `None` if it is unlimited
+pub fn budget(state: &State, user: UserId) -> Option<u64> {
+    let limit = state.config.compute.fuel_per_call?;
+    let credits = state
+        .users
+        .get(&user)
+        .map_or(0, |user_data| user_data.burst_credits);
+    Some(limit.saturating_add(credits))
+}
+
+// Saves the fuel the invocation of the user left unused as credits or draws the fuel it
+// consumed beyond the limit from them
+pub(crate) fn settle(state: &mut State, user: UserId, consumed: u64) {
+    let config = state.config.compute;
+    let Some(limit) = config.fuel_per_call else {
+        return;
+    };
+    let Some(user_data) = state.users.get_mut(&user) else {
+        return;
+    };
+    let credits = user_data.burst_credits;
+    user_data.burst_credits = match consumed.checked_sub(limit) {
+        Some(excess) => credits.saturating_sub(excess),
+        None => credits
+            .saturating_add(limit - consumed)
+            .min(config.max_burst_credits),
+    };
+}
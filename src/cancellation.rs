@@ -0,0 +1,313 @@
+// Cancellation of orders by their users, see `host.cancel_service`. Cancelling a hosting
+// order takes its unused days off the account, cancelling a bundle order deprovisions the
+// services of the bundle. The unused part of the price, i.e. the share of the unused days of a
+// hosting order or of the rest of `CancellationConfig::bundle_term_secs` since a bundle was
+// ordered, is refunded according to the `RefundPolicy` of the user's plan and recorded as an
+// `EntryKind::CancellationRefund` entry, even when nothing is refunded. The price is the one
+// paid, net of the discount of the user's group credited on the order, see `groups`. The data
+// of the deprovisioned services is retained for a while, see `retention`. Other purchases,
+// disputed orders and cancelled ones cannot be cancelled.
+
+use crate::{
+    groups,
+    ledger::{self, EntryKind, LedgerEntry},
+    money::MoneyUnit,
+    orders::{self, Order, OrderId, OrderStatus},
+    plan::Plan,
+    policy::{self, Action},
+    retention, BillingError, Error, State, UserData, UserId,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RefundPolicy {
+    // The whole unused part of the price
+    #[default]
+    Full,
+    // A share of the unused part of the price
+    Partial {
+        basis_points: u32,
+    },
+    None,
+}
+
+impl RefundPolicy {
+    fn apply(self, unused: MoneyUnit) -> Option<MoneyUnit> {
+        match self {
+            RefundPolicy::Full => Some(unused),
+            RefundPolicy::Partial { basis_points } => unused.basis_points(basis_points),
+            RefundPolicy::None => Some(MoneyUnit::zero(unused.currency())),
+        }
+    }
+}
+
+// The refund policies of the cancellations by the plan of the user
+#[derive(Clone, Copy, Debug)]
+pub struct CancellationConfig {
+    pub trial: RefundPolicy,
+    pub paid: RefundPolicy,
+    pub grace: RefundPolicy,
+    pub suspended: RefundPolicy,
+    // The period the price of a bundle pays for, over which its refund is pro-rated. Nothing is
+    // refunded for the bundles cancelled after it.
+    pub bundle_term_secs: u64,
+}
+
+impl Default for CancellationConfig {
+    fn default() -> Self {
+        Self {
+            trial: RefundPolicy::default(),
+            paid: RefundPolicy::default(),
+            grace: RefundPolicy::default(),
+            suspended: RefundPolicy::default(),
+            bundle_term_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl CancellationConfig {
+    pub fn policy(&self, plan: Plan) -> RefundPolicy {
+        match plan {
+            Plan::Trial { .. } => self.trial,
+            Plan::Paid => self.paid,
+            Plan::Grace { .. } => self.grace,
+            Plan::Suspended => self.suspended,
+        }
+    }
+}
+
+// What the cancellation of an order stops
+enum Cancelled {
+    HostingDays(u32),
+    Services(Vec<String>),
+}
+
+// The hosting days are used up oldest order first, so the days of the later orders that are
+// still in effect are the last to be used
+fn unused_hosting_days(
+    state: &State,
+    user: UserId,
+    user_data: &UserData,
+    entry: usize,
+    days: u32,
+) -> u32 {
+    let later_days = state
+        .orders
+        .of_user(user)
+        .filter(|order| order.entry > entry && order.cancelled_at.is_none())
+        .filter_map(|order| match user_data.ledger_entry(order.entry) {
+            Ok(LedgerEntry {
+                kind: EntryKind::HostingOrder { days },
+                ..
+            }) => Some(*days),
+            _ => None,
+        })
+        .sum::<u32>();
+    days.min(user_data.hosting_days_left.saturating_sub(later_days))
+}
+
+// The discount of the user's group credited on the purchase at the index, i.e. by the first
+// discount after it if that one covers it, see `groups::apply_discount`
+fn discount_on(user_data: &UserData, entry: usize, price: MoneyUnit) -> Option<MoneyUnit> {
+    let zero = MoneyUnit::zero(price.currency());
+    let purchase = user_data.ledger_entry(entry).ok()?;
+    if !groups::is_discounted(purchase) {
+        return Some(zero);
+    }
+    let after = entry + 1 - user_data.archived_entries;
+    let discount = user_data.ledger[after..]
+        .iter()
+        .find_map(|later| match later.kind {
+            EntryKind::GroupDiscount {
+                since,
+                basis_points,
+                ..
+            } => Some((since <= entry).then_some(basis_points)),
+            _ => None,
+        })
+        .flatten();
+    match discount {
+        Some(basis_points) => price.basis_points(basis_points),
+        None => Some(zero),
+    }
+}
+
+// The share of the price for the part of the period that is left
+fn pro_rated(price: MoneyUnit, unused: u64, period: u64) -> MoneyUnit {
+    let minor = price.minor_units() as i128 * unused.min(period) as i128 / period.max(1) as i128;
+    MoneyUnit::from_minor_units(minor as i64, price.currency())
+}
+
+// The services the cancellation deprovisions and the unused part of the price
+fn quote(state: &State, user: UserId, order: &Order) -> Result<(Cancelled, MoneyUnit), Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let entry = order.entry;
+    let purchase = user_data.ledger_entry(entry)?;
+    let gross = purchase
+        .amount
+        .checked_neg()
+        .ok_or(BillingError::TotalCostExceededMaxValue)?;
+    let discount =
+        discount_on(&user_data, entry, gross).ok_or(BillingError::TotalCostExceededMaxValue)?;
+    let price = (gross - discount)?;
+    match &purchase.kind {
+        EntryKind::HostingOrder { days } if *days > 0 => {
+            let unused = unused_hosting_days(state, user, &user_data, entry, *days);
+            let unused_price = pro_rated(price, unused.into(), (*days).into());
+            Ok((Cancelled::HostingDays(unused), unused_price))
+        }
+        EntryKind::BundleOrder { .. } => {
+            let members = orders::services(state, order)?;
+            // The services ordered since may not lose their dependencies
+            let depended_on = user_data
+                .services
+                .iter()
+                .filter(|service| !members.contains(service))
+                .filter_map(|service| state.config.catalog.services.get(service))
+                .any(|service| service.depends_on.iter().any(|d| members.contains(d)));
+            if depended_on {
+                return Err(BillingError::NotCancellable.into());
+            }
+            let term = state.config.cancellation.bundle_term_secs;
+            let used = ledger::now_secs().saturating_sub(purchase.at);
+            let unused_price = pro_rated(price, term.saturating_sub(used), term);
+            Ok((Cancelled::Services(members), unused_price))
+        }
+        _ => Err(BillingError::NotCancellable.into()),
+    }
+}
+
+// Cancels an order of the user, returning the refund. The services of a bundle are
+// deprovisioned in the reverse of their provisioning order.
+pub fn cancel(state: &mut State, user: UserId, id: OrderId) -> Result<MoneyUnit, Error> {
+    let order = orders::get(state, user, id)?.clone();
+    if orders::status(state, &order) != OrderStatus::Completed {
+        return Err(BillingError::NotCancellable.into());
+    }
+    let (cancelled, unused_price) = quote(state, user, &order)?;
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let refund = state
+        .config
+        .cancellation
+        .policy(user_data.plan)
+        .apply(unused_price)
+        .ok_or(BillingError::TotalCostExceededMaxValue)?;
+    let balance = (user_data.balance + refund)?;
+    drop(user_data);
+    if !refund.is_zero() {
+        policy::authorize(state, user, Action::Refund, refund)?;
+    }
+
+    let user_data = state.users.get_mut(&user).unwrap();
+    let deprovisioned = match cancelled {
+        Cancelled::HostingDays(days) => {
+            user_data.hosting_days_left -= days;
+            Vec::new()
+        }
+        Cancelled::Services(services) => {
+            for service in services.iter().rev() {
+                user_data.services.remove(service);
+                user_data.service_uptime.remove(service);
+                state.provisioner.deprovision(user, service);
+            }
+            services
+        }
+    };
+    user_data.balance = balance;
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::CancellationRefund { order: id.0 },
+        refund,
+        balance,
+    ));
+    state.orders.cancel(id, ledger::now_secs());
+    retention::retain(state, user, id, &deprovisioned);
+    Ok(refund)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        groups::GroupConfig,
+        host,
+        services::{Bundle, Service},
+        store::UserStore,
+    };
+
+    const USER: UserId = UserId(0);
+    const DAY_SECS: u64 = 24 * 60 * 60;
+
+    // A user with 1000.00 and a bundle of a single service at 100.00
+    fn state() -> State {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(100_000)));
+        let mut state = State::new(users);
+        let catalog = &mut state.config.catalog;
+        let service = Service {
+            price: MoneyUnit::from_cents(10_000),
+            depends_on: Vec::new(),
+            sla: None,
+        };
+        catalog.services.insert("web".to_owned(), service);
+        let bundle = Bundle {
+            services: vec!["web".to_owned()],
+        };
+        catalog.bundles.insert("starter".to_owned(), bundle);
+        state
+    }
+
+    fn order_bundle(state: &mut State) -> OrderId {
+        host::record_charges(state, USER, |state| {
+            host::order_bundle(state, USER, "starter")
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn balance(state: &State) -> MoneyUnit {
+        state.users.get(&USER).unwrap().balance
+    }
+
+    #[test]
+    fn refund_is_net_of_the_group_discount() {
+        let mut state = state();
+        let group = GroupConfig {
+            discount_basis_points: 2_000,
+            ..GroupConfig::default()
+        };
+        state.config.groups.insert("edu".to_owned(), group);
+        groups::assign(&mut state, USER, Some("edu")).unwrap();
+        let order = order_bundle(&mut state);
+        assert_eq!(balance(&state), MoneyUnit::from_cents(92_000));
+
+        let refund = cancel(&mut state, USER, order).unwrap();
+        assert_eq!(refund, MoneyUnit::from_cents(8_000));
+        assert_eq!(balance(&state), MoneyUnit::from_cents(100_000));
+    }
+
+    #[test]
+    fn bundle_refund_is_pro_rated_over_its_term() {
+        let mut state = state();
+        let order = order_bundle(&mut state);
+        let term = state.config.cancellation.bundle_term_secs;
+        let user_data = state.users.get_mut(&USER).unwrap();
+        user_data.ledger.last_mut().unwrap().at -= term / 2;
+
+        let refund = cancel(&mut state, USER, order).unwrap();
+        // The clock may have ticked since the order
+        assert!((4_990..=5_000).contains(&refund.minor_units()), "{refund}");
+    }
+
+    #[test]
+    fn bundle_cancelled_after_its_term_is_not_refunded() {
+        let mut state = state();
+        let order = order_bundle(&mut state);
+        let user_data = state.users.get_mut(&USER).unwrap();
+        user_data.ledger.last_mut().unwrap().at -= 31 * DAY_SECS;
+
+        let refund = cancel(&mut state, USER, order).unwrap();
+        assert!(refund.is_zero());
+        assert_eq!(balance(&state), MoneyUnit::from_cents(90_000));
+        assert!(state.users.get(&USER).unwrap().services.is_empty());
+    }
+}
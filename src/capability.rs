@@ -0,0 +1,13 @@
+// Capabilities gate the host functions that are not available to every user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    Transfer,
+    // Allows managing other accounts, e.g. registering new users
+    Admin,
+    // Allows the guests to read the secrets of the user
+    Secrets,
+    // Allows the guests to rotate the API tokens of the user
+    ManageKeys,
+    // Allows creating and funding sub-accounts, see `resellers`
+    Reseller,
+}
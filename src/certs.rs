@@ -0,0 +1,303 @@
+use crate::{
+    domains::{self, Whois},
+    ledger::{self, EntryKind, LedgerEntry},
+    notifications::{self, NotificationEvent},
+    BillingError, Error, HostError, State, UserId,
+};
+
+// Days before the expiry at which the owners are reminded to renew their certificates
+pub const REMINDER_DAYS: [u32; 3] = [30, 7, 1];
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IssuedCertificate {
+    // The certificate chain, leaf first
+    pub certificate_pem: String,
+    pub private_key_pem: String,
+    pub valid_days: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Certificate {
+    pub domain: String,
+    // Seconds since the Unix epoch
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub days_left: u32,
+    pub certificate_pem: String,
+    pub private_key_pem: String,
+}
+
+// Issues the certificates for the domains that have already been checked to belong to the user
+pub trait CertificateIssuer {
+    fn issue(&mut self, domain: &str) -> Result<IssuedCertificate, Error>;
+}
+
+fn certificate_error(e: impl ToString) -> Error {
+    HostError::Certificate(e.to_string()).into()
+}
+
+// Issues certificates signed by their own keys, e.g. for development or internal services
+pub struct SelfSignedIssuer {
+    pub valid_days: u32,
+}
+
+impl Default for SelfSignedIssuer {
+    fn default() -> Self {
+        Self { valid_days: 90 }
+    }
+}
+
+impl CertificateIssuer for SelfSignedIssuer {
+    fn issue(&mut self, domain: &str) -> Result<IssuedCertificate, Error> {
+        let mut params =
+            rcgen::CertificateParams::new(vec![domain.to_owned()]).map_err(certificate_error)?;
+        params.not_before = time::OffsetDateTime::now_utc();
+        params.not_after = params.not_before + time::Duration::days(self.valid_days as i64);
+        let key_pair = rcgen::KeyPair::generate().map_err(certificate_error)?;
+        let certificate = params.self_signed(&key_pair).map_err(certificate_error)?;
+        Ok(IssuedCertificate {
+            certificate_pem: certificate.pem(),
+            private_key_pem: key_pair.serialize_pem(),
+            valid_days: self.valid_days,
+        })
+    }
+}
+
+// Serves the HTTP-01 challenges of the ACME server, i.e. makes the key authorization available
+// at `http://<domain>/.well-known/acme-challenge/<token>` while the certificate is issued.
+#[cfg(feature = "acme")]
+pub trait Http01Responder {
+    fn publish(&mut self, domain: &str, token: &str, key_authorization: &str);
+    fn unpublish(&mut self, domain: &str, token: &str);
+}
+
+// Obtains certificates from an ACME certificate authority such as Let's Encrypt
+#[cfg(feature = "acme")]
+pub struct AcmeIssuer {
+    runtime: tokio::runtime::Runtime,
+    account: instant_acme::Account,
+    responder: Box<dyn Http01Responder>,
+    // The validity of the certificates issued by the authority
+    pub valid_days: u32,
+}
+
+#[cfg(feature = "acme")]
+impl AcmeIssuer {
+    // Registers a new account at the directory, e.g. `instant_acme::LetsEncrypt::Production.url()`
+    pub fn new(directory_url: &str, responder: Box<dyn Http01Responder>) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(certificate_error)?;
+        let (account, _) = runtime
+            .block_on(instant_acme::Account::create(
+                &instant_acme::NewAccount {
+                    contact: &[],
+                    terms_of_service_agreed: true,
+                    only_return_existing: false,
+                },
+                directory_url,
+                None,
+            ))
+            .map_err(certificate_error)?;
+        Ok(Self {
+            runtime,
+            account,
+            responder,
+            valid_days: 90,
+        })
+    }
+
+    async fn order(
+        account: &instant_acme::Account,
+        responder: &mut dyn Http01Responder,
+        domain: &str,
+    ) -> Result<(String, String), Error> {
+        use instant_acme::{AuthorizationStatus, ChallengeType, Identifier, NewOrder, OrderStatus};
+
+        const MAX_POLLS: u32 = 10;
+
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &[Identifier::Dns(domain.to_owned())],
+            })
+            .await
+            .map_err(certificate_error)?;
+        let authorizations = order.authorizations().await.map_err(certificate_error)?;
+        let mut challenges = Vec::new();
+        for authorization in authorizations
+            .iter()
+            .filter(|a| a.status == AuthorizationStatus::Pending)
+        {
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|c| c.r#type == ChallengeType::Http01)
+                .ok_or_else(|| HostError::Certificate("no HTTP-01 challenge offered".to_owned()))?;
+            let key_authorization = order.key_authorization(challenge);
+            responder.publish(domain, &challenge.token, key_authorization.as_str());
+            challenges.push((challenge.token.clone(), challenge.url.clone()));
+        }
+        for (_, url) in &challenges {
+            order
+                .set_challenge_ready(url)
+                .await
+                .map_err(certificate_error)?;
+        }
+
+        let mut delay = std::time::Duration::from_millis(250);
+        let mut status = order.state().status;
+        for _ in 0..MAX_POLLS {
+            if matches!(status, OrderStatus::Ready | OrderStatus::Invalid) {
+                break;
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            status = order.refresh().await.map_err(certificate_error)?.status;
+        }
+        for (token, _) in &challenges {
+            responder.unpublish(domain, token);
+        }
+        if status != OrderStatus::Ready {
+            return Err(HostError::Certificate(format!("the order is {status:?}")).into());
+        }
+
+        let params =
+            rcgen::CertificateParams::new(vec![domain.to_owned()]).map_err(certificate_error)?;
+        let key_pair = rcgen::KeyPair::generate().map_err(certificate_error)?;
+        let csr = params
+            .serialize_request(&key_pair)
+            .map_err(certificate_error)?;
+        order.finalize(csr.der()).await.map_err(certificate_error)?;
+        for _ in 0..MAX_POLLS {
+            if let Some(chain) = order.certificate().await.map_err(certificate_error)? {
+                return Ok((chain, key_pair.serialize_pem()));
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        Err(HostError::Certificate("the certificate was not issued in time".to_owned()).into())
+    }
+}
+
+#[cfg(feature = "acme")]
+impl CertificateIssuer for AcmeIssuer {
+    fn issue(&mut self, domain: &str) -> Result<IssuedCertificate, Error> {
+        let (certificate_pem, private_key_pem) =
+            self.runtime
+                .block_on(Self::order(&self.account, self.responder.as_mut(), domain))?;
+        Ok(IssuedCertificate {
+            certificate_pem,
+            private_key_pem,
+            valid_days: self.valid_days,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertificateEvent {
+    RenewalReminder {
+        user: UserId,
+        domain: String,
+        days_left: u32,
+    },
+    Expired {
+        user: UserId,
+        domain: String,
+    },
+}
+
+// Notified about the certificates approaching their expiry, e.g. to email the owners
+pub trait CertificateListener {
+    fn on_event(&mut self, event: &CertificateEvent);
+}
+
+pub struct NoopCertificateListener;
+
+impl CertificateListener for NoopCertificateListener {
+    fn on_event(&mut self, _event: &CertificateEvent) {}
+}
+
+// Issues a certificate for a domain registered by the user, replacing the previous
+// certificate of the domain if there is one.
+pub fn issue(state: &mut State, user: UserId, domain: &str) -> Result<(), Error> {
+    let price = state.config.certificates.price_per_issuance;
+    let domain = domains::normalize(domain)?;
+    match domains::whois(&state.users, &domain)? {
+        Whois::Registered(record) if record.owner == user => {}
+        _ => return Err(BillingError::DomainNotOwned.into()),
+    }
+    let issuer = state
+        .certificate_issuer
+        .as_mut()
+        .ok_or(BillingError::CertificatesUnavailable)?;
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    let balance = (user_data.balance - price)?;
+
+    let issued = issuer.issue(&domain)?;
+    user_data.balance = balance;
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::CertificateIssued {
+            domain: domain.clone(),
+        },
+        price.checked_neg().unwrap(),
+        balance,
+    ));
+    let issued_at = ledger::now_secs();
+    user_data.certificates.insert(
+        domain.clone(),
+        Certificate {
+            domain,
+            issued_at,
+            expires_at: issued_at + issued.valid_days as u64 * SECS_PER_DAY,
+            days_left: issued.valid_days,
+            certificate_pem: issued.certificate_pem,
+            private_key_pem: issued.private_key_pem,
+        },
+    );
+    Ok(())
+}
+
+// Counts down the validity of the certificates, reminding the owners of the upcoming
+// expiry and dropping the expired certificates.
+pub(crate) fn advance_day(state: &mut State) {
+    let mut events = Vec::new();
+    for (&user, user_data) in state.users.iter_mut() {
+        user_data.certificates.retain(|domain, certificate| {
+            certificate.days_left = certificate.days_left.saturating_sub(1);
+            let domain = domain.clone();
+            if certificate.days_left == 0 {
+                events.push(CertificateEvent::Expired { user, domain });
+                return false;
+            }
+            if REMINDER_DAYS.contains(&certificate.days_left) {
+                events.push(CertificateEvent::RenewalReminder {
+                    user,
+                    domain,
+                    days_left: certificate.days_left,
+                });
+            }
+            true
+        });
+    }
+    for event in &events {
+        state.certificate_listener.on_event(event);
+        let (user, message) = match event {
+            CertificateEvent::RenewalReminder {
+                user,
+                domain,
+                days_left,
+            } => (
+                *user,
+                format!("The certificate of {domain} expires in {days_left} days."),
+            ),
+            CertificateEvent::Expired { user, domain } => {
+                (*user, format!("The certificate of {domain} has expired."))
+            }
+        };
+        notifications::notify(state, user, NotificationEvent::Expiry, message);
+    }
+}
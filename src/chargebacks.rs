@@ -0,0 +1,122 @@
+use crate::{
+    ledger::{EntryKind, LedgerEntry},
+    money::MoneyUnit,
+    plan::Plan,
+    BillingError, Error, State, UserId,
+};
+
+// Records a payment of the user that has been reversed externally, e.g. by the card issuer.
+// The amount is debited even if the balance becomes negative, and the account is suspended
+// if requested. Returns the new balance.
+pub fn record_chargeback(
+    state: &mut State,
+    user: UserId,
+    amount: MoneyUnit,
+    reference: impl Into<String>,
+    suspend: bool,
+) -> Result<MoneyUnit, Error> {
+    if amount.is_negative() || amount.is_zero() {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    let balance = user_data.balance.sub_allowing_negative(amount)?;
+    user_data.balance = balance;
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::Chargeback {
+            reference: reference.into(),
+        },
+        amount.checked_neg().unwrap(),
+        balance,
+    ));
+    if suspend {
+        user_data.plan = Plan::Suspended;
+    }
+    Ok(balance)
+}
+
+// Writes off the negative balance of the user as unrecoverable, bringing it back to zero.
+// Returns the written off amount.
+pub fn write_off(state: &mut State, user: UserId) -> Result<MoneyUnit, Error> {
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    if !user_data.balance.is_negative() {
+        return Err(BillingError::NothingToWriteOff.into());
+    }
+    let amount = user_data
+        .balance
+        .checked_neg()
+        .ok_or(BillingError::BalanceWouldOverflow)?;
+    user_data.balance = MoneyUnit::zero(amount.currency());
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::WriteOff,
+        amount,
+        user_data.balance,
+    ));
+    Ok(amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{store::UserStore, UserData};
+
+    const USER: UserId = UserId(0);
+
+    fn state(cents: i64) -> State {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(cents)));
+        State::new(users)
+    }
+
+    #[test]
+    fn chargeback_below_zero_is_written_off() {
+        let mut state = state(1_000);
+        let amount = MoneyUnit::from_cents(1_500);
+        let balance = record_chargeback(&mut state, USER, amount, "cb-1", true).unwrap();
+        assert_eq!(balance, MoneyUnit::from_cents(-500));
+        assert_eq!(state.users.get(&USER).unwrap().plan, Plan::Suspended);
+
+        let written_off = write_off(&mut state, USER).unwrap();
+        assert_eq!(written_off, MoneyUnit::from_cents(500));
+        let user_data = state.users.get(&USER).unwrap();
+        assert!(user_data.balance.is_zero());
+        let kinds = user_data
+            .ledger
+            .iter()
+            .map(|e| e.kind.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                EntryKind::Chargeback {
+                    reference: "cb-1".to_owned()
+                },
+                EntryKind::WriteOff
+            ]
+        );
+    }
+
+    #[test]
+    fn only_negative_balances_are_written_off() {
+        let mut state = state(1_000);
+        assert!(matches!(
+            write_off(&mut state, USER),
+            Err(Error::Billing(BillingError::NothingToWriteOff))
+        ));
+        let zero = MoneyUnit::from_cents(0);
+        assert!(matches!(
+            record_chargeback(&mut state, USER, zero, "cb-1", false),
+            Err(Error::Billing(BillingError::InvalidArgumentValue))
+        ));
+        assert!(matches!(
+            write_off(&mut state, UserId(1)),
+            Err(Error::Billing(BillingError::UnknownUser))
+        ));
+        assert!(state.users.get(&USER).unwrap().ledger.is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+// Caps the runs a user may have in progress at once, so that a single user launching many
+// parallel runs cannot saturate the host, e.g. the command loop of the daemon. The cap depends
+// on the plan of the user, see `ConcurrencyConfig`. The runs beyond it wait for one of the
+// user's runs to finish, failing with `BillingError::ConcurrencyLimitExceeded` once they have
+// waited for `ConcurrencyConfig::queue_timeout`. The waits are recorded in `QueueMetrics`.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{plan::Plan, BillingError, Error, State, UserId};
+
+// The runs a user may have in progress at once, by the plan of the user
+#[derive(Clone, Copy, Debug)]
+pub struct ConcurrencyConfig {
+    pub trial: u32,
+    pub paid: u32,
+    pub grace: u32,
+    pub suspended: u32,
+    // How long a run beyond the cap waits for a slot
+    pub queue_timeout: Duration,
+}
+
+impl ConcurrencyConfig {
+    pub fn limit(&self, plan: Plan) -> u32 {
+        match plan {
+            Plan::Trial { .. } => self.trial,
+            Plan::Paid => self.paid,
+            Plan::Grace { .. } => self.grace,
+            Plan::Suspended => self.suspended,
+        }
+    }
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            trial: 1,
+            paid: 4,
+            grace: 1,
+            suspended: 1,
+            queue_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+// The cap of the user by their plan, `None` for unknown users
+pub fn limit(state: &State, user: UserId) -> Option<u32> {
+    let user_data = state.users.get(&user)?;
+    Some(state.config.concurrency.limit(user_data.plan))
+}
+
+fn limit_exceeded(limit: u32, running: u32) -> Error {
+    Error::from(BillingError::ConcurrencyLimitExceeded)
+        .with_context("limit", limit as i64)
+        .with_context("running", running as i64)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct QueueMetrics {
+    // Runs let in, whether right away or after waiting
+    pub admitted: u64,
+    // Runs that had to wait for a slot
+    pub queued: u64,
+    pub timed_out: u64,
+    // Over the runs that had to wait, including the ones that timed out
+    pub total_wait_micros: u64,
+    pub max_wait_micros: u64,
+}
+
+impl QueueMetrics {
+    pub fn mean_wait_micros(&self) -> u64 {
+        self.total_wait_micros
+            .checked_div(self.queued)
+            .unwrap_or_default()
+    }
+
+    fn record_wait(&mut self, wait: Duration) {
+        let micros = wait.as_micros() as u64;
+        self.total_wait_micros = self.total_wait_micros.saturating_add(micros);
+        self.max_wait_micros = self.max_wait_micros.max(micros);
+    }
+}
+
+#[derive(Default)]
+struct Slots {
+    // Users without runs in progress are left out
+    running: BTreeMap<UserId, u32>,
+    metrics: QueueMetrics,
+}
+
+// Shared between the threads starting runs, e.g. the connections of the daemon
+#[derive(Default)]
+pub struct InvocationLimiter {
+    slots: Mutex<Slots>,
+    released: Condvar,
+}
+
+// Holds a slot of the user until it is dropped
+pub struct InvocationPermit<'a> {
+    limiter: &'a InvocationLimiter,
+    user: UserId,
+}
+
+impl Drop for InvocationPermit<'_> {
+    fn drop(&mut self) {
+        let mut slots = self.limiter.slots.lock().unwrap();
+        if let Some(running) = slots.running.get_mut(&self.user) {
+            *running -= 1;
+            if *running == 0 {
+                slots.running.remove(&self.user);
+            }
+        }
+        // The waiting runs may belong to any user
+        self.limiter.released.notify_all();
+    }
+}
+
+impl InvocationLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Takes one of the `limit` slots of the user, waiting up to `timeout` for one to be freed.
+    // A limit of 0 fails right away.
+    pub fn acquire(
+        &self,
+        user: UserId,
+        limit: u32,
+        timeout: Duration,
+    ) -> Result<InvocationPermit<'_>, Error> {
+        if limit == 0 {
+            return Err(limit_exceeded(limit, 0));
+        }
+        let mut slots = self.slots.lock().unwrap();
+        let is_full = |slots: &Slots| slots.running.get(&user).copied().unwrap_or(0) >= limit;
+        if is_full(&slots) {
+            slots.metrics.queued += 1;
+            let started = Instant::now();
+            let (waited, _) = self
+                .released
+                .wait_timeout_while(slots, timeout, |slots| is_full(slots))
+                .unwrap();
+            slots = waited;
+            slots.metrics.record_wait(started.elapsed());
+            if is_full(&slots) {
+                slots.metrics.timed_out += 1;
+                return Err(limit_exceeded(limit, slots.running[&user]));
+            }
+        }
+        *slots.running.entry(user).or_default() += 1;
+        slots.metrics.admitted += 1;
+        Ok(InvocationPermit {
+            limiter: self,
+            user,
+        })
+    }
+
+    // The runs of the user in progress
+    pub fn running(&self, user: UserId) -> u32 {
+        let slots = self.slots.lock().unwrap();
+        slots.running.get(&user).copied().unwrap_or(0)
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        self.slots.lock().unwrap().metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const USER: UserId = UserId(0);
+    const OTHER: UserId = UserId(1);
+
+    #[test]
+    fn waiting_run_is_admitted_once_a_slot_is_freed() {
+        let limiter = InvocationLimiter::new();
+        let first = limiter.acquire(USER, 1, Duration::ZERO).unwrap();
+        let _other = limiter.acquire(OTHER, 1, Duration::ZERO).unwrap();
+        assert_eq!(limiter.running(USER), 1);
+
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| {
+                limiter
+                    .acquire(USER, 1, Duration::from_secs(30))
+                    .map(|_| ())
+            });
+            while limiter.metrics().queued == 0 {
+                thread::yield_now();
+            }
+            drop(first);
+            waiting.join().unwrap().unwrap();
+        });
+
+        assert_eq!(limiter.running(USER), 0);
+        assert_eq!(limiter.running(OTHER), 1);
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.admitted, 3);
+        assert_eq!(metrics.queued, 1);
+        assert_eq!(metrics.timed_out, 0);
+    }
+
+    #[test]
+    fn runs_beyond_the_limit_time_out() {
+        let limiter = InvocationLimiter::new();
+        let _permits = [
+            limiter.acquire(USER, 2, Duration::ZERO).unwrap(),
+            limiter.acquire(USER, 2, Duration::ZERO).unwrap(),
+        ];
+        let exceeded = limiter
+            .acquire(USER, 2, Duration::from_millis(10))
+            .err()
+            .unwrap();
+        assert_eq!(
+            exceeded.code(),
+            BillingError::ConcurrencyLimitExceeded.code()
+        );
+        assert_eq!(exceeded.context(), [("limit", 2), ("running", 2)]);
+        let no_slots = limiter.acquire(OTHER, 0, Duration::ZERO).err().unwrap();
+        assert_eq!(no_slots.context(), [("limit", 0), ("running", 0)]);
+
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.admitted, 2);
+        assert_eq!(metrics.queued, 1);
+        assert_eq!(metrics.timed_out, 1);
+        assert!(metrics.max_wait_micros >= 10_000);
+        assert_eq!(metrics.mean_wait_micros(), metrics.total_wait_micros);
+    }
+}
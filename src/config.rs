@@ -0,0 +1,523 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use crate::{
+    adjustments::AdjustmentConfig,
+    alerts::AlertConfig,
+    anomalies::AnomalyConfig,
+    cancellation::CancellationConfig,
+    concurrency::ConcurrencyConfig,
+    determinism::DeterminismConfig,
+    features::FeatureFlag,
+    groups::GroupConfig,
+    guest_metrics::GuestMetricsConfig,
+    indirect_calls::IndirectCallConfig,
+    money::MoneyUnit,
+    notifications::NotificationConfig,
+    plan::{GraceConfig, Plan, TrialConfig},
+    policy::Policy,
+    profiling::ProfilingConfig,
+    queues::Overflow,
+    result_cache::ResultCacheConfig,
+    retention::RetentionConfig,
+    services::Catalog,
+    templates::AccountTemplate,
+    wasm_features::WasmFeatureConfig,
+    watchdog::WatchdogConfig,
+    BillingError, Error, UserId,
+};
+
+// The fee charged to the sender of a transfer on top of the transferred amount.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransferFee {
+    pub flat: MoneyUnit,
+    // Proportional part of the fee in basis points (1/100 of a percent)
+    pub basis_points: u32,
+}
+
+// Terms for the accounts created with `host.register_user`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RegistrationConfig {
+    pub starting_balance: MoneyUnit,
+    // Paid to the referring user when the new account makes its first purchase
+    pub referral_credit: MoneyUnit,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StorageConfig {
+    pub price_per_request: MoneyUnit,
+    pub price_per_gb_day: MoneyUnit,
+    // The most bytes a single user may store, in objects and files, see `quota`
+    pub quota_bytes: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            price_per_request: MoneyUnit::from_cents(0),
+            price_per_gb_day: MoneyUnit::from_cents(1),
+            // 10 GB
+            quota_bytes: 10_000_000_000,
+        }
+    }
+}
+
+// The directories preopened for the guests by the embedder, e.g. in `State::component_wasi`,
+// one per user at `<root>/<user>`. Their files count towards the storage quota, see `quota`.
+#[derive(Clone, Debug, Default)]
+pub struct FilesConfig {
+    // `None` if the guests get no directories
+    pub root: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HostingConfig {
+    // In the currency of the balances, e.g. `MoneyUnit::from_minor_units(150, Currency::JPY)`
+    // on a host billing in yen
+    pub price_per_day: MoneyUnit,
+}
+
+impl Default for HostingConfig {
+    fn default() -> Self {
+        Self {
+            price_per_day: MoneyUnit::from_cents(100),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DomainConfig {
+    // Charged on registration for every year and on every yearly renewal
+    pub price_per_year: MoneyUnit,
+    // The longest period a name can be registered for at once
+    pub max_years: u32,
+}
+
+impl Default for DomainConfig {
+    fn default() -> Self {
+        Self {
+            price_per_year: MoneyUnit::from_cents(1_200),
+            max_years: 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EmailConfig {
+    pub price_per_email: MoneyUnit,
+    // Emails a user may send per day
+    pub daily_quota: u32,
+    // Emails a user may send within any minute, to stop spam bursts
+    pub max_per_minute: u32,
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        Self {
+            price_per_email: MoneyUnit::from_cents(1),
+            daily_quota: 100,
+            max_per_minute: 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DatabaseConfig {
+    pub price_per_month: MoneyUnit,
+    // Days the data of a deprovisioned database is kept before it is wiped
+    pub grace_days: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            price_per_month: MoneyUnit::from_cents(500),
+            grace_days: 7,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CertificateConfig {
+    pub price_per_issuance: MoneyUnit,
+}
+
+impl Default for CertificateConfig {
+    fn default() -> Self {
+        Self {
+            price_per_issuance: MoneyUnit::from_cents(100),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BandwidthConfig {
+    // The bandwidth included in the plan per billing cycle
+    pub included_bytes: u64,
+    // The price of the bandwidth beyond the allowance
+    pub price_per_gb: MoneyUnit,
+    pub cycle_days: u32,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            // 100 GB
+            included_bytes: 100_000_000_000,
+            price_per_gb: MoneyUnit::from_cents(9),
+            cycle_days: 30,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    // Charged to the sender of every message
+    pub price_per_message: MoneyUnit,
+    pub max_message_bytes: usize,
+    // Messages an inbox may hold
+    pub max_messages: usize,
+    pub on_full: Overflow,
+    // Seconds the unread messages are kept
+    pub retention_secs: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            price_per_message: MoneyUnit::from_cents(1),
+            max_message_bytes: 64 * 1024,
+            max_messages: 1_000,
+            on_full: Overflow::Reject,
+            // A week
+            retention_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct JobConfig {
+    // Charged for every run of a scheduled job, including the retries
+    pub price_per_run: MoneyUnit,
+    pub max_jobs_per_user: usize,
+    // Retries of a failed run before the job waits for its next scheduled run
+    pub max_retries: u32,
+    // The delay before the first retry, doubled for every further one
+    pub retry_delay_secs: u64,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            price_per_run: MoneyUnit::from_cents(1),
+            max_jobs_per_user: 10,
+            max_retries: 3,
+            retry_delay_secs: 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BalanceHistoryConfig {
+    // Samples younger than this are kept as recorded
+    pub full_resolution_secs: u64,
+    // Older samples are reduced to one per bucket of this length
+    pub bucket_secs: u64,
+    // Samples older than this are dropped
+    pub retention_secs: u64,
+}
+
+impl Default for BalanceHistoryConfig {
+    fn default() -> Self {
+        const DAY: u64 = 24 * 60 * 60;
+        Self {
+            full_resolution_secs: 7 * DAY,
+            bucket_secs: DAY,
+            retention_secs: 365 * DAY,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryConfig {
+    // Charged for the peak linear memory of every invocation over its duration,
+    // zero disables the billing of memory
+    pub price_per_gb_second: MoneyUnit,
+}
+
+// The fuel an invocation of a core module may consume, see `burst`. Components are not limited.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ComputeConfig {
+    // `None` for invocations limited only by the watchdog, if any
+    pub fuel_per_call: Option<u64>,
+    // The most unused fuel a user may save up to exceed the limit later
+    pub max_burst_credits: u64,
+    // Charged for the fuel of every invocation at sub-cent precision, see
+    // `metering::charge_compute`, zero disables the billing of compute
+    pub price_per_million_fuel: MoneyUnit,
+}
+
+// The prices of the calls of the host functions by name, charged on top of what the functions
+// charge themselves, e.g. 0.02 for every call of `send_email`. Functions without a price are
+// free to call. The calls of an invocation are charged once it ends, see
+// `metering::charge_calls`.
+#[derive(Clone, Debug, Default)]
+pub struct CallPricingConfig {
+    pub prices: BTreeMap<String, MoneyUnit>,
+}
+
+impl CallPricingConfig {
+    // The price of a call of the function, `None` if it is free
+    pub fn price(&self, function: &str) -> Option<MoneyUnit> {
+        self.prices
+            .get(function)
+            .copied()
+            .filter(|price| !price.is_zero())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ArchiveConfig {
+    // Entries at least that old are archived by the daily job, `None` disables archival
+    pub max_age_days: Option<u32>,
+    // Where the archives are written
+    pub dir: PathBuf,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            max_age_days: None,
+            dir: PathBuf::from("ledger-archive"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PostpaidConfig {
+    // Days after the month-end invoicing the invoices are due
+    pub payment_terms_days: u32,
+    // Days an invoice may be overdue before the account is suspended, `None` never suspends it
+    pub suspend_after_days: Option<u32>,
+}
+
+impl Default for PostpaidConfig {
+    fn default() -> Self {
+        Self {
+            payment_terms_days: 30,
+            suspend_after_days: Some(14),
+        }
+    }
+}
+
+// The support tickets a user may have unresolved at once, by the plan of the user
+#[derive(Clone, Copy, Debug)]
+pub struct TicketConfig {
+    pub trial: u32,
+    pub paid: u32,
+    pub grace: u32,
+    pub suspended: u32,
+}
+
+impl TicketConfig {
+    pub fn quota(&self, plan: Plan) -> u32 {
+        match plan {
+            Plan::Trial { .. } => self.trial,
+            Plan::Paid => self.paid,
+            Plan::Grace { .. } => self.grace,
+            Plan::Suspended => self.suspended,
+        }
+    }
+}
+
+impl Default for TicketConfig {
+    fn default() -> Self {
+        Self {
+            trial: 1,
+            paid: 5,
+            grace: 5,
+            suspended: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub transfer_fee: TransferFee,
+    pub trial: TrialConfig,
+    pub grace: GraceConfig,
+    pub catalog: Catalog,
+    // The hosting ordered with `host.order_hosting`
+    pub hosting: HostingConfig,
+    pub registration: RegistrationConfig,
+    pub storage: StorageConfig,
+    pub files: FilesConfig,
+    pub domains: DomainConfig,
+    pub email: EmailConfig,
+    pub database: DatabaseConfig,
+    pub certificates: CertificateConfig,
+    pub bandwidth: BandwidthConfig,
+    pub queues: QueueConfig,
+    pub jobs: JobConfig,
+    pub balance_history: BalanceHistoryConfig,
+    pub tickets: TicketConfig,
+    pub memory: MemoryConfig,
+    pub compute: ComputeConfig,
+    pub call_pricing: CallPricingConfig,
+    // Checked before orders, transfers and refunds, see `policy`
+    pub policy: Policy,
+    pub archive: ArchiveConfig,
+    // The user groups by name, see `groups`
+    pub groups: BTreeMap<String, GroupConfig>,
+    pub postpaid: PostpaidConfig,
+    pub alerts: AlertConfig,
+    // The notifications of the events of the accounts, see `notifications`
+    pub notifications: NotificationConfig,
+    // The feature flags by name, see `features`
+    pub features: BTreeMap<String, FeatureFlag>,
+    // The refunds of the orders cancelled by the users, see `cancellation`
+    pub cancellation: CancellationConfig,
+    // How long the data of the services deprovisioned by the cancellations is kept, see
+    // `retention`
+    pub retention: RetentionConfig,
+    pub guest_metrics: GuestMetricsConfig,
+    // Where the balances, objects and databases are kept
+    pub store: StoreKind,
+    // The runs a user may have in progress at once, see `concurrency`
+    pub concurrency: ConcurrencyConfig,
+    // How the guests of the users may call functions indirectly, by plan, see `indirect_calls`
+    pub indirect_calls: IndirectCallConfig,
+    // Whether a second operator must approve the corrections of the balances, see `adjustments`
+    pub adjustments: AdjustmentConfig,
+    // How long the results of the pure exports are cached, see `result_cache`
+    pub result_cache: ResultCacheConfig,
+    // The templates of the new accounts by name, see `templates`
+    pub templates: BTreeMap<String, AccountTemplate>,
+    // The WebAssembly proposals the modules of the users may use, by plan, see `wasm_features`
+    pub wasm_features: WasmFeatureConfig,
+    // When the spend of a user is unusual and whether it is then capped, see `anomalies`
+    pub anomalies: AnomalyConfig,
+    // The accounts of the operators, one each, whose tokens authorize the operations on all the
+    // accounts, e.g. the maintenance requests to the daemon, see `auth::authorize_operator`
+    pub operators: BTreeSet<UserId>,
+}
+
+// The backend selected by `store = "..."` in the configuration
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum StoreKind {
+    // Nothing outlives the host, e.g. for demos and tests, see `memory_store`
+    #[default]
+    Memory,
+    // A `postgres://` URL, used with the `postgres` feature, see `pg_store`
+    Postgres(String),
+}
+
+impl StoreKind {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "memory" => Ok(StoreKind::Memory),
+            url if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+                Ok(StoreKind::Postgres(url.to_owned()))
+            }
+            _ => Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+}
+
+// Settings of wasmtime's pooling instance allocator, which preallocates the memory for
+// instances up front and makes instantiation faster when many instances are created.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolingConfig {
+    pub max_instances: u32,
+    pub memory_pages_per_instance: u64,
+}
+
+impl Default for PoolingConfig {
+    fn default() -> Self {
+        Self {
+            max_instances: 1_000,
+            // 10 MiB
+            memory_pages_per_instance: 160,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShardingConfig {
+    // Number of independently locked shards of the accounts, see `sharded_store`
+    pub shards: usize,
+}
+
+impl Default for ShardingConfig {
+    fn default() -> Self {
+        Self { shards: 16 }
+    }
+}
+
+// The optimization level of the code compiled by Cranelift
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    // Compiles the fastest, e.g. for short-lived guests
+    None,
+    #[default]
+    Speed,
+    SpeedAndSize,
+}
+
+impl OptLevel {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "none" => Ok(OptLevel::None),
+            "speed" => Ok(OptLevel::Speed),
+            "speed_and_size" => Ok(OptLevel::SpeedAndSize),
+            _ => Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+}
+
+// Settings of the wasmtime engine compiling the guests
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    pub opt_level: OptLevel,
+    // Disabling SIMD disables relaxed SIMD too
+    pub simd: bool,
+    // Disabling bulk memory disables the reference types and threads proposals too, which
+    // depend on it
+    pub bulk_memory: bool,
+    // Compiles the functions of a module on several threads
+    pub parallel_compilation: bool,
+    // Where the compiled modules are cached across runs, `None` disables the cache
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            opt_level: OptLevel::Speed,
+            simd: true,
+            bulk_memory: true,
+            parallel_compilation: true,
+            cache_dir: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RuntimeConfig {
+    pub engine: EngineConfig,
+    // `None` uses the on-demand allocator
+    pub pooling: Option<PoolingConfig>,
+    // `None` disables `host.heartbeat` checks and lets guests run without interruption
+    pub watchdog: Option<WatchdogConfig>,
+    // `None` ignores `Profiler::profile_next_run`
+    pub profiling: Option<ProfilingConfig>,
+    // Provides only the `host` imports, e.g. to pure-logic guests built for
+    // `wasm32-unknown-unknown`. Modules importing WASI and components are rejected
+    // with `HostError::WasiDisabled`.
+    pub without_wasi: bool,
+    // `None` lets the runs depend on the time, the randomness and the platform, see
+    // `determinism`
+    pub determinism: Option<DeterminismConfig>,
+}
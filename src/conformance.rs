@@ -0,0 +1,448 @@
+// The conformance suite of the host ABI: guest modules with the outcomes every implementation
+// of the host must produce, e.g. an alternate host written in another language. The cases
+// cover the error codes and how they are returned, the marshalling of strings through the
+// guest memory, the rounding of amounts and the semantics of the quotas.
+//
+// Every case runs the `run` export of its module, as user 0 of a host holding the accounts
+// of `Fixture` and otherwise configured with its defaults, e.g. inboxes of at most 1000
+// messages of up to 64 KiB at 0.01 a message and at most 50 guest metrics per user. The cases
+// are exported with `conformance export <dir>` as their modules in the text format and a
+// `suite.json` manifest, and run against this host with `conformance run`.
+
+use std::{fs, path::Path};
+
+use serde_json::json;
+
+use crate::{
+    capability::Capability, config::TransferFee, history, money::MoneyUnit, runtime::WasmRuntime,
+    store::UserStore, BillingError, Error, HostError, State, UserData, UserId,
+};
+
+// The accounts of the host: user 0, who runs the case, and user 1, with no balance
+#[derive(Clone, Copy, Debug)]
+pub struct Fixture {
+    pub balance_cents: i64,
+    // Whether user 0 has `Capability::Transfer`
+    pub transfer: bool,
+    // The proportional fee of the transfers, without a flat part
+    pub transfer_fee_basis_points: u32,
+}
+
+const FIXTURE: Fixture = Fixture {
+    balance_cents: 100_000,
+    transfer: false,
+    transfer_fee_basis_points: 0,
+};
+
+const TRANSFERS: Fixture = Fixture {
+    transfer: true,
+    ..FIXTURE
+};
+
+#[derive(Debug)]
+pub enum Expected {
+    // The export returns the value
+    Value(i64),
+    // The export returns the code of the error
+    Code(BillingError),
+    // The export returns the negated code of the error, as the functions not changing the
+    // state of the host do
+    NegatedCode(BillingError),
+    // The module fails to instantiate or the call traps
+    Failure,
+}
+
+impl Expected {
+    fn value(&self) -> Option<i64> {
+        match self {
+            Expected::Value(value) => Some(*value),
+            Expected::Code(e) => Some(e.code() as i64),
+            Expected::NegatedCode(e) => Some(-e.code() as i64),
+            Expected::Failure => None,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Expected::Value(value) => json!({ "value": value }),
+            Expected::Code(e) => json!({ "value": e.code(), "error": <&str>::from(e) }),
+            Expected::NegatedCode(e) => json!({ "value": -e.code(), "error": <&str>::from(e) }),
+            Expected::Failure => json!({ "failure": true }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Case {
+    pub name: &'static str,
+    // `error_codes`, `strings`, `rounding` or `quotas`
+    pub category: &'static str,
+    pub description: &'static str,
+    pub fixture: Fixture,
+    // The module in the text format
+    pub wat: &'static str,
+    pub expected: Expected,
+}
+
+pub const CASES: &[Case] = &[
+    Case {
+        name: "balance_in_cents",
+        category: "error_codes",
+        description: "`balance` returns the balance in cents.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "balance" (func $balance (result i64)))
+  (func (export "run") (result i64) (call $balance)))"#,
+        expected: Expected::Value(100_000),
+    },
+    Case {
+        name: "success_is_zero",
+        category: "error_codes",
+        description: "A successful mutating call returns 0 and charges the account.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "balance" (func $balance (result i64)))
+  (import "host" "order_hosting" (func $order_hosting (param i32) (result i32)))
+  (func (export "run") (result i64)
+    (if (i32.ne (call $order_hosting (i32.const 3)) (i32.const 0)) (then (return (i64.const -1))))
+    (call $balance)))"#,
+        expected: Expected::Value(99_700),
+    },
+    Case {
+        name: "invalid_argument",
+        category: "error_codes",
+        description: "Ordering no days of hosting returns the code of `InvalidArgumentValue`.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "order_hosting" (func $order_hosting (param i32) (result i32)))
+  (func (export "run") (result i64) (i64.extend_i32_s (call $order_hosting (i32.const 0)))))"#,
+        expected: Expected::Code(BillingError::InvalidArgumentValue),
+    },
+    Case {
+        name: "insufficient_balance",
+        category: "error_codes",
+        description: "An order the balance cannot cover fails without charging the account.",
+        fixture: Fixture {
+            balance_cents: 150,
+            ..FIXTURE
+        },
+        wat: r#"(module
+  (import "host" "balance" (func $balance (result i64)))
+  (import "host" "order_hosting" (func $order_hosting (param i32) (result i32)))
+  (func (export "run") (result i64)
+    (if (i32.ne (call $order_hosting (i32.const 2)) (i32.const 4)) (then (return (i64.const -1))))
+    (call $balance)))"#,
+        expected: Expected::Value(150),
+    },
+    Case {
+        name: "self_transfer",
+        category: "error_codes",
+        description: "Transferring to oneself returns the code of `SelfTransfer`.",
+        fixture: TRANSFERS,
+        wat: r#"(module
+  (import "host" "transfer" (func $transfer (param i64 i64) (result i32)))
+  (func (export "run") (result i64)
+    (i64.extend_i32_s (call $transfer (i64.const 0) (i64.const 100)))))"#,
+        expected: Expected::Code(BillingError::SelfTransfer),
+    },
+    Case {
+        name: "unknown_user",
+        category: "error_codes",
+        description: "Transferring to an unknown user returns the code of `UnknownUser`.",
+        fixture: TRANSFERS,
+        wat: r#"(module
+  (import "host" "transfer" (func $transfer (param i64 i64) (result i32)))
+  (func (export "run") (result i64)
+    (i64.extend_i32_s (call $transfer (i64.const 7) (i64.const 100)))))"#,
+        expected: Expected::Code(BillingError::UnknownUser),
+    },
+    Case {
+        name: "missing_capability",
+        category: "error_codes",
+        description: "Transferring without `Capability::Transfer` returns the code of \
+                      `MissingCapability`.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "transfer" (func $transfer (param i64 i64) (result i32)))
+  (func (export "run") (result i64)
+    (i64.extend_i32_s (call $transfer (i64.const 1) (i64.const 100)))))"#,
+        expected: Expected::Code(BillingError::MissingCapability),
+    },
+    Case {
+        name: "unknown_import",
+        category: "error_codes",
+        description: "A module importing a function the host does not provide fails to \
+                      instantiate.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "no_such_function" (func $missing (result i32)))
+  (func (export "run") (result i64) (i64.const 0)))"#,
+        expected: Expected::Failure,
+    },
+    Case {
+        name: "negated_code",
+        category: "error_codes",
+        description: "Functions not changing the state of the host return negated codes, \
+                      e.g. `metric_incr` with an invalid name.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "metric_incr" (func $metric_incr (param i32 i32 i64) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "1st")
+  (func (export "run") (result i64)
+    (i64.extend_i32_s (call $metric_incr (i32.const 0) (i32.const 3) (i64.const 1)))))"#,
+        expected: Expected::NegatedCode(BillingError::InvalidArgumentValue),
+    },
+    Case {
+        name: "no_error_message",
+        category: "strings",
+        description: "`last_error_message` returns 0 before any error is reported.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "last_error_message" (func $message (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "run") (result i64)
+    (i64.extend_i32_s (call $message (i32.const 0) (i32.const 64)))))"#,
+        expected: Expected::Value(0),
+    },
+    Case {
+        name: "error_message_length",
+        category: "strings",
+        description: "`last_error_message` returns the full length of the message in bytes, \
+                      even when the buffer is shorter.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "order_hosting" (func $order_hosting (param i32) (result i32)))
+  (import "host" "last_error_message" (func $message (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "run") (result i64)
+    (drop (call $order_hosting (i32.const 0)))
+    (i64.extend_i32_s (call $message (i32.const 0) (i32.const 4)))))"#,
+        // "Invalid argument value passed to the function."
+        expected: Expected::Value(46),
+    },
+    Case {
+        name: "error_message_truncated",
+        category: "strings",
+        description: "`last_error_message` writes the UTF-8 bytes of the message up to the \
+                      length of the buffer and nothing past it.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "order_hosting" (func $order_hosting (param i32) (result i32)))
+  (import "host" "last_error_message" (func $message (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "\ff\ff\ff\ff\ff\ff\ff\ff")
+  (func (export "run") (result i64)
+    (drop (call $order_hosting (i32.const 0)))
+    (drop (call $message (i32.const 0) (i32.const 4)))
+    (i64.load (i32.const 0))))"#,
+        // "Inva" followed by the untouched bytes, little-endian
+        expected: Expected::Value(0xffff_ffff_6176_6e49_u64 as i64),
+    },
+    Case {
+        name: "buffer_out_of_bounds",
+        category: "strings",
+        description: "A buffer past the end of the memory returns the negated code of \
+                      `GuestMemoryOutOfBounds`.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "order_hosting" (func $order_hosting (param i32) (result i32)))
+  (import "host" "last_error_message" (func $message (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "run") (result i64)
+    (drop (call $order_hosting (i32.const 0)))
+    (i64.extend_i32_s (call $message (i32.const 65530) (i32.const 16)))))"#,
+        expected: Expected::NegatedCode(BillingError::GuestMemoryOutOfBounds),
+    },
+    Case {
+        name: "memory_missing",
+        category: "strings",
+        description: "A guest exporting no memory gets the negated code of \
+                      `GuestMemoryMissing` for functions taking buffers.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "order_hosting" (func $order_hosting (param i32) (result i32)))
+  (import "host" "last_error_message" (func $message (param i32 i32) (result i32)))
+  (func (export "run") (result i64)
+    (drop (call $order_hosting (i32.const 0)))
+    (i64.extend_i32_s (call $message (i32.const 0) (i32.const 16)))))"#,
+        expected: Expected::NegatedCode(BillingError::GuestMemoryMissing),
+    },
+    Case {
+        name: "transfer_fee_rounds_toward_zero",
+        category: "rounding",
+        description: "A fee of 150 basis points of 99 cents, i.e. 1.485 cents, is 1 cent.",
+        fixture: Fixture {
+            balance_cents: 1_000,
+            transfer: true,
+            transfer_fee_basis_points: 150,
+        },
+        wat: r#"(module
+  (import "host" "balance" (func $balance (result i64)))
+  (import "host" "transfer" (func $transfer (param i64 i64) (result i32)))
+  (func (export "run") (result i64)
+    (if (i32.ne (call $transfer (i64.const 1) (i64.const 99)) (i32.const 0))
+      (then (return (i64.const -1))))
+    (call $balance)))"#,
+        expected: Expected::Value(900),
+    },
+    Case {
+        name: "transfer_fee_under_a_cent",
+        category: "rounding",
+        description: "A fee of 150 basis points of 66 cents, i.e. 0.99 cents, is nothing.",
+        fixture: Fixture {
+            balance_cents: 1_000,
+            transfer: true,
+            transfer_fee_basis_points: 150,
+        },
+        wat: r#"(module
+  (import "host" "balance" (func $balance (result i64)))
+  (import "host" "transfer" (func $transfer (param i64 i64) (result i32)))
+  (func (export "run") (result i64)
+    (if (i32.ne (call $transfer (i64.const 1) (i64.const 66)) (i32.const 0))
+      (then (return (i64.const -1))))
+    (call $balance)))"#,
+        expected: Expected::Value(934),
+    },
+    Case {
+        name: "message_too_large",
+        category: "quotas",
+        description: "A message over 64 KiB returns the code of `MessageTooLarge`.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "queue_send" (func $send (param i64 i32 i32) (result i32)))
+  (memory (export "memory") 2)
+  (func (export "run") (result i64)
+    (i64.extend_i32_s (call $send (i64.const 1) (i32.const 0) (i32.const 65537)))))"#,
+        expected: Expected::Code(BillingError::MessageTooLarge),
+    },
+    Case {
+        name: "queue_full",
+        category: "quotas",
+        description: "The 1001st message to an inbox returns the code of `QueueFull`, and the \
+                      rejected message is not charged.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "balance" (func $balance (result i64)))
+  (import "host" "queue_send" (func $send (param i64 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "run") (result i64)
+    (local $i i32)
+    (loop $sending
+      (if (i32.ne (call $send (i64.const 1) (i32.const 0) (i32.const 1)) (i32.const 0))
+        (then (return (i64.const -1))))
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br_if $sending (i32.lt_u (local.get $i) (i32.const 1000))))
+    (if (i32.ne (call $send (i64.const 1) (i32.const 0) (i32.const 1)) (i32.const 51))
+      (then (return (i64.const -2))))
+    (call $balance)))"#,
+        expected: Expected::Value(99_000),
+    },
+    Case {
+        name: "too_many_metrics",
+        category: "quotas",
+        description: "The 51st distinct metric returns the negated code of `TooManyMetrics`, \
+                      while the existing ones can still be updated.",
+        fixture: FIXTURE,
+        wat: r#"(module
+  (import "host" "metric_incr" (func $incr (param i32 i32 i64) (result i32)))
+  (memory (export "memory") 1)
+  (func $name (param $i i32)
+    (i32.store8 (i32.const 0) (i32.add (i32.const 97) (i32.rem_u (local.get $i) (i32.const 26))))
+    (i32.store8 (i32.const 1) (i32.add (i32.const 97) (i32.div_u (local.get $i) (i32.const 26)))))
+  (func (export "run") (result i64)
+    (local $i i32)
+    (loop $metrics
+      (call $name (local.get $i))
+      (if (i32.ne (call $incr (i32.const 0) (i32.const 2) (i64.const 1)) (i32.const 0))
+        (then (return (i64.const -1))))
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br_if $metrics (i32.lt_u (local.get $i) (i32.const 50))))
+    (call $name (i32.const 0))
+    (if (i32.ne (call $incr (i32.const 0) (i32.const 2) (i64.const 1)) (i32.const 0))
+      (then (return (i64.const -2))))
+    (call $name (i32.const 50))
+    (i64.extend_i32_s (call $incr (i32.const 0) (i32.const 2) (i64.const 1)))))"#,
+        expected: Expected::NegatedCode(BillingError::TooManyMetrics),
+    },
+];
+
+#[derive(Debug)]
+pub struct CaseResult {
+    pub case: &'static Case,
+    // The value returned by the export or the error of the run
+    pub outcome: Result<i64, Error>,
+}
+
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        match (&self.outcome, self.case.expected.value()) {
+            (Ok(value), Some(expected)) => *value == expected,
+            (Err(_), None) => true,
+            _ => false,
+        }
+    }
+}
+
+fn state(fixture: &Fixture) -> State {
+    let mut users = UserStore::new();
+    let mut guest = UserData::new(MoneyUnit::from_cents(fixture.balance_cents));
+    if fixture.transfer {
+        guest.capabilities.insert(Capability::Transfer);
+    }
+    users.insert(UserId(0), guest);
+    users.insert(UserId(1), UserData::new(MoneyUnit::from_cents(0)));
+    let mut state = State::new(users);
+    state.config.transfer_fee = TransferFee {
+        flat: MoneyUnit::from_cents(0),
+        basis_points: fixture.transfer_fee_basis_points,
+    };
+    state
+}
+
+// Runs the case against this host in a store of its own
+pub fn run_case<R: WasmRuntime>(runtime: &R, case: &'static Case) -> CaseResult {
+    let mut store = runtime.new_store(state(&case.fixture));
+    let outcome = history::execute(runtime, &mut store, UserId(0), case.wat.as_bytes(), "run");
+    CaseResult { case, outcome }
+}
+
+pub fn run_all<R: WasmRuntime>(runtime: &R) -> Vec<CaseResult> {
+    CASES.iter().map(|case| run_case(runtime, case)).collect()
+}
+
+// Writes the module of every case as `<name>.wat` and the manifest of the suite as
+// `suite.json` into the directory, creating it if needed
+pub fn export(dir: impl AsRef<Path>) -> Result<(), Error> {
+    let dir = dir.as_ref();
+    let persistence = |e: std::io::Error| HostError::Persistence(e.to_string());
+    fs::create_dir_all(dir).map_err(persistence)?;
+    let mut cases = Vec::new();
+    for case in CASES {
+        let file = format!("{}.wat", case.name);
+        fs::write(dir.join(&file), case.wat).map_err(persistence)?;
+        cases.push(json!({
+            "name": case.name,
+            "category": case.category,
+            "description": case.description,
+            "module": file,
+            "export": "run",
+            "fixture": {
+                "balance_cents": case.fixture.balance_cents,
+                "capabilities": match case.fixture.transfer {
+                    true => vec!["Transfer"],
+                    false => vec![],
+                },
+                "transfer_fee_basis_points": case.fixture.transfer_fee_basis_points,
+            },
+            "expected": case.expected.to_json(),
+        }));
+    }
+    let manifest = json!({
+        "host_api_version": crate::host::HOST_API_VERSION,
+        "cases": cases,
+    });
+    let json = serde_json::to_string_pretty(&manifest).unwrap();
+    fs::write(dir.join("suite.json"), json).map_err(persistence)?;
+    Ok(())
+}
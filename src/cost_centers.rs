@@ -0,0 +1,107 @@
+// Cost centers tag the ledger entries with a name of the user's choosing, e.g. a project or an
+// environment, so that the charges can be grouped by it, see `totals` and
+// `invoice::Invoice::totals_by_cost_center`. The entries made on a thread are tagged with its
+// current cost center: a guest sets it for the rest of its invocation with
+// `host.set_default_cost_center`, the embedder for the next invocation with `tag_next` and for
+// the charges it makes itself with `enter`.
+
+use std::{cell::RefCell, collections::BTreeMap};
+
+use crate::{
+    ledger::LedgerEntry,
+    money::{MoneySum, MoneyUnit},
+    BillingError, Error, State,
+};
+
+pub const MAX_LEN: usize = 64;
+
+thread_local! {
+    // The cost center of the charges made on the thread
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Letters, digits and `-_.:/`, e.g. `shop/staging`, so that the names need no escaping
+fn validate(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':' | b'/'));
+    match valid {
+        true => Ok(()),
+        false => Err(BillingError::InvalidArgumentValue.into()),
+    }
+}
+
+// The cost center of the charges made on the thread, if any
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// Restores the cost center the thread had before `enter` when dropped
+pub struct Scope {
+    previous: Option<String>,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+// Tags the charges made on the thread with the cost center until the scope is dropped
+pub fn enter(name: &str) -> Result<Scope, Error> {
+    validate(name)?;
+    Ok(enter_unchecked(Some(name.to_owned())))
+}
+
+pub(crate) fn enter_unchecked(name: Option<String>) -> Scope {
+    let previous = CURRENT.with(|current| current.replace(name));
+    Scope { previous }
+}
+
+// Sets the cost center of the rest of the running invocation, `None` to leave its charges
+// untagged, see `host.set_default_cost_center`
+pub(crate) fn set_default(name: Option<&str>) -> Result<(), Error> {
+    if let Some(name) = name {
+        validate(name)?;
+    }
+    CURRENT.with(|current| *current.borrow_mut() = name.map(str::to_owned));
+    Ok(())
+}
+
+// Tags the charges of the next invocation with the cost center, unless the guest sets another
+pub fn tag_next(state: &mut State, name: &str) -> Result<(), Error> {
+    validate(name)?;
+    state.next_cost_center = Some(name.to_owned());
+    Ok(())
+}
+
+// The net amounts of the billable entries by their cost centers, `None` for the untagged ones,
+// in the order of the names. The charges are positive and the discounts negative, like on
+// the invoices.
+pub fn totals<'a>(
+    entries: impl IntoIterator<Item = &'a LedgerEntry>,
+) -> Result<BTreeMap<Option<String>, MoneyUnit>, Error> {
+    let mut grouped = BTreeMap::<Option<String>, Vec<MoneyUnit>>::new();
+    for entry in entries {
+        if !entry.kind.is_billable() {
+            continue;
+        }
+        let charged = entry
+            .amount
+            .checked_neg()
+            .ok_or(BillingError::TotalCostExceededMaxValue)?;
+        grouped
+            .entry(entry.cost_center.clone())
+            .or_default()
+            .push(charged);
+    }
+    grouped
+        .into_iter()
+        .map(|(name, amounts)| {
+            let currency = amounts[0].currency();
+            Ok((name, amounts.into_iter().checked_sum(currency)?))
+        })
+        .collect()
+}
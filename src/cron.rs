@@ -0,0 +1,418 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+use time::{Date, OffsetDateTime, Time};
+
+use crate::{
+    history,
+    ledger::{self, EntryKind, LedgerEntry},
+    module_hash,
+    runtime::WasmRuntime,
+    BillingError, Error, HostError, State, UserId,
+};
+
+// How far ahead the next run of a schedule is looked for, e.g. `0 0 29 2 *` runs
+// only in leap years
+const MAX_LOOKAHEAD_DAYS: u32 = 8 * 366;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct JobId(pub u64);
+
+// A cron expression of five fields, `minute hour day-of-month month day-of-week`, in UTC.
+// The fields accept `*`, numbers, ranges `a-b`, steps `*/n` or `a-b/n` and lists of those.
+// Days of the week are numbered from Sunday, which is both 0 and 7. As in Vixie cron,
+// a day matches if either of the day fields does when both of them are restricted.
+// `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` are accepted as well.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u32,
+    days_of_month: u32,
+    months: u16,
+    days_of_week: u8,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+// Parses a field into a bitmask of the allowed values
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, Error> {
+    let number = |s: &str| {
+        s.parse::<u32>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .ok_or(BillingError::InvalidSchedule)
+    };
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .map_err(|_| BillingError::InvalidSchedule)?,
+            ),
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (number(from)?, number(to)?),
+                // `a/n` runs from `a` to the end of the range
+                None if part.contains('/') => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if step == 0 || from > to {
+            return Err(BillingError::InvalidSchedule.into());
+        }
+        for value in (from..=to).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, Error> {
+        let expanded = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" => "0 0 1 1 *",
+            expression => expression,
+        };
+        let [minute, hour, day_of_month, month, day_of_week] = expanded
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| BillingError::InvalidSchedule)?;
+        let days_of_week = parse_field(day_of_week, 0, 7)?;
+        Ok(Self {
+            expression: expression.trim().to_owned(),
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)? as u32,
+            days_of_month: parse_field(day_of_month, 1, 31)? as u32,
+            months: parse_field(month, 1, 12)? as u16,
+            // Sunday is folded into 0
+            days_of_week: ((days_of_week | days_of_week >> 7) & 0x7f) as u8,
+            any_day_of_month: day_of_month.starts_with('*'),
+            any_day_of_week: day_of_week.starts_with('*'),
+        })
+    }
+
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    fn matches_day(&self, date: Date) -> bool {
+        if self.months & 1 << u8::from(date.month()) == 0 {
+            return false;
+        }
+        let day_of_month = self.days_of_month & 1 << date.day() != 0;
+        let day_of_week = self.days_of_week & 1 << date.weekday().number_days_from_sunday() != 0;
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+
+    // The first matching minute strictly after the time, in seconds since the Unix epoch
+    pub fn next_after(&self, secs: u64) -> Option<u64> {
+        let after = OffsetDateTime::from_unix_timestamp(i64::try_from(secs).ok()?).ok()?;
+        let mut date = after.date();
+        // Minutes of the day that are already over, including the current one
+        let mut min_minute = after.hour() as u32 * 60 + after.minute() as u32 + 1;
+        for _ in 0..MAX_LOOKAHEAD_DAYS {
+            if self.matches_day(date) {
+                let found = (min_minute..24 * 60).find(|&minute| {
+                    self.hours & 1 << (minute / 60) != 0 && self.minutes & 1 << (minute % 60) != 0
+                });
+                if let Some(minute) = found {
+                    let time = Time::from_hms((minute / 60) as u8, (minute % 60) as u8, 0).ok()?;
+                    return u64::try_from(date.with_time(time).assume_utc().unix_timestamp()).ok();
+                }
+            }
+            date = date.next_day()?;
+            min_minute = 0;
+        }
+        None
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub user: UserId,
+    // Hex-encoded SHA-256 of the module bytes, see `crate::module_hash`
+    pub module_hash: String,
+    pub export: String,
+    pub schedule: CronSchedule,
+    // Seconds since the Unix epoch
+    pub next_run: u64,
+    // The failed attempts of the current run
+    pub failed_attempts: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct JobRun {
+    pub job: JobId,
+    pub user: UserId,
+    pub result: Result<i64, String>,
+    // Whether the run will be attempted again after failing
+    pub retrying: bool,
+}
+
+// The scheduled jobs and the modules they run
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CronJobs {
+    modules: BTreeMap<String, Vec<u8>>,
+    jobs: BTreeMap<JobId, Job>,
+    next_id: u64,
+}
+
+impl CronJobs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Makes the module available to the jobs, returning its hash
+    pub fn add_module(&mut self, bytes: &[u8]) -> String {
+        let hash = module_hash(bytes);
+        self.modules
+            .entry(hash.clone())
+            .or_insert_with(|| bytes.to_vec());
+        hash
+    }
+
+    pub fn get(&self, job: JobId) -> Option<&Job> {
+        self.jobs.get(&job)
+    }
+
+    pub fn jobs_of(&self, user: UserId) -> impl Iterator<Item = (JobId, &Job)> {
+        self.jobs
+            .iter()
+            .filter(move |(_, job)| job.user == user)
+            .map(|(&id, job)| (id, job))
+    }
+
+    // Cancels the jobs of the user, returning how many there were, see `erasure`
+    pub(crate) fn remove_user(&mut self, user: UserId) -> usize {
+        let before = self.jobs.len();
+        self.jobs.retain(|_, job| job.user != user);
+        before - self.jobs.len()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let bytes = bincode::serialize(self).map_err(|e| HostError::Persistence(e.to_string()))?;
+        std::fs::write(path, bytes).map_err(|e| HostError::Persistence(e.to_string()).into())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let bytes = std::fs::read(path).map_err(|e| HostError::Persistence(e.to_string()))?;
+        bincode::deserialize(&bytes).map_err(|e| HostError::Persistence(e.to_string()).into())
+    }
+}
+
+pub fn schedule_job(
+    state: &mut State,
+    user: UserId,
+    module_hash: &str,
+    export: &str,
+    expression: &str,
+) -> Result<JobId, Error> {
+    let config = state.config.jobs;
+    let schedule = CronSchedule::parse(expression)?;
+    let jobs = &mut state.cron_jobs;
+    if !jobs.modules.contains_key(module_hash) {
+        return Err(BillingError::UnknownModule.into());
+    }
+    if !state.users.contains(&user) {
+        return Err(BillingError::UnknownUser.into());
+    }
+    if jobs.jobs_of(user).count() >= config.max_jobs_per_user {
+        return Err(BillingError::TooManyJobs.into());
+    }
+    let next_run = schedule
+        .next_after(ledger::now_secs())
+        .ok_or(BillingError::InvalidSchedule)?;
+    let id = JobId(jobs.next_id);
+    jobs.next_id += 1;
+    jobs.jobs.insert(
+        id,
+        Job {
+            user,
+            module_hash: module_hash.to_owned(),
+            export: export.to_owned(),
+            schedule,
+            next_run,
+            failed_attempts: 0,
+        },
+    );
+    Ok(id)
+}
+
+// Cancels a job of the user. Other users' jobs are reported as unknown.
+pub fn cancel_job(state: &mut State, user: UserId, job: JobId) -> Result<(), Error> {
+    match state.cron_jobs.jobs.get(&job) {
+        Some(found) if found.user == user => {
+            state.cron_jobs.jobs.remove(&job);
+            Ok(())
+        }
+        _ => Err(BillingError::UnknownJob.into()),
+    }
+}
+
+fn charge_run(state: &mut State, user: UserId, job: JobId) -> Result<(), Error> {
+    let price = state.config.jobs.price_per_run;
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    user_data.balance = (user_data.balance - price)?;
+    if !price.is_zero() {
+        user_data.ledger.push(LedgerEntry::new(
+            EntryKind::ScheduledRun { job },
+            price.checked_neg().unwrap(),
+            user_data.balance,
+        ));
+    }
+    Ok(())
+}
+
+// Runs the jobs that are due at `now`, seconds since the Unix epoch. Every run is charged
+// upfront, recorded in the user's execution history and billed for the host calls of the
+// guest like any other execution. Failed runs are retried with exponential backoff until
+// `JobConfig::max_retries` is reached, after which the job waits for its next scheduled run.
+pub fn run_due<R: WasmRuntime>(runtime: &R, store: &mut R::Store, now: u64) -> Vec<JobRun> {
+    let due = runtime
+        .state_mut(store)
+        .cron_jobs
+        .jobs
+        .iter()
+        .filter(|(_, job)| job.next_run <= now)
+        .map(|(&id, _)| id)
+        .collect::<Vec<_>>();
+    let mut runs = Vec::new();
+    for id in due {
+        let state = runtime.state_mut(store);
+        let config = state.config.jobs;
+        let job = state.cron_jobs.jobs[&id].clone();
+        let bytes = state.cron_jobs.modules.get(&job.module_hash).cloned();
+        let result = bytes
+            .ok_or(BillingError::UnknownModule.into())
+            .and_then(|bytes| {
+                charge_run(state, job.user, id)?;
+                Ok(bytes)
+            })
+            .and_then(|bytes| history::execute(runtime, store, job.user, &bytes, &job.export));
+
+        let state = runtime.state_mut(store);
+        // The guest may have cancelled its own job
+        let Some(scheduled) = state.cron_jobs.jobs.get_mut(&id) else {
+            continue;
+        };
+        let retrying = result.is_err() && scheduled.failed_attempts < config.max_retries;
+        if retrying {
+            let backoff = config.retry_delay_secs << scheduled.failed_attempts.min(16);
+            scheduled.failed_attempts += 1;
+            scheduled.next_run = now + backoff;
+        } else {
+            scheduled.failed_attempts = 0;
+            match scheduled.schedule.next_after(now) {
+                Some(next_run) => scheduled.next_run = next_run,
+                None => {
+                    state.cron_jobs.jobs.remove(&id);
+                }
+            }
+        }
+        runs.push(JobRun {
+            job: id,
+            user: job.user,
+            result: result.map_err(|e| e.to_string()),
+            retrying,
+        });
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        money::MoneyUnit,
+        runtime::{WasmRuntime, WasmtimeRuntime},
+        store::UserStore,
+        UserData,
+    };
+
+    const USER: UserId = UserId(0);
+    const DAY: u64 = 24 * 60 * 60;
+
+    // `run` succeeds, `fail` traps
+    const MODULE: &str = r#"
+        (module
+            (func (export "run") (result i64) (i64.const 42))
+            (func (export "fail") (result i64) unreachable))
+    "#;
+
+    #[test]
+    fn schedules_run_on_the_next_matching_minute() {
+        // 1970-01-01 was a Thursday
+        let daily = CronSchedule::parse("@daily").unwrap();
+        assert_eq!(daily.next_after(0), Some(DAY));
+        let monday_noon = CronSchedule::parse("0 12 * * 1").unwrap();
+        assert_eq!(monday_noon.next_after(0), Some(4 * DAY + 12 * 60 * 60));
+        let every_quarter = CronSchedule::parse("*/15 * * * *").unwrap();
+        assert_eq!(every_quarter.next_after(15 * 60), Some(30 * 60));
+
+        for expression in ["* * * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *"] {
+            assert!(matches!(
+                CronSchedule::parse(expression),
+                Err(Error::Billing(BillingError::InvalidSchedule))
+            ));
+        }
+    }
+
+    #[test]
+    fn due_jobs_are_charged_and_failed_runs_retried() {
+        let runtime = WasmtimeRuntime::new();
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(100)));
+        let mut store = runtime.new_store(State::new(users));
+        let state = runtime.state_mut(&mut store);
+        let hash = state.cron_jobs.add_module(MODULE.as_bytes());
+        let run = schedule_job(state, USER, &hash, "run", "@hourly").unwrap();
+        let fail = schedule_job(state, USER, &hash, "fail", "@hourly").unwrap();
+        let now = state.cron_jobs.get(run).unwrap().next_run;
+
+        let runs = run_due(&runtime, &mut store, now);
+        assert_eq!(runs[0].result, Ok(42));
+        assert!(runs[1].result.is_err() && runs[1].retrying);
+        let state = runtime.state_mut(&mut store);
+        assert_eq!(state.cron_jobs.get(run).unwrap().next_run, now + 60 * 60);
+        let failed = state.cron_jobs.get(fail).unwrap();
+        assert_eq!(failed.next_run, now + state.config.jobs.retry_delay_secs);
+        assert_eq!(failed.failed_attempts, 1);
+        let user_data = state.users.get(&USER).unwrap();
+        assert_eq!(user_data.balance, MoneyUnit::from_cents(98));
+    }
+
+    #[test]
+    fn jobs_need_a_known_module_and_belong_to_their_user() {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(100)));
+        let mut state = State::new(users);
+        assert!(matches!(
+            schedule_job(&mut state, USER, "unknown", "run", "@daily"),
+            Err(Error::Billing(BillingError::UnknownModule))
+        ));
+        let hash = state.cron_jobs.add_module(MODULE.as_bytes());
+        let job = schedule_job(&mut state, USER, &hash, "run", "@daily").unwrap();
+        assert!(matches!(
+            cancel_job(&mut state, UserId(1), job),
+            Err(Error::Billing(BillingError::UnknownJob))
+        ));
+        cancel_job(&mut state, USER, job).unwrap();
+        assert!(state.cron_jobs.get(job).is_none());
+    }
+}
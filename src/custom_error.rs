@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+
+use crate::{BillingError, Error};
+
+// Codes below this value are reserved for the errors of the host itself.
+pub const FIRST_CUSTOM_ERROR_CODE: i32 = 1000;
+
+// An error defined by an embedder of the library
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CustomError {
+    pub code: i32,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CustomErrors {
+    errors: BTreeMap<i32, String>,
+}
+
+impl CustomErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Registers a new error, returning the `Error` to be returned from host functions.
+    pub fn register(&mut self, code: i32, message: impl Into<String>) -> Result<Error, Error> {
+        if code < FIRST_CUSTOM_ERROR_CODE || self.errors.contains_key(&code) {
+            return Err(BillingError::InvalidArgumentValue.into());
+        }
+        let message = message.into();
+        self.errors.insert(code, message.clone());
+        Ok(CustomError { code, message }.into())
+    }
+
+    pub fn get(&self, code: i32) -> Option<Error> {
+        self.errors.get(&code).map(|message| {
+            CustomError {
+                code,
+                message: message.clone(),
+            }
+            .into()
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (i32, &str)> {
+        self.errors
+            .iter()
+            .map(|(&code, message)| (code, message.as_str()))
+    }
+}
@@ -0,0 +1,1006 @@
+// The daemon mode lets tools written in other languages submit work over a Unix socket.
+// Every frame is a big-endian `u32` length followed by that many bytes of JSON, e.g.
+//
+// ```text
+// -> {"op": "run", "user": 0, "module": [0, 97, 115, 109, ...], "export": "run",
+//     "stdin": [104, 105]}
+// <- {"type": "result", "value": 42}
+// -> {"op": "balance", "user": 0}
+// <- {"type": "balance", "minor_units": 9700, "currency": "USD"}
+// -> {"op": "logs"}
+// <- {"type": "log", "user": 0, "stream": "stdout", "line": "hello"}
+// <- ...
+// ```
+//
+// The standard input of the guest is empty unless given as `stdin`.
+// Every request but `health` carries an API token of the account it is about, e.g.
+// `{"op": "balance", "user": 0, "token": "wsm_..."}`, see `auth`, a read token for the requests
+// only reading. The requests about all the accounts, i.e. `logs`, `reconcile`, `queue_metrics`,
+// `outbox` and `redrive`, need a token of an operator instead, see `Config::operators`, which
+// also authorizes the requests about any account. The others fail with
+// `BillingError::InvalidToken` or `BillingError::InsufficientScope`.
+// Failed requests are answered with `{"type": "error", "code": ..., "message": ...}`.
+// After `logs`, the connection only receives the lines written by the guests to their
+// standard output and error until the client disconnects.
+//
+// `{"op": "health"}` is answered with a `HealthReport`: the daemon is live while its command
+// loop answers within `HEALTH_TIMEOUT` (a guest running for longer makes it look stuck) and
+// ready when the engine compiles modules and the database backend, if any, is reachable.
+// With a health address, the same report is served over HTTP at `/healthz` (liveness) and
+// `/readyz` (readiness) with a 503 status when failing, e.g. for container orchestrators.
+//
+// `{"op": "reconcile", "repair": false}` is answered with the discrepancies between the
+// balances and the ledgers, see `reconcile`, repairing the balances with `"repair": true`.
+// With `DaemonConfig::reconcile_interval`, the daemon also reconciles periodically and
+// reports the discrepancies on its standard error.
+//
+// The runs of a user beyond the cap of their plan wait on their connections before they reach
+// the command loop and fail with `BillingError::ConcurrencyLimitExceeded` if they wait too long,
+// see `concurrency`. `{"op": "queue_metrics"}` is answered with the `QueueMetrics` of the waits.
+//
+// `{"op": "profile", "user": 0}` is answered with the `Profile` of the account, e.g. for the
+// dashboards of the customer portals, see `portal`.
+//
+// `{"op": "outbox", "dead": true}` is answered with the webhook deliveries waiting in the outbox
+// of the state, only the dead-lettered ones with `"dead": true`, and `{"op": "redrive", "id": 3}`
+// makes the dead-lettered delivery due again, every one of them without the id, see `outbox`.
+// The daemon attempts the due deliveries every `OUTBOX_POLL_INTERVAL` on a thread of its own.
+//
+// On SIGTERM the daemon stops listening, answers the requests it has already received, lets
+// `DaemonHooks::shutdown` flush the stores and returns. On SIGHUP it calls `DaemonHooks::reload`
+// between requests, e.g. to read the configuration again. `systemd_unit` generates a unit
+// running the daemon as a systemd service accordingly. The daemon mode is not available on
+// Windows.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{self, Scope},
+    concurrency::{self, InvocationLimiter, QueueMetrics},
+    history,
+    outbox::{Delivery, DeliveryId, Outbox},
+    portal::{self, Profile},
+    reconcile::{self, Discrepancy},
+    runtime::WasmRuntime,
+    BillingError, Error, HostError, State, UserId,
+};
+
+// Larger frames are rejected before they are read, e.g. modules over 16 MiB
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+// How long the command loop may take to answer a health check before the daemon is
+// considered stuck
+pub const HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+// The smallest valid module, compiled to check the engine
+const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    // Runs the export of the module (binary or text) as the user
+    Run {
+        user: UserId,
+        module: Vec<u8>,
+        export: String,
+        // The standard input of the guest
+        #[serde(default)]
+        stdin: Vec<u8>,
+    },
+    Balance {
+        user: UserId,
+    },
+    // Subscribes the connection to the output of the guests
+    Logs,
+    Health,
+    Reconcile {
+        #[serde(default)]
+        repair: bool,
+    },
+    QueueMetrics,
+    // The profile of the account for the customer portals, see `portal`
+    Profile {
+        user: UserId,
+    },
+    // The deliveries of the outbox, see `outbox`
+    Outbox {
+        #[serde(default)]
+        dead: bool,
+    },
+    // Redrives the dead-lettered delivery, or all of them without the id
+    Redrive {
+        #[serde(default)]
+        id: Option<DeliveryId>,
+    },
+    // Asked by the connections before the runs of the user
+    #[serde(skip)]
+    ConcurrencyLimit {
+        user: UserId,
+    },
+}
+
+// A request with the token of the client, see `authorize`
+#[derive(Deserialize)]
+struct Frame {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(flatten)]
+    request: Request,
+}
+
+// How often the command loop checks for signals while it has no requests to answer
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// How often the due deliveries of the outbox are attempted
+pub const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Default)]
+pub struct DaemonConfig {
+    // Where to serve the health checks over HTTP, e.g. `0.0.0.0:8080`
+    pub health_addr: Option<String>,
+    // How often to check the balances against the ledgers, see `reconcile`
+    pub reconcile_interval: Option<Duration>,
+    // Whether the periodic checks repair the balances
+    pub repair_balances: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    // `engine`, `store` or `scheduler`
+    pub name: String,
+    // `None` if the check passed
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReport {
+    // Whether the command loop answers
+    pub live: bool,
+    // Whether all the checks passed
+    pub ready: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    fn new(checks: Vec<HealthCheck>) -> Self {
+        let check_passed = |name| {
+            checks
+                .iter()
+                .any(|check| check.name == name && check.error.is_none())
+        };
+        Self {
+            live: check_passed("scheduler"),
+            ready: checks.iter().all(|check| check.error.is_none()),
+            checks,
+        }
+    }
+
+    fn check(name: &str, result: Result<(), Error>) -> HealthCheck {
+        HealthCheck {
+            name: name.to_owned(),
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+
+    // The report of a command loop that did not answer
+    fn stuck() -> Self {
+        Self::new(vec![HealthCheck {
+            name: "scheduler".to_owned(),
+            error: Some(format!(
+                "the command loop did not answer within {} seconds",
+                HEALTH_TIMEOUT.as_secs()
+            )),
+        }])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Result {
+        value: i64,
+    },
+    Balance {
+        minor_units: i64,
+        currency: &'static str,
+    },
+    Log {
+        user: UserId,
+        stream: LogStream,
+        line: String,
+    },
+    Error {
+        code: i32,
+        message: String,
+    },
+    Health(HealthReport),
+    Reconciliation {
+        discrepancies: Vec<Discrepancy>,
+        // The balances set to the ones recomputed from the ledgers
+        repaired: usize,
+    },
+    QueueMetrics(QueueMetrics),
+    Profile(Profile),
+    Deliveries(Vec<Delivery>),
+    Redriven {
+        count: usize,
+    },
+    // The client may subscribe to the logs or read the queue metrics
+    #[serde(skip)]
+    Authorized,
+    // `None` for unknown users, whose runs fail in the command loop anyway
+    #[serde(skip)]
+    ConcurrencyLimit {
+        limit: Option<u32>,
+        queue_timeout: Duration,
+    },
+}
+
+impl From<Error> for Response {
+    fn from(e: Error) -> Self {
+        Response::Error {
+            code: e.code(),
+            message: e.to_string(),
+        }
+    }
+}
+
+fn io_error(e: io::Error) -> Error {
+    HostError::Daemon(e.to_string()).into()
+}
+
+// Reads a frame, `None` if the peer closed the connection between frames
+pub fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>, Error> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(io_error(e)),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(HostError::Daemon(format!("frame of {len} bytes is too large")).into());
+    }
+    let mut frame = vec![0; len as usize];
+    reader.read_exact(&mut frame).map_err(io_error)?;
+    Ok(Some(frame))
+}
+
+pub fn write_frame(writer: &mut impl Write, frame: &[u8]) -> Result<(), Error> {
+    let len = u32::try_from(frame.len())
+        .ok()
+        .filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| HostError::Daemon(format!("frame of {} bytes is too large", frame.len())))?;
+    writer.write_all(&len.to_be_bytes()).map_err(io_error)?;
+    writer.write_all(frame).map_err(io_error)?;
+    writer.flush().map_err(io_error)
+}
+
+fn write_response(writer: &mut impl Write, response: &Response) -> Result<(), Error> {
+    let json = serde_json::to_vec(response).map_err(|e| HostError::Daemon(e.to_string()))?;
+    write_frame(writer, &json)
+}
+
+type Subscribers = Arc<Mutex<Vec<Sender<Response>>>>;
+
+// Splits the output of a guest into lines and sends them to the subscribers.
+// The unfinished last line is sent when the guest is done, i.e. when this is dropped.
+struct LogWriter {
+    user: UserId,
+    stream: LogStream,
+    subscribers: Subscribers,
+    partial: Vec<u8>,
+}
+
+impl LogWriter {
+    fn publish(&self, line: &[u8]) {
+        let response = Response::Log {
+            user: self.user,
+            stream: self.stream,
+            line: String::from_utf8_lossy(line).into_owned(),
+        };
+        // Disconnected subscribers are dropped
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(response.clone()).is_ok());
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(newline) = self.partial.iter().position(|&b| b == b'\n') {
+            let line = self.partial.drain(..=newline).collect::<Vec<_>>();
+            self.publish(&line[..newline]);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        if !self.partial.is_empty() {
+            self.publish(&self.partial);
+        }
+    }
+}
+
+// Who sent a request to the command loop
+enum Origin {
+    // A thread of the daemon, e.g. the periodic reconciliation, trusted
+    Daemon,
+    // A client, with the token of its request, if any
+    Client(Option<String>),
+}
+
+// A request of a connection together with where to send the response
+struct Command {
+    request: Request,
+    origin: Origin,
+    reply: Sender<Response>,
+}
+
+// Sends the request of the client to the command loop and waits for the response, `None` if the
+// loop is gone
+fn forward(
+    commands: &Sender<Command>,
+    token: &Option<String>,
+    request: Request,
+) -> Option<Response> {
+    let (reply, response) = mpsc::channel();
+    let origin = Origin::Client(token.clone());
+    commands
+        .send(Command {
+            request,
+            origin,
+            reply,
+        })
+        .ok()?;
+    response.recv().ok()
+}
+
+fn handle_connection(
+    mut stream: UnixStream,
+    commands: Sender<Command>,
+    subscribers: Subscribers,
+    limiter: Arc<InvocationLimiter>,
+) {
+    loop {
+        let frame = match read_frame(&mut stream) {
+            Ok(Some(frame)) => serde_json::from_slice::<Frame>(&frame)
+                .map_err(|e| HostError::Daemon(format!("malformed request: {e}"))),
+            Ok(None) | Err(_) => return,
+        };
+        let (token, request) = match frame {
+            Ok(Frame { token, request }) => (token, Ok(request)),
+            Err(e) => (None, Err(e)),
+        };
+        let response = match request {
+            Ok(Request::Health) => Response::Health(ask_health(&commands)),
+            Ok(request @ (Request::Logs | Request::QueueMetrics)) => {
+                let logs = request == Request::Logs;
+                match forward(&commands, &token, request) {
+                    Some(Response::Authorized) if logs => {
+                        let (sender, receiver) = mpsc::channel();
+                        subscribers.lock().unwrap().push(sender);
+                        stream_logs(stream, receiver);
+                        return;
+                    }
+                    Some(Response::Authorized) => Response::QueueMetrics(limiter.metrics()),
+                    Some(response) => response,
+                    None => return,
+                }
+            }
+            Ok(request) => {
+                let permit = match &request {
+                    Request::Run { user, .. } => {
+                        let user = *user;
+                        match forward(&commands, &token, Request::ConcurrencyLimit { user }) {
+                            Some(Response::ConcurrencyLimit {
+                                limit: Some(limit),
+                                queue_timeout,
+                            }) => Some(limiter.acquire(user, limit, queue_timeout)),
+                            Some(Response::Error { code, message }) => {
+                                let response = Response::Error { code, message };
+                                if write_response(&mut stream, &response).is_err() {
+                                    return;
+                                }
+                                continue;
+                            }
+                            Some(_) => None,
+                            None => return,
+                        }
+                    }
+                    _ => None,
+                };
+                // The slot is held until the command loop has answered
+                match permit.transpose() {
+                    Ok(_permit) => match forward(&commands, &token, request) {
+                        Some(response) => response,
+                        None => return,
+                    },
+                    Err(e) => e.into(),
+                }
+            }
+            Err(e) => Error::from(e).into(),
+        };
+        if write_response(&mut stream, &response).is_err() {
+            return;
+        }
+    }
+}
+
+// Passes the health check to the command loop, which runs the checks needing the runtime
+fn ask_health(commands: &Sender<Command>) -> HealthReport {
+    let (reply, response) = mpsc::channel();
+    let request = Request::Health;
+    let origin = Origin::Daemon;
+    if commands
+        .send(Command {
+            request,
+            origin,
+            reply,
+        })
+        .is_err()
+    {
+        return HealthReport::stuck();
+    }
+    match response.recv_timeout(HEALTH_TIMEOUT) {
+        Ok(Response::Health(report)) => report,
+        _ => HealthReport::stuck(),
+    }
+}
+
+// Answers `GET /healthz` and `GET /readyz` with the JSON of the health report
+fn handle_health_connection(stream: TcpStream, commands: &Sender<Command>) -> io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match path {
+        "/healthz" | "/readyz" => {
+            let report = ask_health(commands);
+            let healthy = match path {
+                "/healthz" => report.live,
+                _ => report.ready,
+            };
+            let status = match healthy {
+                true => "200 OK",
+                false => "503 Service Unavailable",
+            };
+            (status, serde_json::to_string(&report).unwrap_or_default())
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+fn serve_health(listener: TcpListener, commands: Sender<Command>) {
+    for stream in listener.incoming().flatten() {
+        let commands = commands.clone();
+        std::thread::spawn(move || {
+            // The probe may have given up in the meantime
+            let _ = handle_health_connection(stream, &commands);
+        });
+    }
+}
+
+// Asks the command loop to reconcile the balances every interval, until the daemon stops
+fn reconcile_periodically(commands: Sender<Command>, interval: Duration, repair: bool) {
+    loop {
+        std::thread::sleep(interval);
+        let (reply, response) = mpsc::channel();
+        let request = Request::Reconcile { repair };
+        let origin = Origin::Daemon;
+        if commands
+            .send(Command {
+                request,
+                origin,
+                reply,
+            })
+            .is_err()
+        {
+            return;
+        }
+        match response.recv() {
+            Ok(Response::Reconciliation {
+                discrepancies,
+                repaired,
+            }) => {
+                for d in &discrepancies {
+                    let computed = d.computed.map_or("unknown".to_owned(), |c| c.to_string());
+                    let broken = d.first_broken_entry.map_or(String::new(), |entry| {
+                        format!(", broken from entry {entry}")
+                    });
+                    eprintln!(
+                        "The balance of user {} is {} but the ledger adds up to {computed}{broken}",
+                        d.user.0, d.stored
+                    );
+                }
+                if repaired > 0 {
+                    eprintln!("Repaired {repaired} balances");
+                }
+            }
+            Ok(_) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+// Attempts the due deliveries of the outbox until the daemon stops
+fn deliver_periodically(outbox: Outbox) {
+    while !TERMINATE.load(Ordering::SeqCst) {
+        std::thread::sleep(OUTBOX_POLL_INTERVAL);
+        match outbox.deliver_due() {
+            Ok(round) if round.dead_lettered > 0 => {
+                eprintln!("Dead-lettered {} webhook deliveries", round.dead_lettered);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to record the webhook deliveries: {e}"),
+        }
+    }
+}
+
+fn stream_logs(mut stream: UnixStream, logs: Receiver<Response>) {
+    for log in logs {
+        if write_response(&mut stream, &log).is_err() {
+            return;
+        }
+    }
+}
+
+// Checks the token of a client against the request, see the protocol above
+fn authorize(state: &State, token: Option<&str>, request: &Request) -> Result<(), Error> {
+    let (scope, user) = match request {
+        Request::Health => return Ok(()),
+        Request::Run { user, .. } | Request::ConcurrencyLimit { user } => {
+            (Scope::Write, Some(*user))
+        }
+        Request::Balance { user } | Request::Profile { user } => (Scope::Read, Some(*user)),
+        Request::Logs
+        | Request::QueueMetrics
+        | Request::Outbox { .. }
+        | Request::Reconcile { repair: false } => (Scope::Read, None),
+        Request::Reconcile { repair: true } | Request::Redrive { .. } => (Scope::Write, None),
+    };
+    let token = token.ok_or(BillingError::InvalidToken)?;
+    match user {
+        Some(user) if auth::authorize(&state.users, token, scope)? == user => Ok(()),
+        _ => auth::authorize_operator(state, token, scope).map(drop),
+    }
+}
+
+fn handle_request<R: WasmRuntime>(
+    runtime: &R,
+    store: &mut R::Store,
+    subscribers: &Subscribers,
+    request: Request,
+    origin: Origin,
+) -> Response {
+    if let Origin::Client(token) = &origin {
+        if let Err(e) = authorize(runtime.state_mut(store), token.as_deref(), &request) {
+            return e.into();
+        }
+    }
+    match request {
+        Request::Run {
+            user,
+            module,
+            export,
+            stdin,
+        } => {
+            for stream in [LogStream::Stdout, LogStream::Stderr] {
+                let writer = Box::new(LogWriter {
+                    user,
+                    stream,
+                    subscribers: subscribers.clone(),
+                    partial: Vec::new(),
+                });
+                match stream {
+                    LogStream::Stdout => runtime.set_stdout(store, writer),
+                    LogStream::Stderr => runtime.set_stderr(store, writer),
+                }
+            }
+            let result =
+                history::execute_with_input(runtime, store, user, &module, &export, &stdin);
+            // Replacing the writers flushes the unfinished lines of the run
+            runtime.set_stdout(store, Box::new(io::sink()));
+            runtime.set_stderr(store, Box::new(io::sink()));
+            match result {
+                Ok(value) => Response::Result { value },
+                Err(e) => e.into(),
+            }
+        }
+        Request::Balance { user } => match runtime.state_mut(store).users.get(&user) {
+            Some(user_data) => Response::Balance {
+                minor_units: user_data.balance.minor_units(),
+                currency: user_data.balance.currency().code(),
+            },
+            None => Error::from(BillingError::UnknownUser).into(),
+        },
+        Request::Health => {
+            let engine = runtime.compile(EMPTY_MODULE).map(|_| ());
+            let state = runtime.state_mut(store);
+            let store = match state.database_backend.as_mut() {
+                Some(backend) => backend.ping(),
+                None => Ok(()),
+            };
+            Response::Health(HealthReport::new(vec![
+                HealthReport::check("engine", engine),
+                HealthReport::check("store", store),
+                // Answering at all shows that the command loop is running
+                HealthReport::check("scheduler", Ok(())),
+            ]))
+        }
+        Request::Reconcile { repair } => {
+            let state = runtime.state_mut(store);
+            let discrepancies = reconcile::reconcile(state);
+            let repaired = match repair {
+                true => reconcile::repair(state, &discrepancies),
+                false => 0,
+            };
+            Response::Reconciliation {
+                discrepancies,
+                repaired,
+            }
+        }
+        Request::Profile { user } => match portal::profile(runtime.state_mut(store), user) {
+            Ok(profile) => Response::Profile(profile),
+            Err(e) => e.into(),
+        },
+        Request::Outbox { dead } => {
+            let outbox = &runtime.state_mut(store).outbox;
+            Response::Deliveries(match dead {
+                true => outbox.dead_lettered(),
+                false => outbox.deliveries(),
+            })
+        }
+        Request::Redrive { id } => {
+            let outbox = &runtime.state_mut(store).outbox;
+            let redriven = match id {
+                Some(id) => outbox.redrive(id).map(|()| 1),
+                None => outbox.redrive_all(),
+            };
+            match redriven {
+                Ok(count) => Response::Redriven { count },
+                Err(e) => e.into(),
+            }
+        }
+        Request::ConcurrencyLimit { user } => {
+            let state = runtime.state_mut(store);
+            Response::ConcurrencyLimit {
+                limit: concurrency::limit(state, user),
+                queue_timeout: state.config.concurrency.queue_timeout,
+            }
+        }
+        // The subscriptions and the queue metrics are handled by the connections
+        Request::Logs | Request::QueueMetrics => Response::Authorized,
+    }
+}
+
+// Asks the daemon listening on the socket for its `HealthReport`, e.g. for the exec probes
+// of container orchestrators
+pub fn check_health(path: impl AsRef<Path>) -> Result<HealthReport, Error> {
+    let mut stream = UnixStream::connect(path).map_err(io_error)?;
+    stream
+        .set_read_timeout(Some(HEALTH_TIMEOUT + Duration::from_secs(1)))
+        .map_err(io_error)?;
+    write_frame(&mut stream, br#"{"op": "health"}"#)?;
+    let frame = read_frame(&mut stream)?
+        .ok_or_else(|| HostError::Daemon("the daemon closed the connection".to_owned()))?;
+    let malformed = |e: serde_json::Error| HostError::Daemon(format!("malformed response: {e}"));
+    let response = serde_json::from_slice::<serde_json::Value>(&frame).map_err(malformed)?;
+    match response["type"].as_str() {
+        Some("health") => Ok(serde_json::from_value(response).map_err(malformed)?),
+        _ => Err(HostError::Daemon(format!("unexpected response: {response}")).into()),
+    }
+}
+
+// What the daemon does on the signals of the service manager
+pub trait DaemonHooks {
+    // On SIGHUP, e.g. to read the configuration again. A failure is reported on the standard
+    // error and leaves the daemon running.
+    fn reload(&mut self, _state: &mut State) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // On SIGTERM, once the received requests have been answered, e.g. to flush the stores
+    fn shutdown(&mut self, _state: &mut State) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub struct NoopDaemonHooks;
+
+impl DaemonHooks for NoopDaemonHooks {}
+
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    // Only the flags are set, which is safe within a signal handler
+    match signal {
+        libc::SIGTERM => TERMINATE.store(true, Ordering::SeqCst),
+        libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        _ => {}
+    }
+}
+
+fn handle_signals() -> Result<(), Error> {
+    for signal in [libc::SIGTERM, libc::SIGHUP] {
+        // SAFETY: the handler only stores into atomics, and the blocking calls of the other
+        // threads are restarted rather than failed by `SA_RESTART`
+        let failed = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut()) != 0
+        };
+        if failed {
+            return Err(io_error(io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+// A systemd unit running `exec_start`, e.g. `/usr/bin/wsm daemon /run/wsm.sock`, as a service
+// that is stopped with SIGTERM, given `stop_timeout` to drain, and reloaded with SIGHUP
+pub fn systemd_unit(exec_start: &str, stop_timeout: Duration) -> String {
+    format!(
+        "[Unit]
+Description=WASI services management daemon
+After=network.target
+
+[Service]
+Type=simple
+ExecStart={exec_start}
+ExecReload=/bin/kill -HUP $MAINPID
+KillSignal=SIGTERM
+TimeoutStopSec={}
+Restart=on-failure
+
+[Install]
+WantedBy=multi-user.target
+",
+        stop_timeout.as_secs()
+    )
+}
+
+// Serves the requests of the clients connecting to the socket at the path until
+// the listener fails or the daemon receives SIGTERM. The guests run one at a time on the
+// calling thread, while every connection is read on a thread of its own.
+pub fn serve<R: WasmRuntime>(
+    runtime: &R,
+    store: &mut R::Store,
+    path: impl AsRef<Path>,
+    config: &DaemonConfig,
+) -> Result<(), Error> {
+    serve_with_hooks(runtime, store, path, config, &mut NoopDaemonHooks)
+}
+
+// Like `serve`, returning after SIGTERM once the hooks have shut down, see `DaemonHooks`
+pub fn serve_with_hooks<R: WasmRuntime>(
+    runtime: &R,
+    store: &mut R::Store,
+    path: impl AsRef<Path>,
+    config: &DaemonConfig,
+    hooks: &mut dyn DaemonHooks,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    // A socket left behind by a previous run would make binding fail
+    let stale = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if stale {
+        std::fs::remove_file(path).map_err(io_error)?;
+    }
+    let listener = UnixListener::bind(path).map_err(io_error)?;
+    handle_signals()?;
+    let subscribers = Subscribers::default();
+    let limiter = Arc::new(InvocationLimiter::new());
+    let (commands, incoming) = mpsc::channel::<Command>();
+    if let Some(addr) = &config.health_addr {
+        let listener = TcpListener::bind(addr).map_err(io_error)?;
+        let commands = commands.clone();
+        std::thread::spawn(move || serve_health(listener, commands));
+    }
+    if let Some(interval) = config.reconcile_interval {
+        let commands = commands.clone();
+        let repair = config.repair_balances;
+        std::thread::spawn(move || reconcile_periodically(commands, interval, repair));
+    }
+    let outbox = runtime.state_mut(store).outbox.clone();
+    std::thread::spawn(move || deliver_periodically(outbox));
+
+    let accepting = {
+        let subscribers = subscribers.clone();
+        std::thread::spawn(move || -> Result<(), Error> {
+            for stream in listener.incoming() {
+                let stream = stream.map_err(io_error)?;
+                let commands = commands.clone();
+                let subscribers = subscribers.clone();
+                let limiter = limiter.clone();
+                std::thread::spawn(move || {
+                    handle_connection(stream, commands, subscribers, limiter)
+                });
+            }
+            Ok(())
+        })
+    };
+
+    let answer = |store: &mut R::Store,
+                  Command {
+                      request,
+                      origin,
+                      reply,
+                  }| {
+        let response = handle_request(runtime, store, &subscribers, request, origin);
+        // The client may have disconnected in the meantime
+        let _ = reply.send(response);
+    };
+    // Ends once the accepting thread and all the connections are gone or on SIGTERM
+    while !TERMINATE.load(Ordering::SeqCst) {
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            match hooks.reload(runtime.state_mut(store)) {
+                Ok(()) => eprintln!("Reloaded"),
+                Err(e) => eprintln!("Failed to reload: {e}"),
+            }
+        }
+        match incoming.recv_timeout(SIGNAL_POLL_INTERVAL) {
+            Ok(command) => answer(store, command),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return accepting
+                    .join()
+                    .map_err(|_| HostError::Daemon("the accepting thread panicked".to_owned()))?;
+            }
+        }
+    }
+    // New clients can no longer connect, while the requests already received are answered
+    std::fs::remove_file(path).map_err(io_error)?;
+    while let Ok(command) = incoming.try_recv() {
+        answer(store, command);
+    }
+    hooks.shutdown(runtime.state_mut(store))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        money::MoneyUnit,
+        runtime::{SMStore, WasmRuntime, WasmtimeRuntime},
+        store::UserStore,
+        UserData,
+    };
+
+    const USER: UserId = UserId(0);
+
+    // Writes two lines to the standard output, the second one unfinished, and returns 42
+    const MODULE: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "hello\nworld")
+            (func (export "run") (result i64)
+                (i32.store (i32.const 0) (i32.const 16))
+                (i32.store (i32.const 4) (i32.const 11))
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+                (i64.const 42)))
+    "#;
+
+    // A store with a user of 100.00 and a write token of theirs
+    fn store(runtime: &WasmtimeRuntime) -> (SMStore, String) {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(10_000)));
+        users.insert(UserId(1), UserData::new(MoneyUnit::from_cents(0)));
+        let token = auth::issue_token(&mut users, USER, Scope::Write).unwrap();
+        (runtime.new_store(State::new(users)), token)
+    }
+
+    #[test]
+    fn frames_round_trip_until_the_peer_closes() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, br#"{"op": "health"}"#).unwrap();
+        assert_eq!(buffer[..4], 16u32.to_be_bytes());
+        let mut reader = buffer.as_slice();
+        assert_eq!(
+            read_frame(&mut reader).unwrap().unwrap(),
+            br#"{"op": "health"}"#
+        );
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+
+        let too_large = (MAX_FRAME_LEN + 1).to_be_bytes();
+        assert!(read_frame(&mut too_large.as_slice()).is_err());
+        // The length promises more than the peer sent
+        let truncated = [&16u32.to_be_bytes()[..], b"{}"].concat();
+        assert!(read_frame(&mut truncated.as_slice()).is_err());
+    }
+
+    #[test]
+    fn runs_publish_the_output_of_the_guest() {
+        let runtime = WasmtimeRuntime::new();
+        let (mut store, token) = store(&runtime);
+        let (subscriber, logs) = mpsc::channel();
+        let subscribers = Arc::new(Mutex::new(vec![subscriber]));
+        let request = Request::Run {
+            user: USER,
+            module: MODULE.as_bytes().to_vec(),
+            export: "run".to_owned(),
+            stdin: Vec::new(),
+        };
+        let origin = Origin::Client(Some(token));
+        let response = handle_request(&runtime, &mut store, &subscribers, request, origin);
+        assert_eq!(response, Response::Result { value: 42 });
+        let lines = logs.try_iter().collect::<Vec<_>>();
+        let log = |line: &str| Response::Log {
+            user: USER,
+            stream: LogStream::Stdout,
+            line: line.to_owned(),
+        };
+        assert_eq!(lines, [log("hello"), log("world")]);
+    }
+
+    #[test]
+    fn requests_need_a_token_of_their_account() {
+        let runtime = WasmtimeRuntime::new();
+        let (mut store, token) = store(&runtime);
+        let subscribers = Subscribers::default();
+        let mut balance = |user, token: Option<&str>| {
+            let origin = Origin::Client(token.map(str::to_owned));
+            let request = Request::Balance { user };
+            handle_request(&runtime, &mut store, &subscribers, request, origin)
+        };
+        assert_eq!(
+            balance(USER, Some(&token)),
+            Response::Balance {
+                minor_units: 10_000,
+                currency: "USD"
+            }
+        );
+        for (user, token, error) in [
+            (USER, None, BillingError::InvalidToken),
+            (
+                UserId(1),
+                Some(token.as_str()),
+                BillingError::InsufficientScope,
+            ),
+        ] {
+            assert!(matches!(
+                balance(user, token),
+                Response::Error { code, .. } if code == error.code()
+            ));
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    config::DatabaseConfig,
+    ledger::{EntryKind, LedgerEntry},
+    BillingError, Error, HostError, State, UserData, UserId,
+};
+
+const DAYS_PER_MONTH: u32 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DatabaseStatus {
+    // Days until the next month is billed
+    Active { days_left: u32 },
+    // The data is kept until the grace period ends, so the database can be ordered again
+    Deprovisioned { days_until_wipe: u32 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Database {
+    // Handed to the guest by `host.db_connection_info`
+    pub connection_info: String,
+    pub status: DatabaseStatus,
+}
+
+// Creates and destroys the databases of the users
+pub trait DatabaseBackend {
+    // Returns the information the user needs to connect to the new database
+    fn create(&mut self, user: UserId) -> Result<String, Error>;
+    fn wipe(&mut self, user: UserId) -> Result<(), Error>;
+
+    // Checks that the backend is reachable, e.g. for the readiness of the daemon
+    fn ping(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn database_error(e: impl ToString) -> Error {
+    HostError::Database(e.to_string()).into()
+}
+
+// Gives every user a SQLite file of their own
+pub struct SqliteBackend {
+    dir: PathBuf,
+}
+
+impl SqliteBackend {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+
+    fn path(&self, user: UserId) -> PathBuf {
+        self.dir.join(format!("user_{}.sqlite", user.0))
+    }
+}
+
+impl DatabaseBackend for SqliteBackend {
+    fn create(&mut self, user: UserId) -> Result<String, Error> {
+        std::fs::create_dir_all(&self.dir).map_err(database_error)?;
+        let path = self.path(user);
+        // SQLite treats an empty file as an empty database
+        std::fs::File::create(&path).map_err(database_error)?;
+        Ok(format!("sqlite://{}", path.display()))
+    }
+
+    fn wipe(&mut self, user: UserId) -> Result<(), Error> {
+        match std::fs::remove_file(self.path(user)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(database_error(e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn ping(&mut self) -> Result<(), Error> {
+        std::fs::create_dir_all(&self.dir).map_err(database_error)
+    }
+}
+
+// Gives every user a schema of their own in a shared Postgres database,
+// accessible only to a role created for the user
+#[cfg(feature = "postgres")]
+pub struct PostgresBackend {
+    client: postgres::Client,
+    // `host:port/database` the users connect to
+    address: String,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresBackend {
+    // `admin_url` must belong to a role allowed to create roles and schemas
+    pub fn new(admin_url: &str, address: &str) -> Result<Self, Error> {
+        let client =
+            postgres::Client::connect(admin_url, postgres::NoTls).map_err(database_error)?;
+        Ok(Self {
+            client,
+            address: address.to_owned(),
+        })
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl DatabaseBackend for PostgresBackend {
+    fn create(&mut self, user: UserId) -> Result<String, Error> {
+        use rand::RngCore;
+
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let password = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        // The identifiers and the password consist of safe characters only
+        let name = format!("user_{}", user.0);
+        self.client
+            .batch_execute(&format!(
+                "CREATE ROLE {name} LOGIN PASSWORD '{password}';
+                 CREATE SCHEMA {name} AUTHORIZATION {name};
+                 ALTER ROLE {name} SET search_path = {name};"
+            ))
+            .map_err(database_error)?;
+        Ok(format!("postgres://{name}:{password}@{}", self.address))
+    }
+
+    fn wipe(&mut self, user: UserId) -> Result<(), Error> {
+        let name = format!("user_{}", user.0);
+        self.client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {name} CASCADE;
+                 DROP ROLE IF EXISTS {name};"
+            ))
+            .map_err(database_error)
+    }
+
+    fn ping(&mut self) -> Result<(), Error> {
+        self.client
+            .is_valid(std::time::Duration::from_secs(5))
+            .map_err(database_error)
+    }
+}
+
+fn charge_month(user_data: &mut UserData, config: &DatabaseConfig) -> Result<(), Error> {
+    user_data.balance = (user_data.balance - config.price_per_month)?;
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::DatabaseMonth,
+        config.price_per_month.checked_neg().unwrap(),
+        user_data.balance,
+    ));
+    Ok(())
+}
+
+// Orders a database for the user, billing the first month upfront. Ordering a deprovisioned
+// database within its grace period restores it with the data intact.
+pub fn order(state: &mut State, user: UserId) -> Result<(), Error> {
+    let config = state.config.database;
+    let backend = state
+        .database_backend
+        .as_mut()
+        .ok_or(BillingError::DatabaseUnavailable)?;
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    if let Some(Database {
+        status: DatabaseStatus::Active { .. },
+        ..
+    }) = user_data.database
+    {
+        return Err(BillingError::DatabaseExists.into());
+    }
+    // The balance is checked before anything gets provisioned
+    (user_data.balance - config.price_per_month)?;
+    if user_data.database.is_none() {
+        let connection_info = backend.create(user)?;
+        user_data.database = Some(Database {
+            connection_info,
+            status: DatabaseStatus::Active { days_left: 0 },
+        });
+    }
+    charge_month(user_data, &config)?;
+    user_data.database.as_mut().unwrap().status = DatabaseStatus::Active {
+        days_left: DAYS_PER_MONTH,
+    };
+    Ok(())
+}
+
+// Stops billing the database. The data is wiped once the grace period is over.
+pub fn deprovision(state: &mut State, user: UserId) -> Result<(), Error> {
+    let grace_days = state.config.database.grace_days;
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    match &mut user_data.database {
+        Some(database) if matches!(database.status, DatabaseStatus::Active { .. }) => {
+            database.status = DatabaseStatus::Deprovisioned {
+                days_until_wipe: grace_days,
+            };
+            Ok(())
+        }
+        _ => Err(BillingError::NoDatabase.into()),
+    }
+}
+
+// The connection information of the user's active database
+pub fn connection_info(user_data: &UserData) -> Result<&str, Error> {
+    match &user_data.database {
+        Some(Database {
+            connection_info,
+            status: DatabaseStatus::Active { .. },
+        }) => Ok(connection_info),
+        _ => Err(BillingError::NoDatabase.into()),
+    }
+}
+
+// Bills the databases whose month is over, deprovisioning those whose owners cannot pay,
+// and wipes the databases whose grace period has ended.
+pub(crate) fn advance_day(state: &mut State) {
+    let config = state.config.database;
+    let mut wiped = Vec::new();
+    for (&user, user_data) in state.users.iter_mut() {
+        let Some(database) = &mut user_data.database else {
+            continue;
+        };
+        match &mut database.status {
+            DatabaseStatus::Active { days_left } if *days_left > 1 => *days_left -= 1,
+            DatabaseStatus::Active { .. } => {
+                database.status = DatabaseStatus::Active {
+                    days_left: DAYS_PER_MONTH,
+                };
+                if charge_month(user_data, &config).is_err() {
+                    user_data.database.as_mut().unwrap().status = DatabaseStatus::Deprovisioned {
+                        days_until_wipe: config.grace_days,
+                    };
+                }
+            }
+            DatabaseStatus::Deprovisioned { days_until_wipe } if *days_until_wipe > 1 => {
+                *days_until_wipe -= 1
+            }
+            DatabaseStatus::Deprovisioned { .. } => wiped.push(user),
+        }
+    }
+    let Some(backend) = state.database_backend.as_mut() else {
+        return;
+    };
+    for user in wiped {
+        // A database that fails to be wiped is retried the next day
+        if backend.wipe(user).is_ok() {
+            state.users.get_mut(&user).unwrap().database = None;
+        }
+    }
+}
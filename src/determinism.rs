@@ -0,0 +1,218 @@
+// Deterministic mode, in which the same module run on the same inputs always returns the same
+// result and makes the same ledger entries, so that a disputed invocation can be replayed, see
+// `RuntimeConfig::determinism`. In this mode:
+//
+// - the NaNs are canonicalized and the relaxed SIMD instructions behave the same on every
+//   platform, and the threads proposal is disabled
+// - every invocation draws its trace id and the bytes of the WASI `random_get` and of
+//   `host.random_u64` from randomness seeded with `DeterminismConfig::seed`
+// - the clock is pinned at `DeterminismConfig::clock_secs` for the guests, with the WASI
+//   `clock_time_get` and `clock_res_get` and `host.now_secs`, and for the ledger entries made
+//   during the invocations
+// - the modules importing the WASI functions whose outcome depends on timing or on the network,
+//   i.e. `poll_oneoff` and the sockets, and the components, whose WASI is built by
+//   `State::component_wasi`, are rejected with `HostError::Nondeterministic`
+
+use std::cell::Cell;
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use wasmtime::{Caller, Extern, Func, ImportType, Store};
+
+use crate::{guest_memory, runtime::WasmtimeState, trace, Error, HostError, State};
+
+// The imports of `wasi_snapshot_preview1` and `wasi_unstable` rejected in the deterministic mode
+const NONDETERMINISTIC_WASI_IMPORTS: &[&str] = &[
+    "poll_oneoff",
+    "sock_accept",
+    "sock_recv",
+    "sock_send",
+    "sock_shutdown",
+];
+
+// The WASI errno values returned by the pinned clock
+const ERRNO_SUCCESS: i32 = 0;
+const ERRNO_FAULT: i32 = 21;
+const ERRNO_INVAL: i32 = 28;
+// `realtime`, `monotonic`, `process_cputime_id` and `thread_cputime_id`
+const CLOCK_IDS: std::ops::Range<i32> = 0..4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeterminismConfig {
+    pub seed: u64,
+    // Seconds since the Unix epoch, e.g. the time of the invocation being replayed
+    pub clock_secs: u64,
+}
+
+thread_local! {
+    // The time of the ledger entries made on the thread, see `ledger::now_secs`
+    static PINNED_CLOCK: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+// The pinned time of the invocation running on the thread, if it is deterministic
+pub(crate) fn pinned_secs() -> Option<u64> {
+    PINNED_CLOCK.with(Cell::get)
+}
+
+// A deterministic invocation, whose clock stays pinned until it is dropped
+pub(crate) struct Run {
+    pub trace_id: String,
+    previous: Option<u64>,
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        PINNED_CLOCK.with(|clock| clock.set(self.previous));
+    }
+}
+
+// Starts an invocation, reseeding the randomness and pinning the clock if the state is in the
+// deterministic mode
+pub(crate) fn begin(state: &mut State) -> Option<Run> {
+    let config = state.determinism?;
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let trace_id = trace::new_id_from(&mut rng);
+    *state.random.lock().unwrap() = StdRng::seed_from_u64(rng.next_u64());
+    let previous = PINNED_CLOCK.with(|clock| clock.replace(Some(config.clock_secs)));
+    Some(Run { trace_id, previous })
+}
+
+// Fails on the imports of the module that would make its runs nondeterministic
+pub fn check_import(import: &ImportType<'_>) -> Result<(), Error> {
+    let nondeterministic = match import.module() {
+        "wasi_snapshot_preview1" | "wasi_unstable" => {
+            NONDETERMINISTIC_WASI_IMPORTS.contains(&import.name())
+        }
+        // The threads of `wasi-threads`
+        "wasi" => import.name() == "thread-spawn",
+        _ => false,
+    };
+    match nondeterministic {
+        true => Err(
+            HostError::Nondeterministic(format!("{}.{}", import.module(), import.name())).into(),
+        ),
+        false => Ok(()),
+    }
+}
+
+// The WASI clock functions reading the pinned clock, `None` for the other imports
+pub(crate) fn resolve_import(
+    store: &mut Store<WasmtimeState>,
+    import: &ImportType<'_>,
+    config: DeterminismConfig,
+) -> Option<Extern> {
+    if !matches!(import.module(), "wasi_snapshot_preview1" | "wasi_unstable") {
+        return None;
+    }
+    let nanos = config.clock_secs.saturating_mul(1_000_000_000);
+    let write = |caller: &mut Caller<'_, WasmtimeState>, id: i32, ptr: i32, value: u64| {
+        if !CLOCK_IDS.contains(&id) {
+            return ERRNO_INVAL;
+        }
+        match guest_memory::write(caller, ptr, 8, &value.to_le_bytes()) {
+            Ok(8) => ERRNO_SUCCESS,
+            _ => ERRNO_FAULT,
+        }
+    };
+    let func = match import.name() {
+        "clock_time_get" => Func::wrap(
+            store,
+            move |mut caller: Caller<'_, WasmtimeState>, id: i32, _precision: i64, ptr: i32| {
+                write(&mut caller, id, ptr, nanos)
+            },
+        ),
+        "clock_res_get" => Func::wrap(
+            store,
+            move |mut caller: Caller<'_, WasmtimeState>, id: i32, ptr: i32| {
+                write(&mut caller, id, ptr, 1)
+            },
+        ),
+        _ => return None,
+    };
+    Some(func.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::RuntimeConfig,
+        runtime::{WasmRuntime, WasmtimeRuntime},
+        store::UserStore,
+        UserId,
+    };
+
+    const USER: UserId = UserId(0);
+
+    const CONFIG: DeterminismConfig = DeterminismConfig {
+        seed: 42,
+        clock_secs: 1_700_000_000,
+    };
+
+    // Returns the realtime clock read from WASI and 8 random bytes from `random_get`
+    const MODULE: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "clock_time_get"
+                (func $clock_time_get (param i32 i64 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "random_get"
+                (func $random_get (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "clock") (result i64)
+                (drop (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 0)))
+                (i64.load (i32.const 0)))
+            (func (export "random") (result i64)
+                (drop (call $random_get (i32.const 8) (i32.const 8)))
+                (i64.load (i32.const 8))))
+    "#;
+
+    fn runtime() -> WasmtimeRuntime {
+        WasmtimeRuntime::with_config(&RuntimeConfig {
+            determinism: Some(CONFIG),
+            ..RuntimeConfig::default()
+        })
+        .unwrap()
+    }
+
+    // The trace id, the clock and the random bytes seen by a fresh invocation
+    fn run(runtime: &WasmtimeRuntime) -> (String, i64, i64) {
+        let mut store = runtime.new_store(State::new(UserStore::new()));
+        let module = runtime.compile(MODULE.as_bytes()).unwrap();
+        let instance = runtime.instantiate(&mut store, &module, USER).unwrap();
+        let run = begin(runtime.state_mut(&mut store)).unwrap();
+        assert_eq!(pinned_secs(), Some(CONFIG.clock_secs));
+        let clock = runtime.call(&mut store, &instance, "clock").unwrap();
+        let random = runtime.call(&mut store, &instance, "random").unwrap();
+        let trace_id = run.trace_id.clone();
+        drop(run);
+        assert_eq!(pinned_secs(), None);
+        (trace_id, clock, random)
+    }
+
+    #[test]
+    fn invocations_are_replayed_identically() {
+        let runtime = runtime();
+        let first = run(&runtime);
+        assert_eq!(first.1, 1_700_000_000 * 1_000_000_000);
+        assert_eq!(run(&runtime), first);
+
+        let mut state = State::new(UserStore::new());
+        assert!(begin(&mut state).is_none());
+    }
+
+    #[test]
+    fn nondeterministic_imports_are_rejected() {
+        let runtime = runtime();
+        let mut store = runtime.new_store(State::new(UserStore::new()));
+        let module = r#"
+            (module
+                (import "wasi_snapshot_preview1" "poll_oneoff"
+                    (func (param i32 i32 i32 i32) (result i32))))
+        "#;
+        let module = runtime.compile(module.as_bytes()).unwrap();
+        let rejected = runtime.instantiate(&mut store, &module, USER);
+        assert!(matches!(
+            rejected,
+            Err(Error::Host(HostError::Nondeterministic(import)))
+                if import == "wasi_snapshot_preview1.poll_oneoff"
+        ));
+    }
+}
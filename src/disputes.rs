@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ledger::{self, EntryKind, LedgerEntry},
+    money::MoneyUnit,
+    policy::{self, Action},
+    BillingError, Error, State, UserId,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct DisputeId(pub u64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisputeStatus {
+    Open,
+    Refunded,
+    Denied,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    Refund,
+    Deny,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Actor {
+    User(UserId),
+    Operator(String),
+}
+
+// A transition of a dispute into `status`
+#[derive(Clone, Debug)]
+pub struct DisputeEvent {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    pub dispute: DisputeId,
+    pub actor: Actor,
+    pub status: DisputeStatus,
+    pub note: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Dispute {
+    pub id: DisputeId,
+    pub user: UserId,
+    // Index of the disputed entry in the user's ledger
+    pub entry: usize,
+    // The disputed charge, always positive
+    pub amount: MoneyUnit,
+    pub status: DisputeStatus,
+    // Every transition of the dispute, oldest first. The trail is never rewritten.
+    pub audit_trail: Vec<DisputeEvent>,
+}
+
+// Notified about every transition of every dispute, e.g. to alert the operators
+// or to forward the events to an external audit log.
+pub trait DisputeListener {
+    fn on_event(&mut self, dispute: &Dispute, event: &DisputeEvent);
+}
+
+pub struct NoopDisputeListener;
+
+impl DisputeListener for NoopDisputeListener {
+    fn on_event(&mut self, _dispute: &Dispute, _event: &DisputeEvent) {}
+}
+
+#[derive(Default)]
+pub struct Disputes {
+    disputes: BTreeMap<DisputeId, Dispute>,
+    next_id: u64,
+}
+
+impl Disputes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, id: DisputeId) -> Option<&Dispute> {
+        self.disputes.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Dispute> {
+        self.disputes.values()
+    }
+
+    pub fn of_user(&self, user: UserId) -> impl Iterator<Item = &Dispute> {
+        self.iter().filter(move |dispute| dispute.user == user)
+    }
+}
+
+fn record(state: &mut State, id: DisputeId, actor: Actor, status: DisputeStatus, note: String) {
+    let dispute = state.disputes.disputes.get_mut(&id).unwrap();
+    let event = DisputeEvent {
+        at: ledger::now_secs(),
+        dispute: id,
+        actor,
+        status,
+        note,
+    };
+    dispute.status = status;
+    dispute.audit_trail.push(event.clone());
+    state.dispute_listener.on_event(dispute, &event);
+}
+
+// Opens a dispute against a charge in the user's ledger. Until the dispute is resolved,
+// the charged amount is frozen in `UserData::frozen`: it is not yet earned by the operator
+// but not returned to the user either. Every charge can be disputed at most once.
+pub fn open(
+    state: &mut State,
+    user: UserId,
+    entry: usize,
+    reason: impl Into<String>,
+) -> Result<DisputeId, Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let disputed = user_data.ledger_entry(entry)?;
+    let already_disputed = state
+        .disputes
+        .of_user(user)
+        .any(|dispute| dispute.entry == entry);
+    if !disputed.kind.is_billable() || already_disputed {
+        return Err(BillingError::NotDisputable.into());
+    }
+    let amount = disputed
+        .amount
+        .checked_neg()
+        .ok_or(BillingError::TotalCostExceededMaxValue)?;
+    let frozen = (user_data.frozen + amount)?;
+    drop(user_data);
+
+    state.users.get_mut(&user).unwrap().frozen = frozen;
+    let id = DisputeId(state.disputes.next_id);
+    state.disputes.next_id += 1;
+    state.disputes.disputes.insert(
+        id,
+        Dispute {
+            id,
+            user,
+            entry,
+            amount,
+            status: DisputeStatus::Open,
+            audit_trail: Vec::new(),
+        },
+    );
+    record(
+        state,
+        id,
+        Actor::User(user),
+        DisputeStatus::Open,
+        reason.into(),
+    );
+    Ok(id)
+}
+
+// Resolves an open dispute on behalf of the operator, unfreezing the disputed amount.
+// A refund credits it back to the user's balance.
+pub fn resolve(
+    state: &mut State,
+    id: DisputeId,
+    operator: impl Into<String>,
+    resolution: Resolution,
+    note: impl Into<String>,
+) -> Result<(), Error> {
+    let dispute = state.disputes.get(id).ok_or(BillingError::UnknownDispute)?;
+    if dispute.status != DisputeStatus::Open {
+        return Err(BillingError::DisputeClosed.into());
+    }
+    let (user, amount) = (dispute.user, dispute.amount);
+    if resolution == Resolution::Refund {
+        policy::authorize(state, user, Action::Refund, amount)?;
+    }
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let frozen = (user_data.frozen - amount)?;
+    let balance = (user_data.balance + amount)?;
+    drop(user_data);
+    let status = match resolution {
+        Resolution::Refund => {
+            let user_data = state.users.get_mut(&user).unwrap();
+            user_data.balance = balance;
+            user_data.ledger.push(LedgerEntry::new(
+                EntryKind::DisputeRefund { dispute: id },
+                amount,
+                balance,
+            ));
+            DisputeStatus::Refunded
+        }
+        Resolution::Deny => DisputeStatus::Denied,
+    };
+    state.users.get_mut(&user).unwrap().frozen = frozen;
+    record(
+        state,
+        id,
+        Actor::Operator(operator.into()),
+        status,
+        note.into(),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{billing, store::UserStore, UserData};
+
+    const USER: UserId = UserId(0);
+
+    // A user with 100.00 who has ordered 10 days of hosting, the first entry of the ledger
+    fn state() -> (State, MoneyUnit) {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(10_000)));
+        let mut state = State::new(users);
+        let user_data = state.users.get_mut(&USER).unwrap();
+        billing::order_hosting(user_data, &state.config.hosting, &state.config.grace, 10).unwrap();
+        let charged = billing::hosting_cost(&state.config.hosting, 10).unwrap();
+        (state, charged)
+    }
+
+    #[test]
+    fn refund_unfreezes_and_credits_the_charge() {
+        let (mut state, charged) = state();
+        let balance = state.users.get(&USER).unwrap().balance;
+        let id = open(&mut state, USER, 0, "never used").unwrap();
+        assert_eq!(state.users.get(&USER).unwrap().frozen, charged);
+
+        resolve(&mut state, id, "operator", Resolution::Refund, "goodwill").unwrap();
+        let user_data = state.users.get(&USER).unwrap();
+        assert_eq!(user_data.frozen, MoneyUnit::from_cents(0));
+        assert_eq!(user_data.balance, (balance + charged).unwrap());
+        assert_eq!(
+            user_data.ledger.last().unwrap().kind,
+            EntryKind::DisputeRefund { dispute: id }
+        );
+        let statuses = state.disputes.get(id).unwrap().audit_trail.iter();
+        let statuses = statuses.map(|event| event.status).collect::<Vec<_>>();
+        assert_eq!(statuses, [DisputeStatus::Open, DisputeStatus::Refunded]);
+    }
+
+    #[test]
+    fn charges_are_disputed_and_resolved_once() {
+        let (mut state, _) = state();
+        let balance = state.users.get(&USER).unwrap().balance;
+        let id = open(&mut state, USER, 0, "never used").unwrap();
+        assert!(matches!(
+            open(&mut state, USER, 0, "again"),
+            Err(Error::Billing(BillingError::NotDisputable))
+        ));
+        assert!(matches!(
+            open(&mut state, USER, 1, "no such entry"),
+            Err(Error::Billing(BillingError::InvalidArgumentValue))
+        ));
+
+        resolve(&mut state, id, "operator", Resolution::Deny, "used").unwrap();
+        assert!(matches!(
+            resolve(&mut state, id, "operator", Resolution::Refund, ""),
+            Err(Error::Billing(BillingError::DisputeClosed))
+        ));
+        let user_data = state.users.get(&USER).unwrap();
+        assert_eq!(user_data.balance, balance);
+        assert_eq!(user_data.frozen, MoneyUnit::from_cents(0));
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    config::DomainConfig,
+    ledger::{self, EntryKind, LedgerEntry},
+    money::MoneyUnit,
+    store::UserStore,
+    BillingError, Error, UserId,
+};
+
+const DAYS_PER_YEAR: u32 = 365;
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DomainRecord {
+    pub owner: UserId,
+    // Seconds since the Unix epoch
+    pub registered_at: u64,
+    // Days until the next renewal
+    pub days_left: u32,
+}
+
+// The names registered by all the users. A name is held by at most one user.
+#[derive(Clone, Debug, Default)]
+pub struct DomainRegistry {
+    domains: BTreeMap<String, DomainRecord>,
+}
+
+// The answer to a WHOIS-like query
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Whois<'a> {
+    Available,
+    Registered(&'a DomainRecord),
+}
+
+// Names are case-insensitive and stored in lowercase. They consist of at least two
+// dot-separated labels of ASCII letters, digits and inner hyphens.
+pub fn normalize(name: &str) -> Result<String, Error> {
+    let name = name.to_ascii_lowercase();
+    let valid_label = |label: &str| {
+        (1..=MAX_LABEL_LEN).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    };
+    if name.len() > MAX_NAME_LEN || name.split('.').count() < 2 || !name.split('.').all(valid_label)
+    {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    Ok(name)
+}
+
+pub fn whois<'a>(users: &'a UserStore, name: &str) -> Result<Whois<'a>, Error> {
+    Ok(match users.domains.domains.get(&normalize(name)?) {
+        Some(record) => Whois::Registered(record),
+        None => Whois::Available,
+    })
+}
+
+pub fn is_available(users: &UserStore, name: &str) -> Result<bool, Error> {
+    Ok(whois(users, name)? == Whois::Available)
+}
+
+// The names held by the user, in alphabetical order
+pub fn domains_of(users: &UserStore, user: UserId) -> impl Iterator<Item = &str> {
+    users
+        .domains
+        .domains
+        .iter()
+        .filter(move |(_, record)| record.owner == user)
+        .map(|(name, _)| name.as_str())
+}
+
+// The price of registering a name for the years
+pub fn cost(config: &DomainConfig, years: i32) -> Result<MoneyUnit, Error> {
+    let years = u32::try_from(years)
+        .ok()
+        .filter(|years| (1..=config.max_years).contains(years))
+        .ok_or(BillingError::InvalidArgumentValue)?;
+    Ok((config.price_per_year * years as i64).ok_or(BillingError::TotalCostExceededMaxValue)?)
+}
+
+pub fn register(
+    users: &mut UserStore,
+    config: &DomainConfig,
+    user: UserId,
+    name: &str,
+    years: i32,
+) -> Result<(), Error> {
+    let name = normalize(name)?;
+    let total_cost = cost(config, years)?;
+    let years = years as u32;
+    if users.domains.domains.contains_key(&name) {
+        return Err(BillingError::DomainTaken.into());
+    }
+    let user_data = users.get_mut(&user).ok_or(BillingError::UnknownUser)?;
+    user_data.balance = (user_data.balance - total_cost)?;
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::DomainRegistration {
+            domain: name.clone(),
+            years,
+        },
+        total_cost.checked_neg().unwrap(),
+        user_data.balance,
+    ));
+    users.domains.domains.insert(
+        name,
+        DomainRecord {
+            owner: user,
+            registered_at: ledger::now_secs(),
+            days_left: years * DAYS_PER_YEAR,
+        },
+    );
+    Ok(())
+}
+
+// Counts down the registrations by a day, renewing the due ones for another year.
+// The names whose owners cannot pay for the renewal are released.
+pub(crate) fn advance_day(users: &mut UserStore, config: &DomainConfig) {
+    let mut due = Vec::new();
+    for (name, record) in users.domains.domains.iter_mut() {
+        record.days_left = record.days_left.saturating_sub(1);
+        if record.days_left == 0 {
+            due.push((name.clone(), record.owner));
+        }
+    }
+    for (name, owner) in due {
+        let renewed = users.get_mut(&owner).is_some_and(|user_data| {
+            let Ok(balance) = user_data.balance - config.price_per_year else {
+                return false;
+            };
+            user_data.balance = balance;
+            user_data.ledger.push(LedgerEntry::new(
+                EntryKind::DomainRenewal {
+                    domain: name.clone(),
+                },
+                config.price_per_year.checked_neg().unwrap(),
+                balance,
+            ));
+            true
+        });
+        if renewed {
+            users.domains.domains.get_mut(&name).unwrap().days_left = DAYS_PER_YEAR;
+        } else {
+            users.domains.domains.remove(&name);
+        }
+    }
+}
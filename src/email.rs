@@ -0,0 +1,256 @@
+use crate::{
+    config::EmailConfig,
+    ledger::{self, EntryKind, LedgerEntry},
+    trace, BillingError, Error, State, UserData, UserId,
+};
+
+// Seconds of the sliding window of `EmailConfig::max_per_minute`
+const RATE_LIMIT_WINDOW: u64 = 60;
+const MAX_ADDRESS_LEN: usize = 254;
+const MAX_SUBJECT_LEN: usize = 998;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Email {
+    pub from: UserId,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Delivers the emails paid for by the users
+pub trait EmailTransport {
+    fn send(&mut self, email: &Email) -> Result<(), Error>;
+}
+
+// Writes the emails to the standard error instead of delivering them, e.g. for development
+pub struct LogTransport;
+
+impl EmailTransport for LogTransport {
+    fn send(&mut self, email: &Email) -> Result<(), Error> {
+        eprintln!(
+            "{}Email from user {} to {}: {}\n{}",
+            trace::log_prefix(),
+            email.from.0,
+            email.to,
+            email.subject,
+            email.body
+        );
+        Ok(())
+    }
+}
+
+#[cfg(feature = "smtp")]
+fn email_error(e: impl ToString) -> Error {
+    crate::HostError::Email(e.to_string()).into()
+}
+
+// Delivers the emails through an SMTP relay over TLS
+#[cfg(feature = "smtp")]
+pub struct SmtpTransport {
+    transport: lettre::SmtpTransport,
+    // The address all the emails are sent from
+    sender: lettre::message::Mailbox,
+}
+
+#[cfg(feature = "smtp")]
+impl SmtpTransport {
+    pub fn new(relay: &str, username: &str, password: &str, sender: &str) -> Result<Self, Error> {
+        let transport = lettre::SmtpTransport::relay(relay)
+            .map_err(email_error)?
+            .credentials(lettre::transport::smtp::authentication::Credentials::new(
+                username.to_owned(),
+                password.to_owned(),
+            ))
+            .build();
+        let sender = sender.parse().map_err(email_error)?;
+        Ok(Self { transport, sender })
+    }
+}
+
+#[cfg(feature = "smtp")]
+impl EmailTransport for SmtpTransport {
+    fn send(&mut self, email: &Email) -> Result<(), Error> {
+        use lettre::Transport;
+
+        let message = lettre::Message::builder()
+            .from(self.sender.clone())
+            .to(email.to.parse().map_err(email_error)?)
+            .subject(email.subject.clone())
+            .body(email.body.clone())
+            .map_err(email_error)?;
+        self.transport
+            .send(&message)
+            .map(|_| ())
+            .map_err(email_error)
+    }
+}
+
+// A plausible `local@domain` address. Line breaks are rejected everywhere
+// in the headers so that guests cannot inject headers of their own.
+fn validate(to: &str, subject: &str) -> Result<(), Error> {
+    let valid_address = match to.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.contains('@')
+                && to.len() <= MAX_ADDRESS_LEN
+                && !to.contains(|c: char| c.is_whitespace() || c.is_control())
+        }
+        None => false,
+    };
+    if !valid_address || subject.len() > MAX_SUBJECT_LEN || subject.contains(['\r', '\n']) {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    Ok(())
+}
+
+// Checks the daily quota and the spam-preventing rate limit, forgetting the sends
+// that have left the rate limit window.
+fn check_limits(user_data: &mut UserData, config: &EmailConfig, now: u64) -> Result<(), Error> {
+    if user_data.emails_sent_today >= config.daily_quota {
+        return Err(Error::from(BillingError::EmailQuotaExceeded)
+            .with_context("limit", config.daily_quota as i64)
+            .with_context("sent_today", user_data.emails_sent_today as i64));
+    }
+    let recent = &mut user_data.recent_emails;
+    while recent
+        .front()
+        .is_some_and(|&at| at + RATE_LIMIT_WINDOW <= now)
+    {
+        recent.pop_front();
+    }
+    if recent.len() >= config.max_per_minute as usize {
+        return Err(BillingError::EmailRateLimited.into());
+    }
+    Ok(())
+}
+
+// Sends the email on behalf of the user. Nothing is charged if the email
+// is rejected by the limits or fails to be delivered.
+pub fn send(
+    state: &mut State,
+    user: UserId,
+    to: &str,
+    subject: &str,
+    body: &str,
+) -> Result<(), Error> {
+    let config = state.config.email;
+    validate(to, subject)?;
+    let transport = state
+        .email_transport
+        .as_mut()
+        .ok_or(BillingError::EmailUnavailable)?;
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    let now = ledger::now_secs();
+    check_limits(user_data, &config, now)?;
+    let balance = (user_data.balance - config.price_per_email)?;
+
+    transport.send(&Email {
+        from: user,
+        to: to.to_owned(),
+        subject: subject.to_owned(),
+        body: body.to_owned(),
+    })?;
+    user_data.balance = balance;
+    user_data.emails_sent_today += 1;
+    user_data.recent_emails.push_back(now);
+    if !config.price_per_email.is_zero() {
+        user_data.ledger.push(LedgerEntry::new(
+            EntryKind::EmailSent { to: to.to_owned() },
+            config.price_per_email.checked_neg().unwrap(),
+            balance,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{money::MoneyUnit, store::UserStore};
+
+    const USER: UserId = UserId(0);
+
+    // Keeps the emails sent through it
+    struct Outbox(Arc<Mutex<Vec<Email>>>);
+
+    impl EmailTransport for Outbox {
+        fn send(&mut self, email: &Email) -> Result<(), Error> {
+            self.0.lock().unwrap().push(email.clone());
+            Ok(())
+        }
+    }
+
+    // A user with 1.00 who may send two emails a day, one per minute, at 0.10 each
+    fn state() -> (State, Arc<Mutex<Vec<Email>>>) {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(100)));
+        let mut state = State::new(users);
+        state.config.email = EmailConfig {
+            price_per_email: MoneyUnit::from_cents(10),
+            daily_quota: 2,
+            max_per_minute: 1,
+        };
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        state.email_transport = Some(Box::new(Outbox(sent.clone())));
+        (state, sent)
+    }
+
+    #[test]
+    fn sent_emails_are_charged() {
+        let (mut state, sent) = state();
+        send(&mut state, USER, "a@example.com", "Hello", "Body").unwrap();
+        assert_eq!(sent.lock().unwrap()[0].to, "a@example.com");
+        let user_data = state.users.get(&USER).unwrap();
+        assert_eq!(user_data.balance, MoneyUnit::from_cents(90));
+        assert_eq!(user_data.emails_sent_today, 1);
+        assert_eq!(
+            user_data.ledger[0].kind,
+            EntryKind::EmailSent {
+                to: "a@example.com".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn rejected_emails_are_not_charged() {
+        let (mut state, sent) = state();
+        for (to, subject) in [
+            ("example.com", "Hello"),
+            ("a@example.com", "Bcc: b@example.com\n"),
+        ] {
+            assert!(matches!(
+                send(&mut state, USER, to, subject, ""),
+                Err(Error::Billing(BillingError::InvalidArgumentValue))
+            ));
+        }
+        send(&mut state, USER, "a@example.com", "Hello", "").unwrap();
+        assert!(matches!(
+            send(&mut state, USER, "a@example.com", "Again", ""),
+            Err(Error::Billing(BillingError::EmailRateLimited))
+        ));
+        state.users.get_mut(&USER).unwrap().emails_sent_today = 2;
+        let quota = send(&mut state, USER, "a@example.com", "Again", "").unwrap_err();
+        assert_eq!(quota.code(), BillingError::EmailQuotaExceeded.code());
+        assert_eq!(quota.context(), [("limit", 2), ("sent_today", 2)]);
+
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        let user_data = state.users.get(&USER).unwrap();
+        assert_eq!(user_data.balance, MoneyUnit::from_cents(90));
+    }
+
+    #[test]
+    fn emails_need_a_transport() {
+        let (mut state, _) = state();
+        state.email_transport = None;
+        assert!(matches!(
+            send(&mut state, USER, "a@example.com", "Hello", ""),
+            Err(Error::Billing(BillingError::EmailUnavailable))
+        ));
+    }
+}
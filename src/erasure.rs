@@ -0,0 +1,248 @@
+// Deletion of accounts, e.g. at the request of their owners, see `users delete` of the CLI.
+// `close` stops the account: it is suspended, its services deprovisioned, its API tokens
+// revoked and its scheduled jobs cancelled, its data kept. `erase` also erases the personal
+// data of the account across the stores, e.g. for a request under the GDPR: the stored objects,
+// the files, the database, the secrets, the tickets, the execution history, the messages, the
+// metrics and the retained data of the deprovisioned services, and every detail of the account
+// record. The ledger, the balance and the invoices are kept for accounting, with the entries
+// anonymized: the addresses, the domains and the traces are replaced or dropped. Every erasure
+// is recorded with an `ErasureCertificate` in a tamper-evident audit log, see `audit`.
+
+use std::{collections::BTreeMap, io::ErrorKind};
+
+use serde::Serialize;
+
+use crate::{
+    auth,
+    ledger::{self, EntryKind, LedgerEntry},
+    plan::Plan,
+    quota,
+    secrets::Accessor,
+    sha256_hex, storage, BillingError, Error, HostError, State, UserData, UserId,
+};
+
+// What replaces the personal data in the anonymized ledger entries
+const ERASED: &str = "[erased]";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deletion {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    // Whether the personal data of the account has been erased
+    pub erased: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ErasureCertificate {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    pub user: UserId,
+    // The number of the erased items by their kind, e.g. `secrets`
+    pub erased: BTreeMap<&'static str, u64>,
+    // The ledger entries kept, anonymized
+    pub retained_entries: u64,
+    // The hex-encoded SHA-256 of the JSON of the anonymized ledger, proving what was kept
+    pub ledger_hash: String,
+}
+
+fn storage_error(e: impl ToString) -> Error {
+    HostError::Storage(e.to_string()).into()
+}
+
+// Stops the account, keeping its data. Closing a closed account does nothing.
+pub fn close(state: &mut State, user: UserId) -> Result<(), Error> {
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    if user_data.deleted.is_some() {
+        return Ok(());
+    }
+    user_data.plan = Plan::Suspended;
+    user_data.deleted = Some(Deletion {
+        at: ledger::now_secs(),
+        erased: false,
+    });
+    for service in std::mem::take(&mut user_data.services) {
+        state.provisioner.deprovision(user, &service);
+    }
+    auth::revoke_all_tokens(&mut state.users, user);
+    state.cron_jobs.remove_user(user);
+    Ok(())
+}
+
+// The entry without the personal data in its kind and without its trace
+fn anonymize(entry: &mut LedgerEntry) {
+    entry.trace_id = None;
+    match &mut entry.kind {
+        EntryKind::EmailSent { to } => *to = ERASED.to_owned(),
+        EntryKind::DomainRegistration { domain, .. }
+        | EntryKind::DomainRenewal { domain }
+        | EntryKind::CertificateIssued { domain } => *domain = ERASED.to_owned(),
+        _ => {}
+    }
+}
+
+// Erases the data the account keeps outside of its record, failing on the first backend that
+// fails, after which the erasure can be retried
+fn erase_backends(
+    state: &mut State,
+    user: UserId,
+    erased: &mut BTreeMap<&'static str, u64>,
+) -> Result<(), Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    if let Some(object_store) = state.object_store.as_mut() {
+        for key in user_data.objects.keys() {
+            object_store.delete(&storage::object_path(user, key)?)?;
+        }
+    }
+    erased.insert("objects", user_data.objects.len() as u64);
+    if let (Some(_), Some(backend)) = (&user_data.database, state.database_backend.as_mut()) {
+        backend.wipe(user)?;
+        erased.insert("databases", 1);
+    }
+    if let Some(dir) = quota::user_dir(&state.config.files, user) {
+        match std::fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(storage_error(e)),
+            _ => {}
+        }
+    }
+    // The directories of the template of the account, see `templates`
+    for dir in user_data.dirs.values() {
+        match std::fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(storage_error(e)),
+            _ => {}
+        }
+    }
+    let retained = state.retained_data.remove_user(user);
+    for service in &retained {
+        state.provisioner.delete_data(user, service)?;
+    }
+    erased.insert("retained_services", retained.len() as u64);
+    Ok(())
+}
+
+// Closes the account and erases its personal data, returning the certificate of the erasure,
+// which is also appended to `State::erasure_log`
+pub fn erase(state: &mut State, user: UserId) -> Result<ErasureCertificate, Error> {
+    let deleted = state
+        .users
+        .get(&user)
+        .ok_or(BillingError::UnknownUser)?
+        .deleted;
+    if deleted.is_some_and(|deletion| deletion.erased) {
+        return Err(BillingError::AccountErased.into());
+    }
+    close(state, user)?;
+    let mut erased = BTreeMap::new();
+    erase_backends(state, user, &mut erased)?;
+    erased.insert(
+        "secrets",
+        state.secrets.delete_all(user, Accessor::Operator) as u64,
+    );
+    erased.insert("tickets", state.tickets.remove_user(user) as u64);
+    state.guest_metrics.remove_user(user);
+
+    let user_data = state.users.get_mut(&user).unwrap();
+    erased.insert("executions", user_data.executions.len() as u64);
+    erased.insert("messages", user_data.inbox.len() as u64);
+    erased.insert("certificates", user_data.certificates.len() as u64);
+    erased.insert("metadata", user_data.metadata.len() as u64);
+    for entry in user_data.ledger.iter_mut() {
+        anonymize(entry);
+    }
+    // Only what accounting requires is carried over to the emptied record
+    let old = std::mem::replace(user_data, UserData::new(user_data.balance));
+    user_data.plan = Plan::Suspended;
+    user_data.ledger = old.ledger;
+    user_data.archived_entries = old.archived_entries;
+    user_data.ledger_summaries = old.ledger_summaries;
+    user_data.frozen = old.frozen;
+    user_data.balance_history = old.balance_history;
+    user_data.postpaid = old.postpaid;
+    user_data.referred_by = old.referred_by;
+    user_data.reseller = old.reseller;
+    user_data.deleted = Some(Deletion {
+        at: ledger::now_secs(),
+        erased: true,
+    });
+
+    let ledger_json = serde_json::to_vec(&user_data.ledger).map_err(storage_error)?;
+    let certificate = ErasureCertificate {
+        at: ledger::now_secs(),
+        user,
+        erased,
+        retained_entries: user_data.ledger.len() as u64,
+        ledger_hash: sha256_hex(&ledger_json),
+    };
+    state.erasure_log.append(certificate.clone());
+    Ok(certificate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Scope, host, money::MoneyUnit, store::UserStore};
+
+    const USER: UserId = UserId(0);
+
+    // A user with 100.00 who has ordered 10 days of hosting, with a token, a secret and metadata
+    fn state() -> (State, String) {
+        let mut users = UserStore::new();
+        let mut user_data = UserData::new(MoneyUnit::from_cents(10_000));
+        user_data
+            .metadata
+            .insert("email".to_owned(), "a@example.com".to_owned());
+        users.insert(USER, user_data);
+        let mut state = State::new(users);
+        host::order_hosting(&mut state, USER, 10).unwrap();
+        let token = auth::issue_token(&mut state.users, USER, Scope::Write).unwrap();
+        let secrets = &mut state.secrets;
+        secrets
+            .set(USER, "key", b"value", Accessor::Operator)
+            .unwrap();
+        (state, token)
+    }
+
+    #[test]
+    fn erasure_keeps_only_the_anonymized_ledger() {
+        let (mut state, token) = state();
+        let certificate = erase(&mut state, USER).unwrap();
+        assert_eq!(certificate.user, USER);
+        assert_eq!(certificate.erased["secrets"], 1);
+        assert_eq!(certificate.erased["metadata"], 1);
+        assert_eq!(certificate.retained_entries, 1);
+        assert_eq!(state.erasure_log.entries().len(), 1);
+        assert_eq!(state.erasure_log.entries()[0].event, certificate);
+
+        let user_data = state.users.get(&USER).unwrap();
+        assert_eq!(user_data.plan, Plan::Suspended);
+        assert_eq!(user_data.balance, MoneyUnit::from_cents(9_000));
+        assert_eq!(user_data.ledger.len(), 1);
+        assert!(user_data.metadata.is_empty());
+        assert!(user_data.deleted.is_some_and(|deletion| deletion.erased));
+        let ledger_json = serde_json::to_vec(&user_data.ledger).unwrap();
+        assert_eq!(certificate.ledger_hash, sha256_hex(&ledger_json));
+        drop(user_data);
+        assert!(auth::authorize(&state.users, &token, Scope::Read).is_err());
+        assert_eq!(state.secrets.names_of(USER).count(), 0);
+    }
+
+    #[test]
+    fn erased_and_unknown_accounts_are_not_erased() {
+        let (mut state, _) = state();
+        close(&mut state, USER).unwrap();
+        close(&mut state, USER).unwrap();
+        assert!(state.users.get(&USER).unwrap().deleted.is_some());
+        erase(&mut state, USER).unwrap();
+        assert!(matches!(
+            erase(&mut state, USER),
+            Err(Error::Billing(BillingError::AccountErased))
+        ));
+        assert!(matches!(
+            erase(&mut state, UserId(1)),
+            Err(Error::Billing(BillingError::UnknownUser))
+        ));
+        assert_eq!(state.erasure_log.entries().len(), 1);
+    }
+}
@@ -0,0 +1,508 @@
+use strum::{EnumIter, IntoEnumIterator, IntoStaticStr};
+
+use crate::custom_error::CustomError;
+
+// The errors of the users' requests, reported to the guests by their codes.
+// The codes are part of the host API: they never change and are never reused.
+#[derive(Debug, thiserror::Error, EnumIter, IntoStaticStr)]
+pub enum BillingError {
+    #[error("Invalid argument value passed to the function.")]
+    InvalidArgumentValue,
+    #[error("The total cost of the service exceeded the maximum value.")]
+    TotalCostExceededMaxValue,
+    #[error("The balance is negative.")]
+    NegativeBalance,
+    #[error("The balance would become negative after the transaction.")]
+    BalanceWouldBecomeNegative,
+    #[error("The balance would underflow after the transaction.")]
+    BalanceWouldUnderflow,
+    #[error("The user is unknown.")]
+    UnknownUser,
+    #[error("The API token is invalid or has been revoked.")]
+    InvalidToken,
+    #[error("The API token does not grant the required scope.")]
+    InsufficientScope,
+    #[error("The balance would overflow after the transaction.")]
+    BalanceWouldOverflow,
+    #[error("Transfers to oneself are not allowed.")]
+    SelfTransfer,
+    #[error("The user lacks the capability required by the function.")]
+    MissingCapability,
+    #[error("A user with the same id already exists.")]
+    UserAlreadyExists,
+    #[error("The guest does not export its memory.")]
+    GuestMemoryMissing,
+    #[error("The guest buffer is out of the bounds of its memory.")]
+    GuestMemoryOutOfBounds,
+    // Errors registered by the embedders of the library, see `custom_error::CustomErrors`
+    #[error("{}", .0.message)]
+    Custom(CustomError),
+    #[error("The amounts are in different currencies.")]
+    CurrencyMismatch,
+    #[error("The service or bundle is unknown.")]
+    UnknownService,
+    #[error("A dependency of the service is neither ordered nor provisioned.")]
+    MissingDependency,
+    #[error("The ledger entry is not a charge or has already been disputed.")]
+    NotDisputable,
+    #[error("The dispute is unknown.")]
+    UnknownDispute,
+    #[error("The dispute has already been resolved.")]
+    DisputeClosed,
+    #[error("No object storage backend is configured.")]
+    StorageUnavailable,
+    #[error("The object would exceed the storage quota of the user.")]
+    StorageQuotaExceeded,
+    #[error("The object does not exist.")]
+    UnknownObject,
+    #[error("The domain name is already registered.")]
+    DomainTaken,
+    #[error("No email transport is configured.")]
+    EmailUnavailable,
+    #[error("The daily email quota of the user is exhausted.")]
+    EmailQuotaExceeded,
+    #[error("Too many emails have been sent within the last minute.")]
+    EmailRateLimited,
+    #[error("No database backend is configured.")]
+    DatabaseUnavailable,
+    #[error("The user already has a database.")]
+    DatabaseExists,
+    #[error("The user has no active database.")]
+    NoDatabase,
+    #[error("No certificate issuer is configured.")]
+    CertificatesUnavailable,
+    #[error("The domain is not registered by the user.")]
+    DomainNotOwned,
+    #[error("The message exceeds the maximum message size.")]
+    MessageTooLarge,
+    #[error("The inbox of the recipient is full.")]
+    QueueFull,
+    #[error("The schedule is not a valid cron expression.")]
+    InvalidSchedule,
+    #[error("The module has not been added to the scheduled jobs.")]
+    UnknownModule,
+    #[error("The job does not exist.")]
+    UnknownJob,
+    #[error("The user has reached the maximum number of scheduled jobs.")]
+    TooManyJobs,
+    #[error("The secret does not exist.")]
+    UnknownSecret,
+    #[error("The locale is not supported.")]
+    UnsupportedLocale,
+    #[error("The balance is not negative, so there is nothing to write off.")]
+    NothingToWriteOff,
+    #[error("The balance at the requested time is not known.")]
+    BalanceHistoryUnavailable,
+    #[error("The order does not exist.")]
+    UnknownOrder,
+    #[error("The user has reached the maximum number of unresolved support tickets.")]
+    TicketQuotaExceeded,
+    #[error("The support ticket does not exist.")]
+    UnknownTicket,
+    #[error("The support ticket has been closed.")]
+    TicketClosed,
+    #[error("The action is denied by the operator's policy.")]
+    PolicyDenied,
+    #[error("The action is waiting for the approval of an operator.")]
+    ApprovalRequired,
+    #[error("The policy contains a line that is not a valid rule.")]
+    InvalidPolicy,
+    #[error("The approval does not exist or has already been decided.")]
+    UnknownApproval,
+    #[error("The call is not allowed while a transaction is open.")]
+    TransactionActive,
+    #[error("No transaction is open.")]
+    NoTransaction,
+    #[error("The ledger entry has been archived.")]
+    EntryArchived,
+    #[error("The user group does not exist.")]
+    UnknownGroup,
+    #[error("The user group has reached its maximum number of members.")]
+    GroupFull,
+    #[error("The order would exceed the daily spending limit of the user's group.")]
+    GroupLimitExceeded,
+    #[error("The guest does not export `alloc` as `(i32) -> i32`.")]
+    GuestAllocatorMissing,
+    #[error("The guest's `alloc` failed or returned a buffer out of the bounds of its memory.")]
+    GuestAllocationFailed,
+    #[error("The postpaid account has no unpaid invoice with this id.")]
+    UnknownInvoice,
+    #[error("The account is not postpaid.")]
+    NotPostpaid,
+    #[error("The account is already postpaid.")]
+    AlreadyPostpaid,
+    #[error("The postpaid account is suspended until its overdue invoices are paid.")]
+    InvoiceOverdue,
+    #[error("The feature is disabled on this host.")]
+    FeatureDisabled,
+    #[error("The order cannot be cancelled.")]
+    NotCancellable,
+    #[error("The call was vetoed by the authorization of the host.")]
+    CallVetoed,
+    #[error("The user has reached the limit of distinct metrics.")]
+    TooManyMetrics,
+    #[error("The user has updated their metrics too often, try again in a minute.")]
+    MetricRateLimited,
+    #[error("The marketplace listing does not exist.")]
+    UnknownListing,
+    #[error("The marketplace listing is not installed by the user.")]
+    NotInstalled,
+    #[error("No data of the service is retained for the order, or its retention has ended.")]
+    NoRetainedData,
+    #[error("The account is not a sub-account of the reseller.")]
+    NotSubAccount,
+    #[error("Too many runs of the user are in progress.")]
+    ConcurrencyLimitExceeded,
+    #[error("The configuration is invalid.")]
+    InvalidConfig,
+    #[error("The account has been deleted and its personal data erased.")]
+    AccountErased,
+    #[error("The approval must come from another operator than the proposal.")]
+    SecondApproverRequired,
+    #[error("The export is marked pure and may not call mutating host functions.")]
+    ReadOnlyExport,
+    #[error("The account template is unknown.")]
+    UnknownTemplate,
+    #[error("The buffer is too small for the next item of the page.")]
+    PageBufferTooSmall,
+    #[error("The delivery is unknown or not dead-lettered.")]
+    UnknownDelivery,
+    #[error("The spending is paused until the cost anomaly is acknowledged.")]
+    SpendCapped,
+    #[error("There is no cost anomaly to acknowledge.")]
+    NoActiveAnomaly,
+    #[error("The settlement file has been imported already.")]
+    DuplicateSettlement,
+}
+
+// Failures of the host itself, e.g. of the engine, the persistence or a service backend.
+// They can still reach the guests, but only as opaque failures whose codes may change.
+#[derive(Debug, thiserror::Error, EnumIter, IntoStaticStr)]
+pub enum HostError {
+    #[error("The requested import (e.g. a host function) is unknown.")]
+    UnknownImport,
+    #[error("The module failed to compile: {0}")]
+    CompilationFailed(String),
+    #[error("The module failed to instantiate: {0}")]
+    InstantiationFailed(String),
+    #[error("The call to the guest function failed: {0}")]
+    CallFailed(String),
+    #[error("Failed to persist or load data: {0}")]
+    Persistence(String),
+    #[error("The guest instance cannot be snapshotted or restored.")]
+    Snapshot,
+    #[error("The snapshot was taken from a different module.")]
+    SnapshotModuleMismatch,
+    #[error("The service dependencies form a cycle.")]
+    DependencyCycle,
+    #[error("The service failed to provision: {0}")]
+    ProvisioningFailed(String),
+    #[error("The engine configuration is invalid: {0}")]
+    EngineConfig(String),
+    #[error("The guest was killed after missing {0} heartbeats.")]
+    MissedHeartbeats(u32),
+    #[error("The host function `{name}` is imported as {found} but its signature is {expected}.")]
+    ImportSignatureMismatch {
+        name: String,
+        expected: String,
+        found: String,
+    },
+    #[error("The object storage failed: {0}")]
+    Storage(String),
+    #[error("The email could not be sent: {0}")]
+    Email(String),
+    #[error("The database service failed: {0}")]
+    Database(String),
+    #[error("The certificate could not be issued: {0}")]
+    Certificate(String),
+    #[error("The secret could not be encrypted or decrypted with the master key.")]
+    SecretUnreadable,
+    #[error("The update kept conflicting with concurrent updates and was given up.")]
+    LockContention,
+    #[error("The daemon failed: {0}")]
+    Daemon(String),
+    #[error("The script of the mock host is invalid: {0}")]
+    MockScript(String),
+    #[error("The audit log does not match its hash chain at entry {0}.")]
+    AuditChainBroken(u64),
+    #[error("The guest needs WASI ({0}), which the host does not provide to pure guests.")]
+    WasiDisabled(String),
+    #[error("The webhook could not be notified: {0}")]
+    Webhook(String),
+    #[error("The guest ran out of fuel, including its burst credits.")]
+    FuelExhausted,
+    #[error("The guest needs {0}, which the host does not provide in the deterministic mode.")]
+    Nondeterministic(String),
+    #[error("The plan of the user does not allow the module: {0}")]
+    ModuleRestricted(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Billing(#[from] BillingError),
+    #[error(transparent)]
+    Host(#[from] HostError),
+    // The error with the values it depends on, e.g. the limit and the usage of an exceeded
+    // quota, which the guests read with `host.last_error_context`, see `abi`
+    #[error("{error}")]
+    WithContext {
+        error: Box<Error>,
+        context: Vec<(&'static str, i64)>,
+    },
+}
+
+impl BillingError {
+    // 0 is reserved for success. The host errors share the numbering, so a new variant
+    // of either enum takes the next code unused by both.
+    pub fn code(&self) -> i32 {
+        match self {
+            BillingError::InvalidArgumentValue => 1,
+            BillingError::TotalCostExceededMaxValue => 2,
+            BillingError::NegativeBalance => 3,
+            BillingError::BalanceWouldBecomeNegative => 4,
+            BillingError::BalanceWouldUnderflow => 5,
+            BillingError::UnknownUser => 10,
+            BillingError::InvalidToken => 11,
+            BillingError::InsufficientScope => 12,
+            BillingError::BalanceWouldOverflow => 13,
+            BillingError::SelfTransfer => 14,
+            BillingError::MissingCapability => 15,
+            BillingError::UserAlreadyExists => 16,
+            BillingError::GuestMemoryMissing => 18,
+            BillingError::GuestMemoryOutOfBounds => 19,
+            BillingError::Custom(custom) => custom.code,
+            BillingError::CurrencyMismatch => 23,
+            BillingError::UnknownService => 24,
+            BillingError::MissingDependency => 26,
+            BillingError::NotDisputable => 31,
+            BillingError::UnknownDispute => 32,
+            BillingError::DisputeClosed => 33,
+            BillingError::StorageUnavailable => 35,
+            BillingError::StorageQuotaExceeded => 36,
+            BillingError::UnknownObject => 37,
+            BillingError::DomainTaken => 38,
+            BillingError::EmailUnavailable => 40,
+            BillingError::EmailQuotaExceeded => 41,
+            BillingError::EmailRateLimited => 42,
+            BillingError::DatabaseUnavailable => 44,
+            BillingError::DatabaseExists => 45,
+            BillingError::NoDatabase => 46,
+            BillingError::CertificatesUnavailable => 48,
+            BillingError::DomainNotOwned => 49,
+            BillingError::MessageTooLarge => 50,
+            BillingError::QueueFull => 51,
+            BillingError::InvalidSchedule => 52,
+            BillingError::UnknownModule => 53,
+            BillingError::UnknownJob => 54,
+            BillingError::TooManyJobs => 55,
+            BillingError::UnknownSecret => 56,
+            BillingError::UnsupportedLocale => 59,
+            BillingError::NothingToWriteOff => 60,
+            BillingError::BalanceHistoryUnavailable => 61,
+            BillingError::UnknownOrder => 63,
+            BillingError::TicketQuotaExceeded => 64,
+            BillingError::UnknownTicket => 65,
+            BillingError::TicketClosed => 66,
+            BillingError::PolicyDenied => 67,
+            BillingError::ApprovalRequired => 68,
+            BillingError::InvalidPolicy => 69,
+            BillingError::UnknownApproval => 70,
+            BillingError::TransactionActive => 71,
+            BillingError::NoTransaction => 72,
+            BillingError::EntryArchived => 73,
+            BillingError::UnknownGroup => 74,
+            BillingError::GroupFull => 75,
+            BillingError::GroupLimitExceeded => 76,
+            BillingError::GuestAllocatorMissing => 80,
+            BillingError::GuestAllocationFailed => 81,
+            BillingError::UnknownInvoice => 82,
+            BillingError::NotPostpaid => 83,
+            BillingError::AlreadyPostpaid => 84,
+            BillingError::InvoiceOverdue => 85,
+            BillingError::FeatureDisabled => 87,
+            BillingError::NotCancellable => 88,
+            BillingError::CallVetoed => 89,
+            BillingError::TooManyMetrics => 90,
+            BillingError::MetricRateLimited => 91,
+            BillingError::UnknownListing => 92,
+            BillingError::NotInstalled => 93,
+            BillingError::NoRetainedData => 95,
+            BillingError::NotSubAccount => 96,
+            BillingError::ConcurrencyLimitExceeded => 97,
+            BillingError::InvalidConfig => 98,
+            BillingError::AccountErased => 101,
+            BillingError::SecondApproverRequired => 102,
+            BillingError::ReadOnlyExport => 103,
+            BillingError::UnknownTemplate => 104,
+            BillingError::PageBufferTooSmall => 105,
+            BillingError::UnknownDelivery => 106,
+            BillingError::SpendCapped => 107,
+            BillingError::NoActiveAnomaly => 108,
+            BillingError::DuplicateSettlement => 109,
+        }
+    }
+
+    // The error with the code, `None` for the codes of the host and the custom errors
+    pub fn from_code(code: i32) -> Option<Self> {
+        BillingError::iter().find(|e| !matches!(e, BillingError::Custom(_)) && e.code() == code)
+    }
+}
+
+impl HostError {
+    pub fn code(&self) -> i32 {
+        match self {
+            HostError::UnknownImport => 6,
+            HostError::CompilationFailed(_) => 7,
+            HostError::InstantiationFailed(_) => 8,
+            HostError::CallFailed(_) => 9,
+            HostError::Persistence(_) => 17,
+            HostError::Snapshot => 21,
+            HostError::SnapshotModuleMismatch => 22,
+            HostError::DependencyCycle => 25,
+            HostError::ProvisioningFailed(_) => 27,
+            HostError::EngineConfig(_) => 28,
+            HostError::MissedHeartbeats(_) => 29,
+            HostError::ImportSignatureMismatch { .. } => 30,
+            HostError::Storage(_) => 34,
+            HostError::Email(_) => 39,
+            HostError::Database(_) => 43,
+            HostError::Certificate(_) => 47,
+            HostError::SecretUnreadable => 57,
+            HostError::LockContention => 58,
+            HostError::Daemon(_) => 62,
+            HostError::MockScript(_) => 77,
+            HostError::AuditChainBroken(_) => 78,
+            HostError::Webhook(_) => 86,
+            HostError::WasiDisabled(_) => 79,
+            HostError::FuelExhausted => 94,
+            HostError::Nondeterministic(_) => 99,
+            HostError::ModuleRestricted(_) => 100,
+        }
+    }
+}
+
+impl Error {
+    pub fn code(&self) -> i32 {
+        match self {
+            Error::Billing(e) => e.code(),
+            Error::Host(e) => e.code(),
+            Error::WithContext { error, .. } => error.code(),
+        }
+    }
+
+    // The name of the variant, e.g. `UnknownUser`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Error::Billing(e) => e.into(),
+            Error::Host(e) => e.into(),
+            Error::WithContext { error, .. } => error.name(),
+        }
+    }
+
+    // Adds the value under the key to the context of the error
+    pub fn with_context(self, key: &'static str, value: i64) -> Self {
+        match self {
+            Error::WithContext { error, mut context } => {
+                context.push((key, value));
+                Error::WithContext { error, context }
+            }
+            error => Error::WithContext {
+                error: Box::new(error),
+                context: vec![(key, value)],
+            },
+        }
+    }
+
+    // The key/value pairs in the order they were added, empty if there are none
+    pub fn context(&self) -> &[(&'static str, i64)] {
+        match self {
+            Error::WithContext { context, .. } => context,
+            _ => &[],
+        }
+    }
+}
+
+impl From<CustomError> for Error {
+    fn from(custom: CustomError) -> Self {
+        BillingError::Custom(custom).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::custom_error::FIRST_CUSTOM_ERROR_CODE;
+
+    fn billing_codes() -> Vec<i32> {
+        BillingError::iter()
+            .filter(|e| !matches!(e, BillingError::Custom(_)))
+            .map(|e| e.code())
+            .collect()
+    }
+
+    #[test]
+    fn billing_codes_are_stable() {
+        assert_eq!(BillingError::InvalidArgumentValue.code(), 1);
+        assert_eq!(BillingError::BalanceWouldBecomeNegative.code(), 4);
+        assert_eq!(BillingError::UnknownUser.code(), 10);
+        assert_eq!(BillingError::MissingCapability.code(), 15);
+        assert_eq!(BillingError::QueueFull.code(), 51);
+        let codes = billing_codes();
+        assert_eq!(codes.iter().collect::<BTreeSet<_>>().len(), codes.len());
+        assert!(codes
+            .iter()
+            .all(|&code| code > 0 && code < FIRST_CUSTOM_ERROR_CODE));
+        for code in codes {
+            assert_eq!(BillingError::from_code(code).unwrap().code(), code);
+        }
+    }
+
+    #[test]
+    fn host_codes_do_not_collide_with_billing_codes() {
+        let billing = billing_codes().into_iter().collect::<BTreeSet<_>>();
+        let host = HostError::iter().map(|e| e.code()).collect::<Vec<_>>();
+        assert_eq!(host.iter().collect::<BTreeSet<_>>().len(), host.len());
+        for code in host {
+            assert!(code > 0 && code < FIRST_CUSTOM_ERROR_CODE);
+            assert!(!billing.contains(&code));
+            assert!(BillingError::from_code(code).is_none());
+        }
+    }
+
+    #[test]
+    fn custom_errors_keep_their_codes() {
+        let custom = CustomError {
+            code: FIRST_CUSTOM_ERROR_CODE,
+            message: "out of widgets".to_owned(),
+        };
+        let error = Error::from(custom);
+        assert_eq!(error.code(), FIRST_CUSTOM_ERROR_CODE);
+        assert_eq!(error.to_string(), "out of widgets");
+        assert!(BillingError::from_code(FIRST_CUSTOM_ERROR_CODE).is_none());
+    }
+
+    #[test]
+    fn context_keeps_the_code_and_message() {
+        let error = Error::from(BillingError::StorageQuotaExceeded)
+            .with_context("limit_bytes", 100)
+            .with_context("used_bytes", 150);
+        assert_eq!(error.code(), BillingError::StorageQuotaExceeded.code());
+        assert_eq!(error.name(), "StorageQuotaExceeded");
+        assert_eq!(
+            error.to_string(),
+            BillingError::StorageQuotaExceeded.to_string()
+        );
+        assert_eq!(error.context(), [("limit_bytes", 100), ("used_bytes", 150)]);
+        let decoded = crate::abi::decode_context(&crate::abi::encode_context(error.context()));
+        assert_eq!(
+            decoded.unwrap(),
+            [
+                ("limit_bytes".to_owned(), 100),
+                ("used_bytes".to_owned(), 150)
+            ]
+        );
+    }
+}
@@ -0,0 +1,96 @@
+// Feature flags of the deployment, configured by the operator in `Config::features` and
+// queried by the guests with `host.feature_enabled`, so that a single guest binary can adapt
+// to hosts configured differently. A flag may enable its feature only on some plans.
+//
+// The flags of `HOST_FEATURES` also gate the actions of the host checked by
+// `policy::authorize`, which fail with `BillingError::FeatureDisabled` while their feature
+// is disabled. They are enabled unless configured otherwise, other features are disabled.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{plan::Plan, policy::Action, BillingError, Error, State, UserId};
+
+// The features of the host itself, by the actions they gate
+pub const HOST_FEATURES: &[(&str, Action)] = &[
+    ("orders", Action::Order),
+    ("transfers", Action::Transfer),
+    ("refunds", Action::Refund),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeatureFlag {
+    pub enabled: bool,
+    // The names of the plans the feature is enabled on (`trial`, `paid`, `grace` or
+    // `suspended`), `None` for all of them
+    pub plans: Option<BTreeSet<String>>,
+}
+
+impl FeatureFlag {
+    pub fn enabled() -> Self {
+        Self {
+            enabled: true,
+            plans: None,
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            plans: None,
+        }
+    }
+
+    pub fn on_plans<'a>(plans: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            enabled: true,
+            plans: Some(plans.into_iter().map(str::to_owned).collect()),
+        }
+    }
+
+    fn is_enabled_on(&self, plan: Plan) -> bool {
+        self.enabled
+            && self
+                .plans
+                .as_ref()
+                .is_none_or(|plans| plans.contains(plan_name(plan)))
+    }
+}
+
+// The name of the plan as in the policy rules and the feature flags
+pub fn plan_name(plan: Plan) -> &'static str {
+    match plan {
+        Plan::Trial { .. } => "trial",
+        Plan::Paid => "paid",
+        Plan::Grace { .. } => "grace",
+        Plan::Suspended => "suspended",
+    }
+}
+
+// Whether the feature is enabled for the user, on the plan of the user
+pub fn is_enabled(state: &State, user: UserId, name: &str) -> Result<bool, Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    Ok(match state.config.features.get(name) {
+        Some(flag) => flag.is_enabled_on(user_data.plan),
+        None => HOST_FEATURES.iter().any(|&(feature, _)| feature == name),
+    })
+}
+
+// Enables or disables the feature for every plan, e.g. as the operator reacts to an incident
+pub fn set_enabled(features: &mut BTreeMap<String, FeatureFlag>, name: &str, enabled: bool) {
+    let flag = match enabled {
+        true => FeatureFlag::enabled(),
+        false => FeatureFlag::disabled(),
+    };
+    features.insert(name.to_owned(), flag);
+}
+
+// Fails the action while its feature is disabled for the user, see `policy::authorize`
+pub(crate) fn check_action(state: &State, user: UserId, action: Action) -> Result<(), Error> {
+    let Some(&(name, _)) = HOST_FEATURES.iter().find(|&&(_, a)| a == action) else {
+        return Ok(());
+    };
+    match is_enabled(state, user, name)? {
+        true => Ok(()),
+        false => Err(BillingError::FeatureDisabled.into()),
+    }
+}
@@ -0,0 +1,205 @@
+// User groups, e.g. "education" or "nonprofit", configured by the operator in
+// `Config::groups`. The members of a group are credited the group's discount on the charges
+// of their calls as a `EntryKind::GroupDiscount` entry, may not spend more on orders
+// a day than the group's limit, and are reported on together by `report`.
+
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use crate::{
+    ledger::{EntryKind, LedgerEntry},
+    money::{Currency, MoneySum, MoneyUnit},
+    BillingError, Error, State, UserId,
+};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GroupConfig {
+    // Discount on the charges of the members in basis points (1/100 of a percent)
+    pub discount_basis_points: u32,
+    // `None` lets the members spend without a group limit
+    pub daily_spend_limit: Option<MoneyUnit>,
+    // `None` lets any number of users join the group
+    pub max_members: Option<u32>,
+}
+
+// What the members of a group have been charged between two points in time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupReport {
+    pub group: String,
+    pub members: u32,
+    // The charges before the discounts, positive
+    pub charged: MoneyUnit,
+    pub discounts: MoneyUnit,
+    // The charges minus the discounts
+    pub revenue: MoneyUnit,
+}
+
+pub fn members(state: &State, group: &str) -> Vec<UserId> {
+    state
+        .users
+        .read()
+        .iter()
+        .filter(|(_, user_data)| user_data.group.as_deref() == Some(group))
+        .map(|(&user, _)| user)
+        .collect()
+}
+
+// Moves the user into the group, or out of any with `None`
+pub fn assign(state: &mut State, user: UserId, group: Option<&str>) -> Result<(), Error> {
+    let current = state
+        .users
+        .get(&user)
+        .ok_or(BillingError::UnknownUser)?
+        .group
+        .clone();
+    if let Some(group) = group.filter(|&group| current.as_deref() != Some(group)) {
+        let config = state
+            .config
+            .groups
+            .get(group)
+            .ok_or(BillingError::UnknownGroup)?;
+        if let Some(max_members) = config.max_members {
+            if members(state, group).len() >= max_members as usize {
+                return Err(BillingError::GroupFull.into());
+            }
+        }
+    }
+    state.users.get_mut(&user).unwrap().group = group.map(str::to_owned);
+    Ok(())
+}
+
+// The configuration of the user's group, if the user belongs to one
+pub fn group_of(state: &State, user: UserId) -> Option<&GroupConfig> {
+    let group = state.users.get(&user)?.group.clone()?;
+    state.config.groups.get(&group)
+}
+
+// Fails the orders that would take the daily spend of a member beyond the group's limit,
+// see `policy::authorize`
+pub(crate) fn check_limit(
+    state: &State,
+    user: UserId,
+    daily_spend: MoneyUnit,
+) -> Result<(), Error> {
+    let limit = group_of(state, user).and_then(|config| config.daily_spend_limit);
+    match limit {
+        Some(limit) if matches!(daily_spend.checked_cmp(limit), Ok(Ordering::Greater)) => {
+            Err(Error::from(BillingError::GroupLimitExceeded)
+                .with_context("limit_minor_units", limit.minor_units())
+                .with_context("daily_spend_minor_units", daily_spend.minor_units()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Whether the entry is charged at the group's discount. Fees and penalties are not.
+pub(crate) fn is_discounted(entry: &LedgerEntry) -> bool {
+    entry.amount.is_negative()
+        && entry.kind.is_billable()
+        && !matches!(entry.kind, EntryKind::TransferFee | EntryKind::LateFee)
+}
+
+// Credits the discount of the user's group on the entries from `before` on
+pub(crate) fn apply_discount(state: &mut State, user: UserId, before: usize) {
+    let Some(user_data) = state.users.get(&user) else {
+        return;
+    };
+    let Some(group) = user_data.group.clone() else {
+        return;
+    };
+    let Some(basis_points) = state
+        .config
+        .groups
+        .get(&group)
+        .map(|config| config.discount_basis_points)
+        .filter(|&basis_points| basis_points > 0)
+    else {
+        return;
+    };
+    let discount = user_data.ledger[before..]
+        .iter()
+        .filter(|entry| is_discounted(entry))
+        .filter_map(|entry| entry.amount.checked_neg()?.basis_points(basis_points))
+        .checked_sum(user_data.balance.currency());
+    drop(user_data);
+    let Some(discount) = discount.ok().filter(|discount| !discount.is_zero()) else {
+        return;
+    };
+    let user_data = state.users.get_mut(&user).unwrap();
+    let since = user_data.archived_entries + before;
+    // A discount that would overflow the balance is not credited
+    if let Ok(balance) = user_data.balance + discount {
+        user_data.balance = balance;
+        user_data.ledger.push(LedgerEntry::new(
+            EntryKind::GroupDiscount {
+                group,
+                since,
+                basis_points,
+            },
+            discount,
+            balance,
+        ));
+    }
+}
+
+// Aggregates the charges of the members of every configured group between the points
+// in time (seconds since the Unix epoch, `to` is exclusive), in the currency. Charges in other
+// currencies and archived entries are left out.
+pub fn report(
+    state: &State,
+    currency: Currency,
+    from: u64,
+    to: u64,
+) -> Result<Vec<GroupReport>, Error> {
+    let zero = MoneyUnit::zero(currency);
+    let mut reports = state
+        .config
+        .groups
+        .keys()
+        .map(|group| {
+            let report = GroupReport {
+                group: group.clone(),
+                members: 0,
+                charged: zero,
+                discounts: zero,
+                revenue: zero,
+            };
+            (group.as_str(), report)
+        })
+        .collect::<BTreeMap<_, _>>();
+    for (_, user_data) in state.users.read().iter() {
+        let Some(report) = user_data
+            .group
+            .as_deref()
+            .and_then(|group| reports.get_mut(group))
+        else {
+            continue;
+        };
+        report.members += 1;
+        let entries = user_data.ledger.iter().filter(|entry| {
+            (from..to).contains(&entry.at)
+                && entry.kind.is_billable()
+                && entry.amount.currency() == currency
+        });
+        for entry in entries {
+            match entry.kind {
+                EntryKind::GroupDiscount { .. } => {
+                    report.discounts = (report.discounts + entry.amount)?
+                }
+                _ => {
+                    let charge = entry
+                        .amount
+                        .checked_neg()
+                        .ok_or(BillingError::TotalCostExceededMaxValue)?;
+                    report.charged = (report.charged + charge)?;
+                }
+            }
+        }
+    }
+    reports
+        .into_values()
+        .map(|mut report| {
+            report.revenue = report.charged.sub_allowing_negative(report.discounts)?;
+            Ok(report)
+        })
+        .collect()
+}
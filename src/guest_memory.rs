@@ -0,0 +1,123 @@
+use wasmtime::{Caller, Extern, Func, Memory, TypedFunc, Val};
+
+use crate::{runtime::WasmtimeState, BillingError, Error};
+
+// Guests exchanging data with the host through buffers must export their memory under this name
+pub const MEMORY_EXPORT: &str = "memory";
+// Guests receiving variable-length data in buffers of their own export their allocator as
+// `alloc(len: i32) -> i32` and `dealloc(ptr: i32, len: i32)`, see `write_allocated`
+pub const ALLOC_EXPORT: &str = "alloc";
+pub const DEALLOC_EXPORT: &str = "dealloc";
+
+fn memory(caller: &mut Caller<'_, WasmtimeState>) -> Result<Memory, Error> {
+    match caller.get_export(MEMORY_EXPORT) {
+        Some(Extern::Memory(memory)) => Ok(memory),
+        _ => caller
+            .data()
+            .forwarded_memory
+            .ok_or(BillingError::GuestMemoryMissing.into()),
+    }
+}
+
+// Calls a host function wrapped by another one, e.g. to authorize its calls, on behalf of the
+// guest. The wrapped function is not called by the guest itself and cannot see its exports, so
+// the memory of the guest is forwarded to it.
+pub(crate) fn call_wrapped(
+    caller: &mut Caller<'_, WasmtimeState>,
+    func: &Func,
+    params: &[Val],
+    results: &mut [Val],
+) -> wasmtime::Result<()> {
+    let memory = memory(caller).ok();
+    let previous = std::mem::replace(&mut caller.data_mut().forwarded_memory, memory);
+    let result = func.call(&mut *caller, params, results);
+    caller.data_mut().forwarded_memory = previous;
+    result
+}
+
+fn range(ptr: i32, len: i32) -> Result<(usize, usize), Error> {
+    let ptr = usize::try_from(ptr).map_err(|_| BillingError::GuestMemoryOutOfBounds)?;
+    let len = usize::try_from(len).map_err(|_| BillingError::GuestMemoryOutOfBounds)?;
+    Ok((ptr, len))
+}
+
+// Writes as much of `bytes` as fits into the guest buffer, returning the number of bytes written.
+pub(crate) fn write(
+    caller: &mut Caller<'_, WasmtimeState>,
+    ptr: i32,
+    len: i32,
+    bytes: &[u8],
+) -> Result<usize, Error> {
+    let (ptr, len) = range(ptr, len)?;
+    let n = bytes.len().min(len);
+    memory(caller)?
+        .write(caller, ptr, &bytes[..n])
+        .map_err(|_| BillingError::GuestMemoryOutOfBounds)?;
+    Ok(n)
+}
+
+pub(crate) fn read(
+    caller: &mut Caller<'_, WasmtimeState>,
+    ptr: i32,
+    len: i32,
+) -> Result<Vec<u8>, Error> {
+    let (ptr, len) = range(ptr, len)?;
+    let memory = memory(caller)?;
+    // The bounds are checked before allocating the buffer of the guest-controlled length
+    let end = ptr
+        .checked_add(len)
+        .ok_or(BillingError::GuestMemoryOutOfBounds)?;
+    let bytes = memory
+        .data(&*caller)
+        .get(ptr..end)
+        .ok_or(BillingError::GuestMemoryOutOfBounds)?;
+    Ok(bytes.to_vec())
+}
+
+fn guest_func<Params, Results>(
+    caller: &mut Caller<'_, WasmtimeState>,
+    name: &str,
+) -> Option<TypedFunc<Params, Results>>
+where
+    Params: wasmtime::WasmParams,
+    Results: wasmtime::WasmResults,
+{
+    match caller.get_export(name) {
+        Some(Extern::Func(func)) => func.typed(&*caller).ok(),
+        _ => None,
+    }
+}
+
+// Copies the bytes into a buffer allocated by the guest's `alloc` export and returns
+// the pointer to the buffer in the upper 32 bits and its length in the lower 32 bits,
+// so that the result is never negative. The buffer belongs to the guest afterwards.
+// A null buffer fails the call, one out of the bounds of the memory (or beyond 2 GiB) is not
+// written and, if the guest exports `dealloc`, freed. Empty payloads are not allocated
+// and return 0.
+pub(crate) fn write_allocated(
+    caller: &mut Caller<'_, WasmtimeState>,
+    bytes: &[u8],
+) -> Result<i64, Error> {
+    if bytes.is_empty() {
+        return Ok(0);
+    }
+    let len = i32::try_from(bytes.len()).map_err(|_| BillingError::GuestAllocationFailed)?;
+    // Checked first, so that writing can only fail on the bounds of the buffer
+    memory(caller)?;
+    let alloc =
+        guest_func::<i32, i32>(caller, ALLOC_EXPORT).ok_or(BillingError::GuestAllocatorMissing)?;
+    // A trap in the allocator fails the call rather than the guest
+    let ptr = alloc
+        .call(&mut *caller, len)
+        .map_err(|_| BillingError::GuestAllocationFailed)?;
+    if ptr == 0 {
+        return Err(BillingError::GuestAllocationFailed.into());
+    }
+    if write(caller, ptr, len, bytes).is_err() {
+        if let Some(dealloc) = guest_func::<(i32, i32), ()>(caller, DEALLOC_EXPORT) {
+            let _ = dealloc.call(&mut *caller, (ptr, len));
+        }
+        return Err(BillingError::GuestAllocationFailed.into());
+    }
+    Ok(((ptr as i64) << 32) | len as i64)
+}
@@ -0,0 +1,157 @@
+// Metrics emitted by the guests with `host.metric_incr` and `host.metric_gauge`, e.g. the
+// requests a guest has served. The metrics of every user are kept apart, so that guests cannot
+// overwrite each other's, and exported with the user as a label by `GuestMetrics::render`.
+// To keep the cardinality bounded, every user may emit at most
+// `GuestMetricsConfig::max_metrics_per_user` distinct metrics and update them at most
+// `GuestMetricsConfig::max_updates_per_minute` times a minute.
+
+use std::collections::BTreeMap;
+
+use crate::{ledger, BillingError, Error, UserId};
+
+const SECS_PER_MINUTE: u64 = 60;
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub struct GuestMetricsConfig {
+    pub max_metrics_per_user: usize,
+    pub max_updates_per_minute: u32,
+}
+
+impl Default for GuestMetricsConfig {
+    fn default() -> Self {
+        Self {
+            max_metrics_per_user: 50,
+            max_updates_per_minute: 1_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    // Only ever increases, e.g. the requests served
+    Counter(u64),
+    // Set to the latest value, e.g. the size of a cache
+    Gauge(i64),
+}
+
+#[derive(Clone, Debug, Default)]
+struct UserMetrics {
+    metrics: BTreeMap<String, Metric>,
+    // The start of the current minute of the rate limit, seconds since the Unix epoch
+    window_start: u64,
+    updates: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GuestMetrics {
+    users: BTreeMap<UserId, UserMetrics>,
+}
+
+// Names are those of Prometheus: ASCII letters, digits and underscores, not starting with a digit
+fn check_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(BillingError::InvalidArgumentValue.into()),
+    }
+}
+
+impl GuestMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, user: UserId, name: &str) -> Option<Metric> {
+        self.users.get(&user)?.metrics.get(name).copied()
+    }
+
+    pub fn of_user(&self, user: UserId) -> impl Iterator<Item = (&str, Metric)> {
+        self.users
+            .get(&user)
+            .into_iter()
+            .flat_map(|metrics| metrics.metrics.iter())
+            .map(|(name, &metric)| (name.as_str(), metric))
+    }
+
+    // Drops the metrics of the user, see `erasure`
+    pub(crate) fn remove_user(&mut self, user: UserId) {
+        self.users.remove(&user);
+    }
+
+    // Checks the limits of the user and the kind of an existing metric, returning the metric
+    fn metric(
+        &mut self,
+        config: &GuestMetricsConfig,
+        user: UserId,
+        name: &str,
+        new: Metric,
+    ) -> Result<&mut Metric, Error> {
+        check_name(name)?;
+        let user_metrics = self.users.entry(user).or_default();
+        let now = ledger::now_secs();
+        if now >= user_metrics.window_start + SECS_PER_MINUTE {
+            user_metrics.window_start = now - now % SECS_PER_MINUTE;
+            user_metrics.updates = 0;
+        }
+        if user_metrics.updates >= config.max_updates_per_minute {
+            return Err(BillingError::MetricRateLimited.into());
+        }
+        let metrics = &mut user_metrics.metrics;
+        if !metrics.contains_key(name) && metrics.len() >= config.max_metrics_per_user {
+            return Err(BillingError::TooManyMetrics.into());
+        }
+        let metric = metrics.entry(name.to_owned()).or_insert(new);
+        if std::mem::discriminant(metric) != std::mem::discriminant(&new) {
+            return Err(BillingError::InvalidArgumentValue.into());
+        }
+        user_metrics.updates += 1;
+        Ok(metric)
+    }
+
+    // Increments the counter, which starts at 0. The value must not be negative.
+    pub fn incr(
+        &mut self,
+        config: &GuestMetricsConfig,
+        user: UserId,
+        name: &str,
+        value: i64,
+    ) -> Result<(), Error> {
+        let value = u64::try_from(value).map_err(|_| BillingError::InvalidArgumentValue)?;
+        if let Metric::Counter(count) = self.metric(config, user, name, Metric::Counter(0))? {
+            *count = count.saturating_add(value);
+        }
+        Ok(())
+    }
+
+    pub fn gauge(
+        &mut self,
+        config: &GuestMetricsConfig,
+        user: UserId,
+        name: &str,
+        value: i64,
+    ) -> Result<(), Error> {
+        *self.metric(config, user, name, Metric::Gauge(value))? = Metric::Gauge(value);
+        Ok(())
+    }
+
+    // The metrics of all the users in the Prometheus text format, prefixed with `guest_` and
+    // labelled with the user, e.g. `guest_requests{user="0"} 12`. The samples are untyped,
+    // since the guests of different users may emit metrics of the same name but of other kinds.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (user, user_metrics) in &self.users {
+            for (name, metric) in &user_metrics.metrics {
+                let value = match metric {
+                    Metric::Counter(count) => count.to_string(),
+                    Metric::Gauge(value) => value.to_string(),
+                };
+                out.push_str(&format!("guest_{name}{{user=\"{}\"}} {value}\n", user.0));
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,366 @@
+use std::{
+    io::{self, Read, Write},
+    time::Instant,
+};
+
+use serde::Serialize;
+
+use crate::{
+    burst, cost_centers, determinism, indirect_calls,
+    instance_pool::InstancePool,
+    ledger,
+    metering::{self, CallCharge},
+    module_hash,
+    preview2::{self, WasiFlavor},
+    quota, reload,
+    result_cache::{self, CacheKey, ResultCache},
+    runtime::WasmRuntime,
+    trace, tx, wasm_features, Error, State, UserId,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ExecutionRecord {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    // Hex-encoded SHA-256 of the module bytes
+    pub module_hash: String,
+    pub export: String,
+    pub result: Option<i64>,
+    pub fuel: Option<u64>,
+    // The most WebAssembly pages of linear memory the instance has had
+    pub peak_memory_pages: Option<u64>,
+    pub duration_micros: u64,
+    // The calls of the priced host functions, see `config::CallPricingConfig`
+    pub call_charges: Vec<CallCharge>,
+    pub error: Option<String>,
+    // The trace the invocation ran within, see `trace`
+    pub trace_id: String,
+    // Answered from the cache without running, see `result_cache`
+    pub cached: bool,
+    // The profile of the run in the Firefox processed profile format, if it was profiled,
+    // see `profiling`
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+impl ExecutionRecord {
+    pub fn outcome(&self) -> Outcome {
+        match self.error {
+            None => Outcome::Success,
+            Some(_) => Outcome::Failure,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HistoryFilter {
+    // Seconds since the Unix epoch, `to` is exclusive
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub outcome: Option<Outcome>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, record: &ExecutionRecord) -> bool {
+        self.from.is_none_or(|from| record.at >= from)
+            && self.to.is_none_or(|to| record.at < to)
+            && self
+                .outcome
+                .is_none_or(|outcome| record.outcome() == outcome)
+    }
+}
+
+pub struct HistoryPage<'a> {
+    // Newest records first
+    pub records: Vec<&'a ExecutionRecord>,
+    // Offset of the next page, `None` if this is the last one
+    pub next_offset: Option<usize>,
+    // Number of records matching the filter
+    pub total: usize,
+}
+
+pub fn page<'a>(
+    history: &'a [ExecutionRecord],
+    filter: &HistoryFilter,
+    offset: usize,
+    limit: usize,
+) -> HistoryPage<'a> {
+    let matching = history
+        .iter()
+        .rev()
+        .filter(|record| filter.matches(record))
+        .collect::<Vec<_>>();
+    let total = matching.len();
+    let records = matching
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .collect::<Vec<_>>();
+    let end = offset.saturating_add(records.len());
+    HistoryPage {
+        records,
+        next_offset: (end < total).then_some(end),
+        total,
+    }
+}
+
+// The latest records as a JSON array, newest first, without their profiles
+pub fn latest_json(history: &[ExecutionRecord], limit: usize) -> String {
+    let latest = history.iter().rev().take(limit).collect::<Vec<_>>();
+    serde_json::to_string(&latest).unwrap()
+}
+
+// Compiles, instantiates and calls the export on behalf of the user,
+// recording the execution in the user's history and billing its memory,
+// see `config::MemoryConfig`. Preview2 components are detected and run as
+// commands instead, ignoring the export. The results of the pure exports may be
+// answered from the cache, see `result_cache`.
+pub fn execute<R: WasmRuntime>(
+    runtime: &R,
+    store: &mut R::Store,
+    user: UserId,
+    bytes: &[u8],
+    export: &str,
+) -> Result<i64, Error> {
+    invoke(runtime, store, user, bytes, export, Some(&[]))
+}
+
+// Like `execute`, with the input as the standard input of the guest, which is part of the key
+// of the cached results of the pure exports. The guest is left an empty input after the run.
+pub fn execute_with_input<R: WasmRuntime>(
+    runtime: &R,
+    store: &mut R::Store,
+    user: UserId,
+    bytes: &[u8],
+    export: &str,
+    input: &[u8],
+) -> Result<i64, Error> {
+    runtime.set_stdin(store, Box::new(io::Cursor::new(input.to_vec())));
+    let result = invoke(runtime, store, user, bytes, export, Some(input));
+    runtime.set_stdin(store, Box::new(io::empty()));
+    result
+}
+
+// The invocation of `execute`, with the input keying the cached results or `None` if they may
+// not be cached, e.g. for a streamed input
+fn invoke<R: WasmRuntime>(
+    runtime: &R,
+    store: &mut R::Store,
+    user: UserId,
+    bytes: &[u8],
+    export: &str,
+    input: Option<&[u8]>,
+) -> Result<i64, Error> {
+    let flavor = preview2::detect(bytes);
+    record(
+        runtime,
+        store,
+        user,
+        bytes,
+        export,
+        flavor,
+        input,
+        |runtime, store| match flavor {
+            WasiFlavor::Preview1 => wasm_features::check(runtime.state_mut(store), user, bytes)
+                .and_then(|()| indirect_calls::check(runtime.state_mut(store), user, bytes))
+                .and_then(|()| runtime.compile(bytes))
+                .and_then(|module| runtime.instantiate(store, &module, user))
+                .and_then(|instance| runtime.call(store, &instance, export)),
+            WasiFlavor::Preview2 => wasm_features::check(runtime.state_mut(store), user, bytes)
+                .and_then(|()| runtime.run_component(store, user, bytes)),
+        },
+    )
+}
+
+// Like `execute`, with the standard input of the guest read from `stdin` and its standard
+// output written to `stdout` as the guest writes it, e.g. to use the guest as a Unix filter.
+// Both are detached after the run, leaving the guest an empty input and discarding its output.
+// Preview2 components get their standard streams from `State::component_wasi` instead.
+pub fn execute_piped<R: WasmRuntime>(
+    runtime: &R,
+    store: &mut R::Store,
+    user: UserId,
+    bytes: &[u8],
+    export: &str,
+    stdin: impl Read + Send + Sync + 'static,
+    stdout: impl Write + Send + Sync + 'static,
+) -> Result<i64, Error> {
+    runtime.set_stdin(store, Box::new(stdin));
+    runtime.set_stdout(store, Box::new(stdout));
+    let result = invoke(runtime, store, user, bytes, export, None);
+    runtime.set_stdin(store, Box::new(io::empty()));
+    runtime.set_stdout(store, Box::new(io::sink()));
+    result
+}
+
+// Like `execute`, with an instance checked out of the pool, see `instance_pool`.
+// Components are not pooled.
+pub fn execute_pooled<R: WasmRuntime>(
+    runtime: &R,
+    store: &mut R::Store,
+    pool: &mut InstancePool<R>,
+    user: UserId,
+    bytes: &[u8],
+    export: &str,
+) -> Result<i64, Error> {
+    let flavor = preview2::detect(bytes);
+    if flavor == WasiFlavor::Preview2 {
+        return execute(runtime, store, user, bytes, export);
+    }
+    record(
+        runtime,
+        store,
+        user,
+        bytes,
+        export,
+        flavor,
+        Some(&[]),
+        |runtime, store| {
+            wasm_features::check(runtime.state_mut(store), user, bytes)
+                .and_then(|()| indirect_calls::check(runtime.state_mut(store), user, bytes))
+                .and_then(|()| pool.checkout(runtime, store, user, bytes))
+                .and_then(|instance| runtime.call(store, &instance, export))
+        },
+    )
+}
+
+// The key of the cached result of the invocation if the export is pure and the cache enabled
+fn cache_key(
+    state: &State,
+    user: UserId,
+    bytes: &[u8],
+    export: &str,
+    flavor: WasiFlavor,
+    input: Option<&[u8]>,
+) -> Option<CacheKey> {
+    let input = input.filter(|_| state.config.result_cache.ttl_secs > 0)?;
+    if flavor != WasiFlavor::Preview1 || !result_cache::pure_exports(bytes).contains(export) {
+        return None;
+    }
+    Some(ResultCache::key(bytes, user, export, input))
+}
+
+// Records the invocation answered from the cache
+fn record_hit(state: &mut State, user: UserId, bytes: &[u8], export: &str, value: i64) -> i64 {
+    let record = ExecutionRecord {
+        at: ledger::now_secs(),
+        module_hash: module_hash(bytes),
+        export: export.to_owned(),
+        result: Some(value),
+        fuel: None,
+        peak_memory_pages: None,
+        duration_micros: 0,
+        call_charges: Vec::new(),
+        error: None,
+        trace_id: state.next_trace_id.take().unwrap_or_else(trace::new_id),
+        cached: true,
+        profile: None,
+    };
+    state.next_cost_center = None;
+    if let Some(user_data) = state.users.get_mut(&user) {
+        user_data.executions.push(record);
+    }
+    value
+}
+
+// Runs the invocation, metering it and limiting its fuel, see `burst`, if it is not a
+// component (whose own store is not metered), and records it. The pure exports are answered
+// from the cache if they can be and run read-only otherwise, see `result_cache`.
+#[allow(clippy::too_many_arguments)]
+fn record<R: WasmRuntime>(
+    runtime: &R,
+    store: &mut R::Store,
+    user: UserId,
+    bytes: &[u8],
+    export: &str,
+    flavor: WasiFlavor,
+    input: Option<&[u8]>,
+    invoke: impl FnOnce(&R, &mut R::Store) -> Result<i64, Error>,
+) -> Result<i64, Error> {
+    reload::apply_staged(runtime.state_mut(store));
+    let state = runtime.state_mut(store);
+    let cache_key = cache_key(state, user, bytes, export, flavor, input);
+    if let Some(key) = &cache_key {
+        if let Some(value) = state.result_cache.get(key) {
+            return Ok(record_hit(state, user, bytes, export, value));
+        }
+    }
+    state.read_only = cache_key.is_some();
+    let deterministic = determinism::begin(runtime.state_mut(store));
+    let trace_id = runtime
+        .state_mut(store)
+        .next_trace_id
+        .take()
+        .or_else(|| deterministic.as_ref().map(|run| run.trace_id.clone()))
+        .unwrap_or_else(trace::new_id);
+    let _span = trace::enter(trace_id.clone());
+    let cost_center = runtime.state_mut(store).next_cost_center.take();
+    let _cost_center = cost_centers::enter_unchecked(cost_center);
+    runtime.state_mut(store).call_charges.clear();
+    let started = Instant::now();
+    let at = ledger::now_secs();
+    let (result, fuel, peak_memory_pages) = match flavor {
+        WasiFlavor::Preview1 => {
+            let budget = burst::budget(runtime.state_mut(store), user);
+            runtime.limit_fuel(store, budget);
+            let fuel_before = runtime.meter(store);
+            let result = invoke(runtime, store);
+            runtime.state_mut(store).read_only = false;
+            let fuel = runtime
+                .meter(store)
+                .zip(fuel_before)
+                .map(|(after, before)| after - before);
+            runtime.limit_fuel(store, None);
+            if let Some(fuel) = fuel {
+                burst::settle(runtime.state_mut(store), user, fuel);
+            }
+            (result, fuel, runtime.peak_memory_pages(store))
+        }
+        WasiFlavor::Preview2 => (invoke(runtime, store), None, None),
+    };
+    let duration_micros = started.elapsed().as_micros() as u64;
+    let measured = quota::measure_files(runtime.state_mut(store), user);
+    let result = result.and_then(|value| measured.map(|()| value));
+
+    let mut record = ExecutionRecord {
+        at,
+        module_hash: module_hash(bytes),
+        export: export.to_owned(),
+        result: result.as_ref().ok().copied(),
+        fuel,
+        peak_memory_pages,
+        duration_micros,
+        call_charges: Vec::new(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+        trace_id,
+        cached: false,
+        profile: runtime.state_mut(store).profiler.take_profile(),
+    };
+    let state = runtime.state_mut(store);
+    // A transaction left open by the guest, e.g. because it trapped, is rolled back
+    if tx::is_active(state) {
+        tx::rollback(state).unwrap();
+    }
+    record.call_charges = metering::charge_calls(state, user);
+    if let Some(pages) = peak_memory_pages {
+        state.stats.record_memory(pages);
+        metering::charge_memory(state, user, pages, duration_micros);
+    }
+    if let Some(fuel) = fuel {
+        metering::charge_compute(state, user, fuel);
+    }
+    if let (Some(key), Ok(value)) = (cache_key, &result) {
+        let config = state.config.result_cache;
+        state.result_cache.insert(key, *value, config);
+    }
+    if let Some(user_data) = state.users.get_mut(&user) {
+        user_data.executions.push(record);
+    }
+    result
+}
@@ -0,0 +1,1226 @@
+use wasmtime::{ExternType, ImportType, ValType};
+
+use crate::{
+    alerts, anomalies, billing,
+    capability::Capability,
+    config::Config,
+    groups,
+    orders::OrderId,
+    policy::{self, Action},
+    services, tx, BillingError, Error, HostError, State, UserData, UserId,
+};
+
+pub const HOST_MODULE: &str = "host";
+
+// The version of the host API is bumped whenever new host functions are added.
+pub const HOST_API_VERSION: u32 = 37;
+
+pub struct HostFunction {
+    pub name: &'static str,
+    // The host API version that introduced the function
+    pub since: u32,
+    pub capability: Option<Capability>,
+    // Whether the function changes the state of the host, e.g. charges the user or consumes a
+    // message of the inbox, reporting its result as described in `abi`. The calls of the
+    // mutating functions are authorized by `State::authorization_hooks`.
+    pub mutating: bool,
+    pub params: &'static [ValType],
+    pub results: &'static [ValType],
+    // The rest is rendered by `host_docs::render`
+    pub doc: &'static str,
+    // The errors the function may report to the guest, see `BillingError::code`
+    pub errors: &'static [BillingError],
+    // The price of a call under the configuration, `None` if it is free
+    pub pricing: Option<fn(&Config) -> String>,
+}
+
+pub const HOST_FUNCTIONS: &[HostFunction] = &[
+    HostFunction {
+        name: "balance",
+        params: &[],
+        results: &[ValType::I64],
+        since: 1,
+        capability: None,
+        mutating: false,
+        doc: "Returns the balance of the user in cents.",
+        errors: &[],
+        pricing: None,
+    },
+    HostFunction {
+        name: "order_hosting",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+        since: 1,
+        capability: None,
+        mutating: true,
+        doc: "Orders the given number of days of hosting, reactivating a suspended account. \
+              Orders placed during the grace period are charged a late fee on top.",
+        errors: &[
+            BillingError::InvalidArgumentValue,
+            BillingError::TotalCostExceededMaxValue,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::PolicyDenied,
+            BillingError::FeatureDisabled,
+            BillingError::ApprovalRequired,
+            BillingError::GroupLimitExceeded,
+            BillingError::CallVetoed,
+        ],
+        pricing: Some(|config| match config.grace.late_fee.is_zero() {
+            true => format!("{} per day", config.hosting.price_per_day),
+            false => format!(
+                "{} per day, plus a late fee of {} during the grace period",
+                config.hosting.price_per_day, config.grace.late_fee
+            ),
+        }),
+    },
+    HostFunction {
+        name: "transfer",
+        params: &[ValType::I64, ValType::I64],
+        results: &[ValType::I32],
+        since: 2,
+        capability: Some(Capability::Transfer),
+        mutating: true,
+        doc: "Transfers the given cents to another user, charging the transfer fee to the caller.",
+        errors: &[
+            BillingError::InvalidArgumentValue,
+            BillingError::SelfTransfer,
+            BillingError::UnknownUser,
+            BillingError::TotalCostExceededMaxValue,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::PolicyDenied,
+            BillingError::FeatureDisabled,
+            BillingError::ApprovalRequired,
+            BillingError::CallVetoed,
+        ],
+        pricing: Some(|config| {
+            let fee = config.transfer_fee;
+            format!(
+                "{} plus {} basis points of the amount",
+                fee.flat, fee.basis_points
+            )
+        }),
+    },
+    HostFunction {
+        name: "trial_days_left",
+        params: &[],
+        results: &[ValType::I32],
+        since: 3,
+        capability: None,
+        mutating: false,
+        doc: "Returns the days left in the user's trial, 0 if the user is not on a trial.",
+        errors: &[],
+        pricing: None,
+    },
+    HostFunction {
+        name: "last_error_message",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 4,
+        capability: None,
+        mutating: false,
+        doc: "Writes up to `len` bytes of the message of the last error, translated into the \
+              user's locale if possible, into the buffer and returns its full length. Returns 0 \
+              if no error has been reported yet.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "heartbeat",
+        params: &[],
+        results: &[],
+        since: 5,
+        capability: None,
+        mutating: false,
+        doc: "Tells the watchdog that the guest is still making progress.",
+        errors: &[],
+        pricing: None,
+    },
+    HostFunction {
+        name: "register_user",
+        params: &[],
+        results: &[ValType::I64],
+        since: 6,
+        capability: Some(Capability::Admin),
+        mutating: true,
+        doc: "Registers a new user referred by the caller and returns their id or the negated \
+              error code.",
+        errors: &[
+            BillingError::MissingCapability,
+            BillingError::InvalidArgumentValue,
+            BillingError::UserAlreadyExists,
+            BillingError::CallVetoed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "storage_put",
+        params: &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 7,
+        capability: None,
+        mutating: true,
+        doc: "Stores the object under the key, replacing the existing one.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::StorageUnavailable,
+            BillingError::StorageQuotaExceeded,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::CallVetoed,
+        ],
+        pricing: Some(|config| {
+            let storage = config.storage;
+            format!(
+                "{} per request, {} per GB stored per day",
+                storage.price_per_request, storage.price_per_gb_day
+            )
+        }),
+    },
+    HostFunction {
+        name: "storage_get",
+        params: &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
+        results: &[ValType::I64],
+        since: 7,
+        capability: None,
+        mutating: true,
+        doc: "Writes up to `len` bytes of the object into the buffer and returns the full size of \
+              the object or the negated error code.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::StorageUnavailable,
+            BillingError::UnknownObject,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::CallVetoed,
+        ],
+        pricing: Some(|config| format!("{} per request", config.storage.price_per_request)),
+    },
+    HostFunction {
+        name: "storage_delete",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 7,
+        capability: None,
+        mutating: true,
+        doc: "Deletes the object stored under the key.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::StorageUnavailable,
+            BillingError::UnknownObject,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::CallVetoed,
+        ],
+        pricing: Some(|config| format!("{} per request", config.storage.price_per_request)),
+    },
+    HostFunction {
+        name: "register_domain",
+        params: &[ValType::I32, ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 8,
+        capability: None,
+        mutating: true,
+        doc: "Registers the domain name for the given number of years.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::DomainTaken,
+            BillingError::TotalCostExceededMaxValue,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::PolicyDenied,
+            BillingError::FeatureDisabled,
+            BillingError::ApprovalRequired,
+            BillingError::GroupLimitExceeded,
+            BillingError::CallVetoed,
+        ],
+        pricing: Some(|config| format!("{} per year", config.domains.price_per_year)),
+    },
+    HostFunction {
+        name: "domain_available",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 8,
+        capability: None,
+        mutating: false,
+        doc: "Returns 1 if the domain name is available, 0 if it is taken or the negated error \
+              code.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "send_email",
+        params: &[
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+        ],
+        results: &[ValType::I32],
+        since: 9,
+        capability: None,
+        mutating: true,
+        doc: "Sends an email with the subject and the body to the address.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::EmailUnavailable,
+            BillingError::EmailQuotaExceeded,
+            BillingError::EmailRateLimited,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::CallVetoed,
+        ],
+        pricing: Some(|config| format!("{} per email", config.email.price_per_email)),
+    },
+    HostFunction {
+        name: "db_connection_info",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 10,
+        capability: None,
+        mutating: false,
+        doc: "Writes up to `len` bytes of the connection information of the user's database into \
+              the buffer and returns its full length or the negated error code.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::NoDatabase,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "bandwidth_used_this_cycle",
+        params: &[],
+        results: &[ValType::I64],
+        since: 11,
+        capability: None,
+        mutating: false,
+        doc: "Returns the bytes served to the user in the current billing cycle.",
+        errors: &[],
+        pricing: None,
+    },
+    HostFunction {
+        name: "queue_send",
+        params: &[ValType::I64, ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 12,
+        capability: None,
+        mutating: true,
+        doc: "Sends the message to the inbox of another user.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::UnknownUser,
+            BillingError::MessageTooLarge,
+            BillingError::QueueFull,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::CallVetoed,
+        ],
+        pricing: Some(|config| format!("{} per message", config.queues.price_per_message)),
+    },
+    HostFunction {
+        name: "queue_poll",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 12,
+        capability: None,
+        mutating: true,
+        doc: "Moves the oldest message of the user's inbox into the buffer and returns its \
+              length. If the buffer is too small, the message stays queued and its length is \
+              returned. Returns 0 if the inbox is empty.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::CallVetoed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "schedule_job",
+        params: &[
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+        ],
+        results: &[ValType::I64],
+        since: 13,
+        capability: None,
+        mutating: true,
+        doc: "Schedules the export of a module, identified by its hash, to run on the cron \
+              schedule. Returns the id of the job or the negated error code.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::InvalidSchedule,
+            BillingError::UnknownModule,
+            BillingError::TooManyJobs,
+            BillingError::CallVetoed,
+        ],
+        pricing: Some(|config| format!("{} per run, including retries", config.jobs.price_per_run)),
+    },
+    HostFunction {
+        name: "cancel_job",
+        params: &[ValType::I64],
+        results: &[ValType::I32],
+        since: 13,
+        capability: None,
+        mutating: true,
+        doc: "Cancels a job scheduled by the user.",
+        errors: &[BillingError::UnknownJob, BillingError::CallVetoed],
+        pricing: None,
+    },
+    HostFunction {
+        name: "secret_get",
+        params: &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 14,
+        capability: Some(Capability::Secrets),
+        mutating: false,
+        doc: "Writes up to `len` bytes of the named secret into the buffer and returns its full \
+              length or the negated error code.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::UnknownSecret,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "balance_at",
+        params: &[ValType::I64],
+        results: &[ValType::I64],
+        since: 15,
+        capability: None,
+        mutating: false,
+        doc: "Returns the balance in cents at the time in seconds since the Unix epoch, or \
+              `i64::MIN` if it is not known.",
+        errors: &[BillingError::BalanceHistoryUnavailable],
+        pricing: None,
+    },
+    HostFunction {
+        name: "set_result_buffer",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 16,
+        capability: None,
+        mutating: false,
+        doc: "Registers a buffer of at least 16 bytes the mutating functions write their \
+              structured results into: the error code, the kind of the payload and the payload, \
+              e.g. the new balance. A `len` of 0 unregisters it.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "order_status",
+        params: &[ValType::I64],
+        results: &[ValType::I32],
+        since: 17,
+        capability: None,
+        mutating: false,
+        doc: "Returns the status of an order of the caller: 0 if completed, 1 if disputed, \
+              2 if refunded and 3 if cancelled. Every purchase places an order, whose id is the \
+              payload of the structured result of the call.",
+        errors: &[BillingError::UnknownOrder],
+        pricing: None,
+    },
+    HostFunction {
+        name: "order_details",
+        params: &[ValType::I64, ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 17,
+        capability: None,
+        mutating: false,
+        doc: "Writes up to `len` bytes of an order of the caller as JSON into the buffer and \
+              returns the full length of the JSON, so that the guest can retry with a larger \
+              buffer.",
+        errors: &[
+            BillingError::UnknownOrder,
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "grace_days_remaining",
+        params: &[],
+        results: &[ValType::I32],
+        since: 18,
+        capability: None,
+        mutating: false,
+        doc: "Returns the days left before the account gets suspended for running out of \
+              hosting days, 0 if the account is not in its grace period.",
+        errors: &[],
+        pricing: None,
+    },
+    HostFunction {
+        name: "open_ticket",
+        params: &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
+        results: &[ValType::I64],
+        since: 19,
+        capability: None,
+        mutating: true,
+        doc: "Opens a support ticket with the subject and the first message, both UTF-8, and \
+              returns the id of the ticket or the negated error code.",
+        errors: &[
+            BillingError::InvalidArgumentValue,
+            BillingError::TicketQuotaExceeded,
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::CallVetoed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "rotate_api_key",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 20,
+        capability: Some(Capability::ManageKeys),
+        mutating: true,
+        doc: "Replaces the API token of the user in the buffer with a new token of the same \
+              scope, which is written over it. The old token stops working immediately.",
+        errors: &[
+            BillingError::MissingCapability,
+            BillingError::InvalidToken,
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::TransactionActive,
+            BillingError::CallVetoed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "order_bundle",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 21,
+        capability: None,
+        mutating: true,
+        doc: "Orders the services of the named bundle of the catalog the user does not have yet. \
+              Within a transaction, they are provisioned once it is committed.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::UnknownService,
+            BillingError::MissingDependency,
+            BillingError::TotalCostExceededMaxValue,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::PolicyDenied,
+            BillingError::FeatureDisabled,
+            BillingError::ApprovalRequired,
+            BillingError::GroupLimitExceeded,
+            BillingError::TransactionActive,
+            BillingError::CallVetoed,
+        ],
+        pricing: Some(|_| "the prices of the services in the catalog".to_owned()),
+    },
+    HostFunction {
+        name: "begin_tx",
+        params: &[],
+        results: &[ValType::I32],
+        since: 21,
+        capability: None,
+        mutating: true,
+        doc: "Opens a transaction: the orders placed until `commit_tx` either all apply or none \
+              do. Calls with effects beyond the user's own account fail within a transaction, \
+              which is rolled back if the guest returns without committing it.",
+        errors: &[BillingError::TransactionActive, BillingError::CallVetoed],
+        pricing: None,
+    },
+    HostFunction {
+        name: "commit_tx",
+        params: &[],
+        results: &[ValType::I32],
+        since: 21,
+        capability: None,
+        mutating: true,
+        doc: "Provisions the services ordered within the transaction and keeps its changes. \
+              If any service fails to provision, the transaction is rolled back.",
+        errors: &[BillingError::NoTransaction, BillingError::CallVetoed],
+        pricing: None,
+    },
+    HostFunction {
+        name: "rollback_tx",
+        params: &[],
+        results: &[ValType::I32],
+        since: 21,
+        capability: None,
+        mutating: true,
+        doc: "Undoes the changes made within the transaction.",
+        errors: &[BillingError::NoTransaction, BillingError::CallVetoed],
+        pricing: None,
+    },
+    HostFunction {
+        name: "execution_history",
+        params: &[ValType::I32],
+        results: &[ValType::I64],
+        since: 22,
+        capability: None,
+        mutating: false,
+        doc: "Returns up to `limit` of the user's latest executions as a JSON array, newest \
+              first, in a buffer allocated with the guest's `alloc` export. The result is the \
+              pointer in the upper 32 bits and the length in the lower 32 bits, or the negated \
+              error code.",
+        errors: &[
+            BillingError::InvalidArgumentValue,
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestAllocatorMissing,
+            BillingError::GuestAllocationFailed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "queue_poll_alloc",
+        params: &[],
+        results: &[ValType::I64],
+        since: 22,
+        capability: None,
+        mutating: true,
+        doc: "Moves the oldest message of the user's inbox into a buffer allocated with the \
+              guest's `alloc` export and returns the pointer in the upper 32 bits and the length \
+              in the lower 32 bits, or the negated error code. Returns 0 if the inbox is empty.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestAllocatorMissing,
+            BillingError::GuestAllocationFailed,
+            BillingError::CallVetoed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "set_spend_alert",
+        params: &[ValType::I32, ValType::I64],
+        results: &[ValType::I32],
+        since: 23,
+        capability: None,
+        mutating: true,
+        doc: "Sets the user's spending alert of the kind, 0 for the spend within 24 hours and 1 \
+              for a single charge, to the threshold in minor units of the balance's currency. \
+              A threshold of 0 removes the alert.",
+        errors: &[BillingError::InvalidArgumentValue, BillingError::CallVetoed],
+        pricing: None,
+    },
+    HostFunction {
+        name: "feature_enabled",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 24,
+        capability: None,
+        mutating: false,
+        doc: "Returns 1 if the named feature, e.g. `transfers`, is enabled on the host for the \
+              user's plan, 0 if it is not or the negated error code.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "cancel_service",
+        params: &[ValType::I64],
+        results: &[ValType::I32],
+        since: 25,
+        capability: None,
+        mutating: true,
+        doc: "Cancels a hosting or bundle order of the caller: the unused hosting days are taken \
+              off the account and the services of a bundle are deprovisioned. The unused part of \
+              the price is refunded as the plan of the user allows.",
+        errors: &[
+            BillingError::UnknownOrder,
+            BillingError::NotCancellable,
+            BillingError::EntryArchived,
+            BillingError::TransactionActive,
+            BillingError::BalanceWouldOverflow,
+            BillingError::PolicyDenied,
+            BillingError::FeatureDisabled,
+            BillingError::ApprovalRequired,
+            BillingError::CallVetoed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "metric_incr",
+        params: &[ValType::I32, ValType::I32, ValType::I64],
+        results: &[ValType::I32],
+        since: 26,
+        capability: None,
+        mutating: false,
+        doc: "Adds the value, which must not be negative, to the user's counter of the name, e.g. \
+              `requests_served`. Names consist of ASCII letters, digits and underscores. Returns \
+              0 or the negated error code.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::TooManyMetrics,
+            BillingError::MetricRateLimited,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "metric_gauge",
+        params: &[ValType::I32, ValType::I32, ValType::I64],
+        results: &[ValType::I32],
+        since: 26,
+        capability: None,
+        mutating: false,
+        doc: "Sets the user's gauge of the name, e.g. `cache_entries`, to the value. Names \
+              consist of ASCII letters, digits and underscores. Returns 0 or the negated error \
+              code.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::TooManyMetrics,
+            BillingError::MetricRateLimited,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "trace_id",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 27,
+        capability: None,
+        mutating: false,
+        doc: "Writes up to `len` bytes of the id of the trace the invocation runs within, 32 \
+              lowercase hex digits as in the W3C Trace Context, into the buffer and returns the \
+              full length of the id. The ledger entries made during the invocation carry the id.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "export_service_data",
+        params: &[
+            ValType::I64,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+        ],
+        results: &[ValType::I32],
+        since: 28,
+        capability: None,
+        mutating: false,
+        doc: "Writes up to `len` bytes of the data of the service, named in UTF-8, deprovisioned \
+              by the cancelled order into the buffer and returns the full length of the data, so \
+              that the guest can retry with a larger buffer. The data is kept for a while after \
+              the cancellation, see `retention`.",
+        errors: &[
+            BillingError::UnknownOrder,
+            BillingError::NoRetainedData,
+            BillingError::InvalidArgumentValue,
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "create_sub_account",
+        params: &[ValType::I64],
+        results: &[ValType::I64],
+        since: 29,
+        capability: Some(Capability::Reseller),
+        mutating: true,
+        doc: "Creates a sub-account of the caller funded with the given minor units of the \
+              caller's currency from the caller's balance and returns its id or the negated \
+              error code.",
+        errors: &[
+            BillingError::MissingCapability,
+            BillingError::InvalidArgumentValue,
+            BillingError::TotalCostExceededMaxValue,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::PolicyDenied,
+            BillingError::FeatureDisabled,
+            BillingError::ApprovalRequired,
+            BillingError::TransactionActive,
+            BillingError::CallVetoed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "fund_sub_account",
+        params: &[ValType::I64, ValType::I64],
+        results: &[ValType::I32],
+        since: 29,
+        capability: Some(Capability::Reseller),
+        mutating: true,
+        doc: "Moves the given minor units from the caller's balance to the sub-account's.",
+        errors: &[
+            BillingError::MissingCapability,
+            BillingError::NotSubAccount,
+            BillingError::InvalidArgumentValue,
+            BillingError::TotalCostExceededMaxValue,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::PolicyDenied,
+            BillingError::FeatureDisabled,
+            BillingError::ApprovalRequired,
+            BillingError::TransactionActive,
+            BillingError::CallVetoed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "withdraw_from_sub_account",
+        params: &[ValType::I64, ValType::I64],
+        results: &[ValType::I32],
+        since: 29,
+        capability: Some(Capability::Reseller),
+        mutating: true,
+        doc: "Moves the given minor units from the sub-account's balance back to the caller's.",
+        errors: &[
+            BillingError::MissingCapability,
+            BillingError::NotSubAccount,
+            BillingError::InvalidArgumentValue,
+            BillingError::TotalCostExceededMaxValue,
+            BillingError::NegativeBalance,
+            BillingError::BalanceWouldBecomeNegative,
+            BillingError::PolicyDenied,
+            BillingError::FeatureDisabled,
+            BillingError::ApprovalRequired,
+            BillingError::TransactionActive,
+            BillingError::CallVetoed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "set_markup",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+        since: 29,
+        capability: Some(Capability::Reseller),
+        mutating: true,
+        doc: "Sets the markup, in basis points of at most 10000, the sub-accounts of the caller \
+              pay on top of the catalog prices of the bundles they order. The markup is \
+              credited to the caller.",
+        errors: &[
+            BillingError::MissingCapability,
+            BillingError::InvalidArgumentValue,
+            BillingError::TransactionActive,
+            BillingError::CallVetoed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "sub_account_balance",
+        params: &[ValType::I64],
+        results: &[ValType::I64],
+        since: 29,
+        capability: Some(Capability::Reseller),
+        mutating: false,
+        doc: "Returns the balance of the sub-account in minor units or the negated error code.",
+        errors: &[BillingError::MissingCapability, BillingError::NotSubAccount],
+        pricing: None,
+    },
+    HostFunction {
+        name: "reseller_billing",
+        params: &[],
+        results: &[ValType::I64],
+        since: 29,
+        capability: Some(Capability::Reseller),
+        mutating: false,
+        doc: "Returns the balances and the billable charges of every account in the tree of \
+              sub-accounts below the caller, with their totals, as a JSON object in a buffer \
+              allocated with the guest's `alloc` export. The result is the pointer in the upper \
+              32 bits and the length in the lower 32 bits, or the negated error code.",
+        errors: &[
+            BillingError::MissingCapability,
+            BillingError::TotalCostExceededMaxValue,
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestAllocatorMissing,
+            BillingError::GuestAllocationFailed,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "last_error_context",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 30,
+        capability: None,
+        mutating: false,
+        doc: "Writes up to `len` bytes of the context of the last error, its key/value pairs \
+              such as the limit and the usage of an exceeded quota, into the buffer and returns \
+              its full length. Returns 0 if the error has no context. The failed mutating calls \
+              announce the length of the context in their result, see `abi`.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "set_default_cost_center",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 31,
+        capability: None,
+        mutating: false,
+        doc: "Tags the charges of the subsequent calls of the invocation with the cost center \
+              named in UTF-8, e.g. a project or an environment, of at most 64 letters, digits \
+              and `-_.:/`. A `len` of 0 leaves them untagged.",
+        errors: &[
+            BillingError::InvalidArgumentValue,
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "price_of",
+        params: &[ValType::I32, ValType::I32, ValType::I64],
+        results: &[ValType::I64],
+        since: 32,
+        capability: None,
+        mutating: false,
+        doc: "Returns the price the caller would be charged for the quantity of the item named \
+              in UTF-8, `hosting` for days of hosting or a service or bundle of the catalog, in \
+              minor units, or the negated error code. The price includes the markup of the \
+              caller's reseller and the discount of the caller's group.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::InvalidArgumentValue,
+            BillingError::UnknownService,
+            BillingError::TotalCostExceededMaxValue,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "price_catalog",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 32,
+        capability: None,
+        mutating: false,
+        doc: "Writes up to `len` bytes of the prices the caller would be charged for a day of \
+              hosting and for every service and bundle of the catalog, as a JSON array of \
+              objects with the `name`, the `kind`, the `list_price` and the `price`, into the \
+              buffer and returns its full length, so that the guest can retry with a larger \
+              buffer.",
+        errors: &[
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+            BillingError::UnknownService,
+            BillingError::TotalCostExceededMaxValue,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "set_notification_pref",
+        params: &[ValType::I32, ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 33,
+        capability: None,
+        mutating: true,
+        doc: "Enables, with 1, or disables, with 0, the notifications of the user about the \
+              event, 0 for a low balance, 1 for an expiry, 2 for an invoice, 3 for an incident, \
+              4 for a spending alert and 5 for a cost anomaly, on the channel, 0 for the log, 1 \
+              for email and 2 for webhooks. Every event is notified on every channel by default.",
+        errors: &[BillingError::InvalidArgumentValue, BillingError::CallVetoed],
+        pricing: None,
+    },
+    HostFunction {
+        name: "now_secs",
+        params: &[],
+        results: &[ValType::I64],
+        since: 34,
+        capability: None,
+        mutating: false,
+        doc: "Returns the seconds since the Unix epoch by the clock of the ledger, pinned in the \
+              deterministic mode, so that the guests can date what they bill without WASI.",
+        errors: &[],
+        pricing: None,
+    },
+    HostFunction {
+        name: "random_u64",
+        params: &[],
+        results: &[ValType::I64],
+        since: 34,
+        capability: None,
+        mutating: false,
+        doc: "Returns 64 random bits from the randomness of the WASI `random_get`, seeded in the \
+              deterministic mode, so that the guests can draw random numbers without WASI.",
+        errors: &[],
+        pricing: None,
+    },
+    HostFunction {
+        name: "service_health",
+        params: &[ValType::I64],
+        results: &[ValType::I32],
+        since: 35,
+        capability: None,
+        mutating: false,
+        doc: "Returns the health of the services provisioned by a bundle order of the caller by \
+              their last health checks: 0 if healthy, 1 if some are failing, 2 if all are \
+              failing and 3 if none has been checked yet.",
+        errors: &[BillingError::UnknownOrder, BillingError::UnknownService],
+        pricing: None,
+    },
+    HostFunction {
+        name: "incidents_since",
+        params: &[ValType::I64, ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+        since: 35,
+        capability: None,
+        mutating: false,
+        doc: "Writes up to `len` bytes of the incidents of the services of the caller this month \
+              that were open at the given second since the Unix epoch or started later as JSON \
+              Lines into the buffer and returns the full length, e.g. to build status pages.",
+        errors: &[
+            BillingError::InvalidArgumentValue,
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "ledger_page",
+        params: &[ValType::I64, ValType::I32, ValType::I32],
+        results: &[ValType::I64],
+        since: 36,
+        capability: None,
+        mutating: false,
+        doc: "Writes the entries of the caller's ledger from the cursor, 0 for the first page, \
+              that fit into the buffer as JSON Lines followed by an empty line and returns the \
+              cursor of the next page, the same one at the end. See `pagination`.",
+        errors: &[
+            BillingError::PageBufferTooSmall,
+            BillingError::InvalidArgumentValue,
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "orders_page",
+        params: &[ValType::I64, ValType::I32, ValType::I32],
+        results: &[ValType::I64],
+        since: 36,
+        capability: None,
+        mutating: false,
+        doc: "Writes the orders of the caller from the cursor, 0 for the first page, as the JSON \
+              of `order_details` like `ledger_page` and returns the cursor of the next page, the \
+              same one at the end.",
+        errors: &[
+            BillingError::PageBufferTooSmall,
+            BillingError::InvalidArgumentValue,
+            BillingError::GuestMemoryMissing,
+            BillingError::GuestMemoryOutOfBounds,
+        ],
+        pricing: None,
+    },
+    HostFunction {
+        name: "acknowledge_anomaly",
+        params: &[],
+        results: &[ValType::I32],
+        since: 37,
+        capability: None,
+        mutating: true,
+        doc: "Acknowledges the unusual spend of the caller detected by the host, resuming the \
+              orders and the transfers if the host paused them.",
+        errors: &[BillingError::NoActiveAnomaly, BillingError::CallVetoed],
+        pricing: None,
+    },
+];
+
+pub fn find_host_function(name: &str) -> Option<&'static HostFunction> {
+    HOST_FUNCTIONS.iter().find(|f| f.name == name)
+}
+
+// Whether the user may import the host function, e.g. as the policy for `inspect`.
+pub fn is_allowed(user_data: &UserData, name: &str) -> bool {
+    find_host_function(name).is_some_and(|f| {
+        f.capability
+            .is_none_or(|c| user_data.capabilities.contains(&c))
+    })
+}
+
+// The action of `policy::authorize` the host function performs, if any, whose feature the plan
+// of the user may disable, see `features::check_action`
+pub(crate) fn action_of(name: &str) -> Option<Action> {
+    match name {
+        "order_hosting" | "order_bundle" | "register_domain" => Some(Action::Order),
+        "transfer" => Some(Action::Transfer),
+        "cancel_service" => Some(Action::Refund),
+        _ => None,
+    }
+}
+
+pub(crate) fn signature<'a>(
+    params: impl Iterator<Item = &'a ValType>,
+    results: impl Iterator<Item = &'a ValType>,
+) -> String {
+    let join = |types: Vec<String>| types.join(", ");
+    format!(
+        "({}) -> ({})",
+        join(params.map(ToString::to_string).collect()),
+        join(results.map(ToString::to_string).collect())
+    )
+}
+
+// Checks that the guest imports a known host function with the declared signature,
+// so that a mismatch is reported precisely instead of as a failed instantiation.
+pub fn check_import(import: &ImportType<'_>) -> Result<(), Error> {
+    let host_function = find_host_function(import.name()).ok_or(HostError::UnknownImport)?;
+    let expected = signature(host_function.params.iter(), host_function.results.iter());
+    let found = match import.ty() {
+        ExternType::Func(ty) => {
+            let (params, results) = (
+                ty.params().collect::<Vec<_>>(),
+                ty.results().collect::<Vec<_>>(),
+            );
+            if params == host_function.params && results == host_function.results {
+                return Ok(());
+            }
+            signature(params.iter(), results.iter())
+        }
+        ExternType::Global(_) => "global".to_owned(),
+        ExternType::Table(_) => "table".to_owned(),
+        ExternType::Memory(_) => "memory".to_owned(),
+    };
+    Err(HostError::ImportSignatureMismatch {
+        name: import.name().to_owned(),
+        expected,
+        found,
+    }
+    .into())
+}
+
+// Remembers the error for `host.last_error_message` and returns its code for the guest.
+// Embedders should use it in their own host functions so that their custom errors
+// reach the guests the same way as the errors of the host.
+pub fn report_error(state: &mut State, error: impl Into<Error>) -> i32 {
+    let error = error.into();
+    let code = error.code();
+    state.last_error = Some(error.to_string());
+    state.last_error_code = Some(code);
+    state.last_error_context = error.context().to_vec();
+    code
+}
+
+// Runs a mutating operation on behalf of the user and settles the ledger entries
+// it produced, see `settle`. Returns the order or the error code for the guest.
+pub(crate) fn record_charges(
+    state: &mut State,
+    user: UserId,
+    op: impl FnOnce(&mut State) -> Result<(), Error>,
+) -> Result<Option<OrderId>, i32> {
+    let before = state.users.get(&user).map_or(0, |u| u.ledger.len());
+    if let Err(e) = op(state) {
+        return Err(report_error(state, e));
+    }
+    // The charges made within a transaction are settled once it is committed
+    if tx::is_active(state) {
+        return Ok(None);
+    }
+    Ok(settle(state, user, before))
+}
+
+// Credits the discount of the user's group, records the revenue of the ledger entries
+// of the user from `before` on, places
+// an order for the first purchase among them and credits the referrer of the user
+// if it is the user's first purchase
+pub(crate) fn settle(state: &mut State, user: UserId, before: usize) -> Option<OrderId> {
+    groups::apply_discount(state, user, before);
+    alerts::evaluate(state, user);
+    anomalies::evaluate(state, user);
+    let mut first_purchase = false;
+    let mut order = None;
+    if let Some(user_data) = state.users.get(&user) {
+        let (earlier, new) = user_data.ledger.split_at(before);
+        first_purchase = new.iter().any(|entry| entry.kind.is_purchase())
+            && !earlier.iter().any(|entry| entry.kind.is_purchase())
+            && user_data
+                .ledger_summaries
+                .iter()
+                .all(|summary| summary.purchases == 0);
+        for entry in new.iter().filter(|entry| entry.kind.is_billable()) {
+            if let Some(revenue) = entry.amount.checked_neg() {
+                state.stats.record_revenue(revenue);
+            }
+        }
+        if let Some(i) = new.iter().position(|entry| entry.kind.is_purchase()) {
+            let entry = user_data.archived_entries + before + i;
+            order = Some(state.orders.place(user, entry));
+        }
+    }
+    if first_purchase {
+        // The purchase itself has succeeded, so a failure to credit the referrer
+        // (e.g. an overflow of their balance) is not reported to the guest
+        let _ = billing::credit_referrer(&mut state.users, &state.config.registration, user);
+    }
+    order
+}
+
+// Orders the days of hosting for the user, see `host.order_hosting`
+pub(crate) fn order_hosting(state: &mut State, user: UserId, days: i32) -> Result<(), Error> {
+    let cost = billing::hosting_cost(&state.config.hosting, days)?;
+    policy::authorize(state, user, Action::Order, cost)?;
+    let user_data = state.users.get_mut(&user).unwrap();
+    billing::order_hosting(user_data, &state.config.hosting, &state.config.grace, days)
+}
+
+// Orders the bundle for the user, provisioning it once the transaction is committed if there is
+// one, see `host.order_bundle`
+pub(crate) fn order_bundle(state: &mut State, user: UserId, name: &str) -> Result<(), Error> {
+    let catalog = &state.config.catalog;
+    let cost = services::bundle_cost(catalog, &state.users, user, name)?;
+    policy::authorize(state, user, Action::Order, cost)?;
+    let catalog = &state.config.catalog;
+    match tx::is_active(state) {
+        true => {
+            let order =
+                services::order_bundle_unprovisioned(catalog, &mut state.users, user, name)?;
+            tx::defer_provisioning(state, order);
+            Ok(())
+        }
+        false => services::order_bundle(
+            catalog,
+            &mut state.users,
+            &mut *state.provisioner,
+            user,
+            name,
+        ),
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt::Write;
+
+use crate::{
+    config::Config,
+    host::{self, HostFunction, HOST_API_VERSION, HOST_FUNCTIONS, HOST_MODULE},
+    BillingError, Error,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DocsFormat {
+    Markdown,
+    Html,
+}
+
+impl DocsFormat {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "markdown" | "md" => Ok(DocsFormat::Markdown),
+            "html" => Ok(DocsFormat::Html),
+            _ => Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+}
+
+// The parts of the documentation of a host function, taken from `HOST_FUNCTIONS`
+// so that the documentation cannot get out of sync with the registry
+struct FunctionDocs {
+    name: &'static str,
+    signature: String,
+    since: u32,
+    capability: String,
+    doc: &'static str,
+    pricing: String,
+    // Codes and names, ordered by code
+    errors: Vec<(i32, &'static str)>,
+}
+
+impl FunctionDocs {
+    fn new(function: &HostFunction, config: &Config) -> Self {
+        let mut errors = function
+            .errors
+            .iter()
+            .map(|e| (e.code(), <&'static str>::from(e)))
+            .collect::<Vec<_>>();
+        errors.sort_unstable();
+        errors.dedup();
+        let own_pricing = function.pricing.map(|pricing| pricing(config));
+        let pricing = match (config.call_pricing.price(function.name), own_pricing) {
+            (Some(price), Some(own)) => format!("{price} per call, plus {own}"),
+            (Some(price), None) => format!("{price} per call"),
+            (None, Some(own)) => own,
+            (None, None) => "free".to_owned(),
+        };
+        Self {
+            name: function.name,
+            signature: host::signature(function.params.iter(), function.results.iter()),
+            since: function.since,
+            capability: function
+                .capability
+                .map_or("none".to_owned(), |c| format!("{c:?}")),
+            doc: function.doc,
+            pricing,
+            errors,
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Renders the `code` spans of the descriptions, which are written in Markdown
+fn inline_html(s: &str) -> String {
+    escape_html(s)
+        .split('`')
+        .enumerate()
+        .map(|(i, part)| match i % 2 {
+            0 => part.to_owned(),
+            _ => format!("<code>{part}</code>"),
+        })
+        .collect()
+}
+
+fn markdown(functions: &[FunctionDocs]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# Host API v{HOST_API_VERSION}\n");
+    let _ = writeln!(
+        out,
+        "Guests import these functions from the `{HOST_MODULE}` module. \
+         The message of the last reported error is available through `last_error_message`. \
+         Besides the listed errors, any function may fail with an error of the host itself, \
+         whose code is not part of the API.\n"
+    );
+    for f in functions {
+        let _ = writeln!(out, "## `{}`\n", f.name);
+        let _ = writeln!(out, "`{}`\n", f.signature);
+        let _ = writeln!(out, "- Since: v{}", f.since);
+        let _ = writeln!(out, "- Capability: {}", f.capability);
+        let _ = writeln!(out, "- Pricing: {}\n", f.pricing);
+        let _ = writeln!(out, "{}\n", f.doc);
+        if !f.errors.is_empty() {
+            let _ = writeln!(out, "| Code | Error |\n| ---: | --- |");
+            for (code, name) in &f.errors {
+                let _ = writeln!(out, "| {code} | `{name}` |");
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn html(functions: &[FunctionDocs]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\">\
+         <title>Host API v{HOST_API_VERSION}</title></head>\n<body>"
+    );
+    let _ = writeln!(out, "<h1>Host API v{HOST_API_VERSION}</h1>");
+    let _ = writeln!(
+        out,
+        "<p>Guests import these functions from the <code>{HOST_MODULE}</code> module. \
+         The message of the last reported error is available through \
+         <code>last_error_message</code>. \
+         Besides the listed errors, any function may fail with an error of the host itself, \
+         whose code is not part of the API.</p>"
+    );
+    for f in functions {
+        let _ = writeln!(out, "<h2 id=\"{0}\"><code>{0}</code></h2>", f.name);
+        let _ = writeln!(out, "<pre>{}</pre>", escape_html(&f.signature));
+        let _ = writeln!(
+            out,
+            "<ul><li>Since: v{}</li><li>Capability: {}</li><li>Pricing: {}</li></ul>",
+            f.since,
+            escape_html(&f.capability),
+            escape_html(&f.pricing)
+        );
+        let _ = writeln!(out, "<p>{}</p>", inline_html(f.doc));
+        if !f.errors.is_empty() {
+            let _ = writeln!(out, "<table>\n<tr><th>Code</th><th>Error</th></tr>");
+            for (code, name) in &f.errors {
+                let _ = writeln!(out, "<tr><td>{code}</td><td><code>{name}</code></td></tr>");
+            }
+            let _ = writeln!(out, "</table>");
+        }
+    }
+    let _ = writeln!(out, "</body>\n</html>");
+    out
+}
+
+// Documents every host function, with the prices under the configuration
+pub fn render(config: &Config, format: DocsFormat) -> String {
+    let functions = HOST_FUNCTIONS
+        .iter()
+        .map(|f| FunctionDocs::new(f, config))
+        .collect::<Vec<_>>();
+    match format {
+        DocsFormat::Markdown => markdown(&functions),
+        DocsFormat::Html => html(&functions),
+    }
+}
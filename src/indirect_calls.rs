@@ -0,0 +1,145 @@
+// Restrictions on how the guests call functions indirectly, for the plans whose guests must not
+// be able to call functions they cannot see in their own module, see `IndirectCallConfig`.
+// A module calling `call_indirect` through a table imported from the host or another module
+// may end up calling any function put into the table from outside, and a module changing its
+// function tables at runtime makes the targets of its indirect calls impossible to audit from
+// its code. The modules breaking the restrictions of the plan of the user are rejected before
+// they are compiled with `HostError::ModuleRestricted`, whose message explains what to change.
+
+use wasmparser::{Operator, Parser, Payload, TypeRef};
+
+use crate::{plan::Plan, Error, HostError, State, UserId};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IndirectCallRules {
+    // Rejects `call_indirect` and `return_call_indirect` through imported tables
+    pub deny_imported_tables: bool,
+    // Rejects `table.set`, `table.grow`, `table.fill`, `table.copy` and `table.init` on the
+    // function tables
+    pub deny_table_mutations: bool,
+}
+
+impl IndirectCallRules {
+    fn is_unrestricted(self) -> bool {
+        !self.deny_imported_tables && !self.deny_table_mutations
+    }
+}
+
+// The restrictions by the plan of the user, none by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IndirectCallConfig {
+    pub trial: IndirectCallRules,
+    pub paid: IndirectCallRules,
+    pub grace: IndirectCallRules,
+    pub suspended: IndirectCallRules,
+}
+
+impl IndirectCallConfig {
+    pub fn rules(&self, plan: Plan) -> IndirectCallRules {
+        match plan {
+            Plan::Trial { .. } => self.trial,
+            Plan::Paid => self.paid,
+            Plan::Grace { .. } => self.grace,
+            Plan::Suspended => self.suspended,
+        }
+    }
+}
+
+fn restricted(message: String) -> Error {
+    HostError::ModuleRestricted(message).into()
+}
+
+// Checks the module, in the binary or the text format, against the rules. Modules that fail to
+// parse are left to fail to compile.
+pub fn check_module(bytes: &[u8], rules: IndirectCallRules) -> Result<(), Error> {
+    if rules.is_unrestricted() {
+        return Ok(());
+    }
+    let Ok(bytes) = wat::parse_bytes(bytes) else {
+        return Ok(());
+    };
+    // Whether the tables, by index, are imported and whether they hold functions
+    let mut tables = Vec::<(bool, bool)>::new();
+    let mut function = 0;
+    for payload in Parser::new(0).parse_all(&bytes) {
+        let Ok(payload) = payload else {
+            return Ok(());
+        };
+        match payload {
+            Payload::ImportSection(reader) => {
+                for import in reader.into_iter().flatten() {
+                    match import.ty {
+                        TypeRef::Func(_) => function += 1,
+                        TypeRef::Table(table) => {
+                            tables.push((true, table.element_type.is_func_ref()))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Payload::TableSection(reader) => {
+                for table in reader.into_iter().flatten() {
+                    tables.push((false, table.ty.element_type.is_func_ref()));
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                check_function(body, function, &tables, rules)?;
+                function += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn check_function(
+    body: wasmparser::FunctionBody<'_>,
+    function: u32,
+    tables: &[(bool, bool)],
+    rules: IndirectCallRules,
+) -> Result<(), Error> {
+    let is_imported = |table: u32| {
+        tables
+            .get(table as usize)
+            .is_some_and(|&(imported, _)| imported)
+    };
+    let holds_functions = |table: u32| tables.get(table as usize).is_some_and(|&(_, funcs)| funcs);
+    let Ok(operators) = body.get_operators_reader() else {
+        return Ok(());
+    };
+    for operator in operators.into_iter().flatten() {
+        let (instruction, table) = match operator {
+            Operator::CallIndirect { table_index, .. }
+            | Operator::ReturnCallIndirect { table_index, .. }
+                if rules.deny_imported_tables && is_imported(table_index) =>
+            {
+                return Err(restricted(format!(
+                    "function {function} calls indirectly through the imported table \
+                     {table_index}, define the table in the module instead of importing it"
+                )));
+            }
+            Operator::TableSet { table } => ("table.set", table),
+            Operator::TableGrow { table } => ("table.grow", table),
+            Operator::TableFill { table } => ("table.fill", table),
+            Operator::TableCopy { dst_table, .. } => ("table.copy", dst_table),
+            Operator::TableInit { table, .. } => ("table.init", table),
+            _ => continue,
+        };
+        if rules.deny_table_mutations && holds_functions(table) {
+            return Err(restricted(format!(
+                "function {function} changes the function table {table} with `{instruction}`, \
+                 fill the table with active element segments when the module is instantiated \
+                 instead"
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Checks the module against the rules of the plan of the user
+pub fn check(state: &State, user: UserId, bytes: &[u8]) -> Result<(), Error> {
+    match state.users.get(&user) {
+        Some(user_data) => check_module(bytes, state.config.indirect_calls.rules(user_data.plan)),
+        None => Ok(()),
+    }
+}
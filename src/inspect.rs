@@ -0,0 +1,176 @@
+use std::fmt;
+
+use wasmtime::{ExternType, Module};
+
+use crate::{
+    features,
+    host::{self, HOST_MODULE},
+    runtime::{WasmRuntime, WasmtimeRuntime},
+    store::UserStore,
+    BillingError, Error, State, UserId,
+};
+
+pub struct ImportReport {
+    pub module: String,
+    pub name: String,
+    pub kind: &'static str,
+    pub resolves: bool,
+}
+
+pub struct ExportReport {
+    pub name: String,
+    pub kind: &'static str,
+}
+
+pub struct MemoryReport {
+    pub name: String,
+    pub min_pages: u64,
+    pub max_pages: Option<u64>,
+}
+
+// Static analysis of a module. Producing it neither instantiates the module
+// nor touches any user's balance.
+pub struct ModuleReport {
+    pub imports: Vec<ImportReport>,
+    pub exports: Vec<ExportReport>,
+    pub memories: Vec<MemoryReport>,
+    // The minimal host API version providing all of the imported host functions,
+    // `None` if the module imports an unknown host function.
+    pub required_host_api_version: Option<u32>,
+    // Size of the compiled machine code in bytes
+    pub estimated_code_size: usize,
+}
+
+impl ModuleReport {
+    pub fn all_imports_resolve(&self) -> bool {
+        self.imports.iter().all(|import| import.resolves)
+    }
+}
+
+fn extern_kind(ty: &ExternType) -> &'static str {
+    match ty {
+        ExternType::Func(_) => "func",
+        ExternType::Global(_) => "global",
+        ExternType::Table(_) => "table",
+        ExternType::Memory(_) => "memory",
+    }
+}
+
+fn memory_report(name: String, ty: &ExternType) -> Option<MemoryReport> {
+    let ExternType::Memory(memory) = ty else {
+        return None;
+    };
+    Some(MemoryReport {
+        name,
+        min_pages: memory.minimum(),
+        max_pages: memory.maximum(),
+    })
+}
+
+// The policy of `inspect` for the user: the host functions the capabilities of the user allow,
+// see `host::is_allowed`, unless the plan of the user disables their feature, see `features`.
+pub fn user_policy(state: &State, user: UserId) -> Result<impl Fn(&str) -> bool + '_, Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    Ok(move |name: &str| {
+        host::is_allowed(&user_data, name)
+            && host::action_of(name)
+                .is_none_or(|action| features::check_action(state, user, action).is_ok())
+    })
+}
+
+// `policy` decides whether the user on whose behalf the module is inspected
+// may import the given host function.
+pub fn inspect(
+    runtime: &WasmtimeRuntime,
+    bytes: &[u8],
+    policy: impl Fn(&str) -> bool,
+) -> Result<ModuleReport, Error> {
+    let module: Module = runtime.compile(bytes)?;
+    // The store is only needed to look up the definitions in the linker
+    let mut store = runtime.new_store(State::new(UserStore::new()));
+
+    let mut required_host_api_version = Some(1);
+    let mut memories = Vec::new();
+    let imports = module
+        .imports()
+        .map(|import| {
+            let ty = import.ty();
+            let resolves = if import.module() == HOST_MODULE {
+                let since = host::find_host_function(import.name()).map(|f| f.since);
+                required_host_api_version =
+                    required_host_api_version.zip(since).map(|(a, b)| a.max(b));
+                since.is_some() && host::check_import(&import).is_ok() && policy(import.name())
+            } else {
+                runtime
+                    .linker()
+                    .get_by_import(&mut store, &import)
+                    .is_some()
+            };
+            memories.extend(memory_report(
+                format!("{}.{}", import.module(), import.name()),
+                &ty,
+            ));
+            ImportReport {
+                module: import.module().to_owned(),
+                name: import.name().to_owned(),
+                kind: extern_kind(&ty),
+                resolves,
+            }
+        })
+        .collect();
+    let exports = module
+        .exports()
+        .map(|export| {
+            let ty = export.ty();
+            memories.extend(memory_report(export.name().to_owned(), &ty));
+            ExportReport {
+                name: export.name().to_owned(),
+                kind: extern_kind(&ty),
+            }
+        })
+        .collect();
+
+    Ok(ModuleReport {
+        imports,
+        exports,
+        memories,
+        required_host_api_version,
+        estimated_code_size: module.image_range().len(),
+    })
+}
+
+impl fmt::Display for ModuleReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Imports:")?;
+        for import in &self.imports {
+            let status = if import.resolves { "ok" } else { "UNRESOLVED" };
+            writeln!(
+                f,
+                "  {}.{} ({}) [{status}]",
+                import.module, import.name, import.kind
+            )?;
+        }
+        writeln!(f, "Exports:")?;
+        for export in &self.exports {
+            writeln!(f, "  {} ({})", export.name, export.kind)?;
+        }
+        writeln!(f, "Memories:")?;
+        for memory in &self.memories {
+            let max = match memory.max_pages {
+                Some(max) => max.to_string(),
+                None => "unbounded".to_owned(),
+            };
+            writeln!(
+                f,
+                "  {}: min {} pages, max {max}",
+                memory.name, memory.min_pages
+            )?;
+        }
+        match self.required_host_api_version {
+            Some(version) => writeln!(f, "Required host API version: {version}")?,
+            None => writeln!(f, "Required host API version: unknown")?,
+        }
+        writeln!(f, "Estimated code size: {} bytes", self.estimated_code_size)?;
+        write!(f, "All imports resolve: {}", self.all_imports_resolve())
+    }
+}
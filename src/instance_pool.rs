@@ -0,0 +1,163 @@
+// Pre-instantiated instances for latency-sensitive workloads. The pool keeps up to
+// `InstancePoolConfig::instances_per_key` idle instances of every module for every user (the
+// host functions an instance may call depend on the capabilities of its user), and
+// `history::execute_pooled` checks one out instead of compiling and instantiating the module.
+// Instances are not reused: the one checked out is dropped after its call, and `refill`,
+// e.g. called while the host is idle, instantiates its replacement.
+//
+// The instances belong to the store they were created in, so a pool must only be used
+// with one store. The start functions of the modules run when their instances are
+// created, ahead of the invocations.
+
+use std::collections::HashMap;
+
+use crate::{module_hash, runtime::WasmRuntime, Error, UserId};
+
+#[derive(Clone, Copy, Debug)]
+pub struct InstancePoolConfig {
+    // The idle instances kept of every module for every user
+    pub instances_per_key: usize,
+    // The (module, user) pairs pooled at most, the others are instantiated on demand
+    pub max_keys: usize,
+}
+
+impl Default for InstancePoolConfig {
+    fn default() -> Self {
+        Self {
+            instances_per_key: 2,
+            max_keys: 64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    // Invocations served by an idle instance
+    pub hits: u64,
+    // Invocations that had to instantiate their module
+    pub misses: u64,
+}
+
+impl PoolStats {
+    // `None` before the first invocation
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 / total as f64)
+    }
+}
+
+pub struct InstancePool<R: WasmRuntime> {
+    config: InstancePoolConfig,
+    // The compiled modules by their hashes
+    modules: HashMap<String, R::Module>,
+    idle: HashMap<(String, UserId), Vec<R::Instance>>,
+    stats: PoolStats,
+}
+
+impl<R: WasmRuntime> InstancePool<R> {
+    pub fn new(config: InstancePoolConfig) -> Self {
+        Self {
+            config,
+            modules: HashMap::new(),
+            idle: HashMap::new(),
+            stats: PoolStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.stats
+    }
+
+    // The idle instances of the module for the user
+    pub fn idle(&self, user: UserId, bytes: &[u8]) -> usize {
+        self.idle
+            .get(&(module_hash(bytes), user))
+            .map_or(0, Vec::len)
+    }
+
+    // Compiles the module once, keeping it for the instances to come
+    fn module(&mut self, runtime: &R, hash: &str, bytes: &[u8]) -> Result<&R::Module, Error> {
+        if !self.modules.contains_key(hash) {
+            let module = runtime.compile(bytes)?;
+            self.modules.insert(hash.to_owned(), module);
+        }
+        Ok(&self.modules[hash])
+    }
+
+    // Pools the module for the user, filling its idle instances up right away. Returns false
+    // if the pool already holds `InstancePoolConfig::max_keys` other pairs.
+    pub fn warm(
+        &mut self,
+        runtime: &R,
+        store: &mut R::Store,
+        user: UserId,
+        bytes: &[u8],
+    ) -> Result<bool, Error> {
+        let hash = module_hash(bytes);
+        let key = (hash.clone(), user);
+        if !self.idle.contains_key(&key) && self.idle.len() >= self.config.max_keys {
+            return Ok(false);
+        }
+        self.module(runtime, &hash, bytes)?;
+        self.idle.entry(key.clone()).or_default();
+        self.fill(runtime, store, &key)?;
+        Ok(true)
+    }
+
+    fn fill(
+        &mut self,
+        runtime: &R,
+        store: &mut R::Store,
+        key: &(String, UserId),
+    ) -> Result<(), Error> {
+        let module = &self.modules[&key.0];
+        let idle = self.idle.get_mut(key).unwrap();
+        while idle.len() < self.config.instances_per_key {
+            idle.push(runtime.prewarm(store, module, key.1)?);
+        }
+        Ok(())
+    }
+
+    // Replaces the instances checked out since the last refill. The pairs whose module
+    // no longer instantiates, e.g. because the user lost a capability, are no longer pooled.
+    pub fn refill(&mut self, runtime: &R, store: &mut R::Store) {
+        let keys = self.idle.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            if self.fill(runtime, store, &key).is_err() {
+                self.idle.remove(&key);
+            }
+        }
+        let pooled = self.idle.keys().map(|(hash, _)| hash).collect::<Vec<_>>();
+        self.modules.retain(|hash, _| pooled.contains(&hash));
+    }
+
+    // An instance of the module for the user, ready to be called. The module is pooled
+    // from then on if the pool has room for it.
+    pub(crate) fn checkout(
+        &mut self,
+        runtime: &R,
+        store: &mut R::Store,
+        user: UserId,
+        bytes: &[u8],
+    ) -> Result<R::Instance, Error> {
+        let hash = module_hash(bytes);
+        let key = (hash.clone(), user);
+        let pooled = self.idle.get_mut(&key).and_then(Vec::pop);
+        let instance = match pooled {
+            Some(instance) => {
+                self.stats.hits += 1;
+                instance
+            }
+            None => {
+                self.stats.misses += 1;
+                self.module(runtime, &hash, bytes)?;
+                if self.idle.len() < self.config.max_keys {
+                    self.idle.entry(key).or_default();
+                }
+                runtime.prewarm(store, &self.modules[&hash], user)?
+            }
+        };
+        runtime.activate(store, &self.modules[&hash], &instance, user);
+        Ok(instance)
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    ledger::EntryKind,
+    locale::Locale,
+    money::{MoneySum, MoneyUnit},
+    BillingError, Error, UserData, UserId,
+};
+
+pub struct InvoiceLine {
+    pub at: u64,
+    pub kind: EntryKind,
+    // The charged amount, always positive
+    pub amount: MoneyUnit,
+    // See `cost_centers`
+    pub cost_center: Option<String>,
+}
+
+pub struct Invoice {
+    pub user: UserId,
+    // Seconds since the Unix epoch, `to` is exclusive
+    pub from: u64,
+    pub to: u64,
+    pub lines: Vec<InvoiceLine>,
+    pub total: MoneyUnit,
+}
+
+impl EntryKind {
+    // Whether the entry is a charge for a service or a discount on one. Transfers between
+    // users and free trial usage are not invoiced.
+    pub fn is_billable(&self) -> bool {
+        match self {
+            EntryKind::HostingOrder { .. }
+            | EntryKind::TransferFee
+            | EntryKind::LateFee
+            | EntryKind::MemoryUsage { .. }
+            | EntryKind::ComputeUsage { .. }
+            | EntryKind::BundleOrder { .. }
+            | EntryKind::StorageRequest { .. }
+            | EntryKind::StorageDay { .. }
+            | EntryKind::StorageCycle { .. }
+            | EntryKind::DomainRegistration { .. }
+            | EntryKind::DomainRenewal { .. }
+            | EntryKind::EmailSent { .. }
+            | EntryKind::DatabaseMonth
+            | EntryKind::CertificateIssued { .. }
+            | EntryKind::BandwidthOverage { .. }
+            | EntryKind::MessageSent { .. }
+            | EntryKind::ScheduledRun { .. }
+            | EntryKind::GroupDiscount { .. }
+            | EntryKind::SlaCredit { .. }
+            | EntryKind::ModuleInstall { .. }
+            | EntryKind::HostCalls { .. }
+            | EntryKind::ResellerMarkup { .. } => true,
+            EntryKind::TransferIn { .. }
+            | EntryKind::TransferOut { .. }
+            | EntryKind::TrialStarted { .. }
+            | EntryKind::ReferralCredit { .. }
+            | EntryKind::DisputeRefund { .. }
+            | EntryKind::CancellationRefund { .. }
+            | EntryKind::Reconciliation { .. }
+            | EntryKind::Adjustment { .. }
+            | EntryKind::TopUp { .. }
+            | EntryKind::ModuleSale { .. }
+            | EntryKind::SubAccountFunding { .. }
+            | EntryKind::ResellerFunding { .. }
+            | EntryKind::MarkupEarned { .. }
+            | EntryKind::Chargeback { .. }
+            | EntryKind::WriteOff
+            | EntryKind::PostpaidEnrollment
+            | EntryKind::InvoicePayment { .. }
+            | EntryKind::Import => false,
+        }
+    }
+}
+
+pub fn invoice(user: UserId, user_data: &UserData, from: u64, to: u64) -> Result<Invoice, Error> {
+    let lines = user_data
+        .ledger
+        .iter()
+        .filter(|entry| (from..to).contains(&entry.at) && entry.kind.is_billable())
+        .map(|entry| {
+            Ok(InvoiceLine {
+                at: entry.at,
+                kind: entry.kind.clone(),
+                amount: entry
+                    .amount
+                    .checked_neg()
+                    .ok_or(BillingError::TotalCostExceededMaxValue)?,
+                cost_center: entry.cost_center.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let total = lines
+        .iter()
+        .map(|line| line.amount)
+        .checked_sum(user_data.balance.currency())?;
+    Ok(Invoice {
+        user,
+        from,
+        to,
+        lines,
+        total,
+    })
+}
+
+impl Invoice {
+    // The totals of the lines by their cost centers, `None` for the untagged lines, in the order
+    // of the names
+    pub fn totals_by_cost_center(&self) -> Result<BTreeMap<Option<&str>, MoneyUnit>, Error> {
+        let mut grouped = BTreeMap::<Option<&str>, Vec<MoneyUnit>>::new();
+        for line in &self.lines {
+            grouped
+                .entry(line.cost_center.as_deref())
+                .or_default()
+                .push(line.amount);
+        }
+        grouped
+            .into_iter()
+            .map(|(name, amounts)| {
+                let total = amounts.into_iter().checked_sum(self.total.currency())?;
+                Ok((name, total))
+            })
+            .collect()
+    }
+
+    // Renders the invoice as a plain text statement with the dates and the amounts
+    // formatted for the locale, e.g. the one of the user
+    pub fn render(&self, locale: Locale) -> String {
+        let mut statement = format!(
+            "Statement of user {}, {} - {}\n",
+            self.user.0,
+            locale.format_date(self.from),
+            locale.format_date(self.to.saturating_sub(1))
+        );
+        for line in &self.lines {
+            let details = line.kind.details();
+            let description = if details.is_empty() {
+                line.kind.name().to_owned()
+            } else {
+                format!("{} ({details})", line.kind.name())
+            };
+            statement.push_str(&format!(
+                "{}  {description}  {}\n",
+                locale.format_date(line.at),
+                locale.format_money(line.amount)
+            ));
+        }
+        statement.push_str(&format!("Total  {}\n", locale.format_money(self.total)));
+        // Tagged statements are broken down by cost center
+        let totals = self.totals_by_cost_center().unwrap_or_default();
+        if totals.keys().any(Option::is_some) {
+            for (name, total) in totals {
+                statement.push_str(&format!(
+                    "Cost center {}  {}\n",
+                    name.unwrap_or("(none)"),
+                    locale.format_money(total)
+                ));
+            }
+        }
+        statement
+    }
+}
@@ -0,0 +1,423 @@
+use std::{
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cost_centers, cron::JobId, determinism, disputes::DisputeId, money::MoneyUnit,
+    storage::StorageOp, trace, BillingError, Error, HostError, UserId,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    HostingOrder {
+        days: u32,
+    },
+    TransferOut {
+        to: UserId,
+    },
+    TransferIn {
+        from: UserId,
+    },
+    TransferFee,
+    TrialStarted {
+        days: u32,
+    },
+    BundleOrder {
+        bundle: String,
+    },
+    // Credit for a referred user's first purchase
+    ReferralCredit {
+        referred: UserId,
+    },
+    DisputeRefund {
+        dispute: DisputeId,
+    },
+    StorageRequest {
+        op: StorageOp,
+    },
+    // A day of keeping the objects of the given total size, billed daily before `StorageCycle`
+    StorageDay {
+        bytes: u64,
+    },
+    // The objects and files stored during a billing cycle, see `quota`
+    StorageCycle {
+        byte_days: u64,
+    },
+    DomainRegistration {
+        domain: String,
+        years: u32,
+    },
+    DomainRenewal {
+        domain: String,
+    },
+    EmailSent {
+        to: String,
+    },
+    DatabaseMonth,
+    CertificateIssued {
+        domain: String,
+    },
+    // The bandwidth beyond the plan allowance served since the previous charge
+    BandwidthOverage {
+        bytes: u64,
+    },
+    MessageSent {
+        to: UserId,
+    },
+    ScheduledRun {
+        job: JobId,
+    },
+    // A payment reversed externally, e.g. by the card issuer
+    Chargeback {
+        reference: String,
+    },
+    // An unrecoverable negative balance forgiven by the operator
+    WriteOff,
+    // Charged with a hosting order placed during the grace period
+    LateFee,
+    // The peak linear memory of an invocation held for its duration
+    MemoryUsage {
+        pages: u64,
+        duration_micros: u64,
+    },
+    // The discount of the user's group on the charges of a call, i.e. on the entries from the
+    // index `since` on, at the rate of the group then, see `groups`
+    GroupDiscount {
+        group: String,
+        #[serde(default)]
+        since: usize,
+        #[serde(default)]
+        basis_points: u32,
+    },
+    // The credit limit granted to a postpaid account, see `postpaid`
+    PostpaidEnrollment,
+    // The payment of an invoice of a postpaid account, restoring its credit
+    InvoicePayment {
+        invoice: u64,
+    },
+    // The credit for a service that fell short of its service level for a month, see `sla`
+    SlaCredit {
+        service: String,
+    },
+    // The change of the balance of an account updated by a bulk import, see `bulk`
+    Import,
+    // The refund of an order cancelled by the user, see `cancellation`
+    CancellationRefund {
+        order: u64,
+    },
+    // The repair of a balance that differed from the ledger, of no amount, see `reconcile`
+    Reconciliation {
+        previous: MoneyUnit,
+    },
+    // The installation of a module of the marketplace, see `marketplace`
+    ModuleInstall {
+        listing: u64,
+    },
+    // The price of an installation of a module credited to its author
+    ModuleSale {
+        listing: u64,
+    },
+    // The calls of a priced host function made by an invocation, see `metering::charge_calls`
+    HostCalls {
+        function: String,
+        calls: u64,
+    },
+    // Money moved by a reseller to its sub-account, negative, or back, see `resellers`
+    SubAccountFunding {
+        account: UserId,
+    },
+    // The same movement on the side of the sub-account
+    ResellerFunding {
+        reseller: UserId,
+    },
+    // The markup of the reseller on an order of the sub-account
+    ResellerMarkup {
+        reseller: UserId,
+    },
+    // The same markup credited to the reseller
+    MarkupEarned {
+        account: UserId,
+    },
+    // The fuel of the invocations charged since the last entry, see `metering::charge_compute`
+    ComputeUsage {
+        fuel: u64,
+    },
+    // A manual correction by the operators, see `adjustments`
+    Adjustment {
+        adjustment: u64,
+        reason: String,
+    },
+    // A payment settled by a bank or a payment service provider, see `settlements`
+    TopUp {
+        transaction: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    pub kind: EntryKind,
+    // Negative amounts are debits, positive ones are credits
+    pub amount: MoneyUnit,
+    pub balance_after: MoneyUnit,
+    // The trace of the invocation that made the entry, see `trace`
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    // See `cost_centers`
+    #[serde(default)]
+    pub cost_center: Option<String>,
+}
+
+// The pinned time during the deterministic invocations, see `determinism`
+pub(crate) fn now_secs() -> u64 {
+    if let Some(secs) = determinism::pinned_secs() {
+        return secs;
+    }
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl LedgerEntry {
+    pub(crate) fn new(kind: EntryKind, amount: MoneyUnit, balance_after: MoneyUnit) -> Self {
+        Self {
+            at: now_secs(),
+            kind,
+            amount,
+            balance_after,
+            trace_id: trace::current(),
+            cost_center: cost_centers::current(),
+        }
+    }
+}
+
+impl EntryKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EntryKind::HostingOrder { .. } => "hosting_order",
+            EntryKind::TransferOut { .. } => "transfer_out",
+            EntryKind::TransferIn { .. } => "transfer_in",
+            EntryKind::TransferFee => "transfer_fee",
+            EntryKind::TrialStarted { .. } => "trial_started",
+            EntryKind::BundleOrder { .. } => "bundle_order",
+            EntryKind::ReferralCredit { .. } => "referral_credit",
+            EntryKind::DisputeRefund { .. } => "dispute_refund",
+            EntryKind::StorageRequest { .. } => "storage_request",
+            EntryKind::StorageDay { .. } => "storage_day",
+            EntryKind::StorageCycle { .. } => "storage_cycle",
+            EntryKind::DomainRegistration { .. } => "domain_registration",
+            EntryKind::DomainRenewal { .. } => "domain_renewal",
+            EntryKind::EmailSent { .. } => "email_sent",
+            EntryKind::DatabaseMonth => "database_month",
+            EntryKind::CertificateIssued { .. } => "certificate_issued",
+            EntryKind::BandwidthOverage { .. } => "bandwidth_overage",
+            EntryKind::MessageSent { .. } => "message_sent",
+            EntryKind::ScheduledRun { .. } => "scheduled_run",
+            EntryKind::Chargeback { .. } => "chargeback",
+            EntryKind::WriteOff => "write_off",
+            EntryKind::LateFee => "late_fee",
+            EntryKind::MemoryUsage { .. } => "memory_usage",
+            EntryKind::GroupDiscount { .. } => "group_discount",
+            EntryKind::PostpaidEnrollment => "postpaid_enrollment",
+            EntryKind::InvoicePayment { .. } => "invoice_payment",
+            EntryKind::SlaCredit { .. } => "sla_credit",
+            EntryKind::Import => "import",
+            EntryKind::CancellationRefund { .. } => "cancellation_refund",
+            EntryKind::Reconciliation { .. } => "reconciliation",
+            EntryKind::ModuleInstall { .. } => "module_install",
+            EntryKind::ModuleSale { .. } => "module_sale",
+            EntryKind::HostCalls { .. } => "host_calls",
+            EntryKind::SubAccountFunding { .. } => "sub_account_funding",
+            EntryKind::ResellerFunding { .. } => "reseller_funding",
+            EntryKind::ResellerMarkup { .. } => "reseller_markup",
+            EntryKind::MarkupEarned { .. } => "markup_earned",
+            EntryKind::ComputeUsage { .. } => "compute_usage",
+            EntryKind::Adjustment { .. } => "adjustment",
+            EntryKind::TopUp { .. } => "top_up",
+        }
+    }
+
+    // Whether the entry is an order of a service, as opposed to fees and movements of money
+    pub fn is_purchase(&self) -> bool {
+        matches!(
+            self,
+            EntryKind::HostingOrder { .. }
+                | EntryKind::BundleOrder { .. }
+                | EntryKind::DomainRegistration { .. }
+                | EntryKind::DatabaseMonth
+                | EntryKind::CertificateIssued { .. }
+                | EntryKind::ModuleInstall { .. }
+        )
+    }
+
+    pub fn details(&self) -> String {
+        match self {
+            EntryKind::HostingOrder { days } | EntryKind::TrialStarted { days } => {
+                format!("{days} days")
+            }
+            EntryKind::TransferOut { to } | EntryKind::MessageSent { to } => {
+                format!("to user {}", to.0)
+            }
+            EntryKind::TransferIn { from } => format!("from user {}", from.0),
+            EntryKind::SubAccountFunding { account } | EntryKind::MarkupEarned { account } => {
+                format!("sub-account {}", account.0)
+            }
+            EntryKind::ResellerFunding { reseller } | EntryKind::ResellerMarkup { reseller } => {
+                format!("reseller {}", reseller.0)
+            }
+            EntryKind::TransferFee
+            | EntryKind::DatabaseMonth
+            | EntryKind::WriteOff
+            | EntryKind::LateFee
+            | EntryKind::PostpaidEnrollment
+            | EntryKind::Import => String::new(),
+            EntryKind::Chargeback { reference } => reference.clone(),
+            EntryKind::GroupDiscount { group, .. } => group.clone(),
+            EntryKind::SlaCredit { service } => service.clone(),
+            EntryKind::InvoicePayment { invoice } => format!("invoice {invoice}"),
+            EntryKind::CancellationRefund { order } => format!("order {order}"),
+            EntryKind::Reconciliation { previous } => format!("stored balance {previous}"),
+            EntryKind::ModuleInstall { listing } | EntryKind::ModuleSale { listing } => {
+                format!("listing {listing}")
+            }
+            EntryKind::HostCalls { function, calls } => format!("{calls} calls of {function}"),
+            EntryKind::ComputeUsage { fuel } => format!("{fuel} fuel"),
+            EntryKind::Adjustment { reason, .. } => reason.clone(),
+            EntryKind::TopUp { transaction } => transaction.clone(),
+            EntryKind::StorageCycle { byte_days } => format!("{byte_days} byte-days"),
+            EntryKind::BundleOrder { bundle } => bundle.clone(),
+            EntryKind::ReferralCredit { referred } => format!("referred user {}", referred.0),
+            EntryKind::DisputeRefund { dispute } => format!("dispute {}", dispute.0),
+            EntryKind::ScheduledRun { job } => format!("job {}", job.0),
+            EntryKind::StorageRequest { op } => format!("{op:?}").to_lowercase(),
+            EntryKind::StorageDay { bytes } | EntryKind::BandwidthOverage { bytes } => {
+                format!("{bytes} bytes")
+            }
+            EntryKind::DomainRegistration { domain, years } => {
+                format!("{domain} for {years} years")
+            }
+            EntryKind::DomainRenewal { domain } | EntryKind::CertificateIssued { domain } => {
+                domain.clone()
+            }
+            EntryKind::EmailSent { to } => format!("to {to}"),
+            EntryKind::MemoryUsage {
+                pages,
+                duration_micros,
+            } => format!("{pages} pages for {} ms", duration_micros / 1_000),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+}
+
+// A ledger entry as presented to accountants. The amounts are decimals in the
+// major units of `currency`. The order of the fields is the order of the CSV columns.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LedgerRow {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    pub user: usize,
+    pub kind: &'static str,
+    pub details: String,
+    pub amount: String,
+    pub currency: &'static str,
+    pub running_balance: String,
+    // Empty for the untagged entries, see `cost_centers`
+    pub cost_center: String,
+}
+
+const CSV_HEADER: &str = "at,user,kind,details,amount,currency,running_balance,cost_center";
+
+impl LedgerRow {
+    pub(crate) fn new(user: UserId, entry: &LedgerEntry) -> Self {
+        Self {
+            at: entry.at,
+            user: user.0,
+            kind: entry.kind.name(),
+            details: entry.kind.details(),
+            amount: entry.amount.to_decimal_string(),
+            currency: entry.amount.currency().code(),
+            running_balance: entry.balance_after.to_decimal_string(),
+            cost_center: entry.cost_center.clone().unwrap_or_default(),
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        [
+            self.at.to_string(),
+            self.user.to_string(),
+            self.kind.to_owned(),
+            csv_field(&self.details),
+            self.amount.clone(),
+            self.currency.to_owned(),
+            self.running_balance.clone(),
+            self.cost_center.clone(),
+        ]
+        .join(",")
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+// The entries of the user's ledger made within `[from, to)` (seconds since the Unix epoch),
+// oldest first.
+pub fn rows(
+    user: UserId,
+    ledger: &[LedgerEntry],
+    from: Option<u64>,
+    to: Option<u64>,
+) -> impl Iterator<Item = LedgerRow> + '_ {
+    ledger
+        .iter()
+        .filter(move |entry| from.is_none_or(|from| entry.at >= from))
+        .filter(move |entry| to.is_none_or(|to| entry.at < to))
+        .map(move |entry| LedgerRow::new(user, entry))
+}
+
+pub fn export(
+    rows: impl Iterator<Item = LedgerRow>,
+    format: ExportFormat,
+    mut out: impl Write,
+) -> Result<(), Error> {
+    match format {
+        ExportFormat::Csv => {
+            writeln!(out, "{CSV_HEADER}").map_err(|e| HostError::Persistence(e.to_string()))?;
+            for row in rows {
+                writeln!(out, "{}", row.to_csv())
+                    .map_err(|e| HostError::Persistence(e.to_string()))?;
+            }
+            Ok(())
+        }
+        ExportFormat::Json => {
+            let rows = rows.collect::<Vec<_>>();
+            serde_json::to_writer_pretty(&mut out, &rows)
+                .map_err(|e| HostError::Persistence(e.to_string()))?;
+            writeln!(out).map_err(|e| HostError::Persistence(e.to_string()).into())
+        }
+    }
+}
@@ -0,0 +1,456 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub mod abi;
+pub mod adjustments;
+pub mod alerts;
+pub mod anomalies;
+pub mod archive;
+pub mod audit;
+pub mod auth;
+pub mod authorization;
+pub mod balance_cache;
+pub mod balance_history;
+pub mod billing;
+pub mod bulk;
+pub mod burst;
+pub mod cancellation;
+pub mod capability;
+pub mod certs;
+pub mod chargebacks;
+pub mod concurrency;
+pub mod config;
+pub mod conformance;
+pub mod cost_centers;
+pub mod cron;
+pub mod custom_error;
+#[cfg(unix)]
+pub mod daemon;
+pub mod db;
+pub mod determinism;
+pub mod disputes;
+pub mod domains;
+pub mod email;
+pub mod erasure;
+pub mod error;
+pub mod features;
+pub mod groups;
+mod guest_memory;
+pub mod guest_metrics;
+pub mod history;
+pub mod host;
+pub mod host_docs;
+pub mod indirect_calls;
+pub mod inspect;
+pub mod instance_pool;
+pub mod invoice;
+pub mod ledger;
+pub mod locale;
+pub mod marketplace;
+pub mod memory_store;
+pub mod metering;
+pub mod migrate;
+pub mod mock_host;
+pub mod money;
+pub mod notifications;
+pub mod orders;
+pub mod outbox;
+pub mod pagination;
+#[cfg(feature = "postgres")]
+pub mod pg_store;
+pub mod plan;
+pub mod policy;
+pub mod portal;
+pub mod postpaid;
+pub mod precise;
+pub mod preview2;
+pub mod prices;
+pub mod profiling;
+pub mod queues;
+pub mod quota;
+pub mod reconcile;
+pub mod reload;
+pub mod resellers;
+pub mod result_cache;
+pub mod retention;
+pub mod runtime;
+pub mod scheduler;
+pub mod secrets;
+pub mod service_catalog;
+pub mod services;
+pub mod settlements;
+pub mod sharded_store;
+pub mod sla;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
+pub mod stats;
+pub mod storage;
+pub mod store;
+pub mod store_pool;
+pub mod templates;
+pub mod tickets;
+pub mod trace;
+pub mod tx;
+pub mod wasm_features;
+pub mod wasmtime_host;
+pub mod watchdog;
+
+use adjustments::Adjustments;
+use alerts::{AlertChannel, AlertRule};
+use anomalies::Anomaly;
+use archive::MonthlySummary;
+use audit::AuditLog;
+use authorization::AuthorizationHook;
+use balance_history::BalanceSeries;
+use capability::Capability;
+use certs::{Certificate, CertificateIssuer, CertificateListener, NoopCertificateListener};
+use config::Config;
+use cron::CronJobs;
+use custom_error::CustomErrors;
+use db::{Database, DatabaseBackend};
+use determinism::DeterminismConfig;
+use disputes::{DisputeListener, Disputes, NoopDisputeListener};
+use email::EmailTransport;
+use erasure::{Deletion, ErasureCertificate};
+use guest_metrics::GuestMetrics;
+use history::ExecutionRecord;
+use ledger::LedgerEntry;
+use locale::Locale;
+use marketplace::Marketplace;
+use metering::{BandwidthMeter, BandwidthUsage, CallCharge, ComputeUsage, MemoryMeter};
+use money::MoneyUnit;
+use notifications::NotificationPreferences;
+use orders::Orders;
+use outbox::Outbox;
+use plan::{GraceListener, NoopGraceListener, Plan};
+use policy::PolicyLog;
+use postpaid::PostpaidAccount;
+use profiling::Profiler;
+use queues::Message;
+use reload::{ConfigListener, NoopConfigListener, StagedConfig};
+use result_cache::ResultCache;
+use retention::RetainedData;
+use secrets::SecretVault;
+use services::{NoopProvisioner, Provisioner};
+use settlements::Settlements;
+use sla::ServiceUptime;
+use stats::Stats;
+use storage::ObjectStore;
+use store::UserStore;
+use tickets::{NoopTicketListener, TicketListener, Tickets};
+use tx::Transaction;
+
+pub use error::{BillingError, Error, HostError};
+
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+// Identifies a module version by the SHA-256 of its bytes
+pub fn module_hash(bytes: &[u8]) -> String {
+    sha256_hex(bytes)
+}
+
+#[derive(Clone)]
+pub struct UserData {
+    pub balance: MoneyUnit,
+    pub hosting_days_left: u32,
+    pub plan: Plan,
+    pub capabilities: HashSet<Capability>,
+    // The entries not archived yet, see `archive`
+    pub ledger: Vec<LedgerEntry>,
+    // The number of entries moved from the start of the ledger to the archives
+    pub archived_entries: usize,
+    // The archived entries by month, oldest first
+    pub ledger_summaries: Vec<MonthlySummary>,
+    pub executions: Vec<ExecutionRecord>,
+    // Names of the provisioned catalog services
+    pub services: BTreeSet<String>,
+    // The user who registered the account, credited for its first purchase
+    pub referred_by: Option<UserId>,
+    // The reseller the account is a sub-account of, see `resellers`
+    pub reseller: Option<UserId>,
+    // The markup of a reseller on the catalog prices its sub-accounts pay
+    pub markup_basis_points: u32,
+    // The charges under open disputes
+    pub frozen: MoneyUnit,
+    // Sizes of the stored objects in bytes by their keys
+    pub objects: BTreeMap<String, u64>,
+    // The bytes of the files in the user's directory as of the end of the last invocation,
+    // see `quota`
+    pub file_bytes: u64,
+    // The bytes stored every day of the current billing cycle, summed
+    pub stored_byte_days: u64,
+    // The fuel saved up from invocations that consumed less than the limit, see `burst`
+    pub burst_credits: u64,
+    pub emails_sent_today: u32,
+    // Seconds since the Unix epoch of the emails sent within the rate limit window
+    pub recent_emails: VecDeque<u64>,
+    pub database: Option<Database>,
+    // The certificates by their domains
+    pub certificates: BTreeMap<String, Certificate>,
+    pub bandwidth: BandwidthUsage,
+    // The messages sent to the user by other guests, oldest first
+    pub inbox: VecDeque<Message>,
+    // The locale of the statements and of the error messages returned to the guests
+    pub locale: Locale,
+    // The name of the user's group in `Config::groups`, see `groups`
+    pub group: Option<String>,
+    pub balance_history: BalanceSeries,
+    // `None` for prepaid accounts, see `postpaid`
+    pub postpaid: Option<PostpaidAccount>,
+    // The spending alert rules of the user, see `alerts`
+    pub alert_rules: Vec<AlertRule>,
+    // The ledger entries before this index have been evaluated against the alert rules
+    pub alerts_checked: usize,
+    // The events the user is notified about on which channels, see `notifications`
+    pub notification_preferences: NotificationPreferences,
+    // The health checks of the provisioned services this month by their names, see `sla`
+    pub service_uptime: BTreeMap<String, ServiceUptime>,
+    // Free-form data about the account, e.g. its id in the billing system it was imported from
+    pub metadata: BTreeMap<String, String>,
+    // `None` unless the account has been deleted, see `erasure`
+    pub deleted: Option<Deletion>,
+    // The fuel charged but not posted to the ledger yet, see `metering::charge_compute`
+    pub compute: ComputeUsage,
+    // The environment variables and the preopened directories of the host by their paths in the
+    // guests of the preview2 components of the user, see `templates`
+    pub env: BTreeMap<String, String>,
+    pub dirs: BTreeMap<String, PathBuf>,
+    // The unusual spends of the user, oldest first, see `anomalies`
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl UserData {
+    pub fn new(balance: MoneyUnit) -> Self {
+        Self {
+            balance,
+            hosting_days_left: 0,
+            plan: Plan::Paid,
+            capabilities: HashSet::new(),
+            ledger: Vec::new(),
+            archived_entries: 0,
+            ledger_summaries: Vec::new(),
+            executions: Vec::new(),
+            services: BTreeSet::new(),
+            referred_by: None,
+            reseller: None,
+            markup_basis_points: 0,
+            frozen: MoneyUnit::zero(balance.currency()),
+            objects: BTreeMap::new(),
+            file_bytes: 0,
+            stored_byte_days: 0,
+            burst_credits: 0,
+            emails_sent_today: 0,
+            recent_emails: VecDeque::new(),
+            database: None,
+            certificates: BTreeMap::new(),
+            bandwidth: BandwidthUsage::default(),
+            inbox: VecDeque::new(),
+            locale: Locale::default(),
+            group: None,
+            balance_history: BalanceSeries::new(balance),
+            postpaid: None,
+            alert_rules: Vec::new(),
+            alerts_checked: 0,
+            notification_preferences: NotificationPreferences::default(),
+            service_uptime: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+            deleted: None,
+            compute: ComputeUsage::new(balance.currency()),
+            env: BTreeMap::new(),
+            dirs: BTreeMap::new(),
+            anomalies: Vec::new(),
+        }
+    }
+
+    // The entry at the index, counting the archived entries
+    pub fn ledger_entry(&self, index: usize) -> Result<&LedgerEntry, Error> {
+        let position = index
+            .checked_sub(self.archived_entries)
+            .ok_or(BillingError::EntryArchived)?;
+        self.ledger
+            .get(position)
+            .ok_or(BillingError::InvalidArgumentValue.into())
+    }
+}
+
+// See `State::component_wasi`
+pub type ComponentWasi = dyn FnMut(UserId, &mut wasmtime_wasi::preview2::WasiCtxBuilder);
+
+pub struct State {
+    // The generator of the random numbers handed to the guests, see `host.random`, shared with
+    // their WASI context and reseeded in the deterministic mode, see `determinism`
+    pub(crate) random: Arc<Mutex<StdRng>>,
+    // Completes the WASI context of every run of a preview2 component, which has the environment
+    // variables and the directories of the user already, see `preview2` and `templates`
+    pub component_wasi: Box<ComponentWasi>,
+    pub users: UserStore,
+    pub config: Config,
+    pub stats: Stats,
+    pub custom_errors: CustomErrors,
+    // The message of the last error returned to the guest
+    pub last_error: Option<String>,
+    // Its code, by which the message is translated for `host.last_error_message`
+    pub last_error_code: Option<i32>,
+    // Its context, see `Error::with_context`
+    pub last_error_context: Vec<(&'static str, i64)>,
+    pub provisioner: Box<dyn Provisioner>,
+    // Watchdog ticks since the last `host.heartbeat` call of the running guest
+    pub missed_heartbeats: u32,
+    // Epoch ticks since the running guest was called
+    pub elapsed_epochs: u64,
+    pub disputes: Disputes,
+    pub orders: Orders,
+    pub dispute_listener: Box<dyn DisputeListener>,
+    // `None` if the object storage service is not offered
+    pub object_store: Option<Box<dyn ObjectStore>>,
+    // `None` if the email service is not offered
+    pub email_transport: Option<Box<dyn EmailTransport>>,
+    // `None` if the database service is not offered
+    pub database_backend: Option<Box<dyn DatabaseBackend>>,
+    // `None` if the certificate service is not offered
+    pub certificate_issuer: Option<Box<dyn CertificateIssuer>>,
+    pub certificate_listener: Box<dyn CertificateListener>,
+    pub grace_listener: Box<dyn GraceListener>,
+    // Deliver the spending alerts of the users, none by default
+    pub alert_channels: Vec<Box<dyn AlertChannel>>,
+    // Asked before every call of a mutating host function, see `authorization`
+    pub authorization_hooks: Vec<Box<dyn AuthorizationHook>>,
+    pub tickets: Tickets,
+    // The modules published by the users and their installations, see `marketplace`
+    pub marketplace: Marketplace,
+    pub ticket_listener: Box<dyn TicketListener>,
+    // Where the provisioners and webhooks report the bytes served to the users
+    pub bandwidth_meter: BandwidthMeter,
+    // The metrics emitted by the guests, see `guest_metrics`
+    pub guest_metrics: GuestMetrics,
+    // Observes the memory of the running instance, see `WasmRuntime::peak_memory_pages`
+    pub memory_meter: MemoryMeter,
+    // The priced host calls of the running invocation, see `metering::meter_call`
+    pub call_charges: Vec<CallCharge>,
+    // Profiles the runs requested with `Profiler::profile_next_run`
+    pub profiler: Profiler,
+    // The trace the next invocation runs within, see `trace::propagate`
+    pub next_trace_id: Option<String>,
+    // The cost center the charges of the next invocation are tagged with, see
+    // `cost_centers::tag_next`
+    pub next_cost_center: Option<String>,
+    // The decisions of `Config::policy` and the actions waiting for approval
+    pub policy_log: PolicyLog,
+    // Swapped in before the next invocation, see `reload`
+    pub staged_config: Option<StagedConfig>,
+    pub config_listener: Box<dyn ConfigListener>,
+    pub cron_jobs: CronJobs,
+    pub secrets: SecretVault,
+    // The data of the deprovisioned services until it is deleted, see `retention`
+    pub retained_data: RetainedData,
+    // The certificates of the erasures of the personal data of the accounts, see `erasure`
+    pub erasure_log: AuditLog<ErasureCertificate>,
+    // The manual corrections of the balances, see `adjustments`
+    pub adjustments: Adjustments,
+    // The results of the pure exports, see `result_cache`
+    pub result_cache: ResultCache,
+    // The webhook deliveries waiting for their webhooks, in memory unless replaced by a durable
+    // outbox, see `outbox`
+    pub outbox: Outbox,
+    // The settlement files imported and the payments they credited, see `settlements`
+    pub settlements: Settlements,
+    // Whether the running guest is a pure export, whose mutating calls trap
+    pub(crate) read_only: bool,
+    // Where the running guest wants the results of its mutating calls, see `abi`
+    pub result_buffer: Option<i32>,
+    // The transaction opened by the running guest, if any
+    pub transaction: Option<Transaction>,
+    // Set by the runtime, see `determinism`
+    pub determinism: Option<DeterminismConfig>,
+}
+
+impl State {
+    pub fn new(users: UserStore) -> Self {
+        Self {
+            random: Arc::new(Mutex::new(StdRng::from_entropy())),
+            component_wasi: Box::new(|_, _| {}),
+            users,
+            config: Config::default(),
+            stats: Stats::default(),
+            custom_errors: CustomErrors::new(),
+            last_error: None,
+            last_error_code: None,
+            last_error_context: Vec::new(),
+            provisioner: Box::new(NoopProvisioner),
+            missed_heartbeats: 0,
+            elapsed_epochs: 0,
+            disputes: Disputes::new(),
+            orders: Orders::new(),
+            dispute_listener: Box::new(NoopDisputeListener),
+            object_store: None,
+            email_transport: None,
+            database_backend: None,
+            certificate_issuer: None,
+            certificate_listener: Box::new(NoopCertificateListener),
+            grace_listener: Box::new(NoopGraceListener),
+            alert_channels: Vec::new(),
+            authorization_hooks: Vec::new(),
+            tickets: Tickets::new(),
+            marketplace: Marketplace::new(),
+            ticket_listener: Box::new(NoopTicketListener),
+            bandwidth_meter: BandwidthMeter::new(),
+            guest_metrics: GuestMetrics::new(),
+            memory_meter: MemoryMeter::default(),
+            call_charges: Vec::new(),
+            profiler: Profiler::default(),
+            next_trace_id: None,
+            next_cost_center: None,
+            policy_log: PolicyLog::new(),
+            staged_config: None,
+            config_listener: Box::new(NoopConfigListener),
+            cron_jobs: CronJobs::new(),
+            secrets: SecretVault::new(),
+            retained_data: RetainedData::new(),
+            erasure_log: AuditLog::new(),
+            adjustments: Adjustments::new(),
+            result_cache: ResultCache::new(),
+            outbox: Outbox::default(),
+            settlements: Settlements::new(),
+            read_only: false,
+            result_buffer: None,
+            transaction: None,
+            determinism: None,
+        }
+    }
+
+    // Clears the data of the last invocation, keeping the allocations of its collections,
+    // e.g. before the store is reused by `store_pool::StorePool`. A transaction left open is
+    // rolled back.
+    pub(crate) fn reset_invocation(&mut self) {
+        if tx::is_active(self) {
+            let _ = tx::rollback(self);
+        }
+        self.last_error = None;
+        self.last_error_code = None;
+        self.last_error_context.clear();
+        self.missed_heartbeats = 0;
+        self.elapsed_epochs = 0;
+        self.memory_meter = MemoryMeter::default();
+        self.call_charges.clear();
+        self.next_trace_id = None;
+        self.next_cost_center = None;
+        self.result_buffer = None;
+        self.read_only = false;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct UserId(pub usize);
@@ -0,0 +1,210 @@
+use time::OffsetDateTime;
+
+use crate::{
+    auth::{self, Scope},
+    money::MoneyUnit,
+    store::UserStore,
+    BillingError, Error,
+};
+
+// The locales the statements and the error messages of the guests can be rendered in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Locale {
+    #[default]
+    EnUs,
+    EnGb,
+    DeDe,
+    FrFr,
+    EsEs,
+    JaJp,
+}
+
+const LOCALES: [Locale; 6] = [
+    Locale::EnUs,
+    Locale::EnGb,
+    Locale::DeDe,
+    Locale::FrFr,
+    Locale::EsEs,
+    Locale::JaJp,
+];
+
+enum DateOrder {
+    MonthDayYear,
+    DayMonthYear,
+    YearMonthDay,
+}
+
+impl Locale {
+    // The BCP 47 tag, e.g. `de-DE`
+    pub const fn tag(self) -> &'static str {
+        match self {
+            Locale::EnUs => "en-US",
+            Locale::EnGb => "en-GB",
+            Locale::DeDe => "de-DE",
+            Locale::FrFr => "fr-FR",
+            Locale::EsEs => "es-ES",
+            Locale::JaJp => "ja-JP",
+        }
+    }
+
+    // Tags are matched case-insensitively and `_` is accepted in place of `-`
+    pub fn parse(tag: &str) -> Result<Self, Error> {
+        let tag = tag.replace('_', "-");
+        LOCALES
+            .into_iter()
+            .find(|locale| locale.tag().eq_ignore_ascii_case(&tag))
+            .ok_or(BillingError::UnsupportedLocale.into())
+    }
+
+    // The decimal and the thousands separators
+    const fn separators(self) -> (char, &'static str) {
+        match self {
+            Locale::EnUs | Locale::EnGb | Locale::JaJp => ('.', ","),
+            Locale::DeDe | Locale::EsEs => (',', "."),
+            // A narrow no-break space
+            Locale::FrFr => (',', "\u{202f}"),
+        }
+    }
+
+    const fn date_order(self) -> (DateOrder, char) {
+        match self {
+            Locale::EnUs => (DateOrder::MonthDayYear, '/'),
+            Locale::EnGb | Locale::FrFr | Locale::EsEs => (DateOrder::DayMonthYear, '/'),
+            Locale::DeDe => (DateOrder::DayMonthYear, '.'),
+            Locale::JaJp => (DateOrder::YearMonthDay, '/'),
+        }
+    }
+
+    // The amount with the separators of the locale followed by the currency code,
+    // e.g. `-1.234,50 EUR` in `de-DE`
+    pub fn format_money(self, money: MoneyUnit) -> String {
+        let (decimal, thousands) = self.separators();
+        let decimal_string = money.to_decimal_string();
+        let (sign, unsigned) = match decimal_string.strip_prefix('-') {
+            Some(unsigned) => ("-", unsigned),
+            None => ("", decimal_string.as_str()),
+        };
+        let (integer, fraction) = match unsigned.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (unsigned, None),
+        };
+        let mut grouped = String::new();
+        for (i, digit) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i) % 3 == 0 {
+                grouped.push_str(thousands);
+            }
+            grouped.push(digit);
+        }
+        let fraction = fraction.map_or(String::new(), |fraction| format!("{decimal}{fraction}"));
+        format!("{sign}{grouped}{fraction} {}", money.currency().code())
+    }
+
+    // The UTC date of the time in seconds since the Unix epoch, e.g. `31.12.2024` in `de-DE`
+    pub fn format_date(self, secs: u64) -> String {
+        let Some(date) = i64::try_from(secs)
+            .ok()
+            .and_then(|secs| OffsetDateTime::from_unix_timestamp(secs).ok())
+        else {
+            return secs.to_string();
+        };
+        let (year, month, day) = (date.year(), u8::from(date.month()), date.day());
+        match self.date_order() {
+            (DateOrder::MonthDayYear, sep) => format!("{month:02}{sep}{day:02}{sep}{year}"),
+            (DateOrder::DayMonthYear, sep) => format!("{day:02}{sep}{month:02}{sep}{year}"),
+            (DateOrder::YearMonthDay, sep) => format!("{year}{sep}{month:02}{sep}{day:02}"),
+        }
+    }
+
+    // The translation of the error with the code, see `BillingError::code`. `None` if the catalog
+    // has no translation, in which case the English message should be used.
+    pub fn translate(self, code: i32) -> Option<&'static str> {
+        let error = BillingError::from_code(code)?;
+        let [de, fr, es, ja] = match error {
+            BillingError::InvalidArgumentValue => [
+                "Ungültiger Argumentwert.",
+                "Valeur d'argument invalide.",
+                "Valor de argumento no válido.",
+                "引数の値が無効です。",
+            ],
+            BillingError::BalanceWouldBecomeNegative => [
+                "Das Guthaben würde negativ werden.",
+                "Le solde deviendrait négatif.",
+                "El saldo se volvería negativo.",
+                "残高がマイナスになります。",
+            ],
+            BillingError::NegativeBalance => [
+                "Das Guthaben ist negativ.",
+                "Le solde est négatif.",
+                "El saldo es negativo.",
+                "残高がマイナスです。",
+            ],
+            BillingError::UnknownUser => [
+                "Unbekannter Benutzer.",
+                "Utilisateur inconnu.",
+                "Usuario desconocido.",
+                "不明なユーザーです。",
+            ],
+            BillingError::MissingCapability => [
+                "Dem Benutzer fehlt die erforderliche Berechtigung.",
+                "L'utilisateur n'a pas l'autorisation requise.",
+                "El usuario no tiene el permiso necesario.",
+                "必要な権限がありません。",
+            ],
+            BillingError::SelfTransfer => [
+                "Überweisungen an sich selbst sind nicht erlaubt.",
+                "Les virements à soi-même ne sont pas autorisés.",
+                "No se permiten transferencias a uno mismo.",
+                "自分自身への送金はできません。",
+            ],
+            BillingError::StorageQuotaExceeded => [
+                "Das Objekt würde das Speicherkontingent überschreiten.",
+                "L'objet dépasserait le quota de stockage.",
+                "El objeto superaría la cuota de almacenamiento.",
+                "オブジェクトがストレージの上限を超えます。",
+            ],
+            BillingError::DomainTaken => [
+                "Der Domainname ist bereits registriert.",
+                "Le nom de domaine est déjà enregistré.",
+                "El nombre de dominio ya está registrado.",
+                "このドメイン名は既に登録されています。",
+            ],
+            BillingError::EmailQuotaExceeded => [
+                "Das tägliche E-Mail-Kontingent ist aufgebraucht.",
+                "Le quota quotidien d'e-mails est épuisé.",
+                "Se ha agotado la cuota diaria de correos.",
+                "1日のメール送信上限に達しました。",
+            ],
+            BillingError::QueueFull => [
+                "Der Posteingang des Empfängers ist voll.",
+                "La boîte de réception du destinataire est pleine.",
+                "La bandeja de entrada del destinatario está llena.",
+                "受信者の受信箱がいっぱいです。",
+            ],
+            BillingError::UnknownSecret => [
+                "Das Geheimnis existiert nicht.",
+                "Le secret n'existe pas.",
+                "El secreto no existe.",
+                "シークレットが存在しません。",
+            ],
+            _ => return None,
+        };
+        match self {
+            Locale::EnUs | Locale::EnGb => None,
+            Locale::DeDe => Some(de),
+            Locale::FrFr => Some(fr),
+            Locale::EsEs => Some(es),
+            Locale::JaJp => Some(ja),
+        }
+    }
+}
+
+// Sets the locale of the owner of the API token
+pub fn set_locale_with_token(users: &mut UserStore, token: &str, tag: &str) -> Result<(), Error> {
+    let user = auth::authorize(users, token, Scope::Write)?;
+    let locale = Locale::parse(tag)?;
+    users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?
+        .locale = locale;
+    Ok(())
+}
@@ -1,162 +1,1286 @@
-use std::collections::HashMap;
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use strum::{EnumIter, IntoEnumIterator};
-use wasmtime::{Caller, Engine, Extern, Func, ImportType, Instance, Linker, Module, Store};
-use wasmtime_wasi::{sync::WasiCtxBuilder, WasiCtx};
+use wasi_services_management::{
+    adjustments, archive, audit,
+    auth::{self, Scope},
+    bulk,
+    capability::Capability,
+    config::{Config, EngineConfig, OptLevel, RuntimeConfig, StoreKind},
+    conformance,
+    determinism::DeterminismConfig,
+    disputes::{self, Actor, Dispute, DisputeEvent, DisputeListener, Resolution},
+    erasure, history,
+    host_docs::{self, DocsFormat},
+    inspect,
+    ledger::{self, ExportFormat},
+    marketplace::{self, ModuleMetadata},
+    memory_store::{self, MemoryAccountStore},
+    migrate::{self, AccountBackend},
+    mock_host::{MockHost, MockScript},
+    money::MoneyUnit,
+    outbox::{DeliveryId, DeliveryStatus, Outbox, OutboxConfig},
+    plan::Plan,
+    policy::Policy,
+    portal,
+    profiling::ProfilingConfig,
+    reconcile, reload,
+    runtime::{SMStore, WasmRuntime, WasmtimeRuntime},
+    secrets::Accessor,
+    settlements::{self, Settlements},
+    stats::Stats,
+    store::UserStore,
+    templates::{self, AccountTemplate},
+    tickets::{self, Ticket, TicketListener},
+    BillingError, Error, HostError, State, UserData, UserId,
+};
 
-mod money;
+// `inspect <module> [--user <id>]` prints the imports, the exports and the memories of the
+// module, and whether its imports resolve for the example's account or the given one, by its
+// capabilities and its plan, see `inspect`.
+fn inspect_module(path: &str, args: &[String]) -> Result<(), Error> {
+    let user = match args {
+        [] => UserId(0),
+        [flag, id] if flag == "--user" => {
+            UserId(id.parse().map_err(|_| BillingError::InvalidArgumentValue)?)
+        }
+        _ => return Err(BillingError::InvalidArgumentValue.into()),
+    };
+    let runtime = WasmtimeRuntime::new();
+    let bytes = std::fs::read(path).map_err(|e| HostError::Persistence(e.to_string()))?;
+    let (store, _) = run_example(&runtime, false);
+    let policy = inspect::user_policy(store.data(), user)?;
+    let report = inspect::inspect(&runtime, &bytes, policy)?;
+    println!("{report}");
+    Ok(())
+}
 
-use money::MoneyUnit;
+// Runs the example guest, returning the store with the resulting accounts
+// and the balance returned by the guest. The run is profiled if requested,
+// see `profiling`.
+fn run_example(runtime: &WasmtimeRuntime, profile: bool) -> (SMStore, i64) {
+    let wat = r#"
+        (module
+            (import "host" "balance" (func $balance (result i64)))
+            (import "host" "order_hosting" (func $order_hosting (param i32) (result i32)))
 
-#[derive(Debug, thiserror::Error, EnumIter)]
-enum Error {
-    #[error("Invalid argument value passed to the function.")]
-    InvalidArgumentValue,
-    #[error("The total cost of the service exceeded the maximum value.")]
-    TotalCostExceededMaxValue,
-    #[error("The balance is negative.")]
-    NegativeBalance,
-    #[error("The balance would become negative after the transaction.")]
-    BalanceWouldBecomeNegative,
-    #[error("The balance would underflow after the transaction.")]
-    BalanceWouldUnderflow,
-    #[error("The requested import (e.g. a host function) is unknown.")]
-    UnknownImport,
+            (func (export "run") (result i64)
+                (i32.const 30)  ;; Pass 30 to $order_hosting in order to order a month of hosting
+                (call $order_hosting)
+
+                ;; Discard the error code
+                (drop)
+
+                (call $balance)
+            )
+        )
+    "#;
+    let mut store = {
+        let mut users = UserStore::new();
+
+        users.insert(UserId(0), UserData::new(MoneyUnit::from_cents(100_000)));
+
+        let mut state = State::new(users);
+        memory_store::install(&mut state);
+        let mut store = runtime.new_store(state);
+        runtime.set_stdin(&mut store, Box::new(io::stdin()));
+        runtime.set_stdout(&mut store, Box::new(io::stdout()));
+        runtime.set_stderr(&mut store, Box::new(io::stderr()));
+        store
+    };
+    if profile {
+        store.data_mut().profiler.profile_next_run();
+    }
+
+    let balance = history::execute(runtime, &mut store, UserId(0), wat.as_bytes(), "run").unwrap();
+    (store, balance)
 }
 
-struct UserData {
-    balance: MoneyUnit,
-    hosting_days_left: u32,
+// Adds the example's operator accounts, `1` and `2`, without funds, see `Config::operators`
+fn add_example_operators(state: &mut State) {
+    for operator in [UserId(1), UserId(2)] {
+        let currency = MoneyUnit::from_cents(0);
+        state.users.insert(operator, UserData::new(currency));
+        state.config.operators.insert(operator);
+    }
 }
 
-struct State {
-    wasi_ctx: WasiCtx,
-    user_data: HashMap<UserId, UserData>,
+// `ledger export --format csv|json --user 0 [--from <secs>] [--to <secs>]`
+// exports the ledger of the example's accounts to the standard output.
+fn export_ledger(args: &[String]) -> Result<(), Error> {
+    let mut format = ExportFormat::Csv;
+    let mut user = UserId(0);
+    let (mut from, mut to) = (None, None);
+    for pair in args.chunks(2) {
+        let [flag, value] = pair else {
+            return Err(BillingError::InvalidArgumentValue.into());
+        };
+        let number = || {
+            value
+                .parse()
+                .map_err(|_| BillingError::InvalidArgumentValue)
+        };
+        match flag.as_str() {
+            "--format" => format = ExportFormat::parse(value)?,
+            "--user" => user = UserId(number()? as usize),
+            "--from" => from = Some(number()?),
+            "--to" => to = Some(number()?),
+            _ => return Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+    let runtime = WasmtimeRuntime::new();
+    let (store, _) = run_example(&runtime, false);
+    let user_data = store
+        .data()
+        .users
+        .get(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    let rows = ledger::rows(user, &user_data.ledger, from, to);
+    ledger::export(rows, format, std::io::stdout().lock())
 }
 
-type SMStore = Store<State>;
+// `ledger archive [--dir <dir>] [--max-age-days <n>] [--restore]` archives the old entries
+// of the example's accounts, e.g. all of them with `--max-age-days 0`, and prints
+// the monthly summaries kept in memory. `--restore` moves the entries back right away.
+fn archive_ledger(args: &[String]) -> Result<(), Error> {
+    let mut config = Config::default().archive;
+    config.max_age_days = Some(365);
+    let mut restore = false;
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or(BillingError::InvalidArgumentValue);
+        match flag.as_str() {
+            "--dir" => config.dir = value()?.into(),
+            "--max-age-days" => {
+                let days = value()?.parse();
+                config.max_age_days = Some(days.map_err(|_| BillingError::InvalidArgumentValue)?);
+            }
+            "--restore" => restore = true,
+            _ => return Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+    let runtime = WasmtimeRuntime::new();
+    let (mut store, _) = run_example(&runtime, false);
+    let state = store.data_mut();
+    state.config.archive = config;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let report = archive::archive_all(state, now)?;
+    println!(
+        "Archived {} entries of {} users",
+        report.entries, report.users
+    );
+    for (user, user_data) in state.users.read().iter() {
+        for summary in &user_data.ledger_summaries {
+            println!(
+                "user {} {}-{:02}: {} entries, debits {}, credits {}, closing balance {}",
+                user.0,
+                summary.year,
+                summary.month,
+                summary.entries,
+                summary.debits,
+                summary.credits,
+                summary.closing_balance
+            );
+        }
+    }
+    if restore {
+        let users = state
+            .users
+            .read()
+            .iter()
+            .map(|(&user, _)| user)
+            .collect::<Vec<_>>();
+        for user in users {
+            let restored = archive::restore(state, user)?;
+            println!("Restored {restored} entries of user {}", user.0);
+        }
+    }
+    Ok(())
+}
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
-struct UserId(usize);
+// `ledger adjust --tokens <file> --token <token> --user 0 --amount <minor units> --reason <text>
+// [--approver-token <token>] [--single-approver]` proposes a correction of the balance of the
+// example's account, e.g. `--amount -250` to debit 2.50 in the currency of the balance, with a
+// write token of one of the example's operators issued to the file with `token issue`, and
+// approves it with the write token of the other operator given as `--approver-token`.
+// `--single-approver` commits the adjustment without a second approval. Prints the balance and
+// the audit log of the adjustments as JSON Lines, see `adjustments`.
+fn adjust_ledger(args: &[String]) -> Result<(), Error> {
+    let mut user = UserId(0);
+    let mut amount = None;
+    let mut reason = String::new();
+    let (mut tokens_path, mut token, mut approver) = (None, None, None);
+    let mut single_approver = false;
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or(BillingError::InvalidArgumentValue);
+        match flag.as_str() {
+            "--user" => {
+                let id = value()?.parse();
+                user = UserId(id.map_err(|_| BillingError::InvalidArgumentValue)?);
+            }
+            "--amount" => {
+                let minor = value()?.parse::<i64>();
+                amount = Some(minor.map_err(|_| BillingError::InvalidArgumentValue)?);
+            }
+            "--reason" => reason = value()?.clone(),
+            "--tokens" => tokens_path = Some(value()?),
+            "--token" => token = Some(value()?),
+            "--approver-token" => approver = Some(value()?),
+            "--single-approver" => single_approver = true,
+            _ => return Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+    let amount = amount.ok_or(BillingError::InvalidArgumentValue)?;
+    let tokens_path = tokens_path.ok_or(BillingError::InvalidArgumentValue)?;
+    let token = token.ok_or(BillingError::InvalidArgumentValue)?;
+    let runtime = WasmtimeRuntime::new();
+    let (mut store, _) = run_example(&runtime, false);
+    let state = store.data_mut();
+    add_example_operators(state);
+    auth::load_tokens(&mut state.users, tokens_path)?;
+    state.config.adjustments.require_second_approver = !single_approver;
+    let currency = state
+        .users
+        .get(&user)
+        .ok_or(BillingError::UnknownUser)?
+        .balance
+        .currency();
+    let amount = MoneyUnit::from_minor_units(amount, currency);
+    let id = adjustments::propose(state, token, user, amount, &reason)?;
+    if let (Some(approver), false) = (approver, single_approver) {
+        adjustments::approve(state, id, approver)?;
+    }
+    let balance = state.users.get(&user).unwrap().balance;
+    match state.adjustments.pending().next() {
+        Some(_) => println!("Adjustment {} awaits approval, balance {balance}", id.0),
+        None => println!("Adjustment {} committed, balance {balance}", id.0),
+    }
+    state
+        .adjustments
+        .audit_log()
+        .export(std::io::stdout().lock())
+}
 
-fn order_hosting(user_data: &mut UserData, days: i32) -> Result<(), Error> {
-    const PRICE_PER_DAY: MoneyUnit = MoneyUnit::from_cents(100);
-    if days <= 0 {
-        return Err(Error::InvalidArgumentValue);
-    };
+struct PrintingDisputeListener;
 
-    let total_cost = (PRICE_PER_DAY * days).ok_or(Error::TotalCostExceededMaxValue)?;
-    user_data.balance = (user_data.balance - total_cost)?;
-    user_data.hosting_days_left += days as u32;
+impl DisputeListener for PrintingDisputeListener {
+    fn on_event(&mut self, dispute: &Dispute, event: &DisputeEvent) {
+        println!(
+            "[{}] dispute {} of user {} over {}: {:?} by {:?} ({})",
+            event.at,
+            dispute.id.0,
+            dispute.user.0,
+            dispute.amount,
+            event.status,
+            event.actor,
+            event.note
+        );
+    }
+}
+
+// `dispute --user 0 --entry 0 --reason <text> [--resolve refund|deny]` disputes an entry
+// in the ledger of the example's accounts, optionally resolving the dispute right away.
+fn dispute(args: &[String]) -> Result<(), Error> {
+    let mut user = UserId(0);
+    let mut entry = None;
+    let mut reason = String::new();
+    let mut resolution = None;
+    for pair in args.chunks(2) {
+        let [flag, value] = pair else {
+            return Err(BillingError::InvalidArgumentValue.into());
+        };
+        let number = || {
+            value
+                .parse()
+                .map_err(|_| BillingError::InvalidArgumentValue)
+        };
+        match flag.as_str() {
+            "--user" => user = UserId(number()?),
+            "--entry" => entry = Some(number()?),
+            "--reason" => reason = value.clone(),
+            "--resolve" if value == "refund" => resolution = Some(Resolution::Refund),
+            "--resolve" if value == "deny" => resolution = Some(Resolution::Deny),
+            _ => return Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+    let entry = entry.ok_or(BillingError::InvalidArgumentValue)?;
+    let runtime = WasmtimeRuntime::new();
+    let (mut store, _) = run_example(&runtime, false);
+    let state = store.data_mut();
+    state.dispute_listener = Box::new(PrintingDisputeListener);
+    let id = disputes::open(state, user, entry, reason)?;
+    if let Some(resolution) = resolution {
+        disputes::resolve(
+            state,
+            id,
+            "cli",
+            resolution,
+            "resolved from the command line",
+        )?;
+    }
+    let user_data = state.users.get(&user).unwrap();
+    println!(
+        "Balance: {}, frozen: {}",
+        user_data.balance, user_data.frozen
+    );
     Ok(())
 }
 
-fn resolve_or_construct_import<'a>(
-    linker: &Linker<State>,
-    mut store: &mut Store<State>,
-    import: ImportType<'a>,
-    user: UserId,
-) -> Option<Extern> {
-    if import.module() != "host" {
-        return linker.get_by_import(&mut store, &import);
-    };
+struct PrintingTicketListener;
 
-    let host_import = match import.name() {
-        "balance" => Func::wrap(&mut store, move |caller: Caller<'_, State>| {
-            caller.data().user_data[&user].balance.to_cents_as_i64()
-        }),
-        "order_hosting" => Func::wrap(
-            &mut store,
-            move |mut caller: Caller<'_, State>, days: i32| {
-                let user_data = caller.data_mut().user_data.get_mut(&user).unwrap();
-                let ret = match order_hosting(user_data, days) {
-                    Ok(()) => 0,
-                    Err(e) => {
-                        let discr = std::mem::discriminant(&e);
-                        let error_code = Error::iter()
-                            .map(|err| core::mem::discriminant(&err))
-                            .enumerate()
-                            .find_map(|(i, d)| if d == discr { Some(i + 1) } else { None });
-                        match error_code {
-                            Some(error_code) => {
-                                debug_assert!(error_code > 0);
-                                error_code
-                            }
-                            None => unreachable!(),
-                        }
-                    }
-                };
-                ret as i32
-            },
-        ),
-        _ => return None,
+impl TicketListener for PrintingTicketListener {
+    fn on_status_change(&mut self, ticket: &Ticket) {
+        println!(
+            "ticket {} of user {} ({}): {:?}",
+            ticket.id.0, ticket.user.0, ticket.subject, ticket.status
+        );
+    }
+}
+
+// `ticket --user 0 --subject <text> --body <text> [--reply <text>]` opens a support ticket
+// for one of the example's accounts, optionally answers it as an operator and lists
+// the conversation.
+fn ticket(args: &[String]) -> Result<(), Error> {
+    let mut user = UserId(0);
+    let (mut subject, mut body) = (String::new(), String::new());
+    let mut reply = None;
+    for pair in args.chunks(2) {
+        let [flag, value] = pair else {
+            return Err(BillingError::InvalidArgumentValue.into());
+        };
+        match flag.as_str() {
+            "--user" => {
+                user = UserId(
+                    value
+                        .parse()
+                        .map_err(|_| BillingError::InvalidArgumentValue)?,
+                )
+            }
+            "--subject" => subject = value.clone(),
+            "--body" => body = value.clone(),
+            "--reply" => reply = Some(value.clone()),
+            _ => return Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+    let runtime = WasmtimeRuntime::new();
+    let (mut store, _) = run_example(&runtime, false);
+    let state = store.data_mut();
+    state.ticket_listener = Box::new(PrintingTicketListener);
+    let id = tickets::open(state, user, &subject, &body)?;
+    if let Some(reply) = reply {
+        tickets::reply(state, id, Actor::Operator("cli".to_owned()), &reply)?;
+    }
+    for message in &state.tickets.get(id).unwrap().messages {
+        println!("[{}] {:?}: {}", message.at, message.author, message.body);
+    }
+    Ok(())
+}
+
+// `host-api docs [--format markdown|html]` documents the host functions with the default prices.
+fn host_api_docs(args: &[String]) -> Result<(), Error> {
+    let format = match args {
+        [] => DocsFormat::Markdown,
+        [flag, value] if flag == "--format" => DocsFormat::parse(value)?,
+        _ => return Err(BillingError::InvalidArgumentValue.into()),
     };
-    Some(Extern::Func(host_import))
+    print!("{}", host_docs::render(&Config::default(), format));
+    Ok(())
 }
 
-fn instantiate_services_management_module(
-    linker: &Linker<State>,
-    store: &mut SMStore,
-    user: UserId,
-    module: &Module,
-) -> Result<Instance, Error> {
-    let imports = module
-        .imports()
-        .map(|import| resolve_or_construct_import(linker, store, import, user))
-        .collect::<Option<Vec<Extern>>>()
-        .ok_or(Error::UnknownImport)?;
-    let instance = Instance::new(store, &module, &imports).unwrap();
-    Ok(instance)
+// `run [--profile <path>] [--opt-level none|speed|speed_and_size] [--cache-dir <dir>]` runs
+// the example guest. With `--profile`, the run is profiled and its profile, kept in the
+// execution record, is written to the path for a flame graph viewer such as
+// https://profiler.firefox.com. With `--cache-dir`, the compiled guest is cached in the directory.
+fn run(args: &[String]) -> Result<(), Error> {
+    let mut profile_path = None;
+    let mut engine = EngineConfig::default();
+    for pair in args.chunks(2) {
+        match pair {
+            [flag, path] if flag == "--profile" => profile_path = Some(path),
+            [flag, level] if flag == "--opt-level" => engine.opt_level = OptLevel::parse(level)?,
+            [flag, dir] if flag == "--cache-dir" => engine.cache_dir = Some(dir.into()),
+            _ => return Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+    let runtime = WasmtimeRuntime::with_config(&RuntimeConfig {
+        engine,
+        profiling: profile_path.map(|_| ProfilingConfig::default()),
+        ..RuntimeConfig::default()
+    })?;
+    let (store, balance) = run_example(&runtime, profile_path.is_some());
+    println!("The balance of root is {balance}");
+    if let Some(path) = profile_path {
+        let user_data = store.data().users.get(&UserId(0)).unwrap();
+        let profile = user_data
+            .executions
+            .last()
+            .and_then(|record| record.profile.as_deref())
+            .unwrap_or_default();
+        std::fs::write(path, profile).map_err(|e| HostError::Persistence(e.to_string()))?;
+    }
+    Ok(())
 }
 
-fn main() {
-    let engine = Engine::default();
+// `pipe <module> [--export <name>] [--stdin <file> | --input <text>] [--seed <n>]
+// [--clock <secs>]` runs the guest as user 0 of the example's accounts like a Unix filter: its
+// standard input is the file, the text or, by default, the standard input of this process, and
+// its standard output is streamed to the standard output of this process as it writes it. The
+// result is printed to the standard error. With `--seed` or `--clock`, the guest runs in the
+// deterministic mode with the seed and the clock pinned at the time, 0 by default, see
+// `determinism`.
+fn pipe(path: &str, args: &[String]) -> Result<(), Error> {
+    let mut export = "run".to_owned();
+    let mut determinism = None::<DeterminismConfig>;
+    let mut stdin: Box<dyn Read + Send + Sync> = Box::new(std::io::stdin());
+    for pair in args.chunks(2) {
+        match pair {
+            [flag, name] if flag == "--export" => export = name.clone(),
+            [flag, file] if flag == "--stdin" => {
+                let file =
+                    std::fs::File::open(file).map_err(|e| HostError::Persistence(e.to_string()))?;
+                stdin = Box::new(file);
+            }
+            [flag, text] if flag == "--input" => {
+                stdin = Box::new(std::io::Cursor::new(text.clone().into_bytes()))
+            }
+            [flag, seed] if flag == "--seed" => {
+                determinism.get_or_insert_default().seed = seed
+                    .parse()
+                    .map_err(|_| BillingError::InvalidArgumentValue)?;
+            }
+            [flag, secs] if flag == "--clock" => {
+                determinism.get_or_insert_default().clock_secs = secs
+                    .parse()
+                    .map_err(|_| BillingError::InvalidArgumentValue)?;
+            }
+            _ => return Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+    let bytes = std::fs::read(path).map_err(|e| HostError::Persistence(e.to_string()))?;
+    let runtime = WasmtimeRuntime::with_config(&RuntimeConfig {
+        determinism,
+        ..RuntimeConfig::default()
+    })?;
+    let (mut store, _) = run_example(&runtime, false);
+    let result = history::execute_piped(
+        &runtime,
+        &mut store,
+        UserId(0),
+        &bytes,
+        &export,
+        stdin,
+        std::io::stdout(),
+    )?;
+    eprintln!("The guest returned {result}");
+    Ok(())
+}
+
+// `mock-host <module> [--script <file>] [--export <name>]` runs the guest against
+// the mock host, printing its host calls and its result, see `mock_host`.
+fn mock_host(path: &str, args: &[String]) -> Result<(), Error> {
+    let mut script = MockScript::default();
+    let mut export = "run".to_owned();
+    for pair in args.chunks(2) {
+        match pair {
+            [flag, value] if flag == "--script" => {
+                let text = std::fs::read_to_string(value)
+                    .map_err(|e| HostError::Persistence(e.to_string()))?;
+                script = MockScript::parse(&text)?;
+            }
+            [flag, value] if flag == "--export" => export = value.clone(),
+            _ => return Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+    let bytes = std::fs::read(path).map_err(|e| HostError::Persistence(e.to_string()))?;
+    let run = MockHost::new()?.run(&bytes, &export, script);
+    for call in &run.calls {
+        let params = call
+            .params
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        match call.result {
+            Some(result) => println!("{}({}) -> {result}", call.function, params.join(", ")),
+            None => println!("{}({})", call.function, params.join(", ")),
+        }
+    }
+    println!("The guest returned {}", run.result?);
+    Ok(())
+}
+
+// `audit export [--log tokens|secrets]` exports the audit log of the example's accounts
+// as JSON Lines to the standard output, after the example's root account has been issued
+// an API token and has set a secret. `audit verify <file>` checks the hash chain of an
+// exported log, see `audit`.
+fn audit_command(args: &[String]) -> Result<(), Error> {
+    match args {
+        [subcommand, path] if subcommand == "verify" => {
+            let file =
+                std::fs::File::open(path).map_err(|e| HostError::Persistence(e.to_string()))?;
+            let entries = audit::verify_export(std::io::BufReader::new(file))?;
+            println!("The hash chain is intact, {entries} entries verified");
+            Ok(())
+        }
+        [subcommand, rest @ ..] if subcommand == "export" => {
+            let log = match rest {
+                [] => "tokens",
+                [flag, log] if flag == "--log" => log.as_str(),
+                _ => return Err(BillingError::InvalidArgumentValue.into()),
+            };
+            let runtime = WasmtimeRuntime::new();
+            let (mut store, _) = run_example(&runtime, false);
+            let state = store.data_mut();
+            auth::issue_token(&mut state.users, UserId(0), Scope::Read)?;
+            state
+                .secrets
+                .set(UserId(0), "api-key", b"example", Accessor::Operator)?;
+            let out = std::io::stdout().lock();
+            match log {
+                "tokens" => auth::audit_log(&state.users).export(out),
+                "secrets" => state.secrets.audit_log().export(out),
+                _ => Err(BillingError::InvalidArgumentValue.into()),
+            }
+        }
+        _ => Err(BillingError::InvalidArgumentValue.into()),
+    }
+}
+
+// `outbox <file> [--dead]` lists the webhook deliveries waiting in the outbox journaled in the
+// file, e.g. by `daemon --outbox <file>`, only the dead-lettered ones with `--dead`.
+// `outbox <file> redrive <id>|--all` makes the dead-lettered delivery, or every one of them, due
+// again, see `outbox`. The outbox of a running daemon is managed over its socket instead, since
+// the daemon does not read the journal again.
+fn outbox_command(path: &str, args: &[String]) -> Result<(), Error> {
+    let outbox = Outbox::open(path, OutboxConfig::default())?;
+    let deliveries = match args {
+        [] => outbox.deliveries(),
+        [flag] if flag == "--dead" => outbox.dead_lettered(),
+        [subcommand, flag] if subcommand == "redrive" && flag == "--all" => {
+            println!("Redrove {} deliveries", outbox.redrive_all()?);
+            return Ok(());
+        }
+        [subcommand, id] if subcommand == "redrive" => {
+            let id = id.parse().map_err(|_| BillingError::InvalidArgumentValue)?;
+            outbox.redrive(DeliveryId(id))?;
+            println!("Redrove delivery {id}");
+            return Ok(());
+        }
+        _ => return Err(BillingError::InvalidArgumentValue.into()),
+    };
+    for delivery in deliveries {
+        let status = match delivery.status {
+            DeliveryStatus::Dead => "dead",
+            _ => "pending",
+        };
+        println!(
+            "{} {status} {} attempts {} next at {}: {}",
+            delivery.id.0,
+            delivery.url,
+            delivery.attempts,
+            delivery.next_attempt_at,
+            delivery
+                .last_error
+                .as_deref()
+                .unwrap_or("not attempted yet")
+        );
+    }
+    Ok(())
+}
+
+// `marketplace list` lists the modules of the marketplace, `marketplace search <query>` those
+// whose name or description contains the query, after the example's root account has published
+// a module, see `marketplace`.
+fn marketplace_command(args: &[String]) -> Result<(), Error> {
     let wat = r#"
         (module
             (import "host" "balance" (func $balance (result i64)))
-            (import "host" "order_hosting" (func $order_hosting (param i32) (result i32)))
+            (func (export "run") (result i64) (call $balance))
+        )
+    "#;
+    let runtime = WasmtimeRuntime::new();
+    let (mut store, _) = run_example(&runtime, false);
+    let state = store.data_mut();
+    let metadata = ModuleMetadata {
+        name: "Balance".to_owned(),
+        description: "Returns the balance of the account in cents.".to_owned(),
+        price: MoneyUnit::from_cents(100),
+    };
+    marketplace::publish(&runtime, state, UserId(0), wat.as_bytes(), metadata)?;
+    let listings = match args {
+        [subcommand] if subcommand == "list" => state.marketplace.list().collect(),
+        [subcommand, query] if subcommand == "search" => state.marketplace.search(query),
+        _ => return Err(BillingError::InvalidArgumentValue.into()),
+    };
+    for listing in listings {
+        let capabilities = listing
+            .required_capabilities
+            .iter()
+            .map(|capability| format!("{capability:?}"))
+            .collect::<Vec<_>>();
+        println!(
+            "{}: {} by user {}, {}, {} installs{}",
+            listing.id.0,
+            listing.metadata.name,
+            listing.author.0,
+            listing.metadata.price,
+            listing.installs,
+            match capabilities.is_empty() {
+                true => String::new(),
+                false => format!(", requires {}", capabilities.join(", ")),
+            }
+        );
+        println!("    {}", listing.metadata.description);
+    }
+    Ok(())
+}
 
-            (func (export "run") (result i64)
-                (i32.const 30)  ;; Pass 30 to $order_hosting in order to order a month of hosting
-                (call $order_hosting)
+// `conformance run` runs the conformance suite of the host ABI against this host, failing if
+// any case fails. `conformance export <dir>` writes the suite into the directory for other
+// hosts, see `conformance`.
+fn conformance_command(args: &[String]) -> Result<(), Error> {
+    match args {
+        [subcommand, dir] if subcommand == "export" => {
+            conformance::export(dir)?;
+            println!("Exported {} cases to {dir}", conformance::CASES.len());
+            Ok(())
+        }
+        [subcommand] if subcommand == "run" => {
+            let results = conformance::run_all(&WasmtimeRuntime::new());
+            let failed = results.iter().filter(|result| !result.passed()).count();
+            for result in &results {
+                match (result.passed(), &result.outcome) {
+                    (true, _) => println!("ok   {}", result.case.name),
+                    (false, Ok(value)) => println!(
+                        "FAIL {}: returned {value}, expected {:?}",
+                        result.case.name, result.case.expected
+                    ),
+                    (false, Err(e)) => println!(
+                        "FAIL {}: {e}, expected {:?}",
+                        result.case.name, result.case.expected
+                    ),
+                }
+            }
+            match failed {
+                0 => Ok(()),
+                failed => Err(HostError::CallFailed(format!("{failed} cases failed")).into()),
+            }
+        }
+        _ => Err(BillingError::InvalidArgumentValue.into()),
+    }
+}
 
-                ;; Discard the error code
-                (drop)
+// The templates of the tiers of the example's customers
+fn example_templates() -> BTreeMap<String, AccountTemplate> {
+    let standard = AccountTemplate {
+        starting_balance: MoneyUnit::from_cents(1_000),
+        ..AccountTemplate::default()
+    };
+    let premium = AccountTemplate {
+        starting_balance: MoneyUnit::from_cents(10_000),
+        plan: Some(Plan::Paid),
+        capabilities: vec![Capability::Transfer, Capability::Secrets],
+        env: BTreeMap::from([("TIER".to_owned(), "premium".to_owned())]),
+        dirs: BTreeMap::from([(
+            "/data".to_owned(),
+            std::env::temp_dir().join("wsm-premium").join("{user}"),
+        )]),
+    };
+    BTreeMap::from([
+        ("standard".to_owned(), standard),
+        ("premium".to_owned(), premium),
+    ])
+}
 
-                (call $balance)
-            )
-        )
-    "#;
-    let mut linker = Linker::<State>::new(&engine);
+// `users export [--format csv|json]` exports the example's accounts to the standard output.
+// `users import <file> [--format csv|json]` imports the accounts of the file into them,
+// reporting the invalid rows, if any, in which case nothing is imported, see `bulk`. The format
+// defaults to the extension of the file. `users delete <id> [--erase]` closes the account of
+// the example and, with `--erase`, erases its personal data, printing the erasure certificate
+// appended to the audit log, see `erasure`. `users profile <id>` prints the profile of the
+// account of the example as JSON, as served to the customer portals, see `portal`.
+// `users add [--template standard|premium]` creates an account under the next free id, from one
+// of the example's templates, see `templates`, and prints it. `users top-up <file> [--journal
+// <file>]` credits the example's accounts with the payments of a settlement file, reporting the
+// invalid rows, if any, in which case nothing is credited, and the rows left for the
+// reconciliation, see `settlements`. The files and the payments credited are remembered in the
+// journal, so that they are not credited again by later runs.
+fn users_command(args: &[String]) -> Result<(), Error> {
+    let format_flag = |rest: &[String]| match rest {
+        [] => Ok(None),
+        [flag, format] if flag == "--format" => ExportFormat::parse(format).map(Some),
+        _ => Err(Error::from(BillingError::InvalidArgumentValue)),
+    };
+    let runtime = WasmtimeRuntime::new();
+    match args {
+        [subcommand, rest @ ..] if subcommand == "export" => {
+            let format = format_flag(rest)?.unwrap_or(ExportFormat::Csv);
+            let (store, _) = run_example(&runtime, false);
+            let records = bulk::records(store.data());
+            bulk::export(&records, format, std::io::stdout().lock())
+        }
+        [subcommand, path, rest @ ..] if subcommand == "import" => {
+            let format = match format_flag(rest)? {
+                Some(format) => format,
+                None if path.ends_with(".json") => ExportFormat::Json,
+                None => ExportFormat::Csv,
+            };
+            let text =
+                std::fs::read_to_string(path).map_err(|e| HostError::Persistence(e.to_string()))?;
+            let rows = bulk::parse(&text, format)?;
+            let (mut store, _) = run_example(&runtime, false);
+            let report = bulk::import(store.data_mut(), rows);
+            for error in &report.errors {
+                eprintln!("Row {}: {}", error.row, error.error);
+            }
+            if !report.errors.is_empty() {
+                let message = "nothing was imported, see the invalid rows above".to_owned();
+                return Err(HostError::Persistence(message).into());
+            }
+            println!(
+                "Created {} and updated {} accounts",
+                report.created, report.updated
+            );
+            Ok(())
+        }
+        [subcommand, path, rest @ ..] if subcommand == "top-up" => {
+            let journal = match rest {
+                [] => None,
+                [flag, journal] if flag == "--journal" => Some(journal),
+                _ => return Err(BillingError::InvalidArgumentValue.into()),
+            };
+            let text =
+                std::fs::read_to_string(path).map_err(|e| HostError::Persistence(e.to_string()))?;
+            let (mut store, _) = run_example(&runtime, false);
+            if let Some(journal) = journal {
+                store.data_mut().settlements = Settlements::open(journal)?;
+            }
+            let report = settlements::import(store.data_mut(), &text)?;
+            for error in &report.errors {
+                eprintln!("Row {}: {}", error.row, error.error);
+            }
+            if !report.errors.is_empty() {
+                let message = "nothing was credited, see the invalid rows above".to_owned();
+                return Err(HostError::Persistence(message).into());
+            }
+            for (row, user, amount) in &report.credited {
+                println!("Row {row}: credited {amount} to user {}", user.0);
+            }
+            for unmatched in &report.unmatched {
+                let settlement = &unmatched.settlement;
+                println!(
+                    "Row {}: no account matches `{}`, {} not credited",
+                    unmatched.row, settlement.reference, settlement.amount
+                );
+            }
+            for row in &report.duplicates {
+                println!("Row {row}: the payment has been credited already");
+            }
+            println!(
+                "Credited {} rows, {} unmatched, {} duplicates",
+                report.credited.len(),
+                report.unmatched.len(),
+                report.duplicates.len()
+            );
+            Ok(())
+        }
+        [subcommand, id, rest @ ..] if subcommand == "delete" => {
+            let user = UserId(id.parse().map_err(|_| BillingError::InvalidArgumentValue)?);
+            let erase = match rest {
+                [] => false,
+                [flag] if flag == "--erase" => true,
+                _ => return Err(BillingError::InvalidArgumentValue.into()),
+            };
+            let (mut store, _) = run_example(&runtime, false);
+            let state = store.data_mut();
+            if !erase {
+                erasure::close(state, user)?;
+                println!("Closed the account of user {}", user.0);
+                return Ok(());
+            }
+            erasure::erase(state, user)?;
+            state.erasure_log.export(std::io::stdout().lock())
+        }
+        [subcommand, rest @ ..] if subcommand == "add" => {
+            let template = match rest {
+                [] => None,
+                [flag, template] if flag == "--template" => Some(template.as_str()),
+                _ => return Err(BillingError::InvalidArgumentValue.into()),
+            };
+            let (mut store, _) = run_example(&runtime, false);
+            let state = runtime.state_mut(&mut store);
+            state.config.templates = example_templates();
+            let user = match template {
+                Some(template) => {
+                    let user = state.users.next_id()?;
+                    templates::create_user(state, user, template)?;
+                    user
+                }
+                None => state.users.register_user(&state.config, None)?,
+            };
+            let user_data = state.users.get(&user).unwrap();
+            println!(
+                "Created user {}: balance {}, plan {:?}",
+                user.0, user_data.balance, user_data.plan
+            );
+            let mut capabilities = user_data.capabilities.iter().collect::<Vec<_>>();
+            capabilities.sort_by_key(|capability| format!("{capability:?}"));
+            println!("Capabilities: {capabilities:?}");
+            for (key, value) in &user_data.env {
+                println!("Environment: {key}={value}");
+            }
+            for (guest, host) in &user_data.dirs {
+                println!("Directory: {guest} -> {}", host.display());
+            }
+            Ok(())
+        }
+        [subcommand, id] if subcommand == "profile" => {
+            let user = UserId(id.parse().map_err(|_| BillingError::InvalidArgumentValue)?);
+            let (store, _) = run_example(&runtime, false);
+            let profile = portal::profile(store.data(), user)?;
+            let json = serde_json::to_string_pretty(&profile)
+                .map_err(|e| HostError::Persistence(e.to_string()))?;
+            println!("{json}");
+            Ok(())
+        }
+        _ => Err(BillingError::InvalidArgumentValue.into()),
+    }
+}
 
-    wasmtime_wasi::add_to_linker(&mut linker, |s| &mut s.wasi_ctx).unwrap();
-    let mut store = {
-        let mut user_data = HashMap::new();
-
-        user_data.insert(
-            UserId(0),
-            UserData {
-                balance: MoneyUnit::from_cents(1_000_00),
-                hosting_days_left: 0,
-            },
+// `reconcile [--repair]` checks the balances of the example's accounts against their ledgers,
+// printing the discrepancies and setting the balances to the ones of the ledgers with
+// `--repair`, see `reconcile`. Fails if discrepancies are left.
+fn reconcile_command(args: &[String]) -> Result<(), Error> {
+    let repair = match args {
+        [] => false,
+        [flag] if flag == "--repair" => true,
+        _ => return Err(BillingError::InvalidArgumentValue.into()),
+    };
+    let runtime = WasmtimeRuntime::new();
+    let (mut store, _) = run_example(&runtime, false);
+    let state = store.data_mut();
+    let discrepancies = reconcile::reconcile(state);
+    for d in &discrepancies {
+        let computed = d.computed.map_or("unknown".to_owned(), |c| c.to_string());
+        match d.first_broken_entry {
+            Some(entry) => println!(
+                "User {}: balance {}, ledger {computed}, broken from entry {entry}",
+                d.user.0, d.stored
+            ),
+            None => println!("User {}: balance {}, ledger {computed}", d.user.0, d.stored),
+        }
+    }
+    if repair {
+        println!(
+            "Repaired {} balances",
+            reconcile::repair(state, &discrepancies)
         );
+    }
+    match reconcile::reconcile(state).len() {
+        0 => {
+            println!("The balances match the ledgers");
+            Ok(())
+        }
+        left => Err(HostError::Persistence(format!("{left} discrepancies left")).into()),
+    }
+}
 
-        let wasi_ctx = WasiCtxBuilder::new().inherit_stdio().build();
+#[cfg(feature = "sqlite")]
+fn sqlite_backend(path: &Path) -> Result<Box<dyn AccountBackend>, Error> {
+    use wasi_services_management::sqlite_store::SqliteAccountStore;
 
-        let data = State {
-            user_data,
-            wasi_ctx,
-        };
-        Store::new(&engine, data)
+    Ok(Box::new(SqliteAccountStore::open(path)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_backend(path: &Path) -> Result<Box<dyn AccountBackend>, Error> {
+    Err(HostError::Persistence(format!(
+        "unsupported store backend `sqlite:{}`, enable the `sqlite` feature",
+        path.display()
+    ))
+    .into())
+}
+
+// A backend of `store migrate`: `example` for the example's host, `memory` for an empty in-memory
+// store, with the `sqlite` feature a `sqlite:<file>`, or with the `postgres` feature a
+// `postgres://` URL
+fn account_backend(spec: &str) -> Result<Box<dyn AccountBackend>, Error> {
+    if spec == "example" {
+        let runtime = WasmtimeRuntime::new();
+        let (store, _) = run_example(&runtime, false);
+        return Ok(Box::new(runtime.take_state(store)));
+    }
+    let unsupported = || HostError::Persistence(format!("unsupported store backend `{spec}`"));
+    if let Some(path) = spec.strip_prefix("sqlite:") {
+        return sqlite_backend(Path::new(path));
+    }
+    match StoreKind::parse(spec).map_err(|_| unsupported())? {
+        StoreKind::Memory => Ok(Box::new(MemoryAccountStore::new())),
+        #[cfg(feature = "postgres")]
+        StoreKind::Postgres(url) => {
+            use wasi_services_management::pg_store::{LockConfig, PgBalanceStore};
+
+            let mut store = PgBalanceStore::connect(&url, LockConfig::default())?;
+            store.create_schema()?;
+            Ok(Box::new(store))
+        }
+        #[cfg(not(feature = "postgres"))]
+        StoreKind::Postgres(_) => Err(unsupported().into()),
+    }
+}
+
+// `store migrate --from <backend> --to <backend> [--checkpoint <file>]` copies the accounts and
+// the audit logs between the backends, resuming from the checkpoint of an interrupted run, see
+// `migrate`
+fn store_command(args: &[String]) -> Result<(), Error> {
+    let [subcommand, rest @ ..] = args else {
+        return Err(BillingError::InvalidArgumentValue.into());
+    };
+    if subcommand != "migrate" {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    let (mut from, mut to, mut checkpoint) = (None, None, None);
+    for pair in rest.chunks(2) {
+        match pair {
+            [flag, spec] if flag == "--from" => from = Some(spec),
+            [flag, spec] if flag == "--to" => to = Some(spec),
+            [flag, path] if flag == "--checkpoint" => checkpoint = Some(Path::new(path)),
+            _ => return Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+    let (Some(from), Some(to)) = (from, to) else {
+        return Err(BillingError::InvalidArgumentValue.into());
     };
+    let mut from = account_backend(from)?;
+    let mut to = account_backend(to)?;
+    let report = migrate::migrate(from.as_mut(), to.as_mut(), checkpoint)?;
+    if let Some(last) = report.resumed_after {
+        println!("Resumed after user {}", last.0);
+    }
+    println!(
+        "Copied {} users, {} of them again after they changed, and {} audit logs, and verified \
+        the copies",
+        report.copied, report.recopied, report.audit_logs
+    );
+    Ok(())
+}
 
-    let module = Module::new(&engine, wat).unwrap();
-    // let instance = linker.instantiate(&mut store, &module).unwrap();
-    let instance =
-        instantiate_services_management_module(&linker, &mut store, UserId(0), &module).unwrap();
-    let run_fn = instance
-        .get_typed_func::<(), i64>(&mut store, "run")
-        .unwrap();
-    let balance = run_fn.call(&mut store, ()).unwrap();
-    println!("The balance of root is {balance}");
+// `token issue <file> --user <id> [--scope read|write]` issues an API token of the example's
+// account or of one of its operators, `1` and `2`, and prints it, a read token unless asked
+// otherwise. `token revoke <file> <token>` revokes it. The tokens are kept in the file, as hashes,
+// e.g. for the daemon to check, see `auth::save_tokens`.
+fn token_command(args: &[String]) -> Result<(), Error> {
+    let runtime = WasmtimeRuntime::new();
+    let (mut store, _) = run_example(&runtime, false);
+    let state = store.data_mut();
+    add_example_operators(state);
+    match args {
+        [subcommand, path, rest @ ..] if subcommand == "issue" => {
+            let mut user = None;
+            let mut scope = Scope::Read;
+            let mut rest = rest.iter();
+            while let Some(flag) = rest.next() {
+                let value = rest.next().ok_or(BillingError::InvalidArgumentValue)?;
+                match flag.as_str() {
+                    "--user" => {
+                        let id = value.parse();
+                        user = Some(UserId(id.map_err(|_| BillingError::InvalidArgumentValue)?));
+                    }
+                    "--scope" => scope = Scope::parse(value)?,
+                    _ => return Err(BillingError::InvalidArgumentValue.into()),
+                }
+            }
+            let user = user.ok_or(BillingError::InvalidArgumentValue)?;
+            auth::load_tokens(&mut state.users, path)?;
+            let token = auth::issue_token(&mut state.users, user, scope)?;
+            auth::save_tokens(&state.users, path)?;
+            println!("{token}");
+            Ok(())
+        }
+        [subcommand, path, token] if subcommand == "revoke" => {
+            auth::load_tokens(&mut state.users, path)?;
+            auth::revoke_token(&mut state.users, token)?;
+            auth::save_tokens(&state.users, path)
+        }
+        _ => Err(BillingError::InvalidArgumentValue.into()),
+    }
+}
+
+// Reads the policy, the configuration and the tokens again on SIGHUP and saves the stats on
+// SIGTERM
+#[cfg(unix)]
+#[derive(Default)]
+struct CliDaemonHooks {
+    policy_path: Option<String>,
+    // See `reload`
+    config_path: Option<String>,
+    stats_path: Option<String>,
+    // See `token_command`
+    tokens_path: Option<String>,
+}
+
+#[cfg(unix)]
+impl wasi_services_management::daemon::DaemonHooks for CliDaemonHooks {
+    fn reload(&mut self, state: &mut State) -> Result<(), Error> {
+        if let Some(path) = &self.policy_path {
+            let text =
+                std::fs::read_to_string(path).map_err(|e| HostError::Persistence(e.to_string()))?;
+            state.config.policy = Policy::parse(&text)?;
+        }
+        if let Some(path) = &self.config_path {
+            let text =
+                std::fs::read_to_string(path).map_err(|e| HostError::Persistence(e.to_string()))?;
+            reload::stage(state, &text)?;
+            // No invocation is in progress between the commands
+            for change in reload::apply_staged(state) {
+                eprintln!("Changed {change}");
+            }
+        }
+        if let Some(path) = &self.tokens_path {
+            auth::load_tokens(&mut state.users, path)?;
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self, state: &mut State) -> Result<(), Error> {
+        match &self.stats_path {
+            Some(path) => state.stats.save(path),
+            None => Ok(()),
+        }
+    }
+}
+
+// `daemon <socket-path> [--health <addr>] [--reconcile-every <secs> [--repair]]
+// [--policy <file>] [--config <file>] [--stats <file>] [--outbox <file>] [--webhook <url>]
+// [--tokens <file>]` serves the example's accounts over a Unix socket, the health checks over
+// HTTP at the address and reconciles the balances with the ledgers every so many seconds, see
+// `daemon`. The requests are authorized by the tokens of the file issued with `token issue`, none
+// without one. The policy, the prices and quotas of the configuration file, see `reload`, and the
+// tokens are read again on SIGHUP, and the stats are loaded at the start and saved on SIGTERM.
+// The alerts and the notifications are posted to the webhook through the outbox journaled in the
+// file, see `outbox`.
+// `daemon <socket-path> --health-check` prints the health of the daemon listening on the socket
+// instead and fails unless it is ready.
+// `daemon <socket-path> [<options>] --install-systemd-unit <unit-path>` writes a systemd unit
+// running the daemon with the options instead, e.g. to `/etc/systemd/system/wsm.service`.
+#[cfg(unix)]
+fn run_daemon(path: &str, args: &[String]) -> Result<(), Error> {
+    use std::time::Duration;
+
+    use wasi_services_management::{
+        alerts::WebhookChannel,
+        daemon::{self, DaemonConfig, DaemonHooks},
+    };
+
+    if let [flag] = args {
+        if flag == "--health-check" {
+            let report = daemon::check_health(path)?;
+            for check in &report.checks {
+                match &check.error {
+                    None => println!("{}: ok", check.name),
+                    Some(error) => println!("{}: {error}", check.name),
+                }
+            }
+            return match report.ready {
+                true => Ok(()),
+                false => Err(HostError::Daemon("the daemon is unhealthy".to_owned()).into()),
+            };
+        }
+    }
+    let mut unit_path = None;
+    let mut options = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--install-systemd-unit" => {
+                unit_path = Some(rest.next().ok_or(BillingError::InvalidArgumentValue)?);
+            }
+            _ => options.push(arg.as_str()),
+        }
+    }
+    let mut config = DaemonConfig::default();
+    let mut hooks = CliDaemonHooks::default();
+    let mut outbox_path = None;
+    let mut webhook = None;
+    let mut args = options.iter();
+    while let Some(&flag) = args.next() {
+        let mut value = || args.next().ok_or(BillingError::InvalidArgumentValue);
+        match flag {
+            "--repair" => config.repair_balances = true,
+            "--health" => config.health_addr = Some(value()?.to_string()),
+            "--reconcile-every" => {
+                let secs = value()?
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&secs| secs > 0)
+                    .ok_or(BillingError::InvalidArgumentValue)?;
+                config.reconcile_interval = Some(Duration::from_secs(secs));
+            }
+            "--policy" => hooks.policy_path = Some(value()?.to_string()),
+            "--config" => hooks.config_path = Some(value()?.to_string()),
+            "--stats" => hooks.stats_path = Some(value()?.to_string()),
+            "--outbox" => outbox_path = Some(value()?.to_string()),
+            "--tokens" => hooks.tokens_path = Some(value()?.to_string()),
+            "--webhook" => webhook = Some(WebhookChannel::new(value()?)?),
+            _ => return Err(BillingError::InvalidArgumentValue.into()),
+        }
+    }
+    if let Some(unit_path) = unit_path {
+        let exe = std::env::current_exe().map_err(|e| HostError::Daemon(e.to_string()))?;
+        let exec_start = [exe.to_string_lossy().as_ref(), "daemon", path]
+            .into_iter()
+            .chain(options)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let unit = daemon::systemd_unit(&exec_start, Duration::from_secs(90));
+        return std::fs::write(unit_path, unit)
+            .map_err(|e| HostError::Persistence(e.to_string()).into());
+    }
+    let runtime = WasmtimeRuntime::new();
+    let (mut store, _) = run_example(&runtime, false);
+    let state = store.data_mut();
+    add_example_operators(state);
+    hooks.reload(state)?;
+    if let Some(stats_path) = &hooks.stats_path {
+        state.stats = Stats::load(stats_path)?;
+    }
+    if let Some(outbox_path) = &outbox_path {
+        state.outbox = Outbox::open(outbox_path, OutboxConfig::default())?;
+    }
+    if let Some(webhook) = webhook {
+        let webhook = webhook.with_outbox(state.outbox.clone());
+        state.alert_channels.push(Box::new(webhook));
+    }
+    daemon::serve_with_hooks(&runtime, &mut store, path, &config, &mut hooks)
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    match args.as_slice() {
+        [command, path, rest @ ..] if command == "inspect" => {
+            if let Err(e) = inspect_module(path, rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, subcommand, rest @ ..] if command == "ledger" && subcommand == "export" => {
+            if let Err(e) = export_ledger(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, subcommand, rest @ ..] if command == "ledger" && subcommand == "archive" => {
+            if let Err(e) = archive_ledger(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, subcommand, rest @ ..] if command == "ledger" && subcommand == "adjust" => {
+            if let Err(e) = adjust_ledger(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, subcommand, rest @ ..] if command == "host-api" && subcommand == "docs" => {
+            if let Err(e) = host_api_docs(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, rest @ ..] if command == "token" => {
+            if let Err(e) = token_command(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, rest @ ..] if command == "dispute" => {
+            if let Err(e) = dispute(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, rest @ ..] if command == "ticket" => {
+            if let Err(e) = ticket(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, rest @ ..] if command == "audit" => {
+            if let Err(e) = audit_command(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, rest @ ..] if command == "users" => {
+            if let Err(e) = users_command(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, path, rest @ ..] if command == "mock-host" => {
+            if let Err(e) = mock_host(path, rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, path, rest @ ..] if command == "pipe" => {
+            if let Err(e) = pipe(path, rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, rest @ ..] if command == "conformance" => {
+            if let Err(e) = conformance_command(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, path, rest @ ..] if command == "outbox" => {
+            if let Err(e) = outbox_command(path, rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, rest @ ..] if command == "marketplace" => {
+            if let Err(e) = marketplace_command(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, rest @ ..] if command == "store" => {
+            if let Err(e) = store_command(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, rest @ ..] if command == "reconcile" => {
+            if let Err(e) = reconcile_command(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        [command, rest @ ..] if command == "run" => {
+            if let Err(e) = run(rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        #[cfg(unix)]
+        [command, path, rest @ ..] if command == "daemon" => {
+            if let Err(e) = run_daemon(path, rest) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            let (_, balance) = run_example(&WasmtimeRuntime::new(), false);
+            println!("The balance of root is {balance}");
+        }
+    }
 }
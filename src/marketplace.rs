@@ -0,0 +1,261 @@
+// The marketplace of guest modules published by the users for others to install and run,
+// e.g. a ready-made static site server. A listing describes its module with the metadata of
+// its author and the capabilities its host imports require, which are derived from the module
+// rather than declared, see `host::HostFunction::capability`. Installing a listing charges its
+// price to the user and credits it to the author, installing it again is free, and only the
+// installed listings may be run by a user, with the user's own account and capabilities.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    capability::Capability,
+    history,
+    host::{self, HOST_MODULE},
+    inspect,
+    ledger::{self, EntryKind, LedgerEntry},
+    module_hash,
+    money::MoneyUnit,
+    policy::{self, Action},
+    runtime::{WasmRuntime, WasmtimeRuntime},
+    BillingError, Error, State, UserId,
+};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 5_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ListingId(pub u64);
+
+// What the author tells about the module
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModuleMetadata {
+    pub name: String,
+    pub description: String,
+    // Charged once per user on installation, free if zero
+    pub price: MoneyUnit,
+}
+
+#[derive(Clone, Debug)]
+pub struct Listing {
+    pub id: ListingId,
+    pub author: UserId,
+    pub metadata: ModuleMetadata,
+    // Hex-encoded SHA-256 of the module bytes, see `crate::module_hash`
+    pub module_hash: String,
+    // The capabilities of the host functions the module imports
+    pub required_capabilities: Vec<Capability>,
+    // Seconds since the Unix epoch
+    pub published_at: u64,
+    // The users who have installed it, including those who have uninstalled it since
+    pub installs: u64,
+}
+
+impl Listing {
+    // Whether the query is found in the name or the description, ignoring the case
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.metadata.name.to_lowercase().contains(&query)
+            || self.metadata.description.to_lowercase().contains(&query)
+    }
+}
+
+#[derive(Default)]
+pub struct Marketplace {
+    modules: BTreeMap<String, Vec<u8>>,
+    listings: BTreeMap<ListingId, Listing>,
+    installed: BTreeMap<UserId, BTreeSet<ListingId>>,
+    next_id: u64,
+}
+
+impl Marketplace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, id: ListingId) -> Option<&Listing> {
+        self.listings.get(&id)
+    }
+
+    // All the listings, oldest first
+    pub fn list(&self) -> impl Iterator<Item = &Listing> {
+        self.listings.values()
+    }
+
+    // The listings matching the query, the most installed first
+    pub fn search(&self, query: &str) -> Vec<&Listing> {
+        let mut found = self
+            .listings
+            .values()
+            .filter(|listing| listing.matches(query))
+            .collect::<Vec<_>>();
+        found.sort_by_key(|listing| std::cmp::Reverse(listing.installs));
+        found
+    }
+
+    pub fn installed_by(&self, user: UserId) -> impl Iterator<Item = &Listing> {
+        self.installed
+            .get(&user)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.listings.get(id))
+    }
+
+    pub fn is_installed(&self, user: UserId, id: ListingId) -> bool {
+        self.installed
+            .get(&user)
+            .is_some_and(|installed| installed.contains(&id))
+    }
+}
+
+// The capabilities required by the host imports of the module, failing unless all of them
+// are known host functions
+fn required_capabilities(
+    runtime: &WasmtimeRuntime,
+    bytes: &[u8],
+) -> Result<Vec<Capability>, Error> {
+    let report = inspect::inspect(runtime, bytes, |_| true)?;
+    if !report.all_imports_resolve() {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    let mut capabilities = Vec::new();
+    let imported = report
+        .imports
+        .iter()
+        .filter(|import| import.module == HOST_MODULE)
+        .filter_map(|import| host::find_host_function(&import.name)?.capability);
+    for capability in imported {
+        if !capabilities.contains(&capability) {
+            capabilities.push(capability);
+        }
+    }
+    Ok(capabilities)
+}
+
+// Publishes the module as a listing of the author. The module must compile and import only
+// known host functions.
+pub fn publish(
+    runtime: &WasmtimeRuntime,
+    state: &mut State,
+    author: UserId,
+    bytes: &[u8],
+    metadata: ModuleMetadata,
+) -> Result<ListingId, Error> {
+    if !state.users.contains(&author) {
+        return Err(BillingError::UnknownUser.into());
+    }
+    let name_len = metadata.name.trim().len();
+    if name_len == 0
+        || name_len > MAX_NAME_LEN
+        || metadata.description.len() > MAX_DESCRIPTION_LEN
+        || metadata.price.is_negative()
+    {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    let required_capabilities = required_capabilities(runtime, bytes)?;
+    let marketplace = &mut state.marketplace;
+    let module_hash = module_hash(bytes);
+    marketplace
+        .modules
+        .entry(module_hash.clone())
+        .or_insert_with(|| bytes.to_vec());
+    let id = ListingId(marketplace.next_id);
+    marketplace.next_id += 1;
+    marketplace.listings.insert(
+        id,
+        Listing {
+            id,
+            author,
+            metadata,
+            module_hash,
+            required_capabilities,
+            published_at: ledger::now_secs(),
+            installs: 0,
+        },
+    );
+    Ok(id)
+}
+
+// Installs the listing for the user, who must have the capabilities it requires. The price is
+// charged as an `EntryKind::ModuleInstall` entry and credited to the author as an
+// `EntryKind::ModuleSale` one, unless the user is the author.
+pub fn install(state: &mut State, user: UserId, id: ListingId) -> Result<(), Error> {
+    let listing = state
+        .marketplace
+        .get(id)
+        .ok_or(BillingError::UnknownListing)?
+        .clone();
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    if state.marketplace.is_installed(user, id) {
+        return Ok(());
+    }
+    let missing = listing
+        .required_capabilities
+        .iter()
+        .any(|capability| !user_data.capabilities.contains(capability));
+    if missing {
+        return Err(BillingError::MissingCapability.into());
+    }
+    let price = listing.metadata.price;
+    if !price.is_zero() && user != listing.author {
+        let balance = (user_data.balance - price)?;
+        let author_data = state
+            .users
+            .get(&listing.author)
+            .ok_or(BillingError::UnknownUser)?;
+        let author_balance = (author_data.balance + price)?;
+        drop((user_data, author_data));
+        policy::authorize(state, user, Action::Order, price)?;
+
+        let user_data = state.users.get_mut(&user).unwrap();
+        user_data.balance = balance;
+        user_data.ledger.push(LedgerEntry::new(
+            EntryKind::ModuleInstall { listing: id.0 },
+            price.checked_neg().unwrap(),
+            balance,
+        ));
+        let author_data = state.users.get_mut(&listing.author).unwrap();
+        author_data.balance = author_balance;
+        author_data.ledger.push(LedgerEntry::new(
+            EntryKind::ModuleSale { listing: id.0 },
+            price,
+            author_balance,
+        ));
+    }
+    let marketplace = &mut state.marketplace;
+    marketplace.installed.entry(user).or_default().insert(id);
+    marketplace.listings.get_mut(&id).unwrap().installs += 1;
+    Ok(())
+}
+
+// Uninstalls the listing for the user without refunding it
+pub fn uninstall(state: &mut State, user: UserId, id: ListingId) -> Result<(), Error> {
+    let removed = state
+        .marketplace
+        .installed
+        .get_mut(&user)
+        .is_some_and(|installed| installed.remove(&id));
+    match removed {
+        true => Ok(()),
+        false => Err(BillingError::NotInstalled.into()),
+    }
+}
+
+// Runs the export of the listing installed by the user, see `history::execute`
+pub fn run<R: WasmRuntime>(
+    runtime: &R,
+    store: &mut R::Store,
+    user: UserId,
+    id: ListingId,
+    export: &str,
+) -> Result<i64, Error> {
+    let marketplace = &runtime.state_mut(store).marketplace;
+    if !marketplace.is_installed(user, id) {
+        return Err(BillingError::NotInstalled.into());
+    }
+    let bytes = marketplace
+        .get(id)
+        .and_then(|listing| marketplace.modules.get(&listing.module_hash))
+        .ok_or(BillingError::UnknownListing)?
+        .clone();
+    history::execute(runtime, store, user, &bytes, export)
+}
@@ -0,0 +1,166 @@
+// Implementations of the persistence traits that keep everything in memory, selected with
+// `store = "memory"` in the configuration, see `config::StoreKind`. Nothing outlives the host,
+// so they suit demos and tests, and since they iterate in the order of the user ids and paths,
+// their contents can be compared against snapshots. The accounts themselves are kept in
+// `store::UserStore`, which iterates in the same order.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    balance_cache::BalanceBackend,
+    config::StoreKind,
+    db::DatabaseBackend,
+    migrate::{AccountBackend, AccountRecord},
+    money::MoneyUnit,
+    storage::ObjectStore,
+    BillingError, Error, State, UserId,
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryAccountStore {
+    accounts: BTreeMap<UserId, AccountRecord>,
+    audit_logs: BTreeMap<String, Vec<u8>>,
+}
+
+impl MemoryAccountStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The accounts in the order of the user ids
+    pub fn iter(&self) -> impl Iterator<Item = (UserId, &AccountRecord)> {
+        self.accounts.iter().map(|(&user, account)| (user, account))
+    }
+
+    fn update(
+        &mut self,
+        user: UserId,
+        update: impl FnOnce(MoneyUnit) -> Result<MoneyUnit, Error>,
+    ) -> Result<MoneyUnit, Error> {
+        let account = self
+            .accounts
+            .get_mut(&user)
+            .ok_or(BillingError::UnknownUser)?;
+        account.balance = update(account.balance)?;
+        Ok(account.balance)
+    }
+}
+
+impl BalanceBackend for MemoryAccountStore {
+    fn balance(&mut self, user: UserId) -> Result<MoneyUnit, Error> {
+        self.account(user).map(|account| account.balance)
+    }
+
+    fn charge(&mut self, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error> {
+        self.update(user, |balance| balance - amount)
+    }
+
+    fn credit(&mut self, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error> {
+        self.update(user, |balance| balance + amount)
+    }
+}
+
+impl AccountBackend for MemoryAccountStore {
+    fn users(&mut self) -> Result<Vec<UserId>, Error> {
+        Ok(self.accounts.keys().copied().collect())
+    }
+
+    fn account(&mut self, user: UserId) -> Result<AccountRecord, Error> {
+        self.accounts
+            .get(&user)
+            .cloned()
+            .ok_or(BillingError::UnknownUser.into())
+    }
+
+    fn put_account(&mut self, user: UserId, account: &AccountRecord) -> Result<(), Error> {
+        self.accounts.insert(user, account.clone());
+        Ok(())
+    }
+
+    fn audit_logs(&mut self) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+        Ok(self.audit_logs.clone())
+    }
+
+    fn put_audit_log(&mut self, name: &str, jsonl: &[u8]) -> Result<(), Error> {
+        self.audit_logs.insert(name.to_owned(), jsonl.to_vec());
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryObjectStore {
+    objects: BTreeMap<String, Vec<u8>>,
+}
+
+impl MemoryObjectStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The objects in the order of their paths
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.objects
+            .iter()
+            .map(|(path, bytes)| (path.as_str(), bytes.as_slice()))
+    }
+}
+
+impl ObjectStore for MemoryObjectStore {
+    fn put(&mut self, path: &str, bytes: &[u8]) -> Result<(), Error> {
+        self.objects.insert(path.to_owned(), bytes.to_vec());
+        Ok(())
+    }
+
+    fn get(&mut self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.objects.get(path).cloned())
+    }
+
+    fn delete(&mut self, path: &str) -> Result<(), Error> {
+        self.objects.remove(path);
+        Ok(())
+    }
+}
+
+// Only remembers which users have a database, handing out `memory://` connection info
+// that nothing listens on
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryDatabaseBackend {
+    databases: BTreeSet<UserId>,
+}
+
+impl MemoryDatabaseBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The users with databases in the order of their ids
+    pub fn users(&self) -> impl Iterator<Item = UserId> + '_ {
+        self.databases.iter().copied()
+    }
+}
+
+impl DatabaseBackend for MemoryDatabaseBackend {
+    fn create(&mut self, user: UserId) -> Result<String, Error> {
+        self.databases.insert(user);
+        Ok(format!("memory://user_{}", user.0))
+    }
+
+    fn wipe(&mut self, user: UserId) -> Result<(), Error> {
+        self.databases.remove(&user);
+        Ok(())
+    }
+}
+
+// Sets up the in-memory object store and database backend where no other ones have been set
+// up, if the configuration selects the memory store
+pub fn install(state: &mut State) {
+    if state.config.store != StoreKind::Memory {
+        return;
+    }
+    if state.object_store.is_none() {
+        state.object_store = Some(Box::new(MemoryObjectStore::new()));
+    }
+    if state.database_backend.is_none() {
+        state.database_backend = Some(Box::new(MemoryDatabaseBackend::new()));
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{BandwidthConfig, MemoryConfig},
+    ledger::{EntryKind, LedgerEntry},
+    money::{Currency, MoneySum, MoneyUnit},
+    plan::Plan,
+    precise::PreciseMoney,
+    storage, BillingError, Error, State, UserData, UserId,
+};
+
+const BYTES_PER_GB: u64 = 1_000_000_000;
+const WASM_PAGE_BYTES: u64 = 64 * 1024;
+const MICROS_PER_SEC: u128 = 1_000_000;
+
+// Observes the growth of the linear memories of the running instance, installed as
+// the resource limiter of the store. It never denies growth, limits are left to
+// the maximums of the memories and the pooling allocator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryMeter {
+    peak_bytes: u64,
+}
+
+impl MemoryMeter {
+    // Called for every invocation, with the size of the largest memory of its instance
+    pub(crate) fn reset(&mut self, bytes: u64) {
+        self.peak_bytes = bytes;
+    }
+
+    // The most WebAssembly pages any memory of the instance has had
+    pub fn peak_pages(&self) -> u64 {
+        self.peak_bytes.div_ceil(WASM_PAGE_BYTES)
+    }
+}
+
+impl wasmtime::ResourceLimiter for MemoryMeter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        self.peak_bytes = self.peak_bytes.max(desired as u64);
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        _desired: u32,
+        _maximum: Option<u32>,
+    ) -> wasmtime::Result<bool> {
+        Ok(true)
+    }
+}
+
+// Bills the memory held by an invocation for its duration, billing partial gigabyte-seconds
+// proportionally and rounding up to the minor unit. Accounts that cannot pay get suspended.
+pub(crate) fn charge_memory(state: &mut State, user: UserId, pages: u64, duration_micros: u64) {
+    let config: MemoryConfig = state.config.memory;
+    let price = config.price_per_gb_second;
+    let Some(user_data) = state.users.get_mut(&user) else {
+        return;
+    };
+    let byte_micros = (pages * WASM_PAGE_BYTES) as u128 * duration_micros as u128;
+    let minor = (byte_micros * price.minor_units().max(0) as u128)
+        .div_ceil(BYTES_PER_GB as u128 * MICROS_PER_SEC);
+    let Ok(minor) = i64::try_from(minor) else {
+        user_data.plan = Plan::Suspended;
+        return;
+    };
+    if minor == 0 {
+        return;
+    }
+    let cost = MoneyUnit::from_minor_units(minor, price.currency());
+    match user_data.balance - cost {
+        Ok(balance) => {
+            user_data.balance = balance;
+            user_data.ledger.push(LedgerEntry::new(
+                EntryKind::MemoryUsage {
+                    pages,
+                    duration_micros,
+                },
+                cost.checked_neg().unwrap(),
+                balance,
+            ));
+            state.stats.record_revenue(cost);
+        }
+        Err(_) => user_data.plan = Plan::Suspended,
+    }
+}
+
+// The compute of the user not posted to the ledger yet, the fuel since the last entry and its
+// price short of the rounded amounts already posted, within half a minor unit either way
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ComputeUsage {
+    pub unposted_fuel: u64,
+    pub unposted: PreciseMoney,
+}
+
+impl ComputeUsage {
+    pub fn new(currency: Currency) -> Self {
+        Self {
+            unposted_fuel: 0,
+            unposted: PreciseMoney::zero(currency),
+        }
+    }
+}
+
+// Bills the fuel of an invocation, accumulating its price, usually a fraction of the minor unit,
+// and posting the accumulated amount rounded half to even once it rounds to a whole minor unit.
+// Accounts that cannot pay get suspended.
+pub(crate) fn charge_compute(state: &mut State, user: UserId, fuel: u64) {
+    let price = state.config.compute.price_per_million_fuel;
+    let Some(user_data) = state.users.get_mut(&user) else {
+        return;
+    };
+    if price.is_zero() || fuel == 0 {
+        return;
+    }
+    let usage = user_data.compute;
+    let charged = PreciseMoney::from_ratio(
+        fuel as i128 * price.minor_units().max(0) as i128,
+        1_000_000,
+        price.currency(),
+    )
+    .and_then(|charge| usage.unposted.checked_add(charge))
+    .and_then(PreciseMoney::round_half_even);
+    let Ok((cost, rest)) = charged else {
+        user_data.plan = Plan::Suspended;
+        return;
+    };
+    let fuel = usage.unposted_fuel.saturating_add(fuel);
+    if cost.is_zero() {
+        user_data.compute = ComputeUsage {
+            unposted_fuel: fuel,
+            unposted: rest,
+        };
+        return;
+    }
+    match user_data.balance - cost {
+        Ok(balance) => {
+            user_data.balance = balance;
+            user_data.compute = ComputeUsage {
+                unposted_fuel: 0,
+                unposted: rest,
+            };
+            user_data.ledger.push(LedgerEntry::new(
+                EntryKind::ComputeUsage { fuel },
+                cost.checked_neg().unwrap(),
+                balance,
+            ));
+            state.stats.record_revenue(cost);
+        }
+        Err(_) => user_data.plan = Plan::Suspended,
+    }
+}
+
+// The calls of a priced host function made by an invocation, see `config::CallPricingConfig`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CallCharge {
+    pub function: &'static str,
+    pub calls: u64,
+    pub cost: MoneyUnit,
+}
+
+// Adds the price of a call to the charges of the running invocation, failing if the user
+// could not afford them
+pub(crate) fn meter_call(
+    state: &mut State,
+    user: UserId,
+    function: &'static str,
+    price: MoneyUnit,
+) -> Result<(), Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let charges = &mut state.call_charges;
+    let pending = charges
+        .iter()
+        .map(|charge| charge.cost)
+        .checked_sum(price.currency())?;
+    (user_data.balance - (pending + price)?)?;
+    match charges
+        .iter_mut()
+        .find(|charge| charge.function == function)
+    {
+        Some(charge) => {
+            charge.calls += 1;
+            charge.cost = (charge.cost + price)?;
+        }
+        None => charges.push(CallCharge {
+            function,
+            calls: 1,
+            cost: price,
+        }),
+    }
+    Ok(())
+}
+
+// Charges the calls of the invocation that ended as one ledger entry per function, so that
+// cheap calls made in a loop do not flood the ledger, and returns them for its
+// `history::ExecutionRecord`. Accounts that cannot pay get suspended.
+pub(crate) fn charge_calls(state: &mut State, user: UserId) -> Vec<CallCharge> {
+    let charges = std::mem::take(&mut state.call_charges);
+    let Some(user_data) = state.users.get_mut(&user) else {
+        return charges;
+    };
+    for charge in &charges {
+        match user_data.balance - charge.cost {
+            Ok(balance) => {
+                user_data.balance = balance;
+                user_data.ledger.push(LedgerEntry::new(
+                    EntryKind::HostCalls {
+                        function: charge.function.to_owned(),
+                        calls: charge.calls,
+                    },
+                    charge.cost.checked_neg().unwrap(),
+                    balance,
+                ));
+                state.stats.record_revenue(charge.cost);
+            }
+            Err(_) => user_data.plan = Plan::Suspended,
+        }
+    }
+    charges
+}
+
+// The bandwidth of the user within the current billing cycle
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthUsage {
+    pub used_this_cycle: u64,
+    // The part of the overage that has already been charged
+    pub billed_overage: u64,
+    pub cycle_days_left: u32,
+}
+
+// Collects the bytes served to the users until the nightly job aggregates them.
+// The meter is cheap to clone and can be shared with the provisioners and webhook
+// handlers, including those running on other threads.
+#[derive(Clone, Debug, Default)]
+pub struct BandwidthMeter {
+    pending: Arc<Mutex<BTreeMap<UserId, u64>>>,
+}
+
+impl BandwidthMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn report(&self, user: UserId, bytes: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        let total = pending.entry(user).or_insert(0);
+        *total = total.saturating_add(bytes);
+    }
+
+    // The bytes reported for the user since the last aggregation
+    pub fn pending(&self, user: UserId) -> u64 {
+        self.pending
+            .lock()
+            .unwrap()
+            .get(&user)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn drain(&self) -> BTreeMap<UserId, u64> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+// The bytes served to the user in the current cycle, including those not aggregated yet
+pub fn used_this_cycle(state: &State, user: UserId) -> Option<u64> {
+    let user_data = state.users.get(&user)?;
+    Some(
+        user_data
+            .bandwidth
+            .used_this_cycle
+            .saturating_add(state.bandwidth_meter.pending(user)),
+    )
+}
+
+// The price of the bytes, billing partial gigabytes proportionally and rounding up
+// to the minor unit. `None` if it does not fit into the money type.
+fn overage_cost(bytes: u64, price_per_gb: MoneyUnit) -> Option<i64> {
+    let minor =
+        (bytes as u128 * price_per_gb.minor_units().max(0) as u128).div_ceil(BYTES_PER_GB as u128);
+    i64::try_from(minor).ok()
+}
+
+// Charges the overage accumulated since the last charge. Accounts that cannot pay get suspended.
+fn charge_overage(user_data: &mut UserData, config: &BandwidthConfig) {
+    let usage = user_data.bandwidth;
+    let overage = usage.used_this_cycle.saturating_sub(config.included_bytes);
+    if overage <= usage.billed_overage {
+        return;
+    }
+    let price = config.price_per_gb;
+    let (Some(total), Some(billed)) = (
+        overage_cost(overage, price),
+        overage_cost(usage.billed_overage, price),
+    ) else {
+        user_data.plan = Plan::Suspended;
+        return;
+    };
+    let cost = MoneyUnit::from_minor_units(total - billed, price.currency());
+    match user_data.balance - cost {
+        Ok(balance) => {
+            user_data.balance = balance;
+            user_data.bandwidth.billed_overage = overage;
+            if !cost.is_zero() {
+                user_data.ledger.push(LedgerEntry::new(
+                    EntryKind::BandwidthOverage {
+                        bytes: overage - usage.billed_overage,
+                    },
+                    cost.checked_neg().unwrap(),
+                    balance,
+                ));
+            }
+        }
+        Err(_) => user_data.plan = Plan::Suspended,
+    }
+}
+
+// Aggregates the reported bandwidth into the users' cycles, charges the overage beyond
+// the allowance, records the day of storage of the active accounts, see `quota`, and bills
+// it and starts new cycles for the users whose cycle has ended.
+pub(crate) fn advance_day(state: &mut State) {
+    let config = state.config.bandwidth;
+    let storage_config = state.config.storage;
+    for (user, bytes) in state.bandwidth_meter.drain() {
+        // The reports for unknown users are dropped
+        if let Some(user_data) = state.users.get_mut(&user) {
+            let usage = &mut user_data.bandwidth;
+            usage.used_this_cycle = usage.used_this_cycle.saturating_add(bytes);
+        }
+    }
+    for (_, user_data) in state.users.iter_mut() {
+        charge_overage(user_data, &config);
+        if user_data.plan != Plan::Suspended {
+            storage::record_storage_day(user_data);
+        }
+        if user_data.bandwidth.cycle_days_left == 1 {
+            storage::charge_storage_cycle(user_data, &storage_config);
+        }
+        let usage = &mut user_data.bandwidth;
+        match usage.cycle_days_left {
+            // The first cycle of a new user starts on the first night
+            0 => usage.cycle_days_left = config.cycle_days,
+            1 => {
+                *usage = BandwidthUsage {
+                    cycle_days_left: config.cycle_days,
+                    ..Default::default()
+                }
+            }
+            _ => usage.cycle_days_left -= 1,
+        }
+    }
+}
@@ -0,0 +1,474 @@
+// Copies the accounts from one store backend to another, e.g. from the memory of a single host
+// into `pg_store::PgBalanceStore` shared by several, or out of a SQLite file written by an older
+// deployment, see `sqlite_store`. An account carries the balance and the plan of the user, the
+// ledger, the orders and the subscriptions, i.e. the provisioned catalog services. The audit logs
+// are copied after the accounts, as exported by `audit::AuditLog::export`, and their chains are
+// verified before they are written.
+//
+// The users are copied in the order of their ids and the last one copied is written to the
+// checkpoint file, if any, so that an interrupted migration resumes where it stopped. The
+// source may keep serving while it is copied: the copies are verified against the source
+// afterwards, the accounts and logs updated in the meantime are copied again, and the migration
+// fails if they keep changing for `MAX_CATCH_UP_PASSES`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, AuditLog},
+    auth,
+    ledger::LedgerEntry,
+    money::MoneyUnit,
+    orders::{Order, OrderId},
+    plan::Plan,
+    BillingError, Error, HostError, State, UserData, UserId,
+};
+
+// How many times the users updated during the migration are copied again
+pub const MAX_CATCH_UP_PASSES: u32 = 3;
+// Users copied between the writes of the checkpoint
+const CHECKPOINT_EVERY: usize = 100;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderRecord {
+    pub id: u64,
+    // Index of the purchase in the user's ledger, counting the archived entries
+    pub entry: usize,
+    // Seconds since the Unix epoch
+    pub cancelled_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountRecord {
+    pub balance: MoneyUnit,
+    pub plan: Plan,
+    pub hosting_days_left: u32,
+    // The entries not archived yet, see `archive`
+    pub ledger: Vec<LedgerEntry>,
+    pub archived_entries: usize,
+    pub orders: Vec<OrderRecord>,
+    // The catalog services the user subscribes to, see `UserData::services`
+    pub subscriptions: BTreeSet<String>,
+}
+
+impl AccountRecord {
+    // The account of a new user with the balance, e.g. one known only to the balance store
+    pub fn new(balance: MoneyUnit) -> Self {
+        Self::from(&UserData::new(balance))
+    }
+}
+
+impl From<&UserData> for AccountRecord {
+    // Without the orders, which the host keeps apart from the users
+    fn from(user_data: &UserData) -> Self {
+        Self {
+            balance: user_data.balance,
+            plan: user_data.plan,
+            hosting_days_left: user_data.hosting_days_left,
+            ledger: user_data.ledger.clone(),
+            archived_entries: user_data.archived_entries,
+            orders: Vec::new(),
+            subscriptions: user_data.services.clone(),
+        }
+    }
+}
+
+pub trait AccountBackend {
+    // The ids of all the users, in ascending order
+    fn users(&mut self) -> Result<Vec<UserId>, Error>;
+    fn account(&mut self, user: UserId) -> Result<AccountRecord, Error>;
+    // Creates the user or overwrites the account of the existing one
+    fn put_account(&mut self, user: UserId, account: &AccountRecord) -> Result<(), Error>;
+    // The audit logs by their names, e.g. `tokens`, exported as JSON Lines
+    fn audit_logs(&mut self) -> Result<BTreeMap<String, Vec<u8>>, Error>;
+    // Creates or overwrites the log, whose chain has been verified
+    fn put_audit_log(&mut self, name: &str, jsonl: &[u8]) -> Result<(), Error>;
+}
+
+fn export(log: &AuditLog<impl Serialize>) -> Result<Vec<u8>, Error> {
+    let mut jsonl = Vec::new();
+    log.export(&mut jsonl)?;
+    Ok(jsonl)
+}
+
+// The accounts and the audit logs of a running host. The host appends to its audit logs itself,
+// so they cannot be replaced, only written again unchanged.
+impl AccountBackend for State {
+    fn users(&mut self) -> Result<Vec<UserId>, Error> {
+        Ok(self.users.read().iter().map(|(&user, _)| user).collect())
+    }
+
+    fn account(&mut self, user: UserId) -> Result<AccountRecord, Error> {
+        let user_data = self.users.get(&user).ok_or(BillingError::UnknownUser)?;
+        let orders = self
+            .orders
+            .of_user(user)
+            .map(|order| OrderRecord {
+                id: order.id.0,
+                entry: order.entry,
+                cancelled_at: order.cancelled_at,
+            })
+            .collect();
+        Ok(AccountRecord {
+            orders,
+            ..AccountRecord::from(&*user_data)
+        })
+    }
+
+    fn put_account(&mut self, user: UserId, account: &AccountRecord) -> Result<(), Error> {
+        let orders = account
+            .orders
+            .iter()
+            .map(|order| Order {
+                id: OrderId(order.id),
+                user,
+                entry: order.entry,
+                cancelled_at: order.cancelled_at,
+            })
+            .collect();
+        self.orders.replace_user(user, orders)?;
+        if self.users.get(&user).is_none() {
+            self.users.insert(user, UserData::new(account.balance));
+        }
+        let user_data = self.users.get_mut(&user).ok_or(BillingError::UnknownUser)?;
+        user_data.balance = account.balance;
+        user_data.plan = account.plan;
+        user_data.hosting_days_left = account.hosting_days_left;
+        user_data.ledger = account.ledger.clone();
+        user_data.archived_entries = account.archived_entries;
+        user_data.services = account.subscriptions.clone();
+        Ok(())
+    }
+
+    fn audit_logs(&mut self) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+        Ok(BTreeMap::from([
+            (
+                "adjustments".to_owned(),
+                export(self.adjustments.audit_log())?,
+            ),
+            ("erasures".to_owned(), export(&self.erasure_log)?),
+            (
+                "retention".to_owned(),
+                export(self.retained_data.audit_log())?,
+            ),
+            ("secrets".to_owned(), export(self.secrets.audit_log())?),
+            ("tokens".to_owned(), export(auth::audit_log(&self.users))?),
+        ]))
+    }
+
+    fn put_audit_log(&mut self, name: &str, jsonl: &[u8]) -> Result<(), Error> {
+        match self.audit_logs()?.get(name) {
+            Some(existing) if existing == jsonl => Ok(()),
+            _ => Err(HostError::Persistence(format!(
+                "the audit log `{name}` of the host cannot be replaced"
+            ))
+            .into()),
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl AccountBackend for crate::pg_store::PgBalanceStore {
+    fn users(&mut self) -> Result<Vec<UserId>, Error> {
+        self.users()
+    }
+
+    fn account(&mut self, user: UserId) -> Result<AccountRecord, Error> {
+        self.account(user)
+    }
+
+    fn put_account(&mut self, user: UserId, account: &AccountRecord) -> Result<(), Error> {
+        self.put_account(user, account)
+    }
+
+    fn audit_logs(&mut self) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+        self.audit_logs()
+    }
+
+    fn put_audit_log(&mut self, name: &str, jsonl: &[u8]) -> Result<(), Error> {
+        self.put_audit_log(name, jsonl)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub copied: usize,
+    // Copied by an earlier, interrupted run, see the checkpoint
+    pub resumed_after: Option<UserId>,
+    // Copied again because they were updated during the migration
+    pub recopied: usize,
+    // The audit logs copied, including those copied again
+    pub audit_logs: usize,
+}
+
+fn persistence_error(e: impl ToString) -> Error {
+    HostError::Persistence(e.to_string()).into()
+}
+
+fn read_checkpoint(path: &Path) -> Result<Option<UserId>, Error> {
+    match fs::read_to_string(path) {
+        Ok(text) => text
+            .trim()
+            .parse()
+            .map(|user| Some(UserId(user)))
+            .map_err(persistence_error),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(persistence_error(e)),
+    }
+}
+
+fn write_checkpoint(path: &Path, user: UserId) -> Result<(), Error> {
+    // Renamed into place, so that a crash never leaves a torn checkpoint
+    let partial = path.with_extension("partial");
+    fs::write(&partial, user.0.to_string()).map_err(persistence_error)?;
+    fs::rename(&partial, path).map_err(persistence_error)
+}
+
+// The users whose accounts differ between the backends, including those missing from `to`
+fn differing(
+    from: &mut (impl AccountBackend + ?Sized),
+    to: &mut (impl AccountBackend + ?Sized),
+) -> Result<Vec<UserId>, Error> {
+    let mut differing = Vec::new();
+    for user in from.users()? {
+        let account = from.account(user)?;
+        if to.account(user).ok() != Some(account) {
+            differing.push(user);
+        }
+    }
+    Ok(differing)
+}
+
+// The audit logs that differ between the backends, including those missing from `to`, with
+// their exports from `from`
+fn differing_audit_logs(
+    from: &mut (impl AccountBackend + ?Sized),
+    to: &mut (impl AccountBackend + ?Sized),
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let copied = to.audit_logs()?;
+    Ok(from
+        .audit_logs()?
+        .into_iter()
+        .filter(|(name, jsonl)| copied.get(name) != Some(jsonl))
+        .collect())
+}
+
+// Copies the audit logs of `from` that differ in `to`, returning their number
+fn copy_audit_logs(
+    from: &mut (impl AccountBackend + ?Sized),
+    to: &mut (impl AccountBackend + ?Sized),
+) -> Result<usize, Error> {
+    let differing = differing_audit_logs(from, to)?;
+    for (name, jsonl) in &differing {
+        audit::verify_export(jsonl.as_slice())?;
+        to.put_audit_log(name, jsonl)?;
+    }
+    Ok(differing.len())
+}
+
+// Copies the users of `from` missing from the checkpoint and the audit logs into `to` and
+// verifies the copies
+pub fn migrate(
+    from: &mut (impl AccountBackend + ?Sized),
+    to: &mut (impl AccountBackend + ?Sized),
+    checkpoint: Option<&Path>,
+) -> Result<MigrationReport, Error> {
+    let resumed_after = match checkpoint {
+        Some(path) => read_checkpoint(path)?,
+        None => None,
+    };
+    let mut report = MigrationReport {
+        resumed_after,
+        ..MigrationReport::default()
+    };
+    let users = from.users()?;
+    let pending = users
+        .iter()
+        .filter(|&&user| resumed_after.is_none_or(|last| user > last));
+    for (i, &user) in pending.enumerate() {
+        to.put_account(user, &from.account(user)?)?;
+        report.copied += 1;
+        if let Some(path) = checkpoint.filter(|_| (i + 1) % CHECKPOINT_EVERY == 0) {
+            write_checkpoint(path, user)?;
+        }
+    }
+    if let (Some(path), Some(&last)) = (checkpoint, users.last()) {
+        write_checkpoint(path, last)?;
+    }
+    report.audit_logs = copy_audit_logs(from, to)?;
+    for _ in 0..MAX_CATCH_UP_PASSES {
+        let differing = differing(from, to)?;
+        let audit_logs = copy_audit_logs(from, to)?;
+        if differing.is_empty() && audit_logs == 0 {
+            return Ok(report);
+        }
+        for user in differing {
+            to.put_account(user, &from.account(user)?)?;
+            report.recopied += 1;
+        }
+        report.audit_logs += audit_logs;
+    }
+    let (users, audit_logs) = (
+        differing(from, to)?.len(),
+        differing_audit_logs(from, to)?.len(),
+    );
+    match (users, audit_logs) {
+        (0, 0) => Ok(report),
+        _ => Err(HostError::Persistence(format!(
+            "{users} users and {audit_logs} audit logs kept changing during the migration"
+        ))
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        auth::Scope, ledger::EntryKind, memory_store::MemoryAccountStore, store::UserStore,
+    };
+
+    const USER: UserId = UserId(3);
+
+    // A host whose user has a ledger, a cancelled order, a subscription and a token
+    fn state() -> State {
+        let mut users = UserStore::new();
+        let mut user_data = UserData::new(MoneyUnit::from_cents(900));
+        user_data.plan = Plan::Grace { days_left: 2 };
+        user_data.hosting_days_left = 7;
+        user_data.ledger.push(LedgerEntry::new(
+            EntryKind::BundleOrder {
+                bundle: "starter".to_owned(),
+            },
+            MoneyUnit::from_cents(-100),
+            MoneyUnit::from_cents(900),
+        ));
+        user_data.archived_entries = 4;
+        user_data.services.insert("web".to_owned());
+        users.insert(USER, user_data);
+        users.insert(UserId(8), UserData::new(MoneyUnit::from_cents(5)));
+        auth::issue_token(&mut users, USER, Scope::Write).unwrap();
+        let mut state = State::new(users);
+        state.orders.place(UserId(8), 0);
+        let order = state.orders.place(USER, 4);
+        state.orders.cancel(order, 1_700_000_000);
+        state
+    }
+
+    // Every account and audit log of `from` is found unchanged in `to`
+    fn assert_copied(from: &mut impl AccountBackend, to: &mut impl AccountBackend) {
+        assert_eq!(from.users().unwrap(), to.users().unwrap());
+        for user in from.users().unwrap() {
+            assert_eq!(from.account(user).unwrap(), to.account(user).unwrap());
+        }
+        assert_eq!(from.audit_logs().unwrap(), to.audit_logs().unwrap());
+    }
+
+    #[test]
+    fn accounts_and_audit_logs_are_copied() {
+        let mut state = state();
+        let mut store = MemoryAccountStore::new();
+        let report = migrate(&mut state, &mut store, None).unwrap();
+        assert_eq!(
+            (report.copied, report.recopied, report.audit_logs),
+            (2, 0, 5)
+        );
+        assert_copied(&mut state, &mut store);
+
+        let account = store.account(USER).unwrap();
+        assert_eq!(account.balance, MoneyUnit::from_cents(900));
+        assert_eq!(account.plan, Plan::Grace { days_left: 2 });
+        assert_eq!(account.hosting_days_left, 7);
+        assert_eq!(account.ledger.len(), 1);
+        assert_eq!(account.archived_entries, 4);
+        assert_eq!(
+            account.orders,
+            [OrderRecord {
+                id: 1,
+                entry: 4,
+                cancelled_at: Some(1_700_000_000),
+            }]
+        );
+        assert_eq!(account.subscriptions, BTreeSet::from(["web".to_owned()]));
+        let tokens = &store.audit_logs().unwrap()["tokens"];
+        assert_eq!(audit::verify_export(tokens.as_slice()).unwrap(), 1);
+    }
+
+    #[test]
+    fn accounts_are_restored_into_a_host() {
+        let account = state().account(USER).unwrap();
+        let mut state = State::new(UserStore::new());
+        state.put_account(USER, &account).unwrap();
+        assert_eq!(state.account(USER).unwrap(), account);
+        // The ids of the orders placed afterwards follow the copied ones
+        assert_eq!(state.orders.place(USER, 5), OrderId(2));
+    }
+
+    #[test]
+    fn orders_of_other_users_are_not_overwritten() {
+        let mut state = state();
+        let mut account = state.account(USER).unwrap();
+        account.orders[0].id = 0;
+        assert!(state.put_account(USER, &account).is_err());
+        assert_eq!(state.orders.of_user(UserId(8)).count(), 1);
+    }
+
+    #[test]
+    fn broken_audit_logs_are_not_copied() {
+        let mut from = MemoryAccountStore::new();
+        let mut tokens = Vec::new();
+        auth::audit_log(&state().users).export(&mut tokens).unwrap();
+        let tampered = String::from_utf8(tokens)
+            .unwrap()
+            .replace("\"by_guest\":false", "\"by_guest\":true");
+        from.put_audit_log("tokens", tampered.as_bytes()).unwrap();
+        let mut to = MemoryAccountStore::new();
+        assert!(matches!(
+            migrate(&mut from, &mut to, None),
+            Err(Error::Host(HostError::AuditChainBroken(0)))
+        ));
+        assert!(to.audit_logs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn interrupted_migrations_resume_after_the_checkpoint() {
+        let checkpoint = std::env::temp_dir().join(format!("migrate-{}", std::process::id()));
+        fs::write(&checkpoint, "3").unwrap();
+        let mut state = state();
+        let mut store = MemoryAccountStore::new();
+        let report = migrate(&mut state, &mut store, Some(&checkpoint)).unwrap();
+        fs::remove_file(&checkpoint).unwrap();
+        // User 3 is missing from the store, so the verification copies it again
+        assert_eq!(report.resumed_after, Some(USER));
+        assert_eq!((report.copied, report.recopied), (1, 1));
+        assert_copied(&mut state, &mut store);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn accounts_and_audit_logs_are_read_back_from_sqlite() {
+        use crate::sqlite_store::SqliteAccountStore;
+
+        let path = std::env::temp_dir().join(format!("migrate-{}.sqlite", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut state = state();
+        migrate(
+            &mut state,
+            &mut SqliteAccountStore::open(&path).unwrap(),
+            None,
+        )
+        .unwrap();
+        // Reopened, as `store migrate --from sqlite:<file>` would
+        let mut sqlite = SqliteAccountStore::open(&path).unwrap();
+        let mut store = MemoryAccountStore::new();
+        migrate(&mut sqlite, &mut store, None).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_copied(&mut state, &mut store);
+    }
+}
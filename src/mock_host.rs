@@ -0,0 +1,194 @@
+// A stand-in for the host to run guest modules against in CI, without accounts, services
+// or databases behind it, e.g. with `mock-host <module> [--script <file>] [--export <name>]`.
+// Every function of `host::HOST_FUNCTIONS` is implemented with its exact signature,
+// its calls are recorded and answered from a script:
+//
+// ```text
+// # <function> <response> [<response>...]
+// balance 100000
+// order_hosting 0 PolicyDenied
+// ```
+//
+// Successive calls get successive responses and the last one repeats. A response is
+// an integer or the name of a `BillingError` standing for its code, e.g. `PolicyDenied`.
+// Functions without a line in the script return 0. The WASI imports of the guest are linked
+// with the standard streams inherited.
+
+use std::collections::BTreeMap;
+
+use strum::IntoEnumIterator;
+use wasmtime::{Caller, Config, Engine, FuncType, Linker, Module, Store, Val, ValType};
+use wasmtime_wasi::{sync::WasiCtxBuilder, WasiCtx};
+
+use crate::{
+    host::{self, HOST_FUNCTIONS, HOST_MODULE},
+    BillingError, Error, HostError,
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MockScript {
+    responses: BTreeMap<&'static str, Vec<i64>>,
+}
+
+fn script_error(line: usize, message: impl std::fmt::Display) -> Error {
+    HostError::MockScript(format!("line {line}: {message}")).into()
+}
+
+fn parse_response(word: &str) -> Option<i64> {
+    word.parse().ok().or_else(|| {
+        BillingError::iter()
+            .find(|e| !matches!(e, BillingError::Custom(_)) && <&str>::from(e) == word)
+            .map(|e| e.code() as i64)
+    })
+}
+
+impl MockScript {
+    // Fails on unknown functions, unparsable responses and responses to functions
+    // without results
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut responses = BTreeMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let mut words = line.split('#').next().unwrap().split_whitespace();
+            let Some(name) = words.next() else {
+                continue;
+            };
+            let function = host::find_host_function(name)
+                .ok_or_else(|| script_error(i + 1, format!("unknown function `{name}`")))?;
+            let values = words
+                .map(|word| {
+                    parse_response(word)
+                        .ok_or_else(|| script_error(i + 1, format!("invalid response `{word}`")))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if function.results.is_empty() && !values.is_empty() {
+                return Err(script_error(i + 1, format!("`{name}` returns nothing")));
+            }
+            responses.insert(function.name, values);
+        }
+        Ok(Self { responses })
+    }
+
+    // The response to the call of the function made after `previous` others
+    fn response(&self, function: &str, previous: usize) -> i64 {
+        match self.responses.get(function) {
+            Some(values) if !values.is_empty() => values[previous.min(values.len() - 1)],
+            _ => 0,
+        }
+    }
+}
+
+// A call of a host function made by the guest
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockCall {
+    pub function: &'static str,
+    pub params: Vec<i64>,
+    // `None` for the functions without results
+    pub result: Option<i64>,
+}
+
+pub struct MockState {
+    wasi_ctx: WasiCtx,
+    script: MockScript,
+    calls: Vec<MockCall>,
+}
+
+// The outcome of `MockHost::run`
+#[derive(Debug)]
+pub struct MockRun {
+    pub result: Result<i64, Error>,
+    // In call order
+    pub calls: Vec<MockCall>,
+}
+
+pub struct MockHost {
+    engine: Engine,
+    linker: Linker<MockState>,
+}
+
+fn to_i64(val: &Val) -> i64 {
+    match *val {
+        Val::I32(value) => value as i64,
+        Val::I64(value) => value,
+        _ => 0,
+    }
+}
+
+impl MockHost {
+    pub fn new() -> Result<Self, Error> {
+        let engine =
+            Engine::new(&Config::new()).map_err(|e| HostError::EngineConfig(e.to_string()))?;
+        let mut linker = Linker::<MockState>::new(&engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |s| &mut s.wasi_ctx)
+            .map_err(|e| HostError::EngineConfig(e.to_string()))?;
+        for function in HOST_FUNCTIONS {
+            let ty = FuncType::new(
+                function.params.iter().cloned(),
+                function.results.iter().cloned(),
+            );
+            let name = function.name;
+            linker
+                .func_new(
+                    HOST_MODULE,
+                    name,
+                    ty,
+                    move |mut caller: Caller<'_, MockState>, params, results| {
+                        let state = caller.data_mut();
+                        let previous = state
+                            .calls
+                            .iter()
+                            .filter(|call| call.function == name)
+                            .count();
+                        let response = state.script.response(name, previous);
+                        let result = results.first_mut().map(|result| {
+                            *result = match function.results[0] {
+                                ValType::I32 => Val::I32(response as i32),
+                                _ => Val::I64(response),
+                            };
+                            response
+                        });
+                        state.calls.push(MockCall {
+                            function: name,
+                            params: params.iter().map(to_i64).collect(),
+                            result,
+                        });
+                        Ok(())
+                    },
+                )
+                .map_err(|e| HostError::EngineConfig(e.to_string()))?;
+        }
+        Ok(Self { engine, linker })
+    }
+
+    // Instantiates the module and calls its `() -> i64` export like `WasmRuntime::call`,
+    // answering its calls of the host functions from the script
+    pub fn run(&self, bytes: &[u8], export: &str, script: MockScript) -> MockRun {
+        let state = MockState {
+            wasi_ctx: WasiCtxBuilder::new().inherit_stdio().build(),
+            script,
+            calls: Vec::new(),
+        };
+        let mut store = Store::new(&self.engine, state);
+        let result = self.call(&mut store, bytes, export);
+        MockRun {
+            result,
+            calls: store.into_data().calls,
+        }
+    }
+
+    fn call(&self, store: &mut Store<MockState>, bytes: &[u8], export: &str) -> Result<i64, Error> {
+        let module = Module::new(&self.engine, bytes)
+            .map_err(|e| HostError::CompilationFailed(e.to_string()))?;
+        for import in module.imports().filter(|i| i.module() == HOST_MODULE) {
+            host::check_import(&import)?;
+        }
+        let instance = self
+            .linker
+            .instantiate(&mut *store, &module)
+            .map_err(|e| HostError::InstantiationFailed(e.to_string()))?;
+        let func = instance
+            .get_typed_func::<(), i64>(&mut *store, export)
+            .map_err(|e| HostError::CallFailed(e.to_string()))?;
+        func.call(&mut *store, ())
+            .map_err(|e| HostError::CallFailed(e.to_string()).into())
+    }
+}
@@ -1,18 +1,235 @@
-use std::ops::{Mul, Sub};
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt,
+    iter::Sum,
+    ops::{Add, Mul, Sub},
+};
 
-use crate::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::{BillingError, Error};
+
+// ISO 4217 currencies. The list is not exhaustive, currencies are added as needed.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+pub enum Currency {
+    #[default]
+    USD,
+    EUR,
+    GBP,
+    JPY,
+    KRW,
+    KWD,
+    BHD,
+    TND,
+}
+
+impl Currency {
+    // The number of decimal places of the minor unit,
+    // e.g. 2 for cents of USD, 0 for JPY and 3 for fils of KWD.
+    pub const fn exponent(self) -> u32 {
+        match self {
+            Currency::JPY | Currency::KRW => 0,
+            Currency::USD | Currency::EUR | Currency::GBP => 2,
+            Currency::KWD | Currency::BHD | Currency::TND => 3,
+        }
+    }
+
+    pub const fn code(self) -> &'static str {
+        match self {
+            Currency::USD => "USD",
+            Currency::EUR => "EUR",
+            Currency::GBP => "GBP",
+            Currency::JPY => "JPY",
+            Currency::KRW => "KRW",
+            Currency::KWD => "KWD",
+            Currency::BHD => "BHD",
+            Currency::TND => "TND",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        [
+            Currency::USD,
+            Currency::EUR,
+            Currency::GBP,
+            Currency::JPY,
+            Currency::KRW,
+            Currency::KWD,
+            Currency::BHD,
+            Currency::TND,
+        ]
+        .into_iter()
+        .find(|c| c.code() == code)
+    }
+}
 
 // TODO: consider using rusty-money crate
-#[derive(Clone, Copy)]
-pub(crate) struct MoneyUnit(i64);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct MoneyUnit {
+    // The amount in the minor units of the currency (e.g. cents)
+    minor: i64,
+    currency: Currency,
+}
+
+// Divides rounding half to even ("banker's rounding")
+pub(crate) fn div_round_half_even(n: i128, d: i128) -> i128 {
+    let q = n.div_euclid(d);
+    let r = n.rem_euclid(d);
+    match (2 * r).cmp(&d) {
+        Ordering::Less => q,
+        Ordering::Greater => q + 1,
+        Ordering::Equal => q + (q & 1),
+    }
+}
 
 impl MoneyUnit {
-    pub(crate) const fn from_cents(v: i64) -> Self {
-        Self(v)
+    // Cents of the default currency (USD)
+    pub const fn from_cents(v: i64) -> Self {
+        Self::from_minor_units(v, Currency::USD)
+    }
+
+    pub const fn from_minor_units(minor: i64, currency: Currency) -> Self {
+        Self { minor, currency }
+    }
+
+    pub const fn zero(currency: Currency) -> Self {
+        Self::from_minor_units(0, currency)
+    }
+
+    // For currencies other than USD, this is the amount in their minor units
+    pub const fn to_cents_as_i64(self) -> i64 {
+        self.minor
+    }
+
+    pub const fn minor_units(self) -> i64 {
+        self.minor
+    }
+
+    pub const fn currency(self) -> Currency {
+        self.currency
+    }
+
+    pub const fn is_negative(self) -> bool {
+        self.minor < 0
+    }
+
+    pub const fn is_zero(self) -> bool {
+        self.minor == 0
+    }
+
+    // Compares amounts of the same currency. `MoneyUnit` is not `Ord`, since amounts of
+    // different currencies cannot be ordered without an exchange rate.
+    pub fn checked_cmp(self, other: Self) -> Result<Ordering, Error> {
+        if self.currency != other.currency {
+            return Err(BillingError::CurrencyMismatch.into());
+        }
+        Ok(self.minor.cmp(&other.minor))
+    }
+
+    // The amount with the opposite sign, e.g. for recording debits in the ledger
+    pub const fn checked_neg(self) -> Option<Self> {
+        match self.minor.checked_neg() {
+            Some(minor) => Some(Self::from_minor_units(minor, self.currency)),
+            None => None,
+        }
+    }
+
+    // The given fraction of the amount expressed in basis points (1/100 of a percent),
+    // rounded towards zero.
+    pub fn basis_points(self, bp: u32) -> Option<Self> {
+        self.minor
+            .checked_mul(bp as i64)
+            .map(|v| Self::from_minor_units(v / 10_000, self.currency))
+    }
+
+    // Parses a decimal amount like `12.345` in the major units of the currency.
+    // Amounts more precise than the minor unit are rounded half to even.
+    pub fn parse(s: &str, currency: Currency) -> Result<Self, Error> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let is_number = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        // The length limits keep the digits within i128, scaling them to the minor unit may
+        // still overflow
+        if whole.is_empty()
+            || whole.len() > 19
+            || fraction.len() > 18
+            || !is_number(whole)
+            || !is_number(fraction)
+        {
+            return Err(BillingError::InvalidArgumentValue.into());
+        }
+        let exponent = currency.exponent();
+        let scale = 10i128.pow(fraction.len() as u32);
+        let value = format!("{whole}{fraction}")
+            .parse::<i128>()
+            .map_err(|_| BillingError::InvalidArgumentValue)?;
+        let value = value
+            .checked_mul(10i128.pow(exponent))
+            .ok_or(BillingError::TotalCostExceededMaxValue)?;
+        let minor = div_round_half_even(value, scale);
+        let minor = if negative { -minor } else { minor };
+        i64::try_from(minor)
+            .map(|minor| Self::from_minor_units(minor, currency))
+            .map_err(|_| BillingError::TotalCostExceededMaxValue.into())
+    }
+
+    // Converts the amount at the exchange rate `numerator / denominator`
+    // (units of the target currency per unit of the source currency),
+    // rounding half to even to the minor unit of the target currency.
+    pub fn convert(self, to: Currency, numerator: u64, denominator: u64) -> Option<Self> {
+        if denominator == 0 {
+            return None;
+        }
+        let from_scale = 10i128.pow(self.currency.exponent());
+        let to_scale = 10i128.pow(to.exponent());
+        let n = (self.minor as i128)
+            .checked_mul(numerator as i128)?
+            .checked_mul(to_scale)?;
+        let d = (denominator as i128).checked_mul(from_scale)?;
+        let minor = i64::try_from(div_round_half_even(n, d)).ok()?;
+        Some(Self::from_minor_units(minor, to))
+    }
+
+    // Unlike `-`, the result may become negative, e.g. for debits the user cannot refuse
+    // such as chargebacks
+    pub fn sub_allowing_negative(self, rhs: Self) -> Result<Self, Error> {
+        if self.currency != rhs.currency {
+            return Err(BillingError::CurrencyMismatch.into());
+        }
+        self.minor
+            .checked_sub(rhs.minor)
+            .map(|minor| Self::from_minor_units(minor, self.currency))
+            .ok_or(BillingError::BalanceWouldUnderflow.into())
     }
 
-    pub(crate) const fn to_cents_as_i64(self) -> i64 {
-        self.0
+    // The amount in the major units of the currency without the currency code, e.g. `-0.05`
+    pub fn to_decimal_string(self) -> String {
+        let sign = if self.minor < 0 { "-" } else { "" };
+        let abs = self.minor.unsigned_abs();
+        let exponent = self.currency.exponent();
+        if exponent == 0 {
+            return format!("{sign}{abs}");
+        }
+        let scale = 10u64.pow(exponent);
+        format!(
+            "{sign}{}.{:0width$}",
+            abs / scale,
+            abs % scale,
+            width = exponent as usize
+        )
+    }
+}
+
+impl fmt::Display for MoneyUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.to_decimal_string(), self.currency.code())
     }
 }
 
@@ -22,7 +239,9 @@ impl Mul<i64> for MoneyUnit {
     type Output = Option<Self>;
 
     fn mul(self, rhs: i64) -> Self::Output {
-        self.0.checked_mul(rhs).map(Self)
+        self.minor
+            .checked_mul(rhs)
+            .map(|minor| Self::from_minor_units(minor, self.currency))
     }
 }
 
@@ -30,7 +249,7 @@ impl Mul<i32> for MoneyUnit {
     type Output = Option<Self>;
 
     fn mul(self, rhs: i32) -> Self::Output {
-        self.0.checked_mul(rhs as i64).map(Self)
+        self * (rhs as i64)
     }
 }
 
@@ -44,16 +263,267 @@ impl Sub<Self> for MoneyUnit {
     type Output = Result<Self, Error>;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        if self.0 < 0 {
-            return Err(Error::NegativeBalance);
+        if self.currency != rhs.currency {
+            return Err(BillingError::CurrencyMismatch.into());
+        }
+        if self.minor < 0 {
+            return Err(BillingError::NegativeBalance.into());
         };
         let res = self
-            .0
-            .checked_sub(rhs.0)
-            .ok_or(Error::BalanceWouldUnderflow)?;
+            .minor
+            .checked_sub(rhs.minor)
+            .ok_or(BillingError::BalanceWouldUnderflow)?;
         if res < 0 {
-            return Err(Error::BalanceWouldBecomeNegative);
+            return Err(BillingError::BalanceWouldBecomeNegative.into());
+        }
+        Ok(Self::from_minor_units(res, self.currency))
+    }
+}
+
+impl Add<Self> for MoneyUnit {
+    type Output = Result<Self, Error>;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        if self.currency != rhs.currency {
+            return Err(BillingError::CurrencyMismatch.into());
+        }
+        self.minor
+            .checked_add(rhs.minor)
+            .map(|minor| Self::from_minor_units(minor, self.currency))
+            .ok_or(BillingError::BalanceWouldOverflow.into())
+    }
+}
+
+// Sums amounts of the same currency without panicking, e.g.
+// `lines.iter().map(..).checked_sum(currency)`
+pub trait MoneySum: Iterator {
+    // The total of the amounts, zero if there are none. Stops at the first amount of
+    // another currency or the first overflow.
+    fn checked_sum(self, currency: Currency) -> Result<MoneyUnit, Error>;
+}
+
+impl<I> MoneySum for I
+where
+    I: Iterator,
+    I::Item: Borrow<MoneyUnit>,
+{
+    fn checked_sum(mut self, currency: Currency) -> Result<MoneyUnit, Error> {
+        self.try_fold(MoneyUnit::zero(currency), |total, amount| {
+            let amount = *amount.borrow();
+            if amount.currency != currency {
+                return Err(BillingError::CurrencyMismatch.into());
+            }
+            (total + amount).map_err(|_| BillingError::TotalCostExceededMaxValue.into())
+        })
+    }
+}
+
+// The totals of amounts of possibly different currencies, e.g. of the ledgers of several users
+pub fn sum_by_currency<I>(amounts: I) -> Result<BTreeMap<Currency, MoneyUnit>, Error>
+where
+    I: IntoIterator,
+    I::Item: Borrow<MoneyUnit>,
+{
+    let mut totals = BTreeMap::new();
+    for amount in amounts {
+        let amount = *amount.borrow();
+        let total = totals
+            .entry(amount.currency)
+            .or_insert(MoneyUnit::zero(amount.currency));
+        *total = (*total + amount).map_err(|_| BillingError::TotalCostExceededMaxValue)?;
+    }
+    Ok(totals)
+}
+
+// `None` if there are no amounts, since their currency is unknown then, if they are of
+// different currencies or if the total overflows. `MoneySum::checked_sum` tells which.
+impl Sum<MoneyUnit> for Option<MoneyUnit> {
+    fn sum<I: Iterator<Item = MoneyUnit>>(mut iter: I) -> Self {
+        let first = iter.next()?;
+        iter.try_fold(first, |total, amount| (total + amount).ok())
+    }
+}
+
+impl<'a> Sum<&'a MoneyUnit> for Option<MoneyUnit> {
+    fn sum<I: Iterator<Item = &'a MoneyUnit>>(iter: I) -> Self {
+        iter.copied().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_according_to_the_exponent() {
+        let jpy = MoneyUnit::parse("1234", Currency::JPY).unwrap();
+        assert_eq!(jpy.minor_units(), 1234);
+        let kwd = MoneyUnit::parse("12.345", Currency::KWD).unwrap();
+        assert_eq!(kwd.minor_units(), 12_345);
+        let usd = MoneyUnit::parse("-0.5", Currency::USD).unwrap();
+        assert_eq!(usd.minor_units(), -50);
+        assert!(MoneyUnit::parse("1.2.3", Currency::USD).is_err());
+        assert!(MoneyUnit::parse(".5", Currency::USD).is_err());
+    }
+
+    #[test]
+    fn rejects_the_longest_amounts_beyond_the_range() {
+        let longest = format!("{}.{}", "9".repeat(19), "9".repeat(18));
+        for currency in [Currency::JPY, Currency::USD, Currency::KWD] {
+            let error = MoneyUnit::parse(&longest, currency).unwrap_err();
+            assert_eq!(error.code(), BillingError::TotalCostExceededMaxValue.code());
+            let error = MoneyUnit::parse(&format!("-{longest}"), currency).unwrap_err();
+            assert_eq!(error.code(), BillingError::TotalCostExceededMaxValue.code());
         }
-        Ok(Self(res))
+    }
+
+    #[test]
+    fn rounds_excess_precision_half_to_even() {
+        assert_eq!(
+            MoneyUnit::parse("0.5", Currency::JPY)
+                .unwrap()
+                .minor_units(),
+            0
+        );
+        assert_eq!(
+            MoneyUnit::parse("1.5", Currency::JPY)
+                .unwrap()
+                .minor_units(),
+            2
+        );
+        assert_eq!(
+            MoneyUnit::parse("0.0125", Currency::KWD)
+                .unwrap()
+                .minor_units(),
+            12
+        );
+        assert_eq!(
+            MoneyUnit::parse("0.0135", Currency::KWD)
+                .unwrap()
+                .minor_units(),
+            14
+        );
+        assert_eq!(
+            MoneyUnit::parse("-2.5", Currency::KRW)
+                .unwrap()
+                .minor_units(),
+            -2
+        );
+    }
+
+    #[test]
+    fn formats_according_to_the_exponent() {
+        assert_eq!(
+            MoneyUnit::from_minor_units(1234, Currency::JPY).to_string(),
+            "1234 JPY"
+        );
+        assert_eq!(
+            MoneyUnit::from_minor_units(12_005, Currency::KWD).to_string(),
+            "12.005 KWD"
+        );
+        assert_eq!(MoneyUnit::from_cents(-5).to_string(), "-0.05 USD");
+    }
+
+    #[test]
+    fn converts_between_exponents() {
+        // 1 KWD = 3.25 USD
+        let kwd = MoneyUnit::from_minor_units(1_000, Currency::KWD);
+        let usd = kwd.convert(Currency::USD, 325, 100).unwrap();
+        assert_eq!(usd, MoneyUnit::from_cents(325));
+        // 1 USD = 150 JPY
+        let jpy = MoneyUnit::from_cents(1)
+            .convert(Currency::JPY, 150, 1)
+            .unwrap();
+        assert_eq!(jpy.minor_units(), 2);
+        let back = MoneyUnit::from_minor_units(3, Currency::JPY)
+            .convert(Currency::KWD, 1, 500)
+            .unwrap();
+        assert_eq!(back.minor_units(), 6);
+    }
+
+    #[test]
+    fn rejects_mixed_currencies() {
+        let usd = MoneyUnit::from_cents(100);
+        let eur = MoneyUnit::from_minor_units(100, Currency::EUR);
+        assert!(matches!(
+            usd + eur,
+            Err(Error::Billing(BillingError::CurrencyMismatch))
+        ));
+        assert!(matches!(
+            usd - eur,
+            Err(Error::Billing(BillingError::CurrencyMismatch))
+        ));
+    }
+
+    #[test]
+    fn compares_within_a_currency() {
+        let usd = MoneyUnit::from_cents(100);
+        let eur = MoneyUnit::from_minor_units(50, Currency::EUR);
+        assert_eq!(
+            usd.checked_cmp(MoneyUnit::from_cents(99)).unwrap(),
+            Ordering::Greater
+        );
+        assert_eq!(usd.checked_cmp(usd).unwrap(), Ordering::Equal);
+        assert!(matches!(
+            usd.checked_cmp(eur),
+            Err(Error::Billing(BillingError::CurrencyMismatch))
+        ));
+    }
+
+    #[test]
+    fn sums_without_panicking() {
+        let amounts = [MoneyUnit::from_cents(150), MoneyUnit::from_cents(-50)];
+        assert_eq!(
+            amounts.iter().checked_sum(Currency::USD).unwrap(),
+            MoneyUnit::from_cents(100)
+        );
+        assert_eq!(
+            std::iter::empty::<MoneyUnit>()
+                .checked_sum(Currency::JPY)
+                .unwrap(),
+            MoneyUnit::zero(Currency::JPY)
+        );
+        assert!(matches!(
+            amounts.iter().checked_sum(Currency::EUR),
+            Err(Error::Billing(BillingError::CurrencyMismatch))
+        ));
+        let overflowing = [MoneyUnit::from_cents(i64::MAX), MoneyUnit::from_cents(1)];
+        assert!(matches!(
+            overflowing.into_iter().checked_sum(Currency::USD),
+            Err(Error::Billing(BillingError::TotalCostExceededMaxValue))
+        ));
+
+        assert_eq!(
+            amounts.iter().sum::<Option<MoneyUnit>>(),
+            Some(MoneyUnit::from_cents(100))
+        );
+        assert_eq!(overflowing.into_iter().sum::<Option<MoneyUnit>>(), None);
+        assert_eq!(
+            std::iter::empty::<MoneyUnit>().sum::<Option<MoneyUnit>>(),
+            None
+        );
+
+        let eur = MoneyUnit::from_minor_units(5, Currency::EUR);
+        let totals = sum_by_currency(amounts.iter().chain([&eur, &eur])).unwrap();
+        assert_eq!(totals[&Currency::USD], MoneyUnit::from_cents(100));
+        assert_eq!(totals[&Currency::EUR].minor_units(), 10);
+    }
+
+    #[test]
+    fn subtracts_below_zero_only_when_allowed() {
+        let balance = MoneyUnit::from_cents(100);
+        let chargeback = MoneyUnit::from_cents(250);
+        assert!(matches!(
+            balance - chargeback,
+            Err(Error::Billing(BillingError::BalanceWouldBecomeNegative))
+        ));
+        assert_eq!(
+            balance.sub_allowing_negative(chargeback).unwrap(),
+            MoneyUnit::from_cents(-150)
+        );
+        assert!(matches!(
+            MoneyUnit::from_cents(i64::MIN).sub_allowing_negative(MoneyUnit::from_cents(1)),
+            Err(Error::Billing(BillingError::BalanceWouldUnderflow))
+        ));
     }
 }
@@ -0,0 +1,198 @@
+// Notifications of the events of the accounts to their owners: a balance running low, a
+// certificate expiring, an invoice issued, an incident of a provisioned service, the spending
+// alerts, see `alerts`, and the cost anomalies, see `anomalies`. They are delivered through the
+// `AlertChannel`s of `State::alert_channels`, each of which is a `NotificationChannel`, and every
+// user chooses which events are delivered on which channels with `set_preference`, or from a
+// guest with `host.set_notification_pref`. Every event is delivered on every channel by default.
+
+use std::{cmp::Ordering, collections::BTreeSet};
+
+use serde::Serialize;
+
+use crate::{ledger, money::MoneyUnit, trace, BillingError, Error, State, UserId};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NotificationConfig {
+    // The users whose balance is below this amount, in the same currency, are notified by the
+    // daily job, zero disables the notification
+    pub low_balance: MoneyUnit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEvent {
+    LowBalance,
+    // A certificate approaching its expiry or expired, see `certs`
+    Expiry,
+    // An invoice of a postpaid account, see `postpaid`
+    Invoice,
+    // A provisioned service failing its health checks, see `sla`
+    Incident,
+    SpendingAlert,
+    // An unusual spend, see `anomalies`
+    CostAnomaly,
+}
+
+impl NotificationEvent {
+    // The events by their codes in the host functions
+    pub const ALL: [NotificationEvent; 6] = [
+        NotificationEvent::LowBalance,
+        NotificationEvent::Expiry,
+        NotificationEvent::Invoice,
+        NotificationEvent::Incident,
+        NotificationEvent::SpendingAlert,
+        NotificationEvent::CostAnomaly,
+    ];
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        Self::ALL.get(usize::try_from(code).ok()?).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NotificationEvent::LowBalance => "low_balance",
+            NotificationEvent::Expiry => "expiry",
+            NotificationEvent::Invoice => "invoice",
+            NotificationEvent::Incident => "incident",
+            NotificationEvent::SpendingAlert => "spending_alert",
+            NotificationEvent::CostAnomaly => "cost_anomaly",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationChannel {
+    Log,
+    Email,
+    Webhook,
+}
+
+impl NotificationChannel {
+    // The channels by their codes in the host functions
+    pub const ALL: [NotificationChannel; 3] = [
+        NotificationChannel::Log,
+        NotificationChannel::Email,
+        NotificationChannel::Webhook,
+    ];
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        Self::ALL.get(usize::try_from(code).ok()?).copied()
+    }
+}
+
+// The events the user does not want delivered on the channels
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NotificationPreferences {
+    muted: BTreeSet<(NotificationEvent, NotificationChannel)>,
+}
+
+impl NotificationPreferences {
+    pub fn is_enabled(&self, event: NotificationEvent, channel: NotificationChannel) -> bool {
+        !self.muted.contains(&(event, channel))
+    }
+
+    pub fn set(&mut self, event: NotificationEvent, channel: NotificationChannel, enabled: bool) {
+        match enabled {
+            true => self.muted.remove(&(event, channel)),
+            false => self.muted.insert((event, channel)),
+        };
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Notification {
+    pub user: UserId,
+    pub event: NotificationEvent,
+    pub message: String,
+    // Seconds since the Unix epoch
+    pub at: u64,
+    // The trace of the invocation during which the event happened, see `trace`
+    pub trace_id: Option<String>,
+}
+
+pub fn preferences(state: &State, user: UserId) -> Result<NotificationPreferences, Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    Ok(user_data.notification_preferences.clone())
+}
+
+// Enables or disables the delivery of the event to the user on the channel
+pub fn set_preference(
+    state: &mut State,
+    user: UserId,
+    event: NotificationEvent,
+    channel: NotificationChannel,
+    enabled: bool,
+) -> Result<(), Error> {
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    user_data
+        .notification_preferences
+        .set(event, channel, enabled);
+    Ok(())
+}
+
+// See the `set_notification_pref` host function
+pub(crate) fn guest_set_preference(
+    state: &mut State,
+    user: UserId,
+    event: i32,
+    channel: i32,
+    enabled: i32,
+) -> Result<(), Error> {
+    let (Some(event), Some(channel), enabled @ (0 | 1)) = (
+        NotificationEvent::from_code(event),
+        NotificationChannel::from_code(channel),
+        enabled,
+    ) else {
+        return Err(BillingError::InvalidArgumentValue.into());
+    };
+    set_preference(state, user, event, channel, enabled == 1)
+}
+
+// Delivers the notification of the event to the user on the channels the user has not muted it
+// on. Failures of the channels are ignored.
+pub(crate) fn notify(state: &mut State, user: UserId, event: NotificationEvent, message: String) {
+    let Some(user_data) = state.users.get(&user) else {
+        return;
+    };
+    let notification = Notification {
+        user,
+        event,
+        message,
+        at: ledger::now_secs(),
+        trace_id: trace::current(),
+    };
+    let preferences = &user_data.notification_preferences;
+    for channel in state.alert_channels.iter_mut() {
+        if preferences.is_enabled(event, channel.channel()) {
+            let _ = channel.deliver(&notification);
+        }
+    }
+}
+
+// The daily job: notifies the users whose balance is below `NotificationConfig::low_balance`
+pub(crate) fn advance_day(state: &mut State) {
+    let threshold = state.config.notifications.low_balance;
+    if threshold.is_zero() {
+        return;
+    }
+    let low = state
+        .users
+        .read()
+        .iter()
+        .filter(|(_, user_data)| {
+            matches!(user_data.balance.checked_cmp(threshold), Ok(Ordering::Less))
+        })
+        .map(|(&user, user_data)| (user, user_data.balance))
+        .collect::<Vec<_>>();
+    for (user, balance) in low {
+        let message = format!(
+            "The balance of user {} is {balance}, below {threshold}.",
+            user.0
+        );
+        notify(state, user, NotificationEvent::LowBalance, message);
+    }
+}
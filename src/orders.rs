@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{disputes::DisputeStatus, ledger::EntryKind, BillingError, Error, State, UserId};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OrderId(pub u64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    Completed,
+    // A dispute against the order is open
+    Disputed,
+    Refunded,
+    // Cancelled by the user, see `cancellation`
+    Cancelled,
+}
+
+impl OrderStatus {
+    // The value returned by `host.order_status`
+    pub const fn code(self) -> i32 {
+        match self {
+            OrderStatus::Completed => 0,
+            OrderStatus::Disputed => 1,
+            OrderStatus::Refunded => 2,
+            OrderStatus::Cancelled => 3,
+        }
+    }
+}
+
+// A purchase of the user, i.e. a ledger entry for which `EntryKind::is_purchase` holds
+#[derive(Clone, Debug)]
+pub struct Order {
+    pub id: OrderId,
+    pub user: UserId,
+    // Index of the purchase in the user's ledger, e.g. to dispute it
+    pub entry: usize,
+    // Seconds since the Unix epoch
+    pub cancelled_at: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct Orders {
+    orders: BTreeMap<OrderId, Order>,
+    next_id: u64,
+}
+
+impl Orders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, id: OrderId) -> Option<&Order> {
+        self.orders.get(&id)
+    }
+
+    pub fn of_user(&self, user: UserId) -> impl Iterator<Item = &Order> {
+        self.orders.values().filter(move |order| order.user == user)
+    }
+
+    pub(crate) fn place(&mut self, user: UserId, entry: usize) -> OrderId {
+        let id = OrderId(self.next_id);
+        self.next_id += 1;
+        self.orders.insert(
+            id,
+            Order {
+                id,
+                user,
+                entry,
+                cancelled_at: None,
+            },
+        );
+        id
+    }
+
+    pub(crate) fn cancel(&mut self, id: OrderId, at: u64) {
+        if let Some(order) = self.orders.get_mut(&id) {
+            order.cancelled_at = Some(at);
+        }
+    }
+
+    // Replaces the orders of the user with ones copied from another host, see `migrate`. Fails
+    // if one of their ids is taken by an order of another user.
+    pub(crate) fn replace_user(&mut self, user: UserId, orders: Vec<Order>) -> Result<(), Error> {
+        let taken = orders.iter().any(|order| {
+            self.orders
+                .get(&order.id)
+                .is_some_and(|existing| existing.user != user)
+        });
+        if taken {
+            return Err(BillingError::InvalidArgumentValue.into());
+        }
+        self.orders.retain(|_, order| order.user != user);
+        for order in orders {
+            self.next_id = self.next_id.max(order.id.0 + 1);
+            self.orders.insert(order.id, order);
+        }
+        Ok(())
+    }
+}
+
+// An order of the user. Other users' orders are reported as unknown.
+pub fn get(state: &State, user: UserId, id: OrderId) -> Result<&Order, Error> {
+    match state.orders.get(id) {
+        Some(order) if order.user == user => Ok(order),
+        _ => Err(BillingError::UnknownOrder.into()),
+    }
+}
+
+// The services provisioned by the order that the user still has, i.e. the members of its
+// bundle, in the order of the bundle. Other purchases and cancelled orders provision no
+// services.
+pub fn services(state: &State, order: &Order) -> Result<Vec<String>, Error> {
+    let user_data = state
+        .users
+        .get(&order.user)
+        .ok_or(BillingError::UnknownUser)?;
+    let EntryKind::BundleOrder { bundle } = &user_data.ledger_entry(order.entry)?.kind else {
+        return Ok(Vec::new());
+    };
+    if order.cancelled_at.is_some() {
+        return Ok(Vec::new());
+    }
+    let members = state
+        .config
+        .catalog
+        .bundles
+        .get(bundle)
+        .map(|bundle| bundle.services.clone())
+        .unwrap_or_default();
+    Ok(members
+        .into_iter()
+        .filter(|service| user_data.services.contains(service))
+        .collect())
+}
+
+// The status follows the dispute of the order's ledger entry, if any
+pub fn status(state: &State, order: &Order) -> OrderStatus {
+    if order.cancelled_at.is_some() {
+        return OrderStatus::Cancelled;
+    }
+    let dispute = state
+        .disputes
+        .of_user(order.user)
+        .find(|dispute| dispute.entry == order.entry);
+    match dispute.map(|dispute| dispute.status) {
+        Some(DisputeStatus::Open) => OrderStatus::Disputed,
+        Some(DisputeStatus::Refunded) => OrderStatus::Refunded,
+        Some(DisputeStatus::Denied) | None => OrderStatus::Completed,
+    }
+}
+
+#[derive(Serialize)]
+struct OrderDetails {
+    id: u64,
+    kind: &'static str,
+    details: String,
+    // The charged amount, positive
+    minor_units: i64,
+    currency: &'static str,
+    at: u64,
+    // Index of the purchase in the user's ledger
+    entry: usize,
+    status: OrderStatus,
+}
+
+// The order as JSON for `host.order_details`, e.g.
+// `{"id":0,"kind":"hosting_order","details":"30 days","minor_units":3000,"currency":"USD",
+// "at":1700000000,"entry":0,"status":"completed"}`
+pub fn details_json(state: &State, order: &Order) -> Result<String, Error> {
+    let user_data = state
+        .users
+        .get(&order.user)
+        .ok_or(BillingError::UnknownUser)?;
+    let entry = user_data.ledger_entry(order.entry)?;
+    let amount = entry
+        .amount
+        .checked_neg()
+        .ok_or(BillingError::TotalCostExceededMaxValue)?;
+    let details = OrderDetails {
+        id: order.id.0,
+        kind: entry.kind.name(),
+        details: entry.kind.details(),
+        minor_units: amount.minor_units(),
+        currency: amount.currency().code(),
+        at: entry.at,
+        entry: order.entry,
+        status: status(state, order),
+    };
+    Ok(serde_json::to_string(&details).unwrap())
+}
@@ -0,0 +1,405 @@
+// The outbox of the webhook deliveries, so that the events survive the crashes of the host and
+// the outages of the webhooks. A `WebhookChannel` given the outbox, see
+// `WebhookChannel::with_outbox`, records every event in it before attempting the delivery, and
+// the deliveries that fail are attempted again by `Outbox::deliver_due`, e.g. every second by
+// the daemon, after a delay doubling with every failure, see `OutboxConfig`. A delivery failing
+// `OutboxConfig::max_attempts` times is dead-lettered: it is kept, but no longer attempted until
+// an operator redrives it, e.g. with `outbox <path> redrive <id>` of the CLI or
+// `{"op": "redrive", "id": 3}` to the daemon, once the webhook is fixed.
+//
+// The outbox opened with `Outbox::open` is a table of the deliveries kept in a JSON Lines
+// journal: every change of a delivery appends its row, synced to the disk before the change is
+// acknowledged, and the last row of a delivery wins. The journal is compacted when opened,
+// dropping the delivered rows but the one of the latest delivery, so that its id is not reused.
+// The deliveries are at least once: a crash between a delivery and its row, or two hosts sharing
+// the journal, repeat it, so the webhooks should tolerate duplicates.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{alerts::WebhookChannel, ledger, BillingError, Error, HostError};
+
+#[derive(Clone, Copy, Debug)]
+pub struct OutboxConfig {
+    // The attempts of a delivery, including the first one, before it is dead-lettered
+    pub max_attempts: u32,
+    // The delay after the first failure, doubled for every further one
+    pub base_delay_secs: u64,
+    pub max_delay_secs: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            base_delay_secs: 30,
+            max_delay_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct DeliveryId(pub u64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    // Failed `OutboxConfig::max_attempts` times, waiting to be redriven
+    Dead,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: DeliveryId,
+    pub url: String,
+    // The JSON of the event
+    pub body: String,
+    // Seconds since the Unix epoch
+    pub created_at: u64,
+    // The failed attempts since the delivery was created or redriven
+    pub attempts: u32,
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+    pub status: DeliveryStatus,
+}
+
+// What a round of `Outbox::deliver_due` did
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeliveryRound {
+    pub delivered: usize,
+    // Failed and to be attempted again
+    pub retried: usize,
+    // Failed for the last time
+    pub dead_lettered: usize,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    // The pending and the dead-lettered deliveries
+    deliveries: BTreeMap<DeliveryId, Delivery>,
+    next_id: u64,
+    // `None` for the outboxes kept in memory only
+    journal: Option<File>,
+}
+
+fn persistence_error(e: impl ToString) -> Error {
+    HostError::Persistence(e.to_string()).into()
+}
+
+impl Table {
+    // Journals the row of the delivery, then applies it
+    fn write(&mut self, delivery: Delivery) -> Result<(), Error> {
+        if let Some(journal) = &mut self.journal {
+            let mut row = serde_json::to_string(&delivery).map_err(persistence_error)?;
+            row.push('\n');
+            journal
+                .write_all(row.as_bytes())
+                .and_then(|_| journal.sync_data())
+                .map_err(persistence_error)?;
+        }
+        match delivery.status {
+            DeliveryStatus::Delivered => self.deliveries.remove(&delivery.id),
+            _ => self.deliveries.insert(delivery.id, delivery),
+        };
+        Ok(())
+    }
+}
+
+// The outbox is cheap to clone and can be shared with the channels and the threads delivering
+// the events
+#[derive(Clone, Debug, Default)]
+pub struct Outbox {
+    config: OutboxConfig,
+    table: Arc<Mutex<Table>>,
+}
+
+impl Outbox {
+    // An outbox kept in memory only, whose deliveries are lost with the host
+    pub fn new(config: OutboxConfig) -> Self {
+        Self {
+            config,
+            table: Arc::default(),
+        }
+    }
+
+    // Opens the journal at the path, creating it if needed, with the deliveries left in it by a
+    // previous instance
+    pub fn open(path: impl AsRef<Path>, config: OutboxConfig) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut table = Table::default();
+        // The last row of the latest delivery, kept as the high-water mark of the ids
+        let mut latest = None::<Delivery>;
+        if let Ok(file) = File::open(path) {
+            // A row torn by a crash is the last line and was never acknowledged
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(persistence_error)?;
+                let Ok(delivery) = serde_json::from_str::<Delivery>(&line) else {
+                    break;
+                };
+                table.next_id = table.next_id.max(delivery.id.0 + 1);
+                if latest
+                    .as_ref()
+                    .is_none_or(|latest| latest.id <= delivery.id)
+                {
+                    latest = Some(delivery.clone());
+                }
+                table.write(delivery)?;
+            }
+        }
+        // The compacted journal is written aside and renamed over the old one, so that a crash
+        // leaves one of them intact
+        let mut aside = path.to_owned().into_os_string();
+        aside.push(".tmp");
+        let aside = PathBuf::from(aside);
+        let mut rows = String::new();
+        let delivered = latest.filter(|latest| !table.deliveries.contains_key(&latest.id));
+        for delivery in delivered.iter().chain(table.deliveries.values()) {
+            rows.push_str(&serde_json::to_string(delivery).map_err(persistence_error)?);
+            rows.push('\n');
+        }
+        let mut file = File::create(&aside).map_err(persistence_error)?;
+        file.write_all(rows.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(persistence_error)?;
+        fs::rename(&aside, path).map_err(persistence_error)?;
+        table.journal = Some(
+            OpenOptions::new()
+                .append(true)
+                .open(path)
+                .map_err(persistence_error)?,
+        );
+        Ok(Self {
+            config,
+            table: Arc::new(Mutex::new(table)),
+        })
+    }
+
+    // Records the delivery of the body to the webhook, due right away
+    pub fn enqueue(&self, url: &str, body: String) -> Result<DeliveryId, Error> {
+        let mut table = self.table.lock().unwrap();
+        let id = DeliveryId(table.next_id);
+        let now = ledger::now_secs();
+        table.write(Delivery {
+            id,
+            url: url.to_owned(),
+            body,
+            created_at: now,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            status: DeliveryStatus::Pending,
+        })?;
+        table.next_id += 1;
+        Ok(id)
+    }
+
+    // The pending and the dead-lettered deliveries, oldest first
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        let table = self.table.lock().unwrap();
+        table.deliveries.values().cloned().collect()
+    }
+
+    pub fn delivery(&self, id: DeliveryId) -> Option<Delivery> {
+        self.table.lock().unwrap().deliveries.get(&id).cloned()
+    }
+
+    pub fn dead_lettered(&self) -> Vec<Delivery> {
+        let mut deliveries = self.deliveries();
+        deliveries.retain(|delivery| delivery.status == DeliveryStatus::Dead);
+        deliveries
+    }
+
+    // Makes the dead-lettered delivery due right away with all its attempts
+    pub fn redrive(&self, id: DeliveryId) -> Result<(), Error> {
+        let mut table = self.table.lock().unwrap();
+        let delivery = table
+            .deliveries
+            .get(&id)
+            .filter(|delivery| delivery.status == DeliveryStatus::Dead)
+            .ok_or(BillingError::UnknownDelivery)?
+            .clone();
+        table.write(Delivery {
+            attempts: 0,
+            next_attempt_at: ledger::now_secs(),
+            status: DeliveryStatus::Pending,
+            ..delivery
+        })
+    }
+
+    // Redrives every dead-lettered delivery, returning their number
+    pub fn redrive_all(&self) -> Result<usize, Error> {
+        let dead = self.dead_lettered();
+        for delivery in &dead {
+            self.redrive(delivery.id)?;
+        }
+        Ok(dead.len())
+    }
+
+    // Attempts the pending deliveries that are due. The webhooks are called without holding the
+    // outbox, so the channels may keep enqueuing meanwhile.
+    pub fn deliver_due(&self) -> Result<DeliveryRound, Error> {
+        let now = ledger::now_secs();
+        let due = self
+            .deliveries()
+            .into_iter()
+            .filter(|delivery| {
+                delivery.status == DeliveryStatus::Pending && delivery.next_attempt_at <= now
+            })
+            .collect::<Vec<_>>();
+        let mut round = DeliveryRound::default();
+        for delivery in due {
+            match self.attempt(delivery)? {
+                DeliveryStatus::Delivered => round.delivered += 1,
+                DeliveryStatus::Pending => round.retried += 1,
+                DeliveryStatus::Dead => round.dead_lettered += 1,
+            }
+        }
+        Ok(round)
+    }
+
+    // Posts the delivery and records the outcome, returning the new status
+    pub(crate) fn attempt(&self, delivery: Delivery) -> Result<DeliveryStatus, Error> {
+        let posted =
+            WebhookChannel::new(&delivery.url).and_then(|webhook| webhook.post(&delivery.body));
+        let mut table = self.table.lock().unwrap();
+        // Redriven or delivered by another round meanwhile
+        if table.deliveries.get(&delivery.id) != Some(&delivery) {
+            return Ok(delivery.status);
+        }
+        let delivery = match posted {
+            Ok(()) => Delivery {
+                status: DeliveryStatus::Delivered,
+                ..delivery
+            },
+            Err(e) => {
+                let attempts = delivery.attempts + 1;
+                let delay = self
+                    .config
+                    .base_delay_secs
+                    .saturating_mul(1 << (attempts - 1).min(32))
+                    .min(self.config.max_delay_secs);
+                Delivery {
+                    attempts,
+                    next_attempt_at: ledger::now_secs().saturating_add(delay),
+                    last_error: Some(e.to_string()),
+                    status: match attempts >= self.config.max_attempts {
+                        true => DeliveryStatus::Dead,
+                        false => DeliveryStatus::Pending,
+                    },
+                    ..delivery
+                }
+            }
+        };
+        let status = delivery.status;
+        table.write(delivery)?;
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::TcpListener,
+        path::PathBuf,
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+
+    // Nothing listens on the port
+    const UNREACHABLE: &str = "http://127.0.0.1:1/hook";
+
+    fn journal(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("outbox-{name}-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    // A webhook answering the requests with 200, returning its URL
+    fn webhook(requests: usize) -> (String, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .unwrap();
+            }
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn ids_are_not_reused_after_compaction() {
+        let path = journal("ids");
+        let (url, server) = webhook(1);
+        let outbox = Outbox::open(&path, OutboxConfig::default()).unwrap();
+        assert_eq!(
+            outbox.enqueue(&url, "{}".to_owned()).unwrap(),
+            DeliveryId(0)
+        );
+        assert_eq!(outbox.deliver_due().unwrap().delivered, 1);
+        server.join().unwrap();
+        drop(outbox);
+
+        let outbox = Outbox::open(&path, OutboxConfig::default()).unwrap();
+        assert!(outbox.deliveries().is_empty());
+        drop(outbox);
+        // Compacted twice, the high-water mark is still there
+        let outbox = Outbox::open(&path, OutboxConfig::default()).unwrap();
+        let id = outbox.enqueue(UNREACHABLE, "{}".to_owned()).unwrap();
+        assert_eq!(id, DeliveryId(1));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pending_deliveries_survive_a_restart() {
+        let path = journal("pending");
+        let outbox = Outbox::open(&path, OutboxConfig::default()).unwrap();
+        let id = outbox.enqueue(UNREACHABLE, "{\"a\":1}".to_owned()).unwrap();
+        drop(outbox);
+
+        let outbox = Outbox::open(&path, OutboxConfig::default()).unwrap();
+        let delivery = outbox.delivery(id).unwrap();
+        assert_eq!(delivery.body, "{\"a\":1}");
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_deliveries_are_retried_then_dead_lettered() {
+        let config = OutboxConfig {
+            max_attempts: 2,
+            base_delay_secs: 0,
+            max_delay_secs: 0,
+        };
+        let outbox = Outbox::new(config);
+        let id = outbox.enqueue(UNREACHABLE, "{}".to_owned()).unwrap();
+        assert_eq!(outbox.deliver_due().unwrap().retried, 1);
+        let delivery = outbox.delivery(id).unwrap();
+        assert_eq!(delivery.attempts, 1);
+        assert!(delivery.last_error.is_some());
+        assert_eq!(outbox.deliver_due().unwrap().dead_lettered, 1);
+        assert_eq!(outbox.dead_lettered().len(), 1);
+        // Dead-lettered deliveries are no longer attempted
+        assert_eq!(outbox.deliver_due().unwrap(), DeliveryRound::default());
+
+        outbox.redrive(id).unwrap();
+        let delivery = outbox.delivery(id).unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert_eq!(delivery.attempts, 0);
+    }
+}
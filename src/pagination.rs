@@ -0,0 +1,98 @@
+// The pagination of the lists returned to the guests, e.g. `host.ledger_page` and
+// `host.orders_page`. A guest pages through a list with a cursor, starting from 0:
+//
+// ```text
+// cursor = 0
+// loop {
+//     next = host.ledger_page(cursor, buf, len)
+//     read the lines of `buf` up to the first empty one
+//     if next == cursor { break }  // the end of the list, for now
+//     cursor = next
+// }
+// ```
+//
+// Every call writes as many whole items of the list as fit into the buffer as JSON Lines,
+// oldest first, followed by an empty line, and returns the cursor of the next page, or the
+// negated error code. A page with no items returns its own cursor; calling again later returns
+// the items added since, e.g. to follow the ledger. A buffer too small for the next item fails
+// with `BillingError::PageBufferTooSmall`, and a larger one may be passed with the same cursor.
+//
+// The cursors are positions in lists that only grow at their ends, so the pages of a list
+// never skip nor repeat items while the list changes: the cursor of the ledger is the index of
+// an entry, counting the archived ones, see `archive`, which are not paged, and the cursor of
+// the orders is the id of an order. The items themselves may change between the pages, e.g.
+// the status of an order.
+
+use crate::{
+    ledger::LedgerRow,
+    orders::{self, OrderId},
+    BillingError, Error, State, UserId,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Page {
+    // The cursor of the next page
+    pub cursor: u64,
+    // The items as JSON Lines followed by an empty line
+    pub body: Vec<u8>,
+}
+
+// The page of the items, given with the cursors following them, that fits into `len` bytes
+fn fill(
+    cursor: u64,
+    items: impl Iterator<Item = Result<(u64, String), Error>>,
+    len: usize,
+) -> Result<Page, Error> {
+    let mut page = Page {
+        cursor,
+        body: Vec::new(),
+    };
+    for item in items {
+        let (next, item) = item?;
+        // The item, its newline and the empty line ending the page
+        if page.body.len() + item.len() + 2 > len {
+            if page.body.is_empty() {
+                return Err(BillingError::PageBufferTooSmall.into());
+            }
+            break;
+        }
+        page.body.extend_from_slice(item.as_bytes());
+        page.body.push(b'\n');
+        page.cursor = next;
+    }
+    if page.body.len() + 1 > len {
+        return Err(BillingError::PageBufferTooSmall.into());
+    }
+    page.body.push(b'\n');
+    Ok(page)
+}
+
+// The entries of the ledger of the user from the cursor as `LedgerRow`s
+pub fn ledger_page(state: &State, user: UserId, cursor: u64, len: usize) -> Result<Page, Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let archived = user_data.archived_entries as u64;
+    let cursor = cursor.max(archived);
+    let start = usize::try_from(cursor - archived).unwrap_or(usize::MAX);
+    let entries = user_data.ledger.iter().enumerate().skip(start);
+    let items = entries.map(|(i, entry)| {
+        let row = LedgerRow::new(user, entry);
+        Ok((
+            archived + i as u64 + 1,
+            serde_json::to_string(&row).unwrap(),
+        ))
+    });
+    fill(cursor, items, len)
+}
+
+// The orders of the user from the cursor as the JSON of `host.order_details`, but for the
+// orders whose purchases have been archived
+pub fn orders_page(state: &State, user: UserId, cursor: u64, len: usize) -> Result<Page, Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let items = state
+        .orders
+        .of_user(user)
+        .skip_while(|order| order.id < OrderId(cursor))
+        .filter(|order| order.entry >= user_data.archived_entries)
+        .map(|order| Ok((order.id.0 + 1, orders::details_json(state, order)?)));
+    fill(cursor, items, len)
+}
@@ -0,0 +1,590 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use postgres::{error::SqlState, Client, NoTls, Transaction};
+use rand::Rng;
+
+use crate::{
+    balance_cache::{WriteBehindCache, WriteBehindConfig},
+    migrate::AccountRecord,
+    money::{Currency, MoneyUnit},
+    BillingError, Error, HostError, UserId,
+};
+
+// How the balance updates of concurrent host instances are serialized
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockStrategy {
+    // `SELECT ... FOR UPDATE` on the rows of the balances
+    #[default]
+    RowLock,
+    // Transaction-level advisory locks keyed by the user ids, which also serialize
+    // the updates of users whose rows do not exist yet
+    AdvisoryLock,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LockConfig {
+    pub strategy: LockStrategy,
+    // Attempts of a transaction that fails on a deadlock, a serialization failure
+    // or a lock timeout, including the first one
+    pub max_attempts: u32,
+    // The delay before the first retry, doubled for every further one and jittered
+    pub base_delay: Duration,
+    // How long a transaction waits for a lock before it is retried
+    pub lock_timeout: Duration,
+}
+
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self {
+            strategy: LockStrategy::RowLock,
+            max_attempts: 5,
+            base_delay: Duration::from_millis(10),
+            lock_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+// Why a transaction failed
+enum TxError {
+    Postgres(postgres::Error),
+    Rejected(Error),
+}
+
+impl From<postgres::Error> for TxError {
+    fn from(e: postgres::Error) -> Self {
+        TxError::Postgres(e)
+    }
+}
+
+impl From<Error> for TxError {
+    fn from(e: Error) -> Self {
+        TxError::Rejected(e)
+    }
+}
+
+fn persistence_error(e: impl ToString) -> Error {
+    HostError::Persistence(e.to_string()).into()
+}
+
+// The failures that are resolved by running the transaction again. Postgres detects
+// deadlocks itself and aborts one of the transactions involved.
+fn is_retryable(e: &postgres::Error) -> bool {
+    e.code().is_some_and(|code| {
+        [
+            SqlState::T_R_DEADLOCK_DETECTED,
+            SqlState::T_R_SERIALIZATION_FAILURE,
+            SqlState::LOCK_NOT_AVAILABLE,
+        ]
+        .contains(code)
+    })
+}
+
+// Balances kept in a Postgres database shared by several host instances.
+// Every update runs in a transaction holding the lock of each user involved,
+// so that concurrent charges to one user cannot overdraw the balance.
+pub struct PgBalanceStore {
+    client: Client,
+    config: LockConfig,
+    retries: u64,
+}
+
+impl PgBalanceStore {
+    pub fn connect(url: &str, config: LockConfig) -> Result<Self, Error> {
+        let client = Client::connect(url, NoTls).map_err(persistence_error)?;
+        Ok(Self {
+            client,
+            config,
+            retries: 0,
+        })
+    }
+
+    pub fn create_schema(&mut self) -> Result<(), Error> {
+        self.client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS wsm_balances (
+                    user_id BIGINT PRIMARY KEY,
+                    minor BIGINT NOT NULL,
+                    currency TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS wsm_accounts (
+                    user_id BIGINT PRIMARY KEY,
+                    record TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS wsm_audit_logs (
+                    name TEXT PRIMARY KEY,
+                    jsonl BYTEA NOT NULL
+                );",
+            )
+            .map_err(persistence_error)
+    }
+
+    // Serves the balances from memory and writes the updates in batches, for the guests that
+    // check their balances on every call, see `balance_cache`
+    pub fn write_behind(self, config: WriteBehindConfig) -> Result<WriteBehindCache<Self>, Error> {
+        WriteBehindCache::open(self, config)
+    }
+
+    // Transactions run again after a retryable failure, see `LockConfig::max_attempts`
+    pub fn retries(&self) -> u64 {
+        self.retries
+    }
+
+    fn in_transaction<T>(
+        &mut self,
+        mut op: impl FnMut(&mut Transaction<'_>, LockStrategy) -> Result<T, TxError>,
+    ) -> Result<T, Error> {
+        let config = self.config;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match run_transaction(&mut self.client, &config, &mut op) {
+                Ok(value) => return Ok(value),
+                Err(TxError::Rejected(e)) => return Err(e),
+                Err(TxError::Postgres(e)) if is_retryable(&e) => {
+                    if attempt >= config.max_attempts {
+                        return Err(HostError::LockContention.into());
+                    }
+                    self.retries += 1;
+                    let delay = config.base_delay * 2u32.pow((attempt - 1).min(16));
+                    std::thread::sleep(delay.mul_f64(rand::thread_rng().gen_range(0.5..1.5)));
+                }
+                Err(TxError::Postgres(e)) => return Err(persistence_error(e)),
+            }
+        }
+    }
+
+    pub fn insert_user(&mut self, user: UserId, balance: MoneyUnit) -> Result<(), Error> {
+        self.in_transaction(|transaction, _| {
+            transaction.execute(
+                "INSERT INTO wsm_balances (user_id, minor, currency) VALUES ($1, $2, $3)",
+                &[
+                    &(user.0 as i64),
+                    &balance.minor_units(),
+                    &balance.currency().code(),
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn balance(&mut self, user: UserId) -> Result<MoneyUnit, Error> {
+        self.in_transaction(|transaction, _| read_balance(transaction, user, ""))
+    }
+
+    // The ids of the users with balances, in ascending order
+    pub fn users(&mut self) -> Result<Vec<UserId>, Error> {
+        self.in_transaction(|transaction, _| {
+            let rows =
+                transaction.query("SELECT user_id FROM wsm_balances ORDER BY user_id", &[])?;
+            Ok(rows
+                .iter()
+                .map(|row| UserId(row.get::<_, i64>(0) as usize))
+                .collect())
+        })
+    }
+
+    // Creates the user or overwrites the balance of the existing one, e.g. when migrating
+    // the accounts, see `migrate`
+    pub fn put_balance(&mut self, user: UserId, balance: MoneyUnit) -> Result<(), Error> {
+        self.in_transaction(|transaction, _| {
+            transaction.execute(
+                "INSERT INTO wsm_balances (user_id, minor, currency) VALUES ($1, $2, $3)
+                ON CONFLICT (user_id) DO UPDATE SET minor = $2, currency = $3",
+                &[
+                    &(user.0 as i64),
+                    &balance.minor_units(),
+                    &balance.currency().code(),
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    // The account of the user as JSON, see `migrate::AccountRecord`, with the balance of the
+    // balance table. Users only known by their balances have the account of a new user.
+    pub fn account(&mut self, user: UserId) -> Result<AccountRecord, Error> {
+        self.in_transaction(|transaction, _| {
+            let balance = read_balance(transaction, user, "")?;
+            let row = transaction.query_opt(
+                "SELECT record FROM wsm_accounts WHERE user_id = $1",
+                &[&(user.0 as i64)],
+            )?;
+            let account = match row {
+                Some(row) => serde_json::from_str(row.get(0)).map_err(persistence_error)?,
+                None => AccountRecord::new(balance),
+            };
+            Ok(AccountRecord { balance, ..account })
+        })
+    }
+
+    // Creates the user or overwrites the account of the existing one, see `migrate`
+    pub fn put_account(&mut self, user: UserId, account: &AccountRecord) -> Result<(), Error> {
+        let record = serde_json::to_string(account).map_err(persistence_error)?;
+        self.in_transaction(|transaction, _| {
+            let balance = account.balance;
+            transaction.execute(
+                "INSERT INTO wsm_balances (user_id, minor, currency) VALUES ($1, $2, $3)
+                ON CONFLICT (user_id) DO UPDATE SET minor = $2, currency = $3",
+                &[
+                    &(user.0 as i64),
+                    &balance.minor_units(),
+                    &balance.currency().code(),
+                ],
+            )?;
+            transaction.execute(
+                "INSERT INTO wsm_accounts (user_id, record) VALUES ($1, $2)
+                ON CONFLICT (user_id) DO UPDATE SET record = $2",
+                &[&(user.0 as i64), &record],
+            )?;
+            Ok(())
+        })
+    }
+
+    // The audit logs by their names, exported as JSON Lines, see `migrate`
+    pub fn audit_logs(&mut self) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+        self.in_transaction(|transaction, _| {
+            let rows = transaction.query("SELECT name, jsonl FROM wsm_audit_logs", &[])?;
+            Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+        })
+    }
+
+    pub fn put_audit_log(&mut self, name: &str, jsonl: &[u8]) -> Result<(), Error> {
+        self.in_transaction(|transaction, _| {
+            transaction.execute(
+                "INSERT INTO wsm_audit_logs (name, jsonl) VALUES ($1, $2)
+                ON CONFLICT (name) DO UPDATE SET jsonl = $2",
+                &[&name, &jsonl],
+            )?;
+            Ok(())
+        })
+    }
+
+    // Debits the amount, failing if the balance would become negative.
+    // Returns the new balance.
+    pub fn charge(&mut self, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error> {
+        self.in_transaction(|transaction, strategy| {
+            let balance = (lock_balance(transaction, strategy, user)? - amount)?;
+            write_balance(transaction, user, balance)?;
+            Ok(balance)
+        })
+    }
+
+    pub fn credit(&mut self, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error> {
+        self.in_transaction(|transaction, strategy| {
+            let balance = (lock_balance(transaction, strategy, user)? + amount)?;
+            write_balance(transaction, user, balance)?;
+            Ok(balance)
+        })
+    }
+
+    // Moves the amount between the users. The users are locked in the order of their ids,
+    // so that opposite transfers between two users do not deadlock each other.
+    pub fn transfer(&mut self, from: UserId, to: UserId, amount: MoneyUnit) -> Result<(), Error> {
+        if from == to {
+            return Err(BillingError::InvalidArgumentValue.into());
+        }
+        self.in_transaction(|transaction, strategy| {
+            let (first, second) = (from.min(to), from.max(to));
+            let first_balance = lock_balance(transaction, strategy, first)?;
+            let second_balance = lock_balance(transaction, strategy, second)?;
+            let (from_balance, to_balance) = if first == from {
+                (first_balance, second_balance)
+            } else {
+                (second_balance, first_balance)
+            };
+            write_balance(transaction, from, (from_balance - amount)?)?;
+            write_balance(transaction, to, (to_balance + amount)?)?;
+            Ok(())
+        })
+    }
+}
+
+fn run_transaction<T>(
+    client: &mut Client,
+    config: &LockConfig,
+    op: &mut impl FnMut(&mut Transaction<'_>, LockStrategy) -> Result<T, TxError>,
+) -> Result<T, TxError> {
+    let mut transaction = client.transaction()?;
+    transaction.batch_execute(&format!(
+        "SET LOCAL lock_timeout = {}",
+        config.lock_timeout.as_millis()
+    ))?;
+    let value = op(&mut transaction, config.strategy)?;
+    transaction.commit()?;
+    Ok(value)
+}
+
+fn read_balance(
+    transaction: &mut Transaction<'_>,
+    user: UserId,
+    locking_clause: &str,
+) -> Result<MoneyUnit, TxError> {
+    let row = transaction
+        .query_opt(
+            &format!(
+                "SELECT minor, currency FROM wsm_balances WHERE user_id = $1 {locking_clause}"
+            ),
+            &[&(user.0 as i64)],
+        )?
+        .ok_or(Error::from(BillingError::UnknownUser))?;
+    let currency =
+        Currency::from_code(row.get(1)).ok_or(Error::from(BillingError::InvalidArgumentValue))?;
+    Ok(MoneyUnit::from_minor_units(row.get(0), currency))
+}
+
+fn lock_balance(
+    transaction: &mut Transaction<'_>,
+    strategy: LockStrategy,
+    user: UserId,
+) -> Result<MoneyUnit, TxError> {
+    match strategy {
+        LockStrategy::RowLock => read_balance(transaction, user, "FOR UPDATE"),
+        LockStrategy::AdvisoryLock => {
+            transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&(user.0 as i64)])?;
+            read_balance(transaction, user, "")
+        }
+    }
+}
+
+fn write_balance(
+    transaction: &mut Transaction<'_>,
+    user: UserId,
+    balance: MoneyUnit,
+) -> Result<(), TxError> {
+    transaction.execute(
+        "UPDATE wsm_balances SET minor = $2 WHERE user_id = $1",
+        &[&(user.0 as i64), &balance.minor_units()],
+    )?;
+    Ok(())
+}
+
+// These tests need a Postgres server, so they are ignored unless asked for, e.g.
+// `WSM_TEST_POSTGRES_URL=postgres://postgres@localhost cargo test --features postgres --
+// --ignored`, as the `postgres` job of the CI does.
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier, Mutex};
+
+    use super::*;
+
+    // Serializes the creation of the schema, which is not safe to run concurrently
+    static SCHEMA: Mutex<()> = Mutex::new(());
+
+    fn connect(config: LockConfig) -> PgBalanceStore {
+        let url = std::env::var("WSM_TEST_POSTGRES_URL").expect("WSM_TEST_POSTGRES_URL is not set");
+        let mut store = PgBalanceStore::connect(&url, config).unwrap();
+        let _guard = SCHEMA.lock().unwrap();
+        store.create_schema().unwrap();
+        store
+    }
+
+    // Users of distinct tests do not collide even if the table is left over from previous runs
+    fn fresh_user(store: &mut PgBalanceStore, balance: MoneyUnit) -> UserId {
+        let user = UserId(rand::thread_rng().gen_range(1..i64::MAX as usize));
+        store.insert_user(user, balance).unwrap();
+        user
+    }
+
+    fn concurrent_charges(strategy: LockStrategy) {
+        const THREADS: usize = 8;
+        const CHARGES_PER_THREAD: usize = 25;
+        let config = LockConfig {
+            strategy,
+            ..Default::default()
+        };
+        let mut store = connect(config);
+        // Enough for 150 of the 200 charges
+        let user = fresh_user(&mut store, MoneyUnit::from_cents(150));
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let threads = (0..THREADS)
+            .map(|_| {
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let mut store = connect(config);
+                    barrier.wait();
+                    (0..CHARGES_PER_THREAD)
+                        .filter(|_| store.charge(user, MoneyUnit::from_cents(1)).is_ok())
+                        .count()
+                })
+            })
+            .collect::<Vec<_>>();
+        let succeeded = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .sum::<usize>();
+        assert_eq!(succeeded, 150);
+        assert_eq!(store.balance(user).unwrap(), MoneyUnit::from_cents(0));
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres server at WSM_TEST_POSTGRES_URL"]
+    fn concurrent_charges_with_row_locks() {
+        concurrent_charges(LockStrategy::RowLock);
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres server at WSM_TEST_POSTGRES_URL"]
+    fn concurrent_charges_with_advisory_locks() {
+        concurrent_charges(LockStrategy::AdvisoryLock);
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres server at WSM_TEST_POSTGRES_URL"]
+    fn opposite_transfers_keep_the_total() {
+        let mut store = connect(LockConfig::default());
+        let a = fresh_user(&mut store, MoneyUnit::from_cents(100));
+        let b = fresh_user(&mut store, MoneyUnit::from_cents(100));
+        let threads = [(a, b), (b, a)].map(|(from, to)| {
+            std::thread::spawn(move || {
+                let mut store = connect(LockConfig::default());
+                for _ in 0..100 {
+                    let _ = store.transfer(from, to, MoneyUnit::from_cents(3));
+                }
+            })
+        });
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let total = (store.balance(a).unwrap() + store.balance(b).unwrap()).unwrap();
+        assert_eq!(total, MoneyUnit::from_cents(200));
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres server at WSM_TEST_POSTGRES_URL"]
+    fn lock_timeouts_are_retried() {
+        let config = LockConfig {
+            lock_timeout: Duration::from_millis(50),
+            max_attempts: 20,
+            ..Default::default()
+        };
+        let mut store = connect(config);
+        let user = fresh_user(&mut store, MoneyUnit::from_cents(10));
+
+        // Another host holds the lock of the user for a while
+        let mut other = connect(config);
+        let barrier = Arc::new(Barrier::new(2));
+        let holder = {
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let mut transaction = other.client.transaction().unwrap();
+                read_balance(&mut transaction, user, "FOR UPDATE")
+                    .ok()
+                    .unwrap();
+                barrier.wait();
+                std::thread::sleep(Duration::from_millis(200));
+                transaction.commit().unwrap();
+            })
+        };
+        barrier.wait();
+        assert_eq!(
+            store.charge(user, MoneyUnit::from_cents(1)).unwrap(),
+            MoneyUnit::from_cents(9)
+        );
+        assert!(store.retries() > 0);
+        holder.join().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres server at WSM_TEST_POSTGRES_URL"]
+    fn deadlocks_are_retried() {
+        let config = LockConfig {
+            // Longer than the deadlock detection of Postgres, `deadlock_timeout`
+            lock_timeout: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let mut store = connect(config);
+        let a = fresh_user(&mut store, MoneyUnit::from_cents(10));
+        let b = fresh_user(&mut store, MoneyUnit::from_cents(10));
+        let (first, second) = (a.min(b), a.max(b));
+
+        // Another host locks the users in the opposite order
+        let mut other = connect(config);
+        let barrier = Arc::new(Barrier::new(2));
+        let other_host = {
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let mut transaction = other.client.transaction().unwrap();
+                read_balance(&mut transaction, second, "FOR UPDATE")
+                    .ok()
+                    .unwrap();
+                barrier.wait();
+                std::thread::sleep(Duration::from_millis(200));
+                // Either this transaction or the transfer is aborted as the deadlock victim
+                if read_balance(&mut transaction, first, "FOR UPDATE").is_ok() {
+                    transaction.commit().unwrap();
+                }
+            })
+        };
+        barrier.wait();
+        store
+            .transfer(first, second, MoneyUnit::from_cents(5))
+            .unwrap();
+        other_host.join().unwrap();
+        assert_eq!(store.balance(first).unwrap(), MoneyUnit::from_cents(5));
+        assert_eq!(store.balance(second).unwrap(), MoneyUnit::from_cents(15));
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres server at WSM_TEST_POSTGRES_URL"]
+    fn exhausted_retries_are_reported() {
+        let config = LockConfig {
+            lock_timeout: Duration::from_millis(10),
+            max_attempts: 2,
+            ..Default::default()
+        };
+        let mut store = connect(config);
+        let user = fresh_user(&mut store, MoneyUnit::from_cents(10));
+        let mut other = connect(config);
+        let mut transaction = other.client.transaction().unwrap();
+        read_balance(&mut transaction, user, "FOR UPDATE")
+            .ok()
+            .unwrap();
+        assert!(matches!(
+            store.charge(user, MoneyUnit::from_cents(1)),
+            Err(Error::Host(HostError::LockContention))
+        ));
+        transaction.rollback().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres server at WSM_TEST_POSTGRES_URL"]
+    fn accounts_keep_the_balance_of_the_balance_table() {
+        let mut store = connect(LockConfig::default());
+        let user = fresh_user(&mut store, MoneyUnit::from_cents(10));
+        assert_eq!(
+            store.account(user).unwrap(),
+            AccountRecord::new(MoneyUnit::from_cents(10))
+        );
+        let mut account = AccountRecord::new(MoneyUnit::from_cents(20));
+        account.subscriptions.insert("web".to_owned());
+        store.put_account(user, &account).unwrap();
+        store.charge(user, MoneyUnit::from_cents(5)).unwrap();
+        assert_eq!(
+            store.account(user).unwrap(),
+            AccountRecord {
+                balance: MoneyUnit::from_cents(15),
+                ..account
+            }
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres server at WSM_TEST_POSTGRES_URL"]
+    fn cached_updates_are_flushed_to_the_database() {
+        let mut store = connect(LockConfig::default());
+        let user = fresh_user(&mut store, MoneyUnit::from_cents(100));
+        let config = WriteBehindConfig {
+            flush_interval: Duration::from_secs(3600),
+            wal_path: std::env::temp_dir().join(format!("pg-cache-{}.wal", user.0)),
+            sync_wal: true,
+        };
+        let wal_path = config.wal_path.clone();
+        let mut cache = connect(LockConfig::default()).write_behind(config).unwrap();
+        cache.charge(user, MoneyUnit::from_cents(30)).unwrap();
+        assert_eq!(store.balance(user).unwrap(), MoneyUnit::from_cents(100));
+        cache.flush().unwrap();
+        assert_eq!(store.balance(user).unwrap(), MoneyUnit::from_cents(70));
+        drop(cache);
+        std::fs::remove_file(wal_path).unwrap();
+    }
+}
@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{money::MoneyUnit, UserId};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Plan {
+    // Hosting is free while the trial lasts
+    Trial { days_left: u32 },
+    Paid,
+    // The paid hosting days have run out, see `GraceConfig`
+    Grace { days_left: u32 },
+    Suspended,
+}
+
+// What happens to the account once the trial is over
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrialEnd {
+    #[default]
+    ConvertToPaid,
+    // Accounts without any paid hosting days get suspended
+    Suspend,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrialConfig {
+    // Number of free hosting days new users get, 0 disables trials
+    pub days: u32,
+    pub on_end: TrialEnd,
+}
+
+// What happens to paid accounts once their hosting days have run out. During the grace
+// period the services keep working, but hosting orders are charged a late fee.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GraceConfig {
+    // Days before the account gets suspended, `None` never suspends it
+    pub days: Option<u32>,
+    pub late_fee: MoneyUnit,
+}
+
+// Notified on every day of the grace period of every account, e.g. to remind its owner
+pub trait GraceListener {
+    fn on_grace_day(&mut self, user: UserId, days_left: u32);
+}
+
+pub struct NoopGraceListener;
+
+impl GraceListener for NoopGraceListener {
+    fn on_grace_day(&mut self, _user: UserId, _days_left: u32) {}
+}
@@ -0,0 +1,469 @@
+// Operator policies: declarative rules checked before the mutations they govern, e.g.
+//
+// ```text
+// # Trial users may not order more than $100 a day
+// deny order when plan = trial and daily_spend > 100.00
+// require_approval refund when amount > 50.00
+// ```
+//
+// A rule is `<effect> <action> [when <condition> [and <condition>]...]` on a line of its own,
+// `#` starts a comment. The effects are `deny` and `require_approval`, the actions `order`
+// (purchases made through the host functions), `transfer` and `refund` (refunds of disputes
+// and of cancelled orders).
+// A condition compares `amount`, the amount of the action, or `daily_spend`, the amount
+// plus what the user has spent on the action since midnight UTC, with `=`, `!=`, `<`, `<=`,
+// `>` or `>=` to an amount like `100.00` or `100.00 EUR` (USD by default). Amounts in other
+// currencies never match. `plan` is compared with `=` or `!=` to `trial`, `paid`, `grace` or
+// `suspended`. The first matching rule decides, actions no rule matches are allowed.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{
+    anomalies, features, groups,
+    ledger::{self, EntryKind},
+    money::{Currency, MoneyUnit},
+    plan::Plan,
+    postpaid, BillingError, Error, State, UserId,
+};
+
+// The decisions kept by `PolicyLog`, the oldest are dropped first
+const MAX_LOGGED_DECISIONS: usize = 10_000;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Order,
+    Transfer,
+    Refund,
+}
+
+impl Action {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Action::Order => "order",
+            Action::Transfer => "transfer",
+            Action::Refund => "refund",
+        }
+    }
+
+    // Whether the ledger entry was made by the action, for `daily_spend`
+    fn made(self, kind: &EntryKind) -> bool {
+        match self {
+            Action::Order => kind.is_purchase(),
+            Action::Transfer => matches!(kind, EntryKind::TransferOut { .. }),
+            Action::Refund => matches!(
+                kind,
+                EntryKind::DisputeRefund { .. } | EntryKind::CancellationRefund { .. }
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+    Deny,
+    // The action fails until an operator approves it, see `approve`
+    RequireApproval,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn holds(self, ordering: std::cmp::Ordering) -> bool {
+        use std::cmp::Ordering::*;
+        match self {
+            Comparison::Eq => ordering == Equal,
+            Comparison::Ne => ordering != Equal,
+            Comparison::Lt => ordering == Less,
+            Comparison::Le => ordering != Greater,
+            Comparison::Gt => ordering == Greater,
+            Comparison::Ge => ordering != Less,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PlanKind {
+    Trial,
+    Paid,
+    Grace,
+    Suspended,
+}
+
+impl PlanKind {
+    fn of(plan: Plan) -> Self {
+        match plan {
+            Plan::Trial { .. } => PlanKind::Trial,
+            Plan::Paid => PlanKind::Paid,
+            Plan::Grace { .. } => PlanKind::Grace,
+            Plan::Suspended => PlanKind::Suspended,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Condition {
+    Amount(Comparison, MoneyUnit),
+    DailySpend(Comparison, MoneyUnit),
+    Plan { equal: bool, plan: PlanKind },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    // The line the rule was parsed from, for the decision log
+    pub source: String,
+    pub effect: Effect,
+    pub action: Action,
+    conditions: Vec<Condition>,
+}
+
+fn parse_action(word: &str) -> Option<Action> {
+    match word {
+        "order" => Some(Action::Order),
+        "transfer" => Some(Action::Transfer),
+        "refund" => Some(Action::Refund),
+        _ => None,
+    }
+}
+
+fn parse_comparison(word: &str) -> Option<Comparison> {
+    match word {
+        "=" => Some(Comparison::Eq),
+        "!=" => Some(Comparison::Ne),
+        "<" => Some(Comparison::Lt),
+        "<=" => Some(Comparison::Le),
+        ">" => Some(Comparison::Gt),
+        ">=" => Some(Comparison::Ge),
+        _ => None,
+    }
+}
+
+// Parses the words of a condition, e.g. `["amount", ">", "50.00", "EUR"]`
+fn parse_condition(words: &[&str]) -> Option<Condition> {
+    let (field, comparison, value) = match words {
+        [field, comparison, value] | [field, comparison, value, _] => {
+            (*field, parse_comparison(comparison)?, *value)
+        }
+        _ => return None,
+    };
+    let amount = || {
+        let currency = match words.get(3) {
+            Some(code) => Currency::from_code(code)?,
+            None => Currency::USD,
+        };
+        MoneyUnit::parse(value, currency).ok()
+    };
+    match field {
+        "amount" => Some(Condition::Amount(comparison, amount()?)),
+        "daily_spend" => Some(Condition::DailySpend(comparison, amount()?)),
+        "plan" if words.len() == 3 => {
+            let equal = match comparison {
+                Comparison::Eq => true,
+                Comparison::Ne => false,
+                _ => return None,
+            };
+            let plan = match value {
+                "trial" => PlanKind::Trial,
+                "paid" => PlanKind::Paid,
+                "grace" => PlanKind::Grace,
+                "suspended" => PlanKind::Suspended,
+                _ => return None,
+            };
+            Some(Condition::Plan { equal, plan })
+        }
+        _ => None,
+    }
+}
+
+fn parse_rule(line: &str) -> Option<Rule> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let effect = match *words.first()? {
+        "deny" => Effect::Deny,
+        "require_approval" => Effect::RequireApproval,
+        _ => return None,
+    };
+    let action = parse_action(words.get(1)?)?;
+    let conditions = match &words[2..] {
+        [] => Vec::new(),
+        ["when", conditions @ ..] => conditions
+            .split(|word| *word == "and")
+            .map(parse_condition)
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+    Some(Rule {
+        source: line.to_owned(),
+        effect,
+        action,
+        conditions,
+    })
+}
+
+// The rules of `Config::policy`, empty by default
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    // Fails on the first line that is not a rule, see the grammar above
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut rules = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            rules.push(parse_rule(line).ok_or(BillingError::InvalidPolicy)?);
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+}
+
+// What the rules have been evaluated against
+#[derive(Clone, Copy, Debug)]
+struct Request {
+    action: Action,
+    amount: MoneyUnit,
+    daily_spend: MoneyUnit,
+    plan: PlanKind,
+}
+
+fn compare(comparison: Comparison, value: MoneyUnit, limit: MoneyUnit) -> bool {
+    value
+        .checked_cmp(limit)
+        .is_ok_and(|ordering| comparison.holds(ordering))
+}
+
+impl Rule {
+    fn matches(&self, request: &Request) -> bool {
+        self.action == request.action
+            && self.conditions.iter().all(|condition| match *condition {
+                Condition::Amount(comparison, limit) => compare(comparison, request.amount, limit),
+                Condition::DailySpend(comparison, limit) => {
+                    compare(comparison, request.daily_spend, limit)
+                }
+                Condition::Plan { equal, plan } => (request.plan == plan) == equal,
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Allowed,
+    Denied,
+    // An approval has been requested, see `PolicyLog::pending_approvals`
+    ApprovalRequired,
+    // The action was allowed by an approval
+    Approved,
+}
+
+#[derive(Clone, Debug)]
+pub struct Decision {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    pub user: UserId,
+    pub action: Action,
+    pub amount: MoneyUnit,
+    pub outcome: Outcome,
+    // The source of the deciding rule, if any
+    pub rule: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ApprovalId(pub u64);
+
+// An action waiting for the approval of an operator. Once approved, the same action
+// of the same user for the same amount is allowed once.
+#[derive(Clone, Debug)]
+pub struct Approval {
+    pub id: ApprovalId,
+    pub user: UserId,
+    pub action: Action,
+    pub amount: MoneyUnit,
+    // The operator who approved the action
+    pub approved_by: Option<String>,
+}
+
+#[derive(Default)]
+pub struct PolicyLog {
+    decisions: VecDeque<Decision>,
+    approvals: BTreeMap<ApprovalId, Approval>,
+    next_id: u64,
+}
+
+impl PolicyLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The latest decisions, oldest first
+    pub fn decisions(&self) -> impl Iterator<Item = &Decision> {
+        self.decisions.iter()
+    }
+
+    pub fn pending_approvals(&self) -> impl Iterator<Item = &Approval> {
+        self.approvals
+            .values()
+            .filter(|approval| approval.approved_by.is_none())
+    }
+
+    fn log(&mut self, decision: Decision) {
+        if self.decisions.len() == MAX_LOGGED_DECISIONS {
+            self.decisions.pop_front();
+        }
+        self.decisions.push_back(decision);
+    }
+
+    fn find(&self, user: UserId, action: Action, amount: MoneyUnit) -> Option<&Approval> {
+        self.approvals.values().find(|approval| {
+            approval.user == user && approval.action == action && approval.amount == amount
+        })
+    }
+}
+
+// The amount the user has spent on the action since midnight UTC
+fn spent_today(state: &State, user: UserId, action: Action) -> Result<MoneyUnit, Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let midnight = ledger::now_secs() / SECS_PER_DAY * SECS_PER_DAY;
+    let mut spent = MoneyUnit::zero(user_data.balance.currency());
+    for entry in user_data.ledger.iter().rev() {
+        if entry.at < midnight {
+            break;
+        }
+        if !action.made(&entry.kind) {
+            continue;
+        }
+        // Charges are debits and refunds credits
+        let amount = match entry.amount.is_negative() {
+            true => entry
+                .amount
+                .checked_neg()
+                .ok_or(BillingError::TotalCostExceededMaxValue)?,
+            false => entry.amount,
+        };
+        spent = (spent + amount)?;
+    }
+    Ok(spent)
+}
+
+// Checks the action of the user against `Config::policy` before it is carried out
+// and logs the decision. Denied actions fail with `BillingError::PolicyDenied`, the ones
+// waiting for an approval with `BillingError::ApprovalRequired`. Orders beyond the daily
+// limit of the user's group fail with `BillingError::GroupLimitExceeded` before the rules
+// are checked.
+pub fn authorize(
+    state: &mut State,
+    user: UserId,
+    action: Action,
+    amount: MoneyUnit,
+) -> Result<(), Error> {
+    features::check_action(state, user, action)?;
+    if action != Action::Refund {
+        anomalies::check_cap(state, user)?;
+    }
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let plan = user_data.plan;
+    let daily_spend = match amount.currency() == user_data.balance.currency() {
+        true => (spent_today(state, user, action)? + amount)?,
+        false => amount,
+    };
+    if action == Action::Order {
+        groups::check_limit(state, user, daily_spend)?;
+    }
+    postpaid::check_not_suspended(&user_data)?;
+    let request = Request {
+        action,
+        amount,
+        daily_spend,
+        plan: PlanKind::of(plan),
+    };
+    let rule = state
+        .config
+        .policy
+        .rules
+        .iter()
+        .find(|rule| rule.matches(&request));
+    let (outcome, result) = match rule.map(|rule| rule.effect) {
+        None => (Outcome::Allowed, Ok(())),
+        Some(Effect::Deny) => (Outcome::Denied, Err(BillingError::PolicyDenied.into())),
+        Some(Effect::RequireApproval) => match state.policy_log.find(user, action, amount) {
+            Some(approval) if approval.approved_by.is_some() => {
+                let id = approval.id;
+                state.policy_log.approvals.remove(&id);
+                (Outcome::Approved, Ok(()))
+            }
+            // Retrying does not request another approval
+            Some(_) => (
+                Outcome::ApprovalRequired,
+                Err(BillingError::ApprovalRequired.into()),
+            ),
+            None => {
+                let id = ApprovalId(state.policy_log.next_id);
+                state.policy_log.next_id += 1;
+                state.policy_log.approvals.insert(
+                    id,
+                    Approval {
+                        id,
+                        user,
+                        action,
+                        amount,
+                        approved_by: None,
+                    },
+                );
+                (
+                    Outcome::ApprovalRequired,
+                    Err(BillingError::ApprovalRequired.into()),
+                )
+            }
+        },
+    };
+    let decision = Decision {
+        at: ledger::now_secs(),
+        user,
+        action,
+        amount,
+        outcome,
+        rule: rule.map(|rule| rule.source.clone()),
+    };
+    state.policy_log.log(decision);
+    result
+}
+
+// Approves a pending action on behalf of the operator
+pub fn approve(
+    state: &mut State,
+    id: ApprovalId,
+    operator: impl Into<String>,
+) -> Result<(), Error> {
+    match state.policy_log.approvals.get_mut(&id) {
+        Some(approval) if approval.approved_by.is_none() => {
+            approval.approved_by = Some(operator.into());
+            Ok(())
+        }
+        _ => Err(BillingError::UnknownApproval.into()),
+    }
+}
+
+// Rejects a pending action, the user may request it again
+pub fn reject(state: &mut State, id: ApprovalId) -> Result<(), Error> {
+    match state.policy_log.approvals.get(&id) {
+        Some(approval) if approval.approved_by.is_none() => {
+            state.policy_log.approvals.remove(&id);
+            Ok(())
+        }
+        _ => Err(BillingError::UnknownApproval.into()),
+    }
+}
@@ -0,0 +1,129 @@
+// The profile of an account as one document for the customer portals, e.g. to render the
+// dashboard of the user from a single `{"op": "profile", "user": 0}` request to the daemon,
+// see `daemon`, or `users profile <id>` of the CLI. It bundles the balance, the plan, the
+// services, the latest charges, the invoices of postpaid accounts and the support tickets.
+// The amounts are decimals in the major units of the currency, as in the ledger exports.
+
+use serde::Serialize;
+
+use crate::{
+    features,
+    ledger::{self, LedgerRow},
+    plan::Plan,
+    postpaid,
+    tickets::{Ticket, TicketStatus},
+    BillingError, Error, State, UserId,
+};
+
+// The number of the latest charges in a profile
+pub const RECENT_CHARGES: usize = 20;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct InvoiceSummary {
+    pub id: u64,
+    // Seconds since the Unix epoch
+    pub issued_at: u64,
+    pub due_at: u64,
+    pub amount: String,
+    pub paid_at: Option<u64>,
+    pub overdue: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TicketSummary {
+    pub id: u64,
+    pub subject: String,
+    pub status: TicketStatus,
+    pub messages: usize,
+    // When the last message was posted, seconds since the Unix epoch
+    pub updated_at: u64,
+}
+
+impl TicketSummary {
+    fn new(ticket: &Ticket) -> Self {
+        Self {
+            id: ticket.id.0,
+            subject: ticket.subject.clone(),
+            status: ticket.status,
+            messages: ticket.messages.len(),
+            updated_at: ticket.messages.last().map_or(0, |message| message.at),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Profile {
+    pub user: UserId,
+    pub balance: String,
+    pub currency: &'static str,
+    // `trial`, `paid`, `grace` or `suspended`
+    pub plan: &'static str,
+    // The days left of the trial or of the grace period, 0 on the other plans
+    pub plan_days_left: u32,
+    pub hosting_days_left: u32,
+    pub services: Vec<String>,
+    // The latest billable entries of the ledger, newest first, at most `RECENT_CHARGES`
+    pub recent_charges: Vec<LedgerRow>,
+    // The usage not invoiced yet, `None` for prepaid accounts
+    pub unbilled: Option<String>,
+    // The invoices of postpaid accounts, newest first
+    pub invoices: Vec<InvoiceSummary>,
+    // Newest first
+    pub tickets: Vec<TicketSummary>,
+}
+
+pub fn profile(state: &State, user: UserId) -> Result<Profile, Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let plan_days_left = match user_data.plan {
+        Plan::Trial { days_left } | Plan::Grace { days_left } => days_left,
+        Plan::Paid | Plan::Suspended => 0,
+    };
+    let recent_charges = user_data
+        .ledger
+        .iter()
+        .rev()
+        .filter(|entry| entry.kind.is_billable())
+        .take(RECENT_CHARGES)
+        .map(|entry| LedgerRow::new(user, entry))
+        .collect();
+    let now = ledger::now_secs();
+    let (unbilled, invoices) = match &user_data.postpaid {
+        Some(account) => {
+            let invoices = account
+                .invoices
+                .iter()
+                .rev()
+                .map(|invoice| InvoiceSummary {
+                    id: invoice.id,
+                    issued_at: invoice.issued_at,
+                    due_at: invoice.due_at,
+                    amount: invoice.amount.to_decimal_string(),
+                    paid_at: invoice.paid_at,
+                    overdue: invoice.is_overdue(now),
+                })
+                .collect();
+            let unbilled = postpaid::unbilled(&user_data)?.to_decimal_string();
+            (Some(unbilled), invoices)
+        }
+        None => (None, Vec::new()),
+    };
+    let mut tickets = state
+        .tickets
+        .of_user(user)
+        .map(TicketSummary::new)
+        .collect::<Vec<_>>();
+    tickets.reverse();
+    Ok(Profile {
+        user,
+        balance: user_data.balance.to_decimal_string(),
+        currency: user_data.balance.currency().code(),
+        plan: features::plan_name(user_data.plan),
+        plan_days_left,
+        hosting_days_left: user_data.hosting_days_left,
+        services: user_data.services.iter().cloned().collect(),
+        recent_charges,
+        unbilled,
+        invoices,
+        tickets,
+    })
+}
@@ -0,0 +1,290 @@
+// Postpaid accounts are invoiced for their usage at the end of every month (UTC) instead of
+// paying for it in advance. The balance of a postpaid account is the credit left under its
+// credit limit: the charges accrue as the unbilled usage of the month, see `unbilled`, and
+// the month-end invoicing turns them into a `PostpaidInvoice` due after the payment terms of
+// `PostpaidConfig`. Paying an invoice restores the credit. Accounts with an invoice overdue
+// for longer than `PostpaidConfig::suspend_after_days` are suspended until the overdue
+// invoices are paid.
+
+use time::OffsetDateTime;
+
+use crate::{
+    ledger::{self, EntryKind, LedgerEntry},
+    money::MoneyUnit,
+    notifications::{self, NotificationEvent},
+    plan::Plan,
+    BillingError, Error, State, UserData, UserId,
+};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PostpaidInvoice {
+    // The position among the invoices of the account, from 0
+    pub id: u64,
+    // Seconds since the Unix epoch
+    pub issued_at: u64,
+    pub due_at: u64,
+    // The usage invoiced, net of the discounts
+    pub amount: MoneyUnit,
+    // The ledger entries invoiced, `end` is exclusive
+    pub first_entry: usize,
+    pub end_entry: usize,
+    pub paid_at: Option<u64>,
+}
+
+impl PostpaidInvoice {
+    pub fn is_overdue(&self, now: u64) -> bool {
+        self.paid_at.is_none() && now > self.due_at
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PostpaidAccount {
+    pub credit_limit: MoneyUnit,
+    // The ledger entries before this index have been invoiced
+    pub invoiced_entries: usize,
+    pub invoices: Vec<PostpaidInvoice>,
+    // Whether the account has been suspended for its overdue invoices
+    pub suspended: bool,
+}
+
+fn account(user_data: &UserData) -> Result<&PostpaidAccount, Error> {
+    Ok(user_data
+        .postpaid
+        .as_ref()
+        .ok_or(BillingError::NotPostpaid)?)
+}
+
+// Makes the account postpaid, extending its balance by the credit limit
+pub fn enroll(state: &mut State, user: UserId, credit_limit: MoneyUnit) -> Result<(), Error> {
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    if user_data.postpaid.is_some() {
+        return Err(BillingError::AlreadyPostpaid.into());
+    }
+    if credit_limit.is_negative() {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    let balance = (user_data.balance + credit_limit)?;
+    user_data.balance = balance;
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::PostpaidEnrollment,
+        credit_limit,
+        balance,
+    ));
+    user_data.postpaid = Some(PostpaidAccount {
+        credit_limit,
+        invoiced_entries: user_data.archived_entries + user_data.ledger.len(),
+        invoices: Vec::new(),
+        suspended: false,
+    });
+    Ok(())
+}
+
+// The usage accrued since the last invoice, net of the discounts. Archived entries
+// are left out.
+pub fn unbilled(user_data: &UserData) -> Result<MoneyUnit, Error> {
+    let account = account(user_data)?;
+    let first = account
+        .invoiced_entries
+        .saturating_sub(user_data.archived_entries);
+    // Charges are negative and discounts positive, so the usage is the negated sum
+    let mut total = MoneyUnit::zero(user_data.balance.currency());
+    for entry in user_data.ledger.iter().skip(first) {
+        if entry.kind.is_billable() {
+            total = total.sub_allowing_negative(entry.amount)?;
+        }
+    }
+    Ok(total)
+}
+
+// Invoices the unbilled usage of the account, returning the id of the invoice, or `None`
+// if there is nothing to invoice
+pub fn invoice(state: &mut State, user: UserId, now: u64) -> Result<Option<u64>, Error> {
+    let terms_days = state.config.postpaid.payment_terms_days;
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    let amount = unbilled(user_data)?;
+    let end_entry = user_data.archived_entries + user_data.ledger.len();
+    let account = user_data.postpaid.as_mut().unwrap();
+    let first_entry = account.invoiced_entries;
+    account.invoiced_entries = end_entry;
+    if amount.is_zero() || amount.is_negative() {
+        return Ok(None);
+    }
+    let id = account.invoices.len() as u64;
+    account.invoices.push(PostpaidInvoice {
+        id,
+        issued_at: now,
+        due_at: now + terms_days as u64 * SECS_PER_DAY,
+        amount,
+        first_entry,
+        end_entry,
+        paid_at: None,
+    });
+    let message = format!("Invoice {id} of {amount} is due in {terms_days} days.");
+    notifications::notify(state, user, NotificationEvent::Invoice, message);
+    Ok(Some(id))
+}
+
+// Records the payment of the invoice, restoring the credit of the account. The account
+// is reactivated once none of its invoices is overdue anymore.
+pub fn pay(state: &mut State, user: UserId, id: u64, now: u64) -> Result<(), Error> {
+    let user_data = state
+        .users
+        .get_mut(&user)
+        .ok_or(BillingError::UnknownUser)?;
+    let amount = match account(user_data)?.invoices.get(id as usize) {
+        Some(invoice) if invoice.paid_at.is_none() => invoice.amount,
+        _ => return Err(BillingError::UnknownInvoice.into()),
+    };
+    let balance = (user_data.balance + amount)?;
+    user_data.balance = balance;
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::InvoicePayment { invoice: id },
+        amount,
+        balance,
+    ));
+    let account = user_data.postpaid.as_mut().unwrap();
+    account.invoices[id as usize].paid_at = Some(now);
+    if account.suspended && !account.invoices.iter().any(|i| i.is_overdue(now)) {
+        account.suspended = false;
+        user_data.plan = Plan::Paid;
+    }
+    Ok(())
+}
+
+// Suspends the accounts with an invoice overdue for longer than the configuration allows
+pub fn suspend_overdue(state: &mut State, now: u64) {
+    let Some(days) = state.config.postpaid.suspend_after_days else {
+        return;
+    };
+    let grace = days as u64 * SECS_PER_DAY;
+    for (_, user_data) in state.users.iter_mut() {
+        let Some(account) = user_data.postpaid.as_mut() else {
+            continue;
+        };
+        let overdue = account
+            .invoices
+            .iter()
+            .any(|invoice| invoice.is_overdue(now.saturating_sub(grace)));
+        if overdue && !account.suspended {
+            account.suspended = true;
+            user_data.plan = Plan::Suspended;
+        }
+    }
+}
+
+// Fails the orders, transfers and refunds of the accounts suspended for their overdue
+// invoices, see `policy::authorize`
+pub(crate) fn check_not_suspended(user_data: &UserData) -> Result<(), Error> {
+    match &user_data.postpaid {
+        Some(account) if account.suspended => Err(BillingError::InvoiceOverdue.into()),
+        _ => Ok(()),
+    }
+}
+
+// Invoices every postpaid account, e.g. as the month-end job. Fails on the first
+// account whose usage fails to be invoiced.
+pub fn invoice_all(state: &mut State, now: u64) -> Result<(), Error> {
+    let users = state
+        .users
+        .read()
+        .iter()
+        .filter(|(_, user_data)| user_data.postpaid.is_some())
+        .map(|(&user, _)| user)
+        .collect::<Vec<_>>();
+    for user in users {
+        invoice(state, user, now)?;
+    }
+    Ok(())
+}
+
+// The daily job: invoices the postpaid accounts on the first day of the month
+// and suspends the overdue ones
+pub(crate) fn advance_day(state: &mut State) {
+    let now = ledger::now_secs();
+    let first_of_month =
+        OffsetDateTime::from_unix_timestamp(now as i64).is_ok_and(|date| date.day() == 1);
+    if first_of_month {
+        let _ = invoice_all(state, now);
+    }
+    suspend_overdue(state, now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{billing, host, store::UserStore};
+
+    const USER: UserId = UserId(0);
+
+    // A postpaid user without funds and a credit of 100.00 who has used 10 days of hosting
+    fn state() -> (State, MoneyUnit) {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(0)));
+        let mut state = State::new(users);
+        enroll(&mut state, USER, MoneyUnit::from_cents(10_000)).unwrap();
+        host::order_hosting(&mut state, USER, 10).unwrap();
+        let cost = billing::hosting_cost(&state.config.hosting, 10).unwrap();
+        (state, cost)
+    }
+
+    fn balance(state: &State) -> MoneyUnit {
+        state.users.get(&USER).unwrap().balance
+    }
+
+    #[test]
+    fn invoiced_usage_is_paid_back_into_the_credit() {
+        let (mut state, cost) = state();
+        assert_eq!(unbilled(&state.users.get(&USER).unwrap()).unwrap(), cost);
+        let id = invoice(&mut state, USER, 0).unwrap().unwrap();
+        assert!(unbilled(&state.users.get(&USER).unwrap())
+            .unwrap()
+            .is_zero());
+        // Nothing new to invoice
+        assert_eq!(invoice(&mut state, USER, 0).unwrap(), None);
+
+        pay(&mut state, USER, id, 1).unwrap();
+        assert_eq!(balance(&state), MoneyUnit::from_cents(10_000));
+        assert!(matches!(
+            pay(&mut state, USER, id, 1),
+            Err(Error::Billing(BillingError::UnknownInvoice))
+        ));
+    }
+
+    #[test]
+    fn overdue_invoices_suspend_the_account_until_paid() {
+        let (mut state, _) = state();
+        let id = invoice(&mut state, USER, 0).unwrap().unwrap();
+        let due_at = state
+            .users
+            .get(&USER)
+            .unwrap()
+            .postpaid
+            .as_ref()
+            .unwrap()
+            .invoices[0]
+            .due_at;
+        let late = due_at + 15 * SECS_PER_DAY;
+        suspend_overdue(&mut state, late);
+        assert_eq!(state.users.get(&USER).unwrap().plan, Plan::Suspended);
+        assert!(matches!(
+            host::order_hosting(&mut state, USER, 1),
+            Err(Error::Billing(BillingError::InvoiceOverdue))
+        ));
+
+        pay(&mut state, USER, id, late).unwrap();
+        assert_eq!(state.users.get(&USER).unwrap().plan, Plan::Paid);
+        host::order_hosting(&mut state, USER, 1).unwrap();
+        assert!(matches!(
+            enroll(&mut state, USER, MoneyUnit::from_cents(0)),
+            Err(Error::Billing(BillingError::AlreadyPostpaid))
+        ));
+    }
+}
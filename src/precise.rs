@@ -0,0 +1,277 @@
+// Amounts more precise than the minor unit of their currency, for the charges too small to be
+// posted to the ledger one by one, e.g. the fuel of an invocation, see `metering::charge_compute`.
+// They are accumulated at full precision and only rounded, half to even, when the accumulated
+// amount is posted, keeping the rounding error as the remainder to be posted later, so that the
+// posted amounts never drift from the exact sum of the charges by more than half a minor unit.
+//
+// The amounts are fixed-point with nine decimal places of the minor unit by default, or
+// `rust_decimal` decimals with the `decimal` feature, for 28 significant digits.
+
+use std::fmt;
+
+use crate::{
+    money::{Currency, MoneyUnit},
+    BillingError, Error,
+};
+
+#[cfg(not(feature = "decimal"))]
+mod repr {
+    use crate::money::div_round_half_even;
+
+    // Billionths of a minor unit
+    const SCALE: i128 = 1_000_000_000;
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+    pub struct Repr(i128);
+
+    impl Repr {
+        // Rounded half to even to the billionth
+        pub fn from_ratio(numerator: i128, denominator: i128) -> Option<Self> {
+            if denominator == 0 {
+                return None;
+            }
+            let scaled = numerator.checked_mul(SCALE)?;
+            Some(Self(div_round_half_even(scaled, denominator)))
+        }
+
+        pub fn from_minor_units(minor: i64) -> Self {
+            Self(minor as i128 * SCALE)
+        }
+
+        pub fn checked_add(self, rhs: Self) -> Option<Self> {
+            self.0.checked_add(rhs.0).map(Self)
+        }
+
+        pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+            self.0.checked_sub(rhs.0).map(Self)
+        }
+
+        pub fn round_half_even(self) -> Option<i64> {
+            i64::try_from(div_round_half_even(self.0, SCALE)).ok()
+        }
+
+        pub fn is_zero(self) -> bool {
+            self.0 == 0
+        }
+
+        pub fn to_decimal_string(self) -> String {
+            let sign = if self.0 < 0 { "-" } else { "" };
+            let (whole, fraction) = (
+                self.0.unsigned_abs() / 1_000_000_000,
+                self.0.unsigned_abs() % 1_000_000_000,
+            );
+            let fraction = format!("{fraction:09}");
+            match fraction.trim_end_matches('0') {
+                "" => format!("{sign}{whole}"),
+                fraction => format!("{sign}{whole}.{fraction}"),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "decimal")]
+mod repr {
+    use rust_decimal::{prelude::ToPrimitive, Decimal, RoundingStrategy};
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+    pub struct Repr(Decimal);
+
+    impl Repr {
+        // Rounded half to even to the 28 significant digits of the decimals
+        pub fn from_ratio(numerator: i128, denominator: i128) -> Option<Self> {
+            let numerator = Decimal::try_from_i128_with_scale(numerator, 0).ok()?;
+            let denominator = Decimal::try_from_i128_with_scale(denominator, 0).ok()?;
+            numerator.checked_div(denominator).map(Self)
+        }
+
+        pub fn from_minor_units(minor: i64) -> Self {
+            Self(Decimal::from(minor))
+        }
+
+        pub fn checked_add(self, rhs: Self) -> Option<Self> {
+            self.0.checked_add(rhs.0).map(Self)
+        }
+
+        pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+            self.0.checked_sub(rhs.0).map(Self)
+        }
+
+        pub fn round_half_even(self) -> Option<i64> {
+            self.0
+                .round_dp_with_strategy(0, RoundingStrategy::MidpointNearestEven)
+                .to_i64()
+        }
+
+        pub fn is_zero(self) -> bool {
+            self.0.is_zero()
+        }
+
+        pub fn to_decimal_string(self) -> String {
+            self.0.normalize().to_string()
+        }
+    }
+}
+
+use repr::Repr;
+
+// An amount in fractions of the minor unit of its currency
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PreciseMoney {
+    minor: Repr,
+    currency: Currency,
+}
+
+impl PreciseMoney {
+    pub fn zero(currency: Currency) -> Self {
+        Self {
+            minor: Repr::default(),
+            currency,
+        }
+    }
+
+    // `numerator / denominator` minor units, e.g. the price per million units of a quantity
+    // times the quantity over a million
+    pub fn from_ratio(
+        numerator: i128,
+        denominator: i128,
+        currency: Currency,
+    ) -> Result<Self, Error> {
+        let minor = Repr::from_ratio(numerator, denominator)
+            .ok_or(BillingError::TotalCostExceededMaxValue)?;
+        Ok(Self { minor, currency })
+    }
+
+    pub fn currency(self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.minor.is_zero()
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Result<Self, Error> {
+        if self.currency != rhs.currency {
+            return Err(BillingError::CurrencyMismatch.into());
+        }
+        let minor =
+            (self.minor.checked_add(rhs.minor)).ok_or(BillingError::TotalCostExceededMaxValue)?;
+        Ok(Self {
+            minor,
+            currency: self.currency,
+        })
+    }
+
+    // The amount rounded half to even to the minor unit, and the rest, at most half a minor unit
+    // either way
+    pub fn round_half_even(self) -> Result<(MoneyUnit, PreciseMoney), Error> {
+        let rounded = self
+            .minor
+            .round_half_even()
+            .ok_or(BillingError::TotalCostExceededMaxValue)?;
+        let rest = self
+            .minor
+            .checked_sub(Repr::from_minor_units(rounded))
+            .ok_or(BillingError::TotalCostExceededMaxValue)?;
+        Ok((
+            MoneyUnit::from_minor_units(rounded, self.currency),
+            Self {
+                minor: rest,
+                currency: self.currency,
+            },
+        ))
+    }
+}
+
+// In minor units, e.g. `0.25 USD minor units` for a quarter of a cent
+impl fmt::Display for PreciseMoney {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} minor units",
+            self.minor.to_decimal_string(),
+            self.currency.code()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rounded(numerator: i128, denominator: i128) -> (i64, PreciseMoney) {
+        let (amount, rest) = PreciseMoney::from_ratio(numerator, denominator, Currency::USD)
+            .unwrap()
+            .round_half_even()
+            .unwrap();
+        (amount.minor_units(), rest)
+    }
+
+    #[test]
+    fn rounds_half_to_even() {
+        assert_eq!(rounded(1, 2).0, 0);
+        assert_eq!(rounded(3, 2).0, 2);
+        assert_eq!(rounded(5, 2).0, 2);
+        assert_eq!(rounded(7, 2).0, 4);
+        assert_eq!(rounded(-1, 2).0, 0);
+        assert_eq!(rounded(-3, 2).0, -2);
+        assert_eq!(rounded(51, 100).0, 1);
+        assert_eq!(rounded(249, 100).0, 2);
+    }
+
+    #[test]
+    fn keeps_the_rounding_error_as_the_rest() {
+        let (amount, rest) = rounded(5, 4);
+        assert_eq!(amount, 1);
+        assert_eq!(rest, PreciseMoney::from_ratio(1, 4, Currency::USD).unwrap());
+        let (amount, rest) = rounded(7, 4);
+        assert_eq!(amount, 2);
+        assert_eq!(
+            rest,
+            PreciseMoney::from_ratio(-1, 4, Currency::USD).unwrap()
+        );
+        assert!(rounded(3, 1).1.is_zero());
+    }
+
+    #[test]
+    fn posted_amounts_do_not_depend_on_how_the_charges_are_split() {
+        // 0.0037 of a cent per unit, charged in chunks of different sizes
+        let price = 37;
+        let total = 1_000_003;
+        for chunk in [1, 7, 250, 4_096, 99_999, total] {
+            let mut posted = 0;
+            let mut rest = PreciseMoney::zero(Currency::USD);
+            let mut units = 0;
+            while units < total {
+                let size = chunk.min(total - units);
+                units += size;
+                let charge = PreciseMoney::from_ratio(size * price, 10_000, Currency::USD).unwrap();
+                let (amount, remainder) =
+                    rest.checked_add(charge).unwrap().round_half_even().unwrap();
+                posted += amount.minor_units();
+                rest = remainder;
+                let half = PreciseMoney::from_ratio(1, 2, Currency::USD).unwrap();
+                assert!(rest.minor <= half.minor);
+                assert!(
+                    rest.minor
+                        >= PreciseMoney::from_ratio(-1, 2, Currency::USD)
+                            .unwrap()
+                            .minor
+                );
+            }
+            // 3700.0111 cents
+            assert_eq!(posted, 3_700, "chunks of {chunk}");
+            assert_eq!(
+                rest,
+                PreciseMoney::from_ratio(111, 10_000, Currency::USD).unwrap(),
+                "chunks of {chunk}"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_to_mix_currencies() {
+        let usd = PreciseMoney::from_ratio(1, 3, Currency::USD).unwrap();
+        let eur = PreciseMoney::from_ratio(1, 3, Currency::EUR).unwrap();
+        assert!(usd.checked_add(eur).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+// WASI 0.2 (preview2) components next to the preview1 core modules. Components are run
+// through the `wasi:cli/command` world: their `wasi:cli/run` export is called instead
+// of an export chosen by the caller. The host functions are only available to core
+// modules, since they are defined as core imports rather than in a WIT world, but the
+// components may import the `wsm:catalog/service-catalog` interface, see `service_catalog`.
+// The WASI interfaces are the ones implemented by the wasmtime release in use,
+// i.e. `wasi:cli@0.2.0-rc-2023-11-10` for wasmtime 15.
+
+use wasmtime::component::{Component, Linker};
+use wasmtime_wasi::preview2::{command::sync::Command, Table, WasiCtx, WasiView};
+
+use crate::{service_catalog, Error, HostError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WasiFlavor {
+    // A core module, e.g. the output of `wasm32-wasi` toolchains
+    Preview1,
+    // A component, e.g. the output of `wasm32-wasip2` toolchains or `wasm-tools component new`
+    Preview2,
+}
+
+// Tells the flavor from the header of a binary or the first form of a text module.
+// Anything that is not a component is treated as a core module.
+pub fn detect(bytes: &[u8]) -> WasiFlavor {
+    match bytes {
+        // The version of core modules is 1, components have a layer of 1 instead
+        [0, b'a', b's', b'm', _, _, 1, 0, ..] => WasiFlavor::Preview2,
+        [0, b'a', b's', b'm', ..] => WasiFlavor::Preview1,
+        _ => {
+            let text = String::from_utf8_lossy(bytes);
+            let form = text.trim_start().trim_start_matches('(').trim_start();
+            match form.starts_with("component") {
+                true => WasiFlavor::Preview2,
+                false => WasiFlavor::Preview1,
+            }
+        }
+    }
+}
+
+// The store data of a component run
+pub struct ComponentState {
+    table: Table,
+    ctx: WasiCtx,
+}
+
+impl ComponentState {
+    pub fn new(ctx: WasiCtx) -> Self {
+        Self {
+            table: Table::new(),
+            ctx,
+        }
+    }
+}
+
+impl WasiView for ComponentState {
+    fn table(&self) -> &Table {
+        &self.table
+    }
+
+    fn table_mut(&mut self) -> &mut Table {
+        &mut self.table
+    }
+
+    fn ctx(&self) -> &WasiCtx {
+        &self.ctx
+    }
+
+    fn ctx_mut(&mut self) -> &mut WasiCtx {
+        &mut self.ctx
+    }
+}
+
+pub(crate) fn command_linker(engine: &wasmtime::Engine) -> Result<Linker<ComponentState>, Error> {
+    let mut linker = Linker::new(engine);
+    wasmtime_wasi::preview2::command::sync::add_to_linker(&mut linker)
+        .and_then(|()| service_catalog::add_to_linker(&mut linker))
+        .map_err(|e| HostError::EngineConfig(e.to_string()))?;
+    Ok(linker)
+}
+
+// Runs the command, returning 0 if it succeeds like the exports of core modules do
+pub(crate) fn run_command(
+    store: &mut wasmtime::Store<ComponentState>,
+    linker: &Linker<ComponentState>,
+    bytes: &[u8],
+) -> Result<i64, Error> {
+    let component = Component::new(store.engine(), bytes)
+        .map_err(|e| HostError::CompilationFailed(e.to_string()))?;
+    let (command, _) = Command::instantiate(&mut *store, &component, linker)
+        .map_err(|e| HostError::InstantiationFailed(e.to_string()))?;
+    match command.wasi_cli_run().call_run(&mut *store) {
+        Ok(Ok(())) => Ok(0),
+        Ok(Err(())) => Err(HostError::CallFailed("the command failed".to_owned()).into()),
+        Err(e) => Err(HostError::CallFailed(e.to_string()).into()),
+    }
+}
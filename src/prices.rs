@@ -0,0 +1,126 @@
+// The prices the users are charged, for the guests to show instead of hardcoding them, see
+// `host.price_of` and `host.price_catalog`. A price is the list price of the hosting, per day, or
+// of a service or bundle of the catalog, marked up by the reseller of the user for the bundles,
+// see `resellers`, and net of the discount of the user's group, see `groups`, as the user would
+// be charged for it. The price of a bundle is the one of all of its services, including those
+// the user already has.
+
+use serde::Serialize;
+
+use crate::{
+    groups,
+    money::{MoneySum, MoneyUnit},
+    resellers, BillingError, Error, State, UserId,
+};
+
+// The name of the hosting ordered with `host.order_hosting`, priced per day
+pub const HOSTING: &str = "hosting";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemKind {
+    Hosting,
+    Service,
+    Bundle,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ItemPrice {
+    pub name: String,
+    pub kind: ItemKind,
+    // Before the markup and the discount
+    pub list_price: MoneyUnit,
+    pub price: MoneyUnit,
+}
+
+fn list_price(state: &State, user: UserId, name: &str) -> Result<(ItemKind, MoneyUnit), Error> {
+    let catalog = &state.config.catalog;
+    if name == HOSTING {
+        return Ok((ItemKind::Hosting, state.config.hosting.price_per_day));
+    }
+    if let Some(service) = catalog.services.get(name) {
+        return Ok((ItemKind::Service, service.price));
+    }
+    let bundle = catalog
+        .bundles
+        .get(name)
+        .ok_or(BillingError::UnknownService)?;
+    let prices = bundle
+        .services
+        .iter()
+        .map(|service| match catalog.services.get(service) {
+            Some(service) => Ok(service.price),
+            None => Err(BillingError::UnknownService),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // Summed like the orders of the bundle, see `services::bundle_cost`
+    let currency = state
+        .users
+        .get(&user)
+        .ok_or(BillingError::UnknownUser)?
+        .balance
+        .currency();
+    Ok((ItemKind::Bundle, prices.into_iter().checked_sum(currency)?))
+}
+
+// The amount net of the discount of the user's group
+fn discounted(state: &State, user: UserId, amount: MoneyUnit) -> Result<MoneyUnit, Error> {
+    let basis_points = groups::group_of(state, user).map_or(0, |group| group.discount_basis_points);
+    let discount = amount
+        .basis_points(basis_points)
+        .ok_or(BillingError::TotalCostExceededMaxValue)?;
+    amount.sub_allowing_negative(discount)
+}
+
+fn price(
+    state: &State,
+    user: UserId,
+    kind: ItemKind,
+    list_price: MoneyUnit,
+) -> Result<MoneyUnit, Error> {
+    let price = discounted(state, user, list_price)?;
+    // The markup is charged, and discounted, as an entry of its own
+    let markup = match kind {
+        ItemKind::Bundle => resellers::markup(&state.users, user, list_price)?,
+        ItemKind::Hosting | ItemKind::Service => None,
+    };
+    match markup {
+        Some((_, markup)) => price + discounted(state, user, markup)?,
+        None => Ok(price),
+    }
+}
+
+// The price of the quantity of the item, e.g. of so many days of hosting
+pub fn price_of(
+    state: &State,
+    user: UserId,
+    name: &str,
+    quantity: i64,
+) -> Result<MoneyUnit, Error> {
+    if quantity <= 0 {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    let (kind, unit_price) = list_price(state, user, name)?;
+    let list_price = (unit_price * quantity).ok_or(BillingError::TotalCostExceededMaxValue)?;
+    price(state, user, kind, list_price)
+}
+
+// The prices of the hosting and of every service and bundle of the catalog for the user, in
+// this order
+pub fn catalog(state: &State, user: UserId) -> Result<Vec<ItemPrice>, Error> {
+    let catalog = &state.config.catalog;
+    let names = std::iter::once(HOSTING)
+        .chain(catalog.services.keys().map(String::as_str))
+        .chain(catalog.bundles.keys().map(String::as_str));
+    names
+        .map(|name| {
+            let (kind, list_price) = list_price(state, user, name)?;
+            Ok(ItemPrice {
+                name: name.to_owned(),
+                kind,
+                list_price,
+                price: price(state, user, kind, list_price)?,
+            })
+        })
+        .collect()
+}
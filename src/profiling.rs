@@ -0,0 +1,82 @@
+// Profiling of guest invocations with the wasmtime guest profiler. A run is profiled when
+// requested with `Profiler::profile_next_run` on a runtime configured with a
+// `ProfilingConfig`: the guest's stack is sampled on every epoch tick, attributing the
+// time (and so the fuel) of the run to the guest functions on the stack. The profile is
+// kept in the run's `ExecutionRecord::profile` in the Firefox processed profile format,
+// which https://profiler.firefox.com shows as a flame graph.
+
+use std::time::Duration;
+
+use wasmtime::{GuestProfiler, Module, StoreContextMut};
+
+use crate::runtime::WasmtimeState;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProfilingConfig {
+    // How often the stack of a profiled guest is sampled. The epoch of the engine
+    // is incremented this often, see `WatchdogConfig::tick`.
+    pub interval: Duration,
+}
+
+impl Default for ProfilingConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(1),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    requested: bool,
+    active: Option<GuestProfiler>,
+    // The JSON profile of the last profiled run, until taken for its record
+    finished: Option<String>,
+}
+
+impl Profiler {
+    // Profiles the next instance, until its call returns. Ignored by the runtimes
+    // without a `ProfilingConfig`.
+    pub fn profile_next_run(&mut self) {
+        self.requested = true;
+    }
+
+    pub(crate) fn start(&mut self, module: &Module, interval: Duration) {
+        if !std::mem::take(&mut self.requested) {
+            return;
+        }
+        let name = module.name().unwrap_or("guest");
+        self.active = Some(GuestProfiler::new(
+            name,
+            interval,
+            vec![(name.to_owned(), module.clone())],
+        ));
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    pub(crate) fn finish(&mut self) {
+        let Some(profiler) = self.active.take() else {
+            return;
+        };
+        let mut json = Vec::new();
+        // A profile that fails to serialize is dropped, the run itself is unaffected
+        if profiler.finish(&mut json).is_ok() {
+            self.finished = String::from_utf8(json).ok();
+        }
+    }
+
+    pub(crate) fn take_profile(&mut self) -> Option<String> {
+        self.finished.take()
+    }
+}
+
+// Samples the stack of the running guest if its run is profiled
+pub(crate) fn sample(store: &mut StoreContextMut<'_, WasmtimeState>) {
+    if let Some(mut profiler) = store.data_mut().profiler.active.take() {
+        profiler.sample(&*store);
+        store.data_mut().profiler.active = Some(profiler);
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+
+use crate::{
+    config::QueueConfig,
+    ledger::{self, EntryKind, LedgerEntry},
+    store::UserStore,
+    BillingError, Error, UserData, UserId,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub from: UserId,
+    // Seconds since the Unix epoch
+    pub sent_at: u64,
+    pub body: Vec<u8>,
+}
+
+// What happens to a new message when the inbox of the recipient is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    // The message is rejected and the sender is not charged
+    #[default]
+    Reject,
+    // The oldest message of the inbox is dropped to make room
+    DropOldest,
+}
+
+// Drops the messages that have been kept longer than the retention period
+fn drop_expired(inbox: &mut VecDeque<Message>, config: &QueueConfig, now: u64) {
+    while inbox
+        .front()
+        .is_some_and(|message| message.sent_at + config.retention_secs <= now)
+    {
+        inbox.pop_front();
+    }
+}
+
+// Queues the message in the inbox of the recipient and charges the sender for it
+pub fn send(
+    users: &mut UserStore,
+    config: &QueueConfig,
+    from: UserId,
+    to: UserId,
+    body: &[u8],
+) -> Result<(), Error> {
+    if body.is_empty() {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    if body.len() > config.max_message_bytes {
+        return Err(BillingError::MessageTooLarge.into());
+    }
+    let now = ledger::now_secs();
+    let inbox = &mut users.get_mut(&to).ok_or(BillingError::UnknownUser)?.inbox;
+    drop_expired(inbox, config, now);
+    let full = inbox.len() >= config.max_messages;
+    if full && config.on_full == Overflow::Reject {
+        return Err(BillingError::QueueFull.into());
+    }
+    let sender = users.get_mut(&from).ok_or(BillingError::UnknownUser)?;
+    let balance = (sender.balance - config.price_per_message)?;
+    sender.balance = balance;
+    if !config.price_per_message.is_zero() {
+        sender.ledger.push(LedgerEntry::new(
+            EntryKind::MessageSent { to },
+            config.price_per_message.checked_neg().unwrap(),
+            balance,
+        ));
+    }
+
+    let inbox = &mut users.get_mut(&to).unwrap().inbox;
+    if full {
+        inbox.pop_front();
+    }
+    inbox.push_back(Message {
+        from,
+        sent_at: now,
+        body: body.to_vec(),
+    });
+    Ok(())
+}
+
+// The oldest message of the inbox that has not expired yet
+pub fn peek<'a>(user_data: &'a mut UserData, config: &QueueConfig) -> Option<&'a Message> {
+    drop_expired(&mut user_data.inbox, config, ledger::now_secs());
+    user_data.inbox.front()
+}
+
+// Drops the expired messages of all the inboxes
+pub(crate) fn drop_all_expired(users: &mut UserStore, config: &QueueConfig) {
+    let now = ledger::now_secs();
+    for (_, user_data) in users.iter_mut() {
+        drop_expired(&mut user_data.inbox, config, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::MoneyUnit;
+
+    const SENDER: UserId = UserId(0);
+    const RECIPIENT: UserId = UserId(1);
+
+    fn users() -> UserStore {
+        let mut users = UserStore::new();
+        users.insert(SENDER, UserData::new(MoneyUnit::from_cents(100)));
+        users.insert(RECIPIENT, UserData::new(MoneyUnit::from_cents(0)));
+        users
+    }
+
+    // Inboxes of two messages
+    fn config(on_full: Overflow) -> QueueConfig {
+        QueueConfig {
+            max_messages: 2,
+            on_full,
+            ..QueueConfig::default()
+        }
+    }
+
+    fn bodies(users: &UserStore) -> Vec<Vec<u8>> {
+        let user_data = users.get(&RECIPIENT).unwrap();
+        user_data.inbox.iter().map(|m| m.body.clone()).collect()
+    }
+
+    #[test]
+    fn messages_are_charged_and_delivered_in_order() {
+        let mut users = users();
+        let config = config(Overflow::DropOldest);
+        for body in [b"a", b"b", b"c"] {
+            send(&mut users, &config, SENDER, RECIPIENT, body).unwrap();
+        }
+        assert_eq!(bodies(&users), [b"b", b"c"]);
+        let sender = users.get(&SENDER).unwrap();
+        assert_eq!(sender.balance, MoneyUnit::from_cents(97));
+        assert_eq!(sender.ledger.len(), 3);
+        drop(sender);
+        let recipient = users.get_mut(&RECIPIENT).unwrap();
+        assert_eq!(peek(recipient, &config).unwrap().from, SENDER);
+    }
+
+    #[test]
+    fn rejected_messages_are_not_charged() {
+        let mut users = users();
+        let config = config(Overflow::Reject);
+        for body in [b"a", b"b"] {
+            send(&mut users, &config, SENDER, RECIPIENT, body).unwrap();
+        }
+        assert!(matches!(
+            send(&mut users, &config, SENDER, RECIPIENT, b"c"),
+            Err(Error::Billing(BillingError::QueueFull))
+        ));
+        assert!(matches!(
+            send(&mut users, &config, SENDER, RECIPIENT, b""),
+            Err(Error::Billing(BillingError::InvalidArgumentValue))
+        ));
+        let large = vec![0; config.max_message_bytes + 1];
+        assert!(matches!(
+            send(&mut users, &config, SENDER, RECIPIENT, &large),
+            Err(Error::Billing(BillingError::MessageTooLarge))
+        ));
+        assert_eq!(bodies(&users), [b"a", b"b"]);
+        assert_eq!(
+            users.get(&SENDER).unwrap().balance,
+            MoneyUnit::from_cents(98)
+        );
+    }
+
+    #[test]
+    fn expired_messages_are_dropped() {
+        let mut users = users();
+        let config = QueueConfig {
+            retention_secs: 0,
+            ..QueueConfig::default()
+        };
+        send(&mut users, &config, SENDER, RECIPIENT, b"a").unwrap();
+        drop_all_expired(&mut users, &config);
+        assert!(bodies(&users).is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+// The storage quota of the users, shared by their objects, see `storage`, and the files in
+// their directories, see `config::FilesConfig`, which together may not exceed
+// `StorageConfig::quota_bytes`. Storing an object that would exceed the quota fails with
+// `BillingError::StorageQuotaExceeded`. The files are written by the guests through WASI
+// and cannot be refused, so they are measured after every invocation instead, and an
+// invocation that grew them beyond the quota fails with the same error. The files it wrote
+// stay in place and keep counting towards the quota until they are deleted.
+//
+// Both are billed by the bytes stored every day, once per billing cycle, see
+// `storage::charge_storage_cycle`.
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{FilesConfig, StorageConfig},
+    storage, BillingError, Error, HostError, State, UserData, UserId,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StorageUsage {
+    pub objects: u64,
+    // As of the end of the last invocation
+    pub files: u64,
+}
+
+impl StorageUsage {
+    pub fn total(&self) -> u64 {
+        self.objects.saturating_add(self.files)
+    }
+}
+
+pub fn usage(user_data: &UserData) -> StorageUsage {
+    StorageUsage {
+        objects: storage::stored_bytes(user_data),
+        files: user_data.file_bytes,
+    }
+}
+
+// The directory of the user, `None` if no directories are configured
+pub fn user_dir(config: &FilesConfig, user: UserId) -> Option<PathBuf> {
+    Some(config.root.as_ref()?.join(user.0.to_string()))
+}
+
+// The bytes of the files under the directory, 0 if it does not exist. Symbolic links are
+// not followed.
+fn dir_bytes(dir: &Path) -> Result<u64, Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(HostError::Storage(e.to_string()).into()),
+    };
+    let mut bytes = 0u64;
+    for entry in entries {
+        let metadata = entry
+            .and_then(|entry| entry.metadata().map(|metadata| (entry.path(), metadata)))
+            .map_err(|e| HostError::Storage(e.to_string()))?;
+        bytes = bytes.saturating_add(match metadata {
+            (path, metadata) if metadata.is_dir() => dir_bytes(&path)?,
+            (_, metadata) if metadata.is_file() => metadata.len(),
+            _ => 0,
+        });
+    }
+    Ok(bytes)
+}
+
+fn quota_exceeded(quota: u64, total: u64) -> Error {
+    Error::from(BillingError::StorageQuotaExceeded)
+        .with_context("limit_bytes", quota as i64)
+        .with_context("used_bytes", total as i64)
+}
+
+// Fails unless the usage of the user, with an object of `replaced` bytes replaced by one of
+// `stored` bytes, fits into the quota
+pub fn check(
+    user_data: &UserData,
+    config: &StorageConfig,
+    replaced: u64,
+    stored: u64,
+) -> Result<(), Error> {
+    let total = usage(user_data)
+        .total()
+        .saturating_sub(replaced)
+        .saturating_add(stored);
+    match total <= config.quota_bytes {
+        true => Ok(()),
+        false => Err(quota_exceeded(config.quota_bytes, total)),
+    }
+}
+
+// Measures the files of the user after an invocation, failing if they have grown beyond
+// the quota
+pub(crate) fn measure_files(state: &mut State, user: UserId) -> Result<(), Error> {
+    let Some(dir) = user_dir(&state.config.files, user) else {
+        return Ok(());
+    };
+    let files = dir_bytes(&dir)?;
+    let quota = state.config.storage.quota_bytes;
+    let Some(user_data) = state.users.get_mut(&user) else {
+        return Ok(());
+    };
+    let grown = files > user_data.file_bytes;
+    user_data.file_bytes = files;
+    let total = usage(user_data).total();
+    match grown && total > quota {
+        true => Err(quota_exceeded(quota, total)),
+        false => Ok(()),
+    }
+}
@@ -0,0 +1,177 @@
+// Reconciliation of the balances with the ledgers, an integrity check run by operators with
+// `reconcile` or periodically by the daemon, see `daemon::DaemonConfig`. The balance of every
+// user is recomputed from the opening balance of the ledger and the amounts of its entries, and
+// every entry is checked to carry the running balance as its `balance_after`.
+//
+// The ledger is the record the balance is repaired from: `repair` sets the balance to the
+// recomputed one and records the stored one in an `EntryKind::Reconciliation` entry of no
+// amount. Entries not carrying the running balance are only reported, the ledger is never
+// rewritten. The opening balance, e.g. the starting balance of an account, has no entry of its
+// own and is taken as the balance before the first entry, or as the closing balance of the
+// last archived month.
+
+use serde::Serialize;
+
+use crate::{
+    ledger::{EntryKind, LedgerEntry},
+    money::MoneyUnit,
+    State, UserData, UserId,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Discrepancy {
+    pub user: UserId,
+    pub stored: MoneyUnit,
+    // `None` if the amounts of the ledger do not add up, e.g. as they overflow
+    pub computed: Option<MoneyUnit>,
+    // The index of the first entry not carrying the running balance, if any
+    pub first_broken_entry: Option<usize>,
+}
+
+impl Discrepancy {
+    // Whether `repair` can set the balance to the one recomputed from the ledger
+    pub fn is_repairable(&self) -> bool {
+        self.computed
+            .is_some_and(|computed| computed != self.stored)
+    }
+}
+
+// The balance before the entries in memory
+fn opening_balance(user_data: &UserData) -> Option<MoneyUnit> {
+    match (user_data.ledger_summaries.last(), user_data.ledger.first()) {
+        (Some(summary), _) => Some(summary.closing_balance),
+        (None, Some(first)) => first.balance_after.sub_allowing_negative(first.amount).ok(),
+        (None, None) => Some(user_data.balance),
+    }
+}
+
+// The discrepancy between the balance of the user and the ledger, if any
+pub fn check(user: UserId, user_data: &UserData) -> Option<Discrepancy> {
+    let mut running = opening_balance(user_data);
+    let mut first_broken_entry = None;
+    for (i, entry) in user_data.ledger.iter().enumerate() {
+        running = running.and_then(|balance| (balance + entry.amount).ok());
+        if first_broken_entry.is_none() && running != Some(entry.balance_after) {
+            first_broken_entry = Some(user_data.archived_entries + i);
+        }
+    }
+    let discrepancy = Discrepancy {
+        user,
+        stored: user_data.balance,
+        computed: running,
+        first_broken_entry,
+    };
+    (running != Some(user_data.balance) || first_broken_entry.is_some()).then_some(discrepancy)
+}
+
+// The discrepancies of all the users, by user
+pub fn reconcile(state: &State) -> Vec<Discrepancy> {
+    let mut discrepancies = state
+        .users
+        .read()
+        .iter()
+        .filter_map(|(&user, user_data)| check(user, user_data))
+        .collect::<Vec<_>>();
+    discrepancies.sort_by_key(|discrepancy| discrepancy.user);
+    discrepancies
+}
+
+// Sets the balances of the repairable discrepancies to the ones recomputed from the ledgers,
+// returning the number of balances repaired. The discrepancies are checked again, so that
+// the balances that changed since they were found are left alone.
+pub fn repair(state: &mut State, discrepancies: &[Discrepancy]) -> usize {
+    let mut repaired = 0;
+    for discrepancy in discrepancies.iter().filter(|d| d.is_repairable()) {
+        let Some(user_data) = state.users.get_mut(&discrepancy.user) else {
+            continue;
+        };
+        let current = check(discrepancy.user, user_data);
+        let Some(computed) = current
+            .filter(|current| current == discrepancy)
+            .and_then(|current| current.computed)
+        else {
+            continue;
+        };
+        user_data.ledger.push(LedgerEntry::new(
+            EntryKind::Reconciliation {
+                previous: user_data.balance,
+            },
+            MoneyUnit::zero(computed.currency()),
+            computed,
+        ));
+        user_data.balance = computed;
+        repaired += 1;
+    }
+    repaired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{host, store::UserStore};
+
+    const USER: UserId = UserId(0);
+
+    // A user with 100.00 who has ordered 10 days of hosting at 1.00 a day
+    fn state() -> State {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(10_000)));
+        let mut state = State::new(users);
+        host::order_hosting(&mut state, USER, 10).unwrap();
+        state
+    }
+
+    #[test]
+    fn drifted_balance_is_repaired_from_the_ledger() {
+        let mut state = state();
+        assert!(reconcile(&state).is_empty());
+        state.users.get_mut(&USER).unwrap().balance = MoneyUnit::from_cents(5_000);
+
+        let discrepancies = reconcile(&state);
+        assert_eq!(
+            discrepancies,
+            [Discrepancy {
+                user: USER,
+                stored: MoneyUnit::from_cents(5_000),
+                computed: Some(MoneyUnit::from_cents(9_000)),
+                first_broken_entry: None,
+            }]
+        );
+        assert_eq!(repair(&mut state, &discrepancies), 1);
+        let user_data = state.users.get(&USER).unwrap();
+        assert_eq!(user_data.balance, MoneyUnit::from_cents(9_000));
+        assert_eq!(
+            user_data.ledger.last().unwrap().kind,
+            EntryKind::Reconciliation {
+                previous: MoneyUnit::from_cents(5_000)
+            }
+        );
+        drop(user_data);
+        assert!(reconcile(&state).is_empty());
+    }
+
+    #[test]
+    fn broken_entries_and_changed_balances_are_not_repaired() {
+        let mut state = state();
+        host::order_hosting(&mut state, USER, 10).unwrap();
+        let user_data = state.users.get_mut(&USER).unwrap();
+        user_data.ledger[1].balance_after = MoneyUnit::from_cents(1);
+        let discrepancies = reconcile(&state);
+        assert_eq!(discrepancies[0].first_broken_entry, Some(1));
+        // The balance agrees with the amounts of the ledger
+        assert!(!discrepancies[0].is_repairable());
+        assert_eq!(repair(&mut state, &discrepancies), 0);
+
+        // A balance that changed after the discrepancy was found is left alone
+        let user_data = state.users.get_mut(&USER).unwrap();
+        user_data.ledger[1].balance_after = MoneyUnit::from_cents(8_000);
+        user_data.balance = MoneyUnit::from_cents(1);
+        let discrepancies = reconcile(&state);
+        state.users.get_mut(&USER).unwrap().balance = MoneyUnit::from_cents(2);
+        assert_eq!(repair(&mut state, &discrepancies), 0);
+        assert_eq!(
+            state.users.get(&USER).unwrap().balance,
+            MoneyUnit::from_cents(2)
+        );
+    }
+}
@@ -0,0 +1,374 @@
+// Reloads the prices, quotas and policy while the host runs, e.g. on SIGHUP in the daemon. The
+// new values are read from a file like
+//
+// ```text
+// price service hosting = 6.00
+// price call send_email = 0.02 EUR
+// quota storage_bytes = 20000000000
+// quota email_daily = 200
+// quota tickets paid = 10
+// quota concurrency trial = 2
+// policy deny order when plan = trial and daily_spend > 100.00
+// ```
+//
+// with a setting per line, `#` starts a comment. The amounts are in USD unless a currency
+// follows them. The settings left out keep their values, except that the `policy` lines, if any,
+// replace the whole policy, see `policy` for their grammar.
+// A file is staged as a whole: a line that does not parse, names an unknown service or host
+// function or sets a negative price fails it with `BillingError::InvalidConfig`, with the number
+// of the line as context, and nothing is staged. The staged values are swapped in at once by
+// `apply_staged`, which `history` calls before every invocation, so that an invocation runs to
+// its end under the configuration it started with. The changes are reported to
+// `State::config_listener`.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    concurrency::ConcurrencyConfig,
+    config::{Config, TicketConfig},
+    host::HOST_FUNCTIONS,
+    money::{Currency, MoneyUnit},
+    policy::Policy,
+    BillingError, Error, State,
+};
+
+pub trait ConfigListener {
+    fn on_reload(&mut self, changes: &[ConfigChange]);
+}
+
+pub struct NoopConfigListener;
+
+impl ConfigListener for NoopConfigListener {
+    fn on_reload(&mut self, _changes: &[ConfigChange]) {}
+}
+
+// A setting changed by a reload, e.g. `price service hosting` from `5.00 USD` to `6.00 USD`.
+// The rules of the policy are added or removed, their sources being the values.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigChange {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let old = self.old.as_deref().unwrap_or("(none)");
+        let new = self.new.as_deref().unwrap_or("(none)");
+        write!(f, "{}: {old} -> {new}", self.key)
+    }
+}
+
+// The part of `Config` that can be reloaded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StagedConfig {
+    service_prices: BTreeMap<String, MoneyUnit>,
+    call_prices: BTreeMap<String, MoneyUnit>,
+    storage_quota_bytes: u64,
+    email_daily_quota: u32,
+    tickets: [u32; 4],
+    concurrency: [u32; 4],
+    policy: Policy,
+}
+
+const PLANS: [&str; 4] = ["trial", "paid", "grace", "suspended"];
+
+fn plan_index(plan: &str) -> Option<usize> {
+    PLANS.iter().position(|name| *name == plan)
+}
+
+impl StagedConfig {
+    fn of(config: &Config) -> Self {
+        let TicketConfig {
+            trial,
+            paid,
+            grace,
+            suspended,
+        } = config.tickets;
+        let tickets = [trial, paid, grace, suspended];
+        let ConcurrencyConfig {
+            trial,
+            paid,
+            grace,
+            suspended,
+            ..
+        } = config.concurrency;
+        Self {
+            service_prices: (config.catalog.services.iter())
+                .map(|(name, service)| (name.clone(), service.price))
+                .collect(),
+            call_prices: config.call_pricing.prices.clone(),
+            storage_quota_bytes: config.storage.quota_bytes,
+            email_daily_quota: config.email.daily_quota,
+            tickets,
+            concurrency: [trial, paid, grace, suspended],
+            policy: config.policy.clone(),
+        }
+    }
+
+    // The services removed from the catalog since the staging keep their prices
+    fn write(self, config: &mut Config) {
+        for (name, price) in self.service_prices {
+            if let Some(service) = config.catalog.services.get_mut(&name) {
+                service.price = price;
+            }
+        }
+        config.call_pricing.prices = self.call_prices;
+        config.storage.quota_bytes = self.storage_quota_bytes;
+        config.email.daily_quota = self.email_daily_quota;
+        let [trial, paid, grace, suspended] = self.tickets;
+        config.tickets = TicketConfig {
+            trial,
+            paid,
+            grace,
+            suspended,
+        };
+        let [trial, paid, grace, suspended] = self.concurrency;
+        config.concurrency = ConcurrencyConfig {
+            trial,
+            paid,
+            grace,
+            suspended,
+            ..config.concurrency
+        };
+        config.policy = self.policy;
+    }
+
+    // Applies a line other than a `policy` one, `None` if it is invalid
+    fn set(&mut self, words: &[&str]) -> Option<()> {
+        match words {
+            ["price", "service", name, "=", amount @ ..] => {
+                *self.service_prices.get_mut(*name)? = parse_price(amount)?;
+            }
+            ["price", "call", name, "=", amount @ ..] => {
+                HOST_FUNCTIONS.iter().find(|f| f.name == *name)?;
+                let price = parse_price(amount)?;
+                self.call_prices.insert(name.to_string(), price);
+            }
+            ["quota", "storage_bytes", "=", value] => {
+                self.storage_quota_bytes = value.parse().ok()?;
+            }
+            ["quota", "email_daily", "=", value] => self.email_daily_quota = value.parse().ok()?,
+            ["quota", "tickets", plan, "=", value] => {
+                self.tickets[plan_index(plan)?] = value.parse().ok()?;
+            }
+            ["quota", "concurrency", plan, "=", value] => {
+                self.concurrency[plan_index(plan)?] = value.parse().ok()?;
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    // The changes from `old` to `self`, in the order of the keys
+    fn diff(&self, old: &Self) -> Vec<ConfigChange> {
+        let mut changes = Vec::new();
+        let mut change = |key: String, old: Option<String>, new: Option<String>| {
+            if old != new {
+                changes.push(ConfigChange { key, old, new });
+            }
+        };
+        let prices = [
+            ("service", &old.service_prices, &self.service_prices),
+            ("call", &old.call_prices, &self.call_prices),
+        ];
+        for (kind, old, new) in prices {
+            let mut names = old.keys().chain(new.keys()).collect::<Vec<_>>();
+            names.sort();
+            names.dedup();
+            for name in names {
+                let price = |prices: &BTreeMap<String, MoneyUnit>| {
+                    prices.get(name).map(MoneyUnit::to_string)
+                };
+                change(format!("price {kind} {name}"), price(old), price(new));
+            }
+        }
+        change(
+            "quota storage_bytes".to_owned(),
+            Some(old.storage_quota_bytes.to_string()),
+            Some(self.storage_quota_bytes.to_string()),
+        );
+        change(
+            "quota email_daily".to_owned(),
+            Some(old.email_daily_quota.to_string()),
+            Some(self.email_daily_quota.to_string()),
+        );
+        for (i, plan) in PLANS.iter().enumerate() {
+            change(
+                format!("quota tickets {plan}"),
+                Some(old.tickets[i].to_string()),
+                Some(self.tickets[i].to_string()),
+            );
+        }
+        for (i, plan) in PLANS.iter().enumerate() {
+            change(
+                format!("quota concurrency {plan}"),
+                Some(old.concurrency[i].to_string()),
+                Some(self.concurrency[i].to_string()),
+            );
+        }
+        let sources = |policy: &Policy| {
+            (policy.rules().iter())
+                .map(|rule| rule.source.clone())
+                .collect::<Vec<_>>()
+        };
+        let (old_rules, new_rules) = (sources(&old.policy), sources(&self.policy));
+        for rule in old_rules.iter().filter(|rule| !new_rules.contains(rule)) {
+            change("policy".to_owned(), Some(rule.clone()), None);
+        }
+        for rule in new_rules.iter().filter(|rule| !old_rules.contains(rule)) {
+            change("policy".to_owned(), None, Some(rule.clone()));
+        }
+        changes
+    }
+}
+
+// An amount like `0.02` or `0.02 EUR`, not negative
+fn parse_price(words: &[&str]) -> Option<MoneyUnit> {
+    let (value, currency) = match words {
+        [value] => (*value, Currency::USD),
+        [value, code] => (*value, Currency::from_code(code)?),
+        _ => return None,
+    };
+    MoneyUnit::parse(value, currency)
+        .ok()
+        .filter(|price| !price.is_negative())
+}
+
+// Parses and validates the file against the configuration, see the format above
+pub fn parse(config: &Config, text: &str) -> Result<StagedConfig, Error> {
+    let mut staged = StagedConfig::of(config);
+    let mut rules = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let invalid =
+            || Error::from(BillingError::InvalidConfig).with_context("line", i as i64 + 1);
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.strip_prefix("policy ") {
+            Some(rule) => {
+                Policy::parse(rule).map_err(|_| invalid())?;
+                rules.push(rule);
+            }
+            None => {
+                let words = line.split_whitespace().collect::<Vec<_>>();
+                staged.set(&words).ok_or_else(invalid)?;
+            }
+        }
+    }
+    if !rules.is_empty() {
+        staged.policy = Policy::parse(&rules.join("\n"))?;
+    }
+    Ok(staged)
+}
+
+// Stages the file in place of the one staged before, if any, returning the changes it would make
+// to the current configuration
+pub fn stage(state: &mut State, text: &str) -> Result<Vec<ConfigChange>, Error> {
+    let staged = parse(&state.config, text)?;
+    let changes = staged.diff(&StagedConfig::of(&state.config));
+    state.staged_config = Some(staged);
+    Ok(changes)
+}
+
+// Swaps the staged configuration in, if any, returning the changes it made. Never called during
+// an invocation.
+pub fn apply_staged(state: &mut State) -> Vec<ConfigChange> {
+    let Some(staged) = state.staged_config.take() else {
+        return Vec::new();
+    };
+    let changes = staged.diff(&StagedConfig::of(&state.config));
+    staged.write(&mut state.config);
+    if !changes.is_empty() {
+        state.config_listener.on_reload(&changes);
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{services::Service, store::UserStore};
+
+    // Keeps the changes reported to it
+    struct Recorder(Arc<Mutex<Vec<ConfigChange>>>);
+
+    impl ConfigListener for Recorder {
+        fn on_reload(&mut self, changes: &[ConfigChange]) {
+            self.0.lock().unwrap().extend_from_slice(changes);
+        }
+    }
+
+    // A catalog of a single service at 1.00, with the changes reported to the listener
+    fn state() -> (State, Arc<Mutex<Vec<ConfigChange>>>) {
+        let mut state = State::new(UserStore::new());
+        let service = Service {
+            price: MoneyUnit::from_cents(100),
+            depends_on: Vec::new(),
+            sla: None,
+        };
+        state
+            .config
+            .catalog
+            .services
+            .insert("web".to_owned(), service);
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        state.config_listener = Box::new(Recorder(reported.clone()));
+        (state, reported)
+    }
+
+    #[test]
+    fn staged_file_is_applied_at_once() {
+        let (mut state, reported) = state();
+        let text = "# New prices\n\
+                    price service web = 2.00\n\
+                    quota email_daily = 200\n\
+                    quota concurrency trial = 2\n";
+        let changes = stage(&mut state, text).unwrap();
+        assert_eq!(
+            changes
+                .iter()
+                .map(ConfigChange::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "price service web: 1.00 USD -> 2.00 USD",
+                "quota email_daily: 100 -> 200",
+                "quota concurrency trial: 1 -> 2",
+            ]
+        );
+        assert_eq!(state.config.email.daily_quota, 100);
+        assert!(reported.lock().unwrap().is_empty());
+
+        assert_eq!(apply_staged(&mut state), changes);
+        assert_eq!(*reported.lock().unwrap(), changes);
+        assert_eq!(state.config.email.daily_quota, 200);
+        assert_eq!(state.config.concurrency.trial, 2);
+        assert_eq!(
+            state.config.catalog.services["web"].price,
+            MoneyUnit::from_cents(200)
+        );
+        assert!(apply_staged(&mut state).is_empty());
+    }
+
+    #[test]
+    fn invalid_line_fails_the_whole_file() {
+        let (mut state, _) = state();
+        let invalid = [
+            ("quota email_daily = 200\nprice service unknown = 1.00", 2),
+            ("price call no_such_function = 0.01", 1),
+            ("\nprice service web = -1.00", 2),
+            ("quota tickets nobody = 3", 1),
+        ];
+        for (text, line) in invalid {
+            let error = stage(&mut state, text).unwrap_err();
+            assert_eq!(error.code(), BillingError::InvalidConfig.code());
+            assert_eq!(error.context(), [("line", line)]);
+        }
+        assert!(state.staged_config.is_none());
+        assert!(apply_staged(&mut state).is_empty());
+        assert_eq!(state.config.email.daily_quota, 100);
+    }
+}
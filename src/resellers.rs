@@ -0,0 +1,407 @@
+// Resellers are users with `Capability::Reseller` who create sub-accounts under their own
+// account, move money between their balance and the sub-accounts', and mark up the catalog
+// prices their sub-accounts pay for bundles, see `services`. The markup is charged to the
+// sub-account as an `EntryKind::ResellerMarkup` entry next to the order and credited to the
+// reseller, and unlike the order it is not refunded by a cancellation. Sub-accounts may be
+// resellers themselves, and `tree_billing` adds up the billing of the whole tree below one.
+
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+
+use crate::{
+    capability::Capability,
+    ledger::{EntryKind, LedgerEntry},
+    money::{MoneySum, MoneyUnit},
+    policy::{self, Action},
+    sharded_store::UserRef,
+    store::UserStore,
+    BillingError, Error, State, UserData, UserId,
+};
+
+// A markup may at most double the catalog prices
+pub const MAX_MARKUP_BASIS_POINTS: u32 = 10_000;
+
+fn check_reseller(users: &UserStore, reseller: UserId) -> Result<UserRef<'_>, Error> {
+    let reseller_data = users.get(&reseller).ok_or(BillingError::UnknownUser)?;
+    match reseller_data.capabilities.contains(&Capability::Reseller) {
+        true => Ok(reseller_data),
+        false => Err(BillingError::MissingCapability.into()),
+    }
+}
+
+// The data of the sub-account, failing unless it is one of the reseller's
+pub fn sub_account(
+    users: &UserStore,
+    reseller: UserId,
+    account: UserId,
+) -> Result<UserRef<'_>, Error> {
+    check_reseller(users, reseller)?;
+    match users.get(&account) {
+        Some(account_data) if account_data.reseller == Some(reseller) => Ok(account_data),
+        _ => Err(BillingError::NotSubAccount.into()),
+    }
+}
+
+// The sub-accounts of the reseller, in the order of their ids
+pub fn sub_accounts(users: &UserStore, reseller: UserId) -> Vec<UserId> {
+    users
+        .read()
+        .iter()
+        .filter(|(_, user_data)| user_data.reseller == Some(reseller))
+        .map(|(&user, _)| user)
+        .collect()
+}
+
+// Moves the amount between the balances of the reseller and the sub-account, which have been
+// checked to cover it, recording it as funding of the sub-account, negative when the money
+// goes back to the reseller
+fn apply_funds(
+    state: &mut State,
+    reseller: UserId,
+    account: UserId,
+    amount: MoneyUnit,
+    to_reseller: bool,
+) {
+    let (credit, debit) = (amount, amount.checked_neg().unwrap());
+    let (reseller_amount, account_amount) = match to_reseller {
+        true => (credit, debit),
+        false => (debit, credit),
+    };
+    let reseller_data = state.users.get_mut(&reseller).unwrap();
+    reseller_data.balance = (reseller_data.balance + reseller_amount).unwrap();
+    let balance = reseller_data.balance;
+    reseller_data.ledger.push(LedgerEntry::new(
+        EntryKind::SubAccountFunding { account },
+        reseller_amount,
+        balance,
+    ));
+    let account_data = state.users.get_mut(&account).unwrap();
+    account_data.balance = (account_data.balance + account_amount).unwrap();
+    let balance = account_data.balance;
+    account_data.ledger.push(LedgerEntry::new(
+        EntryKind::ResellerFunding { reseller },
+        account_amount,
+        balance,
+    ));
+}
+
+// Checks that `from` can pay the positive amount to `to` and that the reseller may move it
+fn check_funds(
+    state: &mut State,
+    reseller: UserId,
+    from: UserId,
+    to: UserId,
+    amount: MoneyUnit,
+) -> Result<(), Error> {
+    if amount.is_negative() || amount.is_zero() {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    (state
+        .users
+        .get(&from)
+        .ok_or(BillingError::UnknownUser)?
+        .balance
+        - amount)?;
+    if let Some(to_data) = state.users.get(&to) {
+        (to_data.balance + amount)?;
+    }
+    policy::authorize(state, reseller, Action::Transfer, amount)
+}
+
+// Creates a sub-account of the reseller in the reseller's currency, funded with the amount
+// from the reseller's balance unless it is zero
+pub fn create_sub_account(
+    state: &mut State,
+    reseller: UserId,
+    funding: MoneyUnit,
+) -> Result<UserId, Error> {
+    let currency = check_reseller(&state.users, reseller)?.balance.currency();
+    if funding.is_negative() || funding.currency() != currency {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    let account = state.users.next_id()?;
+    if !funding.is_zero() {
+        check_funds(state, reseller, reseller, account, funding)?;
+    }
+    let zero = MoneyUnit::zero(funding.currency());
+    let account_data = state.users.create_user(account, zero, &state.config)?;
+    account_data.reseller = Some(reseller);
+    if !funding.is_zero() {
+        apply_funds(state, reseller, account, funding, false);
+    }
+    Ok(account)
+}
+
+// Moves the amount from the reseller's balance to the sub-account's
+pub fn fund(
+    state: &mut State,
+    reseller: UserId,
+    account: UserId,
+    amount: MoneyUnit,
+) -> Result<(), Error> {
+    sub_account(&state.users, reseller, account)?;
+    check_funds(state, reseller, reseller, account, amount)?;
+    apply_funds(state, reseller, account, amount, false);
+    Ok(())
+}
+
+// Moves the amount from the sub-account's balance back to the reseller's
+pub fn withdraw(
+    state: &mut State,
+    reseller: UserId,
+    account: UserId,
+    amount: MoneyUnit,
+) -> Result<(), Error> {
+    sub_account(&state.users, reseller, account)?;
+    check_funds(state, reseller, account, reseller, amount)?;
+    apply_funds(state, reseller, account, amount, true);
+    Ok(())
+}
+
+// Sets the markup on the catalog prices the sub-accounts of the reseller pay, in basis points
+pub fn set_markup(state: &mut State, reseller: UserId, basis_points: u32) -> Result<(), Error> {
+    check_reseller(&state.users, reseller)?;
+    if basis_points > MAX_MARKUP_BASIS_POINTS {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    state.users.get_mut(&reseller).unwrap().markup_basis_points = basis_points;
+    Ok(())
+}
+
+// The reseller of the user and the markup it takes on the price, rounded towards zero,
+// `None` if the user is not a sub-account or the markup is zero
+pub fn markup(
+    users: &UserStore,
+    user: UserId,
+    price: MoneyUnit,
+) -> Result<Option<(UserId, MoneyUnit)>, Error> {
+    let Some(reseller) = users.get(&user).and_then(|user_data| user_data.reseller) else {
+        return Ok(None);
+    };
+    let basis_points = users
+        .get(&reseller)
+        .map_or(0, |reseller_data| reseller_data.markup_basis_points);
+    let markup = price
+        .basis_points(basis_points)
+        .ok_or(BillingError::TotalCostExceededMaxValue)?;
+    Ok(Some((reseller, markup)).filter(|_| !markup.is_zero()))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AccountBilling {
+    pub user: UserId,
+    // The reseller of the account
+    pub reseller: UserId,
+    // 1 for the sub-accounts of the reseller the tree is of, 2 for theirs and so on
+    pub depth: u32,
+    pub balance: MoneyUnit,
+    // The billable charges net of the discounts in the ledger, not counting the archived entries
+    pub charged: MoneyUnit,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TreeBilling {
+    // Depth first, each account followed by its own sub-accounts
+    pub accounts: Vec<AccountBilling>,
+    pub total_balance: MoneyUnit,
+    pub total_charged: MoneyUnit,
+}
+
+fn charged(user_data: &UserData) -> Result<MoneyUnit, Error> {
+    user_data
+        .ledger
+        .iter()
+        .filter(|entry| entry.kind.is_billable())
+        .map(|entry| entry.amount.checked_neg())
+        .collect::<Option<Vec<_>>>()
+        .ok_or(BillingError::TotalCostExceededMaxValue)?
+        .into_iter()
+        .checked_sum(user_data.balance.currency())
+}
+
+// The billing of every account in the tree of sub-accounts below the reseller
+pub fn tree_billing(state: &State, reseller: UserId) -> Result<TreeBilling, Error> {
+    let currency = check_reseller(&state.users, reseller)?.balance.currency();
+    let mut accounts = Vec::new();
+    let mut visited = BTreeSet::from([reseller]);
+    let mut stack = sub_accounts(&state.users, reseller)
+        .into_iter()
+        .rev()
+        .map(|account| (account, reseller, 1))
+        .collect::<Vec<_>>();
+    while let Some((user, parent, depth)) = stack.pop() {
+        if !visited.insert(user) {
+            continue;
+        }
+        let user_data = state.users.get(&user).unwrap();
+        accounts.push(AccountBilling {
+            user,
+            reseller: parent,
+            depth,
+            balance: user_data.balance,
+            charged: charged(&user_data)?,
+        });
+        stack.extend(
+            sub_accounts(&state.users, user)
+                .into_iter()
+                .rev()
+                .map(|account| (account, user, depth + 1)),
+        );
+    }
+    Ok(TreeBilling {
+        total_balance: accounts.iter().map(|a| a.balance).checked_sum(currency)?,
+        total_charged: accounts.iter().map(|a| a.charged).checked_sum(currency)?,
+        accounts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        host,
+        services::{Bundle, Service},
+    };
+
+    const RESELLER: UserId = UserId(0);
+
+    // A reseller with 1000.00 and a bundle of a single service at 100.00
+    fn state() -> State {
+        let mut users = UserStore::new();
+        let mut reseller_data = UserData::new(MoneyUnit::from_cents(100_000));
+        reseller_data.capabilities.insert(Capability::Reseller);
+        users.insert(RESELLER, reseller_data);
+        let mut state = State::new(users);
+        let catalog = &mut state.config.catalog;
+        let service = Service {
+            price: MoneyUnit::from_cents(10_000),
+            depends_on: Vec::new(),
+            sla: None,
+        };
+        catalog.services.insert("web".to_owned(), service);
+        let bundle = Bundle {
+            services: vec!["web".to_owned()],
+        };
+        catalog.bundles.insert("starter".to_owned(), bundle);
+        state
+    }
+
+    fn balance(state: &State, user: UserId) -> MoneyUnit {
+        state.users.get(&user).unwrap().balance
+    }
+
+    #[test]
+    fn markup_is_charged_to_the_sub_account_and_credited_to_the_reseller() {
+        let mut state = state();
+        let account = create_sub_account(&mut state, RESELLER, MoneyUnit::from_cents(20_000));
+        let account = account.unwrap();
+        set_markup(&mut state, RESELLER, 2_000).unwrap();
+        host::order_bundle(&mut state, account, "starter").unwrap();
+        assert_eq!(balance(&state, account), MoneyUnit::from_cents(8_000));
+        assert_eq!(balance(&state, RESELLER), MoneyUnit::from_cents(82_000));
+        let account_entry = state
+            .users
+            .get(&account)
+            .unwrap()
+            .ledger
+            .last()
+            .cloned()
+            .unwrap();
+        assert_eq!(
+            account_entry.kind,
+            EntryKind::ResellerMarkup { reseller: RESELLER }
+        );
+        assert_eq!(account_entry.amount, MoneyUnit::from_cents(-2_000));
+        let reseller_entry = state
+            .users
+            .get(&RESELLER)
+            .unwrap()
+            .ledger
+            .last()
+            .cloned()
+            .unwrap();
+        assert_eq!(reseller_entry.kind, EntryKind::MarkupEarned { account });
+        assert_eq!(reseller_entry.amount, MoneyUnit::from_cents(2_000));
+    }
+
+    #[test]
+    fn bundle_without_funds_for_the_markup_is_refused() {
+        let mut state = state();
+        let account = create_sub_account(&mut state, RESELLER, MoneyUnit::from_cents(10_000));
+        let account = account.unwrap();
+        set_markup(&mut state, RESELLER, 1_000).unwrap();
+        assert!(host::order_bundle(&mut state, account, "starter").is_err());
+        assert_eq!(balance(&state, account), MoneyUnit::from_cents(10_000));
+        assert_eq!(balance(&state, RESELLER), MoneyUnit::from_cents(90_000));
+    }
+
+    #[test]
+    fn funds_are_checked_on_both_sides() {
+        let mut state = state();
+        let account = create_sub_account(&mut state, RESELLER, MoneyUnit::from_cents(0));
+        let account = account.unwrap();
+        assert!(fund(
+            &mut state,
+            RESELLER,
+            account,
+            MoneyUnit::from_cents(100_001)
+        )
+        .is_err());
+        fund(&mut state, RESELLER, account, MoneyUnit::from_cents(5_000)).unwrap();
+        assert!(withdraw(&mut state, RESELLER, account, MoneyUnit::from_cents(5_001)).is_err());
+        withdraw(&mut state, RESELLER, account, MoneyUnit::from_cents(1_000)).unwrap();
+        assert_eq!(balance(&state, account), MoneyUnit::from_cents(4_000));
+        assert_eq!(balance(&state, RESELLER), MoneyUnit::from_cents(96_000));
+        assert!(matches!(
+            set_markup(&mut state, RESELLER, MAX_MARKUP_BASIS_POINTS + 1),
+            Err(Error::Billing(BillingError::InvalidArgumentValue))
+        ));
+
+        // Neither a reseller nor the reseller of the account
+        assert!(matches!(
+            fund(&mut state, account, RESELLER, MoneyUnit::from_cents(1)),
+            Err(Error::Billing(BillingError::MissingCapability))
+        ));
+        state
+            .users
+            .get_mut(&account)
+            .unwrap()
+            .capabilities
+            .insert(Capability::Reseller);
+        assert!(matches!(
+            fund(&mut state, account, RESELLER, MoneyUnit::from_cents(1)),
+            Err(Error::Billing(BillingError::NotSubAccount))
+        ));
+    }
+
+    #[test]
+    fn tree_billing_covers_every_level_and_stops_at_cycles() {
+        let mut state = state();
+        let funding = MoneyUnit::from_cents(10_000);
+        let child = create_sub_account(&mut state, RESELLER, funding).unwrap();
+        let user_data = state.users.get_mut(&child).unwrap();
+        user_data.capabilities.insert(Capability::Reseller);
+        let grandchild = create_sub_account(&mut state, child, funding).unwrap();
+        host::order_bundle(&mut state, grandchild, "starter").unwrap();
+
+        let tree = tree_billing(&state, RESELLER).unwrap();
+        let levels = tree
+            .accounts
+            .iter()
+            .map(|account| (account.user, account.reseller, account.depth))
+            .collect::<Vec<_>>();
+        assert_eq!(levels, [(child, RESELLER, 1), (grandchild, child, 2)]);
+        assert_eq!(tree.total_charged, MoneyUnit::from_cents(10_000));
+        assert_eq!(tree.total_balance, MoneyUnit::from_cents(0));
+
+        // The reseller becomes a sub-account of its own sub-account
+        state.users.get_mut(&RESELLER).unwrap().reseller = Some(grandchild);
+        let cyclic = tree_billing(&state, RESELLER).unwrap();
+        assert_eq!(cyclic.accounts, tree.accounts);
+        let tree = tree_billing(&state, child).unwrap();
+        let users = tree.accounts.iter().map(|a| a.user).collect::<Vec<_>>();
+        assert_eq!(users, [grandchild, RESELLER]);
+    }
+}
@@ -0,0 +1,231 @@
+// Caching of the results of the pure exports, e.g. of the modules quoting prices or rendering
+// reports that are called again and again with the same input. A module marks its exports as
+// pure with a custom section named `wsm.pure` listing their names separated by whitespace, e.g.
+// `(@custom "wsm.pure" "quote report")` in the text format. With `ResultCacheConfig::ttl_secs`,
+// the successful results of the pure exports are kept by the module, the user, the export and
+// the input of the guest, see `history::execute_with_input`, and the invocations with the same
+// key are answered from the cache until the results expire, without instantiating the module.
+//
+// The pure exports run read-only: their calls of the mutating host functions, including the
+// ones charging a read like `host.storage_get`, trap with `BillingError::ReadOnlyExport`, so
+// that the invocation fails and nothing is cached. What they read, e.g. the balance, may change
+// before their cached results expire. The hits are recorded in the history of the user like the
+// other invocations, but use no fuel and are not charged.
+
+use std::collections::{BTreeSet, HashMap};
+
+use wasmparser::{Parser, Payload};
+
+use crate::{ledger, module_hash, sha256_hex, UserId};
+
+// The name of the custom section listing the pure exports
+pub const PURE_SECTION: &str = "wsm.pure";
+
+#[derive(Clone, Copy, Debug)]
+pub struct ResultCacheConfig {
+    // How long the results are kept, 0 disables the cache
+    pub ttl_secs: u64,
+    // The results kept at most, the ones expiring first are dropped to make room
+    pub max_entries: usize,
+}
+
+impl Default for ResultCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 0,
+            max_entries: 1024,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    // Invocations answered from the cache
+    pub hits: u64,
+    // Invocations of the pure exports that ran
+    pub misses: u64,
+}
+
+// The module hash, the user, the export and the hash of the input
+pub(crate) type CacheKey = (String, UserId, String, String);
+
+#[derive(Default)]
+pub struct ResultCache {
+    // The results with their expiry in seconds since the Unix epoch
+    entries: HashMap<CacheKey, (i64, u64)>,
+    stats: CacheStats,
+}
+
+// The exports the module, in the binary or the text format, marks as pure. Modules that fail to
+// parse have none.
+pub fn pure_exports(bytes: &[u8]) -> BTreeSet<String> {
+    let Ok(bytes) = wat::parse_bytes(bytes) else {
+        return BTreeSet::new();
+    };
+    let mut exports = BTreeSet::new();
+    for payload in Parser::new(0).parse_all(&bytes) {
+        match payload {
+            Ok(Payload::CustomSection(section)) if section.name() == PURE_SECTION => {
+                let names = String::from_utf8_lossy(section.data());
+                exports.extend(names.split_whitespace().map(str::to_owned));
+            }
+            Ok(_) => {}
+            Err(_) => return BTreeSet::new(),
+        }
+    }
+    exports
+}
+
+impl ResultCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Drops every result, e.g. after the data the pure exports read has changed
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub(crate) fn key(bytes: &[u8], user: UserId, export: &str, input: &[u8]) -> CacheKey {
+        (
+            module_hash(bytes),
+            user,
+            export.to_owned(),
+            sha256_hex(input),
+        )
+    }
+
+    // The result of the invocation if it has not expired, counting the lookup in the stats
+    pub(crate) fn get(&mut self, key: &CacheKey) -> Option<i64> {
+        let now = ledger::now_secs();
+        let result = match self.entries.get(key) {
+            Some(&(result, expires_at)) if now < expires_at => Some(result),
+            Some(_) => {
+                self.entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        match result {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        result
+    }
+
+    pub(crate) fn insert(&mut self, key: CacheKey, result: i64, config: ResultCacheConfig) {
+        if config.ttl_secs == 0 || config.max_entries == 0 {
+            return;
+        }
+        let now = ledger::now_secs();
+        if self.entries.len() >= config.max_entries && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, (_, expires_at)| now < *expires_at);
+        }
+        while self.entries.len() >= config.max_entries && !self.entries.contains_key(&key) {
+            let first = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, expires_at))| *expires_at)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            self.entries.remove(&first);
+        }
+        let expires_at = now.saturating_add(config.ttl_secs);
+        self.entries.insert(key, (result, expires_at));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        history,
+        money::MoneyUnit,
+        queues::Message,
+        runtime::{WasmRuntime, WasmtimeRuntime},
+        store::UserStore,
+        BillingError, Error, State, UserData,
+    };
+
+    const USER: UserId = UserId(0);
+
+    // `quote` returns the balance, `poll` consumes a message of the inbox
+    const MODULE: &str = r#"
+        (module
+            (@custom "wsm.pure" "quote poll")
+            (import "host" "balance" (func $balance (result i64)))
+            (import "host" "queue_poll" (func $queue_poll (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "quote") (result i64) (call $balance))
+            (func (export "poll") (result i64)
+                (i64.extend_i32_s (call $queue_poll (i32.const 0) (i32.const 64)))))
+    "#;
+
+    // A user with 100.00 and a message in the inbox, with the results cached for an hour
+    fn state() -> State {
+        let mut users = UserStore::new();
+        let mut user_data = UserData::new(MoneyUnit::from_cents(10_000));
+        user_data.inbox.push_back(Message {
+            from: USER,
+            sent_at: ledger::now_secs(),
+            body: b"hello".to_vec(),
+        });
+        users.insert(USER, user_data);
+        let mut state = State::new(users);
+        state.config.result_cache.ttl_secs = 3600;
+        state
+    }
+
+    #[test]
+    fn pure_exports_are_marked_by_the_custom_section() {
+        let exports = pure_exports(MODULE.as_bytes());
+        let exports = exports.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(exports, ["poll", "quote"]);
+        assert!(pure_exports(b"(module").is_empty());
+    }
+
+    #[test]
+    fn repeated_invocations_are_answered_from_the_cache() {
+        let runtime = WasmtimeRuntime::new();
+        let mut store = runtime.new_store(state());
+        let bytes = MODULE.as_bytes();
+        let first = history::execute_with_input(&runtime, &mut store, USER, bytes, "quote", b"");
+        assert_eq!(first.unwrap(), 10_000);
+        store.data_mut().users.get_mut(&USER).unwrap().balance = MoneyUnit::from_cents(5_000);
+        let second = history::execute_with_input(&runtime, &mut store, USER, bytes, "quote", b"");
+        assert_eq!(second.unwrap(), 10_000);
+        let other_input =
+            history::execute_with_input(&runtime, &mut store, USER, bytes, "quote", b"x");
+        assert_eq!(other_input.unwrap(), 5_000);
+        let stats = store.data().result_cache.stats();
+        assert_eq!(stats, CacheStats { hits: 1, misses: 2 });
+    }
+
+    #[test]
+    fn pure_export_consuming_a_message_traps() {
+        let runtime = WasmtimeRuntime::new();
+        let mut store = runtime.new_store(state());
+        let bytes = MODULE.as_bytes();
+        let result = history::execute_with_input(&runtime, &mut store, USER, bytes, "poll", b"");
+        assert!(matches!(
+            result,
+            Err(Error::Billing(BillingError::ReadOnlyExport))
+        ));
+        let state = store.data();
+        assert_eq!(state.users.get(&USER).unwrap().inbox.len(), 1);
+        assert!(state.result_cache.is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+// The data of the services deprovisioned by a cancellation, see `cancellation`, is kept for
+// `RetentionConfig::days`, during which the user may export it with
+// `host.export_service_data` or `export`. Once the window ends, the data is deleted through
+// `Provisioner::delete_data` and the deletion is recorded in a tamper-evident audit log, see
+// `audit`, e.g. to prove the erasure for compliance.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    audit::AuditLog,
+    ledger,
+    orders::{self, OrderId},
+    BillingError, Error, State, UserId,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetentionConfig {
+    // Days the data of a deprovisioned service is kept before it is deleted
+    pub days: u32,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self { days: 30 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetainedService {
+    // Seconds since the Unix epoch
+    pub deprovisioned_at: u64,
+    pub days_until_deletion: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DataDeletion {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    pub user: UserId,
+    pub service: String,
+    // The cancelled order that deprovisioned the service
+    pub order: u64,
+    pub deprovisioned_at: u64,
+}
+
+#[derive(Default)]
+pub struct RetainedData {
+    services: BTreeMap<(UserId, OrderId, String), RetainedService>,
+    audit_log: AuditLog<DataDeletion>,
+}
+
+impl RetainedData {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, user: UserId, order: OrderId, service: &str) -> Option<&RetainedService> {
+        self.services.get(&(user, order, service.to_owned()))
+    }
+
+    // The retained services of the user with the orders that deprovisioned them
+    pub fn of_user(&self, user: UserId) -> impl Iterator<Item = (OrderId, &str, &RetainedService)> {
+        self.services
+            .iter()
+            .filter(move |((owner, _, _), _)| *owner == user)
+            .map(|((_, order, service), retained)| (*order, service.as_str(), retained))
+    }
+
+    // Stops retaining the data of the user, returning the services it was retained for, see
+    // `erasure`
+    pub(crate) fn remove_user(&mut self, user: UserId) -> Vec<String> {
+        let keys = self
+            .services
+            .keys()
+            .filter(|(owner, _, _)| *owner == user)
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.into_iter()
+            .map(|key| {
+                self.services.remove(&key);
+                key.2
+            })
+            .collect()
+    }
+
+    // The deletions of the retained data, oldest first
+    pub fn audit_log(&self) -> &AuditLog<DataDeletion> {
+        &self.audit_log
+    }
+}
+
+// Keeps the data of the services the cancellation of the order has deprovisioned
+pub(crate) fn retain(state: &mut State, user: UserId, order: OrderId, services: &[String]) {
+    let days = state.config.retention.days;
+    let now = ledger::now_secs();
+    for service in services {
+        state.retained_data.services.insert(
+            (user, order, service.clone()),
+            RetainedService {
+                deprovisioned_at: now,
+                days_until_deletion: days,
+            },
+        );
+    }
+}
+
+// The data of the service deprovisioned by the cancelled order of the user, as dumped by
+// the provisioner
+pub fn export(
+    state: &mut State,
+    user: UserId,
+    order: OrderId,
+    service: &str,
+) -> Result<Vec<u8>, Error> {
+    orders::get(state, user, order)?;
+    if state.retained_data.get(user, order, service).is_none() {
+        return Err(BillingError::NoRetainedData.into());
+    }
+    state.provisioner.export_data(user, service)
+}
+
+// Deletes the data whose retention window has ended, unless the service has been ordered
+// again. Data that fails to be deleted is retried the next day.
+pub(crate) fn advance_day(state: &mut State) {
+    let mut expired = Vec::new();
+    for (key, retained) in state.retained_data.services.iter_mut() {
+        match retained.days_until_deletion {
+            0 | 1 => expired.push(key.clone()),
+            _ => retained.days_until_deletion -= 1,
+        }
+    }
+    for key in expired {
+        let (user, order, service) = &key;
+        let reordered = state
+            .users
+            .get(user)
+            .is_some_and(|user_data| user_data.services.contains(service));
+        // The service has been ordered again, so its data is in use
+        if reordered {
+            state.retained_data.services.remove(&key);
+            continue;
+        }
+        if state.provisioner.delete_data(*user, service).is_err() {
+            continue;
+        }
+        let retained = state.retained_data.services.remove(&key).unwrap();
+        state.retained_data.audit_log.append(DataDeletion {
+            at: ledger::now_secs(),
+            user: *user,
+            service: service.clone(),
+            order: order.0,
+            deprovisioned_at: retained.deprovisioned_at,
+        });
+    }
+}
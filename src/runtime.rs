@@ -0,0 +1,542 @@
+use std::{
+    io::{Read, Write},
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use rand::{rngs::StdRng, RngCore};
+use wasi_common::pipe::{ReadPipe, WritePipe};
+use wasmtime::{
+    Config, Engine, Extern, Instance, InstanceAllocationStrategy, Linker, Memory, Module,
+    PoolingAllocationConfig, Store, StoreContextMut, Trap, UpdateDeadline,
+};
+use wasmtime_wasi::{preview2::WasiCtxBuilder, sync, WasiCtx};
+
+use crate::{
+    config::{EngineConfig, OptLevel, RuntimeConfig},
+    determinism::{self, DeterminismConfig},
+    host,
+    preview2::{self, ComponentState},
+    profiling::{self, ProfilingConfig},
+    service_catalog, templates, wasmtime_host,
+    watchdog::{self, Watchdog},
+    Error, HostError, State, UserId,
+};
+
+// The wasmtime-specific pieces (compilation, instantiation, calls and metering, the data of the
+// stores and the host functions linked into the instances) live behind this trait so that the
+// billing logic does not depend on a particular engine. An alternate backend (e.g. wasmi) can be
+// added as another implementation behind a feature flag.
+pub trait WasmRuntime {
+    type Module;
+    type Instance;
+    type Store;
+
+    fn compile(&self, bytes: &[u8]) -> Result<Self::Module, Error>;
+
+    fn new_store(&self, state: State) -> Self::Store;
+
+    // Instantiates the module for an invocation, i.e. `prewarm` followed by `activate`.
+    fn instantiate(
+        &self,
+        store: &mut Self::Store,
+        module: &Self::Module,
+        user: UserId,
+    ) -> Result<Self::Instance, Error> {
+        let instance = self.prewarm(store, module, user)?;
+        self.activate(store, module, &instance, user);
+        Ok(instance)
+    }
+
+    // Instantiates the module ahead of its invocation, e.g. for `instance_pool::InstancePool`.
+    // The start function of the module, if any, runs right away.
+    fn prewarm(
+        &self,
+        store: &mut Self::Store,
+        module: &Self::Module,
+        user: UserId,
+    ) -> Result<Self::Instance, Error>;
+
+    // Prepares the store for an invocation of the instance, e.g. resetting the metering
+    // of its memory to its current size.
+    fn activate(
+        &self,
+        store: &mut Self::Store,
+        module: &Self::Module,
+        instance: &Self::Instance,
+        user: UserId,
+    );
+
+    // Calls an exported `() -> i64` function of the instance.
+    fn call(
+        &self,
+        store: &mut Self::Store,
+        instance: &Self::Instance,
+        name: &str,
+    ) -> Result<i64, Error>;
+
+    // Returns the amount of fuel consumed in the store so far,
+    // or `None` if the backend does not support metering.
+    fn meter(&self, store: &Self::Store) -> Option<u64>;
+
+    // Limits the fuel the store may consume from now on, lifting the limit with `None`.
+    // Guests running out of it fail with `HostError::FuelExhausted`. Backends that do not
+    // support metering ignore the limit.
+    fn limit_fuel(&self, store: &mut Self::Store, fuel: Option<u64>);
+
+    // Returns the peak size of the linear memories of the last instance in pages,
+    // or `None` if the backend does not observe memory.
+    fn peak_memory_pages(&self, store: &Self::Store) -> Option<u64>;
+
+    fn state_mut<'a>(&self, store: &'a mut Self::Store) -> &'a mut State;
+
+    // Takes the state out of the store, e.g. to migrate its accounts, see `migrate`
+    fn take_state(&self, store: Self::Store) -> State;
+
+    // Redirects the standard input of the guests run in the store, empty by default
+    fn set_stdin(&self, store: &mut Self::Store, stdin: Box<dyn Read + Send + Sync>);
+
+    // Redirects the standard output of the guests run in the store, discarded by default
+    fn set_stdout(&self, store: &mut Self::Store, stdout: Box<dyn Write + Send + Sync>);
+
+    // Redirects the standard error of the guests run in the store, discarded by default
+    fn set_stderr(&self, store: &mut Self::Store, stderr: Box<dyn Write + Send + Sync>);
+
+    // Prepares the store for another invocation, lifting its fuel limit and clearing the data
+    // of the last one, see `store_pool`
+    fn recycle(&self, store: &mut Self::Store) {
+        self.limit_fuel(store, None);
+        self.state_mut(store).reset_invocation();
+    }
+
+    // Runs a WASI preview2 component on behalf of the user, see `preview2`.
+    fn run_component(
+        &self,
+        store: &mut Self::Store,
+        user: UserId,
+        bytes: &[u8],
+    ) -> Result<i64, Error>;
+}
+
+pub type SMStore = Store<WasmtimeState>;
+
+// The data of the wasmtime stores: the state of the host with the pieces specific to wasmtime,
+// which the host functions reach through `Deref`
+pub struct WasmtimeState {
+    state: State,
+    wasi_ctx: WasiCtx,
+    // The memory of the guest calling a wrapped host function, see `guest_memory::call_wrapped`
+    pub(crate) forwarded_memory: Option<Memory>,
+}
+
+impl WasmtimeState {
+    fn new(state: State) -> Self {
+        let wasi_ctx = sync::WasiCtxBuilder::new().build();
+        // WASI draws from the generator of the state, reseeded in the deterministic mode
+        *wasi_ctx.random.lock().unwrap() = Box::new(SharedRng(state.random.clone()));
+        Self {
+            state,
+            wasi_ctx,
+            forwarded_memory: None,
+        }
+    }
+}
+
+impl Deref for WasmtimeState {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        &self.state
+    }
+}
+
+impl DerefMut for WasmtimeState {
+    fn deref_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+}
+
+// See `State::random`
+struct SharedRng(Arc<Mutex<StdRng>>);
+
+impl RngCore for SharedRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.lock().unwrap().next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.lock().unwrap().next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.lock().unwrap().fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.0.lock().unwrap().try_fill_bytes(dest)
+    }
+}
+
+// How the guests are interrupted when the epoch of the engine is ticking
+#[derive(Clone, Copy, Debug)]
+struct Epochs {
+    // `None` without a watchdog
+    max_missed_heartbeats: Option<u32>,
+    // Epoch ticks per watchdog tick, more than one when profiling samples more often
+    per_tick: u64,
+}
+
+impl Epochs {
+    fn new(runtime_config: &RuntimeConfig) -> Option<Self> {
+        let watchdog = runtime_config.watchdog;
+        let tick = watchdog.unwrap_or_default().tick;
+        let per_tick = match runtime_config.profiling {
+            Some(profiling) => tick.as_nanos() / profiling.interval.as_nanos().max(1),
+            None if watchdog.is_some() => 1,
+            None => return None,
+        };
+        Some(Self {
+            max_missed_heartbeats: watchdog.map(|w| w.max_missed_heartbeats),
+            per_tick: (per_tick as u64).max(1),
+        })
+    }
+
+    // Profiled runs are interrupted on every epoch tick to be sampled,
+    // the others on every watchdog tick
+    fn deadline(self, state: &State) -> u64 {
+        match state.profiler.is_active() {
+            true => 1,
+            false => self.per_tick,
+        }
+    }
+}
+
+fn on_epoch(
+    mut store: StoreContextMut<'_, WasmtimeState>,
+    epochs: Epochs,
+) -> wasmtime::Result<UpdateDeadline> {
+    profiling::sample(&mut store);
+    let state = store.data_mut();
+    let deadline = epochs.deadline(state);
+    state.elapsed_epochs += deadline;
+    if let Some(max_missed_heartbeats) = epochs.max_missed_heartbeats {
+        if state.elapsed_epochs.is_multiple_of(epochs.per_tick) {
+            watchdog::on_tick(state, max_missed_heartbeats)?;
+        }
+    }
+    Ok(UpdateDeadline::Continue(deadline))
+}
+
+fn engine_error(e: impl ToString) -> Error {
+    HostError::EngineConfig(e.to_string()).into()
+}
+
+// Applies the engine settings to wasmtime's `Config`
+fn apply_engine_config(config: &mut Config, engine: &EngineConfig) -> Result<(), Error> {
+    config.cranelift_opt_level(match engine.opt_level {
+        OptLevel::None => wasmtime::OptLevel::None,
+        OptLevel::Speed => wasmtime::OptLevel::Speed,
+        OptLevel::SpeedAndSize => wasmtime::OptLevel::SpeedAndSize,
+    });
+    // The proposals depending on the disabled ones are disabled with them
+    config.wasm_simd(engine.simd);
+    if !engine.simd {
+        config.wasm_relaxed_simd(false);
+    }
+    config.wasm_bulk_memory(engine.bulk_memory);
+    if !engine.bulk_memory {
+        config.wasm_reference_types(false).wasm_threads(false);
+    }
+    config.parallel_compilation(engine.parallel_compilation);
+    if let Some(dir) = &engine.cache_dir {
+        // wasmtime reads the settings of its cache from a file, written next to the cache
+        std::fs::create_dir_all(dir).map_err(engine_error)?;
+        let path = dir.join("wasmtime-cache.toml");
+        let directory = dir.to_str().ok_or_else(|| {
+            engine_error(format!(
+                "the cache directory {} is not UTF-8",
+                dir.display()
+            ))
+        })?;
+        let settings = format!("[cache]\nenabled = true\ndirectory = {directory:?}\n");
+        std::fs::write(&path, settings).map_err(engine_error)?;
+        config.cache_config_load(&path).map_err(engine_error)?;
+    }
+    Ok(())
+}
+
+pub struct WasmtimeRuntime {
+    engine: Engine,
+    linker: Linker<WasmtimeState>,
+    // `None` without WASI, see `RuntimeConfig::without_wasi`
+    component_linker: Option<wasmtime::component::Linker<ComponentState>>,
+    // `None` if epoch interruption is disabled
+    epochs: Option<Epochs>,
+    profiling: Option<ProfilingConfig>,
+    determinism: Option<DeterminismConfig>,
+    // Stops ticking when the runtime is dropped
+    _watchdog: Option<Watchdog>,
+}
+
+impl WasmtimeRuntime {
+    // Fuel given to each store and to the invocations without a limit, effectively unbounded
+    const INITIAL_FUEL: u64 = u64::MAX;
+
+    pub fn new() -> Self {
+        Self::with_config(&RuntimeConfig::default()).unwrap()
+    }
+
+    pub fn with_config(runtime_config: &RuntimeConfig) -> Result<Self, Error> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        config.wasm_component_model(true);
+        apply_engine_config(&mut config, &runtime_config.engine)?;
+        if runtime_config.determinism.is_some() {
+            config
+                .cranelift_nan_canonicalization(true)
+                .relaxed_simd_deterministic(true)
+                .wasm_threads(false);
+        }
+        if let Some(pooling) = runtime_config.pooling {
+            let mut pooling_config = PoolingAllocationConfig::default();
+            pooling_config
+                .total_core_instances(pooling.max_instances)
+                .total_memories(pooling.max_instances)
+                .memory_pages(pooling.memory_pages_per_instance);
+            config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling_config));
+        }
+        let epochs = Epochs::new(runtime_config);
+        config.epoch_interruption(epochs.is_some());
+        let engine = Engine::new(&config).map_err(|e| HostError::EngineConfig(e.to_string()))?;
+        let mut linker = Linker::<WasmtimeState>::new(&engine);
+        let component_linker = match runtime_config.without_wasi {
+            true => None,
+            false => {
+                wasmtime_wasi::add_to_linker(&mut linker, |s| &mut s.wasi_ctx)
+                    .map_err(|e| HostError::EngineConfig(e.to_string()))?;
+                Some(preview2::command_linker(&engine)?)
+            }
+        };
+        // Profiling ticks at its own interval, the watchdog counts the ticks, see `Epochs`
+        let tick = match (runtime_config.profiling, runtime_config.watchdog) {
+            (Some(profiling), _) => Some(profiling.interval),
+            (None, watchdog) => watchdog.map(|watchdog| watchdog.tick),
+        };
+        let watchdog = tick.map(|tick| Watchdog::start(engine.clone(), tick));
+        Ok(Self {
+            engine,
+            linker,
+            component_linker,
+            epochs,
+            profiling: runtime_config.profiling,
+            determinism: runtime_config.determinism,
+            _watchdog: watchdog,
+        })
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub fn linker(&self) -> &Linker<WasmtimeState> {
+        &self.linker
+    }
+}
+
+impl Default for WasmtimeRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WasmRuntime for WasmtimeRuntime {
+    type Module = Module;
+    type Instance = Instance;
+    type Store = SMStore;
+
+    fn compile(&self, bytes: &[u8]) -> Result<Module, Error> {
+        Module::new(&self.engine, bytes)
+            .map_err(|e| HostError::CompilationFailed(e.to_string()).into())
+    }
+
+    fn new_store(&self, mut state: State) -> SMStore {
+        state.determinism = self.determinism;
+        let mut store = Store::new(&self.engine, WasmtimeState::new(state));
+        store.set_fuel(Self::INITIAL_FUEL).unwrap();
+        store.limiter(|state| &mut state.memory_meter);
+        if let Some(epochs) = self.epochs {
+            store.epoch_deadline_callback(move |store| on_epoch(store, epochs));
+        }
+        store
+    }
+
+    fn prewarm(
+        &self,
+        store: &mut SMStore,
+        module: &Module,
+        user: UserId,
+    ) -> Result<Instance, Error> {
+        for import in module.imports() {
+            match import.module() {
+                host::HOST_MODULE => host::check_import(&import)?,
+                // e.g. `wasi_snapshot_preview1` or `wasi_unstable`
+                wasi if self.component_linker.is_none() && wasi.starts_with("wasi") => {
+                    let import = format!("{wasi}.{}", import.name());
+                    return Err(HostError::WasiDisabled(import).into());
+                }
+                _ => {}
+            }
+            if self.determinism.is_some() {
+                determinism::check_import(&import)?;
+            }
+        }
+        let imports = module
+            .imports()
+            .map(|import| {
+                self.determinism
+                    .and_then(|config| determinism::resolve_import(store, &import, config))
+                    .or_else(|| {
+                        wasmtime_host::resolve_or_construct_import(
+                            &self.linker,
+                            store,
+                            import,
+                            user,
+                        )
+                    })
+            })
+            .collect::<Option<Vec<Extern>>>()
+            .ok_or(HostError::UnknownImport)?;
+        Instance::new(store, module, &imports)
+            .map_err(|e| HostError::InstantiationFailed(e.to_string()).into())
+    }
+
+    fn activate(&self, store: &mut SMStore, module: &Module, instance: &Instance, user: UserId) {
+        let memory_bytes = instance
+            .exports(&mut *store)
+            .filter_map(|export| export.into_memory())
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|memory| memory.data_size(&*store) as u64)
+            .max()
+            .unwrap_or_default();
+        let state = store.data_mut();
+        state.stats.record_active_user(user);
+        // The buffer registered by a previous instance is not in the memory of this one
+        state.result_buffer = None;
+        state.memory_meter.reset(memory_bytes);
+        if let Some(profiling) = self.profiling {
+            state.profiler.start(module, profiling.interval);
+        }
+    }
+
+    fn call(&self, store: &mut SMStore, instance: &Instance, name: &str) -> Result<i64, Error> {
+        let func = instance
+            .get_typed_func::<(), i64>(&mut *store, name)
+            .map_err(|e| HostError::CallFailed(e.to_string()))?;
+        if let Some(epochs) = self.epochs {
+            let state = store.data_mut();
+            state.missed_heartbeats = 0;
+            state.elapsed_epochs = 0;
+            let deadline = epochs.deadline(state);
+            store.set_epoch_deadline(deadline);
+        }
+        let result = func.call(&mut *store, ());
+        let state = store.data_mut();
+        state.profiler.finish();
+        state.stats.record_invocation(result.is_ok());
+        result.map_err(|e| match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) if e.downcast_ref() == Some(&Trap::OutOfFuel) => HostError::FuelExhausted.into(),
+            Err(e) => HostError::CallFailed(e.to_string()).into(),
+        })
+    }
+
+    fn meter(&self, store: &SMStore) -> Option<u64> {
+        store.get_fuel().ok().map(|left| Self::INITIAL_FUEL - left)
+    }
+
+    fn limit_fuel(&self, store: &mut SMStore, fuel: Option<u64>) {
+        store.set_fuel(fuel.unwrap_or(Self::INITIAL_FUEL)).unwrap();
+    }
+
+    fn peak_memory_pages(&self, store: &SMStore) -> Option<u64> {
+        Some(store.data().memory_meter.peak_pages())
+    }
+
+    fn state_mut<'a>(&self, store: &'a mut SMStore) -> &'a mut State {
+        store.data_mut()
+    }
+
+    fn take_state(&self, store: SMStore) -> State {
+        store.into_data().state
+    }
+
+    fn set_stdin(&self, store: &mut SMStore, stdin: Box<dyn Read + Send + Sync>) {
+        store
+            .data()
+            .wasi_ctx
+            .set_stdin(Box::new(ReadPipe::new(stdin)));
+    }
+
+    fn set_stdout(&self, store: &mut SMStore, stdout: Box<dyn Write + Send + Sync>) {
+        store
+            .data()
+            .wasi_ctx
+            .set_stdout(Box::new(WritePipe::new(stdout)));
+    }
+
+    fn set_stderr(&self, store: &mut SMStore, stderr: Box<dyn Write + Send + Sync>) {
+        store
+            .data()
+            .wasi_ctx
+            .set_stderr(Box::new(WritePipe::new(stderr)));
+    }
+
+    fn recycle(&self, store: &mut SMStore) {
+        self.limit_fuel(store, None);
+        let data = store.data_mut();
+        data.reset_invocation();
+        data.forwarded_memory = None;
+    }
+
+    // The component runs in a store of its own with the WASI context of the user, see
+    // `templates::wasi_builder`, as completed by `State::component_wasi`, since it cannot share
+    // the store of the core modules. The state is lent to its calls to the catalog, see
+    // `service_catalog`.
+    fn run_component(&self, store: &mut SMStore, user: UserId, bytes: &[u8]) -> Result<i64, Error> {
+        let component_linker = self
+            .component_linker
+            .as_ref()
+            .ok_or_else(|| HostError::WasiDisabled("a preview2 component".to_owned()))?;
+        if self.determinism.is_some() {
+            return Err(HostError::Nondeterministic("a preview2 component".to_owned()).into());
+        }
+        let state = store.data_mut();
+        state.stats.record_active_user(user);
+        let mut wasi = match state.users.get(&user) {
+            Some(user_data) => templates::wasi_builder(&user_data)?,
+            None => WasiCtxBuilder::new(),
+        };
+        (state.component_wasi)(user, &mut wasi);
+        let wasi = wasi.build();
+        let mut component_store = Store::new(&self.engine, ComponentState::new(wasi));
+        component_store.set_fuel(Self::INITIAL_FUEL).unwrap();
+        match self.epochs {
+            // Components cannot call `host.heartbeat`, so they get as many ticks
+            // as a core module that never calls it
+            Some(Epochs {
+                max_missed_heartbeats: Some(max_missed_heartbeats),
+                per_tick,
+            }) => component_store
+                .set_epoch_deadline((u64::from(max_missed_heartbeats) + 1) * per_tick),
+            // Components are not profiled, nor interrupted without a watchdog
+            Some(_) => component_store.set_epoch_deadline(u64::MAX / 2),
+            None => {}
+        }
+        let result = service_catalog::lend(store.data_mut(), user, || {
+            preview2::run_command(&mut component_store, component_linker, bytes)
+        });
+        store.data_mut().stats.record_invocation(result.is_ok());
+        result
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{
+    alerts, anomalies, archive, balance_history, certs,
+    config::Config,
+    db, domains, metering, notifications,
+    plan::{Plan, TrialEnd},
+    postpaid, queues, retention, sla,
+    store::UserStore,
+    State,
+};
+
+// Advances every account by one day. Trial days are consumed before
+// the paid hosting days, which are followed by the grace period, if any. The daily email
+// quotas are reset, the due domain registrations are renewed
+// and the expired messages are dropped from the inboxes. The balance histories
+// are compacted last, so that they include the charges of the day.
+pub fn advance_day(users: &mut UserStore, config: &Config) {
+    for (_, user_data) in users.iter_mut() {
+        user_data.emails_sent_today = 0;
+        match user_data.plan {
+            Plan::Trial { days_left } if days_left > 1 => {
+                user_data.plan = Plan::Trial {
+                    days_left: days_left - 1,
+                };
+            }
+            Plan::Trial { .. } => {
+                user_data.plan = match config.trial.on_end {
+                    TrialEnd::Suspend if user_data.hosting_days_left == 0 => Plan::Suspended,
+                    _ => Plan::Paid,
+                };
+            }
+            Plan::Paid if user_data.hosting_days_left > 0 => {
+                user_data.hosting_days_left -= 1;
+            }
+            Plan::Paid => match config.grace.days {
+                Some(0) => user_data.plan = Plan::Suspended,
+                Some(days) => user_data.plan = Plan::Grace { days_left: days },
+                None => {}
+            },
+            Plan::Grace { days_left } if days_left > 1 => {
+                user_data.plan = Plan::Grace {
+                    days_left: days_left - 1,
+                };
+            }
+            Plan::Grace { .. } => user_data.plan = Plan::Suspended,
+            Plan::Suspended => {}
+        }
+    }
+    domains::advance_day(users, &config.domains);
+    queues::drop_all_expired(users, &config.queues);
+    balance_history::compact_all(users, &config.balance_history);
+}
+
+// Advances the whole state by one day, including the services
+// whose resources live outside of the user store, and notifies
+// the grace listener about the accounts in their grace period.
+pub fn advance_state_day(state: &mut State) {
+    advance_day(&mut state.users, &state.config);
+    for (&user, user_data) in state.users.read().iter() {
+        if let Plan::Grace { days_left } = user_data.plan {
+            state.grace_listener.on_grace_day(user, days_left);
+        }
+    }
+    db::advance_day(state);
+    retention::advance_day(state);
+    certs::advance_day(state);
+    metering::advance_day(state);
+    archive::advance_day(state);
+    // Before the invoicing, so that the credits of the past month are netted on its invoices
+    sla::advance_day(state);
+    postpaid::advance_day(state);
+    notifications::advance_day(state);
+    alerts::evaluate_all(state);
+    anomalies::evaluate_all(state);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{billing, money::MoneyUnit, plan::TrialConfig, UserData, UserId};
+
+    const USER: UserId = UserId(0);
+
+    // A new user on a trial of two days, with the given paid hosting days
+    fn users(config: &Config, hosting_days_left: u32) -> UserStore {
+        let mut user_data = UserData::new(MoneyUnit::from_cents(0));
+        user_data.hosting_days_left = hosting_days_left;
+        billing::start_trial(&mut user_data, &config.trial);
+        let mut users = UserStore::new();
+        users.insert(USER, user_data);
+        users
+    }
+
+    fn config(on_end: TrialEnd) -> Config {
+        Config {
+            trial: TrialConfig { days: 2, on_end },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn trial_days_are_consumed_before_the_paid_days() {
+        let config = config(TrialEnd::Suspend);
+        let mut users = users(&config, 1);
+        advance_day(&mut users, &config);
+        let user_data = users.get(&USER).unwrap();
+        assert_eq!(billing::trial_days_left(&user_data), 1);
+        assert_eq!(user_data.hosting_days_left, 1);
+        drop(user_data);
+
+        advance_day(&mut users, &config);
+        assert_eq!(users.get(&USER).unwrap().plan, Plan::Paid);
+        advance_day(&mut users, &config);
+        assert_eq!(users.get(&USER).unwrap().hosting_days_left, 0);
+    }
+
+    #[test]
+    fn trial_without_paid_days_ends_in_suspension() {
+        let config = config(TrialEnd::Suspend);
+        let mut users = users(&config, 0);
+        advance_day(&mut users, &config);
+        advance_day(&mut users, &config);
+        assert_eq!(users.get(&USER).unwrap().plan, Plan::Suspended);
+
+        let config = self::config(TrialEnd::ConvertToPaid);
+        let mut users = self::users(&config, 0);
+        advance_day(&mut users, &config);
+        advance_day(&mut users, &config);
+        assert_eq!(users.get(&USER).unwrap().plan, Plan::Paid);
+    }
+}
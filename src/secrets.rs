@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+use serde::Serialize;
+
+use crate::{
+    audit::AuditLog,
+    auth::{self, Scope},
+    capability::Capability,
+    ledger, BillingError, Error, HostError, State, UserId,
+};
+
+const MAX_NAME_LEN: usize = 128;
+const MAX_VALUE_LEN: usize = 64 * 1024;
+
+// A secret as kept in memory, encrypted if the vault has a master key
+#[derive(Clone)]
+enum StoredSecret {
+    Plain(Vec<u8>),
+    Sealed {
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    },
+}
+
+// Who accessed a secret
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Accessor {
+    Guest,
+    Operator,
+    // A client of the management API authorized by a token of the owner
+    Api,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretOp {
+    Set,
+    Get,
+    Delete,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SecretAccess {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    pub user: UserId,
+    pub name: String,
+    pub accessor: Accessor,
+    pub op: SecretOp,
+    // Whether the access was allowed and succeeded
+    pub granted: bool,
+}
+
+// The named secrets of the users, e.g. API keys of third-party services used by the guests.
+// The values never appear in the audit log or in `Debug` output.
+#[derive(Default)]
+pub struct SecretVault {
+    master_key: Option<Key>,
+    secrets: BTreeMap<(UserId, String), StoredSecret>,
+    audit_log: AuditLog<SecretAccess>,
+}
+
+impl std::fmt::Debug for SecretVault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretVault")
+            .field("encrypted", &self.master_key.is_some())
+            .field("secrets", &self.secrets.len())
+            .finish_non_exhaustive()
+    }
+}
+
+// Binds the ciphertext to the owner and the name of the secret,
+// so that it cannot be moved to another one
+fn associated_data(user: UserId, name: &str) -> Vec<u8> {
+    let mut aad = (user.0 as u64).to_le_bytes().to_vec();
+    aad.extend_from_slice(name.as_bytes());
+    aad
+}
+
+fn validate_name(name: &str) -> Result<(), Error> {
+    let valid = (1..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+    if !valid {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    Ok(())
+}
+
+impl SecretVault {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Encrypts the secrets set from now on with ChaCha20-Poly1305 under the key.
+    // The key has to be configured again to read them after a restart.
+    pub fn with_master_key(master_key: [u8; 32]) -> Self {
+        Self {
+            master_key: Some(master_key.into()),
+            ..Self::default()
+        }
+    }
+
+    // The accesses to the secrets, oldest first, see `audit`
+    pub fn audit_log(&self) -> &AuditLog<SecretAccess> {
+        &self.audit_log
+    }
+
+    // The names of the user's secrets, in alphabetical order
+    pub fn names_of(&self, user: UserId) -> impl Iterator<Item = &str> {
+        self.secrets
+            .keys()
+            .filter(move |(owner, _)| *owner == user)
+            .map(|(_, name)| name.as_str())
+    }
+
+    fn log(&mut self, user: UserId, name: &str, accessor: Accessor, op: SecretOp, granted: bool) {
+        self.audit_log.append(SecretAccess {
+            at: ledger::now_secs(),
+            user,
+            name: name.to_owned(),
+            accessor,
+            op,
+            granted,
+        });
+    }
+
+    fn seal(&self, user: UserId, name: &str, value: &[u8]) -> Result<StoredSecret, Error> {
+        let Some(key) = &self.master_key else {
+            return Ok(StoredSecret::Plain(value.to_vec()));
+        };
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(user, name);
+        let ciphertext = ChaCha20Poly1305::new(key)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| HostError::SecretUnreadable)?;
+        Ok(StoredSecret::Sealed {
+            nonce: nonce.into(),
+            ciphertext,
+        })
+    }
+
+    fn open(&self, user: UserId, name: &str, secret: &StoredSecret) -> Result<Vec<u8>, Error> {
+        match secret {
+            StoredSecret::Plain(value) => Ok(value.clone()),
+            StoredSecret::Sealed { nonce, ciphertext } => {
+                let key = self
+                    .master_key
+                    .as_ref()
+                    .ok_or(HostError::SecretUnreadable)?;
+                let aad = associated_data(user, name);
+                ChaCha20Poly1305::new(key)
+                    .decrypt(
+                        Nonce::from_slice(nonce),
+                        Payload {
+                            msg: ciphertext,
+                            aad: &aad,
+                        },
+                    )
+                    .map_err(|_| HostError::SecretUnreadable.into())
+            }
+        }
+    }
+
+    // Sets the secret of the user, replacing the existing one with the same name
+    pub fn set(
+        &mut self,
+        user: UserId,
+        name: &str,
+        value: &[u8],
+        accessor: Accessor,
+    ) -> Result<(), Error> {
+        validate_name(name)?;
+        if value.len() > MAX_VALUE_LEN {
+            return Err(BillingError::InvalidArgumentValue.into());
+        }
+        let sealed = self.seal(user, name, value);
+        self.log(user, name, accessor, SecretOp::Set, sealed.is_ok());
+        self.secrets.insert((user, name.to_owned()), sealed?);
+        Ok(())
+    }
+
+    pub fn get(&mut self, user: UserId, name: &str, accessor: Accessor) -> Result<Vec<u8>, Error> {
+        let value = match self.secrets.get(&(user, name.to_owned())) {
+            Some(secret) => self.open(user, name, secret),
+            None => Err(BillingError::UnknownSecret.into()),
+        };
+        self.log(user, name, accessor, SecretOp::Get, value.is_ok());
+        value
+    }
+
+    pub fn delete(&mut self, user: UserId, name: &str, accessor: Accessor) -> Result<(), Error> {
+        let removed = self.secrets.remove(&(user, name.to_owned())).is_some();
+        self.log(user, name, accessor, SecretOp::Delete, removed);
+        removed
+            .then_some(())
+            .ok_or(BillingError::UnknownSecret.into())
+    }
+
+    // Deletes every secret of the user, returning how many there were, see `erasure`
+    pub(crate) fn delete_all(&mut self, user: UserId, accessor: Accessor) -> usize {
+        let names = self.names_of(user).map(str::to_owned).collect::<Vec<_>>();
+        for name in &names {
+            self.secrets.remove(&(user, name.clone()));
+            self.log(user, name, accessor, SecretOp::Delete, true);
+        }
+        names.len()
+    }
+}
+
+// Sets a secret on behalf of the owner of the API token
+pub fn set_with_token(
+    state: &mut State,
+    token: &str,
+    name: &str,
+    value: &[u8],
+) -> Result<(), Error> {
+    let user = auth::authorize(&state.users, token, Scope::Write)?;
+    state.secrets.set(user, name, value, Accessor::Api)
+}
+
+pub fn delete_with_token(state: &mut State, token: &str, name: &str) -> Result<(), Error> {
+    let user = auth::authorize(&state.users, token, Scope::Write)?;
+    state.secrets.delete(user, name, Accessor::Api)
+}
+
+// Reads a secret for the user's guest. Guests of users without `Capability::Secrets`
+// are denied, which is recorded in the audit log as well.
+pub fn guest_get(state: &mut State, user: UserId, name: &str) -> Result<Vec<u8>, Error> {
+    let allowed = state
+        .users
+        .get(&user)
+        .is_some_and(|u| u.capabilities.contains(&Capability::Secrets));
+    if !allowed {
+        state
+            .secrets
+            .log(user, name, Accessor::Guest, SecretOp::Get, false);
+        return Err(BillingError::MissingCapability.into());
+    }
+    state.secrets.get(user, name, Accessor::Guest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{money::MoneyUnit, store::UserStore, UserData};
+
+    const USER: UserId = UserId(0);
+
+    fn granted(vault: &SecretVault) -> Vec<(SecretOp, bool)> {
+        let entries = vault.audit_log().entries().iter();
+        entries
+            .map(|entry| (entry.event.op, entry.event.granted))
+            .collect()
+    }
+
+    #[test]
+    fn sealed_secrets_are_read_back_by_their_owner() {
+        let mut vault = SecretVault::with_master_key([7; 32]);
+        vault
+            .set(USER, "api.key", b"hunter2", Accessor::Operator)
+            .unwrap();
+        assert_eq!(
+            vault.get(USER, "api.key", Accessor::Guest).unwrap(),
+            b"hunter2"
+        );
+        let sealed = &vault.secrets[&(USER, "api.key".to_owned())];
+        assert!(
+            matches!(sealed, StoredSecret::Sealed { ciphertext, .. } if ciphertext != b"hunter2")
+        );
+        // The ciphertext is bound to its owner
+        vault
+            .secrets
+            .insert((UserId(1), "api.key".to_owned()), sealed.clone());
+        assert!(matches!(
+            vault.get(UserId(1), "api.key", Accessor::Guest),
+            Err(Error::Host(HostError::SecretUnreadable))
+        ));
+        assert!(!format!("{vault:?}").contains("hunter2"));
+    }
+
+    #[test]
+    fn denied_and_missing_accesses_are_audited() {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(0)));
+        let mut state = State::new(users);
+        state
+            .secrets
+            .set(USER, "token", b"secret", Accessor::Operator)
+            .unwrap();
+        assert!(matches!(
+            guest_get(&mut state, USER, "token"),
+            Err(Error::Billing(BillingError::MissingCapability))
+        ));
+        let user_data = state.users.get_mut(&USER).unwrap();
+        user_data.capabilities.insert(Capability::Secrets);
+        assert_eq!(guest_get(&mut state, USER, "token").unwrap(), b"secret");
+        assert!(matches!(
+            state.secrets.delete(USER, "missing", Accessor::Operator),
+            Err(Error::Billing(BillingError::UnknownSecret))
+        ));
+        assert!(matches!(
+            state
+                .secrets
+                .set(USER, "no spaces", b"", Accessor::Operator),
+            Err(Error::Billing(BillingError::InvalidArgumentValue))
+        ));
+        assert_eq!(
+            granted(&state.secrets),
+            [
+                (SecretOp::Set, true),
+                (SecretOp::Get, false),
+                (SecretOp::Get, true),
+                (SecretOp::Delete, false),
+            ]
+        );
+    }
+}
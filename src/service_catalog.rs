@@ -0,0 +1,281 @@
+// The `wsm:catalog/service-catalog` interface of `wit/service-catalog.wit`, for the preview2
+// components of the users to list, quote, order and cancel the services of the catalog without
+// the core functions of the `host` module, see `preview2`. The host bindings are generated from
+// the WIT file, and so are the ones of the guests, e.g. by the `wsm-service-catalog` crate in
+// `guest/service-catalog`, so that the interface is the documentation.
+//
+// The calls act on behalf of the user running the component, with the state lent to the run, see
+// `lend`, and charge like their core counterparts: `order` like `host.order_hosting` or
+// `host.order_bundle`, `cancel` like `host.cancel_service`. The single services of the catalog
+// are only ordered within the bundles, so they are quoted but not listed. The errors carry the
+// codes of `Error::code` and their messages, which are also left for `host.last_error_code` like
+// the core functions do.
+
+use std::{cell::RefCell, mem};
+
+use crate::{
+    cancellation, host,
+    money::MoneyUnit,
+    orders::OrderId,
+    preview2::ComponentState,
+    prices::{self, ItemKind, ItemPrice, HOSTING},
+    store::UserStore,
+    BillingError, Error, State, UserId,
+};
+
+mod bindings {
+    wasmtime::component::bindgen!({
+        path: "wit",
+        world: "catalog-guest",
+    });
+}
+
+use bindings::wsm::catalog::service_catalog::{self, CatalogError, Host, Item, Money};
+
+thread_local! {
+    // The state lent to the component running on the thread and its user, see `lend`
+    static LENT: RefCell<Option<(UserId, State)>> = const { RefCell::new(None) };
+}
+
+// Puts the lent state back when dropped, even if the run panics
+struct Lent<'a>(&'a mut State);
+
+impl Drop for Lent<'_> {
+    fn drop(&mut self) {
+        // Never missing, the calls only borrow it, but a panic while unwinding would abort
+        if let Some((_, lent)) = LENT.with(|slot| slot.borrow_mut().take()) {
+            *self.0 = lent;
+        }
+    }
+}
+
+// Runs the component of the user with the state lent to its calls to the catalog. The state is
+// moved out for the run, leaving an empty one behind, since the data of the stores of the
+// components has to be `Send` and the state is not.
+pub(crate) fn lend<T>(state: &mut State, user: UserId, run: impl FnOnce() -> T) -> T {
+    let empty = State::new(UserStore::new());
+    let lent = mem::replace(state, empty);
+    LENT.with(|slot| *slot.borrow_mut() = Some((user, lent)));
+    let _lent = Lent(state);
+    run()
+}
+
+pub(crate) fn add_to_linker(
+    linker: &mut wasmtime::component::Linker<ComponentState>,
+) -> wasmtime::Result<()> {
+    service_catalog::add_to_linker(linker, |state: &mut ComponentState| state)
+}
+
+impl From<MoneyUnit> for Money {
+    fn from(amount: MoneyUnit) -> Self {
+        Money {
+            minor_units: amount.minor_units(),
+            currency: amount.currency().code().to_owned(),
+        }
+    }
+}
+
+// The item as listed, unless it is a single service, which cannot be ordered on its own
+fn orderable(item: ItemPrice) -> Option<Item> {
+    let kind = match item.kind {
+        ItemKind::Hosting => service_catalog::ItemKind::Hosting,
+        ItemKind::Service => return None,
+        ItemKind::Bundle => service_catalog::ItemKind::Bundle,
+    };
+    Some(Item {
+        name: item.name,
+        kind,
+        list_price: item.list_price.into(),
+        price: item.price.into(),
+    })
+}
+
+// The error just reported with its code, see `host::report_error`
+fn reported(state: &State, code: i32) -> CatalogError {
+    CatalogError {
+        code,
+        message: state.last_error.clone().unwrap_or_default(),
+    }
+}
+
+fn error(state: &mut State, error: impl Into<Error>) -> CatalogError {
+    let code = host::report_error(state, error);
+    reported(state, code)
+}
+
+// Runs the call on behalf of the user the state is lent for, failing without one, e.g. for the
+// components run outside of `WasmRuntime::run_component`
+fn call<T>(
+    f: impl FnOnce(&mut State, UserId) -> Result<T, CatalogError>,
+) -> wasmtime::Result<Result<T, CatalogError>> {
+    Ok(LENT.with(|slot| match slot.borrow_mut().as_mut() {
+        Some((user, state)) => f(state, *user),
+        None => Err(CatalogError {
+            code: Error::from(BillingError::UnknownUser).code(),
+            message: BillingError::UnknownUser.to_string(),
+        }),
+    }))
+}
+
+impl Host for ComponentState {
+    fn list_services(&mut self) -> wasmtime::Result<Result<Vec<Item>, CatalogError>> {
+        call(|state, user| match prices::catalog(state, user) {
+            Ok(items) => Ok(items.into_iter().filter_map(orderable).collect()),
+            Err(e) => Err(error(state, e)),
+        })
+    }
+
+    fn quote(
+        &mut self,
+        name: String,
+        quantity: u32,
+    ) -> wasmtime::Result<Result<Money, CatalogError>> {
+        call(
+            |state, user| match prices::price_of(state, user, &name, quantity.into()) {
+                Ok(price) => Ok(price.into()),
+                Err(e) => Err(error(state, e)),
+            },
+        )
+    }
+
+    fn order(
+        &mut self,
+        name: String,
+        quantity: u32,
+    ) -> wasmtime::Result<Result<Option<u64>, CatalogError>> {
+        call(|state, user| {
+            let order = host::record_charges(state, user, |state| match name.as_str() {
+                HOSTING => {
+                    let days =
+                        i32::try_from(quantity).map_err(|_| BillingError::InvalidArgumentValue)?;
+                    host::order_hosting(state, user, days)
+                }
+                _ if quantity != 1 || state.config.catalog.services.contains_key(&name) => {
+                    Err(BillingError::InvalidArgumentValue.into())
+                }
+                _ => host::order_bundle(state, user, &name),
+            });
+            order
+                .map(|order| order.map(|OrderId(id)| id))
+                .map_err(|code| reported(state, code))
+        })
+    }
+
+    fn cancel(&mut self, order: u64) -> wasmtime::Result<Result<Money, CatalogError>> {
+        call(|state, user| {
+            let mut refund = None;
+            host::record_charges(state, user, |state| {
+                refund = Some(cancellation::cancel(state, user, OrderId(order))?);
+                Ok(())
+            })
+            .map_err(|code| reported(state, code))?;
+            // Set once the cancellation has succeeded
+            Ok(refund.unwrap().into())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::*;
+    use crate::{
+        history,
+        ledger::EntryKind,
+        runtime::{WasmRuntime, WasmtimeRuntime},
+        UserData,
+    };
+
+    const USER: UserId = UserId(0);
+
+    // Quotes 3 days of hosting, expecting 3.00, orders 2 days and fails to order 100000 days
+    const COMPONENT: &str = r#"
+        (component
+            (import "wsm:catalog/service-catalog@0.1.0" (instance $catalog
+                (type $money (record (field "minor-units" s64) (field "currency" string)))
+                (export $money' "money" (type (eq $money)))
+                (type $error (record (field "code" s32) (field "message" string)))
+                (export $error' "catalog-error" (type (eq $error)))
+                (type $quote (result $money' (error $error')))
+                (export "quote" (func (param "name" string) (param "quantity" u32) (result $quote)))
+                (type $order (result (option u64) (error $error')))
+                (export "order" (func (param "name" string) (param "quantity" u32) (result $order)))
+            ))
+            (core module $memory
+                (memory (export "memory") 1)
+                (global $bump (mut i32) (i32.const 1024))
+                (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $bump))
+                    (global.set $bump (i32.add (global.get $bump) (local.get 3)))
+                    (local.get $ptr))
+                (data (i32.const 0) "hosting"))
+            (core instance $memory_instance (instantiate $memory))
+            (alias core export $memory_instance "memory" (core memory $memory))
+            (alias core export $memory_instance "realloc" (core func $realloc))
+            (alias export $catalog "quote" (func $quote))
+            (core func $quote_lowered
+                (canon lower (func $quote) (memory $memory) (realloc $realloc)))
+            (alias export $catalog "order" (func $order))
+            (core func $order_lowered
+                (canon lower (func $order) (memory $memory) (realloc $realloc)))
+            (core module $main
+                (import "env" "memory" (memory 1))
+                (import "env" "quote" (func $quote (param i32 i32 i32 i32)))
+                (import "env" "order" (func $order (param i32 i32 i32 i32)))
+                (func (export "run") (result i32)
+                    (call $quote (i32.const 0) (i32.const 7) (i32.const 3) (i32.const 64))
+                    (if (i32.load8_u (i32.const 64)) (then (return (i32.const 1))))
+                    (if (i64.ne (i64.load (i32.const 72)) (i64.const 300))
+                        (then (return (i32.const 1))))
+                    (call $order (i32.const 0) (i32.const 7) (i32.const 2) (i32.const 128))
+                    (if (i32.load8_u (i32.const 128)) (then (return (i32.const 1))))
+                    (call $order (i32.const 0) (i32.const 7) (i32.const 100000) (i32.const 128))
+                    (if (i32.eqz (i32.load8_u (i32.const 128))) (then (return (i32.const 1))))
+                    (i32.const 0)))
+            (core instance $main_instance (instantiate $main (with "env" (instance
+                (export "memory" (memory $memory))
+                (export "quote" (func $quote_lowered))
+                (export "order" (func $order_lowered))))))
+            (func $run (result (result)) (canon lift (core func $main_instance "run")))
+            (instance $run_instance (export "run" (func $run)))
+            (export "wasi:cli/run@0.2.0-rc-2023-11-10" (instance $run_instance))
+        )
+    "#;
+
+    // A user with 100.00
+    fn state() -> State {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(10_000)));
+        State::new(users)
+    }
+
+    #[test]
+    fn component_quotes_and_orders_the_hosting() {
+        let runtime = WasmtimeRuntime::new();
+        let mut store = runtime.new_store(state());
+        let result = history::execute(&runtime, &mut store, USER, COMPONENT.as_bytes(), "run");
+        assert_eq!(result.unwrap(), 0);
+        let user_data = store.data().users.get(&USER).unwrap();
+        assert_eq!(user_data.balance, MoneyUnit::from_cents(9_800));
+        let orders = user_data
+            .ledger
+            .iter()
+            .filter(|entry| matches!(entry.kind, EntryKind::HostingOrder { .. }));
+        assert_eq!(orders.count(), 1);
+        // The failed order is left for `host.last_error_code`
+        assert!(store.data().last_error.is_some());
+    }
+
+    #[test]
+    fn lent_state_is_restored_if_the_run_panics() {
+        let mut state = state();
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            lend(&mut state, USER, || panic!("the run panicked"))
+        }));
+        assert!(panicked.is_err());
+        assert!(state.users.get(&USER).is_some());
+        assert!(LENT.with(|slot| slot.borrow().is_none()));
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    ledger::{EntryKind, LedgerEntry},
+    money::{MoneySum, MoneyUnit},
+    resellers,
+    sla::ServiceLevel,
+    store::UserStore,
+    BillingError, Error, HostError, UserId,
+};
+
+#[derive(Clone, Debug)]
+pub struct Service {
+    pub price: MoneyUnit,
+    // Services that have to be provisioned before this one
+    pub depends_on: Vec<String>,
+    // `None` promises no availability, see `sla`
+    pub sla: Option<ServiceLevel>,
+}
+
+// A set of services ordered and billed together
+#[derive(Clone, Debug)]
+pub struct Bundle {
+    pub services: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Catalog {
+    pub services: BTreeMap<String, Service>,
+    pub bundles: BTreeMap<String, Bundle>,
+}
+
+// Sets up and tears down the actual resources backing the services.
+pub trait Provisioner {
+    fn provision(&mut self, user: UserId, service: &str) -> Result<(), Error>;
+    // Stops the service. Its data is kept until `delete_data`, see `retention`.
+    fn deprovision(&mut self, user: UserId, service: &str);
+
+    // Dumps the data of the deprovisioned service of the user, e.g. as an archive
+    fn export_data(&mut self, _user: UserId, _service: &str) -> Result<Vec<u8>, Error> {
+        Ok(Vec::new())
+    }
+
+    fn delete_data(&mut self, _user: UserId, _service: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Whether the resources backing the service of the user are up, see `sla::check_health`
+    fn health_check(&mut self, _user: UserId, _service: &str) -> bool {
+        true
+    }
+}
+
+// A provisioner for setups where the services need no actual resources
+pub struct NoopProvisioner;
+
+impl Provisioner for NoopProvisioner {
+    fn provision(&mut self, _user: UserId, _service: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn deprovision(&mut self, _user: UserId, _service: &str) {}
+}
+
+impl Catalog {
+    // Orders the bundle members so that each service comes after its dependencies.
+    // Dependencies must either be members of the bundle or already provisioned.
+    pub fn provisioning_order(
+        &self,
+        bundle: &Bundle,
+        provisioned: &BTreeSet<String>,
+    ) -> Result<Vec<String>, Error> {
+        let members = bundle.services.iter().collect::<BTreeSet<_>>();
+        let mut order = Vec::new();
+        let mut visiting = BTreeSet::new();
+        let mut done = BTreeSet::new();
+
+        fn visit<'a>(
+            catalog: &'a Catalog,
+            name: &'a String,
+            members: &BTreeSet<&String>,
+            provisioned: &BTreeSet<String>,
+            visiting: &mut BTreeSet<&'a String>,
+            done: &mut BTreeSet<&'a String>,
+            order: &mut Vec<String>,
+        ) -> Result<(), Error> {
+            if done.contains(name) {
+                return Ok(());
+            }
+            if !visiting.insert(name) {
+                return Err(HostError::DependencyCycle.into());
+            }
+            let service = catalog
+                .services
+                .get(name)
+                .ok_or(BillingError::UnknownService)?;
+            for dependency in &service.depends_on {
+                if members.contains(dependency) {
+                    visit(
+                        catalog,
+                        dependency,
+                        members,
+                        provisioned,
+                        visiting,
+                        done,
+                        order,
+                    )?;
+                } else if !provisioned.contains(dependency) {
+                    return Err(BillingError::MissingDependency.into());
+                }
+            }
+            visiting.remove(name);
+            done.insert(name);
+            order.push(name.clone());
+            Ok(())
+        }
+
+        for name in &bundle.services {
+            visit(
+                self,
+                name,
+                &members,
+                provisioned,
+                &mut visiting,
+                &mut done,
+                &mut order,
+            )?;
+        }
+        Ok(order)
+    }
+}
+
+// The members of the bundle the user is charged for, in provisioning order
+struct Quote {
+    order: Vec<String>,
+    total_cost: MoneyUnit,
+    // The reseller of the user and its markup on the total cost, see `resellers`
+    markup: Option<(UserId, MoneyUnit)>,
+}
+
+impl Quote {
+    fn price(&self) -> Result<MoneyUnit, Error> {
+        match self.markup {
+            Some((_, markup)) => self.total_cost + markup,
+            None => Ok(self.total_cost),
+        }
+    }
+}
+
+// The members of the bundle in provisioning order and their total price,
+// failing if the user cannot afford them with the markup of the reseller
+fn quote(
+    catalog: &Catalog,
+    users: &UserStore,
+    user: UserId,
+    bundle_name: &str,
+) -> Result<Quote, Error> {
+    let bundle = catalog
+        .bundles
+        .get(bundle_name)
+        .ok_or(BillingError::UnknownService)?;
+    let user_data = users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let order = catalog.provisioning_order(bundle, &user_data.services)?;
+
+    let total_cost = order
+        .iter()
+        .map(|name| catalog.services[name].price)
+        .checked_sum(user_data.balance.currency())?;
+    let quote = Quote {
+        order,
+        total_cost,
+        markup: resellers::markup(users, user, total_cost)?,
+    };
+    (user_data.balance - quote.price()?)?;
+    if let Some((reseller, markup)) = quote.markup {
+        (users
+            .get(&reseller)
+            .ok_or(BillingError::UnknownUser)?
+            .balance
+            + markup)?;
+    }
+    Ok(quote)
+}
+
+// The price of the members of the bundle the user does not have yet, marked up by the
+// reseller of the user
+pub fn bundle_cost(
+    catalog: &Catalog,
+    users: &UserStore,
+    user: UserId,
+    bundle_name: &str,
+) -> Result<MoneyUnit, Error> {
+    quote(catalog, users, user, bundle_name)?.price()
+}
+
+fn bill(users: &mut UserStore, user: UserId, bundle_name: &str, quote: Quote) -> Result<(), Error> {
+    let user_data = users.get_mut(&user).ok_or(BillingError::UnknownUser)?;
+    let new_balance = (user_data.balance - quote.total_cost)?;
+    user_data.balance = new_balance;
+    user_data.services.extend(quote.order);
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::BundleOrder {
+            bundle: bundle_name.to_owned(),
+        },
+        quote.total_cost.checked_neg().unwrap(),
+        new_balance,
+    ));
+    let Some((reseller, markup)) = quote.markup else {
+        return Ok(());
+    };
+    // Both balances have been checked by the quote
+    let new_balance = (user_data.balance - markup)?;
+    user_data.balance = new_balance;
+    user_data.ledger.push(LedgerEntry::new(
+        EntryKind::ResellerMarkup { reseller },
+        markup.checked_neg().unwrap(),
+        new_balance,
+    ));
+    let reseller_data = users.get_mut(&reseller).ok_or(BillingError::UnknownUser)?;
+    let new_balance = (reseller_data.balance + markup)?;
+    reseller_data.balance = new_balance;
+    reseller_data.ledger.push(LedgerEntry::new(
+        EntryKind::MarkupEarned { account: user },
+        markup,
+        new_balance,
+    ));
+    Ok(())
+}
+
+// Provisions every member of the bundle in dependency order and bills them as a single
+// ledger entry. If any member fails to provision, the already provisioned ones are
+// deprovisioned in reverse order and nothing is charged.
+pub fn order_bundle(
+    catalog: &Catalog,
+    users: &mut UserStore,
+    provisioner: &mut dyn Provisioner,
+    user: UserId,
+    bundle_name: &str,
+) -> Result<(), Error> {
+    // The balance is checked before anything gets provisioned
+    let quote = quote(catalog, users, user, bundle_name)?;
+
+    for (i, name) in quote.order.iter().enumerate() {
+        if let Err(e) = provisioner.provision(user, name) {
+            for provisioned in quote.order[..i].iter().rev() {
+                provisioner.deprovision(user, provisioned);
+            }
+            return Err(e);
+        }
+    }
+
+    bill(users, user, bundle_name, quote)
+}
+
+// Bills the bundle like `order_bundle` but leaves the provisioning of its members to
+// the caller, returning them in provisioning order, e.g. for `tx::commit`. Orders marked up
+// by a reseller are refused, since a rollback would not take the markup back from the
+// reseller.
+pub(crate) fn order_bundle_unprovisioned(
+    catalog: &Catalog,
+    users: &mut UserStore,
+    user: UserId,
+    bundle_name: &str,
+) -> Result<Vec<String>, Error> {
+    let quote = quote(catalog, users, user, bundle_name)?;
+    if quote.markup.is_some() {
+        return Err(BillingError::TransactionActive.into());
+    }
+    let order = quote.order.clone();
+    bill(users, user, bundle_name, quote)?;
+    Ok(order)
+}
@@ -0,0 +1,358 @@
+// Top-ups from the settlement files of the banks and the payment service providers, e.g. with
+// `users top-up <file>` of the CLI. A settlement file is a CSV whose columns are those of
+// `CSV_HEADER`: the reference the customer paid with, the amount, its currency and the id of the
+// payment at the provider, which may be empty. A reference matches the account whose metadata
+// has it as `REFERENCE_KEY`, or else the account whose id it is.
+//
+// A file credits all its matched rows or none: every row is validated first, and any invalid
+// row, e.g. a malformed amount or one in another currency than the balance of its account, fails
+// the whole file with the errors of all the invalid rows. The rows matching no account, or an
+// erased one, are not credited but reported for the reconciliation, e.g. to return the payments.
+// A file is imported once, files seen before fail with `BillingError::DuplicateSettlement`, and
+// the rows of payments credited by an earlier file are reported and skipped, since the providers
+// may send overlapping files. The rows are numbered by their line in the file, the header being
+// the first one.
+//
+// The settlements opened with `Settlements::open` remember the files and the payments across the
+// restarts of the host in a JSON Lines journal: every import appends its row, synced to the disk
+// before anything is credited.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bulk::{self, RowError},
+    ledger::{self, EntryKind, LedgerEntry},
+    money::{Currency, MoneyUnit},
+    sha256_hex, BillingError, Error, HostError, State, UserId,
+};
+
+pub const CSV_HEADER: &str = "reference,amount,currency,transaction";
+// The metadata of the accounts holding the reference their customers pay with
+pub const REFERENCE_KEY: &str = "payment_reference";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SettlementRow {
+    pub reference: String,
+    pub amount: MoneyUnit,
+    // The id of the payment at the provider
+    pub transaction: Option<String>,
+}
+
+// A row matching no account, numbered by its line like the errors
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UnmatchedRow {
+    pub row: usize,
+    #[serde(flatten)]
+    pub settlement: SettlementRow,
+}
+
+#[derive(Debug, Default)]
+pub struct SettlementReport {
+    // The SHA-256 of the file
+    pub file: String,
+    // The rows credited, by number, with their accounts
+    pub credited: Vec<(usize, UserId, MoneyUnit)>,
+    pub unmatched: Vec<UnmatchedRow>,
+    // The rows of payments credited already
+    pub duplicates: Vec<usize>,
+    // Nothing has been credited if any row failed
+    pub errors: Vec<RowError>,
+}
+
+// The files imported and the payments credited
+#[derive(Default)]
+pub struct Settlements {
+    // When the files were imported, by their SHA-256
+    files: BTreeMap<String, u64>,
+    transactions: BTreeSet<String>,
+    // See `open`
+    journal: Option<File>,
+}
+
+// An import of the journal, see `Settlements::open`
+#[derive(Serialize, Deserialize)]
+struct JournalRow {
+    file: String,
+    at: u64,
+    transactions: Vec<String>,
+}
+
+fn persistence_error(e: impl ToString) -> Error {
+    HostError::Persistence(e.to_string()).into()
+}
+
+impl Settlements {
+    // Settlements kept in memory only, forgotten with the host
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Opens the journal at the path, creating it if needed, with the files imported before
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut settlements = Self::default();
+        if let Ok(file) = File::open(path) {
+            // A row torn by a crash is the last line and nothing of it was credited
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(persistence_error)?;
+                let Ok(row) = serde_json::from_str::<JournalRow>(&line) else {
+                    break;
+                };
+                settlements.files.insert(row.file, row.at);
+                settlements.transactions.extend(row.transactions);
+            }
+        }
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(persistence_error)?;
+        settlements.journal = Some(journal);
+        Ok(settlements)
+    }
+
+    fn record(&mut self, row: JournalRow) -> Result<(), Error> {
+        if let Some(journal) = &mut self.journal {
+            let mut line = serde_json::to_string(&row).map_err(persistence_error)?;
+            line.push('\n');
+            journal
+                .write_all(line.as_bytes())
+                .and_then(|_| journal.sync_data())
+                .map_err(persistence_error)?;
+        }
+        self.files.insert(row.file, row.at);
+        self.transactions.extend(row.transactions);
+        Ok(())
+    }
+
+    // Seconds since the Unix epoch of the import of the file with the SHA-256
+    pub fn imported_at(&self, file: &str) -> Option<u64> {
+        self.files.get(file).copied()
+    }
+}
+
+fn invalid() -> Error {
+    BillingError::InvalidArgumentValue.into()
+}
+
+fn parse_row(line: &str) -> Result<SettlementRow, Error> {
+    let [reference, amount, currency, transaction] = &bulk::split_csv_line(line)?[..] else {
+        return Err(invalid());
+    };
+    let currency = Currency::from_code(currency.trim()).ok_or_else(invalid)?;
+    let amount = MoneyUnit::parse(amount.trim(), currency)?;
+    if amount.is_negative() || amount.is_zero() || reference.trim().is_empty() {
+        return Err(invalid());
+    }
+    Ok(SettlementRow {
+        reference: reference.trim().to_owned(),
+        amount,
+        transaction: Some(transaction.trim().to_owned()).filter(|id| !id.is_empty()),
+    })
+}
+
+// The account the reference matches, if it has not been erased
+fn account(state: &State, reference: &str) -> Option<UserId> {
+    let by_metadata = state
+        .users
+        .read()
+        .iter()
+        .find(|(_, user_data)| {
+            user_data.metadata.get(REFERENCE_KEY).map(String::as_str) == Some(reference)
+        })
+        .map(|(&user, _)| user);
+    let user = match by_metadata {
+        Some(user) => user,
+        None => UserId(reference.parse().ok()?),
+    };
+    state
+        .users
+        .get(&user)
+        .filter(|user_data| user_data.deleted.is_none())
+        .map(|_| user)
+}
+
+// Credits the matched rows of the settlement file, all or none of them
+pub fn import(state: &mut State, text: &str) -> Result<SettlementReport, Error> {
+    let file = sha256_hex(text.as_bytes());
+    if state.settlements.files.contains_key(&file) {
+        return Err(BillingError::DuplicateSettlement.into());
+    }
+    let mut lines = text.lines().enumerate();
+    if lines.next().map(|(_, line)| line.trim()) != Some(CSV_HEADER) {
+        return Err(
+            HostError::Persistence(format!("the CSV header must be `{CSV_HEADER}`")).into(),
+        );
+    }
+    let mut report = SettlementReport {
+        file,
+        ..SettlementReport::default()
+    };
+    // The balances of the accounts after the rows so far
+    let mut balances = BTreeMap::new();
+    let mut credits = Vec::new();
+    let mut seen = BTreeSet::new();
+    for (i, line) in lines.filter(|(_, line)| !line.trim().is_empty()) {
+        let row = i + 1;
+        let settlement = match parse_row(line) {
+            Ok(settlement) => settlement,
+            Err(error) => {
+                report.errors.push(RowError { row, error });
+                continue;
+            }
+        };
+        if let Some(transaction) = &settlement.transaction {
+            if state.settlements.transactions.contains(transaction)
+                || !seen.insert(transaction.clone())
+            {
+                report.duplicates.push(row);
+                continue;
+            }
+        }
+        let Some(user) = account(state, &settlement.reference) else {
+            report.unmatched.push(UnmatchedRow { row, settlement });
+            continue;
+        };
+        let balance = *balances
+            .entry(user)
+            .or_insert_with(|| state.users.get(&user).unwrap().balance);
+        match balance + settlement.amount {
+            Ok(balance) => {
+                balances.insert(user, balance);
+                report.credited.push((row, user, settlement.amount));
+                credits.push((user, settlement));
+            }
+            Err(error) => report.errors.push(RowError { row, error }),
+        }
+    }
+    if !report.errors.is_empty() {
+        report.credited.clear();
+        return Ok(report);
+    }
+    let transactions = credits
+        .iter()
+        .filter_map(|(_, settlement)| settlement.transaction.clone())
+        .collect();
+    state.settlements.record(JournalRow {
+        file: report.file.clone(),
+        at: ledger::now_secs(),
+        transactions,
+    })?;
+    for (user, settlement) in credits {
+        let user_data = state.users.get_mut(&user).unwrap();
+        // Cannot fail, the balances were checked above
+        let balance = (user_data.balance + settlement.amount).unwrap();
+        user_data.balance = balance;
+        let transaction = settlement.transaction.unwrap_or_default();
+        user_data.ledger.push(LedgerEntry::new(
+            EntryKind::TopUp {
+                transaction: transaction.clone(),
+            },
+            settlement.amount,
+            balance,
+        ));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{store::UserStore, UserData};
+
+    const USER: UserId = UserId(0);
+
+    // A user with 10.00
+    fn state() -> State {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(1_000)));
+        State::new(users)
+    }
+
+    fn balance(state: &State) -> MoneyUnit {
+        state.users.get(&USER).unwrap().balance
+    }
+
+    fn file(rows: &[&str]) -> String {
+        let mut text = format!("{CSV_HEADER}\n");
+        for row in rows {
+            text.push_str(row);
+            text.push('\n');
+        }
+        text
+    }
+
+    #[test]
+    fn invalid_row_fails_the_whole_file() {
+        let mut state = state();
+        let text = file(&["0,5.00,USD,tx1", "0,five,USD,tx2", "0,1.00,EUR,tx3"]);
+        let report = import(&mut state, &text).unwrap();
+        let rows = report.errors.iter().map(|e| e.row).collect::<Vec<_>>();
+        assert_eq!(rows, [3, 4]);
+        assert!(report.credited.is_empty());
+        assert_eq!(balance(&state), MoneyUnit::from_cents(1_000));
+        // Neither the file nor its payments are remembered, so the fixed file can be imported
+        assert_eq!(state.settlements.imported_at(&report.file), None);
+        let report = import(&mut state, &file(&["0,5.00,USD,tx1"])).unwrap();
+        assert_eq!(report.credited, [(2, USER, MoneyUnit::from_cents(500))]);
+        assert_eq!(balance(&state), MoneyUnit::from_cents(1_500));
+    }
+
+    #[test]
+    fn unmatched_rows_are_reported_by_line() {
+        let mut state = state();
+        let text = file(&["0,5.00,USD,tx1", "", "42,2.00,USD,tx2"]);
+        let report = import(&mut state, &text).unwrap();
+        assert_eq!(report.credited, [(2, USER, MoneyUnit::from_cents(500))]);
+        let unmatched = report.unmatched.iter().map(|u| u.row).collect::<Vec<_>>();
+        assert_eq!(unmatched, [4]);
+        assert_eq!(report.unmatched[0].settlement.reference, "42");
+        assert_eq!(balance(&state), MoneyUnit::from_cents(1_500));
+    }
+
+    #[test]
+    fn duplicate_files_and_payments_are_not_credited_twice() {
+        let mut state = state();
+        let text = file(&["0,5.00,USD,tx1", "0,5.00,USD,tx1"]);
+        let report = import(&mut state, &text).unwrap();
+        assert_eq!(report.duplicates, [3]);
+        assert!(matches!(
+            import(&mut state, &text),
+            Err(Error::Billing(BillingError::DuplicateSettlement))
+        ));
+        // An overlapping file of the provider
+        let report = import(&mut state, &file(&["0,5.00,USD,tx1", "0,1.00,USD,tx2"])).unwrap();
+        assert_eq!(report.duplicates, [2]);
+        assert_eq!(report.credited, [(3, USER, MoneyUnit::from_cents(100))]);
+        assert_eq!(balance(&state), MoneyUnit::from_cents(1_600));
+    }
+
+    #[test]
+    fn journal_remembers_the_imports_across_restarts() {
+        let path = std::env::temp_dir().join(format!("settlements-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let text = file(&["0,5.00,USD,tx1"]);
+        let mut state = state();
+        state.settlements = Settlements::open(&path).unwrap();
+        import(&mut state, &text).unwrap();
+
+        let mut state = self::state();
+        state.settlements = Settlements::open(&path).unwrap();
+        assert!(matches!(
+            import(&mut state, &text),
+            Err(Error::Billing(BillingError::DuplicateSettlement))
+        ));
+        let report = import(&mut state, &file(&["0,5.00,USD,tx1", ""])).unwrap();
+        assert_eq!(report.duplicates, [2]);
+        assert_eq!(balance(&state), MoneyUnit::from_cents(1_000));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
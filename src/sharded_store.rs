@@ -0,0 +1,341 @@
+// The accounts of `store::UserStore`, spread across independently locked shards by the hash of
+// the user id, so that threads sharing the store and working with different users rarely wait
+// for each other. Holding the store mutably needs no locks at all, so the single-threaded paths
+// pay only for the lookup of the shard.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    },
+};
+
+use crate::{config::ShardingConfig, UserData, UserId};
+
+type Users = BTreeMap<UserId, UserData>;
+
+#[derive(Default)]
+struct Shard {
+    users: RwLock<Users>,
+    acquisitions: AtomicU64,
+    // Acquisitions that had to wait for another thread to release the lock
+    contended: AtomicU64,
+}
+
+// A panic while the lock was held leaves the accounts as they were when it happened, which is
+// no worse than the panic itself
+fn unpoisoned<G>(result: Result<G, std::sync::PoisonError<G>>) -> G {
+    result.unwrap_or_else(|e| e.into_inner())
+}
+
+impl Shard {
+    fn read(&self) -> RwLockReadGuard<'_, Users> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        match self.users.try_read() {
+            Ok(users) => users,
+            Err(TryLockError::WouldBlock) => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                unpoisoned(self.users.read())
+            }
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        }
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Users> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        match self.users.try_write() {
+            Ok(users) => users,
+            Err(TryLockError::WouldBlock) => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                unpoisoned(self.users.write())
+            }
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut Users {
+        unpoisoned(self.users.get_mut())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShardContention {
+    pub acquisitions: u64,
+    pub contended: u64,
+}
+
+// The account of a user, holding the read lock of its shard
+pub struct UserRef<'a> {
+    users: RwLockReadGuard<'a, Users>,
+    user: UserId,
+}
+
+impl Deref for UserRef<'_> {
+    type Target = UserData;
+
+    fn deref(&self) -> &UserData {
+        // Present when the reference was made, and the lock keeps it there
+        &self.users[&self.user]
+    }
+}
+
+// All the accounts, holding the read locks of all the shards, so that reports see a consistent
+// state of the accounts while other threads wait to update them
+pub struct UsersView<'a> {
+    shards: Vec<RwLockReadGuard<'a, Users>>,
+}
+
+impl UsersView<'_> {
+    // In the order of the user ids
+    pub fn iter(&self) -> impl Iterator<Item = (&UserId, &UserData)> {
+        let mut users = self
+            .shards
+            .iter()
+            .flat_map(|users| users.iter())
+            .collect::<Vec<_>>();
+        users.sort_unstable_by_key(|(user, _)| **user);
+        users.into_iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|users| users.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct ShardedUserStore {
+    shards: Vec<Shard>,
+}
+
+impl Default for ShardedUserStore {
+    fn default() -> Self {
+        Self::new(&ShardingConfig::default())
+    }
+}
+
+impl ShardedUserStore {
+    pub fn new(config: &ShardingConfig) -> Self {
+        Self {
+            shards: (0..config.shards.max(1))
+                .map(|_| Shard::default())
+                .collect(),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    // `DefaultHasher::new` uses fixed keys, so a user always maps to the same shard
+    pub fn shard_of(&self, user: &UserId) -> usize {
+        let mut hasher = DefaultHasher::new();
+        user.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, user: &UserId) -> &Shard {
+        &self.shards[self.shard_of(user)]
+    }
+
+    fn shard_mut(&mut self, user: &UserId) -> &mut Users {
+        let shard = self.shard_of(user);
+        self.shards[shard].get_mut()
+    }
+
+    pub fn insert(&mut self, user: UserId, data: UserData) -> Option<UserData> {
+        self.shard_mut(&user).insert(user, data)
+    }
+
+    pub fn remove(&mut self, user: &UserId) -> Option<UserData> {
+        self.shard_mut(user).remove(user)
+    }
+
+    pub fn get(&self, user: &UserId) -> Option<UserRef<'_>> {
+        let users = self.shard(user).read();
+        users
+            .contains_key(user)
+            .then_some(UserRef { users, user: *user })
+    }
+
+    pub fn get_mut(&mut self, user: &UserId) -> Option<&mut UserData> {
+        self.shard_mut(user).get_mut(user)
+    }
+
+    pub fn contains(&self, user: &UserId) -> bool {
+        self.shard(user).read().contains_key(user)
+    }
+
+    // Locks the shards in their order, so that two views never deadlock each other
+    pub fn read(&self) -> UsersView<'_> {
+        UsersView {
+            shards: self.shards.iter().map(Shard::read).collect(),
+        }
+    }
+
+    // In the order of the user ids
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&UserId, &mut UserData)> {
+        let mut users = self
+            .shards
+            .iter_mut()
+            .flat_map(|shard| shard.get_mut().iter_mut())
+            .collect::<Vec<_>>();
+        users.sort_unstable_by_key(|(user, _)| **user);
+        users.into_iter()
+    }
+
+    // Updates the user's account while holding the write lock of its shard, e.g. from one of the
+    // threads sharing the store. Returns `None` if the user is unknown.
+    pub fn with_user_mut<R>(&self, user: &UserId, f: impl FnOnce(&mut UserData) -> R) -> Option<R> {
+        self.shard(user).write().get_mut(user).map(f)
+    }
+
+    // Lock acquisitions per shard, in the order of the shards
+    pub fn contention(&self) -> Vec<ShardContention> {
+        self.shards
+            .iter()
+            .map(|shard| ShardContention {
+                acquisitions: shard.acquisitions.load(Ordering::Relaxed),
+                contended: shard.contended.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Barrier, thread, time::Duration};
+
+    use super::*;
+    use crate::{money::MoneyUnit, store::UserStore};
+
+    fn store(shards: usize) -> ShardedUserStore {
+        let mut store = ShardedUserStore::new(&ShardingConfig { shards });
+        for user in 0..32 {
+            store.insert(UserId(user), UserData::new(MoneyUnit::from_cents(100)));
+        }
+        store
+    }
+
+    // Two users whose accounts are in distinct shards
+    fn apart(store: &ShardedUserStore) -> (UserId, UserId) {
+        let first = UserId(0);
+        let second = (1..32)
+            .map(UserId)
+            .find(|user| store.shard_of(user) != store.shard_of(&first))
+            .unwrap();
+        (first, second)
+    }
+
+    #[test]
+    fn work_on_one_shard_does_not_block_another() {
+        let store = store(4);
+        let (busy, idle) = apart(&store);
+        let (busy_shard, idle_shard) = (store.shard_of(&busy), store.shard_of(&idle));
+        let (holding, released) = (Barrier::new(2), Barrier::new(2));
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                store.with_user_mut(&busy, |_| {
+                    holding.wait();
+                    released.wait();
+                });
+            });
+            holding.wait();
+            // The lock of the busy shard is held until the release below
+            store.with_user_mut(&idle, |user_data| user_data.hosting_days_left = 1);
+            assert_eq!(store.get(&idle).unwrap().hosting_days_left, 1);
+            assert!(store.contains(&idle));
+            released.wait();
+        });
+        let contention = store.contention();
+        assert_eq!(contention[idle_shard].contended, 0);
+        assert_eq!(contention[idle_shard].acquisitions, 3);
+        assert_eq!(contention[busy_shard].acquisitions, 1);
+    }
+
+    #[test]
+    fn waiting_for_a_busy_shard_is_counted() {
+        let store = store(4);
+        let (busy, _) = apart(&store);
+        let holding = Barrier::new(2);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                store.with_user_mut(&busy, |user_data| {
+                    holding.wait();
+                    thread::sleep(Duration::from_millis(50));
+                    user_data.hosting_days_left = 2;
+                });
+            });
+            holding.wait();
+            // Sees the update, having waited for it
+            assert_eq!(store.get(&busy).unwrap().hosting_days_left, 2);
+        });
+        assert_eq!(store.contention()[store.shard_of(&busy)].contended, 1);
+    }
+
+    #[test]
+    fn views_iterate_every_shard_in_the_order_of_the_ids() {
+        let mut store = store(4);
+        assert!(store.remove(&UserId(7)).is_some());
+        let view = store.read();
+        assert_eq!(view.len(), 31);
+        let users = view.iter().map(|(&user, _)| user.0).collect::<Vec<_>>();
+        assert_eq!(users, (0..32).filter(|&user| user != 7).collect::<Vec<_>>());
+        // Every shard is locked once for the view
+        drop(view);
+        let contention = store.contention();
+        assert!(contention.iter().all(|shard| shard.acquisitions == 1));
+    }
+
+    #[test]
+    fn views_see_no_update_half_done() {
+        let store = store(4);
+        let (first, second) = apart(&store);
+        thread::scope(|scope| {
+            let view = store.read();
+            let transfer = scope.spawn(|| {
+                store.with_user_mut(&first, |user_data| user_data.hosting_days_left += 1);
+                store.with_user_mut(&second, |user_data| user_data.hosting_days_left += 1);
+            });
+            let days = view
+                .iter()
+                .map(|(_, user_data)| user_data.hosting_days_left)
+                .sum::<u32>();
+            assert_eq!(days, 0);
+            drop(view);
+            transfer.join().unwrap();
+        });
+        let days = store
+            .read()
+            .iter()
+            .map(|(_, user_data)| user_data.hosting_days_left)
+            .sum::<u32>();
+        assert_eq!(days, 2);
+    }
+
+    #[test]
+    fn at_least_one_shard_is_kept() {
+        let store = ShardedUserStore::new(&ShardingConfig { shards: 0 });
+        assert_eq!(store.shard_count(), 1);
+        assert!(store.get(&UserId(0)).is_none());
+        assert!(store.with_user_mut(&UserId(0), |_| ()).is_none());
+    }
+
+    #[test]
+    fn user_store_lookups_go_through_the_shards() {
+        let mut users = UserStore::with_sharding(&ShardingConfig { shards: 2 });
+        users.insert(UserId(0), UserData::new(MoneyUnit::from_cents(100)));
+        // Holding the store mutably takes no locks
+        users.get_mut(&UserId(0)).unwrap().hosting_days_left = 3;
+        assert_eq!(users.get(&UserId(0)).unwrap().hosting_days_left, 3);
+        let contention = users.contention();
+        assert_eq!(contention.len(), 2);
+        let acquisitions = contention.iter().map(|shard| shard.acquisitions);
+        assert_eq!(acquisitions.sum::<u64>(), 1);
+    }
+}
@@ -0,0 +1,261 @@
+// Service-level agreements of the catalog services. The provisioned services of every user
+// are probed with `Provisioner::health_check` by `check_health`, which operators call
+// periodically, e.g. every minute. A failed check opens an incident, which the next passing
+// check resolves. The users are notified about the incidents opened, see `notifications`. The
+// guests read the health of the services of an order with `host.service_health` and the
+// incidents with `host.incidents_since`, e.g. to serve status pages. At the end of every month
+// (UTC) the services whose availability, the share of passing checks, fell below the
+// `ServiceLevel` of the catalog are credited a share of their price as an
+// `EntryKind::SlaCredit` entry, which shows on the invoices, and the tracking starts over.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::{
+    ledger::{self, EntryKind, LedgerEntry},
+    money::MoneyUnit,
+    notifications::{self, NotificationEvent},
+    orders::{self, OrderId},
+    BillingError, Error, State, UserId,
+};
+
+// The whole in basis points
+const FULL: u64 = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServiceLevel {
+    // The promised monthly availability in basis points, e.g. 9_990 for 99.9%
+    pub availability_basis_points: u32,
+    // The share of the price credited when the availability falls short, in basis points
+    pub credit_basis_points: u32,
+}
+
+// A period during which the health checks of a service failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Incident {
+    // Seconds since the Unix epoch
+    pub started_at: u64,
+    // `None` while the service is still failing its checks
+    pub resolved_at: Option<u64>,
+}
+
+// The health checks of a service of a user since the start of the month
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServiceUptime {
+    pub checks: u32,
+    pub failed_checks: u32,
+    // Oldest first
+    pub incidents: Vec<Incident>,
+}
+
+impl ServiceUptime {
+    // The share of passing checks in basis points, `None` before the first check
+    pub fn availability_basis_points(&self) -> Option<u32> {
+        let passed = (self.checks - self.failed_checks) as u64;
+        (self.checks > 0).then(|| (passed * FULL / self.checks as u64) as u32)
+    }
+
+    // Returns whether the check opened an incident
+    fn record(&mut self, healthy: bool, now: u64) -> bool {
+        self.checks += 1;
+        let open = self
+            .incidents
+            .last_mut()
+            .filter(|incident| incident.resolved_at.is_none());
+        let opened = match (healthy, open) {
+            (true, Some(incident)) => {
+                incident.resolved_at = Some(now);
+                false
+            }
+            (false, None) => {
+                self.incidents.push(Incident {
+                    started_at: now,
+                    resolved_at: None,
+                });
+                true
+            }
+            _ => false,
+        };
+        if !healthy {
+            self.failed_checks += 1;
+        }
+        opened
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServiceHealth {
+    // Every checked service passed its last check
+    Healthy,
+    // Some of the services are failing their checks
+    Degraded,
+    // Every checked service is failing its checks
+    Down,
+    // None of the services has been checked yet this month
+    Unknown,
+}
+
+impl ServiceHealth {
+    // The value returned by `host.service_health`
+    pub const fn code(self) -> i32 {
+        match self {
+            ServiceHealth::Healthy => 0,
+            ServiceHealth::Degraded => 1,
+            ServiceHealth::Down => 2,
+            ServiceHealth::Unknown => 3,
+        }
+    }
+}
+
+// The health of the services provisioned by the order of the user, by their last checks.
+// Orders that provision no services, e.g. hosting orders and cancelled bundles, fail with
+// `BillingError::UnknownService`.
+pub fn order_health(state: &State, user: UserId, id: OrderId) -> Result<ServiceHealth, Error> {
+    let order = orders::get(state, user, id)?;
+    let services = orders::services(state, order)?;
+    if services.is_empty() {
+        return Err(BillingError::UnknownService.into());
+    }
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let (mut checked, mut failing) = (0, 0);
+    for service in &services {
+        let Some(uptime) = user_data
+            .service_uptime
+            .get(service)
+            .filter(|uptime| uptime.checks > 0)
+        else {
+            continue;
+        };
+        checked += 1;
+        if uptime
+            .incidents
+            .last()
+            .is_some_and(|incident| incident.resolved_at.is_none())
+        {
+            failing += 1;
+        }
+    }
+    Ok(match (checked, failing) {
+        (0, _) => ServiceHealth::Unknown,
+        (_, 0) => ServiceHealth::Healthy,
+        (checked, failing) if checked == failing => ServiceHealth::Down,
+        _ => ServiceHealth::Degraded,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct IncidentRecord {
+    pub service: String,
+    // Seconds since the Unix epoch
+    pub started_at: u64,
+    pub resolved_at: Option<u64>,
+}
+
+// The incidents of the services of the user this month that were still open at `since`
+// (seconds since the Unix epoch) or started later, by their starts
+pub fn incidents_since(
+    state: &State,
+    user: UserId,
+    since: u64,
+) -> Result<Vec<IncidentRecord>, Error> {
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let mut records = user_data
+        .service_uptime
+        .iter()
+        .flat_map(|(service, uptime)| {
+            uptime.incidents.iter().map(|incident| IncidentRecord {
+                service: service.clone(),
+                started_at: incident.started_at,
+                resolved_at: incident.resolved_at,
+            })
+        })
+        .filter(|record| {
+            record
+                .resolved_at
+                .is_none_or(|resolved_at| resolved_at >= since)
+        })
+        .collect::<Vec<_>>();
+    records.sort_by_key(|record| record.started_at);
+    Ok(records)
+}
+
+// The incidents as JSON Lines for `host.incidents_since`, e.g.
+// `{"service":"db","started_at":1700000000,"resolved_at":null}`
+pub fn incidents_json(records: &[IncidentRecord]) -> String {
+    records
+        .iter()
+        .map(|record| serde_json::to_string(record).unwrap() + "\n")
+        .collect()
+}
+
+// Probes every provisioned service of every user once
+pub fn check_health(state: &mut State) {
+    let now = ledger::now_secs();
+    let mut opened = Vec::new();
+    for (&user, user_data) in state.users.iter_mut() {
+        for service in &user_data.services {
+            let healthy = state.provisioner.health_check(user, service);
+            let uptime = user_data.service_uptime.entry(service.clone()).or_default();
+            if uptime.record(healthy, now) {
+                opened.push((user, service.clone()));
+            }
+        }
+    }
+    for (user, service) in opened {
+        let message = format!("The service {service} is failing its health checks.");
+        notifications::notify(state, user, NotificationEvent::Incident, message);
+    }
+}
+
+// Credits the services that fell short of their service level and starts the tracking
+// over, returning the credits by user and service. Services without a service level are
+// never credited, and credits that would overflow the balance are not made.
+pub fn settle_month(state: &mut State) -> BTreeMap<(UserId, String), MoneyUnit> {
+    let catalog = &state.config.catalog;
+    let mut credits = BTreeMap::new();
+    for (&user, user_data) in state.users.iter_mut() {
+        let uptime = std::mem::take(&mut user_data.service_uptime);
+        for (service, uptime) in uptime {
+            let Some((price, level)) = catalog
+                .services
+                .get(&service)
+                .and_then(|s| Some((s.price, s.sla?)))
+            else {
+                continue;
+            };
+            let fell_short = uptime
+                .availability_basis_points()
+                .is_some_and(|availability| availability < level.availability_basis_points);
+            let Some(credit) = price
+                .basis_points(level.credit_basis_points)
+                .filter(|credit| fell_short && !credit.is_zero())
+            else {
+                continue;
+            };
+            let Ok(balance) = user_data.balance + credit else {
+                continue;
+            };
+            user_data.balance = balance;
+            user_data.ledger.push(LedgerEntry::new(
+                EntryKind::SlaCredit {
+                    service: service.clone(),
+                },
+                credit,
+                balance,
+            ));
+            credits.insert((user, service), credit);
+        }
+    }
+    credits
+}
+
+// The daily job: settles the service levels of the past month on the first day of the month
+pub(crate) fn advance_day(state: &mut State) {
+    let first_of_month = OffsetDateTime::from_unix_timestamp(ledger::now_secs() as i64)
+        .is_ok_and(|date| date.day() == 1);
+    if first_of_month {
+        settle_month(state);
+    }
+}
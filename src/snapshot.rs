@@ -0,0 +1,126 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use wasmtime::{Extern, Instance, Mutability, Val};
+
+use crate::{runtime::SMStore, Error, HostError};
+
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    // Floats are kept as raw bits to preserve NaN payloads
+    F32(u32),
+    F64(u64),
+}
+
+// The exported linear memories and mutable globals of a guest instance.
+// A snapshot can only be restored into an instance of the same module.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub module_hash: String,
+    pub memories: Vec<(String, Vec<u8>)>,
+    pub globals: Vec<(String, GlobalValue)>,
+}
+
+fn to_global_value(val: Val) -> Option<GlobalValue> {
+    match val {
+        Val::I32(v) => Some(GlobalValue::I32(v)),
+        Val::I64(v) => Some(GlobalValue::I64(v)),
+        Val::F32(v) => Some(GlobalValue::F32(v)),
+        Val::F64(v) => Some(GlobalValue::F64(v)),
+        // References cannot outlive the store
+        _ => None,
+    }
+}
+
+impl From<GlobalValue> for Val {
+    fn from(value: GlobalValue) -> Self {
+        match value {
+            GlobalValue::I32(v) => Val::I32(v),
+            GlobalValue::I64(v) => Val::I64(v),
+            GlobalValue::F32(v) => Val::F32(v),
+            GlobalValue::F64(v) => Val::F64(v),
+        }
+    }
+}
+
+// `module_hash` identifies the module version that the instance was created from,
+// see `crate::module_hash`.
+pub fn snapshot(
+    store: &mut SMStore,
+    instance: &Instance,
+    module_hash: &str,
+) -> Result<Snapshot, Error> {
+    let mut memories = Vec::new();
+    let mut globals = Vec::new();
+    let exports = instance
+        .exports(&mut *store)
+        .map(|export| (export.name().to_owned(), export.into_extern()))
+        .collect::<Vec<_>>();
+    for (name, ext) in exports {
+        match ext {
+            Extern::Memory(memory) => memories.push((name, memory.data(&*store).to_vec())),
+            Extern::Global(global) if global.ty(&*store).mutability() == Mutability::Var => {
+                let value = to_global_value(global.get(&mut *store)).ok_or(HostError::Snapshot)?;
+                globals.push((name, value));
+            }
+            _ => {}
+        }
+    }
+    Ok(Snapshot {
+        module_hash: module_hash.to_owned(),
+        memories,
+        globals,
+    })
+}
+
+pub fn restore(
+    store: &mut SMStore,
+    instance: &Instance,
+    module_hash: &str,
+    snapshot: &Snapshot,
+) -> Result<(), Error> {
+    if snapshot.module_hash != module_hash {
+        return Err(HostError::SnapshotModuleMismatch.into());
+    }
+    for (name, data) in &snapshot.memories {
+        let memory = instance
+            .get_memory(&mut *store, name)
+            .ok_or(HostError::Snapshot)?;
+        let current = memory.data_size(&*store);
+        if current < data.len() {
+            let delta = (data.len() - current).div_ceil(WASM_PAGE_SIZE);
+            memory
+                .grow(&mut *store, delta as u64)
+                .map_err(|_| HostError::Snapshot)?;
+        }
+        let memory_data = memory.data_mut(&mut *store);
+        memory_data[..data.len()].copy_from_slice(data);
+        // Memories cannot shrink, the pages grown after the snapshot are cleared instead
+        memory_data[data.len()..].fill(0);
+    }
+    for (name, value) in &snapshot.globals {
+        let global = instance
+            .get_global(&mut *store, name)
+            .ok_or(HostError::Snapshot)?;
+        global
+            .set(&mut *store, Val::from(*value))
+            .map_err(|_| HostError::Snapshot)?;
+    }
+    Ok(())
+}
+
+impl Snapshot {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let bytes = bincode::serialize(self).map_err(|e| HostError::Persistence(e.to_string()))?;
+        std::fs::write(path, bytes).map_err(|e| HostError::Persistence(e.to_string()).into())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let bytes = std::fs::read(path).map_err(|e| HostError::Persistence(e.to_string()))?;
+        bincode::deserialize(&bytes).map_err(|e| HostError::Persistence(e.to_string()).into())
+    }
+}
@@ -0,0 +1,104 @@
+// The accounts and audit logs kept in a SQLite file, e.g. by a deployment on a single host, to be
+// migrated to another backend with `store migrate --from sqlite:<file>`, see `migrate`. The
+// accounts are stored as the JSON of `migrate::AccountRecord`.
+
+use std::{collections::BTreeMap, path::Path};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    migrate::{AccountBackend, AccountRecord},
+    BillingError, Error, HostError, UserId,
+};
+
+fn persistence_error(e: impl ToString) -> Error {
+    HostError::Persistence(e.to_string()).into()
+}
+
+pub struct SqliteAccountStore {
+    connection: Connection,
+}
+
+impl SqliteAccountStore {
+    // Creates the file and the tables if they do not exist
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let connection = Connection::open(path).map_err(persistence_error)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS accounts (
+                    user_id INTEGER PRIMARY KEY,
+                    record TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS audit_logs (
+                    name TEXT PRIMARY KEY,
+                    jsonl BLOB NOT NULL
+                );",
+            )
+            .map_err(persistence_error)?;
+        Ok(Self { connection })
+    }
+}
+
+impl AccountBackend for SqliteAccountStore {
+    fn users(&mut self) -> Result<Vec<UserId>, Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT user_id FROM accounts ORDER BY user_id")
+            .map_err(persistence_error)?;
+        let users = statement
+            .query_map([], |row| row.get::<_, i64>(0))
+            .map_err(persistence_error)?;
+        users
+            .map(|user| user.map(|user| UserId(user as usize)))
+            .collect::<Result<_, _>>()
+            .map_err(persistence_error)
+    }
+
+    fn account(&mut self, user: UserId) -> Result<AccountRecord, Error> {
+        let record = self
+            .connection
+            .query_row(
+                "SELECT record FROM accounts WHERE user_id = ?1",
+                params![user.0 as i64],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()
+            .map_err(persistence_error)?
+            .ok_or(BillingError::UnknownUser)?;
+        serde_json::from_str(&record).map_err(persistence_error)
+    }
+
+    fn put_account(&mut self, user: UserId, account: &AccountRecord) -> Result<(), Error> {
+        let record = serde_json::to_string(account).map_err(persistence_error)?;
+        self.connection
+            .execute(
+                "INSERT INTO accounts (user_id, record) VALUES (?1, ?2)
+                ON CONFLICT (user_id) DO UPDATE SET record = ?2",
+                params![user.0 as i64, record],
+            )
+            .map_err(persistence_error)?;
+        Ok(())
+    }
+
+    fn audit_logs(&mut self) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT name, jsonl FROM audit_logs")
+            .map_err(persistence_error)?;
+        let logs = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(persistence_error)?;
+        logs.collect::<Result<_, _>>().map_err(persistence_error)
+    }
+
+    fn put_audit_log(&mut self, name: &str, jsonl: &[u8]) -> Result<(), Error> {
+        self.connection
+            .execute(
+                "INSERT INTO audit_logs (name, jsonl) VALUES (?1, ?2)
+                ON CONFLICT (name) DO UPDATE SET jsonl = ?2",
+                params![name, jsonl],
+            )
+            .map_err(persistence_error)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ledger, money::MoneyUnit, BillingError, Error, HostError, UserId};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DayStats {
+    pub revenue_cents: i64,
+    pub active_users: BTreeSet<UserId>,
+    pub invocations: u64,
+    pub failed_invocations: u64,
+    // The peak linear memories of the invocations in WebAssembly pages
+    #[serde(default)]
+    pub max_memory_pages: u64,
+    #[serde(default)]
+    pub total_memory_pages: u64,
+    #[serde(default)]
+    pub metered_invocations: u64,
+}
+
+// Per-day aggregates kept for `retention_days`, meant to be persisted
+// between restarts with `save` and `load`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Stats {
+    // Keyed by the number of days since the Unix epoch
+    days: BTreeMap<u64, DayStats>,
+    retention_days: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DailyRevenue {
+    pub day: u64,
+    pub revenue_cents: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsReport {
+    pub window_days: u64,
+    pub revenue_per_day: Vec<DailyRevenue>,
+    pub total_revenue_cents: i64,
+    pub active_users: usize,
+    pub invocations: u64,
+    pub failed_invocations: u64,
+    pub error_rate: f64,
+    pub max_memory_pages: u64,
+    pub avg_memory_pages: f64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new(90)
+    }
+}
+
+fn today() -> u64 {
+    ledger::now_secs() / SECS_PER_DAY
+}
+
+impl Stats {
+    pub fn new(retention_days: u64) -> Self {
+        Self {
+            days: BTreeMap::new(),
+            retention_days,
+        }
+    }
+
+    fn today_mut(&mut self) -> &mut DayStats {
+        let today = today();
+        // Old days are pruned lazily, whenever a new sample is recorded
+        let oldest_kept = today.saturating_sub(self.retention_days);
+        self.days = self.days.split_off(&oldest_kept);
+        self.days.entry(today).or_default()
+    }
+
+    pub fn record_revenue(&mut self, amount: MoneyUnit) {
+        let day = self.today_mut();
+        day.revenue_cents = day.revenue_cents.saturating_add(amount.to_cents_as_i64());
+    }
+
+    pub fn record_active_user(&mut self, user: UserId) {
+        self.today_mut().active_users.insert(user);
+    }
+
+    pub fn record_invocation(&mut self, succeeded: bool) {
+        let day = self.today_mut();
+        day.invocations += 1;
+        if !succeeded {
+            day.failed_invocations += 1;
+        }
+    }
+
+    pub fn record_memory(&mut self, peak_pages: u64) {
+        let day = self.today_mut();
+        day.max_memory_pages = day.max_memory_pages.max(peak_pages);
+        day.total_memory_pages = day.total_memory_pages.saturating_add(peak_pages);
+        day.metered_invocations += 1;
+    }
+
+    // Aggregates over the last `window_days` days, including today.
+    pub fn query(&self, window_days: u64) -> StatsReport {
+        let first_day = today().saturating_sub(window_days.saturating_sub(1));
+        let mut active_users = BTreeSet::new();
+        let mut report = StatsReport {
+            window_days,
+            revenue_per_day: Vec::new(),
+            total_revenue_cents: 0,
+            active_users: 0,
+            invocations: 0,
+            failed_invocations: 0,
+            error_rate: 0.0,
+            max_memory_pages: 0,
+            avg_memory_pages: 0.0,
+        };
+        let (mut total_memory_pages, mut metered_invocations) = (0u64, 0u64);
+        for (&day, stats) in self.days.range(first_day..) {
+            report.revenue_per_day.push(DailyRevenue {
+                day,
+                revenue_cents: stats.revenue_cents,
+            });
+            report.total_revenue_cents = report
+                .total_revenue_cents
+                .saturating_add(stats.revenue_cents);
+            active_users.extend(stats.active_users.iter().copied());
+            report.invocations += stats.invocations;
+            report.failed_invocations += stats.failed_invocations;
+            report.max_memory_pages = report.max_memory_pages.max(stats.max_memory_pages);
+            total_memory_pages = total_memory_pages.saturating_add(stats.total_memory_pages);
+            metered_invocations += stats.metered_invocations;
+        }
+        report.active_users = active_users.len();
+        if report.invocations > 0 {
+            report.error_rate = report.failed_invocations as f64 / report.invocations as f64;
+        }
+        if metered_invocations > 0 {
+            report.avg_memory_pages = total_memory_pages as f64 / metered_invocations as f64;
+        }
+        report
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let json =
+            serde_json::to_string(self).map_err(|e| HostError::Persistence(e.to_string()))?;
+        std::fs::write(path, json).map_err(|e| HostError::Persistence(e.to_string()).into())
+    }
+
+    // Loads the persisted stats, starting afresh if there are none yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| HostError::Persistence(e.to_string()).into()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(HostError::Persistence(e.to_string()).into()),
+        }
+    }
+}
+
+// Parses windows like `7d`, `2w` or a bare number of days.
+pub fn parse_window(window: &str) -> Result<u64, Error> {
+    let (number, multiplier) = match window.as_bytes().last() {
+        Some(b'd') => (&window[..window.len() - 1], 1),
+        Some(b'w') => (&window[..window.len() - 1], 7),
+        _ => (window, 1),
+    };
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(multiplier)
+            .ok_or(BillingError::InvalidArgumentValue.into()),
+        _ => Err(BillingError::InvalidArgumentValue.into()),
+    }
+}
+
+// Serves a `/stats?window=7d` request given its query string.
+// The window defaults to 7 days.
+pub fn stats_endpoint(stats: &Stats, query: &str) -> Result<String, Error> {
+    let window = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(key, value)| (key == "window").then_some(value))
+        .unwrap_or("7d");
+    let report = stats.query(parse_window(window)?);
+    serde_json::to_string(&report).map_err(|e| HostError::Persistence(e.to_string()).into())
+}
@@ -0,0 +1,283 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::StorageConfig,
+    ledger::{EntryKind, LedgerEntry},
+    money::MoneyUnit,
+    plan::Plan,
+    quota, BillingError, Error, HostError, State, UserData, UserId,
+};
+
+const BYTES_PER_GB: u64 = 1_000_000_000;
+const MAX_KEY_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageOp {
+    Put,
+    Get,
+    Delete,
+}
+
+// The backend keeping the objects. The paths are already namespaced by the user
+// and validated, see `object_path`.
+pub trait ObjectStore {
+    fn put(&mut self, path: &str, bytes: &[u8]) -> Result<(), Error>;
+    // Returns `None` if the object does not exist
+    fn get(&mut self, path: &str) -> Result<Option<Vec<u8>>, Error>;
+    fn delete(&mut self, path: &str) -> Result<(), Error>;
+}
+
+// Keeps the objects as files under the root directory
+pub struct LocalDirObjectStore {
+    root: PathBuf,
+}
+
+impl LocalDirObjectStore {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+        }
+    }
+}
+
+fn storage_error(e: impl ToString) -> Error {
+    HostError::Storage(e.to_string()).into()
+}
+
+impl ObjectStore for LocalDirObjectStore {
+    fn put(&mut self, path: &str, bytes: &[u8]) -> Result<(), Error> {
+        let path = self.root.join(path);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(storage_error)?;
+        }
+        std::fs::write(path, bytes).map_err(storage_error)
+    }
+
+    fn get(&mut self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        match std::fs::read(self.root.join(path)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(storage_error(e)),
+        }
+    }
+
+    fn delete(&mut self, path: &str) -> Result<(), Error> {
+        match std::fs::remove_file(self.root.join(path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(storage_error(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Keeps the objects in a bucket of S3 or of an S3-compatible service such as MinIO
+#[cfg(feature = "s3")]
+pub struct S3ObjectStore {
+    bucket: s3::Bucket,
+}
+
+#[cfg(feature = "s3")]
+impl S3ObjectStore {
+    // `endpoint` is the URL of the service, e.g. `http://localhost:9000` for a local MinIO.
+    // Path-style addressing is used since S3-compatible services rarely support the virtual host
+    // style.
+    pub fn new(
+        endpoint: &str,
+        region: &str,
+        bucket: &str,
+        access_key: &str,
+        secret_key: &str,
+    ) -> Result<Self, Error> {
+        let region = s3::Region::Custom {
+            region: region.to_owned(),
+            endpoint: endpoint.to_owned(),
+        };
+        let credentials = s3::creds::Credentials {
+            access_key: Some(access_key.to_owned()),
+            secret_key: Some(secret_key.to_owned()),
+            security_token: None,
+            session_token: None,
+            expiration: None,
+        };
+        let bucket = s3::Bucket::new(bucket, region, credentials).map_err(storage_error)?;
+        Ok(Self {
+            bucket: bucket.with_path_style(),
+        })
+    }
+}
+
+#[cfg(feature = "s3")]
+impl ObjectStore for S3ObjectStore {
+    fn put(&mut self, path: &str, bytes: &[u8]) -> Result<(), Error> {
+        let response = self.bucket.put_object(path, bytes).map_err(storage_error)?;
+        match response.status_code() {
+            200..=299 => Ok(()),
+            status => Err(HostError::Storage(format!("PUT returned {status}")).into()),
+        }
+    }
+
+    fn get(&mut self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self.bucket.get_object(path) {
+            Ok(response) => match response.status_code() {
+                200..=299 => Ok(Some(response.to_vec())),
+                404 => Ok(None),
+                status => Err(HostError::Storage(format!("GET returned {status}")).into()),
+            },
+            Err(s3::error::S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(storage_error(e)),
+        }
+    }
+
+    fn delete(&mut self, path: &str) -> Result<(), Error> {
+        let response = self.bucket.delete_object(path).map_err(storage_error)?;
+        match response.status_code() {
+            200..=299 | 404 => Ok(()),
+            status => Err(HostError::Storage(format!("DELETE returned {status}")).into()),
+        }
+    }
+}
+
+// Keys consist of `/`-separated segments of ASCII letters, digits, `.`, `-` and `_`,
+// which keeps every object inside the user's namespace of any backend.
+pub(crate) fn object_path(user: UserId, key: &str) -> Result<String, Error> {
+    let valid_segment = |segment: &str| {
+        !segment.is_empty()
+            && segment != "."
+            && segment != ".."
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+    };
+    if key.len() > MAX_KEY_LEN || !key.split('/').all(valid_segment) {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    Ok(format!("{}/{key}", user.0))
+}
+
+pub fn stored_bytes(user_data: &UserData) -> u64 {
+    user_data.objects.values().sum()
+}
+
+// Computes the balance after paying for the request, without writing it yet
+fn request_charge(user_data: &UserData, config: &StorageConfig) -> Result<MoneyUnit, Error> {
+    user_data.balance - config.price_per_request
+}
+
+fn charge_request(
+    user_data: &mut UserData,
+    config: &StorageConfig,
+    op: StorageOp,
+    balance: MoneyUnit,
+) {
+    user_data.balance = balance;
+    if !config.price_per_request.is_zero() {
+        user_data.ledger.push(LedgerEntry::new(
+            EntryKind::StorageRequest { op },
+            config.price_per_request.checked_neg().unwrap(),
+            balance,
+        ));
+    }
+}
+
+// Stores the object, replacing the existing one with the same key.
+// Fails without charging if the object would not fit into the user's quota, see `quota`.
+pub fn put(state: &mut State, user: UserId, key: &str, bytes: &[u8]) -> Result<(), Error> {
+    let config = state.config.storage;
+    let path = object_path(user, key)?;
+    let object_store = state
+        .object_store
+        .as_mut()
+        .ok_or(BillingError::StorageUnavailable)?;
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    let replaced = user_data.objects.get(key).copied().unwrap_or(0);
+    quota::check(&user_data, &config, replaced, bytes.len() as u64)?;
+    let balance = request_charge(&user_data, &config)?;
+    drop(user_data);
+
+    object_store.put(&path, bytes)?;
+    let user_data = state.users.get_mut(&user).unwrap();
+    user_data.objects.insert(key.to_owned(), bytes.len() as u64);
+    charge_request(user_data, &config, StorageOp::Put, balance);
+    Ok(())
+}
+
+pub fn get(state: &mut State, user: UserId, key: &str) -> Result<Vec<u8>, Error> {
+    let config = state.config.storage;
+    let path = object_path(user, key)?;
+    let object_store = state
+        .object_store
+        .as_mut()
+        .ok_or(BillingError::StorageUnavailable)?;
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    if !user_data.objects.contains_key(key) {
+        return Err(BillingError::UnknownObject.into());
+    }
+    let balance = request_charge(&user_data, &config)?;
+    drop(user_data);
+
+    let bytes = object_store
+        .get(&path)?
+        .ok_or(BillingError::UnknownObject)?;
+    let user_data = state.users.get_mut(&user).unwrap();
+    charge_request(user_data, &config, StorageOp::Get, balance);
+    Ok(bytes)
+}
+
+pub fn delete(state: &mut State, user: UserId, key: &str) -> Result<(), Error> {
+    let config = state.config.storage;
+    let path = object_path(user, key)?;
+    let object_store = state
+        .object_store
+        .as_mut()
+        .ok_or(BillingError::StorageUnavailable)?;
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    if !user_data.objects.contains_key(key) {
+        return Err(BillingError::UnknownObject.into());
+    }
+    let balance = request_charge(&user_data, &config)?;
+    drop(user_data);
+
+    object_store.delete(&path)?;
+    let user_data = state.users.get_mut(&user).unwrap();
+    user_data.objects.remove(key);
+    charge_request(user_data, &config, StorageOp::Delete, balance);
+    Ok(())
+}
+
+// Records a day of keeping the user's objects and files, billed at the end of the cycle
+pub(crate) fn record_storage_day(user_data: &mut UserData) {
+    let bytes = quota::usage(user_data).total();
+    user_data.stored_byte_days = user_data.stored_byte_days.saturating_add(bytes);
+}
+
+// Bills the bytes stored every day of the billing cycle that ended. Partial gigabytes are
+// billed proportionally, rounding up to the minor unit. Accounts that cannot pay get suspended.
+pub(crate) fn charge_storage_cycle(user_data: &mut UserData, config: &StorageConfig) {
+    let byte_days = std::mem::take(&mut user_data.stored_byte_days);
+    let price = config.price_per_gb_day;
+    let minor =
+        (byte_days as u128 * price.minor_units().max(0) as u128).div_ceil(BYTES_PER_GB as u128);
+    let Ok(minor) = i64::try_from(minor) else {
+        user_data.plan = Plan::Suspended;
+        return;
+    };
+    if minor == 0 {
+        return;
+    }
+    let cost = MoneyUnit::from_minor_units(minor, price.currency());
+    match user_data.balance - cost {
+        Ok(balance) => {
+            user_data.balance = balance;
+            user_data.ledger.push(LedgerEntry::new(
+                EntryKind::StorageCycle { byte_days },
+                cost.checked_neg().unwrap(),
+                balance,
+            ));
+        }
+        Err(_) => user_data.plan = Plan::Suspended,
+    }
+}
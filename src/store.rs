@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    audit::AuditLog,
+    auth::{TokenEvent, TokenRecord},
+    billing,
+    config::{Config, ShardingConfig},
+    domains::DomainRegistry,
+    money::MoneyUnit,
+    sharded_store::{ShardContention, ShardedUserStore, UserRef, UsersView},
+    BillingError, Error, UserData, UserId,
+};
+
+// In-memory store of the user accounts and the data attached to them. The accounts are
+// iterated in the order of their ids, e.g. for snapshot tests, and kept in the shards of
+// `sharded_store`, whose locks are taken only by the lookups through `&self`.
+#[derive(Default)]
+pub struct UserStore {
+    users: ShardedUserStore,
+    // API tokens are never stored in plain text, only their SHA-256 hashes
+    pub(crate) tokens: BTreeMap<String, TokenRecord>,
+    pub(crate) token_audit: AuditLog<TokenEvent>,
+    pub(crate) domains: DomainRegistry,
+}
+
+impl UserStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sharding(config: &ShardingConfig) -> Self {
+        Self {
+            users: ShardedUserStore::new(config),
+            ..Self::default()
+        }
+    }
+
+    pub fn insert(&mut self, user: UserId, data: UserData) -> Option<UserData> {
+        self.users.insert(user, data)
+    }
+
+    // Holds the read lock of the user's shard until the reference is dropped
+    pub fn get(&self, user: &UserId) -> Option<UserRef<'_>> {
+        self.users.get(user)
+    }
+
+    pub fn get_mut(&mut self, user: &UserId) -> Option<&mut UserData> {
+        self.users.get_mut(user)
+    }
+
+    pub fn contains(&self, user: &UserId) -> bool {
+        self.users.contains(user)
+    }
+
+    // All the accounts as of the call, see `UsersView`
+    pub fn read(&self) -> UsersView<'_> {
+        self.users.read()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&UserId, &mut UserData)> {
+        self.users.iter_mut()
+    }
+
+    // Updates the user's account through a store shared between threads
+    pub fn with_user_mut<R>(&self, user: &UserId, f: impl FnOnce(&mut UserData) -> R) -> Option<R> {
+        self.users.with_user_mut(user, f)
+    }
+
+    // Lock acquisitions per shard, see `sharded_store`
+    pub fn contention(&self) -> Vec<ShardContention> {
+        self.users.contention()
+    }
+
+    // Creates an account for a new user, starting a free trial if trials are enabled.
+    pub fn create_user(
+        &mut self,
+        user: UserId,
+        balance: MoneyUnit,
+        config: &Config,
+    ) -> Result<&mut UserData, Error> {
+        if self.contains(&user) {
+            return Err(BillingError::UserAlreadyExists.into());
+        }
+        let mut user_data = UserData::new(balance);
+        billing::start_trial(&mut user_data, &config.trial);
+        self.users.insert(user, user_data);
+        self.users
+            .get_mut(&user)
+            .ok_or(BillingError::UnknownUser.into())
+    }
+
+    // The id after the highest one taken
+    pub fn next_id(&self) -> Result<UserId, Error> {
+        match self.users.read().iter().map(|(&user, _)| user).max() {
+            Some(last) => Ok(UserId(
+                last.0
+                    .checked_add(1)
+                    .ok_or(BillingError::InvalidArgumentValue)?,
+            )),
+            None => Ok(UserId(0)),
+        }
+    }
+
+    // Creates an account under the next free id with the starting balance
+    // of the registration terms, remembering who referred the new user.
+    pub fn register_user(
+        &mut self,
+        config: &Config,
+        referrer: Option<UserId>,
+    ) -> Result<UserId, Error> {
+        let user = self.next_id()?;
+        let user_data = self.create_user(user, config.registration.starting_balance, config)?;
+        user_data.referred_by = referrer;
+        Ok(user)
+    }
+}
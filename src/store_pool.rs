@@ -0,0 +1,88 @@
+// Recycled stores for the hosts that run every invocation in a store of its own, e.g. to
+// isolate the invocations of different users. Creating a store allocates its `State`, with the
+// WASI context and the collections of the services, and the bookkeeping of the engine, so
+// `StorePool::checkout` hands out an idle store instead, and `StorePool::checkin` resets it
+// for the next invocation with `WasmRuntime::recycle`, clearing the data of the invocation that
+// ended and keeping the rest of the state, e.g. the accounts, and the compiled modules warm.
+//
+// A store keeps the instances created in it until it is dropped, so a store is recycled at
+// most `StorePoolConfig::max_uses` times, after which it is dropped and replaced. See
+// `benches/store_pool.rs` for the throughput compared to a new store per invocation.
+
+use crate::{instance_pool::PoolStats, runtime::WasmRuntime, State};
+
+#[derive(Clone, Copy, Debug)]
+pub struct StorePoolConfig {
+    // The idle stores kept at most, the others are dropped when they are checked in
+    pub max_idle: usize,
+    // The invocations a store serves before it is dropped
+    pub max_uses: u32,
+}
+
+impl Default for StorePoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: 8,
+            max_uses: 1_000,
+        }
+    }
+}
+
+// A store checked out of the pool, to be checked in once its invocation has ended
+pub struct PooledStore<R: WasmRuntime> {
+    pub store: R::Store,
+    uses: u32,
+}
+
+pub struct StorePool<R: WasmRuntime> {
+    config: StorePoolConfig,
+    idle: Vec<PooledStore<R>>,
+    stats: PoolStats,
+}
+
+impl<R: WasmRuntime> StorePool<R> {
+    pub fn new(config: StorePoolConfig) -> Self {
+        Self {
+            config,
+            idle: Vec::new(),
+            stats: PoolStats::default(),
+        }
+    }
+
+    // The hits are the checkouts served by an idle store
+    pub fn stats(&self) -> PoolStats {
+        self.stats
+    }
+
+    pub fn idle(&self) -> usize {
+        self.idle.len()
+    }
+
+    // An idle store, most recently used first, or a new one with the state built by `state`
+    pub fn checkout(&mut self, runtime: &R, state: impl FnOnce() -> State) -> PooledStore<R> {
+        match self.idle.pop() {
+            Some(store) => {
+                self.stats.hits += 1;
+                store
+            }
+            None => {
+                self.stats.misses += 1;
+                PooledStore {
+                    store: runtime.new_store(state()),
+                    uses: 0,
+                }
+            }
+        }
+    }
+
+    // Resets the store for its next invocation and keeps it, unless it has served
+    // `StorePoolConfig::max_uses` invocations or the pool is full
+    pub fn checkin(&mut self, runtime: &R, mut store: PooledStore<R>) {
+        store.uses += 1;
+        if store.uses >= self.config.max_uses || self.idle.len() >= self.config.max_idle {
+            return;
+        }
+        runtime.recycle(&mut store.store);
+        self.idle.push(store);
+    }
+}
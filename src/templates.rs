@@ -0,0 +1,89 @@
+// Templates of the new accounts by the tiers of the customers, e.g. `premium`, so that the
+// accounts of a tier are created with `create_user` or `users add --template <name>` of the CLI
+// rather than set up field by field. A template gives the starting balance, the plan, the
+// capabilities, the environment variables and the preopened directories of the accounts, see
+// `Config::templates`. The environment and the directories are those of the WASI context of
+// the preview2 components of the account, see `State::component_wasi`, the core modules sharing
+// the WASI context of the host.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use wasmtime_wasi::{
+    preview2::{DirPerms, FilePerms, WasiCtxBuilder},
+    sync::{ambient_authority, Dir},
+};
+
+use crate::{
+    capability::Capability, money::MoneyUnit, plan::Plan, BillingError, Error, HostError, State,
+    UserData, UserId,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct AccountTemplate {
+    pub starting_balance: MoneyUnit,
+    // `None` for the plan of the other new accounts, e.g. a trial if trials are enabled
+    pub plan: Option<Plan>,
+    pub capabilities: Vec<Capability>,
+    pub env: BTreeMap<String, String>,
+    // The directories of the host by their paths in the guests, with `{user}` replaced by the
+    // id of the account, e.g. `/srv/premium/{user}`. They are created with the account.
+    pub dirs: BTreeMap<String, PathBuf>,
+}
+
+fn user_dir(template: &Path, user: UserId) -> PathBuf {
+    PathBuf::from(
+        template
+            .to_string_lossy()
+            .replace("{user}", &user.0.to_string()),
+    )
+}
+
+// Creates the account from the template of `Config::templates`
+pub fn create_user(state: &mut State, user: UserId, template: &str) -> Result<(), Error> {
+    let template = state
+        .config
+        .templates
+        .get(template)
+        .ok_or(BillingError::UnknownTemplate)?;
+    if state.users.contains(&user) {
+        return Err(BillingError::UserAlreadyExists.into());
+    }
+    let dirs = template
+        .dirs
+        .iter()
+        .map(|(guest, host)| (guest.clone(), user_dir(host, user)))
+        .collect::<BTreeMap<_, _>>();
+    for dir in dirs.values() {
+        std::fs::create_dir_all(dir).map_err(|e| HostError::Storage(e.to_string()))?;
+    }
+    let user_data = state
+        .users
+        .create_user(user, template.starting_balance, &state.config)?;
+    if let Some(plan) = template.plan {
+        user_data.plan = plan;
+    }
+    user_data
+        .capabilities
+        .extend(template.capabilities.iter().copied());
+    user_data.env = template.env.clone();
+    user_data.dirs = dirs;
+    Ok(())
+}
+
+// The WASI context of the preview2 components of the user with its environment variables and its
+// preopened directories, which the guests may read and write
+pub fn wasi_builder(user_data: &UserData) -> Result<WasiCtxBuilder, Error> {
+    let mut builder = WasiCtxBuilder::new();
+    for (key, value) in &user_data.env {
+        builder.env(key, value);
+    }
+    for (guest, host) in &user_data.dirs {
+        let dir = Dir::open_ambient_dir(host, ambient_authority())
+            .map_err(|e| HostError::Storage(e.to_string()))?;
+        builder.preopened_dir(dir, DirPerms::all(), FilePerms::all(), guest);
+    }
+    Ok(builder)
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{disputes::Actor, ledger, BillingError, Error, State, UserId};
+
+const MAX_SUBJECT_LEN: usize = 200;
+const MAX_BODY_LEN: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TicketId(pub u64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TicketStatus {
+    // Waiting for an operator
+    Open,
+    Answered,
+    Closed,
+}
+
+#[derive(Clone, Debug)]
+pub struct TicketMessage {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    pub author: Actor,
+    pub body: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Ticket {
+    pub id: TicketId,
+    pub user: UserId,
+    pub subject: String,
+    pub status: TicketStatus,
+    // The conversation, oldest first, starting with the message the ticket was opened with
+    pub messages: Vec<TicketMessage>,
+}
+
+// Notified whenever a ticket changes its status, including when it is opened,
+// e.g. to page the operators or to email the user about a reply.
+pub trait TicketListener {
+    fn on_status_change(&mut self, ticket: &Ticket);
+}
+
+pub struct NoopTicketListener;
+
+impl TicketListener for NoopTicketListener {
+    fn on_status_change(&mut self, _ticket: &Ticket) {}
+}
+
+#[derive(Default)]
+pub struct Tickets {
+    tickets: BTreeMap<TicketId, Ticket>,
+    next_id: u64,
+}
+
+impl Tickets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, id: TicketId) -> Option<&Ticket> {
+        self.tickets.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Ticket> {
+        self.tickets.values()
+    }
+
+    pub fn of_user(&self, user: UserId) -> impl Iterator<Item = &Ticket> {
+        self.iter().filter(move |ticket| ticket.user == user)
+    }
+
+    // Removes the tickets of the user, returning how many there were, see `erasure`
+    pub(crate) fn remove_user(&mut self, user: UserId) -> usize {
+        let before = self.tickets.len();
+        self.tickets.retain(|_, ticket| ticket.user != user);
+        before - self.tickets.len()
+    }
+
+    // The tickets waiting for an operator, oldest first
+    pub fn open(&self) -> impl Iterator<Item = &Ticket> {
+        self.iter()
+            .filter(|ticket| ticket.status == TicketStatus::Open)
+    }
+}
+
+fn set_status(state: &mut State, id: TicketId, status: TicketStatus) {
+    let ticket = state.tickets.tickets.get_mut(&id).unwrap();
+    ticket.status = status;
+    state.ticket_listener.on_status_change(ticket);
+}
+
+fn validate(body: &str) -> Result<(), Error> {
+    if body.trim().is_empty() || body.len() > MAX_BODY_LEN {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    Ok(())
+}
+
+// Opens a ticket on behalf of the user. The tickets that are not closed count
+// towards the quota of the user's plan, see `config::TicketConfig`.
+pub fn open(state: &mut State, user: UserId, subject: &str, body: &str) -> Result<TicketId, Error> {
+    if subject.trim().is_empty() || subject.len() > MAX_SUBJECT_LEN {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    validate(body)?;
+    let plan = state
+        .users
+        .get(&user)
+        .ok_or(BillingError::UnknownUser)?
+        .plan;
+    let unresolved = state
+        .tickets
+        .of_user(user)
+        .filter(|ticket| ticket.status != TicketStatus::Closed)
+        .count();
+    let quota = state.config.tickets.quota(plan);
+    if unresolved >= quota as usize {
+        return Err(Error::from(BillingError::TicketQuotaExceeded)
+            .with_context("limit", quota as i64)
+            .with_context("unresolved", unresolved as i64));
+    }
+    let id = TicketId(state.tickets.next_id);
+    state.tickets.next_id += 1;
+    state.tickets.tickets.insert(
+        id,
+        Ticket {
+            id,
+            user,
+            subject: subject.to_owned(),
+            status: TicketStatus::Open,
+            messages: vec![TicketMessage {
+                at: ledger::now_secs(),
+                author: Actor::User(user),
+                body: body.to_owned(),
+            }],
+        },
+    );
+    set_status(state, id, TicketStatus::Open);
+    Ok(id)
+}
+
+// Adds a message to the conversation. A reply of an operator marks the ticket as answered
+// and a reply of its user reopens it.
+pub fn reply(state: &mut State, id: TicketId, author: Actor, body: &str) -> Result<(), Error> {
+    validate(body)?;
+    let ticket = state
+        .tickets
+        .tickets
+        .get_mut(&id)
+        .ok_or(BillingError::UnknownTicket)?;
+    if ticket.status == TicketStatus::Closed {
+        return Err(BillingError::TicketClosed.into());
+    }
+    if matches!(author, Actor::User(user) if user != ticket.user) {
+        return Err(BillingError::UnknownTicket.into());
+    }
+    let status = match author {
+        Actor::User(_) => TicketStatus::Open,
+        Actor::Operator(_) => TicketStatus::Answered,
+    };
+    ticket.messages.push(TicketMessage {
+        at: ledger::now_secs(),
+        author,
+        body: body.to_owned(),
+    });
+    if ticket.status != status {
+        set_status(state, id, status);
+    }
+    Ok(())
+}
+
+pub fn close(state: &mut State, id: TicketId) -> Result<(), Error> {
+    let ticket = state.tickets.get(id).ok_or(BillingError::UnknownTicket)?;
+    if ticket.status == TicketStatus::Closed {
+        return Err(BillingError::TicketClosed.into());
+    }
+    set_status(state, id, TicketStatus::Closed);
+    Ok(())
+}
@@ -0,0 +1,72 @@
+// End-to-end tracing of the invocations. Every invocation runs within a trace, identified by
+// a trace id in the format of the W3C Trace Context (32 lowercase hex digits), which is either
+// propagated by the embedder with `propagate`, e.g. from the `traceparent` header of the request
+// that triggered the invocation, or generated. The guest reads it with `host.trace_id`, and the
+// execution record, the ledger entries, the alerts and the log lines of the host made during
+// the invocation carry it, so that they can be correlated in an observability backend.
+
+use std::cell::RefCell;
+
+use rand::RngCore;
+
+use crate::{BillingError, Error, State};
+
+const ID_LEN: usize = 32;
+
+thread_local! {
+    // The trace of the invocation running on the thread
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// A random trace id
+pub fn new_id() -> String {
+    new_id_from(&mut rand::thread_rng())
+}
+
+pub(crate) fn new_id_from(rng: &mut impl RngCore) -> String {
+    let mut bytes = [0u8; ID_LEN / 2];
+    rng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn is_valid(id: &str) -> bool {
+    id.len() == ID_LEN
+        && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        && id.bytes().any(|b| b != b'0')
+}
+
+// Runs the next invocation within the trace of the id instead of a new one
+pub fn propagate(state: &mut State, id: &str) -> Result<(), Error> {
+    if !is_valid(id) {
+        return Err(BillingError::InvalidArgumentValue.into());
+    }
+    state.next_trace_id = Some(id.to_owned());
+    Ok(())
+}
+
+// The trace of the invocation running on the thread, if any
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// The prefix of the log lines of the host, e.g. `[trace 4bf9...] `, empty outside of traces
+pub fn log_prefix() -> String {
+    current().map_or_else(String::new, |id| format!("[trace {id}] "))
+}
+
+// Leaves the trace when dropped, restoring the one the thread was in
+pub(crate) struct Span {
+    previous: Option<String>,
+}
+
+// Enters the trace for an invocation
+pub(crate) fn enter(id: String) -> Span {
+    let previous = CURRENT.with(|current| current.replace(Some(id)));
+    Span { previous }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
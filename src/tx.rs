@@ -0,0 +1,193 @@
+// Transactions let a guest place several orders atomically with `host.begin_tx`,
+// `host.commit_tx` and `host.rollback_tx`: either all of their charges and provisions
+// apply or none do. The user's data as of `begin` is kept aside and restored on rollback,
+// while the provisioning of the ordered services is deferred until the commit.
+//
+// Only the calls confined to the user's own data may be made within a transaction,
+// the others (e.g. transfers or sending emails) fail with `BillingError::TransactionActive`.
+// A transaction left open when the guest returns is rolled back.
+
+use crate::{BillingError, Error, State, UserData, UserId};
+
+pub struct Transaction {
+    user: UserId,
+    // The user's data as of `begin`
+    original: UserData,
+    // The services to provision on commit, in provisioning order
+    deferred: Vec<String>,
+}
+
+pub fn is_active(state: &State) -> bool {
+    state.transaction.is_some()
+}
+
+// Fails the calls that cannot be rolled back while a transaction is open
+pub(crate) fn ensure_inactive(state: &State) -> Result<(), Error> {
+    match is_active(state) {
+        true => Err(BillingError::TransactionActive.into()),
+        false => Ok(()),
+    }
+}
+
+pub fn begin(state: &mut State, user: UserId) -> Result<(), Error> {
+    ensure_inactive(state)?;
+    let user_data = state.users.get(&user).ok_or(BillingError::UnknownUser)?;
+    state.transaction = Some(Transaction {
+        user,
+        original: user_data.clone(),
+        deferred: Vec::new(),
+    });
+    Ok(())
+}
+
+// Provisions the services on commit instead of right away
+pub(crate) fn defer_provisioning(state: &mut State, services: Vec<String>) {
+    if let Some(transaction) = &mut state.transaction {
+        transaction.deferred.extend(services);
+    }
+}
+
+pub fn rollback(state: &mut State) -> Result<(), Error> {
+    let transaction = state
+        .transaction
+        .take()
+        .ok_or(BillingError::NoTransaction)?;
+    state.users.insert(transaction.user, transaction.original);
+    Ok(())
+}
+
+// Provisions the deferred services and keeps the changes. If any service fails to provision,
+// the provisioned ones are deprovisioned in reverse order and the transaction is rolled back.
+// Returns the length of the user's ledger as of `begin`, so that the charges made within
+// the transaction can be settled by the caller.
+pub fn commit(state: &mut State) -> Result<usize, Error> {
+    let transaction = state
+        .transaction
+        .as_ref()
+        .ok_or(BillingError::NoTransaction)?;
+    let (user, deferred) = (transaction.user, transaction.deferred.clone());
+    for (i, service) in deferred.iter().enumerate() {
+        if let Err(e) = state.provisioner.provision(user, service) {
+            for provisioned in deferred[..i].iter().rev() {
+                state.provisioner.deprovision(user, provisioned);
+            }
+            rollback(state)?;
+            return Err(e);
+        }
+    }
+    let transaction = state.transaction.take().unwrap();
+    Ok(transaction.original.ledger.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        host,
+        money::MoneyUnit,
+        services::{Bundle, Provisioner, Service},
+        sharded_store::UserRef,
+        store::UserStore,
+        HostError,
+    };
+
+    const USER: UserId = UserId(0);
+
+    // Records the provisions, e.g. `+db`, and the deprovisions, e.g. `-db`, failing to
+    // provision `failing`
+    struct RecordingProvisioner {
+        events: Rc<RefCell<Vec<String>>>,
+        failing: &'static str,
+    }
+
+    impl Provisioner for RecordingProvisioner {
+        fn provision(&mut self, _user: UserId, service: &str) -> Result<(), Error> {
+            if service == self.failing {
+                return Err(HostError::ProvisioningFailed(service.to_owned()).into());
+            }
+            self.events.borrow_mut().push(format!("+{service}"));
+            Ok(())
+        }
+
+        fn deprovision(&mut self, _user: UserId, service: &str) {
+            self.events.borrow_mut().push(format!("-{service}"));
+        }
+    }
+
+    // A user with 1000.00 and a bundle of `db` and `web` at 100.00 each, provisioned in this
+    // order, with the events of the provisioner
+    fn state(failing: &'static str) -> (State, Rc<RefCell<Vec<String>>>) {
+        let mut users = UserStore::new();
+        users.insert(USER, UserData::new(MoneyUnit::from_cents(100_000)));
+        let mut state = State::new(users);
+        let catalog = &mut state.config.catalog;
+        for (name, depends_on) in [("db", vec![]), ("web", vec!["db".to_owned()])] {
+            let service = Service {
+                price: MoneyUnit::from_cents(10_000),
+                depends_on,
+                sla: None,
+            };
+            catalog.services.insert(name.to_owned(), service);
+        }
+        let bundle = Bundle {
+            services: vec!["web".to_owned(), "db".to_owned()],
+        };
+        catalog.bundles.insert("stack".to_owned(), bundle);
+        let events = Rc::default();
+        state.provisioner = Box::new(RecordingProvisioner {
+            events: Rc::clone(&events),
+            failing,
+        });
+        (state, events)
+    }
+
+    fn user_data(state: &State) -> UserRef<'_> {
+        state.users.get(&USER).unwrap()
+    }
+
+    #[test]
+    fn rolled_back_charge_is_undone() {
+        let (mut state, events) = state("");
+        begin(&mut state, USER).unwrap();
+        host::order_bundle(&mut state, USER, "stack").unwrap();
+        assert_eq!(user_data(&state).balance, MoneyUnit::from_cents(80_000));
+        // Nothing is provisioned before the commit
+        assert!(events.borrow().is_empty());
+
+        rollback(&mut state).unwrap();
+        assert!(!is_active(&state));
+        assert_eq!(user_data(&state).balance, MoneyUnit::from_cents(100_000));
+        assert!(user_data(&state).ledger.is_empty());
+        assert!(user_data(&state).services.is_empty());
+        assert!(events.borrow().is_empty());
+    }
+
+    #[test]
+    fn commit_provisions_in_dependency_order() {
+        let (mut state, events) = state("");
+        begin(&mut state, USER).unwrap();
+        host::order_bundle(&mut state, USER, "stack").unwrap();
+        assert_eq!(commit(&mut state).unwrap(), 0);
+        assert!(!is_active(&state));
+        assert_eq!(*events.borrow(), ["+db", "+web"]);
+        assert_eq!(user_data(&state).balance, MoneyUnit::from_cents(80_000));
+    }
+
+    #[test]
+    fn failed_provision_at_commit_rolls_back() {
+        let (mut state, events) = state("web");
+        begin(&mut state, USER).unwrap();
+        host::order_bundle(&mut state, USER, "stack").unwrap();
+        assert!(matches!(
+            commit(&mut state),
+            Err(Error::Host(HostError::ProvisioningFailed(_)))
+        ));
+        assert!(!is_active(&state));
+        // `db` was provisioned, then deprovisioned
+        assert_eq!(*events.borrow(), ["+db", "-db"]);
+        assert_eq!(user_data(&state).balance, MoneyUnit::from_cents(100_000));
+        assert!(user_data(&state).services.is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+// The WebAssembly proposals the modules of the users may use, by plan, see `WasmFeatureConfig`,
+// e.g. to keep SIMD for the paid plans. Unlike `EngineConfig::simd` and
+// `EngineConfig::bulk_memory`, which apply to every module the engine compiles, the proposals
+// are checked for each module against the plan of its user, so that the plans share an engine.
+// The modules and components using a proposal their plan does not allow are rejected before
+// they are compiled with `HostError::ModuleRestricted`, whose message names the proposal.
+
+use wasmparser::{Validator, WasmFeatures};
+
+use crate::{plan::Plan, Error, HostError, State, UserId};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WasmProposal {
+    // Including relaxed SIMD
+    Simd,
+    BulkMemory,
+    ReferenceTypes,
+}
+
+impl WasmProposal {
+    pub const ALL: [WasmProposal; 3] = [
+        WasmProposal::Simd,
+        WasmProposal::BulkMemory,
+        WasmProposal::ReferenceTypes,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WasmProposal::Simd => "SIMD",
+            WasmProposal::BulkMemory => "bulk memory",
+            WasmProposal::ReferenceTypes => "reference types",
+        }
+    }
+
+    fn enable(self, features: &mut WasmFeatures, enabled: bool) {
+        match self {
+            WasmProposal::Simd => {
+                features.simd = enabled;
+                features.relaxed_simd = enabled;
+            }
+            WasmProposal::BulkMemory => features.bulk_memory = enabled,
+            WasmProposal::ReferenceTypes => features.reference_types = enabled,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllowedProposals {
+    pub simd: bool,
+    pub bulk_memory: bool,
+    pub reference_types: bool,
+}
+
+impl Default for AllowedProposals {
+    fn default() -> Self {
+        Self {
+            simd: true,
+            bulk_memory: true,
+            reference_types: true,
+        }
+    }
+}
+
+impl AllowedProposals {
+    pub fn allows(self, proposal: WasmProposal) -> bool {
+        match proposal {
+            WasmProposal::Simd => self.simd,
+            WasmProposal::BulkMemory => self.bulk_memory,
+            WasmProposal::ReferenceTypes => self.reference_types,
+        }
+    }
+
+    fn denied(self) -> impl Iterator<Item = WasmProposal> {
+        WasmProposal::ALL
+            .into_iter()
+            .filter(move |&proposal| !self.allows(proposal))
+    }
+
+    // Every feature the validator knows but the denied proposals
+    fn features(self) -> WasmFeatures {
+        let mut features = WasmFeatures::all();
+        for proposal in self.denied() {
+            proposal.enable(&mut features, false);
+        }
+        features
+    }
+}
+
+// The proposals allowed by the plan of the user, all of them by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WasmFeatureConfig {
+    pub trial: AllowedProposals,
+    pub paid: AllowedProposals,
+    pub grace: AllowedProposals,
+    pub suspended: AllowedProposals,
+}
+
+impl WasmFeatureConfig {
+    pub fn allowed(&self, plan: Plan) -> AllowedProposals {
+        match plan {
+            Plan::Trial { .. } => self.trial,
+            Plan::Paid => self.paid,
+            Plan::Grace { .. } => self.grace,
+            Plan::Suspended => self.suspended,
+        }
+    }
+}
+
+// The offset of the first invalid byte, if any
+fn validate(bytes: &[u8], features: WasmFeatures) -> Result<(), usize> {
+    Validator::new_with_features(features)
+        .validate_all(bytes)
+        .map(drop)
+        .map_err(|e| e.offset())
+}
+
+fn restricted(proposal: WasmProposal, offset: usize) -> Error {
+    let name = proposal.name();
+    HostError::ModuleRestricted(format!(
+        "the module uses the {name} proposal at offset {offset:#x}, which the plan does not \
+         allow, build it without {name}"
+    ))
+    .into()
+}
+
+// Checks the module or the component, in the binary or the text format, against the allowed
+// proposals. Modules that are invalid anyway are left to fail to compile.
+pub fn check_module(bytes: &[u8], allowed: AllowedProposals) -> Result<(), Error> {
+    if allowed.denied().next().is_none() {
+        return Ok(());
+    }
+    let Ok(bytes) = wat::parse_bytes(bytes) else {
+        return Ok(());
+    };
+    let features = allowed.features();
+    let Err(offset) = validate(&bytes, features) else {
+        return Ok(());
+    };
+    if validate(&bytes, WasmFeatures::all()).is_err() {
+        return Ok(());
+    }
+    // The proposal used at the offset is the one whose enabling moves the first failure further
+    for proposal in allowed.denied() {
+        let mut features = features;
+        proposal.enable(&mut features, true);
+        match validate(&bytes, features) {
+            Err(next) if next <= offset => {}
+            _ => return Err(restricted(proposal, offset)),
+        }
+    }
+    // Only several of the proposals together make the module valid, e.g. when one of them
+    // depends on another
+    Err(restricted(allowed.denied().next().unwrap(), offset))
+}
+
+// Checks the module against the proposals allowed by the plan of the user
+pub fn check(state: &State, user: UserId, bytes: &[u8]) -> Result<(), Error> {
+    match state.users.get(&user) {
+        Some(user_data) => check_module(bytes, state.config.wasm_features.allowed(user_data.plan)),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,1103 @@
+// The host functions as wasmtime functions, linked into the instances of `WasmtimeRuntime`. The
+// functions themselves, their signatures and capabilities, are declared in `host`.
+
+use rand::RngCore;
+use wasmtime::{Caller, Extern, Func, ImportType, Linker, Store, Val, ValType};
+
+use crate::{
+    abi::{self, GuestResult, Payload, RESULT_LEN},
+    alerts,
+    anomalies::{self, Acknowledger},
+    auth,
+    authorization::{self, HostCall},
+    balance_history, billing, cancellation,
+    capability::Capability,
+    cost_centers, cron, db, domains, email, features, guest_memory, history,
+    host::{
+        order_bundle, order_hosting, record_charges, report_error, settle, HostFunction,
+        HOST_FUNCTIONS, HOST_MODULE,
+    },
+    ledger, metering,
+    money::MoneyUnit,
+    notifications,
+    orders::{self, OrderId},
+    pagination::{self, Page},
+    policy::{self, Action},
+    prices, queues, resellers, retention,
+    runtime::WasmtimeState,
+    secrets, sla, storage, tickets, trace, tx, BillingError, Error, State, UserId,
+};
+
+// Writes the result of a mutating call into the buffer registered with
+// `host.set_result_buffer`, if any, and returns its code for the guest.
+fn write_result(caller: &mut Caller<'_, WasmtimeState>, mut result: GuestResult) -> i32 {
+    let state = caller.data();
+    // The error has just been reported, see `report_error`
+    if result.code != 0 && !state.last_error_context.is_empty() {
+        let len = abi::encode_context(&state.last_error_context).len();
+        result.payload = Payload::ErrorContext(len as u64);
+    }
+    if let Some(ptr) = state.result_buffer {
+        // Cannot fail, the buffer was checked when it was registered
+        let _ = guest_memory::write(caller, ptr, RESULT_LEN as i32, &result.encode());
+    }
+    result.code
+}
+
+// The result of a call charging the user, with the order placed by the call on success
+// or, if it was not a purchase, the new balance of the user
+fn charged_result(
+    caller: &mut Caller<'_, WasmtimeState>,
+    user: UserId,
+    outcome: Result<Option<OrderId>, i32>,
+) -> i32 {
+    let result = match outcome {
+        Ok(Some(order)) => GuestResult::ok(Payload::Order(order.0)),
+        Ok(None) => {
+            let balance = caller.data().users.get(&user).unwrap().balance;
+            GuestResult::ok(Payload::Balance(balance.to_cents_as_i64()))
+        }
+        Err(code) => GuestResult::error(code),
+    };
+    write_result(caller, result)
+}
+
+// Reads a UTF-8 string, e.g. the key of an object, from the guest memory
+
+fn read_string(
+    caller: &mut Caller<'_, WasmtimeState>,
+    ptr: i32,
+    len: i32,
+) -> Result<String, Error> {
+    let bytes = guest_memory::read(caller, ptr, len)?;
+    String::from_utf8(bytes).map_err(|_| BillingError::InvalidArgumentValue.into())
+}
+
+pub(crate) fn resolve_or_construct_import(
+    linker: &Linker<WasmtimeState>,
+    mut store: &mut Store<WasmtimeState>,
+    import: ImportType<'_>,
+    user: UserId,
+) -> Option<Extern> {
+    if import.module() != HOST_MODULE {
+        return linker.get_by_import(&mut store, &import);
+    };
+
+    let host_import = match import.name() {
+        "balance" => Func::wrap(&mut store, move |caller: Caller<'_, WasmtimeState>| {
+            caller
+                .data()
+                .users
+                .get(&user)
+                .unwrap()
+                .balance
+                .to_cents_as_i64()
+        }),
+        "order_hosting" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, days: i32| {
+                let outcome = record_charges(caller.data_mut(), user, |state| {
+                    order_hosting(state, user, days)
+                });
+                charged_result(&mut caller, user, outcome)
+            },
+        ),
+        "transfer" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, to_user_id: i64, cents: i64| {
+                let outcome = match usize::try_from(to_user_id) {
+                    Ok(to) => record_charges(caller.data_mut(), user, |state| {
+                        tx::ensure_inactive(state)?;
+                        let fee = state.config.transfer_fee;
+                        let amount = MoneyUnit::from_cents(cents);
+                        policy::authorize(state, user, Action::Transfer, amount)?;
+                        billing::transfer(&mut state.users, fee, user, UserId(to), amount)
+                    }),
+                    Err(_) => Err(report_error(
+                        caller.data_mut(),
+                        BillingError::InvalidArgumentValue,
+                    )),
+                };
+                charged_result(&mut caller, user, outcome)
+            },
+        ),
+        "trial_days_left" => Func::wrap(&mut store, move |caller: Caller<'_, WasmtimeState>| {
+            billing::trial_days_left(&caller.data().users.get(&user).unwrap()) as i32
+        }),
+        "grace_days_remaining" => {
+            Func::wrap(&mut store, move |caller: Caller<'_, WasmtimeState>| {
+                billing::grace_days_left(&caller.data().users.get(&user).unwrap()) as i32
+            })
+        }
+        // Writes up to `len` bytes of the message, translated into the user's locale if possible,
+        // into the buffer and returns the full length of the message, so that the guest can retry
+        // with a larger buffer. Returns 0 if no error has been reported yet.
+        "last_error_message" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let state = caller.data();
+                let Some(message) = state.last_error.clone() else {
+                    return 0;
+                };
+                let locale = state.users.get(&user).unwrap().locale;
+                let message = state
+                    .last_error_code
+                    .and_then(|code| locale.translate(code))
+                    .map_or(message, str::to_owned);
+                match guest_memory::write(&mut caller, ptr, len, message.as_bytes()) {
+                    Ok(_) => message.len() as i32,
+                    Err(e) => -e.code(),
+                }
+            },
+        ),
+        // Tags the rest of the charges of the invocation with the cost center, or leaves them
+        // untagged if `len` is 0. Returns 0 or the negated error code.
+        "set_default_cost_center" => Func::wrap(
+            &mut store,
+            |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let set = match len {
+                    0 => cost_centers::set_default(None),
+                    _ => read_string(&mut caller, ptr, len)
+                        .and_then(|name| cost_centers::set_default(Some(&name))),
+                };
+                match set {
+                    Ok(()) => 0,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        // Returns the price in minor units or the negated error code.
+        "price_of" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32, quantity: i64| {
+                let price = read_string(&mut caller, ptr, len)
+                    .and_then(|name| prices::price_of(caller.data(), user, &name, quantity));
+                match price {
+                    Ok(price) => price.minor_units(),
+                    Err(e) => -report_error(caller.data_mut(), e) as i64,
+                }
+            },
+        ),
+        "price_catalog" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let json = match prices::catalog(caller.data(), user) {
+                    Ok(catalog) => serde_json::to_vec(&catalog).unwrap(),
+                    Err(e) => return -report_error(caller.data_mut(), e),
+                };
+                match guest_memory::write(&mut caller, ptr, len, &json) {
+                    Ok(_) => json.len() as i32,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        "set_notification_pref" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, event: i32, channel: i32, enabled: i32| {
+                let state = caller.data_mut();
+                let result =
+                    match notifications::guest_set_preference(state, user, event, channel, enabled)
+                    {
+                        Ok(()) => GuestResult::ok(Payload::None),
+                        Err(e) => GuestResult::error(report_error(state, e)),
+                    };
+                write_result(&mut caller, result)
+            },
+        ),
+        // Writes up to `len` bytes of the context of the last error, see `abi`, into the buffer
+        // and returns its full length, 0 if the error has no context.
+        "last_error_context" => Func::wrap(
+            &mut store,
+            |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let context = abi::encode_context(&caller.data().last_error_context);
+                if context.is_empty() {
+                    return 0;
+                }
+                match guest_memory::write(&mut caller, ptr, len, &context) {
+                    Ok(_) => context.len() as i32,
+                    Err(e) => -e.code(),
+                }
+            },
+        ),
+        "now_secs" => Func::wrap(&mut store, |_: Caller<'_, WasmtimeState>| {
+            ledger::now_secs() as i64
+        }),
+        "random_u64" => Func::wrap(&mut store, |caller: Caller<'_, WasmtimeState>| {
+            caller.data().random.lock().unwrap().next_u64() as i64
+        }),
+        // Returns the health of the services of the order, see `sla::ServiceHealth::code`, or
+        // the negated error code.
+        "service_health" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, order_id: i64| {
+                let state = caller.data_mut();
+                let health = u64::try_from(order_id)
+                    .map_err(|_| BillingError::UnknownOrder.into())
+                    .and_then(|id| sla::order_health(state, user, OrderId(id)));
+                match health {
+                    Ok(health) => health.code(),
+                    Err(e) => -report_error(state, e),
+                }
+            },
+        ),
+        // Writes up to `len` bytes of the incidents, see `sla::incidents_json`, into the buffer
+        // and returns the full length of the JSON Lines or the negated error code.
+        "incidents_since" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, since: i64, ptr: i32, len: i32| {
+                let state = caller.data_mut();
+                let records = u64::try_from(since)
+                    .map_err(|_| BillingError::InvalidArgumentValue.into())
+                    .and_then(|since| sla::incidents_since(state, user, since));
+                let json = match records {
+                    Ok(records) => sla::incidents_json(&records),
+                    Err(e) => return -report_error(state, e),
+                };
+                match guest_memory::write(&mut caller, ptr, len, json.as_bytes()) {
+                    Ok(_) => json.len() as i32,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        // Write a page of the list into the buffer and return the cursor of the next page or the
+        // negated error code, see `pagination`
+        "ledger_page" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, cursor: i64, ptr: i32, len: i32| {
+                write_page(&mut caller, cursor, ptr, len, |state, cursor, len| {
+                    pagination::ledger_page(state, user, cursor, len)
+                })
+            },
+        ),
+        "orders_page" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, cursor: i64, ptr: i32, len: i32| {
+                write_page(&mut caller, cursor, ptr, len, |state, cursor, len| {
+                    pagination::orders_page(state, user, cursor, len)
+                })
+            },
+        ),
+        // Long-running guests must call it more often than the watchdog allows them to miss,
+        // see `watchdog::WatchdogConfig`.
+        "heartbeat" => Func::wrap(&mut store, |mut caller: Caller<'_, WasmtimeState>| {
+            caller.data_mut().missed_heartbeats = 0;
+        }),
+        // Returns the id of the new user referred by the caller or the negated error code.
+        "register_user" => Func::wrap(&mut store, move |mut caller: Caller<'_, WasmtimeState>| {
+            let state: &mut State = caller.data_mut();
+            let is_admin = state
+                .users
+                .get(&user)
+                .is_some_and(|u| u.capabilities.contains(&Capability::Admin));
+            let registered = match is_admin {
+                true => tx::ensure_inactive(state)
+                    .and_then(|_| state.users.register_user(&state.config, Some(user))),
+                false => Err(BillingError::MissingCapability.into()),
+            };
+            match registered {
+                Ok(new_user) => {
+                    write_result(
+                        &mut caller,
+                        GuestResult::ok(Payload::User(new_user.0 as u64)),
+                    );
+                    new_user.0 as i64
+                }
+                Err(e) => {
+                    let code = report_error(caller.data_mut(), e);
+                    -write_result(&mut caller, GuestResult::error(code)) as i64
+                }
+            }
+        }),
+        "storage_put" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>,
+                  key_ptr: i32,
+                  key_len: i32,
+                  data_ptr: i32,
+                  data_len: i32| {
+                let read = |caller: &mut Caller<'_, WasmtimeState>| -> Result<_, Error> {
+                    Ok((
+                        read_string(caller, key_ptr, key_len)?,
+                        guest_memory::read(caller, data_ptr, data_len)?,
+                    ))
+                };
+                let outcome = match read(&mut caller) {
+                    Ok((key, bytes)) => record_charges(caller.data_mut(), user, |state| {
+                        tx::ensure_inactive(state)?;
+                        storage::put(state, user, &key, &bytes)
+                    }),
+                    Err(e) => Err(report_error(caller.data_mut(), e)),
+                };
+                charged_result(&mut caller, user, outcome)
+            },
+        ),
+        // Writes up to `len` bytes of the object into the buffer and returns the full size
+        // of the object or the negated error code.
+        "storage_get" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>,
+                  key_ptr: i32,
+                  key_len: i32,
+                  ptr: i32,
+                  len: i32| {
+                let key = match read_string(&mut caller, key_ptr, key_len) {
+                    Ok(key) => key,
+                    Err(e) => return -report_error(caller.data_mut(), e) as i64,
+                };
+                let mut object = Vec::new();
+                let outcome = record_charges(caller.data_mut(), user, |state| {
+                    // Rolling back the charge would make the read free
+                    tx::ensure_inactive(state)?;
+                    object = storage::get(state, user, &key)?;
+                    Ok(())
+                });
+                if let Err(code) = outcome {
+                    return -code as i64;
+                }
+                match guest_memory::write(&mut caller, ptr, len, &object) {
+                    Ok(_) => object.len() as i64,
+                    Err(e) => -report_error(caller.data_mut(), e) as i64,
+                }
+            },
+        ),
+        "storage_delete" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, key_ptr: i32, key_len: i32| {
+                let outcome = match read_string(&mut caller, key_ptr, key_len) {
+                    Ok(key) => record_charges(caller.data_mut(), user, |state| {
+                        tx::ensure_inactive(state)?;
+                        storage::delete(state, user, &key)
+                    }),
+                    Err(e) => Err(report_error(caller.data_mut(), e)),
+                };
+                charged_result(&mut caller, user, outcome)
+            },
+        ),
+        "register_domain" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32, years: i32| {
+                let outcome = match read_string(&mut caller, ptr, len) {
+                    Ok(name) => record_charges(caller.data_mut(), user, |state| {
+                        // The registry of the names is not part of the user's data
+                        tx::ensure_inactive(state)?;
+                        let cost = domains::cost(&state.config.domains, years)?;
+                        policy::authorize(state, user, Action::Order, cost)?;
+                        domains::register(
+                            &mut state.users,
+                            &state.config.domains,
+                            user,
+                            &name,
+                            years,
+                        )
+                    }),
+                    Err(e) => Err(report_error(caller.data_mut(), e)),
+                };
+                charged_result(&mut caller, user, outcome)
+            },
+        ),
+        // Returns 1 if the name is available, 0 if it is taken or the negated error code.
+        "domain_available" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let available = read_string(&mut caller, ptr, len)
+                    .and_then(|name| domains::is_available(&caller.data().users, &name));
+                match available {
+                    Ok(available) => available as i32,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        "send_email" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>,
+                  to_ptr: i32,
+                  to_len: i32,
+                  subject_ptr: i32,
+                  subject_len: i32,
+                  body_ptr: i32,
+                  body_len: i32| {
+                let read = |caller: &mut Caller<'_, WasmtimeState>| -> Result<_, Error> {
+                    Ok((
+                        read_string(caller, to_ptr, to_len)?,
+                        read_string(caller, subject_ptr, subject_len)?,
+                        read_string(caller, body_ptr, body_len)?,
+                    ))
+                };
+                let outcome = match read(&mut caller) {
+                    Ok((to, subject, body)) => record_charges(caller.data_mut(), user, |state| {
+                        tx::ensure_inactive(state)?;
+                        email::send(state, user, &to, &subject, &body)
+                    }),
+                    Err(e) => Err(report_error(caller.data_mut(), e)),
+                };
+                charged_result(&mut caller, user, outcome)
+            },
+        ),
+        // Writes up to `len` bytes of the connection information of the user's database
+        // into the buffer and returns its full length or the negated error code.
+        "db_connection_info" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let state = caller.data_mut();
+                let info = db::connection_info(&state.users.get(&user).unwrap()).map(str::to_owned);
+                let info = match info {
+                    Ok(info) => info,
+                    Err(e) => return -report_error(state, e),
+                };
+                match guest_memory::write(&mut caller, ptr, len, info.as_bytes()) {
+                    Ok(_) => info.len() as i32,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        // Returns the bytes served to the user in the current billing cycle
+        "bandwidth_used_this_cycle" => {
+            Func::wrap(&mut store, move |caller: Caller<'_, WasmtimeState>| {
+                let used = metering::used_this_cycle(caller.data(), user).unwrap();
+                i64::try_from(used).unwrap_or(i64::MAX)
+            })
+        }
+        "queue_send" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, to_user_id: i64, ptr: i32, len: i32| {
+                let read = |caller: &mut Caller<'_, WasmtimeState>| -> Result<_, Error> {
+                    let to = usize::try_from(to_user_id)
+                        .map_err(|_| BillingError::InvalidArgumentValue)?;
+                    Ok((UserId(to), guest_memory::read(caller, ptr, len)?))
+                };
+                let outcome = match read(&mut caller) {
+                    Ok((to, body)) => record_charges(caller.data_mut(), user, |state| {
+                        tx::ensure_inactive(state)?;
+                        queues::send(&mut state.users, &state.config.queues, user, to, &body)
+                    }),
+                    Err(e) => Err(report_error(caller.data_mut(), e)),
+                };
+                charged_result(&mut caller, user, outcome)
+            },
+        ),
+        // Moves the oldest message of the user's inbox into the buffer and returns its length.
+        // If the buffer is too small, the message stays queued and its length is returned
+        // so that the guest can retry with a larger buffer. Returns 0 if the inbox is empty.
+        "queue_poll" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let state = caller.data_mut();
+                let config = state.config.queues;
+                let user_data = state.users.get_mut(&user).unwrap();
+                let Some(message) = queues::peek(user_data, &config) else {
+                    return 0;
+                };
+                let body_len = message.body.len();
+                if body_len > len.max(0) as usize {
+                    return body_len as i32;
+                }
+                let message = user_data.inbox.pop_front().unwrap();
+                match guest_memory::write(&mut caller, ptr, len, &message.body) {
+                    Ok(_) => body_len as i32,
+                    Err(e) => {
+                        // The message is put back so that it is not lost
+                        let state = caller.data_mut();
+                        state
+                            .users
+                            .get_mut(&user)
+                            .unwrap()
+                            .inbox
+                            .push_front(message);
+                        -report_error(state, e)
+                    }
+                }
+            },
+        ),
+        // Schedules the export of a module added to the scheduled jobs, identified by its hash,
+        // to run on behalf of the user. Returns the id of the job or the negated error code.
+        "schedule_job" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>,
+                  hash_ptr: i32,
+                  hash_len: i32,
+                  export_ptr: i32,
+                  export_len: i32,
+                  cron_ptr: i32,
+                  cron_len: i32| {
+                let read = |caller: &mut Caller<'_, WasmtimeState>| -> Result<_, Error> {
+                    Ok((
+                        read_string(caller, hash_ptr, hash_len)?,
+                        read_string(caller, export_ptr, export_len)?,
+                        read_string(caller, cron_ptr, cron_len)?,
+                    ))
+                };
+                let scheduled = read(&mut caller).and_then(|(hash, export, expression)| {
+                    let state = caller.data_mut();
+                    tx::ensure_inactive(state)?;
+                    cron::schedule_job(state, user, &hash, &export, &expression)
+                });
+                match scheduled {
+                    Ok(job) => {
+                        write_result(&mut caller, GuestResult::ok(Payload::Job(job.0)));
+                        job.0 as i64
+                    }
+                    Err(e) => {
+                        let code = report_error(caller.data_mut(), e);
+                        -write_result(&mut caller, GuestResult::error(code)) as i64
+                    }
+                }
+            },
+        ),
+        "cancel_job" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, job: i64| {
+                let state = caller.data_mut();
+                let cancelled = tx::ensure_inactive(state)
+                    .and_then(|_| u64::try_from(job).map_err(|_| BillingError::UnknownJob.into()))
+                    .and_then(|job| cron::cancel_job(state, user, cron::JobId(job)));
+                let result = match cancelled {
+                    Ok(()) => GuestResult::ok(Payload::None),
+                    Err(e) => GuestResult::error(report_error(state, e)),
+                };
+                write_result(&mut caller, result)
+            },
+        ),
+        // Writes up to `len` bytes of the secret into the buffer and returns its full length
+        // or the negated error code.
+        "secret_get" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>,
+                  name_ptr: i32,
+                  name_len: i32,
+                  ptr: i32,
+                  len: i32| {
+                let value = read_string(&mut caller, name_ptr, name_len)
+                    .and_then(|name| secrets::guest_get(caller.data_mut(), user, &name));
+                let value = match value {
+                    Ok(value) => value,
+                    Err(e) => return -report_error(caller.data_mut(), e),
+                };
+                match guest_memory::write(&mut caller, ptr, len, &value) {
+                    Ok(_) => value.len() as i32,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        // Returns the balance in cents at the time in seconds since the Unix epoch. Since balances
+        // may be negative, `i64::MIN` is returned if the balance at the time is not known.
+        "balance_at" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, epoch_secs: i64| {
+                let state = caller.data_mut();
+                let user_data = state.users.get_mut(&user).unwrap();
+                let balance = u64::try_from(epoch_secs)
+                    .ok()
+                    .and_then(|at| balance_history::balance_at(user_data, at));
+                match balance {
+                    Some(balance) => balance.to_cents_as_i64(),
+                    None => {
+                        report_error(state, BillingError::BalanceHistoryUnavailable);
+                        i64::MIN
+                    }
+                }
+            },
+        ),
+        // The subject and the body are read from the guest memory, see `tickets::open`
+        "open_ticket" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>,
+                  subject_ptr: i32,
+                  subject_len: i32,
+                  body_ptr: i32,
+                  body_len: i32| {
+                let opened =
+                    read_string(&mut caller, subject_ptr, subject_len).and_then(|subject| {
+                        let body = read_string(&mut caller, body_ptr, body_len)?;
+                        let state = caller.data_mut();
+                        tx::ensure_inactive(state)?;
+                        tickets::open(state, user, &subject, &body)
+                    });
+                match opened {
+                    Ok(ticket) => {
+                        write_result(&mut caller, GuestResult::ok(Payload::Ticket(ticket.0)));
+                        ticket.0 as i64
+                    }
+                    Err(e) => {
+                        let code = report_error(caller.data_mut(), e);
+                        -write_result(&mut caller, GuestResult::error(code)) as i64
+                    }
+                }
+            },
+        ),
+        // Tokens all have the same length, so the new one always fits in place of the old one
+        "rotate_api_key" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let rotated = read_string(&mut caller, ptr, len).and_then(|token| {
+                    let state = caller.data_mut();
+                    let allowed = state
+                        .users
+                        .get(&user)
+                        .is_some_and(|u| u.capabilities.contains(&Capability::ManageKeys));
+                    if !allowed {
+                        return Err(BillingError::MissingCapability.into());
+                    }
+                    tx::ensure_inactive(state)?;
+                    auth::rotate_token(&mut state.users, user, &token)
+                });
+                let result = match rotated
+                    .and_then(|token| guest_memory::write(&mut caller, ptr, len, token.as_bytes()))
+                {
+                    Ok(_) => GuestResult::ok(Payload::None),
+                    Err(e) => GuestResult::error(report_error(caller.data_mut(), e)),
+                };
+                write_result(&mut caller, result)
+            },
+        ),
+        "order_bundle" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let outcome = match read_string(&mut caller, ptr, len) {
+                    Ok(name) => record_charges(caller.data_mut(), user, |state| {
+                        order_bundle(state, user, &name)
+                    }),
+                    Err(e) => Err(report_error(caller.data_mut(), e)),
+                };
+                charged_result(&mut caller, user, outcome)
+            },
+        ),
+        "metric_incr" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32, value: i64| {
+                let recorded = read_string(&mut caller, ptr, len).and_then(|name| {
+                    let state = caller.data_mut();
+                    let config = state.config.guest_metrics;
+                    state.guest_metrics.incr(&config, user, &name, value)
+                });
+                match recorded {
+                    Ok(()) => 0,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        "metric_gauge" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32, value: i64| {
+                let recorded = read_string(&mut caller, ptr, len).and_then(|name| {
+                    let state = caller.data_mut();
+                    let config = state.config.guest_metrics;
+                    state.guest_metrics.gauge(&config, user, &name, value)
+                });
+                match recorded {
+                    Ok(()) => 0,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        "trace_id" => Func::wrap(
+            &mut store,
+            |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let id = trace::current().unwrap_or_default();
+                match guest_memory::write(&mut caller, ptr, len, id.as_bytes()) {
+                    Ok(_) => id.len() as i32,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        "feature_enabled" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                let enabled = read_string(&mut caller, ptr, len)
+                    .and_then(|name| features::is_enabled(caller.data(), user, &name));
+                match enabled {
+                    Ok(enabled) => enabled as i32,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        "cancel_service" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, order_id: i64| {
+                let outcome = match u64::try_from(order_id) {
+                    Ok(id) => record_charges(caller.data_mut(), user, |state| {
+                        tx::ensure_inactive(state)?;
+                        cancellation::cancel(state, user, OrderId(id)).map(|_| ())
+                    }),
+                    Err(_) => Err(report_error(caller.data_mut(), BillingError::UnknownOrder)),
+                };
+                charged_result(&mut caller, user, outcome)
+            },
+        ),
+        "set_spend_alert" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, kind: i32, threshold: i64| {
+                let state = caller.data_mut();
+                let result = match alerts::guest_set_rule(state, user, kind, threshold) {
+                    Ok(()) => GuestResult::ok(Payload::None),
+                    Err(e) => GuestResult::error(report_error(state, e)),
+                };
+                write_result(&mut caller, result)
+            },
+        ),
+        "acknowledge_anomaly" => {
+            Func::wrap(&mut store, move |mut caller: Caller<'_, WasmtimeState>| {
+                let state = caller.data_mut();
+                let result = match anomalies::acknowledge(state, user, Acknowledger::User) {
+                    Ok(()) => GuestResult::ok(Payload::None),
+                    Err(e) => GuestResult::error(report_error(state, e)),
+                };
+                write_result(&mut caller, result)
+            })
+        }
+        "begin_tx" => Func::wrap(&mut store, move |mut caller: Caller<'_, WasmtimeState>| {
+            let state = caller.data_mut();
+            let result = match tx::begin(state, user) {
+                Ok(()) => GuestResult::ok(Payload::None),
+                Err(e) => GuestResult::error(report_error(state, e)),
+            };
+            write_result(&mut caller, result)
+        }),
+        // Reports the order placed for the first purchase of the transaction like the calls
+        // placing orders do, or the new balance if there was none
+        "commit_tx" => Func::wrap(&mut store, move |mut caller: Caller<'_, WasmtimeState>| {
+            let state = caller.data_mut();
+            let outcome = match tx::commit(state) {
+                Ok(before) => Ok(settle(state, user, before)),
+                Err(e) => Err(report_error(state, e)),
+            };
+            charged_result(&mut caller, user, outcome)
+        }),
+        "rollback_tx" => Func::wrap(&mut store, move |mut caller: Caller<'_, WasmtimeState>| {
+            let state = caller.data_mut();
+            let result = match tx::rollback(state) {
+                Ok(()) => GuestResult::ok(Payload::None),
+                Err(e) => GuestResult::error(report_error(state, e)),
+            };
+            write_result(&mut caller, result)
+        }),
+        // Returns the status of an order of the user, see `orders::OrderStatus::code`,
+        // or the negated error code.
+        "order_status" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, order_id: i64| {
+                let state = caller.data_mut();
+                let status = u64::try_from(order_id)
+                    .map_err(|_| BillingError::UnknownOrder.into())
+                    .and_then(|id| orders::get(state, user, OrderId(id)))
+                    .map(|order| orders::status(state, order));
+                match status {
+                    Ok(status) => status.code(),
+                    Err(e) => -report_error(state, e),
+                }
+            },
+        ),
+        // Writes up to `len` bytes of the order as JSON, see `orders::details_json`, into
+        // the buffer and returns the full length of the JSON or the negated error code.
+        "order_details" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, order_id: i64, ptr: i32, len: i32| {
+                let state = caller.data_mut();
+                let details = u64::try_from(order_id)
+                    .map_err(|_| BillingError::UnknownOrder.into())
+                    .and_then(|id| orders::get(state, user, OrderId(id)))
+                    .and_then(|order| orders::details_json(state, order));
+                let details = match details {
+                    Ok(details) => details,
+                    Err(e) => return -report_error(state, e),
+                };
+                match guest_memory::write(&mut caller, ptr, len, details.as_bytes()) {
+                    Ok(_) => details.len() as i32,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        "export_service_data" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>,
+                  order_id: i64,
+                  service_ptr: i32,
+                  service_len: i32,
+                  ptr: i32,
+                  len: i32| {
+                let data = read_string(&mut caller, service_ptr, service_len).and_then(|service| {
+                    let order = u64::try_from(order_id).map_err(|_| BillingError::UnknownOrder)?;
+                    retention::export(caller.data_mut(), user, OrderId(order), &service)
+                });
+                let data = match data {
+                    Ok(data) => data,
+                    Err(e) => return -report_error(caller.data_mut(), e),
+                };
+                match guest_memory::write(&mut caller, ptr, len, &data) {
+                    Ok(_) => data.len() as i32,
+                    Err(e) => -report_error(caller.data_mut(), e),
+                }
+            },
+        ),
+        // Returns the id of the new sub-account or the negated error code.
+        "create_sub_account" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, minor: i64| {
+                let state = caller.data_mut();
+                let created = tx::ensure_inactive(state).and_then(|_| {
+                    let currency = state.users.get(&user).unwrap().balance.currency();
+                    let funding = MoneyUnit::from_minor_units(minor, currency);
+                    resellers::create_sub_account(state, user, funding)
+                });
+                match created {
+                    Ok(account) => {
+                        write_result(
+                            &mut caller,
+                            GuestResult::ok(Payload::User(account.0 as u64)),
+                        );
+                        account.0 as i64
+                    }
+                    Err(e) => {
+                        let code = report_error(caller.data_mut(), e);
+                        -write_result(&mut caller, GuestResult::error(code)) as i64
+                    }
+                }
+            },
+        ),
+        "fund_sub_account" | "withdraw_from_sub_account" => {
+            let withdraw = import.name() == "withdraw_from_sub_account";
+            Func::wrap(
+                &mut store,
+                move |mut caller: Caller<'_, WasmtimeState>, account: i64, minor: i64| {
+                    let outcome = match usize::try_from(account) {
+                        Ok(account) => record_charges(caller.data_mut(), user, |state| {
+                            tx::ensure_inactive(state)?;
+                            let currency = state.users.get(&user).unwrap().balance.currency();
+                            let amount = MoneyUnit::from_minor_units(minor, currency);
+                            match withdraw {
+                                true => resellers::withdraw(state, user, UserId(account), amount),
+                                false => resellers::fund(state, user, UserId(account), amount),
+                            }
+                        }),
+                        Err(_) => Err(report_error(caller.data_mut(), BillingError::NotSubAccount)),
+                    };
+                    charged_result(&mut caller, user, outcome)
+                },
+            )
+        }
+        "set_markup" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, basis_points: i32| {
+                let outcome = record_charges(caller.data_mut(), user, |state| {
+                    tx::ensure_inactive(state)?;
+                    let basis_points = u32::try_from(basis_points)
+                        .map_err(|_| BillingError::InvalidArgumentValue)?;
+                    resellers::set_markup(state, user, basis_points)
+                });
+                charged_result(&mut caller, user, outcome)
+            },
+        ),
+        "sub_account_balance" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, account: i64| {
+                let state = caller.data_mut();
+                let balance = usize::try_from(account)
+                    .map_err(|_| BillingError::NotSubAccount.into())
+                    .and_then(|account| resellers::sub_account(&state.users, user, UserId(account)))
+                    .map(|account_data| account_data.balance.to_cents_as_i64());
+                match balance {
+                    Ok(balance) => balance,
+                    Err(e) => -report_error(state, e) as i64,
+                }
+            },
+        ),
+        "reseller_billing" => {
+            Func::wrap(&mut store, move |mut caller: Caller<'_, WasmtimeState>| {
+                let json = match resellers::tree_billing(caller.data(), user) {
+                    Ok(billing) => serde_json::to_string(&billing).unwrap(),
+                    Err(e) => return -report_error(caller.data_mut(), e) as i64,
+                };
+                match guest_memory::write_allocated(&mut caller, json.as_bytes()) {
+                    Ok(packed) => packed,
+                    Err(e) => -report_error(caller.data_mut(), e) as i64,
+                }
+            })
+        }
+        // Registers the buffer the results of the mutating calls are written into, see `abi`.
+        // It must hold at least `abi::RESULT_LEN` bytes. A `len` of 0 unregisters it.
+        "set_result_buffer" => Func::wrap(
+            &mut store,
+            |mut caller: Caller<'_, WasmtimeState>, ptr: i32, len: i32| {
+                if len == 0 {
+                    caller.data_mut().result_buffer = None;
+                    return 0;
+                }
+                if len < RESULT_LEN as i32 {
+                    return report_error(caller.data_mut(), BillingError::InvalidArgumentValue);
+                }
+                // The buffer is checked once, since memories never shrink
+                if let Err(e) = guest_memory::read(&mut caller, ptr, RESULT_LEN as i32) {
+                    return report_error(caller.data_mut(), e);
+                }
+                caller.data_mut().result_buffer = Some(ptr);
+                0
+            },
+        ),
+        "execution_history" => Func::wrap(
+            &mut store,
+            move |mut caller: Caller<'_, WasmtimeState>, limit: i32| {
+                let Ok(limit) = usize::try_from(limit) else {
+                    return -report_error(caller.data_mut(), BillingError::InvalidArgumentValue)
+                        as i64;
+                };
+                let json = history::latest_json(
+                    &caller.data().users.get(&user).unwrap().executions,
+                    limit,
+                );
+                match guest_memory::write_allocated(&mut caller, json.as_bytes()) {
+                    Ok(packed) => packed,
+                    Err(e) => -report_error(caller.data_mut(), e) as i64,
+                }
+            },
+        ),
+        "queue_poll_alloc" => {
+            Func::wrap(&mut store, move |mut caller: Caller<'_, WasmtimeState>| {
+                let state = caller.data_mut();
+                let config = state.config.queues;
+                let user_data = state.users.get_mut(&user).unwrap();
+                if queues::peek(user_data, &config).is_none() {
+                    return 0;
+                }
+                let message = user_data.inbox.pop_front().unwrap();
+                match guest_memory::write_allocated(&mut caller, &message.body) {
+                    Ok(packed) => packed,
+                    Err(e) => {
+                        // The message is put back so that it is not lost
+                        let state = caller.data_mut();
+                        state
+                            .users
+                            .get_mut(&user)
+                            .unwrap()
+                            .inbox
+                            .push_front(message);
+                        -report_error(state, e) as i64
+                    }
+                }
+            })
+        }
+        _ => return None,
+    };
+    let function = HOST_FUNCTIONS
+        .iter()
+        .find(|function| function.name == import.name())?;
+    let host_import = match function.mutating {
+        true => authorized(store, function, host_import, user),
+        false => host_import,
+    };
+    match store.data().config.call_pricing.price(function.name) {
+        Some(_) => Some(Extern::Func(priced(store, function, host_import, user))),
+        None => Some(Extern::Func(host_import)),
+    }
+}
+
+// Writes the page of the cursor into the buffer, returning the cursor of the next page or the
+// negated error code, see `pagination`
+fn write_page(
+    caller: &mut Caller<'_, WasmtimeState>,
+    cursor: i64,
+    ptr: i32,
+    len: i32,
+    page: impl FnOnce(&State, u64, usize) -> Result<Page, Error>,
+) -> i64 {
+    let page = match (u64::try_from(cursor), usize::try_from(len)) {
+        (Ok(cursor), Ok(len)) => page(caller.data(), cursor, len),
+        _ => Err(BillingError::InvalidArgumentValue.into()),
+    };
+    let written = page.and_then(|page| {
+        guest_memory::write(caller, ptr, len, &page.body)?;
+        Ok(page.cursor)
+    });
+    match written {
+        Ok(cursor) => cursor as i64,
+        Err(e) => -report_error(caller.data_mut(), e) as i64,
+    }
+}
+
+// Reports the error of a call that did not run like the function reports its own errors: as
+// the code of the structured result and, for the functions returning an i64 or a length,
+// negated. Traps if the function returns nothing.
+fn fail_call(
+    caller: &mut Caller<'_, WasmtimeState>,
+    function: &HostFunction,
+    error: Error,
+    results: &mut [Val],
+) -> wasmtime::Result<()> {
+    let Some(ty) = function.results.first() else {
+        return Err(error.into());
+    };
+    let code = report_error(caller.data_mut(), error);
+    let code = write_result(caller, GuestResult::error(code));
+    results[0] = match ty {
+        ValType::I64 => Val::I64(-code as i64),
+        // Its result is the length of the message
+        _ if function.name == "queue_poll" => Val::I32(-code),
+        _ => Val::I32(code),
+    };
+    Ok(())
+}
+
+// Wraps the mutating host function so that `State::authorization_hooks` are asked before every
+// call, which traps in the pure exports, see `result_cache`. A vetoed call reports its error
+// like the function does, see `fail_call`.
+fn authorized(
+    store: &mut Store<WasmtimeState>,
+    function: &'static HostFunction,
+    host_import: Func,
+    user: UserId,
+) -> Func {
+    let ty = host_import.ty(&*store);
+    Func::new(
+        store,
+        ty,
+        move |mut caller: Caller<'_, WasmtimeState>, params: &[Val], results: &mut [Val]| {
+            let args = params
+                .iter()
+                .map(|param| match *param {
+                    Val::I32(value) => value as i64,
+                    Val::I64(value) => value,
+                    _ => 0,
+                })
+                .collect::<Vec<_>>();
+            let call = HostCall {
+                user,
+                function: function.name,
+                args: &args,
+            };
+            let state = caller.data_mut();
+            // Failing the call would let the export ignore the error and have its result cached
+            if state.read_only {
+                return Err(Error::from(BillingError::ReadOnlyExport).into());
+            }
+            match authorization::authorize(state, &call) {
+                Ok(()) => guest_memory::call_wrapped(&mut caller, &host_import, params, results),
+                Err(e) => fail_call(&mut caller, function, e, results),
+            }
+        },
+    )
+}
+
+// Wraps the host function so that every call is charged the price of
+// `config::CallPricingConfig`, see `metering::meter_call`. A call the user cannot afford fails
+// without running.
+fn priced(
+    store: &mut Store<WasmtimeState>,
+    function: &'static HostFunction,
+    host_import: Func,
+    user: UserId,
+) -> Func {
+    let ty = host_import.ty(&*store);
+    Func::new(
+        store,
+        ty,
+        move |mut caller: Caller<'_, WasmtimeState>, params: &[Val], results: &mut [Val]| {
+            let state = caller.data_mut();
+            let metered = match state.config.call_pricing.price(function.name) {
+                Some(price) => metering::meter_call(state, user, function.name, price),
+                None => Ok(()),
+            };
+            match metered {
+                Ok(()) => guest_memory::call_wrapped(&mut caller, &host_import, params, results),
+                Err(e) => fail_call(&mut caller, function, e, results),
+            }
+        },
+    )
+}
@@ -0,0 +1,75 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use wasmtime::Engine;
+
+use crate::{Error, HostError, State};
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchdogConfig {
+    // How often the epoch of the engine is incremented
+    pub tick: Duration,
+    // Number of consecutive ticks without a `host.heartbeat` call after which
+    // the instance is considered hung and gets killed
+    pub max_missed_heartbeats: u32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            tick: Duration::from_secs(1),
+            max_missed_heartbeats: 5,
+        }
+    }
+}
+
+// Increments the epoch of the engine every tick on a background thread
+// until dropped. Also drives the sampling of `profiling`.
+pub struct Watchdog {
+    stop: Arc<AtomicBool>,
+    ticker: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub fn start(engine: Engine, tick: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let ticker = thread::spawn({
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    thread::sleep(tick);
+                    engine.increment_epoch();
+                }
+            }
+        });
+        Self {
+            stop,
+            ticker: Some(ticker),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(ticker) = self.ticker.take() {
+            let _ = ticker.join();
+        }
+    }
+}
+
+// Called on every watchdog tick while the guest executes wasm code. Time spent
+// in host functions does not count, so only guests stuck in their own code get killed.
+pub(crate) fn on_tick(state: &mut State, max_missed_heartbeats: u32) -> wasmtime::Result<()> {
+    if state.missed_heartbeats >= max_missed_heartbeats {
+        return Err(Error::from(HostError::MissedHeartbeats(state.missed_heartbeats)).into());
+    }
+    state.missed_heartbeats += 1;
+    Ok(())
+}
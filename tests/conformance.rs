@@ -0,0 +1,26 @@
+use wasi_services_management::{conformance, runtime::WasmtimeRuntime};
+
+#[test]
+fn host_passes_the_conformance_suite() {
+    let runtime = WasmtimeRuntime::new();
+    let failed = conformance::run_all(&runtime)
+        .into_iter()
+        .filter(|result| !result.passed())
+        .map(|result| format!("{}: {:?}", result.case.name, result.outcome))
+        .collect::<Vec<_>>();
+    assert!(failed.is_empty(), "failed cases:\n{}", failed.join("\n"));
+}
+
+#[test]
+fn suite_exports_every_case() {
+    let dir = std::env::temp_dir().join(format!("conformance-{}", std::process::id()));
+    conformance::export(&dir).unwrap();
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.join("suite.json")).unwrap()).unwrap();
+    let cases = manifest["cases"].as_array().unwrap();
+    assert_eq!(cases.len(), conformance::CASES.len());
+    for case in cases {
+        assert!(dir.join(case["module"].as_str().unwrap()).is_file());
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}